@@ -0,0 +1,124 @@
+//! Binary framing for the object batch API, served as `application/x-git-objects`.
+//!
+//! ```text
+//! "GOBJ" version:u8 count:u32
+//! count x (type:u8 id:[u8; 20] length:u64 content:[u8; length])
+//! ```
+//!
+//! Integers are big-endian and types use the pack numbering (1 commit, 2 tree,
+//! 3 blob, 4 tag). Type 0 marks an object the repository does not have, with no
+//! content. Unlike base64 JSON, content is sent as-is.
+
+use crate::{GitObject, ObjectType};
+use anyhow::{anyhow, Result};
+
+/// Media type of the binary batch encoding
+pub const BATCH_MEDIA_TYPE: &str = "application/x-git-objects";
+
+const MAGIC: &[u8; 4] = b"GOBJ";
+const VERSION: u8 = 1;
+const MISSING: u8 = 0;
+
+/// One requested object: found with its content, or missing
+#[derive(Debug, Clone)]
+pub enum BatchEntry {
+    Found(GitObject),
+    Missing(String),
+}
+
+/// Encode batch entries in request order
+pub fn encode_batch(entries: &[BatchEntry]) -> Result<Vec<u8>> {
+    let content_len: usize = entries
+        .iter()
+        .map(|e| match e {
+            BatchEntry::Found(obj) => obj.content.len(),
+            BatchEntry::Missing(_) => 0,
+        })
+        .sum();
+    let mut out = Vec::with_capacity(9 + entries.len() * 29 + content_len);
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+
+    for entry in entries {
+        let (type_id, id, content): (u8, &str, &[u8]) = match entry {
+            BatchEntry::Found(obj) => (type_id(&obj.obj_type), &obj.id, &obj.content),
+            BatchEntry::Missing(id) => (MISSING, id, &[]),
+        };
+        let raw_id = hex::decode(id).map_err(|_| anyhow!("Invalid object id: {}", id))?;
+        if raw_id.len() != 20 {
+            return Err(anyhow!("Invalid object id: {}", id));
+        }
+        out.push(type_id);
+        out.extend_from_slice(&raw_id);
+        out.extend_from_slice(&(content.len() as u64).to_be_bytes());
+        out.extend_from_slice(content);
+    }
+
+    Ok(out)
+}
+
+/// Decode a batch produced by `encode_batch`
+pub fn decode_batch(data: &[u8]) -> Result<Vec<BatchEntry>> {
+    let mut input = data;
+    if take(&mut input, 4)? != MAGIC {
+        return Err(anyhow!("Not an object batch"));
+    }
+    let version = take(&mut input, 1)?[0];
+    if version != VERSION {
+        return Err(anyhow!("Unsupported object batch version: {}", version));
+    }
+    let count = u32::from_be_bytes(take(&mut input, 4)?.try_into()?);
+
+    let mut entries = Vec::with_capacity(count.min(1024) as usize);
+    for _ in 0..count {
+        let type_id = take(&mut input, 1)?[0];
+        let id = hex::encode(take(&mut input, 20)?);
+        let length = u64::from_be_bytes(take(&mut input, 8)?.try_into()?);
+        let length = usize::try_from(length).map_err(|_| anyhow!("Object too large"))?;
+        let content = take(&mut input, length)?.to_vec();
+
+        entries.push(match type_id {
+            MISSING => BatchEntry::Missing(id),
+            _ => BatchEntry::Found(GitObject {
+                id,
+                obj_type: object_type(type_id)?,
+                size: content.len(),
+                content,
+            }),
+        });
+    }
+    if !input.is_empty() {
+        return Err(anyhow!("Trailing data after object batch"));
+    }
+
+    Ok(entries)
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if input.len() < n {
+        return Err(anyhow!("Truncated object batch"));
+    }
+    let (head, rest) = input.split_at(n);
+    *input = rest;
+    Ok(head)
+}
+
+fn type_id(obj_type: &ObjectType) -> u8 {
+    match obj_type {
+        ObjectType::Commit => 1,
+        ObjectType::Tree => 2,
+        ObjectType::Blob => 3,
+        ObjectType::Tag => 4,
+    }
+}
+
+fn object_type(type_id: u8) -> Result<ObjectType> {
+    match type_id {
+        1 => Ok(ObjectType::Commit),
+        2 => Ok(ObjectType::Tree),
+        3 => Ok(ObjectType::Blob),
+        4 => Ok(ObjectType::Tag),
+        _ => Err(anyhow!("Unknown object type: {}", type_id)),
+    }
+}
@@ -0,0 +1,31 @@
+use thiserror::Error;
+
+/// Malformed protocol input, carried inside `anyhow::Error`. Everything that
+/// parses bytes from the network reports these instead of panicking.
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("truncated {what}")]
+    Truncated { what: &'static str },
+    #[error("invalid {what}: {reason}")]
+    Malformed { what: &'static str, reason: String },
+    #[error("{what} overflows")]
+    Overflow { what: &'static str },
+    #[error("unknown object type: {type_id}")]
+    UnknownObjectType { type_id: u8 },
+    #[error("unsupported pack version: {version}")]
+    UnsupportedPackVersion { version: u32 },
+    /// `object` is the object's id, or where it is in the pack when that isn't known
+    #[error("object {object} is {size} bytes, over the {max} byte limit")]
+    ObjectTooLarge { object: String, size: u64, max: u64 },
+    #[error("object at offset {offset} does not inflate to its declared {declared} bytes")]
+    ObjectSizeMismatch { offset: u64, declared: u64 },
+}
+
+impl ProtocolError {
+    pub(crate) fn malformed(what: &'static str, reason: impl Into<String>) -> Self {
+        Self::Malformed {
+            what,
+            reason: reason.into(),
+        }
+    }
+}
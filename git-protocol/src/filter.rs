@@ -0,0 +1,43 @@
+use crate::ProtocolError;
+use anyhow::Result;
+
+/// A partial clone's `filter <spec>`, for the specs the server applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFilter {
+    /// `blob:none`: no blobs at all
+    BlobNone,
+    /// `blob:limit=<n>[kmg]`: no blobs of `n` bytes or more
+    BlobLimit(u64),
+}
+
+impl ObjectFilter {
+    pub fn parse(spec: &str) -> Result<Self> {
+        if spec == "blob:none" {
+            return Ok(ObjectFilter::BlobNone);
+        }
+        let invalid = || ProtocolError::malformed("filter-spec", spec);
+        let limit = spec.strip_prefix("blob:limit=").ok_or_else(invalid)?;
+        let (digits, unit) = match limit.char_indices().last() {
+            Some((i, suffix)) if suffix.is_ascii_alphabetic() => (&limit[..i], suffix.to_ascii_lowercase()),
+            _ => (limit, ' '),
+        };
+        let scale: u64 = match unit {
+            ' ' => 1,
+            'k' => 1 << 10,
+            'm' => 1 << 20,
+            'g' => 1 << 30,
+            _ => return Err(invalid().into()),
+        };
+        let value: u64 = digits.parse().map_err(|_| invalid())?;
+        let bytes = value.checked_mul(scale).ok_or(ProtocolError::Overflow { what: "blob limit" })?;
+        Ok(ObjectFilter::BlobLimit(bytes))
+    }
+
+    /// Size from which blobs are left out
+    pub fn blob_limit(&self) -> u64 {
+        match self {
+            ObjectFilter::BlobNone => 0,
+            ObjectFilter::BlobLimit(limit) => *limit,
+        }
+    }
+}
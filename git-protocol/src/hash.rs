@@ -0,0 +1,94 @@
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Hash a repository names its objects with, which also checksums its packs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Name as git's `objectFormat` spells it
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Digest length in bytes: 20 or 32
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// Object id length in hex digits
+    pub fn hex_len(&self) -> usize {
+        self.digest_len() * 2
+    }
+
+    /// The algorithm whose ids are `len` hex digits long
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        [HashAlgorithm::Sha1, HashAlgorithm::Sha256]
+            .into_iter()
+            .find(|hash| hash.hex_len() == len)
+    }
+
+    /// The all-zero id git uses for a missing object, e.g. in ref creation and deletion
+    pub fn zero_id(&self) -> String {
+        "0".repeat(self.hex_len())
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+/// Whether `id` is a hex object id of either algorithm
+pub fn is_object_id(id: &str) -> bool {
+    HashAlgorithm::from_hex_len(id.len()).is_some() && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Incremental digest in either algorithm
+#[derive(Clone)]
+pub enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
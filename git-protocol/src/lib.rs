@@ -1,11 +1,19 @@
+pub mod batch;
+pub mod error;
+pub mod filter;
+pub mod hash;
 pub mod pack;
 pub mod refs;
 pub mod objects;
 pub mod protocol;
+pub mod text;
 #[cfg(test)]
 mod tests;
 
-pub use protocol::ProtocolHandler;
+pub use error::ProtocolError;
+pub use filter::ObjectFilter;
+pub use hash::HashAlgorithm;
+pub use protocol::{Capabilities, Negotiation, ProtocolHandler, ReceivePackRequest, RefCommand, ZERO_ID};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
@@ -19,6 +27,38 @@ pub enum ObjectType {
     Tag,
 }
 
+impl ObjectType {
+    /// The lowercase name used in object headers and tag `type` lines
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObjectType::Commit => "commit",
+            ObjectType::Tree => "tree",
+            ObjectType::Blob => "blob",
+            ObjectType::Tag => "tag",
+        }
+    }
+}
+
+impl std::str::FromStr for ObjectType {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "commit" => Ok(ObjectType::Commit),
+            "tree" => Ok(ObjectType::Tree),
+            "blob" => Ok(ObjectType::Blob),
+            "tag" => Ok(ObjectType::Tag),
+            other => Err(ProtocolError::malformed("object type", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// Git object representation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GitObject {
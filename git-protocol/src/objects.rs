@@ -1,8 +1,8 @@
-use crate::{GitObject, ObjectType};
-use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
+use crate::hash::HashAlgorithm;
+use crate::{GitObject, ObjectType, ProtocolError};
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
-use sha1::{Digest, Sha1};
 
 /// Git commit object
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,9 +11,118 @@ pub struct Commit {
     pub parents: Vec<String>,
     pub author: String,
     pub committer: String,
+    /// Everything after the headers, trailing newline included, decoded from
+    /// `encoding` when the commit names one
     pub message: String,
     pub author_date: DateTime<Utc>,
     pub commit_date: DateTime<Utc>,
+    /// The `encoding` header: the message's character set when it isn't UTF-8
+    #[serde(default)]
+    pub encoding: Option<String>,
+}
+
+impl Commit {
+    /// Trailers at the end of the message, see `parse_trailers`
+    pub fn trailers(&self) -> Vec<Trailer> {
+        parse_trailers(&self.message)
+    }
+
+    /// Identities named by `Co-authored-by` trailers, in message order
+    pub fn co_authors(&self) -> Vec<String> {
+        self.trailers()
+            .into_iter()
+            .filter(|t| t.key.eq_ignore_ascii_case("Co-authored-by"))
+            .map(|t| t.value)
+            .collect()
+    }
+
+    /// Whether the author or committer date is more than `max_skew` past `now`,
+    /// which no honest clock produces
+    pub fn is_future_dated(&self, now: DateTime<Utc>, max_skew: TimeDelta) -> bool {
+        let latest = now + max_skew;
+        self.author_date > latest || self.commit_date > latest
+    }
+}
+
+/// A `Key: value` line from the trailer block of a commit message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trailer {
+    pub key: String,
+    pub value: String,
+}
+
+/// Trailers git writes itself; one of these lets a block that also holds other
+/// lines count as trailers, as long as at least a quarter of it are trailers
+const GIT_GENERATED_TRAILERS: &[&str] = &["Signed-off-by"];
+
+/// Extract trailers with `git interpret-trailers` rules: only the last paragraph
+/// is considered, and never the subject paragraph. It counts when every line is a
+/// trailer, or when it contains a git-generated trailer and at least 25% of its
+/// lines are trailers. Lines starting with whitespace continue the previous
+/// trailer's value.
+pub fn parse_trailers(message: &str) -> Vec<Trailer> {
+    let lines: Vec<&str> = message.lines().collect();
+    let end = lines
+        .iter()
+        .rposition(|line| !line.trim().is_empty())
+        .map_or(0, |last| last + 1);
+    let Some(start) = lines[..end].iter().rposition(|line| line.trim().is_empty()) else {
+        // A single paragraph is the subject
+        return Vec::new();
+    };
+    let block = &lines[start + 1..end];
+
+    let mut trailers: Vec<Trailer> = Vec::new();
+    let mut trailer_lines = 0;
+    let mut other_lines = 0;
+    let mut recognized = false;
+    let mut continues_trailer = false;
+    for line in block {
+        if line.starts_with([' ', '\t']) {
+            match trailers.last_mut() {
+                Some(trailer) if continues_trailer => {
+                    trailer.value.push(' ');
+                    trailer.value.push_str(line.trim());
+                }
+                _ => other_lines += 1,
+            }
+            continue;
+        }
+
+        match parse_trailer_line(line) {
+            Some(trailer) => {
+                recognized |= GIT_GENERATED_TRAILERS
+                    .iter()
+                    .any(|key| trailer.key.eq_ignore_ascii_case(key));
+                trailers.push(trailer);
+                trailer_lines += 1;
+                continues_trailer = true;
+            }
+            None => {
+                other_lines += 1;
+                continues_trailer = false;
+            }
+        }
+    }
+
+    let is_trailer_block =
+        trailer_lines > 0 && (other_lines == 0 || (recognized && trailer_lines * 3 >= other_lines));
+    if is_trailer_block {
+        trailers
+    } else {
+        Vec::new()
+    }
+}
+
+/// `Key: value` where the key is letters, digits and dashes
+fn parse_trailer_line(line: &str) -> Option<Trailer> {
+    let (key, value) = line.split_once(':')?;
+    let key = key.trim_end();
+    let valid = !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    valid.then(|| Trailer {
+        key: key.to_string(),
+        value: value.trim().to_string(),
+    })
 }
 
 /// Git tree entry
@@ -40,19 +149,40 @@ pub struct Blob {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tag {
     pub object: String,
-    pub obj_type: String,
+    pub obj_type: ObjectType,
     pub tag_name: String,
     pub tagger: String,
     pub message: String,
-    pub tagger_date: DateTime<Utc>,
+    /// From the tagger line; `None` when the tag has no tagger or date
+    pub tagger_date: Option<DateTime<Utc>>,
 }
 
 /// Object parser and serializer
-pub struct ObjectHandler;
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectHandler {
+    hash: HashAlgorithm,
+}
 
 impl ObjectHandler {
     pub fn new() -> Self {
-        Self
+        Self {
+            hash: HashAlgorithm::default(),
+        }
+    }
+
+    /// Name objects, and read and write tree entries, with `hash` instead of SHA-1
+    pub fn with_hash_algorithm(mut self, hash: HashAlgorithm) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    /// Id of the tree with no entries
+    pub fn empty_tree_id(&self) -> String {
+        hex::encode(self.hash.digest(b"tree 0\0"))
     }
 
     /// Parse a Git object from its raw content
@@ -68,39 +198,52 @@ impl ObjectHandler {
         })
     }
 
-    /// Parse a commit object
+    /// Parse a commit object. Like git, the `encoding` header applies to the whole
+    /// commit, identities and message; without one, or for one we don't know, the
+    /// text is read as UTF-8, lossily.
     pub fn parse_commit(&self, content: &[u8]) -> Result<Commit> {
-        let content_str = String::from_utf8_lossy(content);
-        let lines: Vec<&str> = content_str.lines().collect();
-        
+        // Headers end at the first blank line; the message is everything after it
+        let (headers, body) = match content.windows(2).position(|pair| pair == b"\n\n") {
+            Some(end) => (&content[..end], &content[end + 2..]),
+            None => (content, &content[content.len()..]),
+        };
+        let encoding = headers
+            .split(|&b| b == b'\n')
+            .find_map(|line| line.strip_prefix(b"encoding "))
+            .map(|label| String::from_utf8_lossy(label).into_owned());
+        let charset = encoding.as_deref().and_then(message_encoding);
+        let decode = |bytes: &[u8]| match charset {
+            Some(charset) => charset.decode_without_bom_handling(bytes).0.into_owned(),
+            None => String::from_utf8_lossy(bytes).into_owned(),
+        };
+        let headers = decode(headers);
+
         let mut tree = String::new();
         let mut parents = Vec::new();
         let mut author = String::new();
         let mut committer = String::new();
-        let mut author_date = Utc::now();
-        let mut commit_date = Utc::now();
-        let mut message_start = 0;
+        let mut author_date = DateTime::UNIX_EPOCH;
+        let mut commit_date = DateTime::UNIX_EPOCH;
 
-        for (i, line) in lines.iter().enumerate() {
-            if line.starts_with("tree ") {
-                tree = line[5..].to_string();
-            } else if line.starts_with("parent ") {
-                parents.push(line[7..].to_string());
-            } else if line.starts_with("author ") {
-                author = line[7..].to_string();
-                // Parse date from author line (simplified)
-                author_date = Utc::now(); // Should parse actual timestamp
-            } else if line.starts_with("committer ") {
-                committer = line[10..].to_string();
-                // Parse date from committer line (simplified)
-                commit_date = Utc::now(); // Should parse actual timestamp
-            } else if line.is_empty() {
-                message_start = i + 1;
-                break;
+        for line in headers.lines() {
+            if let Some(rest) = line.strip_prefix("tree ") {
+                tree = rest.to_string();
+            } else if let Some(rest) = line.strip_prefix("parent ") {
+                parents.push(rest.to_string());
+            } else if let Some(rest) = line.strip_prefix("author ") {
+                author = rest.to_string();
+                author_date = parse_signature_date(rest).unwrap_or(DateTime::UNIX_EPOCH);
+            } else if let Some(rest) = line.strip_prefix("committer ") {
+                committer = rest.to_string();
+                commit_date = parse_signature_date(rest).unwrap_or(DateTime::UNIX_EPOCH);
             }
         }
 
-        let message = lines[message_start..].join("\n");
+        if tree.is_empty() {
+            return Err(ProtocolError::malformed("commit", "missing tree").into());
+        }
+
+        let message = decode(body);
 
         Ok(Commit {
             tree,
@@ -110,6 +253,7 @@ impl ObjectHandler {
             message,
             author_date,
             commit_date,
+            encoding,
         })
     }
 
@@ -123,7 +267,7 @@ impl ObjectHandler {
             let space_pos = content[pos..]
                 .iter()
                 .position(|&b| b == b' ')
-                .ok_or_else(|| anyhow!("Invalid tree format: no space after mode"))?;
+                .ok_or_else(|| ProtocolError::malformed("tree", "no space after mode"))?;
             
             let mode = String::from_utf8_lossy(&content[pos..pos + space_pos]).to_string();
             pos += space_pos + 1;
@@ -132,18 +276,19 @@ impl ObjectHandler {
             let null_pos = content[pos..]
                 .iter()
                 .position(|&b| b == 0)
-                .ok_or_else(|| anyhow!("Invalid tree format: no null after filename"))?;
+                .ok_or_else(|| ProtocolError::malformed("tree", "no null after filename"))?;
             
             let name = String::from_utf8_lossy(&content[pos..pos + null_pos]).to_string();
             pos += null_pos + 1;
 
-            // Read 20-byte SHA-1 hash
-            if pos + 20 > content.len() {
-                return Err(anyhow!("Invalid tree format: incomplete hash"));
+            // Read the binary id: 20 bytes for SHA-1, 32 for SHA-256
+            let len = self.hash.digest_len();
+            if pos + len > content.len() {
+                return Err(ProtocolError::Truncated { what: "tree entry hash" }.into());
             }
             
-            let hash = hex::encode(&content[pos..pos + 20]);
-            pos += 20;
+            let hash = hex::encode(&content[pos..pos + len]);
+            pos += len;
 
             entries.push(TreeEntry { mode, name, hash });
         }
@@ -151,6 +296,51 @@ impl ObjectHandler {
         Ok(Tree { entries })
     }
 
+    /// Parse an annotated tag object
+    pub fn parse_tag(&self, content: &[u8]) -> Result<Tag> {
+        let content_str = String::from_utf8_lossy(content);
+        let lines: Vec<&str> = content_str.lines().collect();
+
+        let mut object = String::new();
+        let mut obj_type = None;
+        let mut tag_name = String::new();
+        let mut tagger = String::new();
+        let mut tagger_date = None;
+        let mut message_start = lines.len();
+
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(rest) = line.strip_prefix("object ") {
+                object = rest.to_string();
+            } else if let Some(rest) = line.strip_prefix("type ") {
+                obj_type = Some(rest.parse::<ObjectType>()?);
+            } else if let Some(rest) = line.strip_prefix("tag ") {
+                tag_name = rest.to_string();
+            } else if let Some(rest) = line.strip_prefix("tagger ") {
+                tagger = rest.to_string();
+                tagger_date = parse_signature_date(rest);
+            } else if line.is_empty() {
+                message_start = i + 1;
+                break;
+            }
+        }
+
+        if object.is_empty() {
+            return Err(ProtocolError::malformed("tag", "missing object").into());
+        }
+        let obj_type = obj_type.ok_or_else(|| ProtocolError::malformed("tag", "missing type"))?;
+
+        let message = lines[message_start..].join("\n");
+
+        Ok(Tag {
+            object,
+            obj_type,
+            tag_name,
+            tagger,
+            message,
+            tagger_date,
+        })
+    }
+
     /// Parse a blob object
     pub fn parse_blob(&self, content: &[u8]) -> Result<Blob> {
         Ok(Blob {
@@ -160,48 +350,53 @@ impl ObjectHandler {
 
     /// Serialize a commit object
     pub fn serialize_commit(&self, commit: &Commit) -> Vec<u8> {
-        let mut content = Vec::new();
+        let mut content = String::new();
         
-        content.extend_from_slice(format!("tree {}\n", commit.tree).as_bytes());
+        content.push_str(&format!("tree {}\n", commit.tree));
         
         for parent in &commit.parents {
-            content.extend_from_slice(format!("parent {}\n", parent).as_bytes());
+            content.push_str(&format!("parent {}\n", parent));
         }
         
-        content.extend_from_slice(format!("author {}\n", commit.author).as_bytes());
-        content.extend_from_slice(format!("committer {}\n", commit.committer).as_bytes());
-        content.extend_from_slice(b"\n");
-        content.extend_from_slice(commit.message.as_bytes());
+        content.push_str(&format!("author {}\n", commit.author));
+        content.push_str(&format!("committer {}\n", commit.committer));
+        // Right after the committer, where git writes it
+        if let Some(encoding) = &commit.encoding {
+            content.push_str(&format!("encoding {}\n", encoding));
+        }
+        content.push('\n');
+        content.push_str(&commit.message);
         
-        content
+        match commit.encoding.as_deref().and_then(message_encoding) {
+            Some(charset) => charset.encode(&content).0.into_owned(),
+            None => content.into_bytes(),
+        }
     }
 
-    /// Serialize a tree object
-    pub fn serialize_tree(&self, tree: &Tree) -> Vec<u8> {
+    /// Serialize a tree object; every entry hash must be a hex id of the handler's
+    /// algorithm
+    pub fn serialize_tree(&self, tree: &Tree) -> Result<Vec<u8>> {
         let mut content = Vec::new();
         
         for entry in &tree.entries {
+            let hash = hex::decode(&entry.hash)
+                .ok()
+                .filter(|hash| hash.len() == self.hash.digest_len())
+                .ok_or_else(|| ProtocolError::malformed("tree entry hash", entry.hash.clone()))?;
             content.extend_from_slice(entry.mode.as_bytes());
             content.push(b' ');
             content.extend_from_slice(entry.name.as_bytes());
             content.push(0);
-            content.extend_from_slice(&hex::decode(&entry.hash).unwrap_or_default());
+            content.extend_from_slice(&hash);
         }
         
-        content
+        Ok(content)
     }
 
-    /// Calculate SHA-1 hash for an object
+    /// Calculate an object's id with the handler's hash algorithm
     pub fn calculate_hash(&self, obj_type: ObjectType, content: &[u8]) -> Result<String> {
-        let type_str = match obj_type {
-            ObjectType::Commit => "commit",
-            ObjectType::Tree => "tree",
-            ObjectType::Blob => "blob",
-            ObjectType::Tag => "tag",
-        };
-
-        let header = format!("{} {}\0", type_str, content.len());
-        let mut hasher = Sha1::new();
+        let header = format!("{} {}\0", obj_type.as_str(), content.len());
+        let mut hasher = self.hash.hasher();
         hasher.update(header.as_bytes());
         hasher.update(content);
         
@@ -233,7 +428,7 @@ impl ObjectHandler {
 
     /// Create a new tree object
     pub fn create_tree(&self, tree: &Tree) -> Result<GitObject> {
-        let content = self.serialize_tree(tree);
+        let content = self.serialize_tree(tree)?;
         let id = self.calculate_hash(ObjectType::Tree, &content)?;
         Ok(GitObject {
             id,
@@ -244,6 +439,19 @@ impl ObjectHandler {
     }
 }
 
+/// Timestamp from a `Name <email> <seconds> <tz>` signature
+pub fn parse_signature_date(signature: &str) -> Option<DateTime<Utc>> {
+    let mut parts = signature.rsplitn(3, ' ');
+    let _timezone = parts.next()?;
+    let seconds = parts.next()?.parse().ok()?;
+    DateTime::from_timestamp(seconds, 0)
+}
+
+/// Character set named by a commit's `encoding` header, `None` when unknown
+fn message_encoding(label: &str) -> Option<&'static encoding_rs::Encoding> {
+    encoding_rs::Encoding::for_label(label.trim().as_bytes())
+}
+
 impl Default for ObjectHandler {
     fn default() -> Self {
         Self::new()
@@ -278,4 +486,141 @@ mod tests {
         assert_eq!(hash.len(), 40);
         assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_tag_type_is_parsed_into_an_object_type() {
+        let handler = ObjectHandler::new();
+        let tag = |kind: &str| format!("object {}\ntype {}\ntag v1\n\nrelease\n", "a".repeat(40), kind);
+
+        for kind in [ObjectType::Commit, ObjectType::Tree, ObjectType::Blob, ObjectType::Tag] {
+            assert_eq!(kind.as_str().parse::<ObjectType>().unwrap(), kind);
+            assert_eq!(handler.parse_tag(tag(kind.as_str()).as_bytes()).unwrap().obj_type, kind);
+        }
+
+        let err = handler.parse_tag(tag("Commit").as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid object type: Commit");
+        let untyped = format!("object {}\ntag v1\n\nrelease\n", "a".repeat(40));
+        let err = handler.parse_tag(untyped.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid tag: missing type");
+    }
+
+    #[test]
+    fn test_tag_date_comes_from_the_tagger_line() {
+        let handler = ObjectHandler::new();
+        let header = format!("object {}\ntype commit\ntag v1\n", "a".repeat(40));
+
+        let tagged = format!("{}tagger A U Thor <author@example.com> 1700000000 +0100\n\nrelease\n", header);
+        let tag = handler.parse_tag(tagged.as_bytes()).unwrap();
+        assert_eq!(tag.tagger_date, DateTime::from_timestamp(1700000000, 0));
+
+        // Old tags may have no tagger at all, or one without a date
+        let untagged = format!("{}\nrelease\n", header);
+        assert_eq!(handler.parse_tag(untagged.as_bytes()).unwrap().tagger_date, None);
+        let undated = format!("{}tagger A U Thor <author@example.com>\n\nrelease\n", header);
+        assert_eq!(handler.parse_tag(undated.as_bytes()).unwrap().tagger_date, None);
+    }
+
+    #[test]
+    fn test_commit_encoding_round_trips_byte_for_byte() {
+        let handler = ObjectHandler::new();
+        let mut content = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            author Ren\xe9 <rene@example.com> 1700000000 +0100\n\
+            committer Ren\xe9 <rene@example.com> 1700000000 +0100\n\
+            encoding ISO-8859-1\n\n"
+            .to_vec();
+        content.extend_from_slice(b"Caf\xe9 cr\xe8me\n\nD\xe9tails \xe0 suivre.\n");
+
+        let commit = handler.parse_commit(&content).unwrap();
+        assert_eq!(commit.encoding.as_deref(), Some("ISO-8859-1"));
+        assert_eq!(commit.author, "René <rene@example.com> 1700000000 +0100");
+        assert_eq!(commit.message, "Café crème\n\nDétails à suivre.\n");
+        assert_eq!(handler.serialize_commit(&commit), content);
+        let id = handler.calculate_hash(ObjectType::Commit, &content).unwrap();
+        assert_eq!(handler.create_commit(&commit).unwrap().id, id);
+
+        // Without a known encoding the message is read as UTF-8, replacing what isn't
+        let unknown = String::from_utf8_lossy(&content).replace("ISO-8859-1", "x-unheard-of").into_bytes();
+        let commit = handler.parse_commit(&unknown).unwrap();
+        assert_eq!(commit.encoding.as_deref(), Some("x-unheard-of"));
+        assert!(commit.message.starts_with("Caf\u{fffd} cr\u{fffd}me\n"));
+    }
+
+    fn trailer(key: &str, value: &str) -> Trailer {
+        Trailer { key: key.to_string(), value: value.to_string() }
+    }
+
+    #[test]
+    fn test_trailers_from_last_paragraph() {
+        let message = "Fix the widget\n\nLonger explanation.\n\nSigned-off-by: A U Thor <author@example.com>\nCo-authored-by: Pair Programmer <pair@example.com>\nReviewed-by: R <r@example.com>\n";
+        assert_eq!(
+            parse_trailers(message),
+            vec![
+                trailer("Signed-off-by", "A U Thor <author@example.com>"),
+                trailer("Co-authored-by", "Pair Programmer <pair@example.com>"),
+                trailer("Reviewed-by", "R <r@example.com>"),
+            ]
+        );
+
+        let commit = Commit {
+            tree: String::new(),
+            parents: vec![],
+            author: String::new(),
+            committer: String::new(),
+            message: message.to_string(),
+            author_date: DateTime::UNIX_EPOCH,
+            commit_date: DateTime::UNIX_EPOCH,
+            encoding: None,
+        };
+        assert_eq!(commit.co_authors(), vec!["Pair Programmer <pair@example.com>"]);
+    }
+
+    #[test]
+    fn test_trailer_lookalikes_outside_the_trailer_block() {
+        // Only the last paragraph counts, and a subject is never a trailer block
+        let message = "Fix: the widget\n\nNote: this looks like a trailer\nbut the paragraph goes on.\n\nThanks everyone.\n";
+        assert!(parse_trailers(message).is_empty());
+        assert!(parse_trailers("Fixes: #12").is_empty());
+
+        // A git-generated trailer lets a mostly-trailer block through, keeping only trailers
+        let message = "Fix\n\nCherry-picked manually, see below\nSigned-off-by: A <a@example.com>\n";
+        assert_eq!(parse_trailers(message), vec![trailer("Signed-off-by", "A <a@example.com>")]);
+        let message = "Fix\n\nSome prose here\nmore prose\nyet more\nand more\nSigned-off-by: A <a@example.com>\n";
+        assert!(parse_trailers(message).is_empty());
+    }
+
+    #[test]
+    fn test_folded_trailer_values() {
+        let message = "Subject\n\nBody.\n\nCo-authored-by: A Very Long Name\n  <long@example.com>\nSee-also: one,\n\ttwo\n";
+        assert_eq!(
+            parse_trailers(message),
+            vec![
+                trailer("Co-authored-by", "A Very Long Name <long@example.com>"),
+                trailer("See-also", "one, two"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sha256_handler_names_objects_and_reads_trees() {
+        let handler = ObjectHandler::new().with_hash_algorithm(HashAlgorithm::Sha256);
+        let blob = handler.create_blob(b"").unwrap();
+        // What `git hash-object --object-format=sha256` gives for an empty file
+        assert_eq!(blob.id, "473a0f4c3be8a93681a267e3b1e9a7dcda1185436fe141f7749120a303721813");
+        assert_eq!(
+            handler.empty_tree_id(),
+            "6ef19b41225c5369f1c104d45d8d85efa9b057b53b14b4b9b939dd74decc5321"
+        );
+
+        let tree = Tree {
+            entries: vec![TreeEntry { mode: "100644".to_string(), name: "empty".to_string(), hash: blob.id.clone() }],
+        };
+        let object = handler.create_tree(&tree).unwrap();
+        assert_eq!(object.size, "100644 empty\0".len() + 32);
+        assert_eq!(handler.parse_tree(&object.content).unwrap().entries[0].hash, blob.id);
+
+        // A SHA-1 handler can't write a SHA-256 id into a tree, nor read one back
+        assert!(ObjectHandler::new().create_tree(&tree).is_err());
+        let misread = ObjectHandler::new().parse_tree(&object.content);
+        assert!(misread.map(|tree| tree.entries[0].hash != blob.id).unwrap_or(true));
+    }
+}
@@ -1,17 +1,64 @@
-use crate::{GitObject, ObjectType, PackEntry};
+use crate::hash::{HashAlgorithm, Hasher};
+use crate::{GitObject, ObjectType, PackEntry, ProtocolError};
 use anyhow::{anyhow, Result};
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
-use flate2::Compression;
+use flate2::{Compression, Decompress, FlushDecompress, Status};
 use nom::{
     bytes::complete::tag,
     number::complete::{be_u32, u8},
     IResult,
 };
-use sha1::{Digest, Sha1};
 use std::collections::HashMap;
 use std::io::{Read, Write};
 
+/// Objects of the same type looked back over when searching for a delta base
+const DELTA_WINDOW: usize = 10;
+
+/// Longest chain of deltas built on deltas, as git's default `--depth`
+const MAX_DELTA_DEPTH: usize = 50;
+
+/// Bytes hashed together when indexing a delta base
+const DELTA_BLOCK_LEN: usize = 16;
+
+/// Longest run a single delta copy instruction covers
+const MAX_DELTA_COPY: usize = 0x10000;
+
+/// Longest literal a single delta insert instruction carries
+const MAX_DELTA_INSERT: usize = 0x7f;
+
+/// Bytes inflated at a time while reading a pack entry
+const INFLATE_CHUNK: usize = 64 * 1024;
+
+/// How a delta entry in a created pack names its base
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaFormat {
+    /// OFS_DELTA (type 6): how far back in the pack the base starts. Smaller,
+    /// but only for clients that negotiated `ofs-delta`.
+    Offset,
+    /// REF_DELTA (type 7): the base's object id, understood by every client
+    Ref,
+}
+
+/// Where a delta entry's base is: the base's offset in the pack for OFS_DELTA,
+/// its id for REF_DELTA
+#[derive(Debug)]
+enum DeltaBase {
+    Offset(u64),
+    Id(String),
+}
+
+/// A pack entry as read, before its delta (if any) is applied
+#[derive(Debug)]
+struct RawEntry {
+    offset: u64,
+    /// `None` for deltas, which take their base's type
+    object_type: Option<ObjectType>,
+    base: Option<DeltaBase>,
+    /// Inflated content, or the delta instructions
+    data: Vec<u8>,
+}
+
 /// Git pack file header
 #[derive(Debug)]
 pub struct PackHeader {
@@ -22,61 +69,162 @@ pub struct PackHeader {
 
 /// Git pack file parser with complete delta support and checksum verification
 pub struct PackParser {
-    objects: HashMap<String, PackEntry>,
+    compression: Compression,
+    hash: HashAlgorithm,
+    /// Largest object, or delta, `parse_pack` accepts; `None` for no limit
+    max_object_size: Option<usize>,
 }
 
 impl PackParser {
     pub fn new() -> Self {
         Self {
-            objects: HashMap::new(),
+            compression: Compression::default(),
+            hash: HashAlgorithm::default(),
+            max_object_size: None,
         }
     }
 
-    /// Parse complete pack file with checksum verification (simplified for now)
+    /// Refuse packs holding an object bigger than `max` bytes once inflated
+    pub fn with_max_object_size(mut self, max: Option<usize>) -> Self {
+        self.max_object_size = max;
+        self
+    }
+
+    /// Set the zlib level (0-9) used when creating packs
+    pub fn with_compression_level(mut self, level: u32) -> Result<Self> {
+        if level > 9 {
+            return Err(anyhow!("Invalid compression level: {} (expected 0-9)", level));
+        }
+        self.compression = Compression::new(level);
+        Ok(self)
+    }
+
+    /// Checksum packs and name delta bases with `hash` instead of SHA-1
+    pub fn with_hash_algorithm(mut self, hash: HashAlgorithm) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Parse a complete pack file: verify its trailing checksum, then read and
+    /// resolve every object as `parse_pack` does
     pub fn parse_pack_file_simple(&mut self, data: Vec<u8>) -> Result<Vec<PackEntry>> {
-        if data.len() < 32 {
-            return Err(anyhow!("Pack file too small"));
+        if data.len() < 12 + self.hash.digest_len() {
+            return Err(ProtocolError::Truncated { what: "pack" }.into());
         }
 
-        // Verify checksum (last 20 bytes)
-        let (pack_data, checksum_bytes) = data.split_at(data.len() - 20);
-        let mut hasher = Sha1::new();
-        hasher.update(pack_data);
-        let calculated_checksum = hasher.finalize();
+        // Verify checksum (the trailing digest)
+        let (pack_data, checksum_bytes) = data.split_at(data.len() - self.hash.digest_len());
+        let calculated_checksum = self.hash.digest(pack_data);
 
-        if calculated_checksum.as_slice() != checksum_bytes {
-            return Err(anyhow!("Pack file checksum verification failed"));
+        if calculated_checksum != checksum_bytes {
+            return Err(ProtocolError::malformed("pack", "checksum mismatch").into());
         }
 
-        // For now, use the existing simple header parsing
-        let header_bytes = &pack_data[0..12];
-        if header_bytes.len() < 12 {
-            return Err(anyhow!("Invalid pack header"));
+        if &pack_data[0..4] != b"PACK" {
+            return Err(ProtocolError::malformed("pack", "bad signature").into());
         }
-        
-        // Simple header parsing without nom
-        if &header_bytes[0..4] != b"PACK" {
-            return Err(anyhow!("Invalid pack signature"));
+        self.parse_pack(pack_data)
+    }
+
+    /// Read every object of a pack and resolve its deltas. Packs naming the same
+    /// object twice, or with deltas whose bases never resolve, are refused.
+    pub fn parse_pack(&self, data: &[u8]) -> Result<Vec<PackEntry>> {
+        let (mut input, header) = self.parse_header(data).map_err(|e| pack_error("pack header", e))?;
+        if !matches!(header.version, 2 | 3) {
+            return Err(ProtocolError::UnsupportedPackVersion { version: header.version }.into());
         }
-        
-        let version = u32::from_be_bytes([header_bytes[4], header_bytes[5], header_bytes[6], header_bytes[7]]);
-        let num_objects = u32::from_be_bytes([header_bytes[8], header_bytes[9], header_bytes[10], header_bytes[11]]);
-        
-        if version != 2 {
-            return Err(anyhow!("Unsupported pack version: {}", version));
+
+        let mut raw = Vec::new();
+        for _ in 0..header.num_objects {
+            let offset = (data.len() - input.len()) as u64;
+            let (rest, entry) = self.parse_raw_entry(input, offset)?;
+            raw.push(entry);
+            input = rest;
         }
+        self.resolve_deltas(raw)
+    }
 
-        // For now, return empty entries - full parsing would be implemented here
-        let entries = Vec::new();
-        
-        // Store basic info
-        self.objects.insert("header_info".to_string(), PackEntry {
-            object_type: ObjectType::Blob,
-            size: num_objects as usize,
-            data: vec![],
-        });
+    /// One entry at `offset`, inflated but with any delta still to apply. An entry
+    /// declared over `max_object_size` is refused without being buffered: whole
+    /// objects are hashed as they inflate so the refusal can name them.
+    fn parse_raw_entry<'a>(&self, input: &'a [u8], offset: u64) -> Result<(&'a [u8], RawEntry)> {
+        let entry_error = |e| pack_error("pack object", e);
+        let (input, (type_id, size)) = self.parse_type_and_size(input).map_err(entry_error)?;
+        let (input, base) = match type_id {
+            6 => {
+                let (input, distance) = self.parse_offset(input).map_err(entry_error)?;
+                // A distance of 0 names the entry itself; `resolve_deltas` reports it
+                let base = offset
+                    .checked_sub(distance)
+                    .ok_or_else(|| ProtocolError::malformed("pack object", "delta base before the start of the pack"))?;
+                (input, Some(DeltaBase::Offset(base)))
+            }
+            7 => {
+                let (input, id) = self.read_object_id(input).map_err(entry_error)?;
+                (input, Some(DeltaBase::Id(id)))
+            }
+            _ => (input, None),
+        };
+        let object_type = match base {
+            Some(_) => None,
+            None => Some(self.get_object_type(type_id)?),
+        };
+
+        if let Some(max) = self.max_object_size.filter(|max| size > *max) {
+            let object = match &object_type {
+                Some(object_type) => {
+                    let mut hasher = self.hash.hasher();
+                    hasher.update(format!("{} {}\0", object_type.as_str(), size).as_bytes());
+                    self.inflate_entry(input, offset, size, |chunk| hasher.update(chunk))?;
+                    hex::encode(hasher.finalize())
+                }
+                None => format!("at offset {}", offset),
+            };
+            return Err(ProtocolError::ObjectTooLarge { object, size: size as u64, max: max as u64 }.into());
+        }
+
+        let mut data = Vec::with_capacity(size.min(INFLATE_CHUNK));
+        let rest = self.inflate_entry(input, offset, size, |chunk| data.extend_from_slice(chunk))?;
+        Ok((rest, RawEntry { offset, object_type, base, data }))
+    }
+
+    /// Inflate the zlib stream starting `input`, handing the output to `sink` a
+    /// chunk at a time, and return what follows the stream. A stream that doesn't
+    /// inflate to exactly `declared` bytes is refused, and one that runs past it
+    /// is stopped there, so a small entry can't expand into a huge one.
+    fn inflate_entry<'a>(
+        &self,
+        input: &'a [u8],
+        offset: u64,
+        declared: usize,
+        mut sink: impl FnMut(&[u8]),
+    ) -> Result<&'a [u8]> {
+        let mismatch = || ProtocolError::ObjectSizeMismatch { offset, declared: declared as u64 };
+        let mut decompress = Decompress::new(true);
+        let mut chunk = Vec::with_capacity(INFLATE_CHUNK);
+        loop {
+            chunk.clear();
+            let (consumed, produced) = (decompress.total_in(), decompress.total_out());
+            let status = decompress
+                .decompress_vec(&input[consumed as usize..], &mut chunk, FlushDecompress::None)
+                .map_err(|e| ProtocolError::malformed("pack object", e.to_string()))?;
+            if decompress.total_out() > declared as u64 {
+                return Err(mismatch().into());
+            }
+            sink(&chunk);
 
-        Ok(entries)
+            match status {
+                Status::StreamEnd => break,
+                _ if decompress.total_in() == consumed && decompress.total_out() == produced => {
+                    return Err(ProtocolError::Truncated { what: "pack object" }.into());
+                }
+                _ => {}
+            }
+        }
+        if decompress.total_out() != declared as u64 {
+            return Err(mismatch().into());
+        }
+        Ok(&input[decompress.total_in() as usize..])
     }
 
     /// Parse pack file header
@@ -113,7 +261,7 @@ impl PackParser {
             }
             7 => {
                 // REF_DELTA - reference delta
-                let (input, _base_sha) = self.read_sha1(input)?;
+                let (input, _base_id) = self.read_object_id(input)?;
                 let (input, compressed_data) = self.read_compressed_data_properly(input)?;
                 
                 Ok((input, PackEntry {
@@ -134,6 +282,9 @@ impl PackParser {
                 decoder
                     .read_to_end(&mut data)
                     .map_err(|_| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Verify)))?;
+                if data.len() != size {
+                    return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::LengthValue)));
+                }
 
                 Ok((input, PackEntry {
                     object_type: obj_type,
@@ -144,12 +295,13 @@ impl PackParser {
         }
     }
 
-    /// Read SHA-1 hash (20 bytes)
-    fn read_sha1<'a>(&self, input: &'a [u8]) -> IResult<&'a [u8], String> {
-        if input.len() < 20 {
+    /// Read a binary object id (20 bytes for SHA-1, 32 for SHA-256)
+    fn read_object_id<'a>(&self, input: &'a [u8]) -> IResult<&'a [u8], String> {
+        let len = self.hash.digest_len();
+        if input.len() < len {
             return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Eof)));
         }
-        let (remaining, hash_bytes) = input.split_at(20);
+        let (hash_bytes, remaining) = input.split_at(len);
         Ok((remaining, hex::encode(hash_bytes)))
     }
 
@@ -162,7 +314,11 @@ impl PackParser {
             loop {
                 let (remaining, byte) = u8(input)?;
                 input = remaining;
-                offset = ((offset + 1) << 7) | (byte & 0x7f) as u64;
+                offset = offset
+                    .checked_add(1)
+                    .and_then(|offset| offset.checked_mul(1 << 7))
+                    .ok_or_else(|| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::TooLarge)))?
+                    | (byte & 0x7f) as u64;
                 if (byte & 0x80) == 0 {
                     break;
                 }
@@ -174,26 +330,135 @@ impl PackParser {
 
     /// Properly read compressed data stream
     fn read_compressed_data_properly<'a>(&self, input: &'a [u8]) -> IResult<&'a [u8], Vec<u8>> {
-        // For a real implementation, this would need to properly detect the end of the zlib stream
-        // For now, we'll assume the rest of the data is the compressed content
-        Ok((&[], input.to_vec()))
-    }
-
-    /// Resolve delta objects to their final form
-    fn resolve_deltas(&self, _entries: &mut Vec<PackEntry>) -> Result<()> {
-        // This is a simplified delta resolution
-        // In a complete implementation, this would:
-        // 1. Build a dependency graph of delta objects
-        // 2. Resolve deltas in the correct order
-        // 3. Apply delta instructions to reconstruct objects
-        
-        // For now, we'll just mark that delta resolution would happen here
-        Ok(())
+        // Inflate into a scratch buffer only to find where the zlib stream ends;
+        // the pack format doesn't record compressed lengths
+        let mut decompress = Decompress::new(true);
+        let mut scratch = Vec::with_capacity(8192);
+
+        loop {
+            scratch.clear();
+            let consumed = decompress.total_in() as usize;
+            let status = decompress
+                .decompress_vec(&input[consumed..], &mut scratch, FlushDecompress::None)
+                .map_err(|_| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Verify)))?;
+
+            match status {
+                Status::StreamEnd => break,
+                _ if decompress.total_in() as usize == consumed && scratch.is_empty() => {
+                    return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Eof)));
+                }
+                _ => {}
+            }
+        }
+
+        let consumed = decompress.total_in() as usize;
+        Ok((&input[consumed..], input[..consumed].to_vec()))
+    }
+
+    /// Apply every delta to its base, in pack order. Bases may come later in the
+    /// pack than the deltas built on them, so this repeats until nothing more
+    /// resolves rather than recursing; whatever is left is either a cycle or
+    /// names a base the pack doesn't have. Every object's id is checked against
+    /// the rest, so the same object can't be sent twice.
+    fn resolve_deltas(&self, raw: Vec<RawEntry>) -> Result<Vec<PackEntry>> {
+        let by_offset: HashMap<u64, usize> = raw.iter().enumerate().map(|(index, entry)| (entry.offset, index)).collect();
+        let mut resolved: Vec<Option<(ObjectType, Vec<u8>)>> = vec![None; raw.len()];
+        let mut ids: HashMap<String, usize> = HashMap::new();
+        let record = |index: usize, object_type: &ObjectType, data: &[u8], ids: &mut HashMap<String, usize>| {
+            let id = self.object_id(object_type, data);
+            match ids.insert(id.clone(), index) {
+                Some(_) => Err(ProtocolError::malformed("pack", format!("object {} appears more than once", id))),
+                None => Ok(()),
+            }
+        };
+
+        for (index, entry) in raw.iter().enumerate() {
+            if let Some(object_type) = &entry.object_type {
+                record(index, object_type, &entry.data, &mut ids)?;
+                resolved[index] = Some((object_type.clone(), entry.data.clone()));
+            }
+        }
+
+        let base_index = |entry: &RawEntry, ids: &HashMap<String, usize>| match &entry.base {
+            Some(DeltaBase::Offset(offset)) => by_offset.get(offset).copied(),
+            Some(DeltaBase::Id(id)) => ids.get(id).copied(),
+            None => None,
+        };
+        loop {
+            let mut progress = false;
+            for (index, entry) in raw.iter().enumerate() {
+                if resolved[index].is_some() {
+                    continue;
+                }
+                let Some((object_type, base)) = base_index(entry, &ids).and_then(|base| resolved[base].as_ref()) else {
+                    continue;
+                };
+                if let Some(max) = self.max_object_size {
+                    let size = self.delta_result_size(&entry.data)?;
+                    if size > max {
+                        let object = format!("at offset {}", entry.offset);
+                        return Err(ProtocolError::ObjectTooLarge { object, size: size as u64, max: max as u64 }.into());
+                    }
+                }
+                let (object_type, data) = (object_type.clone(), self.apply_delta(base, &entry.data)?);
+                record(index, &object_type, &data, &mut ids)?;
+                resolved[index] = Some((object_type, data));
+                progress = true;
+            }
+            if !progress {
+                break;
+            }
+        }
+
+        if let Some(first) = resolved.iter().position(Option::is_none) {
+            // Follow the unresolved chain from the first stuck delta: coming back to
+            // an entry already on it is a cycle, running off the pack a missing base
+            let mut seen = Vec::new();
+            let mut index = first;
+            loop {
+                if seen.contains(&index) {
+                    let reason = format!("delta cycle through the object at offset {}", raw[index].offset);
+                    return Err(ProtocolError::malformed("pack", reason).into());
+                }
+                seen.push(index);
+                match base_index(&raw[index], &ids) {
+                    Some(base) if resolved[base].is_none() => index = base,
+                    _ => {
+                        let reason = match &raw[index].base {
+                            Some(DeltaBase::Id(id)) => format!("delta base {} is not in the pack", id),
+                            _ => format!("delta at offset {} has no base in the pack", raw[index].offset),
+                        };
+                        return Err(ProtocolError::malformed("pack", reason).into());
+                    }
+                }
+            }
+        }
+
+        Ok(resolved
+            .into_iter()
+            .flatten()
+            .map(|(object_type, data)| PackEntry { object_type, size: data.len(), data })
+            .collect())
+    }
+
+    /// Id of an object, with this parser's hash algorithm
+    fn object_id(&self, object_type: &ObjectType, data: &[u8]) -> String {
+        let mut hasher = self.hash.hasher();
+        hasher.update(format!("{} {}\0", object_type.as_str(), data.len()).as_bytes());
+        hasher.update(data);
+        hex::encode(hasher.finalize())
+    }
+
+    /// Size of the object a delta builds, from its header
+    fn delta_result_size(&self, delta: &[u8]) -> Result<usize> {
+        let (_base_size, consumed) = self.read_varint(delta)?;
+        let (result_size, _) = self.read_varint(&delta[consumed..])?;
+        Ok(result_size)
     }
 
     /// Apply delta to base object
     fn apply_delta(&self, base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
-        let mut result = Vec::new();
+        let truncated = || ProtocolError::Truncated { what: "delta" };
         let mut delta_pos = 0;
 
         // Read base size
@@ -204,6 +469,8 @@ impl PackParser {
         let (result_size, consumed) = self.read_varint(&delta[delta_pos..])?;
         delta_pos += consumed;
 
+        let mut result = Vec::new();
+
         // Process delta instructions
         while delta_pos < delta.len() {
             let instruction = delta[delta_pos];
@@ -211,13 +478,14 @@ impl PackParser {
 
             if instruction & 0x80 != 0 {
                 // Copy instruction
-                let mut offset = 0u32;
-                let mut size = 0u32;
+                let mut offset = 0usize;
+                let mut size = 0usize;
                 
                 // Read offset
                 for i in 0..4 {
                     if instruction & (1 << i) != 0 {
-                        offset |= (delta[delta_pos] as u32) << (i * 8);
+                        let byte = *delta.get(delta_pos).ok_or_else(truncated)?;
+                        offset |= (byte as usize) << (i * 8);
                         delta_pos += 1;
                     }
                 }
@@ -225,7 +493,9 @@ impl PackParser {
                 // Read size
                 for i in 0..3 {
                     if instruction & (1 << (i + 4)) != 0 {
-                        size |= (delta[delta_pos] as u32) << (i * 8);
+                        let byte = *delta.get(delta_pos).ok_or_else(truncated)?;
+                        size |= (byte as usize) << (i * 8);
+                        delta_pos += 1;
                     }
                 }
                 
@@ -234,22 +504,28 @@ impl PackParser {
                 }
                 
                 // Copy from base
-                let end_offset = (offset + size) as usize;
-                if end_offset <= base.len() {
-                    result.extend_from_slice(&base[offset as usize..end_offset]);
-                }
+                let copied = offset
+                    .checked_add(size)
+                    .and_then(|end| base.get(offset..end))
+                    .ok_or_else(|| ProtocolError::malformed("delta", "copy outside the base object"))?;
+                result.extend_from_slice(copied);
             } else if instruction != 0 {
                 // Insert instruction
                 let size = instruction as usize;
-                if delta_pos + size <= delta.len() {
-                    result.extend_from_slice(&delta[delta_pos..delta_pos + size]);
-                    delta_pos += size;
-                }
+                let inserted = delta.get(delta_pos..delta_pos + size).ok_or_else(truncated)?;
+                result.extend_from_slice(inserted);
+                delta_pos += size;
+            } else {
+                return Err(ProtocolError::malformed("delta", "reserved instruction 0").into());
+            }
+            // Copies can repeat, so stop a delta that outgrows its declared size early
+            if result.len() > result_size {
+                return Err(ProtocolError::malformed("delta", "result has the wrong size").into());
             }
         }
 
         if result.len() != result_size {
-            return Err(anyhow!("Delta application resulted in wrong size"));
+            return Err(ProtocolError::malformed("delta", "result has the wrong size").into());
         }
 
         Ok(result)
@@ -258,24 +534,18 @@ impl PackParser {
     /// Read variable-length integer from delta
     fn read_varint(&self, data: &[u8]) -> Result<(usize, usize)> {
         let mut value = 0usize;
-        let mut consumed = 0;
         let mut shift = 0;
 
-        for &byte in data {
-            consumed += 1;
-            value |= ((byte & 0x7f) as usize) << shift;
+        for (consumed, &byte) in data.iter().enumerate() {
+            value |= shifted(byte & 0x7f, shift).ok_or(ProtocolError::Overflow { what: "varint" })?;
             shift += 7;
             
             if byte & 0x80 == 0 {
-                break;
-            }
-            
-            if consumed > 8 {
-                return Err(anyhow!("Invalid varint encoding"));
+                return Ok((value, consumed + 1));
             }
         }
 
-        Ok((value, consumed))
+        Err(ProtocolError::Truncated { what: "varint" }.into())
     }
 
     fn parse_type_and_size<'a>(&self, input: &'a [u8]) -> IResult<&'a [u8], (u8, usize)> {
@@ -284,15 +554,15 @@ impl PackParser {
         let mut size = (first_byte & 0x0f) as usize;
         let mut shift = 4;
 
-        // Continue reading size bytes if MSB is set
-        while (first_byte & 0x80) != 0 {
+        // Continue reading size bytes while MSB is set
+        let mut more = (first_byte & 0x80) != 0;
+        while more {
             let (remaining, size_byte) = u8(input)?;
             input = remaining;
-            size |= ((size_byte & 0x7f) as usize) << shift;
+            size |= shifted(size_byte & 0x7f, shift)
+                .ok_or_else(|| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::TooLarge)))?;
             shift += 7;
-            if (size_byte & 0x80) == 0 {
-                break;
-            }
+            more = (size_byte & 0x80) != 0;
         }
 
         Ok((input, (obj_type, size)))
@@ -304,7 +574,7 @@ impl PackParser {
             2 => Ok(ObjectType::Tree),
             3 => Ok(ObjectType::Blob),
             4 => Ok(ObjectType::Tag),
-            _ => Err(anyhow!("Unknown object type: {}", type_id)),
+            _ => Err(ProtocolError::UnknownObjectType { type_id }.into()),
         }
     }
 
@@ -313,47 +583,13 @@ impl PackParser {
         self.parse_object_with_delta_support(input)
     }
 
-    fn read_compressed_data<'a>(&self, input: &'a [u8], _size: usize) -> IResult<&'a [u8], Vec<u8>> {
-        // Legacy method - use read_compressed_data_properly instead
-        self.read_compressed_data_properly(input)
-    }
-
     /// Create a pack file from objects with proper compression and checksum
     pub fn create_pack(&self, objects: &[GitObject]) -> Result<Vec<u8>> {
-        let mut pack_data = Vec::new();
-
-        // Write pack header
-        pack_data.extend_from_slice(b"PACK");
-        pack_data.extend_from_slice(&2u32.to_be_bytes()); // version
-        pack_data.extend_from_slice(&(objects.len() as u32).to_be_bytes());
-
-        // Write objects with proper compression
+        let mut writer = PackWriter::with_options(Vec::new(), objects.len() as u32, self.compression, self.hash)?;
         for obj in objects {
-            let type_id = match obj.obj_type {
-                ObjectType::Commit => 1u8,
-                ObjectType::Tree => 2u8,
-                ObjectType::Blob => 3u8,
-                ObjectType::Tag => 4u8,
-            };
-
-            // Write type and size using proper variable-length encoding
-            self.write_type_and_size(&mut pack_data, type_id, obj.size)?;
-
-            // Compress content with zlib
-            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(&obj.content)?;
-            let compressed = encoder.finish()?;
-            
-            pack_data.extend_from_slice(&compressed);
+            writer.write_object(obj)?;
         }
-
-        // Calculate and append SHA-1 checksum
-        let mut hasher = Sha1::new();
-        hasher.update(&pack_data);
-        let checksum = hasher.finalize();
-        pack_data.extend_from_slice(&checksum);
-
-        Ok(pack_data)
+        writer.finish()
     }
 
     /// Write type and size using Git's variable-length encoding
@@ -382,11 +618,100 @@ impl PackParser {
         Ok(())
     }
 
-    /// Create optimized pack with delta compression
-    pub fn create_pack_with_deltas(&self, objects: &[GitObject]) -> Result<Vec<u8>> {
-        // This would implement delta compression between similar objects
-        // For now, fall back to regular pack creation
-        self.create_pack(objects)
+    /// Create a pack where an object may be stored as a delta against an earlier
+    /// object of the same type, whenever that takes less than half its size.
+    /// Bases always come earlier in the pack, so the pack is never thin.
+    pub fn create_pack_with_deltas(&self, objects: &[GitObject], format: DeltaFormat) -> Result<Vec<u8>> {
+        struct Written<'a> {
+            object: &'a GitObject,
+            offset: u64,
+            depth: usize,
+        }
+
+        let mut writer = PackWriter::with_options(Vec::new(), objects.len() as u32, self.compression, self.hash)?;
+        let mut written: Vec<Written> = Vec::with_capacity(objects.len());
+
+        for obj in objects {
+            // git's cut-off: a delta must save at least half the object, less a hash
+            let max_size = (obj.content.len() / 2).saturating_sub(self.hash.digest_len());
+            let best = written
+                .iter()
+                .rev()
+                .filter(|base| base.object.obj_type == obj.obj_type && base.depth < MAX_DELTA_DEPTH)
+                .take(DELTA_WINDOW)
+                .filter_map(|base| {
+                    self.create_delta(&base.object.content, &obj.content, max_size)
+                        .map(|delta| (base, delta))
+                })
+                .min_by_key(|(_, delta)| delta.len());
+
+            let offset = writer.offset();
+            let depth = match best {
+                Some((base, delta)) => {
+                    match format {
+                        DeltaFormat::Offset => writer.write_ofs_delta(offset - base.offset, &delta)?,
+                        DeltaFormat::Ref => writer.write_ref_delta(&base.object.id, &delta)?,
+                    }
+                    base.depth + 1
+                }
+                None => {
+                    writer.write_object(obj)?;
+                    0
+                }
+            };
+            written.push(Written { object: obj, offset, depth });
+        }
+
+        writer.finish()
+    }
+
+    /// Delta instructions rebuilding `target` from `base`, or None if they would
+    /// take more than `max_size` bytes. Matches are found through an index of the
+    /// base's aligned blocks, then extended both ways.
+    fn create_delta(&self, base: &[u8], target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        if base.len() < DELTA_BLOCK_LEN || max_size == 0 {
+            return None;
+        }
+
+        let mut index: HashMap<&[u8], usize> = HashMap::new();
+        for start in (0..=base.len() - DELTA_BLOCK_LEN).step_by(DELTA_BLOCK_LEN) {
+            index.entry(&base[start..start + DELTA_BLOCK_LEN]).or_insert(start);
+        }
+
+        let mut delta = Vec::new();
+        write_delta_size(&mut delta, base.len());
+        write_delta_size(&mut delta, target.len());
+
+        let mut pos = 0;
+        let mut literal_start = 0;
+        while pos + DELTA_BLOCK_LEN <= target.len() {
+            let Some(&found) = index.get(&target[pos..pos + DELTA_BLOCK_LEN]) else {
+                pos += 1;
+                continue;
+            };
+
+            let mut len = DELTA_BLOCK_LEN;
+            while found + len < base.len() && pos + len < target.len() && base[found + len] == target[pos + len] {
+                len += 1;
+            }
+            // Take back bytes that would otherwise be inserted literally
+            let (mut copy_from, mut copy_to) = (found, pos);
+            while copy_from > 0 && copy_to > literal_start && base[copy_from - 1] == target[copy_to - 1] {
+                copy_from -= 1;
+                copy_to -= 1;
+            }
+
+            write_delta_insert(&mut delta, &target[literal_start..copy_to]);
+            write_delta_copy(&mut delta, copy_from, pos + len - copy_to);
+            if delta.len() > max_size {
+                return None;
+            }
+            pos += len;
+            literal_start = pos;
+        }
+        write_delta_insert(&mut delta, &target[literal_start..]);
+
+        (delta.len() <= max_size).then_some(delta)
     }
 
     /// Create thin pack (without base objects)
@@ -398,6 +723,199 @@ impl PackParser {
     }
 }
 
+/// Streaming pack writer: objects are compressed, hashed and written one at a time,
+/// so a pack never has to be held in memory
+pub struct PackWriter<W: Write> {
+    writer: W,
+    hash: HashAlgorithm,
+    hasher: Hasher,
+    remaining: u32,
+    compression: Compression,
+    offset: u64,
+}
+
+impl<W: Write> PackWriter<W> {
+    /// Write the pack header for `num_objects` objects
+    pub fn new(writer: W, num_objects: u32) -> Result<Self> {
+        Self::with_compression(writer, num_objects, Compression::default())
+    }
+
+    /// Write the pack header, compressing objects at the given level
+    pub fn with_compression(writer: W, num_objects: u32, compression: Compression) -> Result<Self> {
+        Self::with_options(writer, num_objects, compression, HashAlgorithm::default())
+    }
+
+    /// Write the pack header for a pack checksummed with `hash`
+    pub fn with_hash_algorithm(writer: W, num_objects: u32, hash: HashAlgorithm) -> Result<Self> {
+        Self::with_options(writer, num_objects, Compression::default(), hash)
+    }
+
+    /// Write the pack header, compressing objects at the given level and
+    /// checksumming the pack with `hash`
+    pub fn with_options(writer: W, num_objects: u32, compression: Compression, hash: HashAlgorithm) -> Result<Self> {
+        let mut pack_writer = Self {
+            writer,
+            hash,
+            hasher: hash.hasher(),
+            remaining: num_objects,
+            compression,
+            offset: 0,
+        };
+
+        pack_writer.write_all(b"PACK")?;
+        pack_writer.write_all(&2u32.to_be_bytes())?; // version
+        pack_writer.write_all(&num_objects.to_be_bytes())?;
+
+        Ok(pack_writer)
+    }
+
+    /// Bytes written so far, which is where the next object starts
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Append one object, zlib-compressed
+    pub fn write_object(&mut self, obj: &GitObject) -> Result<()> {
+        let type_id = match obj.obj_type {
+            ObjectType::Commit => 1u8,
+            ObjectType::Tree => 2u8,
+            ObjectType::Blob => 3u8,
+            ObjectType::Tag => 4u8,
+        };
+        self.write_entry(type_id, &[], &obj.content)
+    }
+
+    /// Append an OFS_DELTA entry whose base starts `distance` bytes before it
+    pub fn write_ofs_delta(&mut self, distance: u64, delta: &[u8]) -> Result<()> {
+        // Big-endian base-128 where every continuation adds one, the inverse of `parse_offset`
+        let mut encoded = vec![(distance & 0x7f) as u8];
+        let mut rest = distance >> 7;
+        while rest != 0 {
+            rest -= 1;
+            encoded.push(0x80 | (rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        encoded.reverse();
+        self.write_entry(6, &encoded, delta)
+    }
+
+    /// Append a REF_DELTA entry against the object `base_id`
+    pub fn write_ref_delta(&mut self, base_id: &str, delta: &[u8]) -> Result<()> {
+        let base = hex::decode(base_id)
+            .ok()
+            .filter(|base| base.len() == self.hash.digest_len())
+            .ok_or_else(|| anyhow!("Invalid delta base id: {}", base_id))?;
+        self.write_entry(7, &base, delta)
+    }
+
+    /// Type and size header, the delta base if any, then the zlib-compressed content
+    fn write_entry(&mut self, type_id: u8, base: &[u8], content: &[u8]) -> Result<()> {
+        if self.remaining == 0 {
+            return Err(anyhow!("Pack already holds all declared objects"));
+        }
+
+        // Write type and size using proper variable-length encoding
+        let mut header = Vec::new();
+        PackParser::new().write_type_and_size(&mut header, type_id, content.len())?;
+        header.extend_from_slice(base);
+        self.write_all(&header)?;
+
+        // Compress content with zlib
+        let mut encoder = ZlibEncoder::new(Vec::new(), self.compression);
+        encoder.write_all(content)?;
+        let compressed = encoder.finish()?;
+        self.write_all(&compressed)?;
+
+        self.remaining -= 1;
+        Ok(())
+    }
+
+    /// Append the checksum trailer and hand back the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        if self.remaining != 0 {
+            return Err(anyhow!("Pack is missing {} declared objects", self.remaining));
+        }
+
+        let checksum = self.hasher.finalize();
+        self.writer.write_all(&checksum)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        self.hasher.update(data);
+        self.writer.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Object size at the start of a delta: little-endian base-128
+fn write_delta_size(out: &mut Vec<u8>, mut size: usize) {
+    while size >= 0x80 {
+        out.push(0x80 | (size & 0x7f) as u8);
+        size >>= 7;
+    }
+    out.push(size as u8);
+}
+
+/// Insert instructions for `literal`, at most 127 bytes each
+fn write_delta_insert(out: &mut Vec<u8>, literal: &[u8]) {
+    for chunk in literal.chunks(MAX_DELTA_INSERT) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Copy instructions for `len` bytes of the base from `offset`. Only the non-zero
+/// bytes of offset and size are written, flagged in the opcode; a size of 0x10000
+/// is written as no size bytes at all.
+fn write_delta_copy(out: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_DELTA_COPY);
+        let opcode_at = out.len();
+        let mut opcode = 0x80u8;
+        for i in 0..4 {
+            let byte = (offset >> (i * 8)) as u8;
+            if byte != 0 {
+                opcode |= 1 << i;
+                out.push(byte);
+            }
+        }
+        for i in 0..3 {
+            let byte = ((size & 0xffff) >> (i * 8)) as u8;
+            if byte != 0 {
+                opcode |= 0x10 << i;
+                out.push(byte);
+            }
+        }
+        out.insert(opcode_at, opcode);
+        offset += size;
+        len -= size;
+    }
+}
+
+/// `bits << shift`, or None when any of the bits would be shifted out
+fn shifted(bits: u8, shift: u32) -> Option<usize> {
+    let value = (bits as usize).checked_shl(shift)?;
+    (value >> shift == bits as usize).then_some(value)
+}
+
+/// Describe a nom failure from `parse_header`/`parse_object` as a `ProtocolError`,
+/// without the remaining input that nom's own error carries
+pub(crate) fn pack_error(what: &'static str, err: nom::Err<nom::error::Error<&[u8]>>) -> ProtocolError {
+    use nom::error::ErrorKind;
+    match err {
+        nom::Err::Incomplete(_) => ProtocolError::Truncated { what },
+        nom::Err::Error(e) | nom::Err::Failure(e) => match e.code {
+            ErrorKind::Eof => ProtocolError::Truncated { what },
+            ErrorKind::TooLarge => ProtocolError::Overflow { what },
+            ErrorKind::LengthValue => ProtocolError::malformed(what, "size does not match its content"),
+            code => ProtocolError::malformed(what, code.description()),
+        },
+    }
+}
+
 impl Default for PackParser {
     fn default() -> Self {
         Self::new()
@@ -407,10 +925,11 @@ impl Default for PackParser {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sha1::{Digest, Sha1};
     
     #[test]
     fn test_pack_header_parsing() {
-        let mut parser = PackParser::new();
+        let parser = PackParser::new();
         
         // Test header parsing
         let header_data = b"PACK\x00\x00\x00\x02\x00\x00\x00\x01"; // version 2, 1 object
@@ -446,7 +965,7 @@ mod tests {
         data.clear();
         parser.write_type_and_size(&mut data, 3, 256).unwrap();
         assert!(data.len() > 1);
-        assert_eq!(data[0] & 0xf0, 0x30); // type=3
+        assert_eq!(data[0] & 0x70, 0x30); // type=3
         assert!(data[0] & 0x80 != 0); // continuation bit set
     }
     
@@ -485,17 +1004,171 @@ mod tests {
         assert!(pack_data.len() > 32); // At least header(12) + some content + checksum(20)
         assert_eq!(&pack_data[0..4], b"PACK");
         
-        // Last 20 bytes should be SHA-1 checksum
-        assert_eq!(pack_data.len() % 20, 12); // Pack should end with 20-byte checksum after 12-byte header
+        // Last 20 bytes should be SHA-1 checksum of everything before them
+        let (body, checksum) = pack_data.split_at(pack_data.len() - 20);
+        assert_eq!(checksum, Sha1::digest(body).as_slice());
+    }
+
+    #[test]
+    fn test_pack_roundtrip_multiple_objects() {
+        let parser = PackParser::new();
+        let objects = vec![
+            GitObject {
+                id: "a".to_string(),
+                obj_type: ObjectType::Blob,
+                size: 5,
+                content: b"hello".to_vec(),
+            },
+            GitObject {
+                id: "b".to_string(),
+                obj_type: ObjectType::Blob,
+                size: 5,
+                content: b"world".to_vec(),
+            },
+        ];
+
+        let pack_data = parser.create_pack(&objects).unwrap();
+        let (input, header) = parser.parse_header(&pack_data).unwrap();
+        assert_eq!(header.num_objects, 2);
+
+        let (input, first) = parser.parse_object(input).unwrap();
+        let (input, second) = parser.parse_object(input).unwrap();
+        assert_eq!(first.data, b"hello");
+        assert_eq!(second.data, b"world");
+        // Only the trailing checksum is left
+        assert_eq!(input.len(), 20);
+    }
+
+    #[test]
+    fn test_compression_level_is_honored() {
+        let content = b"compressible content ".repeat(200);
+        let objects = vec![GitObject {
+            id: "a".to_string(),
+            obj_type: ObjectType::Blob,
+            size: content.len(),
+            content,
+        }];
+
+        let stored = PackParser::new().with_compression_level(0).unwrap().create_pack(&objects).unwrap();
+        let best = PackParser::new().with_compression_level(9).unwrap().create_pack(&objects).unwrap();
+        assert!(stored.len() > best.len());
+
+        assert!(PackParser::new().with_compression_level(10).is_err());
     }
 
     #[test] 
-    fn test_sha1_reading() {
+    fn test_object_id_reading() {
         let parser = PackParser::new();
         let test_hash = hex::decode("1234567890abcdef1234567890abcdef12345678").unwrap();
         
-        let (_, hash_str) = parser.read_sha1(&test_hash).unwrap();
+        let (_, hash_str) = parser.read_object_id(&test_hash).unwrap();
         assert_eq!(hash_str, "1234567890abcdef1234567890abcdef12345678");
+
+        // SHA-256 ids take 32 bytes, so 20 are not enough
+        let parser = PackParser::new().with_hash_algorithm(HashAlgorithm::Sha256);
+        assert!(parser.read_object_id(&test_hash).is_err());
+        let long_hash = [test_hash.clone(), test_hash[..12].to_vec()].concat();
+        let (rest, hash_str) = parser.read_object_id(&long_hash).unwrap();
+        assert_eq!(hash_str.len(), 64);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_sha256_packs_carry_a_32_byte_trailer() {
+        let content: Vec<u8> = (0..200u32).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        let edited = [&content[..], b"one more line\n"].concat();
+        let objects = [&content, &edited].map(|content| {
+            let id = hex::encode(HashAlgorithm::Sha256.digest(&[format!("blob {}\0", content.len()).as_bytes(), content].concat()));
+            GitObject { id, obj_type: ObjectType::Blob, size: content.len(), content: content.clone() }
+        });
+        let parser = PackParser::new().with_hash_algorithm(HashAlgorithm::Sha256);
+
+        let pack = parser.create_pack_with_deltas(&objects, DeltaFormat::Ref).unwrap();
+        let (body, checksum) = pack.split_at(pack.len() - 32);
+        assert_eq!(checksum, HashAlgorithm::Sha256.digest(body).as_slice());
+        assert!(PackParser::new().with_hash_algorithm(HashAlgorithm::Sha256).parse_pack_file_simple(pack.clone()).is_ok());
+        // A SHA-1 reader sees a bad checksum
+        assert!(PackParser::new().parse_pack_file_simple(pack.clone()).is_err());
+
+        // The REF_DELTA base is named by its full 32-byte id
+        let (input, _) = parser.parse_header(&pack).unwrap();
+        let (input, first) = parser.parse_object(input).unwrap();
+        assert_eq!(first.data, content);
+        let (rest, (type_id, _)) = parser.parse_type_and_size(input).unwrap();
+        assert_eq!(type_id, 7);
+        let (rest, base) = parser.read_object_id(rest).unwrap();
+        assert_eq!(base, objects[0].id);
+        let (rest, compressed) = parser.read_compressed_data_properly(rest).unwrap();
+        let mut delta = Vec::new();
+        ZlibDecoder::new(&compressed[..]).read_to_end(&mut delta).unwrap();
+        assert_eq!(parser.apply_delta(&content, &delta).unwrap(), edited);
+        assert_eq!(rest.len(), 32);
+
+        // SHA-1 ids can't name a base in a SHA-256 pack
+        let mut writer = PackWriter::with_options(Vec::new(), 1, Compression::default(), HashAlgorithm::Sha256).unwrap();
+        assert!(writer.write_ref_delta(&"ab".repeat(20), b"delta").is_err());
+    }
+
+    #[test]
+    fn test_packs_resolve_their_deltas() {
+        let handler = crate::objects::ObjectHandler::new();
+        let content: Vec<u8> = (0..200u32).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        let base = handler.create_blob(&content).unwrap();
+        let edited = handler.create_blob(&[&content[..], b"one more line\n"].concat()).unwrap();
+        let parser = PackParser::new();
+
+        for format in [DeltaFormat::Offset, DeltaFormat::Ref] {
+            let pack = parser.create_pack_with_deltas(&[base.clone(), edited.clone()], format).unwrap();
+            assert_eq!(raw_entries(&pack)[1].1, if format == DeltaFormat::Offset { 6 } else { 7 });
+            let entries = PackParser::new().parse_pack_file_simple(pack).unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].data, base.content);
+            assert_eq!(entries[1].data, edited.content);
+            assert_eq!(entries[1].object_type, ObjectType::Blob);
+            assert_eq!(entries[1].size, edited.content.len());
+        }
+
+        // A REF_DELTA may come before the object it is built on
+        let delta = parser.create_delta(&base.content, &edited.content, edited.content.len()).unwrap();
+        let mut writer = PackWriter::new(Vec::new(), 2).unwrap();
+        writer.write_ref_delta(&base.id, &delta).unwrap();
+        writer.write_object(&base).unwrap();
+        let entries = parser.parse_pack(&writer.finish().unwrap()).unwrap();
+        assert_eq!(entries[0].data, edited.content);
+
+        // But not on one the pack doesn't have
+        let mut writer = PackWriter::new(Vec::new(), 1).unwrap();
+        writer.write_ref_delta(&base.id, &delta).unwrap();
+        let err = parser.parse_pack(&writer.finish().unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), format!("invalid pack: delta base {} is not in the pack", base.id));
+    }
+
+    #[test]
+    fn test_packs_with_duplicate_objects_are_refused() {
+        let blob = crate::objects::ObjectHandler::new().create_blob(b"twice\n").unwrap();
+        let pack = PackParser::new().create_pack(&[blob.clone(), blob.clone()]).unwrap();
+        let expected = format!("invalid pack: object {} appears more than once", blob.id);
+        assert_eq!(PackParser::new().parse_pack(&pack).unwrap_err().to_string(), expected);
+        assert_eq!(PackParser::new().parse_pack_file_simple(pack).unwrap_err().to_string(), expected);
+
+        // A delta rebuilding an object the pack already holds is a duplicate too
+        let mut writer = PackWriter::new(Vec::new(), 2).unwrap();
+        writer.write_object(&blob).unwrap();
+        // Base and result are 6 bytes; one instruction copies all of the base
+        writer.write_ref_delta(&blob.id, &[6, 6, 0x90, 6]).unwrap();
+        let err = PackParser::new().parse_pack(&writer.finish().unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+    }
+
+    #[test]
+    fn test_self_referential_delta_is_refused() {
+        // An OFS_DELTA 0 bytes back names itself as its base
+        let mut writer = PackWriter::new(Vec::new(), 1).unwrap();
+        writer.write_ofs_delta(0, &[0, 0]).unwrap();
+        let pack = writer.finish().unwrap();
+        let err = PackParser::new().parse_pack(&pack).unwrap_err();
+        assert_eq!(err.to_string(), "invalid pack: delta cycle through the object at offset 12");
+        assert!(PackParser::new().parse_pack_file_simple(pack).is_err());
     }
 
     #[test]
@@ -512,4 +1185,116 @@ mod tests {
         let (_, offset) = parser.parse_offset(&data).unwrap();
         assert!(offset > 127);
     }
-}
\ No newline at end of file
+
+    /// (offset, type id, OFS distance or REF base id, inflated data) of each entry
+    fn raw_entries(pack: &[u8]) -> Vec<(u64, u8, Option<String>, Vec<u8>)> {
+        let parser = PackParser::new();
+        let (mut input, header) = parser.parse_header(pack).unwrap();
+        let mut entries = Vec::new();
+        for _ in 0..header.num_objects {
+            let offset = (pack.len() - input.len()) as u64;
+            let (rest, (type_id, _)) = parser.parse_type_and_size(input).unwrap();
+            let (rest, base) = match type_id {
+                6 => parser.parse_offset(rest).map(|(rest, distance)| (rest, Some(distance.to_string()))).unwrap(),
+                7 => parser.read_object_id(rest).map(|(rest, id)| (rest, Some(id))).unwrap(),
+                _ => (rest, None),
+            };
+            let (rest, compressed) = parser.read_compressed_data_properly(rest).unwrap();
+            let mut data = Vec::new();
+            ZlibDecoder::new(&compressed[..]).read_to_end(&mut data).unwrap();
+            entries.push((offset, type_id, base, data));
+            input = rest;
+        }
+        entries
+    }
+
+    #[test]
+    fn test_deltas_rebuild_their_target() {
+        let parser = PackParser::new();
+        let base: Vec<u8> = (0..20_000u32).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+
+        let mut edited = base.clone();
+        edited.splice(100..110, b"changed".iter().copied());
+        edited.extend_from_slice(b"appended\n");
+        let moved = [&base[base.len() / 2..], &base[..base.len() / 2]].concat();
+        for target in [edited, moved, base.clone()] {
+            let delta = parser.create_delta(&base, &target, target.len()).unwrap();
+            assert!(delta.len() < target.len() / 10);
+            assert_eq!(parser.apply_delta(&base, &delta).unwrap(), target);
+        }
+
+        // Unrelated content isn't worth a delta
+        let unrelated: Vec<u8> = (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        assert!(parser.create_delta(&base, &unrelated, unrelated.len() / 2).is_none());
+    }
+
+    #[test]
+    fn test_delta_entries_follow_the_negotiated_format() {
+        let handler = crate::objects::ObjectHandler::new();
+        let content: Vec<u8> = (0..200u32).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        let base = handler.create_blob(&content).unwrap();
+        let edited = handler.create_blob(&[&content[..], b"one more line\n"].concat()).unwrap();
+        let objects = [base.clone(), edited.clone()];
+        let protocol = crate::ProtocolHandler::new();
+
+        let pack = protocol
+            .create_fetch_pack(&objects, &crate::Capabilities::parse("ofs-delta side-band-64k"))
+            .unwrap();
+        let entries = raw_entries(&pack);
+        assert_eq!((entries[0].1, entries[1].1), (3, 6));
+        let distance: u64 = entries[1].2.as_deref().unwrap().parse().unwrap();
+        assert_eq!(entries[1].0 - distance, entries[0].0);
+        assert_eq!(PackParser::new().apply_delta(&entries[0].3, &entries[1].3).unwrap(), edited.content);
+
+        let pack = protocol
+            .create_fetch_pack(&objects, &crate::Capabilities::parse("side-band-64k"))
+            .unwrap();
+        let entries = raw_entries(&pack);
+        assert_eq!((entries[0].1, entries[1].1), (3, 7));
+        assert_eq!(entries[1].2.as_deref(), Some(base.id.as_str()));
+        assert_eq!(PackParser::new().apply_delta(&entries[0].3, &entries[1].3).unwrap(), edited.content);
+    }
+
+    #[test]
+    fn test_objects_over_the_size_limit_are_refused_by_id() {
+        let handler = crate::objects::ObjectHandler::new();
+        let small = handler.create_blob(b"small\n").unwrap();
+        let big = handler.create_blob(&[b'x'; 4096]).unwrap();
+        let pack = PackParser::new().create_pack(&[small.clone(), big.clone()]).unwrap();
+        let parser = PackParser::new().with_max_object_size(Some(1024));
+
+        let err = parser.parse_pack(&pack).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::ObjectTooLarge { object, size: 4096, max: 1024 }) if *object == big.id
+        ));
+        assert_eq!(parser.parse_pack(&PackParser::new().create_pack(&[small]).unwrap()).unwrap().len(), 1);
+
+        // A delta is held to the size of the object it builds, not its own
+        let edited = handler.create_blob(&[&[b'x'; 4096][..], b"!"].concat()).unwrap();
+        let pack = PackParser::new().create_pack_with_deltas(&[big, edited], DeltaFormat::Ref).unwrap();
+        let parser = PackParser::new().with_max_object_size(Some(4096));
+        let err = parser.parse_pack(&pack).unwrap_err();
+        assert!(err.to_string().starts_with("object at offset "), "{}", err);
+        assert!(err.to_string().ends_with(" is 4097 bytes, over the 4096 byte limit"), "{}", err);
+    }
+
+    #[test]
+    fn test_entries_inflating_past_their_declared_size_are_refused() {
+        // Declares 16 bytes but inflates to a megabyte
+        let mut compressed = ZlibEncoder::new(Vec::new(), Compression::best());
+        compressed.write_all(&vec![0u8; 1024 * 1024]).unwrap();
+        let compressed = compressed.finish().unwrap();
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+        PackParser::new().write_type_and_size(&mut pack, 3, 16).unwrap();
+        pack.extend_from_slice(&compressed);
+
+        for parser in [PackParser::new(), PackParser::new().with_max_object_size(Some(1024))] {
+            let err = parser.parse_pack(&pack).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<ProtocolError>(),
+                Some(ProtocolError::ObjectSizeMismatch { offset: 12, declared: 16 })
+            ));
+        }
+    }
+}
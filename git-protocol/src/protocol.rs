@@ -1,50 +1,269 @@
-use crate::{GitObject, GitProtocol, PackEntry};
-use anyhow::{anyhow, Result};
+use crate::hash::HashAlgorithm;
+use crate::objects::ObjectHandler;
+use crate::pack::DeltaFormat;
+use crate::{GitObject, GitProtocol, PackEntry, ProtocolError};
+use anyhow::Result;
 use std::str;
 
+/// All-zero SHA-1 id used for pseudo-refs and ref creation/deletion; see
+/// `HashAlgorithm::zero_id` for other formats
+pub const ZERO_ID: &str = "0000000000000000000000000000000000000000";
+
+/// A single `<old-id> <new-id> <ref-name>` command sent to receive-pack
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefCommand {
+    pub old_id: String,
+    pub new_id: String,
+    pub ref_name: String,
+}
+
+impl RefCommand {
+    pub fn is_create(&self) -> bool {
+        is_zero_id(&self.old_id)
+    }
+
+    pub fn is_delete(&self) -> bool {
+        is_zero_id(&self.new_id)
+    }
+}
+
+/// Whether `id` is the zero id of either object format
+fn is_zero_id(id: &str) -> bool {
+    id.bytes().all(|b| b == b'0')
+}
+
+/// Capability list sent by a client on its first command or want line
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Capabilities(Vec<String>);
+
+impl Capabilities {
+    /// Parse a space-separated capability list
+    pub fn parse(caps: &str) -> Self {
+        Self(caps.split_whitespace().map(|s| s.to_string()).collect())
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.0.iter().any(|c| c == name || c.split_once('=').map(|(k, _)| k) == Some(name))
+    }
+
+    /// Value of a `name=value` capability
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .filter_map(|c| c.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+
+    /// Every value given for a repeatable `name=value` capability
+    pub fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter_map(|c| c.split_once('='))
+            .filter(move |(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+
+    /// Client software and version from `agent=<name>/<version>`, e.g. `git/2.43.0`
+    pub fn agent(&self) -> Option<&str> {
+        self.value("agent")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|c| c.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Parsed receive-pack request body: command list, capabilities and pack data
+#[derive(Debug, Clone)]
+pub struct ReceivePackRequest {
+    pub commands: Vec<RefCommand>,
+    pub capabilities: Capabilities,
+    pub pack: Vec<u8>,
+}
+
+impl ReceivePackRequest {
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.has(name)
+    }
+
+    /// Symbolic ref changes requested via `symref=<name>:<target>` capabilities
+    pub fn symref_updates(&self) -> Vec<(String, String)> {
+        self.capabilities
+            .values("symref")
+            .filter_map(|s| s.split_once(':'))
+            .map(|(name, target)| (name.to_string(), target.to_string()))
+            .collect()
+    }
+
+    /// Whether responses must be multiplexed with side-band
+    pub fn uses_sideband(&self) -> bool {
+        self.has_capability("side-band-64k") || self.has_capability("side-band")
+    }
+}
+
+/// An upload-pack request's negotiation: the wants, then haves sent in rounds
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Negotiation {
+    pub wants: Vec<String>,
+    /// `have` ids of each round, in order; a flush ends a round, as does `done`
+    pub rounds: Vec<Vec<String>>,
+    /// Whether the client sent `done`, asking for the pack rather than more ACKs
+    pub done: bool,
+    /// Commits the client's history already stops at, from `shallow <id>` lines
+    pub shallow: Vec<String>,
+    /// Commits to send along each line of history from the wants, from `deepen <n>`
+    pub depth: Option<u32>,
+}
+
+impl Negotiation {
+    /// Every `have` id across rounds
+    pub fn haves(&self) -> Vec<String> {
+        self.rounds.concat()
+    }
+}
+
 /// Git protocol handler implementing the Git wire protocol
 #[derive(Clone)]
-pub struct ProtocolHandler;
+pub struct ProtocolHandler {
+    /// zlib level for packs we create, `None` for flate2's default
+    pack_compression_level: Option<u32>,
+    /// Largest object a received pack may hold, `None` for no limit
+    max_object_size: Option<usize>,
+    /// Object format of the repository being served
+    hash: HashAlgorithm,
+}
 
 impl ProtocolHandler {
     pub fn new() -> Self {
-        Self
+        Self {
+            pack_compression_level: None,
+            max_object_size: None,
+            hash: HashAlgorithm::default(),
+        }
+    }
+
+    /// Serve a repository whose objects are named with `hash`: pack checksums,
+    /// delta bases, command ids and the advertised zero id all follow it
+    pub fn with_hash_algorithm(mut self, hash: HashAlgorithm) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    /// Object handler naming objects the way this repository does
+    pub fn object_handler(&self) -> ObjectHandler {
+        ObjectHandler::new().with_hash_algorithm(self.hash)
+    }
+
+    /// Compress created packs at `level` (0-9)
+    pub fn with_pack_compression_level(mut self, level: u32) -> Self {
+        self.pack_compression_level = Some(level);
+        self
+    }
+
+    /// Refuse received packs holding an object bigger than `max` bytes
+    pub fn with_max_object_size(mut self, max: Option<usize>) -> Self {
+        self.max_object_size = max;
+        self
+    }
+
+    /// Pack answering a fetch, deltified as OFS_DELTA entries when the client
+    /// negotiated `ofs-delta` and as REF_DELTA entries for older clients
+    pub fn create_fetch_pack(&self, objects: &[GitObject], capabilities: &Capabilities) -> Result<Vec<u8>> {
+        let format = if capabilities.has("ofs-delta") {
+            DeltaFormat::Offset
+        } else {
+            DeltaFormat::Ref
+        };
+        self.pack_parser()?.create_pack_with_deltas(objects, format)
+    }
+
+    fn pack_parser(&self) -> Result<crate::pack::PackParser> {
+        let parser = crate::pack::PackParser::new().with_hash_algorithm(self.hash);
+        match self.pack_compression_level {
+            Some(level) => parser.with_compression_level(level),
+            None => Ok(parser),
+        }
     }
 
     /// Parse capabilities from the first pkt-line
-    pub fn parse_capabilities(&self, line: &str) -> (String, Vec<String>) {
+    pub fn parse_capabilities(&self, line: &str) -> (String, Capabilities) {
         if let Some(null_pos) = line.find('\0') {
             let (ref_part, caps_part) = line.split_at(null_pos);
-            let capabilities: Vec<String> = caps_part[1..]
-                .split_whitespace()
-                .map(|s| s.to_string())
-                .collect();
-            (ref_part.to_string(), capabilities)
+            (ref_part.to_string(), Capabilities::parse(&caps_part[1..]))
         } else {
-            (line.to_string(), Vec::new())
+            (line.to_string(), Capabilities::default())
         }
     }
 
-    /// Create a reference advertisement
+    /// Capabilities an upload-pack client appends to its first `want` line
+    pub fn parse_want_capabilities(&self, pkt_lines: &[String]) -> Capabilities {
+        pkt_lines
+            .iter()
+            .find_map(|line| line.trim().strip_prefix("want "))
+            .and_then(|rest| rest.split_once(' '))
+            .map(|(_, caps)| Capabilities::parse(caps))
+            .unwrap_or_default()
+    }
+
+    /// The `# service=<name>` pkt-line and flush that open a smart-HTTP
+    /// `info/refs` response, ahead of the ref advertisement
+    pub fn create_service_announcement(&self, service: &str) -> Vec<u8> {
+        let mut result = Vec::new();
+        write_pkt_line(&mut result, &["# service=", service], &[]);
+        result.extend_from_slice(b"0000");
+        result
+    }
+
+    /// Create a reference advertisement.
+    ///
+    /// Written into one pre-sized buffer so repositories with tens of thousands
+    /// of refs don't pay for a string per line.
     pub fn create_ref_advertisement(&self, refs: &[(String, String)], capabilities: &[&str]) -> Vec<u8> {
-        let mut lines = Vec::new();
-        
+        let caps_len: usize = capabilities.iter().map(|c| c.len() + 1).sum();
+        let refs_len: usize = refs.iter().map(|(name, hash)| name.len() + hash.len() + 6).sum();
+        let mut result = Vec::with_capacity(refs_len + caps_len + 64);
+
         if refs.is_empty() {
-            // Send null ref with capabilities if no refs exist
-            let caps_str = capabilities.join(" ");
-            lines.push(format!("0000000000000000000000000000000000000000 capabilities^{}\0{}", "{}", caps_str));
+            // Send the capabilities^{} pseudo-ref with a zero id if no refs exist
+            write_pkt_line(&mut result, &[&self.hash.zero_id(), " capabilities^{}\0"], capabilities);
         } else {
             // Send first ref with capabilities
-            let caps_str = capabilities.join(" ");
-            lines.push(format!("{} {}\0{}", refs[0].1, refs[0].0, caps_str));
-            
+            let (ref_name, ref_hash) = &refs[0];
+            write_pkt_line(&mut result, &[ref_hash, " ", ref_name, "\0"], capabilities);
+
             // Send remaining refs
             for (ref_name, ref_hash) in refs.iter().skip(1) {
-                lines.push(format!("{} {}", ref_hash, ref_name));
+                write_pkt_line(&mut result, &[ref_hash, " ", ref_name], &[]);
             }
         }
 
-        self.create_pkt_line(&lines.iter().map(|s| s.as_str()).collect::<Vec<_>>())
+        result.extend_from_slice(b"0000");
+        result
+    }
+
+    /// Every pkt-line of a request made of several flush-terminated sections, such as
+    /// an upload-pack request's wants followed by its haves
+    pub fn parse_pkt_sections(&self, data: &[u8]) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let (section, consumed) = self.read_pkt_lines(&data[pos..])?;
+            if consumed == 0 {
+                break;
+            }
+            lines.extend(section);
+            pos += consumed;
+        }
+        Ok(lines)
     }
 
     /// Parse want/have lines from upload-pack request
@@ -70,44 +289,103 @@ impl ProtocolHandler {
         Ok((wants, haves))
     }
 
-    /// Create NAK response
-    pub fn create_nak(&self) -> Vec<u8> {
-        self.create_pkt_line(&["NAK"])
-    }
+    /// Parse an upload-pack request into its negotiation. The first flush-terminated
+    /// section carries the wants; each later one is a round of haves. Lines after
+    /// `done` are ignored.
+    pub fn parse_negotiation(&self, data: &[u8]) -> Result<Negotiation> {
+        let mut negotiation = Negotiation::default();
+        let mut pos = 0;
+        while pos < data.len() && !negotiation.done {
+            let (section, consumed) = self.read_pkt_lines(&data[pos..])?;
+            if consumed == 0 {
+                break;
+            }
+            pos += consumed;
 
-    /// Create ACK response
-    pub fn create_ack(&self, hash: &str) -> Vec<u8> {
-        self.create_pkt_line(&[&format!("ACK {}", hash)])
+            let mut round = Vec::new();
+            for line in &section {
+                let mut parts = line.split_whitespace();
+                match (parts.next(), parts.next()) {
+                    (Some("want"), Some(id)) => negotiation.wants.push(id.to_string()),
+                    (Some("have"), Some(id)) => round.push(id.to_string()),
+                    (Some("shallow"), Some(id)) => negotiation.shallow.push(id.to_string()),
+                    (Some("deepen"), Some(depth)) => {
+                        let depth = depth.parse().ok().filter(|depth| *depth > 0);
+                        negotiation.depth = Some(depth.ok_or_else(|| ProtocolError::malformed("deepen", line))?);
+                    }
+                    (Some("done"), None) => {
+                        negotiation.done = true;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            if !round.is_empty() {
+                negotiation.rounds.push(round);
+            }
+        }
+        Ok(negotiation)
     }
-}
 
-impl GitProtocol for ProtocolHandler {
-    fn parse_pack(&self, data: &[u8]) -> Result<Vec<PackEntry>> {
-        let parser = crate::pack::PackParser::new();
-        let (remaining, header) = parser
-            .parse_header(data)
-            .map_err(|e| anyhow!("Failed to parse pack header: {:?}", e))?;
-
-        let mut entries = Vec::new();
-        let mut current = remaining;
-
-        for _ in 0..header.num_objects {
-            let (remaining, entry) = parser
-                .parse_object(current)
-                .map_err(|e| anyhow!("Failed to parse pack object: {:?}", e))?;
-            entries.push(entry);
-            current = remaining;
+    /// Parse a receive-pack request: pkt-line commands up to the flush, followed by pack data
+    pub fn parse_receive_pack_request(&self, data: &[u8]) -> Result<ReceivePackRequest> {
+        let (lines, consumed) = self.read_pkt_lines(data)?;
+
+        let mut commands = Vec::new();
+        let mut capabilities = Capabilities::default();
+
+        for (i, line) in lines.iter().enumerate() {
+            let command_part = if i == 0 {
+                let (command_part, caps) = self.parse_capabilities(line);
+                capabilities = caps;
+                command_part
+            } else {
+                line.to_string()
+            };
+
+            let parts: Vec<&str> = command_part.split(' ').collect();
+            let id_len = self.hash.hex_len();
+            if parts.len() != 3 || parts[0].len() != id_len || parts[1].len() != id_len {
+                return Err(ProtocolError::malformed("receive-pack command", command_part).into());
+            }
+
+            commands.push(RefCommand {
+                old_id: parts[0].to_string(),
+                new_id: parts[1].to_string(),
+                ref_name: parts[2].to_string(),
+            });
         }
 
-        Ok(entries)
+        Ok(ReceivePackRequest {
+            commands,
+            capabilities,
+            pack: data[consumed..].to_vec(),
+        })
     }
 
-    fn create_pack(&self, objects: &[GitObject]) -> Result<Vec<u8>> {
-        let parser = crate::pack::PackParser::new();
-        parser.create_pack(objects)
+    /// Create a report-status response: `unpack <result>` then `ok`/`ng` per ref
+    pub fn create_report_status(
+        &self,
+        unpack_result: &std::result::Result<(), String>,
+        ref_results: &[(String, std::result::Result<(), String>)],
+    ) -> Vec<u8> {
+        let mut lines = vec![match unpack_result {
+            Ok(()) => "unpack ok".to_string(),
+            Err(e) => format!("unpack {}", e),
+        }];
+
+        for (ref_name, result) in ref_results {
+            lines.push(match result {
+                Ok(()) => format!("ok {}", ref_name),
+                Err(e) => format!("ng {} {}", ref_name, e),
+            });
+        }
+
+        self.create_pkt_line(&lines.iter().map(|s| s.as_str()).collect::<Vec<_>>())
     }
 
-    fn parse_pkt_line(&self, data: &[u8]) -> Result<Vec<String>> {
+    /// Read pkt-lines up to and including the first flush, returning the bytes consumed
+    fn read_pkt_lines(&self, data: &[u8]) -> Result<(Vec<String>, usize)> {
         let mut lines = Vec::new();
         let mut pos = 0;
 
@@ -118,9 +396,9 @@ impl GitProtocol for ProtocolHandler {
 
             // Read length prefix (4 hex digits)
             let length_str = str::from_utf8(&data[pos..pos + 4])
-                .map_err(|e| anyhow!("Invalid UTF-8 in length prefix: {}", e))?;
+                .map_err(|e| ProtocolError::malformed("pkt-line length", e.to_string()))?;
             let length = u16::from_str_radix(length_str, 16)
-                .map_err(|e| anyhow!("Invalid hex length: {}", e))?;
+                .map_err(|e| ProtocolError::malformed("pkt-line length", e.to_string()))?;
 
             if length == 0 {
                 // Flush packet
@@ -129,21 +407,121 @@ impl GitProtocol for ProtocolHandler {
             }
 
             if length < 4 {
-                return Err(anyhow!("Invalid packet length: {}", length));
+                return Err(ProtocolError::malformed("pkt-line length", length.to_string()).into());
             }
 
             let content_length = (length - 4) as usize;
             if pos + 4 + content_length > data.len() {
-                return Err(anyhow!("Packet extends beyond data"));
+                return Err(ProtocolError::Truncated { what: "pkt-line" }.into());
             }
 
             let content = str::from_utf8(&data[pos + 4..pos + 4 + content_length])
-                .map_err(|e| anyhow!("Invalid UTF-8 in packet content: {}", e))?;
+                .map_err(|e| ProtocolError::malformed("pkt-line", e.to_string()))?;
             
             lines.push(content.trim_end_matches('\n').to_string());
             pos += 4 + content_length;
         }
 
+        Ok((lines, pos))
+    }
+
+    /// Wrap data in side-band packets on `band` (1 data, 2 progress, 3 fatal error),
+    /// followed by a flush
+    pub fn create_sideband(&self, band: u8, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::new();
+        self.write_sideband(&mut result, band, data);
+        result.extend_from_slice(b"0000");
+        result
+    }
+
+    /// Append side-band packets on `band` without a flush, so several bands can
+    /// share one response
+    pub fn write_sideband(&self, out: &mut Vec<u8>, band: u8, data: &[u8]) {
+        // side-band-64k allows 65520-byte packets: 4 length bytes, 1 band byte, payload
+        const MAX_PAYLOAD: usize = 65515;
+
+        for chunk in data.chunks(MAX_PAYLOAD) {
+            out.extend_from_slice(format!("{:04x}", chunk.len() + 5).as_bytes());
+            out.push(band);
+            out.extend_from_slice(chunk);
+        }
+    }
+
+    /// Fatal error for a side-band client, shown to the user as `remote: <message>`
+    pub fn create_sideband_error(&self, message: &str) -> Vec<u8> {
+        self.create_sideband(3, format!("{}\n", message).as_bytes())
+    }
+
+    /// `ERR <message>` pkt-line, which aborts the client with the message
+    pub fn create_error(&self, message: &str) -> Vec<u8> {
+        self.create_pkt_line(&[&format!("ERR {}", message)])
+    }
+
+    /// The `shallow`/`unshallow` lines answering a `deepen`, ended by a flush
+    pub fn create_shallow_info(&self, shallow: &[String], unshallow: &[String]) -> Vec<u8> {
+        let lines: Vec<String> = shallow
+            .iter()
+            .map(|id| format!("shallow {}", id))
+            .chain(unshallow.iter().map(|id| format!("unshallow {}", id)))
+            .collect();
+        self.create_pkt_line(&lines.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    /// Create NAK response. No flush follows: the pack comes straight after it.
+    pub fn create_nak(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        write_pkt_line(&mut result, &["NAK"], &[]);
+        result
+    }
+
+    /// Create ACK response
+    pub fn create_ack(&self, hash: &str) -> Vec<u8> {
+        let mut result = Vec::new();
+        write_pkt_line(&mut result, &["ACK ", hash], &[]);
+        result
+    }
+
+    /// Create a multi_ack `ACK <hash> continue` line for a common object found mid-negotiation
+    pub fn create_ack_continue(&self, hash: &str) -> Vec<u8> {
+        let mut result = Vec::new();
+        write_pkt_line(&mut result, &["ACK ", hash, " continue"], &[]);
+        result
+    }
+}
+
+/// Append one newline-terminated pkt-line made of `parts` then space-separated `words`
+fn write_pkt_line(buf: &mut Vec<u8>, parts: &[&str], words: &[&str]) {
+    let parts_len: usize = parts.iter().map(|p| p.len()).sum();
+    let words_len: usize = words.iter().map(|w| w.len()).sum::<usize>() + words.len().saturating_sub(1);
+
+    buf.extend_from_slice(format!("{:04x}", parts_len + words_len + 5).as_bytes());
+    for part in parts {
+        buf.extend_from_slice(part.as_bytes());
+    }
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            buf.push(b' ');
+        }
+        buf.extend_from_slice(word.as_bytes());
+    }
+    buf.push(b'\n');
+}
+
+impl GitProtocol for ProtocolHandler {
+    fn parse_pack(&self, data: &[u8]) -> Result<Vec<PackEntry>> {
+        crate::pack::PackParser::new()
+            .with_hash_algorithm(self.hash)
+            .with_max_object_size(self.max_object_size)
+            .parse_pack(data)
+    }
+
+    fn create_pack(&self, objects: &[GitObject]) -> Result<Vec<u8>> {
+        let parser = self.pack_parser()?;
+        parser.create_pack(objects)
+    }
+
+    fn parse_pkt_line(&self, data: &[u8]) -> Result<Vec<String>> {
+        let (lines, _) = self.read_pkt_lines(data)?;
         Ok(lines)
     }
 
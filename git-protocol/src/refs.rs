@@ -1,7 +1,12 @@
+use crate::error::ProtocolError;
+use crate::hash::is_object_id;
 use crate::GitRef;
 use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 
+/// Header of packed-refs files written by `write_packed_refs`
+pub const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled sorted";
+
 /// Git reference handler
 pub struct RefHandler {
     refs: HashMap<String, GitRef>,
@@ -147,8 +152,8 @@ impl RefHandler {
         // Try to resolve HEAD
         if let Ok(_target) = self.resolve_ref("HEAD") {
             if let Some(git_ref) = self.get_ref("HEAD") {
-                if git_ref.is_symbolic && git_ref.target.starts_with("refs/heads/") {
-                    return Some(git_ref.target[11..].to_string()); // Remove "refs/heads/"
+                if let Some(branch) = git_ref.target.strip_prefix("refs/heads/").filter(|_| git_ref.is_symbolic) {
+                    return Some(branch.to_string());
                 }
             }
         }
@@ -181,6 +186,52 @@ impl RefHandler {
     }
 }
 
+/// Serialize refs in Git's packed-refs format, one `<id> <name>` line per ref,
+/// sorted by name. Packed-refs has no place for symbolic refs, so they follow as
+/// `ref: <target> <name>` lines, the way a loose symbolic ref file reads.
+pub fn write_packed_refs<'a>(refs: impl IntoIterator<Item = &'a GitRef>) -> String {
+    let mut refs: Vec<&GitRef> = refs.into_iter().collect();
+    refs.sort_by(|a, b| (a.is_symbolic, &a.name).cmp(&(b.is_symbolic, &b.name)));
+
+    let mut out = format!("{}\n", PACKED_REFS_HEADER);
+    for git_ref in refs {
+        if git_ref.is_symbolic {
+            out.push_str(&format!("ref: {} {}\n", git_ref.target, git_ref.name));
+        } else {
+            out.push_str(&format!("{} {}\n", git_ref.target, git_ref.name));
+        }
+    }
+    out
+}
+
+/// Parse what `write_packed_refs` wrote. Comment lines and `^<id>` peeled lines
+/// are skipped, so files written by Git itself read too.
+pub fn parse_packed_refs(data: &str) -> Result<Vec<GitRef>> {
+    let mut refs = Vec::new();
+    for line in data.lines() {
+        if line.is_empty() || line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        let (is_symbolic, rest) = match line.strip_prefix("ref: ") {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (target, name) = rest
+            .split_once(' ')
+            .filter(|(target, name)| !target.is_empty() && !name.is_empty())
+            .ok_or_else(|| ProtocolError::malformed("packed-refs line", line))?;
+        if !is_symbolic && !is_object_id(target) {
+            return Err(ProtocolError::malformed("packed-refs line", line).into());
+        }
+        refs.push(GitRef {
+            name: name.to_string(),
+            target: target.to_string(),
+            is_symbolic,
+        });
+    }
+    Ok(refs)
+}
+
 impl Default for RefHandler {
     fn default() -> Self {
         Self::new()
@@ -223,4 +274,37 @@ mod tests {
         let resolved = ref_handler.resolve_ref("HEAD").unwrap();
         assert_eq!(resolved, hash);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_packed_refs_round_trip() {
+        let main = "a".repeat(40);
+        let tag = "b".repeat(40);
+        let refs = vec![
+            GitRef { name: "refs/tags/v1".to_string(), target: tag.clone(), is_symbolic: false },
+            GitRef { name: "HEAD".to_string(), target: "refs/heads/main".to_string(), is_symbolic: true },
+            GitRef { name: "refs/heads/main".to_string(), target: main.clone(), is_symbolic: false },
+        ];
+
+        let packed = write_packed_refs(&refs);
+        assert_eq!(
+            packed,
+            format!("{}\n{} refs/heads/main\n{} refs/tags/v1\nref: refs/heads/main HEAD\n", PACKED_REFS_HEADER, main, tag)
+        );
+
+        let parsed = parse_packed_refs(&packed).unwrap();
+        let names: Vec<_> = parsed.iter().map(|r| (r.name.as_str(), r.target.as_str(), r.is_symbolic)).collect();
+        assert_eq!(
+            names,
+            vec![
+                ("refs/heads/main", main.as_str(), false),
+                ("refs/tags/v1", tag.as_str(), false),
+                ("HEAD", "refs/heads/main", true),
+            ]
+        );
+
+        // Peeled lines from Git's own files are skipped; broken lines are refused
+        assert_eq!(parse_packed_refs(&format!("{} refs/tags/v1\n^{}\n", tag, main)).unwrap().len(), 1);
+        assert!(parse_packed_refs("not-an-id refs/heads/main\n").is_err());
+        assert!(parse_packed_refs(&format!("{}\n", main)).is_err());
+    }
+}
@@ -1,36 +1,311 @@
-#[cfg(test)]
-mod tests {
-    use crate::{GitProtocol, ProtocolHandler};
+use crate::{GitProtocol, HashAlgorithm, ProtocolHandler, ZERO_ID};
+
+#[test]
+fn test_protocol_handler() {
+    let protocol = ProtocolHandler::new();
+    
+    // Test pkt-line parsing
+    let pkt_data = b"0006a\n0000";
+    let lines = protocol.parse_pkt_line(pkt_data).unwrap();
+    assert_eq!(lines, vec!["a"]);
+    
+    // Test pkt-line creation
+    let lines = vec!["hello"];
+    let pkt_data = protocol.create_pkt_line(&lines);
+    assert!(pkt_data.starts_with(b"000a"));
+    assert!(pkt_data.ends_with(b"0000"));
+}
+
+#[test]
+fn test_ref_advertisement() {
+    let protocol = ProtocolHandler::new();
     
-    #[test]
-    fn test_protocol_handler() {
-        let protocol = ProtocolHandler::new();
-        
-        // Test pkt-line parsing
-        let pkt_data = b"0006a\n0000";
-        let lines = protocol.parse_pkt_line(pkt_data).unwrap();
-        assert_eq!(lines, vec!["a"]);
-        
-        // Test pkt-line creation
-        let lines = vec!["hello"];
-        let pkt_data = protocol.create_pkt_line(&lines);
-        assert!(pkt_data.starts_with(b"000a"));
-        assert!(pkt_data.ends_with(b"0000"));
-    }
-
-    #[test]
-    fn test_ref_advertisement() {
-        let protocol = ProtocolHandler::new();
-        
-        let refs = vec![
-            ("refs/heads/main".to_string(), "1234567890abcdef".repeat(2).chars().take(40).collect()),
-            ("refs/heads/develop".to_string(), "abcdef1234567890".repeat(2).chars().take(40).collect()),
-        ];
-        
-        let capabilities = vec!["multi_ack", "side-band-64k"];
-        let advertisement = protocol.create_ref_advertisement(&refs, &capabilities);
-        
-        // Should contain the refs and capabilities
-        assert!(!advertisement.is_empty());
-    }
-}
\ No newline at end of file
+    let refs = vec![
+        ("refs/heads/main".to_string(), "1234567890abcdef".repeat(2).chars().take(40).collect()),
+        ("refs/heads/develop".to_string(), "abcdef1234567890".repeat(2).chars().take(40).collect()),
+    ];
+    
+    let capabilities = vec!["multi_ack", "side-band-64k"];
+    let advertisement = protocol.create_ref_advertisement(&refs, &capabilities);
+    
+    // Should contain the refs and capabilities
+    assert!(!advertisement.is_empty());
+}
+
+#[test]
+fn test_large_ref_advertisement() {
+    let protocol = ProtocolHandler::new();
+    let refs: Vec<(String, String)> = (0..5000)
+        .map(|i| (format!("refs/heads/branch-{:05}", i), format!("{:040x}", i)))
+        .collect();
+    let capabilities = ["multi_ack", "side-band-64k", "ofs-delta"];
+
+    let started = std::time::Instant::now();
+    let advertisement = protocol.create_ref_advertisement(&refs, &capabilities);
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+
+    // Each line is a 4-byte length, 40-byte id, space, 23-byte ref name and newline
+    let caps = "\0multi_ack side-band-64k ofs-delta";
+    assert_eq!(advertisement.len(), 5000 * (4 + 40 + 1 + 23 + 1) + caps.len() + 4);
+
+    let lines = protocol.parse_pkt_line(&advertisement).unwrap();
+    assert_eq!(lines.len(), 5000);
+    assert_eq!(lines[0], format!("{:040x} refs/heads/branch-00000{}", 0, caps));
+    assert_eq!(lines[4999], format!("{:040x} refs/heads/branch-04999", 4999));
+}
+
+#[test]
+fn test_empty_ref_advertisement() {
+    let protocol = ProtocolHandler::new();
+
+    let advertisement = protocol.create_ref_advertisement(&[], &["report-status", "delete-refs"]);
+
+    let expected_line = b"0000000000000000000000000000000000000000 capabilities^{}\0report-status delete-refs\n";
+    let mut expected = format!("{:04x}", expected_line.len() + 4).into_bytes();
+    expected.extend_from_slice(expected_line);
+    expected.extend_from_slice(b"0000");
+    assert_eq!(advertisement, expected);
+}
+
+#[test]
+fn test_parse_receive_pack_request() {
+    let protocol = ProtocolHandler::new();
+    let new_id = "1111111111111111111111111111111111111111";
+
+    let first = format!("{} {} refs/heads/main\0report-status symref=HEAD:refs/heads/main agent=git/2.43.0", ZERO_ID, new_id);
+    let second = format!("{} {} HEAD", new_id, ZERO_ID);
+    let mut body = protocol.create_pkt_line(&[&first, &second]);
+    body.extend_from_slice(b"PACK");
+
+    let request = protocol.parse_receive_pack_request(&body).unwrap();
+    assert_eq!(request.commands.len(), 2);
+    assert!(request.commands[0].is_create());
+    assert_eq!(request.commands[0].ref_name, "refs/heads/main");
+    assert!(request.commands[1].is_delete());
+    assert_eq!(request.commands[1].ref_name, "HEAD");
+    assert!(request.has_capability("report-status"));
+    assert_eq!(
+        request.symref_updates(),
+        vec![("HEAD".to_string(), "refs/heads/main".to_string())]
+    );
+    assert_eq!(request.capabilities.agent(), Some("git/2.43.0"));
+    assert_eq!(request.pack, b"PACK");
+}
+
+#[test]
+fn test_sha256_commands_and_advertisement() {
+    let protocol = ProtocolHandler::new().with_hash_algorithm(HashAlgorithm::Sha256);
+    let zero = HashAlgorithm::Sha256.zero_id();
+    let new_id = "1".repeat(64);
+
+    let line = format!("{} {} refs/heads/main\0report-status", zero, new_id);
+    let request = protocol.parse_receive_pack_request(&protocol.create_pkt_line(&[&line])).unwrap();
+    assert!(request.commands[0].is_create());
+    assert_eq!(request.commands[0].new_id, new_id);
+
+    // SHA-1 ids don't name anything in a SHA-256 repository
+    let line = format!("{} {} refs/heads/main\0report-status", ZERO_ID, "1".repeat(40));
+    assert!(protocol.parse_receive_pack_request(&protocol.create_pkt_line(&[&line])).is_err());
+
+    let advertisement = protocol.create_ref_advertisement(&[], &["object-format=sha256"]);
+    assert!(advertisement[4..].starts_with(format!("{} capabilities^{{}}\0", zero).as_bytes()));
+}
+
+#[test]
+fn test_parse_want_capabilities() {
+    let protocol = ProtocolHandler::new();
+    let lines = vec![
+        "want 1111111111111111111111111111111111111111 multi_ack side-band-64k agent=git/2.39.2.windows.1".to_string(),
+        "want 2222222222222222222222222222222222222222".to_string(),
+        "done".to_string(),
+    ];
+
+    let capabilities = protocol.parse_want_capabilities(&lines);
+    assert!(capabilities.has("side-band-64k"));
+    assert_eq!(capabilities.agent(), Some("git/2.39.2.windows.1"));
+
+    // A sole want without capabilities leaves the list empty
+    assert!(protocol.parse_want_capabilities(&lines[1..]).is_empty());
+}
+
+#[test]
+fn test_parse_negotiation_rounds_and_done() {
+    let protocol = ProtocolHandler::new();
+    let mut request = protocol.create_pkt_line(&[
+        "want 1111111111111111111111111111111111111111 multi_ack_detailed side-band-64k",
+        "want 2222222222222222222222222222222222222222",
+        "deepen 1",
+    ]);
+    request.extend(protocol.create_pkt_line(&[
+        "have 3333333333333333333333333333333333333333",
+        "have 4444444444444444444444444444444444444444",
+    ]));
+    request.extend(protocol.create_pkt_line(&["have 5555555555555555555555555555555555555555"]));
+    // The final round is ended by `done` rather than a flush
+    let mut last = protocol.create_pkt_line(&["have 6666666666666666666666666666666666666666", "done"]);
+    last.truncate(last.len() - 4);
+    let last_round_len = last.len();
+    request.extend(last);
+
+    let negotiation = protocol.parse_negotiation(&request).unwrap();
+    assert_eq!(negotiation.wants, vec!["1".repeat(40), "2".repeat(40)]);
+    assert_eq!(
+        negotiation.rounds,
+        vec![vec!["3".repeat(40), "4".repeat(40)], vec!["5".repeat(40)], vec!["6".repeat(40)]]
+    );
+    assert_eq!(negotiation.haves().len(), 4);
+    assert!(negotiation.done);
+    assert_eq!(negotiation.depth, Some(1));
+
+    // Without `done` the client still expects ACKs for its last round
+    let unfinished = protocol.parse_negotiation(&request[..request.len() - last_round_len]).unwrap();
+    assert_eq!(unfinished.rounds.len(), 2);
+    assert!(!unfinished.done);
+
+    // A clone has no haves: wants, a flush and `done`
+    let mut clone = protocol.create_pkt_line(&["want 1111111111111111111111111111111111111111"]);
+    clone.extend_from_slice(b"0009done\n");
+    let negotiation = protocol.parse_negotiation(&clone).unwrap();
+    assert!(negotiation.rounds.is_empty());
+    assert!(negotiation.done);
+}
+
+#[test]
+fn test_parse_shallow_requests_and_filters() {
+    use crate::ObjectFilter;
+
+    let protocol = ProtocolHandler::new();
+    let mut request = protocol.create_pkt_line(&[
+        "want 1111111111111111111111111111111111111111 shallow",
+        "shallow 2222222222222222222222222222222222222222",
+        "deepen 3",
+    ]);
+    request.extend_from_slice(b"0009done\n");
+    let negotiation = protocol.parse_negotiation(&request).unwrap();
+    assert_eq!(negotiation.shallow, vec!["2".repeat(40)]);
+    assert_eq!(negotiation.depth, Some(3));
+
+    for depth in ["0", "-1", "many"] {
+        let request = protocol.create_pkt_line(&[&format!("deepen {}", depth)]);
+        assert!(protocol.parse_negotiation(&request).is_err(), "deepen {}", depth);
+    }
+
+    assert_eq!(
+        protocol.create_shallow_info(&["2".repeat(40)], &["3".repeat(40)]),
+        format!("0035shallow {}\n0037unshallow {}\n0000", "2".repeat(40), "3".repeat(40)).into_bytes()
+    );
+
+    assert_eq!(ObjectFilter::parse("blob:none").unwrap(), ObjectFilter::BlobNone);
+    assert_eq!(ObjectFilter::parse("blob:limit=1k").unwrap().blob_limit(), 1024);
+    assert_eq!(ObjectFilter::parse("blob:limit=500").unwrap().blob_limit(), 500);
+    for spec in ["tree:0", "blob:limit=", "blob:limit=1x", "blob:limit=99999999999g"] {
+        assert!(ObjectFilter::parse(spec).is_err(), "{}", spec);
+    }
+}
+
+#[test]
+fn test_parse_commit_dates() {
+    let handler = crate::objects::ObjectHandler::new();
+    let content = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nauthor A U Thor <author@example.com> 1700000000 +0200\ncommitter C O Mitter <committer@example.com> 1700003600 -0500\n\nmessage\n";
+
+    let commit = handler.parse_commit(content).unwrap();
+    assert_eq!(commit.author_date.timestamp(), 1700000000);
+    assert_eq!(commit.commit_date.timestamp(), 1700003600);
+    assert_eq!(commit.committer, "C O Mitter <committer@example.com> 1700003600 -0500");
+}
+
+/// Deterministic xorshift bytes, so a failing input can be reproduced
+fn garbage(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Every prefix of `valid`, `valid` with single bytes flipped, and random bytes
+fn mangled(valid: &[u8]) -> Vec<Vec<u8>> {
+    let mut inputs: Vec<Vec<u8>> = (0..=valid.len()).map(|len| valid[..len].to_vec()).collect();
+    for (seed, pos) in (0..valid.len()).step_by(valid.len() / 64 + 1).enumerate() {
+        let mut flipped = valid.to_vec();
+        flipped[pos] ^= garbage(seed as u64, 1)[0] | 1;
+        inputs.push(flipped);
+    }
+    inputs.extend((0..64).map(|seed| garbage(seed, seed as usize * 7)));
+    inputs
+}
+
+#[test]
+fn test_parsers_reject_malformed_input_without_panicking() {
+    use crate::objects::{ObjectHandler, Tree, TreeEntry};
+    use crate::pack::PackParser;
+    use crate::{batch, GitObject, ObjectType};
+
+    let handler = ObjectHandler::new();
+    let protocol = ProtocolHandler::new();
+
+    let commit = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nparent 1111111111111111111111111111111111111111\nauthor A U Thor <a@example.com> 1700000000 +0200\ncommitter A U Thor <a@example.com> 1700000000 +0200\n\nsubject\n\nSigned-off-by: A U Thor <a@example.com>\n";
+    for input in mangled(commit) {
+        if let Ok(commit) = handler.parse_commit(&input) {
+            commit.trailers();
+        }
+    }
+
+    let tree = handler
+        .serialize_tree(&Tree {
+            entries: vec![
+                TreeEntry { mode: "100644".into(), name: "a.txt".into(), hash: "1".repeat(40) },
+                TreeEntry { mode: "40000".into(), name: "dir".into(), hash: "2".repeat(40) },
+            ],
+        })
+        .unwrap();
+    for input in mangled(&tree) {
+        let _ = handler.parse_tree(&input);
+    }
+
+    let tag = b"object 1111111111111111111111111111111111111111\ntype commit\ntag v1\ntagger A U Thor <a@example.com> 1700000000 +0000\n\nrelease\n";
+    for input in mangled(tag) {
+        let _ = handler.parse_tag(&input);
+    }
+
+    let objects: Vec<GitObject> = [&commit[..], &tree, &tag[..]]
+        .iter()
+        .map(|content| handler.parse_object(ObjectType::Blob, content).unwrap())
+        .collect();
+    let pack = PackParser::new().create_pack(&objects).unwrap();
+    for input in mangled(&pack) {
+        let _ = protocol.parse_pack(&input);
+        let _ = PackParser::new().parse_pack_file_simple(input.clone());
+        if let Ok((rest, _)) = PackParser::new().parse_header(&input) {
+            let _ = PackParser::new().parse_object(rest);
+        }
+    }
+    // Object sizes and delta offsets whose varints never end or overflow
+    for input in [&b"PACK\0\0\0\x02\0\0\0\x01\xbf\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"[..], b"PACK\0\0\0\x02\0\0\0\x01\x60\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"] {
+        assert!(protocol.parse_pack(input).is_err());
+    }
+
+    let first = format!("{} {} refs/heads/main\0report-status atomic agent=git/2.43.0", ZERO_ID, "1".repeat(40));
+    let mut request = protocol.create_pkt_line(&[&first, "want 2222222222222222222222222222222222222222", "have"]);
+    request.extend_from_slice(&pack);
+    for input in mangled(&request) {
+        let _ = protocol.parse_receive_pack_request(&input);
+        let _ = protocol.parse_negotiation(&input);
+        if let Ok(lines) = protocol.parse_pkt_sections(&input) {
+            let _ = protocol.parse_want_have(&lines);
+            protocol.parse_want_capabilities(&lines);
+            for line in &lines {
+                protocol.parse_capabilities(line);
+            }
+        }
+    }
+
+    let entries = vec![batch::BatchEntry::Found(objects[0].clone()), batch::BatchEntry::Missing("3".repeat(40))];
+    for input in mangled(&batch::encode_batch(&entries).unwrap()) {
+        let _ = batch::decode_batch(&input);
+    }
+}
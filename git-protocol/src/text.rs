@@ -0,0 +1,144 @@
+//! Telling text from binary content, and the line endings text uses, the same
+//! way for every endpoint and policy that serves or inspects file content.
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// How much of a file is inspected for NUL bytes when deciding it is binary
+pub const BINARY_SNIFF_LEN: usize = 8000;
+
+/// Line endings found in text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    /// Both `\n` and `\r\n`
+    Mixed,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "lf",
+            LineEnding::Crlf => "crlf",
+            LineEnding::Mixed => "mixed",
+        }
+    }
+}
+
+/// What a blob's content is, for deciding how to show it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextInfo {
+    pub is_binary: bool,
+    /// `None` for binary content and text without line breaks
+    pub line_ending: Option<LineEnding>,
+}
+
+/// Whether content looks binary: a NUL byte in its first `BINARY_SNIFF_LEN`
+/// bytes, git's own check. UTF-16 text counts as binary, as it does for git.
+pub fn is_binary(content: &[u8]) -> bool {
+    content.iter().take(BINARY_SNIFF_LEN).any(|&b| b == 0)
+}
+
+/// The line endings text uses, `None` when it has no line breaks. A `\r` not
+/// followed by `\n` doesn't end a line.
+pub fn line_ending(content: &[u8]) -> Option<LineEnding> {
+    let mut lf = false;
+    let mut crlf = false;
+    for (i, &b) in content.iter().enumerate() {
+        if b == b'\n' {
+            if i > 0 && content[i - 1] == b'\r' {
+                crlf = true;
+            } else {
+                lf = true;
+            }
+            if lf && crlf {
+                return Some(LineEnding::Mixed);
+            }
+        }
+    }
+    match (lf, crlf) {
+        (true, false) => Some(LineEnding::Lf),
+        (false, true) => Some(LineEnding::Crlf),
+        _ => None,
+    }
+}
+
+/// Classify content as binary or text, and text by its line endings
+pub fn classify(content: &[u8]) -> TextInfo {
+    let is_binary = is_binary(content);
+    TextInfo {
+        is_binary,
+        line_ending: if is_binary { None } else { line_ending(content) },
+    }
+}
+
+/// Text with every `\r\n` turned into `\n`; borrowed when there is none
+pub fn normalize_eol(content: &[u8]) -> Cow<'_, [u8]> {
+    if !content.windows(2).any(|w| w == b"\r\n") {
+        return Cow::Borrowed(content);
+    }
+    let mut normalized = Vec::with_capacity(content.len());
+    for (i, &b) in content.iter().enumerate() {
+        if b == b'\r' && content.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        normalized.push(b);
+    }
+    Cow::Owned(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_text_and_binary() {
+        let utf8 = "héllo wörld\nsecond line\n".as_bytes();
+        assert_eq!(
+            classify(utf8),
+            TextInfo {
+                is_binary: false,
+                line_ending: Some(LineEnding::Lf)
+            }
+        );
+
+        // UTF-16 puts a NUL next to every ASCII character
+        let utf16: Vec<u8> = [0xFF, 0xFE]
+            .into_iter()
+            .chain("hello\r\n".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        assert_eq!(
+            classify(&utf16),
+            TextInfo {
+                is_binary: true,
+                line_ending: None
+            }
+        );
+
+        // Only the first BINARY_SNIFF_LEN bytes are looked at
+        let mut late_nul = vec![b'a'; BINARY_SNIFF_LEN];
+        late_nul.push(0);
+        assert!(!is_binary(&late_nul));
+        assert!(is_binary(&late_nul[1..]));
+
+        assert_eq!(line_ending(b"a\r\nb\r\n"), Some(LineEnding::Crlf));
+        assert_eq!(line_ending(b"one line"), None);
+        assert_eq!(line_ending(b"old mac\rline"), None);
+        assert_eq!(line_ending(b"\n"), Some(LineEnding::Lf));
+    }
+
+    #[test]
+    fn test_mixed_line_endings_normalize_to_lf() {
+        let mixed = b"unix\nwindows\r\nlone\rcarriage\r\n";
+        assert_eq!(line_ending(mixed), Some(LineEnding::Mixed));
+        assert_eq!(LineEnding::Mixed.as_str(), "mixed");
+
+        let normalized = normalize_eol(mixed);
+        assert_eq!(&normalized[..], b"unix\nwindows\nlone\rcarriage\n");
+        assert_eq!(line_ending(&normalized), Some(LineEnding::Lf));
+
+        assert!(matches!(normalize_eol(b"unix\n"), Cow::Borrowed(_)));
+    }
+}
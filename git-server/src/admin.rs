@@ -0,0 +1,140 @@
+use anyhow::{anyhow, Result};
+use git_storage::transfer::{export_repository, import_repository};
+use git_storage::{CheckOptions, CheckReport, RepositoryService, UserService};
+use uuid::Uuid;
+use std::path::Path;
+
+const USAGE: &str = "usage:
+  git-server migrate
+  git-server export --repo <owner>/<name> --out <dir>
+  git-server import --in <dir> --owner <username>
+  git-server check [--repo <owner>/<name>] [--json] [--fix]
+  git-server rebuild-projections --repo <owner>/<name> [--dry-run]";
+
+/// Run an admin subcommand instead of starting the server
+pub async fn run(
+    command: &str,
+    args: &[String],
+    repository_service: &RepositoryService,
+    user_service: &UserService,
+) -> Result<()> {
+    match command {
+        // Applied before any command runs, so only the outcome is left to report
+        "migrate" => println!("Database schema is up to date"),
+        "export" => {
+            let repo = flag(args, "--repo")?;
+            let out = flag(args, "--out")?;
+            let (owner, name) = repo
+                .split_once('/')
+                .ok_or_else(|| anyhow!("--repo must be <owner>/<name>"))?;
+
+            let export =
+                export_repository(repository_service, user_service, owner, name, Path::new(out)).await?;
+            println!(
+                "Exported {}/{} ({} refs, {} objects) to {}",
+                owner,
+                name,
+                export.refs.len(),
+                export.object_count,
+                out
+            );
+        }
+        "import" => {
+            let in_dir = flag(args, "--in")?;
+            let owner = flag(args, "--owner")?;
+
+            let report =
+                import_repository(repository_service, user_service, Path::new(in_dir), owner).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        "check" => {
+            let repository_id = if args.iter().any(|arg| arg == "--repo") {
+                Some(find_repository(flag(args, "--repo")?, repository_service, user_service).await?)
+            } else {
+                None
+            };
+            let options = CheckOptions {
+                fix: args.iter().any(|arg| arg == "--fix"),
+                ..CheckOptions::default()
+            };
+
+            let report = repository_service.check_consistency(repository_id, &options).await?;
+            if args.iter().any(|arg| arg == "--json") {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_check_report(&report);
+            }
+            if report.has_errors() {
+                return Err(anyhow!("Consistency check found errors"));
+            }
+        }
+        "rebuild-projections" => {
+            let repository_id = find_repository(flag(args, "--repo")?, repository_service, user_service).await?;
+            let dry_run = args.iter().any(|arg| arg == "--dry-run");
+
+            let report = repository_service
+                .rebuild_projections(repository_id, dry_run, |progress| {
+                    tracing::info!(
+                        table = ?progress.table,
+                        rows_checked = progress.rows_checked,
+                        differences = progress.differences,
+                        "Rebuilding projections"
+                    );
+                })
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        _ => return Err(anyhow!("Unknown command: {}\n{}", command, USAGE)),
+    }
+
+    Ok(())
+}
+
+fn print_check_report(report: &CheckReport) {
+    if !report.findings.is_empty() {
+        println!("{:<8} {:<24} {:<36} SUBJECT", "SEVERITY", "CODE", "REPOSITORY");
+    }
+    for finding in &report.findings {
+        let repository = finding.repository_id.map(|id| id.to_string()).unwrap_or_else(|| "-".to_string());
+        let severity = format!("{:?}", finding.severity).to_lowercase();
+        let fixed = if finding.fixed { " (fixed)" } else { "" };
+        println!(
+            "{:<8} {:<24} {:<36} {}{}\n         {}",
+            severity, finding.code, repository, finding.subject, fixed, finding.message
+        );
+    }
+    println!(
+        "Checked {} repositories: {} findings",
+        report.repositories_checked,
+        report.findings.len()
+    );
+}
+
+/// Id of the repository named `<owner>/<name>`
+async fn find_repository(
+    repo: &str,
+    repository_service: &RepositoryService,
+    user_service: &UserService,
+) -> Result<Uuid> {
+    let (owner, name) = repo
+        .split_once('/')
+        .ok_or_else(|| anyhow!("--repo must be <owner>/<name>"))?;
+    let owner = user_service
+        .get_user_by_username(owner)
+        .await?
+        .ok_or_else(|| anyhow!("User {} not found", owner))?;
+    let repo = repository_service
+        .get_repository_by_name_and_owner(name, owner.id)
+        .await?
+        .ok_or_else(|| anyhow!("Repository {} not found", repo))?;
+    Ok(repo.id)
+}
+
+/// Value following `name` in the argument list
+fn flag<'a>(args: &'a [String], name: &str) -> Result<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|value| value.as_str())
+        .ok_or_else(|| anyhow!("Missing {}\n{}", name, USAGE))
+}
@@ -1,6 +1,11 @@
+use crate::payload::ApiJson;
+use crate::scopes::{grants, Caller, Scope};
 use crate::AppState;
-use actix_web::{get, post, web, HttpResponse, Result};
+use actix_web::{delete, get, patch, post, web, HttpResponse, Result};
 use actix_session::Session;
+use git_storage::entities::access_token;
+use git_storage::StorageError;
+use uuid::Uuid;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -39,13 +44,30 @@ pub struct UserResponse {
     pub full_name: Option<String>,
     pub is_active: bool,
     pub is_admin: bool,
+    /// Others see a noreply address instead of `email`, in commits too
+    pub hide_email: bool,
     pub created_at: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct UpdateCurrentUserRequest {
+    pub hide_email: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddEmailRequest {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
 /// User login endpoint
 #[post("/login")]
 pub async fn login(
-    body: web::Json<LoginRequest>,
+    body: ApiJson<LoginRequest>,
     session: Session,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
@@ -66,7 +88,7 @@ pub async fn login(
             }
 
             // Store user session
-            if let Err(_) = session.insert("user_id", user.id.to_string()) {
+            if session.insert("user_id", user.id.to_string()).is_err() {
                 return Ok(HttpResponse::InternalServerError().json(LoginResponse {
                     success: false,
                     user: None,
@@ -81,6 +103,7 @@ pub async fn login(
                 full_name: user.full_name,
                 is_active: user.is_active,
                 is_admin: user.is_admin,
+                hide_email: user.hide_email,
                 created_at: user.created_at.to_string(),
             };
 
@@ -106,7 +129,7 @@ pub async fn login(
 /// User registration endpoint
 #[post("/register")]
 pub async fn register(
-    body: web::Json<RegisterRequest>,
+    body: ApiJson<RegisterRequest>,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
     let req = body.into_inner();
@@ -120,13 +143,16 @@ pub async fn register(
         }));
     }
 
-    if req.email.trim().is_empty() || !req.email.contains('@') {
-        return Ok(HttpResponse::BadRequest().json(RegisterResponse {
-            success: false,
-            user: None,
-            message: "Valid email is required".to_string(),
-        }));
-    }
+    let email = match git_storage::normalize_email(&req.email) {
+        Ok(email) => email,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(RegisterResponse {
+                success: false,
+                user: None,
+                message: e.to_string(),
+            }));
+        }
+    };
 
     if req.password.len() < 6 {
         return Ok(HttpResponse::BadRequest().json(RegisterResponse {
@@ -145,7 +171,7 @@ pub async fn register(
         }));
     }
 
-    if let Ok(true) = state.user_service.email_exists(&req.email).await {
+    if let Ok(true) = state.user_service.email_exists(&email).await {
         return Ok(HttpResponse::Conflict().json(RegisterResponse {
             success: false,
             user: None,
@@ -170,7 +196,7 @@ pub async fn register(
         .user_service
         .create_user(
             req.username,
-            req.email,
+            email,
             password_hash,
             req.full_name,
             false, // New users are not admin by default
@@ -185,6 +211,7 @@ pub async fn register(
                 full_name: user.full_name,
                 is_active: user.is_active,
                 is_admin: user.is_admin,
+                hide_email: user.hide_email,
                 created_at: user.created_at.to_string(),
             };
 
@@ -194,11 +221,18 @@ pub async fn register(
                 message: "Registration successful".to_string(),
             }))
         }
-        Err(_) => Ok(HttpResponse::InternalServerError().json(RegisterResponse {
-            success: false,
-            user: None,
-            message: "Registration failed due to server error".to_string(),
-        })),
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::EmailTaken { .. }) => Ok(HttpResponse::Conflict().json(RegisterResponse {
+                success: false,
+                user: None,
+                message: "Email already exists".to_string(),
+            })),
+            _ => Ok(HttpResponse::InternalServerError().json(RegisterResponse {
+                success: false,
+                user: None,
+                message: "Registration failed due to server error".to_string(),
+            })),
+        },
     }
 }
 
@@ -214,65 +248,399 @@ pub async fn logout(session: Session) -> Result<HttpResponse> {
 
 /// Get current user endpoint (requires authentication)
 #[get("/me")]
-pub async fn get_current_user(
-    session: Session,
+pub async fn get_current_user(caller: Caller) -> Result<HttpResponse> {
+    let Some(user) = caller.user().cloned() else {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Not authenticated"
+        })));
+    };
+
+    let user_response = UserResponse {
+        id: user.id.to_string(),
+        username: user.username,
+        email: user.email,
+        full_name: user.full_name,
+        is_active: user.is_active,
+        is_admin: user.is_admin,
+        hide_email: user.hide_email,
+        created_at: user.created_at.to_string(),
+    };
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "user": user_response
+    })))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    /// e.g. `["repo:read", "status:write"]`
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenResponse {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub expires_at: Option<String>,
+    /// Only returned when the token is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl TokenResponse {
+    fn from_model(token: access_token::Model, plaintext: Option<String>) -> Self {
+        Self {
+            id: token.id.to_string(),
+            name: token.name.clone(),
+            scopes: token.scope_list().into_iter().map(str::to_string).collect(),
+            created_at: token.created_at.to_string(),
+            last_used_at: token.last_used_at.map(|t| t.to_string()),
+            expires_at: token.expires_at.map(|t| t.to_string()),
+            token: plaintext,
+        }
+    }
+}
+
+/// Issue a personal access token. A token-authenticated caller can only hand
+/// out scopes its own token holds, and only admins can grant `admin`.
+#[post("/tokens")]
+pub async fn create_token(
+    body: ApiJson<CreateTokenRequest>,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    match session.get::<String>("user_id") {
-        Ok(Some(user_id_str)) => {
-            let user_id = match uuid::Uuid::parse_str(&user_id_str) {
-                Ok(id) => id,
-                Err(_) => {
-                    return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
-                        "success": false,
-                        "message": "Invalid session"
-                    })));
-                }
-            };
+    let Some(user_id) = caller.user_id() else {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Not authenticated"
+        })));
+    };
 
-            match state.user_service.get_user_by_id(user_id).await {
-                Ok(Some(user)) => {
-                    let user_response = UserResponse {
-                        id: user.id.to_string(),
-                        username: user.username,
-                        email: user.email,
-                        full_name: user.full_name,
-                        is_active: user.is_active,
-                        is_admin: user.is_admin,
-                        created_at: user.created_at.to_string(),
-                    };
-
-                    Ok(HttpResponse::Ok().json(serde_json::json!({
-                        "success": true,
-                        "user": user_response
-                    })))
-                }
-                Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({
-                    "success": false,
-                    "message": "User not found"
-                }))),
-                Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+    let req = body.into_inner();
+    if req.name.trim().is_empty() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "Token name cannot be empty"
+        })));
+    }
+
+    let mut scopes = Vec::new();
+    for name in &req.scopes {
+        match name.parse::<Scope>() {
+            Ok(scope) if !scopes.contains(&scope) => scopes.push(scope),
+            Ok(_) => {}
+            Err(e) => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
                     "success": false,
-                    "message": "Database error"
-                }))),
+                    "message": e.to_string()
+                })));
             }
         }
-        Ok(None) => Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+    }
+    if scopes.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "At least one scope is required"
+        })));
+    }
+
+    if let Some(held) = caller.token_scopes() {
+        if let Some(missing) = scopes.iter().find(|scope| !grants(held, **scope)) {
+            return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+                "success": false,
+                "message": format!("Token is missing the {} scope", missing.as_str()),
+                "missing_scope": missing.as_str()
+            })));
+        }
+    }
+    if scopes.contains(&Scope::Admin) && !caller.user().is_some_and(|user| user.is_admin) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "success": false,
+            "message": "Only admins can grant the admin scope"
+        })));
+    }
+
+    let scope_names: Vec<String> = scopes.iter().map(|scope| scope.as_str().to_string()).collect();
+    let expires_at = req
+        .expires_in_days
+        .map(|days| chrono::Utc::now() + chrono::Duration::days(days as i64));
+    match state
+        .token_service
+        .create_token(user_id, req.name, &scope_names, expires_at)
+        .await
+    {
+        Ok((token, plaintext)) => Ok(HttpResponse::Created().json(serde_json::json!({
+            "success": true,
+            "token": TokenResponse::from_model(token, Some(plaintext))
+        }))),
+        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+            "success": false,
+            "message": "Failed to create token"
+        }))),
+    }
+}
+
+/// List the current user's tokens, without their secrets
+#[get("/tokens")]
+pub async fn list_tokens(
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(user_id) = caller.user_id() else {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Not authenticated"
+        })));
+    };
+
+    match state.token_service.list_tokens(user_id).await {
+        Ok(tokens) => {
+            let tokens: Vec<TokenResponse> = tokens
+                .into_iter()
+                .map(|token| TokenResponse::from_model(token, None))
+                .collect();
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "tokens": tokens
+            })))
+        }
+        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+            "success": false,
+            "message": "Database error"
+        }))),
+    }
+}
+
+/// Revoke one of the current user's tokens
+#[delete("/tokens/{token_id}")]
+pub async fn revoke_token(
+    path: web::Path<String>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(user_id) = caller.user_id() else {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Not authenticated"
+        })));
+    };
+
+    let Ok(token_id) = Uuid::parse_str(&path) else {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "Invalid token ID"
+        })));
+    };
+
+    match state.token_service.revoke_token(user_id, token_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Token revoked"
+        }))),
+        Ok(false) => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "success": false,
+            "message": "Token not found"
+        }))),
+        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+            "success": false,
+            "message": "Database error"
+        }))),
+    }
+}
+
+/// Change the current user's settings; only `hide_email` so far
+#[patch("/me")]
+pub async fn update_current_user(
+    body: ApiJson<UpdateCurrentUserRequest>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(user_id) = caller.user_id() else {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Not authenticated"
+        })));
+    };
+
+    let user = match body.into_inner().hide_email {
+        Some(hide_email) => state.user_service.set_hide_email(user_id, hide_email).await,
+        None => Ok(caller.user().cloned()),
+    };
+    match user {
+        Ok(Some(user)) => {
+            let user_response = UserResponse {
+                id: user.id.to_string(),
+                username: user.username,
+                email: user.email,
+                full_name: user.full_name,
+                is_active: user.is_active,
+                is_admin: user.is_admin,
+                hide_email: user.hide_email,
+                created_at: user.created_at.to_string(),
+            };
+
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "user": user_response
+            })))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "success": false,
+            "message": "User not found"
+        }))),
+        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+            "success": false,
+            "message": "Database error"
+        }))),
+    }
+}
+
+/// List the current user's additional email addresses
+#[get("/emails")]
+pub async fn list_emails(
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(user_id) = caller.user_id() else {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
             "success": false,
             "message": "Not authenticated"
+        })));
+    };
+
+    match state.identity_service.list_aliases(user_id).await {
+        Ok(emails) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "emails": emails
         }))),
         Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
             "success": false,
-            "message": "Session error"
+            "message": "Database error"
+        }))),
+    }
+}
+
+/// Add an email address the current user commits under. It maps commits to the
+/// user once verified with the token sent for it. There is no mail delivery
+/// yet, so the verification link is logged for the operator to pass on.
+#[post("/emails")]
+pub async fn add_email(
+    body: ApiJson<AddEmailRequest>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(user_id) = caller.user_id() else {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Not authenticated"
+        })));
+    };
+
+    match state.identity_service.add_alias(user_id, &body.email).await {
+        Ok((alias, token)) => {
+            tracing::info!(
+                email = %alias.email,
+                link = %state.config.absolute_url(&format!("verify-email?token={}", token)),
+                "email verification link"
+            );
+            Ok(HttpResponse::Created().json(serde_json::json!({
+                "success": true,
+                "email": alias
+            })))
+        }
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::InvalidEmail { .. }) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": e.to_string()
+            }))),
+            Some(StorageError::EmailTaken { .. }) => Ok(HttpResponse::Conflict().json(serde_json::json!({
+                "success": false,
+                "message": e.to_string()
+            }))),
+            _ => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "success": false,
+                "message": "Database error"
+            }))),
+        },
+    }
+}
+
+/// Verify one of the current user's email addresses with the token sent for it
+#[post("/emails/verify")]
+pub async fn verify_email(
+    body: ApiJson<VerifyEmailRequest>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(user_id) = caller.user_id() else {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Not authenticated"
+        })));
+    };
+
+    match state.identity_service.verify_alias(user_id, &body.token).await {
+        Ok(Some(alias)) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "email": alias
+        }))),
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "success": false,
+            "message": "Invalid or already used verification token"
+        }))),
+        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+            "success": false,
+            "message": "Database error"
+        }))),
+    }
+}
+
+/// Remove one of the current user's email addresses
+#[delete("/emails/{email_id}")]
+pub async fn remove_email(
+    path: web::Path<String>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(user_id) = caller.user_id() else {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Not authenticated"
+        })));
+    };
+
+    let Ok(email_id) = Uuid::parse_str(&path) else {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "Invalid email ID"
+        })));
+    };
+
+    match state.identity_service.remove_alias(user_id, email_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Email removed"
+        }))),
+        Ok(false) => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "success": false,
+            "message": "Email not found"
+        }))),
+        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+            "success": false,
+            "message": "Database error"
         }))),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use git_storage::{init_db, run_migrations, RepositoryService};
-    use actix_web::{test, web, App, middleware};
+    use git_storage::{init_db, run_migrations};
     use std::sync::Arc;
 
     async fn create_test_app() -> Arc<git_storage::UserService> {
@@ -280,7 +648,8 @@ mod tests {
         let db = init_db("sqlite::memory:").await.unwrap();
         run_migrations(&db).await.unwrap();
         
-        Arc::new(git_storage::UserService::new(db))
+        // The lowest bcrypt cost keeps these tests fast
+        Arc::new(git_storage::UserService::new(db).with_password_hash_cost(4).unwrap())
     }
 
     #[tokio::test]
@@ -346,6 +715,34 @@ mod tests {
         assert!(!user_service.verify_password("wrongpassword", &hashed).unwrap());
     }
 
+    #[tokio::test]
+    async fn test_password_hashes_verify_across_cost_settings() {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+        let cheap = git_storage::UserService::new(db.clone()).with_password_hash_cost(4).unwrap();
+        let costly = git_storage::UserService::new(db.clone()).with_password_hash_cost(6).unwrap();
+        assert!(git_storage::UserService::new(db.clone()).with_password_hash_cost(3).is_err());
+
+        let cheap_hash = cheap.hash_password("s3cret").unwrap();
+        let costly_hash = costly.hash_password("s3cret").unwrap();
+        assert!(cheap_hash.starts_with("$2b$04$"));
+        assert!(costly_hash.starts_with("$2b$06$"));
+        for service in [&cheap, &costly] {
+            assert!(service.verify_password("s3cret", &cheap_hash).unwrap());
+            assert!(service.verify_password("s3cret", &costly_hash).unwrap());
+            assert!(!service.verify_password("wrong", &costly_hash).unwrap());
+        }
+
+        // Logging in moves a hash made under the old setting to the current cost
+        cheap
+            .create_user("old".to_string(), "old@example.com".to_string(), costly_hash, None, false)
+            .await
+            .unwrap();
+        let user = cheap.authenticate("old", "s3cret").await.unwrap().unwrap();
+        assert!(user.password_hash.starts_with("$2b$04$"));
+        assert!(cheap.authenticate("old", "s3cret").await.unwrap().is_some());
+    }
+
     #[tokio::test]
     async fn test_user_exists_checks() {
         let user_service = create_test_app().await;
@@ -370,4 +767,64 @@ mod tests {
         assert!(user_service.username_exists("testuser").await.unwrap());
         assert!(user_service.email_exists("test@example.com").await.unwrap());
     }
+
+    #[tokio::test]
+    async fn test_malformed_emails_are_rejected() {
+        let user_service = create_test_app().await;
+
+        for email in ["", "no-at-sign", "@example.com", "user@", "a@b@example.com", "user@localhost",
+            "user..name@example.com", "user@-example.com", "user name@example.com"]
+        {
+            let err = user_service
+                .create_user("someone".to_string(), email.to_string(), "hash".to_string(), None, false)
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(git_storage::StorageError::InvalidEmail { .. })),
+                "{email:?} was accepted"
+            );
+        }
+        assert!(!user_service.username_exists("someone").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_emails_are_normalized_and_unique_ignoring_case() {
+        let user_service = create_test_app().await;
+
+        let user = user_service
+            .create_user("alice".to_string(), "  Alice@Example.COM ".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        assert_eq!(user.email, "alice@example.com");
+        assert!(user_service.email_exists("ALICE@example.com").await.unwrap());
+        assert_eq!(user_service.get_user_by_email("Alice@Example.com").await.unwrap().unwrap().id, user.id);
+
+        let err = user_service
+            .create_user("alice2".to_string(), "ALICE@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(git_storage::StorageError::EmailTaken { .. })));
+
+        // Updates are held to the same rules, but keeping your own address is fine
+        let bob = user_service
+            .create_user("bob".to_string(), "bob@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let err = user_service
+            .update_user(bob.id, None, Some("Alice@example.com".to_string()), None, None, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(git_storage::StorageError::EmailTaken { .. })));
+        let err = user_service
+            .update_user(bob.id, None, Some("bob at example.com".to_string()), None, None, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(git_storage::StorageError::InvalidEmail { .. })));
+        let bob = user_service
+            .update_user(bob.id, None, Some("BOB@example.com".to_string()), None, None, None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bob.email, "bob@example.com");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use base64::Engine;
+use git_storage::NewSshKey;
+use serde::{Deserialize, Serialize};
+
+/// Key types accepted unless `SSH_KEY_TYPES` says otherwise: every type the SSH
+/// server can verify signatures for
+pub const DEFAULT_SSH_KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "ssh-rsa",
+];
+
+/// Every key type OpenSSH writes to `authorized_keys`, accepted or not, so a
+/// line's type can be told apart from leading options
+const KNOWN_KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "ssh-rsa",
+    "ssh-dss",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Parse one `authorized_keys` line: `<type> <base64 blob> [comment]`. Blank
+/// lines and `#` comments give `None`. Lines with options (`no-pty`,
+/// `command="..."`) are refused, since registering the key without them would
+/// grant more than the file did.
+pub fn parse_line(line: &str, allowed_types: &[String]) -> Result<Option<NewSshKey>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let mut fields = line.split_whitespace();
+    let key_type = fields.next().unwrap_or_default();
+    if !KNOWN_KEY_TYPES.contains(&key_type) {
+        return Err(if line.split_whitespace().any(|field| KNOWN_KEY_TYPES.contains(&field)) {
+            "key options are not supported".to_string()
+        } else {
+            format!("unknown key type '{}'", key_type)
+        });
+    }
+    if !allowed_types.iter().any(|allowed| allowed == key_type) {
+        return Err(format!("{} keys are not accepted", key_type));
+    }
+
+    let blob = fields.next().ok_or("missing key data")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(blob)
+        .map_err(|_| "key data is not valid base64".to_string())?;
+    // The blob starts with its own type, which must agree with the line's
+    match blob_key_type(&decoded) {
+        Some(inner) if inner == key_type.as_bytes() => {}
+        Some(inner) => {
+            return Err(format!(
+                "key data is a {} key, not {}",
+                String::from_utf8_lossy(inner),
+                key_type
+            ))
+        }
+        None => return Err("key data is truncated".to_string()),
+    }
+    let key = russh_keys::parse_public_key_base64(blob)
+        .map_err(|e| format!("invalid {} key: {}", key_type, e))?;
+
+    let comment = fields.collect::<Vec<_>>().join(" ");
+    Ok(Some(NewSshKey {
+        key_type: key_type.to_string(),
+        public_key: blob.to_string(),
+        fingerprint: format!("SHA256:{}", key.fingerprint()),
+        comment: (!comment.is_empty()).then_some(comment),
+    }))
+}
+
+/// The type name a key blob opens with, as an SSH wire-format string
+fn blob_key_type(blob: &[u8]) -> Option<&[u8]> {
+    let len = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    blob.get(4..4usize.checked_add(len)?)
+}
+
+/// What happened to one line of an import
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyImportStatus {
+    Imported,
+    /// The user already had the key, from before or from an earlier line
+    Duplicate,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyImportLine {
+    /// 1-based line number in the submitted file
+    pub line: usize,
+    pub status: KeyImportStatus,
+    pub fingerprint: Option<String>,
+    pub comment: Option<String>,
+    /// Why the line failed
+    pub message: Option<String>,
+}
+
+/// Per-line results of importing an `authorized_keys` file; blank and comment
+/// lines are left out
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyImportReport {
+    pub imported: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+    pub lines: Vec<KeyImportLine>,
+}
+
+impl KeyImportReport {
+    pub fn push(&mut self, line: KeyImportLine) {
+        match line.status {
+            KeyImportStatus::Imported => self.imported += 1,
+            KeyImportStatus::Duplicate => self.duplicates += 1,
+            KeyImportStatus::Failed => self.failed += 1,
+        }
+        self.lines.push(line);
+    }
+}
@@ -0,0 +1,164 @@
+use actix_web::http::StatusCode;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits, Rgb, RgbImage};
+use sha1::{Digest, Sha1};
+use std::io::Cursor;
+
+/// Sizes, in pixels square, avatars are stored and served at
+pub const AVATAR_SIZES: [u32; 4] = [32, 64, 128, 256];
+
+/// Size served when a request doesn't ask for one
+pub const DEFAULT_AVATAR_SIZE: u32 = 64;
+
+/// Largest avatar upload read
+pub const MAX_AVATAR_UPLOAD_BYTES: usize = 5 * 1024 * 1024;
+
+/// Widest and tallest avatar upload decoded
+pub const MAX_AVATAR_DIMENSION: u32 = 4096;
+
+/// Cells across an identicon; the left half is mirrored onto the right
+const IDENTICON_CELLS: u32 = 5;
+
+/// The stored size serving a request for `requested` pixels: the smallest one at
+/// least that big, or the largest there is
+pub fn canonical_size(requested: u32) -> u32 {
+    AVATAR_SIZES
+        .into_iter()
+        .find(|&size| size >= requested)
+        .unwrap_or(AVATAR_SIZES[AVATAR_SIZES.len() - 1])
+}
+
+/// Why an upload can't be used as an avatar
+#[derive(Debug, thiserror::Error)]
+pub enum AvatarError {
+    #[error("Avatar uploads must be at most {MAX_AVATAR_UPLOAD_BYTES} bytes")]
+    TooLarge,
+    #[error("Avatars must be PNG or JPEG images")]
+    UnsupportedType,
+    #[error("Avatars must be at most {MAX_AVATAR_DIMENSION} pixels wide and tall")]
+    TooManyPixels,
+    #[error("Avatar image could not be decoded: {0}")]
+    Undecodable(String),
+}
+
+impl AvatarError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AvatarError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AvatarError::UnsupportedType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AvatarError::TooManyPixels | AvatarError::Undecodable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+/// Decode an uploaded PNG or JPEG and render it at every stored size, cropped
+/// square around its centre. Decoding is bounded by the upload and dimension
+/// limits, whatever the image's headers claim.
+pub fn resize_upload(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, AvatarError> {
+    if data.len() > MAX_AVATAR_UPLOAD_BYTES {
+        return Err(AvatarError::TooLarge);
+    }
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| AvatarError::Undecodable(e.to_string()))?;
+    if !matches!(reader.format(), Some(ImageFormat::Png | ImageFormat::Jpeg)) {
+        return Err(AvatarError::UnsupportedType);
+    }
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|e| AvatarError::Undecodable(e.to_string()))?;
+    if width > MAX_AVATAR_DIMENSION || height > MAX_AVATAR_DIMENSION {
+        return Err(AvatarError::TooManyPixels);
+    }
+
+    reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| AvatarError::Undecodable(e.to_string()))?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_DIMENSION);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|e| match e {
+        image::ImageError::Limits(_) => AvatarError::TooManyPixels,
+        e => AvatarError::Undecodable(e.to_string()),
+    })?;
+
+    let side = width.min(height);
+    let square = image.crop_imm((width - side) / 2, (height - side) / 2, side, side);
+    Ok(AVATAR_SIZES
+        .into_iter()
+        .map(|size| (size, encode_png(&square.resize_exact(size, size, FilterType::Lanczos3))))
+        .collect())
+}
+
+/// A `size` pixel identicon for `username`: a mirrored grid of cells picked by
+/// the username's hash, in a colour also taken from it, so the same name always
+/// gets the same picture
+pub fn identicon(username: &str, size: u32) -> Vec<u8> {
+    let hash = Sha1::digest(username.as_bytes());
+    let colour = hue_to_rgb(u16::from_be_bytes([hash[0], hash[1]]) % 360);
+    let background = Rgb([240, 240, 240]);
+
+    // Cells in the left half and middle column, each on or off by one hash bit
+    let half = IDENTICON_CELLS.div_ceil(2);
+    let filled = |column: u32, row: u32| {
+        let column = column.min(IDENTICON_CELLS - 1 - column);
+        let bit = (row * half + column) as usize;
+        hash[2 + bit / 8] & (1 << (bit % 8)) != 0
+    };
+
+    // Half a cell of margin all round
+    let grid = IDENTICON_CELLS * 2 + 2;
+    let image = RgbImage::from_fn(size, size, |x, y| {
+        let (x, y) = (x * grid / size, y * grid / size);
+        if x == 0 || y == 0 || x >= grid - 1 || y >= grid - 1 {
+            return background;
+        }
+        if filled((x - 1) / 2, (y - 1) / 2) {
+            colour
+        } else {
+            background
+        }
+    });
+    encode_png(&DynamicImage::ImageRgb8(image))
+}
+
+fn encode_png(image: &DynamicImage) -> Vec<u8> {
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .expect("encoding a PNG in memory cannot fail");
+    png
+}
+
+/// A saturated, mid-lightness colour of `hue` degrees
+fn hue_to_rgb(hue: u16) -> Rgb<u8> {
+    let (chroma, lightness) = (0.55_f32, 0.5_f32);
+    let h = hue as f32 / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |v: f32| ((v + m) * 255.0).round() as u8;
+    Rgb([channel(r), channel(g), channel(b)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_size_rounds_up() {
+        assert_eq!(canonical_size(1), 32);
+        assert_eq!(canonical_size(64), 64);
+        assert_eq!(canonical_size(65), 128);
+        assert_eq!(canonical_size(200), 256);
+        assert_eq!(canonical_size(10_000), 256);
+    }
+}
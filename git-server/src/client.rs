@@ -0,0 +1,315 @@
+use actix_web::HttpRequest;
+use anyhow::{anyhow, Result};
+use git_protocol::Capabilities;
+use git_storage::{NewRepositoryEvent, RepositoryEventType};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Distinct agent labels tracked in metrics before the rest are counted as "other"
+pub const MAX_AGENT_LABELS: usize = 50;
+
+/// Longest agent string kept as a metrics label
+const MAX_AGENT_LABEL_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Http,
+    Ssh,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Http => "http",
+            Transport::Ssh => "ssh",
+        }
+    }
+}
+
+/// Who is talking to us: the `agent=` capability, the User-Agent header (HTTP only)
+/// and the protocol version requested through `Git-Protocol` / `GIT_PROTOCOL`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub transport: Transport,
+    pub protocol_version: u8,
+    pub agent: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_http(req: &HttpRequest, capabilities: &Capabilities) -> Self {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+
+        Self {
+            transport: Transport::Http,
+            protocol_version: parse_protocol_version(header("Git-Protocol").as_deref()),
+            agent: capabilities.agent().map(|a| a.to_string()),
+            user_agent: header("User-Agent"),
+        }
+    }
+
+    /// `git_protocol` is the `GIT_PROTOCOL` environment variable the client sent
+    pub fn from_ssh(git_protocol: Option<&str>, capabilities: &Capabilities) -> Self {
+        Self {
+            transport: Transport::Ssh,
+            protocol_version: parse_protocol_version(git_protocol),
+            agent: capabilities.agent().map(|a| a.to_string()),
+            user_agent: None,
+        }
+    }
+
+    /// Agent for metrics, falling back to the User-Agent when no capability was sent
+    pub fn agent_label(&self) -> String {
+        let agent = self
+            .agent
+            .as_deref()
+            .or(self.user_agent.as_deref())
+            .and_then(|a| a.split_whitespace().next())
+            .unwrap_or("unknown");
+        agent.chars().take(MAX_AGENT_LABEL_LEN).collect()
+    }
+
+    pub fn event(&self, repository_id: Uuid, event_type: RepositoryEventType) -> NewRepositoryEvent {
+        NewRepositoryEvent {
+            repository_id,
+            event_type,
+            transport: self.transport.as_str().to_string(),
+            protocol_version: self.protocol_version,
+            agent: self.agent.clone(),
+            user_agent: self.user_agent.clone(),
+            payload: None,
+            actor_id: None,
+        }
+    }
+}
+
+/// Version from a `version=<n>[:<other keys>]` protocol string; absent means v0
+fn parse_protocol_version(value: Option<&str>) -> u8 {
+    value
+        .into_iter()
+        .flat_map(|v| v.split(':'))
+        .filter_map(|param| param.strip_prefix("version="))
+        .filter_map(|version| version.parse().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Split `git/2.39.2.windows.1` into its product and leading numeric version parts
+fn parse_agent(agent: &str) -> Option<(&str, Vec<u32>)> {
+    let (product, version) = agent.split_once('/')?;
+    let version: Vec<u32> = version
+        .split('.')
+        .map_while(|part| part.parse().ok())
+        .collect();
+    (!version.is_empty()).then_some((product, version))
+}
+
+fn compare_versions(a: &[u32], b: &[u32]) -> Ordering {
+    let len = a.len().max(b.len());
+    let part = |v: &[u32], i: usize| v.get(i).copied().unwrap_or(0);
+    (0..len)
+        .map(|i| part(a, i).cmp(&part(b, i)))
+        .find(|ord| ord.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Rejects clients known to mishandle parts of the protocol
+#[derive(Debug, Clone, Default)]
+pub struct ClientPolicy {
+    /// Oldest agent allowed to push over protocol v0, e.g. `git/2.30.0`
+    min_push_agent: Option<(String, Vec<u32>)>,
+}
+
+impl ClientPolicy {
+    pub fn new(min_push_agent: Option<&str>) -> Result<Self> {
+        let min_push_agent = match min_push_agent {
+            Some(agent) => {
+                let (product, version) = parse_agent(agent)
+                    .ok_or_else(|| anyhow!("Invalid minimum agent '{}', expected <name>/<version>", agent))?;
+                Some((product.to_string(), version))
+            }
+            None => None,
+        };
+        Ok(Self { min_push_agent })
+    }
+
+    /// Check a push. Only agents of the same product with an older version are
+    /// refused; clients that don't identify themselves are let through.
+    pub fn check_push(&self, client: &ClientInfo) -> std::result::Result<(), String> {
+        let Some((min_product, min_version)) = &self.min_push_agent else {
+            return Ok(());
+        };
+        if client.protocol_version != 0 {
+            return Ok(());
+        }
+        let Some(agent) = client.agent.as_deref() else {
+            return Ok(());
+        };
+
+        match parse_agent(agent) {
+            Some((product, version))
+                if product == min_product && compare_versions(&version, min_version).is_lt() =>
+            {
+                let min = min_version.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(".");
+                Err(format!(
+                    "{} is not supported for pushes to this server; please upgrade to {}/{} or newer",
+                    agent, min_product, min
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentCount {
+    pub operation: RepositoryEventType,
+    pub agent: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientMetricsSnapshot {
+    pub agents: Vec<AgentCount>,
+    /// Pushes refused by the client policy
+    pub rejected_pushes: u64,
+}
+
+/// Request counts per client agent, capped at `MAX_AGENT_LABELS` distinct labels
+#[derive(Default)]
+pub struct ClientMetrics {
+    counts: Mutex<HashMap<(RepositoryEventType, String), u64>>,
+    rejected_pushes: AtomicU64,
+}
+
+impl ClientMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, operation: RepositoryEventType, client: &ClientInfo) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut label = client.agent_label();
+
+        let known = counts.keys().any(|(_, agent)| *agent == label);
+        if !known {
+            let distinct = counts
+                .keys()
+                .map(|(_, agent)| agent.as_str())
+                .filter(|agent| *agent != "other")
+                .collect::<std::collections::HashSet<_>>()
+                .len();
+            if distinct >= MAX_AGENT_LABELS {
+                label = "other".to_string();
+            }
+        }
+
+        *counts.entry((operation, label)).or_insert(0) += 1;
+    }
+
+    pub fn record_rejected_push(&self) {
+        self.rejected_pushes.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ClientMetricsSnapshot {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut agents: Vec<AgentCount> = counts
+            .iter()
+            .map(|((operation, agent), count)| AgentCount {
+                operation: *operation,
+                agent: agent.clone(),
+                count: *count,
+            })
+            .collect();
+        agents.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.agent.cmp(&b.agent)));
+
+        ClientMetricsSnapshot {
+            agents,
+            rejected_pushes: self.rejected_pushes.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_client_info_from_both_transports() {
+        let capabilities = Capabilities::parse("report-status side-band-64k agent=git/2.43.0");
+
+        let req = TestRequest::default()
+            .insert_header(("User-Agent", "git/2.43.0"))
+            .insert_header(("Git-Protocol", "version=2"))
+            .to_http_request();
+        let http = ClientInfo::from_http(&req, &capabilities);
+        assert_eq!(http.transport, Transport::Http);
+        assert_eq!(http.protocol_version, 2);
+        assert_eq!(http.agent.as_deref(), Some("git/2.43.0"));
+        assert_eq!(http.user_agent.as_deref(), Some("git/2.43.0"));
+
+        let ssh = ClientInfo::from_ssh(None, &capabilities);
+        assert_eq!(ssh.transport, Transport::Ssh);
+        assert_eq!(ssh.protocol_version, 0);
+        assert_eq!(ssh.agent.as_deref(), Some("git/2.43.0"));
+        assert_eq!(ssh.user_agent, None);
+
+        // Without the capability the User-Agent still labels the request
+        let req = TestRequest::default()
+            .insert_header(("User-Agent", "JGit/6.7.0"))
+            .to_http_request();
+        assert_eq!(ClientInfo::from_http(&req, &Capabilities::default()).agent_label(), "JGit/6.7.0");
+    }
+
+    #[test]
+    fn test_policy_rejects_old_v0_pushes() {
+        let policy = ClientPolicy::new(Some("git/2.30.0")).unwrap();
+        let client = |agent: &str, protocol_version| ClientInfo {
+            transport: Transport::Ssh,
+            protocol_version,
+            agent: Some(agent.to_string()),
+            user_agent: None,
+        };
+
+        assert_eq!(
+            policy.check_push(&client("git/2.17.1", 0)),
+            Err("git/2.17.1 is not supported for pushes to this server; please upgrade to git/2.30.0 or newer"
+                .to_string())
+        );
+        assert!(policy.check_push(&client("git/2.30", 0)).is_ok());
+        assert!(policy.check_push(&client("git/2.39.2.windows.1", 0)).is_ok());
+        assert!(policy.check_push(&client("git/2.17.1", 2)).is_ok());
+        assert!(policy.check_push(&client("JGit/1.0.0", 0)).is_ok());
+        assert!(ClientPolicy::new(Some("git")).is_err());
+    }
+
+    #[test]
+    fn test_metrics_labels_are_capped() {
+        let metrics = ClientMetrics::new();
+        for i in 0..MAX_AGENT_LABELS + 5 {
+            let client = ClientInfo {
+                transport: Transport::Http,
+                protocol_version: 0,
+                agent: Some(format!("git/2.{}.0", i)),
+                user_agent: None,
+            };
+            metrics.record(RepositoryEventType::Fetch, &client);
+        }
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.agents.len(), MAX_AGENT_LABELS + 1);
+        let other = snapshot.agents.iter().find(|a| a.agent == "other").unwrap();
+        assert_eq!(other.count, 5);
+    }
+}
@@ -0,0 +1,106 @@
+use actix_web::{http::header, HttpResponse};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+/// Seconds a clone turned away from a full queue is asked to wait
+pub const CLONE_RETRY_AFTER_SECS: u64 = 10;
+
+/// Caps the upload-packs running against one repository at a time, so a
+/// stampede of clones queues instead of every one building a pack at once
+pub struct CloneLimiter {
+    /// Upload-packs per repository, `None` for no limit
+    max_concurrent: Option<usize>,
+    /// How long a clone waits for a slot before being told to retry
+    queue_timeout: Duration,
+    semaphores: Mutex<HashMap<Uuid, Arc<Semaphore>>>,
+}
+
+/// A running upload-pack's slot, given back when dropped
+pub struct ClonePermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl CloneLimiter {
+    pub fn new(max_concurrent: Option<usize>, queue_timeout: Duration) -> Self {
+        Self {
+            max_concurrent,
+            queue_timeout,
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait for a slot for an upload-pack of `repository_id`, or `None` when
+    /// none came free within the queue timeout
+    pub async fn acquire(&self, repository_id: Uuid) -> Option<ClonePermit> {
+        let Some(max_concurrent) = self.max_concurrent else {
+            return Some(ClonePermit { _permit: None });
+        };
+
+        let semaphore = {
+            let mut semaphores = self.semaphores.lock().unwrap();
+            // Permits and waiters hold a reference, so a semaphore only the map
+            // holds belongs to a repository nobody is cloning
+            semaphores.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+            semaphores
+                .entry(repository_id)
+                .or_insert_with(|| Arc::new(Semaphore::new(max_concurrent)))
+                .clone()
+        };
+        match tokio::time::timeout(self.queue_timeout, semaphore.acquire_owned()).await {
+            Ok(Ok(permit)) => Some(ClonePermit { _permit: Some(permit) }),
+            _ => None,
+        }
+    }
+
+    /// 503 with `Retry-After` for a clone that waited too long
+    pub fn busy_response() -> HttpResponse {
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, CLONE_RETRY_AFTER_SECS.to_string()))
+            .content_type("text/plain")
+            .body("too many clones of this repository in progress, retry\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_clones_past_the_limit_queue_until_a_slot_frees() {
+        let limiter = Arc::new(CloneLimiter::new(Some(2), Duration::from_secs(5)));
+        let repository_id = Uuid::new_v4();
+        let first = limiter.acquire(repository_id).await.unwrap();
+        let _second = limiter.acquire(repository_id).await.unwrap();
+
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(repository_id).await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        // Other repositories have slots of their own
+        assert!(limiter.acquire(Uuid::new_v4()).await.is_some());
+
+        drop(first);
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_clones_are_rejected_when_the_queue_times_out() {
+        let limiter = CloneLimiter::new(Some(1), Duration::from_millis(20));
+        let repository_id = Uuid::new_v4();
+        let _running = limiter.acquire(repository_id).await.unwrap();
+
+        assert!(limiter.acquire(repository_id).await.is_none());
+
+        let unlimited = CloneLimiter::new(None, Duration::ZERO);
+        let mut permits = Vec::new();
+        for _ in 0..10 {
+            permits.push(unlimited.acquire(repository_id).await.unwrap());
+        }
+    }
+}
@@ -1,18 +1,215 @@
+use crate::authorized_keys::DEFAULT_SSH_KEY_TYPES;
+use crate::guardrails::ReadGuardrails;
+use crate::http::{RECEIVE_PACK_CAPABILITIES, UPLOAD_PACK_CAPABILITIES};
+use crate::quota::UserQuota;
+use chrono::{DateTime, Utc};
+use git_protocol::HashAlgorithm;
+use git_storage::entities::{repository, user};
+use git_storage::{
+    ReadLimits, RetentionConfig, RetentionPolicy, WriteLockScope, DEFAULT_PASSWORD_HASH_COST,
+    DEFAULT_BLOB_SHARD_DEPTH, DEFAULT_PRUNE_BATCH_SIZE, DEFAULT_PRUNE_MAX_BATCHES,
+};
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
 
+/// Ref count above which an advertisement is logged as oversized
+pub const DEFAULT_MAX_ADVERTISED_REFS: usize = 10_000;
+
+/// Days reflog entries are kept, as `git gc` does by default
+pub const DEFAULT_REF_LOG_MAX_AGE_DAYS: u64 = 90;
+
+/// Days repository events are kept
+pub const DEFAULT_EVENTS_MAX_AGE_DAYS: u64 = 365;
+
+/// Days a deleted repository can be restored before it is purged
+pub const DEFAULT_DELETED_REPOSITORY_RETENTION_DAYS: u64 = 7;
+
+/// Commits one history or ancestry walk may load
+pub const DEFAULT_MAX_COMMITS_WALKED: u64 = 50_000;
+
+/// Tree entries one diff side may read, subtrees included
+pub const DEFAULT_MAX_TREE_ENTRIES: u64 = 200_000;
+
+/// Blob size above which diffs summarize a file instead of showing its lines
+pub const DEFAULT_MAX_DIFF_BYTES: u64 = 1024 * 1024;
+
+/// Patch text one diff may render before the remaining files are left out
+pub const DEFAULT_MAX_DIFF_TOTAL_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Seconds an expensive read may run before it is abandoned
+pub const DEFAULT_READ_TIMEOUT_SECS: u64 = 30;
+
+/// zlib's default level, matching what packs used before it was configurable
+pub const DEFAULT_PACK_COMPRESSION_LEVEL: u32 = 6;
+
+/// Disk space for cached fetch packs
+pub const DEFAULT_PACK_CACHE_MAX_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Seconds a clone waits for a free slot before getting a 503
+pub const DEFAULT_CLONE_QUEUE_TIMEOUT_SECS: u64 = 30;
+
+/// Seconds a pushed commit's dates may run ahead of the server's clock
+pub const DEFAULT_MAX_COMMIT_DATE_SKEW_SECS: u64 = 24 * 60 * 60;
+
+/// Largest file the raw contents upload accepts
+pub const DEFAULT_MAX_UPLOAD_BYTES: usize = 100 * 1024 * 1024;
+
+/// Seconds a client has to send a request's headers
+pub const DEFAULT_CLIENT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// Seconds a closing connection gets to shut down cleanly
+pub const DEFAULT_CLIENT_DISCONNECT_TIMEOUT_SECS: u64 = 5;
+
+/// Seconds an idle connection is kept open for the next request
+pub const DEFAULT_KEEP_ALIVE_SECS: u64 = 75;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub database_url: String,
+    /// Apply pending migrations at startup; with it off, a server finding any
+    /// refuses to start until `git-server migrate` has run
+    pub migrate_on_start: bool,
     pub http_bind_address: String,
+    /// HTTP worker threads, `None` for one per CPU core
+    pub http_workers: Option<usize>,
+    /// How long a client has to send a request's headers before getting a 408.
+    /// Only the head is timed, so long clones and pushes are unaffected; `None`
+    /// waits forever
+    pub client_request_timeout: Option<Duration>,
+    /// How long a closing connection gets to shut down, `None` to wait forever
+    pub client_disconnect_timeout: Option<Duration>,
+    /// How long an idle connection is kept open, `None` to close after each request
+    pub keep_alive: Option<Duration>,
     pub ssh_bind_address: String,
+    /// Public base URL of the server, used for every absolute URL we hand out
+    pub external_url: String,
+    /// How long SQLite waits on a locked database before failing
+    pub sqlite_busy_timeout_ms: u64,
+    /// Whether writes are serialized per repository or server-wide (SQLite only)
+    pub write_lock_scope: WriteLockScope,
+    /// How long a push waits for the write lock before being told to retry
+    pub write_lock_timeout_ms: u64,
+    /// zlib level (0-9) for packs sent to clients
+    pub pack_compression_level: u32,
+    /// Disk space for cached fetch packs, 0 to build every pack afresh
+    pub pack_cache_max_bytes: u64,
+    /// Upload-packs allowed to run at once against one repository, `None` for no limit
+    pub max_concurrent_clones: Option<u64>,
+    /// How long a clone past that limit waits before getting a 503
+    pub clone_queue_timeout_secs: u64,
+    /// Largest single object a push may carry, once inflated; `None` for no limit
+    pub max_object_bytes: Option<u64>,
+    /// Body limit of the raw contents upload; JSON bodies have fixed, smaller limits
+    pub max_upload_bytes: usize,
+    /// bcrypt cost for new password hashes
+    pub password_hash_cost: u32,
+    /// How long an Idempotency-Key replays the original create response
+    pub idempotency_key_ttl_secs: u64,
+    /// Oldest client agent (e.g. `git/2.30.0`) allowed to push over protocol v0
+    pub min_push_agent: Option<String>,
+    /// Soft limit on advertised refs; larger advertisements are still served but logged
+    pub max_advertised_refs: usize,
+    /// Reject new refs that differ from an existing one only in case
+    pub case_insensitive_refs: bool,
+    /// Refuse pushes carrying commits dated further ahead than the skew, rather
+    /// than only logging them
+    pub reject_future_commits: bool,
+    pub max_commit_date_skew_secs: u64,
+    /// Advertise refs from a cached packed-refs blob instead of reading every ref row
+    pub packed_refs: bool,
+    /// Directory levels new blob files are sharded into (`ab/cd/...` for 2)
+    pub blob_shard_depth: usize,
+    /// Object format of repositories created without one
+    pub default_object_format: repository::ObjectFormat,
+    /// Ref prefixes (e.g. `refs/pull/*`) hidden from every repository's advertisement
+    pub hidden_ref_prefixes: Vec<String>,
+    /// Let clients fetch a hidden ref's tip by id when they already know it
+    pub allow_hidden_ref_wants: bool,
+    /// Refuse anonymous clones and fetches everywhere, whatever a repository allows
+    pub require_auth: bool,
+    /// Reflog retention; the newest entry of each ref is always kept
+    pub ref_log_max_age_days: Option<u64>,
+    pub ref_log_max_rows_per_repository: Option<u64>,
+    /// Push, fetch and settings event retention
+    pub events_max_age_days: Option<u64>,
+    pub events_max_rows_per_repository: Option<u64>,
+    /// Days soft-deleted repositories are kept for restoring; the pruning job
+    /// purges them after that
+    pub deleted_repository_retention_days: u64,
+    /// Seconds between pruning runs, 0 to disable the job
+    pub retention_interval_secs: u64,
+    /// Rows deleted per statement while pruning
+    pub retention_batch_size: u64,
+    /// Batches per table per run before the rest waits for the next run
+    pub retention_max_batches: u32,
+    /// Read guardrails for history and diff; admins can override them per repository
+    pub max_commits_walked: Option<u64>,
+    pub max_tree_entries: Option<u64>,
+    pub read_timeout_secs: Option<u64>,
+    /// Files larger than this on either side of a diff are summarized, not line-diffed
+    pub max_diff_bytes: Option<u64>,
+    /// Patches are cut off, whole files at a time, past this many bytes
+    pub max_diff_total_bytes: Option<u64>,
+    /// Quotas for each non-admin user; admins can override them per user
+    pub max_repositories_per_user: Option<u64>,
+    pub max_bytes_per_user: Option<u64>,
+    /// SSH key algorithms users may register, e.g. `ssh-ed25519`
+    pub ssh_key_types: Vec<String>,
+    /// Protocol features that can be switched off
+    pub protocol: ProtocolSettings,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             database_url: "sqlite:./git_server.db".to_string(),
+            migrate_on_start: true,
             http_bind_address: "127.0.0.1:8080".to_string(),
+            http_workers: None,
+            client_request_timeout: Some(Duration::from_secs(DEFAULT_CLIENT_REQUEST_TIMEOUT_SECS)),
+            client_disconnect_timeout: Some(Duration::from_secs(DEFAULT_CLIENT_DISCONNECT_TIMEOUT_SECS)),
+            keep_alive: Some(Duration::from_secs(DEFAULT_KEEP_ALIVE_SECS)),
             ssh_bind_address: "127.0.0.1:2222".to_string(),
+            external_url: "http://localhost:8080".to_string(),
+            sqlite_busy_timeout_ms: 5000,
+            write_lock_scope: WriteLockScope::Repository,
+            write_lock_timeout_ms: 30000,
+            pack_compression_level: DEFAULT_PACK_COMPRESSION_LEVEL,
+            pack_cache_max_bytes: DEFAULT_PACK_CACHE_MAX_BYTES,
+            max_concurrent_clones: None,
+            clone_queue_timeout_secs: DEFAULT_CLONE_QUEUE_TIMEOUT_SECS,
+            max_object_bytes: None,
+            max_upload_bytes: DEFAULT_MAX_UPLOAD_BYTES,
+            password_hash_cost: DEFAULT_PASSWORD_HASH_COST,
+            idempotency_key_ttl_secs: 86400,
+            min_push_agent: None,
+            max_advertised_refs: DEFAULT_MAX_ADVERTISED_REFS,
+            case_insensitive_refs: false,
+            reject_future_commits: false,
+            max_commit_date_skew_secs: DEFAULT_MAX_COMMIT_DATE_SKEW_SECS,
+            packed_refs: false,
+            blob_shard_depth: DEFAULT_BLOB_SHARD_DEPTH,
+            default_object_format: repository::ObjectFormat::Sha1,
+            hidden_ref_prefixes: Vec::new(),
+            allow_hidden_ref_wants: false,
+            require_auth: false,
+            ref_log_max_age_days: Some(DEFAULT_REF_LOG_MAX_AGE_DAYS),
+            ref_log_max_rows_per_repository: None,
+            events_max_age_days: Some(DEFAULT_EVENTS_MAX_AGE_DAYS),
+            events_max_rows_per_repository: None,
+            deleted_repository_retention_days: DEFAULT_DELETED_REPOSITORY_RETENTION_DAYS,
+            retention_interval_secs: 3600,
+            retention_batch_size: DEFAULT_PRUNE_BATCH_SIZE,
+            retention_max_batches: DEFAULT_PRUNE_MAX_BATCHES,
+            max_commits_walked: Some(DEFAULT_MAX_COMMITS_WALKED),
+            max_tree_entries: Some(DEFAULT_MAX_TREE_ENTRIES),
+            read_timeout_secs: Some(DEFAULT_READ_TIMEOUT_SECS),
+            max_diff_bytes: Some(DEFAULT_MAX_DIFF_BYTES),
+            max_diff_total_bytes: Some(DEFAULT_MAX_DIFF_TOTAL_BYTES),
+            max_repositories_per_user: None,
+            max_bytes_per_user: None,
+            ssh_key_types: DEFAULT_SSH_KEY_TYPES.iter().map(|t| t.to_string()).collect(),
+            protocol: ProtocolSettings::default(),
         }
     }
 }
@@ -22,10 +219,441 @@ impl Config {
         Self {
             database_url: std::env::var("DATABASE_URL")
                 .unwrap_or_else(|_| "sqlite:./git_server.db".to_string()),
-            http_bind_address: std::env::var("HTTP_BIND_ADDRESS")
+            migrate_on_start: std::env::var("MIGRATE_ON_START")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(true),
+            http_bind_address: std::env::var("BIND_ADDRESS")
                 .unwrap_or_else(|_| "127.0.0.1:8080".to_string()),
+            http_workers: env_limit("HTTP_WORKERS", None)
+                .map(|workers| usize::try_from(workers).unwrap_or(usize::MAX)),
+            client_request_timeout: env_limit(
+                "CLIENT_REQUEST_TIMEOUT_SECS",
+                Some(DEFAULT_CLIENT_REQUEST_TIMEOUT_SECS),
+            )
+            .map(Duration::from_secs),
+            client_disconnect_timeout: env_limit(
+                "CLIENT_DISCONNECT_TIMEOUT_SECS",
+                Some(DEFAULT_CLIENT_DISCONNECT_TIMEOUT_SECS),
+            )
+            .map(Duration::from_secs),
+            keep_alive: env_limit("KEEP_ALIVE_SECS", Some(DEFAULT_KEEP_ALIVE_SECS))
+                .map(Duration::from_secs),
             ssh_bind_address: std::env::var("SSH_BIND_ADDRESS")
                 .unwrap_or_else(|_| "127.0.0.1:2222".to_string()),
+            external_url: std::env::var("EXTERNAL_URL")
+                .unwrap_or_else(|_| "http://localhost:8080".to_string()),
+            sqlite_busy_timeout_ms: std::env::var("SQLITE_BUSY_TIMEOUT_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5000),
+            write_lock_scope: std::env::var("WRITE_LOCK_SCOPE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(WriteLockScope::Repository),
+            write_lock_timeout_ms: std::env::var("WRITE_LOCK_TIMEOUT_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30000),
+            pack_compression_level: std::env::var("PACK_COMPRESSION_LEVEL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|level| *level <= 9)
+                .unwrap_or(DEFAULT_PACK_COMPRESSION_LEVEL),
+            pack_cache_max_bytes: std::env::var("PACK_CACHE_MAX_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_PACK_CACHE_MAX_BYTES),
+            max_concurrent_clones: env_limit("MAX_CONCURRENT_CLONES", None),
+            clone_queue_timeout_secs: std::env::var("CLONE_QUEUE_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_CLONE_QUEUE_TIMEOUT_SECS),
+            max_object_bytes: env_limit("MAX_OBJECT_BYTES", None),
+            max_upload_bytes: std::env::var("MAX_UPLOAD_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES),
+            password_hash_cost: std::env::var("PASSWORD_HASH_COST")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_PASSWORD_HASH_COST),
+            idempotency_key_ttl_secs: std::env::var("IDEMPOTENCY_KEY_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(86400),
+            min_push_agent: std::env::var("MIN_PUSH_AGENT").ok().filter(|v| !v.is_empty()),
+            max_advertised_refs: std::env::var("MAX_ADVERTISED_REFS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_ADVERTISED_REFS),
+            case_insensitive_refs: std::env::var("CASE_INSENSITIVE_REFS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            reject_future_commits: std::env::var("REJECT_FUTURE_COMMITS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            max_commit_date_skew_secs: std::env::var("MAX_COMMIT_DATE_SKEW_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_COMMIT_DATE_SKEW_SECS),
+            packed_refs: std::env::var("PACKED_REFS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            blob_shard_depth: std::env::var("BLOB_SHARD_DEPTH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_BLOB_SHARD_DEPTH),
+            default_object_format: std::env::var("DEFAULT_OBJECT_FORMAT")
+                .ok()
+                .and_then(|v| HashAlgorithm::from_name(&v))
+                .map(repository::ObjectFormat::from)
+                .unwrap_or(repository::ObjectFormat::Sha1),
+            hidden_ref_prefixes: std::env::var("HIDDEN_REFS")
+                .map(|v| v.split(',').filter_map(HiddenRefs::normalize_prefix).collect())
+                .unwrap_or_default(),
+            allow_hidden_ref_wants: std::env::var("ALLOW_HIDDEN_REF_WANTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            require_auth: std::env::var("REQUIRE_AUTH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            ref_log_max_age_days: env_limit("REF_LOG_MAX_AGE_DAYS", Some(DEFAULT_REF_LOG_MAX_AGE_DAYS)),
+            ref_log_max_rows_per_repository: env_limit("REF_LOG_MAX_ROWS_PER_REPO", None),
+            events_max_age_days: env_limit("EVENTS_MAX_AGE_DAYS", Some(DEFAULT_EVENTS_MAX_AGE_DAYS)),
+            events_max_rows_per_repository: env_limit("EVENTS_MAX_ROWS_PER_REPO", None),
+            deleted_repository_retention_days: std::env::var("DELETED_REPOSITORY_RETENTION_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_DELETED_REPOSITORY_RETENTION_DAYS),
+            retention_interval_secs: std::env::var("RETENTION_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(3600),
+            retention_batch_size: std::env::var("RETENTION_BATCH_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|size| *size > 0)
+                .unwrap_or(DEFAULT_PRUNE_BATCH_SIZE),
+            retention_max_batches: std::env::var("RETENTION_MAX_BATCHES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|batches| *batches > 0)
+                .unwrap_or(DEFAULT_PRUNE_MAX_BATCHES),
+            max_commits_walked: env_limit("MAX_COMMITS_WALKED", Some(DEFAULT_MAX_COMMITS_WALKED)),
+            max_tree_entries: env_limit("MAX_TREE_ENTRIES", Some(DEFAULT_MAX_TREE_ENTRIES)),
+            read_timeout_secs: env_limit("READ_TIMEOUT_SECS", Some(DEFAULT_READ_TIMEOUT_SECS)),
+            max_diff_bytes: env_limit("MAX_DIFF_BYTES", Some(DEFAULT_MAX_DIFF_BYTES)),
+            max_diff_total_bytes: env_limit("MAX_DIFF_TOTAL_BYTES", Some(DEFAULT_MAX_DIFF_TOTAL_BYTES)),
+            max_repositories_per_user: env_limit("MAX_REPOSITORIES_PER_USER", None),
+            max_bytes_per_user: env_limit("MAX_BYTES_PER_USER", None),
+            ssh_key_types: std::env::var("SSH_KEY_TYPES")
+                .map(|v| v.split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect())
+                .unwrap_or_else(|_| DEFAULT_SSH_KEY_TYPES.iter().map(|t| t.to_string()).collect()),
+            protocol: ProtocolSettings::from_env(),
+        }
+    }
+
+    /// Pruning settings for the storage layer
+    pub fn retention_config(&self) -> RetentionConfig {
+        let days = |d: Option<u64>| d.map(|d| Duration::from_secs(d * 24 * 60 * 60));
+        RetentionConfig {
+            ref_log: RetentionPolicy {
+                max_age: days(self.ref_log_max_age_days),
+                max_rows_per_repository: self.ref_log_max_rows_per_repository,
+            },
+            events: RetentionPolicy {
+                max_age: days(self.events_max_age_days),
+                max_rows_per_repository: self.events_max_rows_per_repository,
+            },
+            batch_size: self.retention_batch_size,
+            max_batches_per_run: self.retention_max_batches,
+        }
+    }
+
+    /// When a repository soft-deleted at `deleted_at` becomes due for purging
+    pub fn purge_after(&self, deleted_at: DateTime<Utc>) -> DateTime<Utc> {
+        deleted_at + chrono::Duration::days(self.deleted_repository_retention_days as i64)
+    }
+
+    /// Repositories soft-deleted before this are due for purging at `now`
+    pub fn purge_cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - chrono::Duration::days(self.deleted_repository_retention_days as i64)
+    }
+
+    /// Refs hidden in a repository: the server-wide prefixes plus the repository's own
+    pub fn hidden_refs(&self, repo: &repository::Model) -> HiddenRefs {
+        HiddenRefs::new(
+            self.hidden_ref_prefixes
+                .iter()
+                .map(String::as_str)
+                .chain(repo.hidden_ref_prefixes()),
+        )
+    }
+
+    /// Read guardrails for a repository: its admin overrides where set, otherwise
+    /// the server-wide settings. An override of 0 lifts that limit.
+    pub fn read_guardrails(&self, repo: &repository::Model) -> ReadGuardrails {
+        let limit = |repo_value: Option<i32>, server_value: Option<u64>| match repo_value {
+            Some(value) => u64::try_from(value).ok().filter(|v| *v > 0),
+            None => server_value,
+        };
+        let to_usize = |value: Option<u64>| value.map(|v| usize::try_from(v).unwrap_or(usize::MAX));
+
+        ReadGuardrails {
+            limits: ReadLimits {
+                max_commits: to_usize(limit(repo.max_commits_walked, self.max_commits_walked)),
+                max_tree_entries: to_usize(limit(repo.max_tree_entries, self.max_tree_entries)),
+                max_diff_bytes: to_usize(self.max_diff_bytes),
+                max_diff_total_bytes: to_usize(self.max_diff_total_bytes),
+            },
+            timeout: limit(repo.read_timeout_secs, self.read_timeout_secs).map(Duration::from_secs),
+        }
+    }
+
+    /// Quota for a user: their admin overrides where set, otherwise the server-wide
+    /// settings. An override of 0 lifts that limit, and admins have none.
+    pub fn user_quota(&self, user: &user::Model) -> UserQuota {
+        if user.is_admin {
+            return UserQuota::default();
+        }
+        let limit = |user_value: Option<i64>, server_value: Option<u64>| match user_value {
+            Some(value) => u64::try_from(value).ok().filter(|v| *v > 0),
+            None => server_value,
+        };
+        UserQuota {
+            max_repositories: limit(user.max_repositories.map(i64::from), self.max_repositories_per_user),
+            max_total_bytes: limit(user.max_total_bytes, self.max_bytes_per_user),
+        }
+    }
+
+    /// Build an absolute URL for a server path, honouring any path prefix in `external_url`
+    pub fn absolute_url(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.external_url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+
+    /// Host name clients should use, taken from `external_url`
+    pub fn external_host(&self) -> String {
+        let without_scheme = self
+            .external_url
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .unwrap_or(&self.external_url);
+        let authority = without_scheme.split('/').next().unwrap_or_default();
+        let authority = authority.rsplit_once('@').map(|(_, host)| host).unwrap_or(authority);
+
+        if authority.starts_with('[') {
+            // IPv6 literal, keep the brackets
+            match authority.find(']') {
+                Some(end) => authority[..=end].to_string(),
+                None => authority.to_string(),
+            }
+        } else {
+            authority.split(':').next().unwrap_or_default().to_string()
+        }
+    }
+
+    /// Port the SSH server listens on
+    pub fn ssh_port(&self) -> u16 {
+        self.ssh_bind_address
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or(22)
+    }
+
+    /// HTTP clone URL for a repository, under its owner
+    pub fn http_clone_url(&self, owner: &str, repo_name: &str) -> String {
+        self.absolute_url(&format!("{}/{}", owner, repo_name))
+    }
+
+    /// SSH clone URL for a repository, under its owner
+    pub fn ssh_clone_url(&self, owner: &str, repo_name: &str) -> String {
+        format!("ssh://git@{}:{}/{}/{}", self.external_host(), self.ssh_port(), owner, repo_name)
+    }
+}
+
+/// Protocol features operators can switch off. A feature that is off is neither
+/// advertised nor accepted, over HTTP or SSH.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolSettings {
+    /// Let clients that ask for protocol v2 have it. The server only speaks v0
+    /// for now, so this can't turn v2 on yet, only keep it off.
+    pub enable_v2: bool,
+    /// `deepen <n>` fetches, as `git clone --depth` sends
+    pub allow_shallow: bool,
+    /// `blob:none` and `blob:limit=<n>` fetch filters, for partial clones
+    pub allow_filters: bool,
+    /// Ref deletions pushed through receive-pack
+    pub allow_deletes: bool,
+    /// Advertised as they are, after the server's own capabilities
+    pub extra_capabilities: Vec<String>,
+}
+
+impl Default for ProtocolSettings {
+    fn default() -> Self {
+        Self {
+            enable_v2: true,
+            allow_shallow: true,
+            allow_filters: true,
+            allow_deletes: true,
+            extra_capabilities: Vec::new(),
+        }
+    }
+}
+
+impl ProtocolSettings {
+    pub fn from_env() -> Self {
+        let flag = |name: &str| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(true);
+        Self {
+            enable_v2: flag("PROTOCOL_ENABLE_V2"),
+            allow_shallow: flag("PROTOCOL_ALLOW_SHALLOW"),
+            allow_filters: flag("PROTOCOL_ALLOW_FILTERS"),
+            allow_deletes: flag("PROTOCOL_ALLOW_DELETES"),
+            extra_capabilities: std::env::var("PROTOCOL_EXTRA_CAPABILITIES")
+                .map(|v| v.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Capabilities advertised for git-upload-pack
+    pub fn upload_pack_capabilities(&self) -> Vec<String> {
+        let mut capabilities: Vec<String> = UPLOAD_PACK_CAPABILITIES.iter().map(|c| c.to_string()).collect();
+        if self.allow_shallow {
+            capabilities.push("shallow".to_string());
+        }
+        if self.allow_filters {
+            capabilities.push("filter".to_string());
+        }
+        capabilities.extend(self.extra_capabilities.iter().cloned());
+        capabilities
+    }
+
+    /// Capabilities advertised for git-receive-pack
+    pub fn receive_pack_capabilities(&self) -> Vec<String> {
+        RECEIVE_PACK_CAPABILITIES
+            .iter()
+            .filter(|c| self.allow_deletes || **c != "delete-refs")
+            .map(|c| c.to_string())
+            .chain(self.extra_capabilities.iter().cloned())
+            .collect()
+    }
+}
+
+/// Ref namespaces left out of advertisements and closed to fetches and non-admin
+/// pushes, with git's `transfer.hideRefs` prefix semantics
+#[derive(Debug, Clone, Default)]
+pub struct HiddenRefs {
+    prefixes: Vec<String>,
+}
+
+impl HiddenRefs {
+    pub fn new<'a>(prefixes: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            prefixes: prefixes.into_iter().filter_map(Self::normalize_prefix).collect(),
+        }
+    }
+
+    /// `refs/pull/*`, `refs/pull/` and `refs/pull` all hide the same namespace.
+    /// Returns `None` for anything outside `refs/`.
+    pub fn normalize_prefix(prefix: &str) -> Option<String> {
+        let prefix = prefix.trim().trim_end_matches('*').trim_end_matches('/');
+        (prefix.starts_with("refs/") && prefix.len() > "refs/".len()).then(|| prefix.to_string())
+    }
+
+    /// Whether `name` is one of the prefixes or lies under one
+    pub fn is_hidden(&self, name: &str) -> bool {
+        self.prefixes.iter().any(|prefix| {
+            name.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+}
+
+/// Optional limit from the environment; `0` means no limit, unset keeps the default
+fn env_limit(name: &str, default: Option<u64>) -> Option<u64> {
+    match std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok()) {
+        Some(0) => None,
+        Some(limit) => Some(limit),
+        None => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clone_urls() {
+        let config = Config {
+            external_url: "https://git.example.com".to_string(),
+            ssh_bind_address: "0.0.0.0:2222".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(config.http_clone_url("alice", "my-repo"), "https://git.example.com/alice/my-repo");
+        assert_eq!(config.ssh_clone_url("alice", "my-repo"), "ssh://git@git.example.com:2222/alice/my-repo");
+    }
+
+    #[test]
+    fn test_clone_urls_with_path_prefix() {
+        let config = Config {
+            external_url: "https://example.com:8443/git-server/".to_string(),
+            ssh_bind_address: "0.0.0.0:22".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(config.absolute_url("/api/meta"), "https://example.com:8443/git-server/api/meta");
+        assert_eq!(config.http_clone_url("alice", "my-repo"), "https://example.com:8443/git-server/alice/my-repo");
+        assert_eq!(config.ssh_clone_url("alice", "my-repo"), "ssh://git@example.com:22/alice/my-repo");
+    }
+
+    #[test]
+    fn test_http_server_settings_from_env() {
+        let vars = [
+            "HTTP_WORKERS",
+            "CLIENT_REQUEST_TIMEOUT_SECS",
+            "CLIENT_DISCONNECT_TIMEOUT_SECS",
+            "KEEP_ALIVE_SECS",
+        ];
+        for var in vars {
+            std::env::remove_var(var);
+        }
+        let config = Config::from_env();
+        assert_eq!(config.http_workers, None);
+        assert_eq!(config.client_request_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.client_disconnect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(config.keep_alive, Some(Duration::from_secs(75)));
+
+        std::env::set_var("HTTP_WORKERS", "4");
+        std::env::set_var("CLIENT_REQUEST_TIMEOUT_SECS", "0");
+        std::env::set_var("CLIENT_DISCONNECT_TIMEOUT_SECS", "10");
+        std::env::set_var("KEEP_ALIVE_SECS", "not-a-number");
+        let config = Config::from_env();
+        for var in vars {
+            std::env::remove_var(var);
         }
+        assert_eq!(config.http_workers, Some(4));
+        assert_eq!(config.client_request_timeout, None);
+        assert_eq!(config.client_disconnect_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(config.keep_alive, Some(Duration::from_secs(75)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_hidden_ref_prefixes() {
+        let hidden = HiddenRefs::new(["refs/pull/*", " refs/notes/ ", "heads", "refs/"]);
+        assert!(hidden.is_hidden("refs/pull/1/head"));
+        assert!(hidden.is_hidden("refs/notes/commits"));
+        assert!(hidden.is_hidden("refs/notes"));
+        assert!(!hidden.is_hidden("refs/pulls/1"));
+        assert!(!hidden.is_hidden("refs/heads/main"));
+    }
+}
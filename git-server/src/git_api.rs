@@ -1,8 +1,32 @@
+use crate::config::HiddenRefs;
+use crate::http::{
+    caller_user, can_read, idempotency_key, record_idempotent_response, replay_response, RepositoryResponse,
+    ARCHIVED_MESSAGE,
+};
+use crate::guardrails::ReadGuardrails;
+use crate::payload::ApiJson;
+use crate::permissions::Permissions;
+use crate::quota;
+use crate::scopes::{AuthenticatedUser, Caller, Scope};
 use crate::AppState;
-use actix_web::{web, HttpResponse, Result, get, post, delete};
-use actix_session::Session;
+use actix_files::HttpRange;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use git_protocol::hash::is_object_id;
+use git_protocol::batch::{encode_batch, BatchEntry, BATCH_MEDIA_TYPE};
+use git_protocol::text::{self, TextInfo};
+use git_protocol::ObjectType;
+use actix_web::{web, http::{header, StatusCode}, HttpRequest, HttpResponse, Result, get, post, put, delete};
 use serde::{Deserialize, Serialize};
-use git_storage::{GitOperations, CreateCommitRequest, MergeRequest};
+use git_storage::diff::{DiffFormat, DiffOptions, DEFAULT_RENAME_THRESHOLD};
+use git_storage::{
+    BackfillReport, CheckOptions, ProjectionReport, CheckReport, CommitStatusState, FileChange, GitOperations, CreateCommitRequest, HistoryOptions, MergeRequest, MergeStrategy,
+    NewCommitStatus, ReadLimitOverrides, StorageError, DEFAULT_NOTES_REF, resolve_revision,
+    bracketed_emails, commit_emails, IdentityMap,
+    Activity, ActivityEvent, MergeEventPayload, NewRepositoryEvent, RefEventPayload, RepositoryEventType, DEFAULT_ACTIVITY_LIMIT,
+};
+use git_storage::entities::git_object::ObjectKind;
+use git_storage::entities::repository;
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize)]
@@ -28,21 +52,10 @@ pub struct ApiResponse<T> {
 #[get("/repositories/{repo_id}/branches")]
 pub async fn list_branches(
     path: web::Path<String>,
-    session: Session,
+    query: web::Query<ListBranchesQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    // Check authentication
-    let _user_id = match get_authenticated_user(&session) {
-        Some(id) => id,
-        None => {
-            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: "Authentication required".to_string(),
-            }));
-        }
-    };
-
     let repo_id = match Uuid::parse_str(&path) {
         Ok(id) => id,
         Err(_) => {
@@ -54,32 +67,45 @@ pub async fn list_branches(
         }
     };
 
-    // Check repository access (simplified - in production, check permissions)
-    match state.repository_service.get_repository_by_id(repo_id).await {
-        Ok(Some(_)) => {
-            let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
-            match git_ops.list_branches(repo_id).await {
-                Ok(branches) => Ok(HttpResponse::Ok().json(ApiResponse {
-                    success: true,
-                    data: Some(branches),
-                    message: "Branches retrieved successfully".to_string(),
-                })),
-                Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                    success: false,
-                    data: None,
-                    message: format!("Failed to list branches: {}", e),
-                })),
+    let repo = match readable_objects_repository(&state, Some(user_id), repo_id).await {
+        Ok(repo) => repo,
+        Err(response) => return Ok(response),
+    };
+    let guardrails = state.config.read_guardrails(&repo);
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone())
+        .with_limits(guardrails.limits);
+    let compare = query.compare.unwrap_or(false);
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.list_branches(state.repository_service.get_db(), repo_id, compare),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(mut branches) => {
+            let emails = branches.iter().flat_map(|b| bracketed_emails(&b.author));
+            let identities = match resolve_identities(&state, emails).await {
+                Ok(identities) => identities,
+                Err(response) => return Ok(response),
+            };
+            let host = state.config.external_host();
+            for branch in &mut branches {
+                branch.identify(&identities, &host);
             }
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(branches),
+                message: "Branches retrieved successfully".to_string(),
+            }))
         }
-        Ok(None) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
-            success: false,
-            data: None,
-            message: "Repository not found".to_string(),
-        })),
         Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
             success: false,
             data: None,
-            message: format!("Database error: {}", e),
+            message: format!("Failed to list branches: {}", e),
         })),
     }
 }
@@ -88,21 +114,11 @@ pub async fn list_branches(
 #[post("/repositories/{repo_id}/branches")]
 pub async fn create_branch(
     path: web::Path<String>,
-    body: web::Json<CreateBranchRequest>,
-    session: Session,
+    body: ApiJson<CreateBranchRequest>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let _user_id = match get_authenticated_user(&session) {
-        Some(id) => id,
-        None => {
-            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: "Authentication required".to_string(),
-            }));
-        }
-    };
-
     let repo_id = match Uuid::parse_str(&path) {
         Ok(id) => id,
         Err(_) => {
@@ -114,6 +130,10 @@ pub async fn create_branch(
         }
     };
 
+    if let Err(response) = writable_repository(&state, &caller, repo_id, Scope::RepoWrite).await {
+        return Ok(response);
+    }
+
     let req = body.into_inner();
 
     // Validate branch name
@@ -126,13 +146,24 @@ pub async fn create_branch(
     }
 
     let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
-    match git_ops.create_branch(repo_id, req.name, req.start_commit).await {
-        Ok(branch_info) => Ok(HttpResponse::Created().json(ApiResponse {
-            success: true,
-            data: Some(branch_info),
-            message: "Branch created successfully".to_string(),
-        })),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+    let result = state
+        .repository_service
+        .transaction(move |txn| Box::pin(async move { git_ops.create_branch(txn, repo_id, req.name, req.start_commit).await }))
+        .await;
+    match result {
+        Ok(branch_info) => {
+            let payload = RefEventPayload {
+                name: format!("refs/heads/{}", branch_info.name),
+                target: branch_info.commit_hash.clone(),
+            };
+            record_api_event(&state, repo_id, RepositoryEventType::BranchCreated, user_id, &payload).await;
+            Ok(HttpResponse::Created().json(ApiResponse {
+                success: true,
+                data: Some(branch_info),
+                message: "Branch created successfully".to_string(),
+            }))
+        }
+        Err(e) => Ok(HttpResponse::build(ref_creation_status(&e)).json(ApiResponse::<()> {
             success: false,
             data: None,
             message: format!("Failed to create branch: {}", e),
@@ -140,24 +171,37 @@ pub async fn create_branch(
     }
 }
 
+/// Case-only name clashes are the caller's to fix; anything else is on us
+fn ref_creation_status(err: &anyhow::Error) -> StatusCode {
+    match err.downcast_ref::<StorageError>() {
+        Some(StorageError::RefNameConflict { .. }) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Store an activity event for a change made through the API. The change has
+/// already been applied, so failing to store it is only logged.
+async fn record_api_event<T: Serialize>(
+    state: &AppState,
+    repo_id: Uuid,
+    event_type: RepositoryEventType,
+    actor_id: Uuid,
+    payload: &T,
+) {
+    let event = NewRepositoryEvent::api(repo_id, event_type, Some(actor_id), payload);
+    if let Err(e) = state.event_service.record(event).await {
+        tracing::warn!("Failed to record {} event: {}", event_type.as_str(), e);
+    }
+}
+
 /// Delete a branch
 #[delete("/repositories/{repo_id}/branches/{branch_name}")]
 pub async fn delete_branch(
     path: web::Path<(String, String)>,
-    session: Session,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let _user_id = match get_authenticated_user(&session) {
-        Some(id) => id,
-        None => {
-            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: "Authentication required".to_string(),
-            }));
-        }
-    };
-
     let (repo_id_str, branch_name) = path.into_inner();
     let repo_id = match Uuid::parse_str(&repo_id_str) {
         Ok(id) => id,
@@ -170,18 +214,40 @@ pub async fn delete_branch(
         }
     };
 
+    if let Err(response) = writable_repository(&state, &caller, repo_id, Scope::RepoWrite).await {
+        return Ok(response);
+    }
+
     let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
-    match git_ops.delete_branch(repo_id, branch_name).await {
-        Ok(_) => Ok(HttpResponse::Ok().json(ApiResponse::<()> {
-            success: true,
-            data: None,
-            message: "Branch deleted successfully".to_string(),
-        })),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
-            success: false,
-            data: None,
-            message: format!("Failed to delete branch: {}", e),
-        })),
+    let name = format!("refs/heads/{}", branch_name);
+    let result = state
+        .repository_service
+        .transaction(move |txn| Box::pin(async move { git_ops.delete_branch(txn, repo_id, branch_name).await }))
+        .await;
+    match result {
+        Ok(deleted) => {
+            if let Some(target) = deleted {
+                let payload = RefEventPayload { name, target };
+                record_api_event(&state, repo_id, RepositoryEventType::BranchDeleted, user_id, &payload).await;
+            }
+            Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+                success: true,
+                data: None,
+                message: "Branch deleted successfully".to_string(),
+            }))
+        }
+        Err(e) => {
+            // A push moved the branch after it was read; retrying deletes the new tip
+            let status = match e.downcast_ref::<StorageError>() {
+                Some(StorageError::StaleRef { .. }) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Ok(HttpResponse::build(status).json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to delete branch: {}", e),
+            }))
+        }
     }
 }
 
@@ -189,20 +255,9 @@ pub async fn delete_branch(
 #[get("/repositories/{repo_id}/tags")]
 pub async fn list_tags(
     path: web::Path<String>,
-    session: Session,
+    AuthenticatedUser(user_id): AuthenticatedUser,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let _user_id = match get_authenticated_user(&session) {
-        Some(id) => id,
-        None => {
-            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: "Authentication required".to_string(),
-            }));
-        }
-    };
-
     let repo_id = match Uuid::parse_str(&path) {
         Ok(id) => id,
         Err(_) => {
@@ -214,8 +269,12 @@ pub async fn list_tags(
         }
     };
 
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
     let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
-    match git_ops.list_tags(repo_id).await {
+    match git_ops.list_tags(state.repository_service.get_db(), repo_id).await {
         Ok(tags) => Ok(HttpResponse::Ok().json(ApiResponse {
             success: true,
             data: Some(tags),
@@ -233,21 +292,11 @@ pub async fn list_tags(
 #[post("/repositories/{repo_id}/tags")]
 pub async fn create_tag(
     path: web::Path<String>,
-    body: web::Json<CreateTagRequest>,
-    session: Session,
+    body: ApiJson<CreateTagRequest>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let _user_id = match get_authenticated_user(&session) {
-        Some(id) => id,
-        None => {
-            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: "Authentication required".to_string(),
-            }));
-        }
-    };
-
     let repo_id = match Uuid::parse_str(&path) {
         Ok(id) => id,
         Err(_) => {
@@ -259,6 +308,10 @@ pub async fn create_tag(
         }
     };
 
+    if let Err(response) = writable_repository(&state, &caller, repo_id, Scope::RepoWrite).await {
+        return Ok(response);
+    }
+
     let req = body.into_inner();
 
     if req.name.trim().is_empty() {
@@ -270,13 +323,24 @@ pub async fn create_tag(
     }
 
     let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
-    match git_ops.create_lightweight_tag(repo_id, req.name, req.target_commit).await {
-        Ok(tag_info) => Ok(HttpResponse::Created().json(ApiResponse {
-            success: true,
-            data: Some(tag_info),
-            message: "Tag created successfully".to_string(),
-        })),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+    let result = state
+        .repository_service
+        .transaction(move |txn| Box::pin(async move { git_ops.create_lightweight_tag(txn, repo_id, req.name, req.target_commit).await }))
+        .await;
+    match result {
+        Ok(tag_info) => {
+            let payload = RefEventPayload {
+                name: format!("refs/tags/{}", tag_info.name),
+                target: tag_info.target_hash.clone(),
+            };
+            record_api_event(&state, repo_id, RepositoryEventType::TagCreated, user_id, &payload).await;
+            Ok(HttpResponse::Created().json(ApiResponse {
+                success: true,
+                data: Some(tag_info),
+                message: "Tag created successfully".to_string(),
+            }))
+        }
+        Err(e) => Ok(HttpResponse::build(ref_creation_status(&e)).json(ApiResponse::<()> {
             success: false,
             data: None,
             message: format!("Failed to create tag: {}", e),
@@ -287,22 +351,88 @@ pub async fn create_tag(
 /// Create a new commit
 #[post("/repositories/{repo_id}/commits")]
 pub async fn create_commit(
+    http_req: HttpRequest,
     path: web::Path<String>,
-    body: web::Json<CreateCommitRequest>,
-    session: Session,
+    body: ApiJson<CreateCommitRequest>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let _user_id = match get_authenticated_user(&session) {
-        Some(id) => id,
-        None => {
-            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
                 success: false,
                 data: None,
-                message: "Authentication required".to_string(),
+                message: "Invalid repository ID".to_string(),
             }));
         }
     };
 
+    if let Err(response) = writable_repository(&state, &caller, repo_id, Scope::RepoWrite).await {
+        return Ok(response);
+    }
+
+    // A retried request with the same key gets the original response
+    let idempotency_scope = format!("create_commit:{}:{}", repo_id, user_id);
+    let idempotency_key = idempotency_key(&http_req);
+    if let Some(key) = &idempotency_key {
+        match state.idempotency_service.get(&idempotency_scope, key).await {
+            Ok(Some(record)) => return Ok(replay_response(&record)),
+            Ok(None) => {}
+            Err(e) => {
+                return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: format!("Database error: {}", e),
+                }));
+            }
+        }
+    }
+
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+    let request = body.into_inner();
+    let result = state
+        .repository_service
+        .transaction(move |txn| Box::pin(async move { git_ops.create_commit(txn, repo_id, request).await }))
+        .await;
+    match result {
+        Ok(commit_hash) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(commit_hash.clone()),
+                message: "Commit created successfully".to_string(),
+            };
+            if let Some(key) = &idempotency_key {
+                record_idempotent_response(
+                    &state,
+                    &idempotency_scope,
+                    key,
+                    commit_hash,
+                    StatusCode::CREATED,
+                    &response,
+                )
+                .await;
+            }
+            Ok(HttpResponse::Created().json(response))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to create commit: {}", e),
+        })),
+    }
+}
+
+/// Commit file additions, edits and deletions to a branch in one call
+#[post("/repositories/{repo_id}/contents")]
+pub async fn commit_files(
+    path: web::Path<String>,
+    body: ApiJson<CommitFilesRequest>,
+    _: AuthenticatedUser,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
     let repo_id = match Uuid::parse_str(&path) {
         Ok(id) => id,
         Err(_) => {
@@ -314,40 +444,176 @@ pub async fn create_commit(
         }
     };
 
+    if let Err(response) = writable_repository(&state, &caller, repo_id, Scope::RepoWrite).await {
+        return Ok(response);
+    }
+
+    let req = body.into_inner();
+    let mut files = Vec::with_capacity(req.files.len());
+    for file in req.files {
+        let change = match (file.content, file.delete.unwrap_or(false)) {
+            (Some(content), false) => FileChange::Write(content.into_bytes()),
+            (None, true) => FileChange::Delete,
+            _ => {
+                return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: format!("'{}' needs either content or delete", file.path),
+                }));
+            }
+        };
+        files.push((file.path, change));
+    }
+
+    Ok(commit_file_changes(&state, repo_id, req.branch, files, req.author, req.message).await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RawContentsQuery {
+    pub branch: String,
+    /// Signature used as author and committer, `Name <email> <seconds> <tz>`
+    pub author: String,
+    pub message: String,
+}
+
+/// Commit one file whose new content is the request body, as is. Unlike the JSON
+/// contents endpoint the file needs no encoding, and the body limit is
+/// `MAX_UPLOAD_BYTES` rather than the JSON limit.
+#[put("/repositories/{repo_id}/contents/{file_path:.*}")]
+pub async fn put_raw_contents(
+    path: web::Path<(String, String)>,
+    query: web::Query<RawContentsQuery>,
+    body: web::Bytes,
+    _: AuthenticatedUser,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id, file_path) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    if let Err(response) = writable_repository(&state, &caller, repo_id, Scope::RepoWrite).await {
+        return Ok(response);
+    }
+
+    let query = query.into_inner();
+    let files = vec![(file_path, FileChange::Write(body.to_vec()))];
+    Ok(commit_file_changes(&state, repo_id, query.branch, files, query.author, query.message).await)
+}
+
+/// Commit `files` to `branch` for the contents endpoints
+async fn commit_file_changes(
+    state: &AppState,
+    repo_id: Uuid,
+    branch: String,
+    files: Vec<(String, FileChange)>,
+    author: String,
+    message: String,
+) -> HttpResponse {
     let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
-    match git_ops.create_commit(repo_id, body.into_inner()).await {
-        Ok(commit_hash) => Ok(HttpResponse::Created().json(ApiResponse {
+    let result = state
+        .repository_service
+        .transaction(move |txn| {
+            Box::pin(async move { git_ops.commit_files(txn, repo_id, &branch, files, author, message).await })
+        })
+        .await;
+    match result {
+        Ok(commit_hash) => HttpResponse::Created().json(ApiResponse {
             success: true,
             data: Some(commit_hash),
-            message: "Commit created successfully".to_string(),
-        })),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
-            success: false,
-            data: None,
-            message: format!("Failed to create commit: {}", e),
-        })),
+            message: "Files committed successfully".to_string(),
+        }),
+        Err(e) => {
+            // A moved branch is worth retrying; anything else is a bad path or change
+            let status = match e.downcast_ref::<StorageError>() {
+                Some(StorageError::StaleRef { .. } | StorageError::RefNameConflict { .. }) => StatusCode::CONFLICT,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            HttpResponse::build(status).json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to commit files: {}", e),
+            })
+        }
     }
 }
 
-/// Merge branches
-#[post("/repositories/{repo_id}/merge")]
-pub async fn merge_branches(
-    path: web::Path<String>,
-    body: web::Json<MergeRequest>,
-    session: Session,
+/// Replace the message and/or tree of a branch's tip commit
+#[post("/repositories/{repo_id}/branches/{branch}/amend")]
+pub async fn amend_commit(
+    path: web::Path<(String, String)>,
+    body: ApiJson<AmendCommitRequest>,
+    _: AuthenticatedUser,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let _user_id = match get_authenticated_user(&session) {
-        Some(id) => id,
-        None => {
-            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()> {
+    let (repo_id_str, branch) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
                 success: false,
                 data: None,
-                message: "Authentication required".to_string(),
+                message: "Invalid repository ID".to_string(),
             }));
         }
     };
 
+    if let Err(response) = writable_repository(&state, &caller, repo_id, Scope::RepoWrite).await {
+        return Ok(response);
+    }
+
+    let req = body.into_inner();
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+    let result = state
+        .repository_service
+        .transaction(move |txn| {
+            Box::pin(async move {
+                git_ops
+                    .amend_commit(txn, repo_id, &branch, req.message, req.tree, req.author)
+                    .await
+            })
+        })
+        .await;
+    match result {
+        Ok(commit_hash) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(commit_hash),
+            message: "Commit amended successfully".to_string(),
+        })),
+        Err(e) => {
+            let status = match e.downcast_ref::<StorageError>() {
+                Some(StorageError::RefNotFound { .. }) => StatusCode::NOT_FOUND,
+                Some(StorageError::StaleRef { .. }) => StatusCode::CONFLICT,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            Ok(HttpResponse::build(status).json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to amend commit: {}", e),
+            }))
+        }
+    }
+}
+
+/// Merge one branch into another with the request's `strategy`. A merge that
+/// can't go ahead, such as a conflict, is 409.
+#[post("/repositories/{repo_id}/merge")]
+pub async fn merge_branches(
+    path: web::Path<String>,
+    body: ApiJson<MergeRequest>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
     let repo_id = match Uuid::parse_str(&path) {
         Ok(id) => id,
         Err(_) => {
@@ -359,18 +625,53 @@ pub async fn merge_branches(
         }
     };
 
+    let repository = match writable_repository(&state, &caller, repo_id, Scope::RepoWrite).await {
+        Ok(repository) => repository,
+        Err(response) => return Ok(response),
+    };
+
     let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
-    match git_ops.merge_branch(repo_id, body.into_inner()).await {
-        Ok(merge_commit) => Ok(HttpResponse::Ok().json(ApiResponse {
-            success: true,
-            data: Some(merge_commit),
-            message: "Branches merged successfully".to_string(),
-        })),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
-            success: false,
-            data: None,
-            message: format!("Failed to merge branches: {}", e),
-        })),
+    let request = body.into_inner();
+    let (source_branch, target_branch) = (request.source_branch.clone(), request.target_branch.clone());
+    // A squash adds one ordinary commit, which a linear history takes as it is
+    let linear = request.strategy != MergeStrategy::Squash
+        && repository.requires_linear_history(&format!("refs/heads/{}", request.target_branch));
+    let result = state
+        .repository_service
+        .transaction(move |txn| {
+            Box::pin(async move {
+                if linear {
+                    git_ops.check_linear_merge(txn, repo_id, &request).await?;
+                }
+                git_ops.merge_branch(txn, repo_id, request).await
+            })
+        })
+        .await;
+    match result {
+        Ok(merge) => {
+            let payload = MergeEventPayload { source_branch, target_branch, result: merge.clone() };
+            record_api_event(&state, repo_id, RepositoryEventType::Merge, user_id, &payload).await;
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(merge),
+                message: "Branches merged successfully".to_string(),
+            }))
+        }
+        Err(e) => {
+            let status = match e.downcast_ref::<StorageError>() {
+                Some(
+                    StorageError::NotFastForward { .. }
+                    | StorageError::NonLinearHistory { .. }
+                    | StorageError::MergeConflict { .. },
+                ) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Ok(HttpResponse::build(status).json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to merge branches: {}", e),
+            }))
+        }
     }
 }
 
@@ -379,21 +680,85 @@ pub async fn merge_branches(
 pub async fn get_commit_history(
     path: web::Path<(String, String)>,
     query: web::Query<CommitHistoryQuery>,
-    session: Session,
+    AuthenticatedUser(user_id): AuthenticatedUser,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let _user_id = match get_authenticated_user(&session) {
-        Some(id) => id,
-        None => {
-            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()> {
+    let (repo_id_str, branch_name) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
                 success: false,
                 data: None,
-                message: "Authentication required".to_string(),
+                message: "Invalid repository ID".to_string(),
             }));
         }
     };
 
-    let (repo_id_str, branch_name) = path.into_inner();
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
+    let options = match query.options() {
+        Ok(options) => options,
+        Err(message) => {
+            return Ok(HttpResponse::UnprocessableEntity().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message,
+            }));
+        }
+    };
+
+    let guardrails = match read_guardrails(&state, repo_id).await {
+        Ok(guardrails) => guardrails,
+        Err(response) => return Ok(response),
+    };
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone())
+        .with_limits(guardrails.limits);
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.get_commit_history(state.repository_service.get_db(), repo_id, branch_name, &options),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(mut history) => {
+            let emails = history.commits.iter().flat_map(|c| commit_emails(&c.commit));
+            let identities = match resolve_identities(&state, emails).await {
+                Ok(identities) => identities,
+                Err(response) => return Ok(response),
+            };
+            let host = state.config.external_host();
+            for commit in &mut history.commits {
+                commit.identify(&identities, &host);
+            }
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(history),
+                message: "Commit history retrieved successfully".to_string(),
+            }))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to get commit history: {}", e),
+        })),
+    }
+}
+
+/// One commit with its trailers and co-authors
+#[get("/repositories/{repo_id}/commits/{sha}")]
+pub async fn get_commit(
+    path: web::Path<(String, String)>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, sha) = path.into_inner();
     let repo_id = match Uuid::parse_str(&repo_id_str) {
         Ok(id) => id,
         Err(_) => {
@@ -405,31 +770,4700 @@ pub async fn get_commit_history(
         }
     };
 
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
     let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
-    match git_ops.get_commit_history(repo_id, branch_name, query.limit).await {
-        Ok(commits) => Ok(HttpResponse::Ok().json(ApiResponse {
-            success: true,
-            data: Some(commits),
-            message: "Commit history retrieved successfully".to_string(),
+    match git_ops.get_commit(state.repository_service.get_db(), repo_id, &sha).await {
+        Ok(Some(mut commit)) => {
+            let identities = match resolve_identities(&state, commit_emails(&commit.commit)).await {
+                Ok(identities) => identities,
+                Err(response) => return Ok(response),
+            };
+            commit.identify(&identities, &state.config.external_host());
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(commit),
+                message: "Commit retrieved successfully".to_string(),
+            }))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Commit '{}' not found", sha),
         })),
         Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
             success: false,
             data: None,
-            message: format!("Failed to get commit history: {}", e),
+            message: format!("Failed to get commit: {}", e),
         })),
     }
 }
 
-#[derive(Deserialize)]
-pub struct CommitHistoryQuery {
-    pub limit: Option<usize>,
-}
-
-/// Helper function to get authenticated user ID from session
-fn get_authenticated_user(session: &Session) -> Option<Uuid> {
-    session
-        .get::<String>("user_id")
-        .ok()
-        .flatten()
-        .and_then(|user_id_str| Uuid::parse_str(&user_id_str).ok())
-}
\ No newline at end of file
+/// The note attached to a commit under `?ref=` (`refs/notes/commits` by default)
+#[get("/repositories/{repo_id}/commits/{sha}/notes")]
+pub async fn get_commit_note(
+    path: web::Path<(String, String)>,
+    query: web::Query<NotesQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, sha) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
+    let repository = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repository)) => repository,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    };
+
+    let notes_ref = query.notes_ref();
+    let note = if state.config.hidden_refs(&repository).is_hidden(&notes_ref) {
+        Ok(None)
+    } else {
+        GitOperations::new(state.repository_service.as_ref().clone())
+            .get_note(state.repository_service.get_db(), repo_id, &notes_ref, &sha)
+            .await
+    };
+    match note {
+        Ok(Some(note)) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(note),
+            message: "Note retrieved successfully".to_string(),
+        })),
+        Ok(None) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("No note for '{}' in {}", sha, notes_ref),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to get note: {}", e),
+        })),
+    }
+}
+
+/// Attach a note to a commit, replacing any it has under the same notes ref
+#[post("/repositories/{repo_id}/commits/{sha}/notes")]
+pub async fn set_commit_note(
+    path: web::Path<(String, String)>,
+    query: web::Query<NotesQuery>,
+    body: ApiJson<SetNoteRequest>,
+    _: AuthenticatedUser,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, sha) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let repository = match writable_repository(&state, &caller, repo_id, Scope::RepoWrite).await {
+        Ok(repository) => repository,
+        Err(response) => return Ok(response),
+    };
+
+    // The same refusal a push to a hidden ref gets
+    let notes_ref = query.notes_ref();
+    if state.config.hidden_refs(&repository).is_hidden(&notes_ref) {
+        return Ok(HttpResponse::Forbidden().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "deny updating a hidden ref".to_string(),
+        }));
+    }
+
+    let req = body.into_inner();
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+    let result = state
+        .repository_service
+        .transaction(move |txn| {
+            Box::pin(async move {
+                git_ops
+                    .set_note(txn, repo_id, &notes_ref, &sha, &req.note, req.author)
+                    .await
+                    .map(|note| note.ok_or(sha))
+            })
+        })
+        .await;
+    match result {
+        Ok(Ok(note)) => Ok(HttpResponse::Created().json(ApiResponse {
+            success: true,
+            data: Some(note),
+            message: "Note saved successfully".to_string(),
+        })),
+        Ok(Err(sha)) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Commit '{}' not found", sha),
+        })),
+        Err(e) => {
+            let status = match e.downcast_ref::<StorageError>() {
+                Some(StorageError::StaleRef { .. } | StorageError::RefNameConflict { .. }) => StatusCode::CONFLICT,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            Ok(HttpResponse::build(status).json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to save note: {}", e),
+            }))
+        }
+    }
+}
+
+/// One commit as a `git format-patch` mbox, ready for `git am`
+#[get("/repositories/{repo_id}/commits/{sha}.patch")]
+pub async fn get_commit_patch(
+    path: web::Path<(String, String)>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, sha) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
+    let guardrails = match read_guardrails(&state, repo_id).await {
+        Ok(guardrails) => guardrails,
+        Err(response) => return Ok(response),
+    };
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone())
+        .with_limits(guardrails.limits);
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.format_patch(state.repository_service.get_db(), repo_id, &sha, env!("CARGO_PKG_VERSION")),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(Some(patch)) => Ok(HttpResponse::Ok()
+            .content_type("text/x-patch; charset=utf-8")
+            .body(patch)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Commit '{}' not found", sha),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to format patch: {}", e),
+        })),
+    }
+}
+
+/// One commit's changes against its first parent as `git show` diff text
+#[get("/repositories/{repo_id}/commits/{sha}.diff")]
+pub async fn get_commit_diff(
+    path: web::Path<(String, String)>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, sha) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
+    let guardrails = match read_guardrails(&state, repo_id).await {
+        Ok(guardrails) => guardrails,
+        Err(response) => return Ok(response),
+    };
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone())
+        .with_limits(guardrails.limits);
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.commit_diff(state.repository_service.get_db(), repo_id, &sha),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(Some((_, diffs))) => Ok(HttpResponse::Ok()
+            .content_type("text/x-diff; charset=utf-8")
+            .body(git_ops.render_diff(&diffs, DiffFormat::Patch))),
+        Ok(None) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Commit '{}' not found", sha),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to compute diff: {}", e),
+        })),
+    }
+}
+
+/// What `head` changed since it forked from `base`, as `git diff base...head`
+#[get("/repositories/{repo_id}/compare/{range}.diff")]
+pub async fn compare_diff(
+    path: web::Path<(String, String)>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, range) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+    let Some((base, head)) = range.split_once("...").filter(|(base, head)| !base.is_empty() && !head.is_empty())
+    else {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Invalid range '{}' (expected <base>...<head>)", range),
+        }));
+    };
+
+    let repository = match readable_objects_repository(&state, Some(user_id), repo_id).await {
+        Ok(repository) => repository,
+        Err(response) => return Ok(response),
+    };
+
+    // Hidden refs can't be compared, as they can't be fetched
+    let hidden = state.config.hidden_refs(&repository);
+    let refs: Vec<_> = match state.repository_service.get_resolved_refs(repo_id).await {
+        Ok(refs) => refs.into_iter().filter(|r| !hidden.is_hidden(&r.name)).collect(),
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to list refs: {}", e),
+            }));
+        }
+    };
+    let mut ids = Vec::with_capacity(2);
+    for revision in [base, head] {
+        match resolve_revision(revision, &refs) {
+            Some(id) => ids.push(id),
+            None => {
+                return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: format!("Revision '{}' not found", revision),
+                }));
+            }
+        }
+    }
+    let (base_id, head_id) = (&ids[0], &ids[1]);
+
+    let guardrails = state.config.read_guardrails(&repository);
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone())
+        .with_limits(guardrails.limits);
+    let options = DiffOptions {
+        detect_renames: true,
+        ..DiffOptions::default()
+    };
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.compare(state.repository_service.get_db(), repo_id, base_id, head_id, &options),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(Some(diffs)) => Ok(HttpResponse::Ok()
+            .content_type("text/x-diff; charset=utf-8")
+            .body(git_ops.render_diff(&diffs, DiffFormat::Patch))),
+        Ok(None) => Ok(HttpResponse::UnprocessableEntity().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("'{}' and '{}' have no common history", base, head),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to compute diff: {}", e),
+        })),
+    }
+}
+
+/// What `head` in one repository changed since it forked from `base` in another,
+/// such as a fork's branch against its upstream, as JSON per-file changes
+#[get("/repositories/{base_id}/compare/{head_id}")]
+pub async fn compare_across_repositories(
+    path: web::Path<(String, String)>,
+    query: web::Query<CrossCompareQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (base_id_str, head_id_str) = path.into_inner();
+    let (Ok(base_repo_id), Ok(head_repo_id)) = (Uuid::parse_str(&base_id_str), Uuid::parse_str(&head_id_str)) else {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Invalid repository ID".to_string(),
+        }));
+    };
+
+    // Each side is resolved in its own repository, hidden refs excluded
+    let mut ids = Vec::with_capacity(2);
+    let mut head_repository = None;
+    for (repo_id, revision) in [(base_repo_id, &query.base), (head_repo_id, &query.head)] {
+        let repository = match readable_objects_repository(&state, Some(user_id), repo_id).await {
+            Ok(repository) => repository,
+            Err(response) => return Ok(response),
+        };
+        let hidden = state.config.hidden_refs(&repository);
+        let refs: Vec<_> = match state.repository_service.get_resolved_refs(repo_id).await {
+            Ok(refs) => refs.into_iter().filter(|r| !hidden.is_hidden(&r.name)).collect(),
+            Err(e) => {
+                return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: format!("Failed to list refs: {}", e),
+                }));
+            }
+        };
+        match resolve_revision(revision, &refs) {
+            Some(id) => ids.push(id),
+            None => {
+                return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: format!("Revision '{}' not found", revision),
+                }));
+            }
+        }
+        head_repository = Some(repository);
+    }
+    let (base, head) = (&ids[0], &ids[1]);
+    let head_repository = head_repository.expect("head repository was read");
+
+    let guardrails = state.config.read_guardrails(&head_repository);
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone())
+        .with_limits(guardrails.limits);
+    let options = DiffOptions {
+        detect_renames: true,
+        ..DiffOptions::default()
+    };
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.diff_across_repos(
+                state.repository_service.get_db(),
+                base_repo_id,
+                base,
+                head_repo_id,
+                head,
+                &options,
+            ),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(Some(diffs)) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(diffs),
+            message: "Diff computed successfully".to_string(),
+        })),
+        Ok(None) => Ok(HttpResponse::UnprocessableEntity().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("'{}' and '{}' have no common history", query.base, query.head),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to compute diff: {}", e),
+        })),
+    }
+}
+
+/// What a branch changed since a commit in its history, for showing what's new
+/// since a client last looked. A commit the branch doesn't contain is 422.
+#[get("/repositories/{repo_id}/branches/{branch}/diff-since")]
+pub async fn diff_since(
+    path: web::Path<(String, String)>,
+    query: web::Query<DiffSinceQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, branch) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let repository = match readable_objects_repository(&state, Some(user_id), repo_id).await {
+        Ok(repository) => repository,
+        Err(response) => return Ok(response),
+    };
+    if state.config.hidden_refs(&repository).is_hidden(&format!("refs/heads/{}", branch)) {
+        return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Branch '{}' not found", branch),
+        }));
+    }
+
+    let guardrails = state.config.read_guardrails(&repository);
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone())
+        .with_limits(guardrails.limits);
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.diff_since(state.repository_service.get_db(), repo_id, &branch, &query.commit),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(diffs) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(diffs),
+            message: "Diff computed successfully".to_string(),
+        })),
+        Err(e) => {
+            let status = match e.downcast_ref::<StorageError>() {
+                Some(StorageError::RefNotFound { .. }) => StatusCode::NOT_FOUND,
+                Some(StorageError::NotAncestor { .. }) => StatusCode::UNPROCESSABLE_ENTITY,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Ok(HttpResponse::build(status).json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to compute diff: {}", e),
+            }))
+        }
+    }
+}
+
+/// Diff two commits as JSON hunks, patch text, name-status or stat output
+#[get("/repositories/{repo_id}/diff")]
+pub async fn get_diff(
+    path: web::Path<String>,
+    query: web::Query<DiffQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
+    let format = match query.format.as_deref().map(str::parse::<DiffFormat>) {
+        None => DiffFormat::default(),
+        Some(Ok(format)) => format,
+        Some(Err(e)) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: e.to_string(),
+            }));
+        }
+    };
+
+    let options = DiffOptions {
+        detect_renames: query.detect_renames.unwrap_or(false),
+        rename_threshold: query.rename_threshold.unwrap_or(DEFAULT_RENAME_THRESHOLD).min(100),
+        normalize_eol: query.normalize_eol.unwrap_or(false),
+    };
+
+    let guardrails = match read_guardrails(&state, repo_id).await {
+        Ok(guardrails) => guardrails,
+        Err(response) => return Ok(response),
+    };
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone())
+        .with_limits(guardrails.limits);
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.diff_commits(state.repository_service.get_db(), repo_id, &query.from, &query.to, &options),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(diffs) if format == DiffFormat::Json => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(diffs),
+            message: "Diff computed successfully".to_string(),
+        })),
+        Ok(diffs) => Ok(HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(git_ops.render_diff(&diffs, format))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to compute diff: {}", e),
+        })),
+    }
+}
+
+/// Commits reachable from a ref laid out for drawing as a graph: topologically
+/// ordered, each with its lane and the refs pointing at it
+#[get("/repositories/{repo_id}/graph")]
+pub async fn get_commit_graph(
+    path: web::Path<String>,
+    query: web::Query<GraphQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let repository = match readable_objects_repository(&state, Some(user_id), repo_id).await {
+        Ok(repository) => repository,
+        Err(response) => return Ok(response),
+    };
+
+    // Hidden refs neither start the walk nor decorate it
+    let hidden = state.config.hidden_refs(&repository);
+    let refs: Vec<_> = match state.repository_service.get_resolved_refs(repo_id).await {
+        Ok(refs) => refs.into_iter().filter(|r| !hidden.is_hidden(&r.name)).collect(),
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to list refs: {}", e),
+            }));
+        }
+    };
+
+    let guardrails = state.config.read_guardrails(&repository);
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone())
+        .with_limits(guardrails.limits);
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.commit_graph(
+                state.repository_service.get_db(),
+                repo_id,
+                query.ref_name.as_deref(),
+                query.limit,
+                &refs,
+            ),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(Some(graph)) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(graph),
+            message: "Commit graph retrieved successfully".to_string(),
+        })),
+        Ok(None) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Ref not found".to_string(),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to build commit graph: {}", e),
+        })),
+    }
+}
+
+/// The README at the root of a ref's tree with its format, for repository
+/// landing pages. A tree without one is 404 with `no_readme` set.
+#[get("/repositories/{repo_id}/readme")]
+pub async fn get_readme(
+    path: web::Path<String>,
+    query: web::Query<ReadmeQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let repository = match readable_objects_repository(&state, Some(user_id), repo_id).await {
+        Ok(repository) => repository,
+        Err(response) => return Ok(response),
+    };
+
+    let hidden = state.config.hidden_refs(&repository);
+    let refs: Vec<_> = match state.repository_service.get_resolved_refs(repo_id).await {
+        Ok(refs) => refs.into_iter().filter(|r| !hidden.is_hidden(&r.name)).collect(),
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to list refs: {}", e),
+            }));
+        }
+    };
+    let ref_not_found = || {
+        HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Ref not found".to_string(),
+        })
+    };
+    // Without a ref, HEAD or, before anything set it, the default branch
+    let commit = match query.ref_name.as_deref() {
+        Some(revision) => resolve_revision(revision, &refs),
+        None => resolve_revision("HEAD", &refs).or_else(|| resolve_revision(&repository.default_branch, &refs)),
+    };
+    let Some(commit) = commit else {
+        return Ok(ref_not_found());
+    };
+
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+    match git_ops.readme(state.repository_service.get_db(), repo_id, &commit).await {
+        Ok(Some(readme)) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(readme),
+            message: "README retrieved successfully".to_string(),
+        })),
+        Ok(None) => Ok(HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            data: Some(serde_json::json!({ "no_readme": true })),
+            message: "no readme".to_string(),
+        })),
+        Err(e) if matches!(e.downcast_ref::<StorageError>(), Some(StorageError::RefNotFound { .. })) => {
+            Ok(ref_not_found())
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to read README: {}", e),
+        })),
+    }
+}
+
+/// The id and kind of the object at a path in a commit's tree, like
+/// `git rev-parse <commit>:<path>`. `commit` may be any revision; an empty path
+/// is the root tree.
+#[get("/repositories/{repo_id}/rev-parse/{commit}/{path:.*}")]
+pub async fn rev_parse_path(
+    path: web::Path<(String, String, String)>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id, revision, object_path) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let repository = match readable_objects_repository(&state, Some(user_id), repo_id).await {
+        Ok(repository) => repository,
+        Err(response) => return Ok(response),
+    };
+
+    let hidden = state.config.hidden_refs(&repository);
+    let refs: Vec<_> = match state.repository_service.get_resolved_refs(repo_id).await {
+        Ok(refs) => refs.into_iter().filter(|r| !hidden.is_hidden(&r.name)).collect(),
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to list refs: {}", e),
+            }));
+        }
+    };
+    let ref_not_found = || {
+        HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Ref not found".to_string(),
+        })
+    };
+    let Some(commit) = resolve_revision(&revision, &refs) else {
+        return Ok(ref_not_found());
+    };
+
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+    match git_ops
+        .object_sha_at_path(state.repository_service.get_db(), repo_id, &commit, &object_path)
+        .await
+    {
+        Ok((sha, kind)) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(serde_json::json!({ "sha": sha, "kind": kind, "path": object_path })),
+            message: "Path resolved successfully".to_string(),
+        })),
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::RefNotFound { .. }) => Ok(ref_not_found()),
+            Some(StorageError::PathNotFound { .. }) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: e.to_string(),
+            })),
+            _ => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to resolve path: {}", e),
+            })),
+        },
+    }
+}
+
+/// The repository whose objects `user_id` (`None` when anonymous) asked for, or
+/// the response to send: 404 both when it doesn't exist and when the caller can't
+/// read it, so object ids can't be probed in repositories they don't know about.
+/// Every route serving object content by id checks through here.
+pub(crate) async fn readable_objects_repository(
+    state: &AppState,
+    user_id: Option<Uuid>,
+    repo_id: Uuid,
+) -> std::result::Result<repository::Model, HttpResponse> {
+    let not_found = || {
+        HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Repository not found".to_string(),
+        })
+    };
+    let database_error = |e: anyhow::Error| {
+        HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Database error: {}", e),
+        })
+    };
+    let repository = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repository)) => repository,
+        Ok(None) => return Err(not_found()),
+        Err(e) => return Err(database_error(e)),
+    };
+    match can_read(state, user_id, &repository).await {
+        Ok(true) => Ok(repository),
+        Ok(false) => Err(not_found()),
+        Err(e) => Err(database_error(e)),
+    }
+}
+
+/// The repository a write goes to, or the response to send: 404 if it doesn't
+/// exist or the caller can't read it, 403 if it is archived or the caller may not
+/// write to it. Write access is [`Permissions::effective_for`]'s, as for pushes;
+/// tokens need `write_scope`.
+pub(crate) async fn writable_repository(
+    state: &AppState,
+    caller: &Caller,
+    repo_id: Uuid,
+    write_scope: Scope,
+) -> std::result::Result<repository::Model, HttpResponse> {
+    let database_error = |e: anyhow::Error| {
+        HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Database error: {}", e),
+        })
+    };
+    let repo = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repo)) => repo,
+        Ok(None) => {
+            return Err(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            }));
+        }
+        Err(e) => return Err(database_error(e)),
+    };
+    let user = caller_user(state, caller).await.map_err(database_error)?;
+    // Whether it is visible doesn't depend on the token's scopes
+    if !repo.readable_by(user.as_ref()) {
+        return Err(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Repository not found".to_string(),
+        }));
+    }
+    if repo.is_archived || !Permissions::effective_for(&repo, user.as_ref(), caller, write_scope).write {
+        let message = if repo.is_archived { ARCHIVED_MESSAGE } else { "Write access required" };
+        return Err(HttpResponse::Forbidden().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: message.to_string(),
+        }));
+    }
+    Ok(repo)
+}
+
+/// The accounts behind `emails`, for linking commits to users and hiding the
+/// emails of those who asked to, or the response to send
+async fn resolve_identities<'a>(
+    state: &AppState,
+    emails: impl IntoIterator<Item = &'a str>,
+) -> std::result::Result<IdentityMap, HttpResponse> {
+    state.identity_service.resolve(emails).await.map_err(|e| {
+        HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to resolve commit identities: {}", e),
+        })
+    })
+}
+
+/// Guardrails for an expensive read in `repo_id`, or the response to send when
+/// the repository can't be loaded
+async fn read_guardrails(state: &AppState, repo_id: Uuid) -> std::result::Result<ReadGuardrails, HttpResponse> {
+    match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repo)) => Ok(state.config.read_guardrails(&repo)),
+        Ok(None) => Err(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Repository not found".to_string(),
+        })),
+        Err(e) => Err(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Database error: {}", e),
+        })),
+    }
+}
+
+/// Whether a whole blob served raw is binary, `true` or `false`
+const BINARY_HEADER: &str = "X-Git-Binary";
+/// Line endings of a whole text blob served raw: `lf`, `crlf` or `mixed`
+const LINE_ENDING_HEADER: &str = "X-Git-Line-Ending";
+
+#[derive(Deserialize)]
+pub struct RawBlobQuery {
+    /// Serve text with `\r\n` turned into `\n`; binary blobs are left alone
+    pub normalize_eol: Option<bool>,
+}
+
+/// Raw blob content. A single `Range: bytes=...` is honored with 206 so large
+/// downloads can resume; only the requested slice is read from storage. Whole
+/// blobs say whether they are binary and which line endings they use in headers.
+#[get("/repositories/{repo_id}/blobs/{sha}/raw")]
+pub async fn get_raw_blob(
+    http_req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<RawBlobQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, sha) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
+    let size = match state.repository_service.get_blob_size(repo_id, &sha).await {
+        Ok(Some(size)) => size,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Blob not found".to_string(),
+            }));
+        }
+        Err(e) => return Ok(object_error_response(&e)),
+    };
+
+    // Several ranges at once are not supported and are refused like an unsatisfiable one.
+    // Normalized content no longer has the stored offsets, so it is always whole.
+    let normalize_eol = query.normalize_eol.unwrap_or(false);
+    let range = match http_req.headers().get(header::RANGE) {
+        _ if normalize_eol => None,
+        None => None,
+        Some(value) => match value.to_str().ok().map(|v| HttpRange::parse(v, size)) {
+            Some(Ok(ranges)) if ranges.len() == 1 => Some(ranges[0]),
+            _ => {
+                return Ok(HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                    .finish());
+            }
+        },
+    };
+
+    let (start, length) = range.map(|r| (r.start, r.length)).unwrap_or((0, size));
+    let mut content = match state.repository_service.read_blob_range(repo_id, &sha, start, length).await {
+        Ok(Some(content)) => content,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Blob not found".to_string(),
+            }));
+        }
+        Err(e) => return Ok(object_error_response(&e)),
+    };
+
+    let mut response = match range {
+        Some(_) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, start + length - 1, size),
+            ));
+            response
+        }
+        None => {
+            let mut response = HttpResponse::Ok();
+            let info = text::classify(&content);
+            response.insert_header((BINARY_HEADER, info.is_binary.to_string()));
+            if let Some(line_ending) = info.line_ending {
+                response.insert_header((LINE_ENDING_HEADER, line_ending.as_str()));
+            }
+            if normalize_eol && !info.is_binary {
+                content = text::normalize_eol(&content).into_owned();
+            }
+            response
+        }
+    };
+    if !normalize_eol {
+        response.insert_header((header::ACCEPT_RANGES, "bytes"));
+    }
+    Ok(response.content_type("application/octet-stream").body(content))
+}
+
+/// Unknown repositories and non-blob objects are the caller's mistake
+fn object_error_response(err: &anyhow::Error) -> HttpResponse {
+    let (status, message) = match err.downcast_ref::<StorageError>() {
+        Some(StorageError::RepositoryNotFound { .. }) => (StatusCode::NOT_FOUND, "Repository not found".to_string()),
+        Some(e @ StorageError::ObjectTypeMismatch { .. }) => (StatusCode::BAD_REQUEST, e.to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read blob: {}", err)),
+    };
+    HttpResponse::build(status).json(ApiResponse::<()> {
+        success: false,
+        data: None,
+        message,
+    })
+}
+
+/// Fetch several objects in one request. Content is base64 in JSON, or sent raw
+/// with `Accept: application/x-git-objects` (see `git_protocol::batch`).
+#[post("/repositories/{repo_id}/objects/batch")]
+pub async fn batch_get_objects(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    body: ApiJson<BatchObjectsRequest>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
+    let ids = body.into_inner().ids;
+    if ids.len() > MAX_BATCH_OBJECTS {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("At most {} objects per batch", MAX_BATCH_OBJECTS),
+        }));
+    }
+    if let Some(id) = ids.iter().find(|id| !is_object_id(id)) {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Invalid object id: {}", id),
+        }));
+    }
+
+    let mut entries = Vec::with_capacity(ids.len());
+    for id in ids {
+        let id = id.to_ascii_lowercase();
+        let entry = match state.repository_service.get_object(repo_id, &id).await {
+            Ok(Some(obj)) => obj.into_git_object().map(BatchEntry::Found),
+            Ok(None) => Ok(BatchEntry::Missing(id)),
+            Err(e) => Err(e),
+        };
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(e) => return Ok(object_error_response(&e)),
+        }
+    }
+
+    if prefers_binary_batch(&http_req) {
+        return match encode_batch(&entries) {
+            Ok(body) => Ok(HttpResponse::Ok().content_type(BATCH_MEDIA_TYPE).body(body)),
+            Err(e) => Ok(object_error_response(&e)),
+        };
+    }
+
+    let mut batch = ObjectBatch { objects: Vec::new(), missing: Vec::new() };
+    for entry in entries {
+        match entry {
+            BatchEntry::Found(obj) => batch.objects.push(BatchObject {
+                text: (obj.obj_type == ObjectType::Blob).then(|| text::classify(&obj.content)),
+                object_type: ObjectKind::from(&obj.obj_type).to_string(),
+                size: obj.size,
+                content: BASE64_STANDARD.encode(&obj.content),
+                id: obj.id,
+            }),
+            BatchEntry::Missing(id) => batch.missing.push(id),
+        }
+    }
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(batch),
+        message: "Objects retrieved successfully".to_string(),
+    }))
+}
+
+/// Whether the client ranks the binary batch encoding above JSON
+fn prefers_binary_batch(req: &HttpRequest) -> bool {
+    use actix_web::http::header::Header;
+
+    let Ok(accept) = header::Accept::parse(req) else {
+        return false;
+    };
+    accept
+        .ranked()
+        .into_iter()
+        .find_map(|mime| match mime.essence_str() {
+            BATCH_MEDIA_TYPE => Some(true),
+            "application/json" | "application/*" | "*/*" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+/// Report the state of a check (CI build, lint, ...) against a commit
+#[post("/repositories/{repo_id}/statuses/{sha}")]
+pub async fn create_commit_status(
+    path: web::Path<(String, String)>,
+    body: ApiJson<CreateCommitStatusRequest>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, sha) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    if !is_object_id(&sha) {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Invalid commit SHA".to_string(),
+        }));
+    }
+
+    let req = body.into_inner();
+    let status_state = match req.state.parse::<CommitStatusState>() {
+        Ok(status_state) => status_state,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: e.to_string(),
+            }));
+        }
+    };
+
+    if let Err(response) = writable_repository(&state, &caller, repo_id, Scope::StatusWrite).await {
+        return Ok(response);
+    }
+
+    let status = NewCommitStatus {
+        repository_id: repo_id,
+        commit_id: sha.to_ascii_lowercase(),
+        state: status_state,
+        context: req.context.unwrap_or_else(|| "default".to_string()),
+        description: req.description,
+        target_url: req.target_url,
+        creator_id: Some(user_id),
+    };
+    match state.status_service.record(status).await {
+        Ok(status) => Ok(HttpResponse::Created().json(ApiResponse {
+            success: true,
+            data: Some(status),
+            message: "Status recorded successfully".to_string(),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to record status: {}", e),
+        })),
+    }
+}
+
+/// List the latest status of each check reported against a commit
+#[get("/repositories/{repo_id}/statuses/{sha}")]
+pub async fn list_commit_statuses(
+    path: web::Path<(String, String)>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (repo_id_str, sha) = path.into_inner();
+    let repo_id = match Uuid::parse_str(&repo_id_str) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    if let Err(response) = readable_objects_repository(&state, Some(user_id), repo_id).await {
+        return Ok(response);
+    }
+
+    match state.status_service.list(repo_id, &sha.to_ascii_lowercase()).await {
+        Ok(statuses) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(statuses),
+            message: "Statuses retrieved successfully".to_string(),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to list statuses: {}", e),
+        })),
+    }
+}
+
+/// What the caller may do with a repository. Anonymous callers are answered
+/// too, which for a public repository means read only.
+#[get("/repositories/{repo_id}/permissions")]
+pub async fn get_repository_permissions(
+    path: web::Path<String>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let not_found = || {
+        HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Repository not found".to_string(),
+        })
+    };
+    let repository = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repository)) => repository,
+        Ok(None) => return Ok(not_found()),
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    };
+    let user = match caller_user(&state, &caller).await {
+        Ok(user) => user,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to load user: {}", e),
+            }));
+        }
+    };
+    if !repository.readable_by(user.as_ref()) {
+        return Ok(not_found());
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(Permissions::effective(&repository, user.as_ref(), &caller)),
+        message: "Permissions retrieved successfully".to_string(),
+    }))
+}
+
+/// A repository's activity feed: pushes, branches and tags created or deleted,
+/// and merges, newest first. Anyone who can read the repository sees it, but
+/// only its owner or an admin sees activity on hidden refs.
+#[get("/repositories/{repo_id}/events")]
+pub async fn list_repository_events(
+    path: web::Path<String>,
+    query: web::Query<EventsQuery>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+    let before = match query.before.as_deref().map(Uuid::parse_str).transpose() {
+        Ok(before) => before,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid 'before': expected a next_cursor".to_string(),
+            }));
+        }
+    };
+
+    let not_found = || {
+        HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: "Repository not found".to_string(),
+        })
+    };
+    let repository = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repository)) => repository,
+        Ok(None) => return Ok(not_found()),
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    };
+    let user = match caller_user(&state, &caller).await {
+        Ok(user) => user,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to load user: {}", e),
+            }));
+        }
+    };
+    if !repository.readable_by(user.as_ref()) {
+        return Ok(not_found());
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_ACTIVITY_LIMIT);
+    let page = match state.event_service.activity(repo_id, limit, before).await {
+        Ok(page) => page,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to list events: {}", e),
+            }));
+        }
+    };
+
+    let hidden = if repository.administered_by(user.as_ref()) {
+        HiddenRefs::default()
+    } else {
+        state.config.hidden_refs(&repository)
+    };
+    let mut usernames: HashMap<Uuid, Option<String>> = HashMap::new();
+    let mut events = Vec::with_capacity(page.events.len());
+    for mut event in page.events {
+        let visible = match &mut event.activity {
+            Activity::Push(push) => {
+                push.refs.retain(|r| !hidden.is_hidden(&r.name));
+                !push.refs.is_empty()
+            }
+            Activity::BranchCreated(r)
+            | Activity::BranchDeleted(r)
+            | Activity::TagCreated(r)
+            | Activity::TagDeleted(r) => !hidden.is_hidden(&r.name),
+            Activity::Merge(merge) => [&merge.source_branch, &merge.target_branch]
+                .iter()
+                .all(|branch| !hidden.is_hidden(&format!("refs/heads/{}", branch))),
+        };
+        if !visible {
+            continue;
+        }
+        // A page rarely has more than a few distinct actors
+        let actor = match event.actor_id {
+            Some(actor_id) => match usernames.get(&actor_id) {
+                Some(username) => username.clone(),
+                None => {
+                    let username = match state.user_service.get_user_by_id(actor_id).await {
+                        Ok(user) => user.map(|user| user.username),
+                        Err(e) => {
+                            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                                success: false,
+                                data: None,
+                                message: format!("Failed to load user: {}", e),
+                            }));
+                        }
+                    };
+                    usernames.insert(actor_id, username.clone());
+                    username
+                }
+            },
+            None => None,
+        };
+        events.push(ActivityEntry { event, actor });
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(ActivityFeed { events, next_cursor: page.next_cursor }),
+        message: "Events retrieved successfully".to_string(),
+    }))
+}
+
+/// List a repository's refs. Refs under hidden prefixes are left out unless the
+/// repository owner or an admin asks for them with `include_hidden=true`.
+#[get("/repositories/{repo_id}/refs")]
+pub async fn list_refs(
+    path: web::Path<String>,
+    query: web::Query<ListRefsQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let repository = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repository)) => repository,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    };
+
+    let include_hidden = query.include_hidden.unwrap_or(false);
+    if include_hidden && repository.owner_id != user_id {
+        match state.user_service.get_user_by_id(user_id).await {
+            Ok(Some(user)) if user.is_admin => {}
+            Ok(_) => {
+                return Ok(HttpResponse::Forbidden().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: "Only the repository owner can list hidden refs".to_string(),
+                }));
+            }
+            Err(e) => {
+                return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: format!("Failed to load user: {}", e),
+                }));
+            }
+        }
+    }
+
+    let hidden = state.config.hidden_refs(&repository);
+    match state.repository_service.get_refs_by_repository(repo_id).await {
+        Ok(refs) => {
+            let refs: Vec<RefInfo> = refs
+                .into_iter()
+                .map(|r| RefInfo {
+                    hidden: hidden.is_hidden(&r.name),
+                    name: r.name,
+                    target: r.target,
+                    is_symbolic: r.is_symbolic,
+                })
+                .filter(|r| include_hidden || !r.hidden)
+                .collect();
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(refs),
+                message: "Refs retrieved successfully".to_string(),
+            }))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to list refs: {}", e),
+        })),
+    }
+}
+
+/// Objects no ref reaches, i.e. what a garbage collection would delete.
+/// Repository owner or admin only.
+#[get("/repositories/{repo_id}/unreachable")]
+pub async fn list_unreachable_objects(
+    path: web::Path<String>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let repository = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repository)) => repository,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    };
+
+    if repository.owner_id != user_id {
+        match state.user_service.get_user_by_id(user_id).await {
+            Ok(Some(user)) if user.is_admin => {}
+            Ok(_) => {
+                return Ok(HttpResponse::Forbidden().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: "Only the repository owner can list unreachable objects".to_string(),
+                }));
+            }
+            Err(e) => {
+                return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: format!("Failed to load user: {}", e),
+                }));
+            }
+        }
+    }
+
+    let guardrails = state.config.read_guardrails(&repository);
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+    let result = match guardrails
+        .run(
+            &state.guardrail_metrics,
+            git_ops.list_unreachable_objects(state.repository_service.get_db(), repo_id),
+        )
+        .await
+    {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
+    match result {
+        Ok(objects) => {
+            let objects: Vec<UnreachableObject> = objects
+                .into_iter()
+                .map(|(id, object_type, size)| UnreachableObject { id, object_type: object_type.to_string(), size })
+                .collect();
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(objects),
+                message: "Unreachable objects listed".to_string(),
+            }))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to list unreachable objects: {}", e),
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListBranchesQuery {
+    /// Include ahead/behind counts against the default branch (costs extra queries)
+    pub compare: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ListRefsQuery {
+    /// Also return refs under hidden prefixes; owner or admin only
+    pub include_hidden: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Events per page, `DEFAULT_ACTIVITY_LIMIT` by default and at most `MAX_ACTIVITY_LIMIT`
+    pub limit: Option<u64>,
+    /// `next_cursor` from the previous page
+    pub before: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ActivityFeed {
+    pub events: Vec<ActivityEntry>,
+    /// Pass as `before` for older events; `None` on the last page
+    pub next_cursor: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct ActivityEntry {
+    #[serde(flatten)]
+    pub event: ActivityEvent,
+    /// Username of the actor, `None` when anonymous or since deleted
+    pub actor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefInfo {
+    pub name: String,
+    pub target: String,
+    pub is_symbolic: bool,
+    /// Left out of the advertisement shown to git clients
+    pub hidden: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnreachableObject {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub object_type: String,
+    pub size: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CommitHistoryQuery {
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+    pub first_parent: Option<bool>,
+    pub merges_only: Option<bool>,
+    /// RFC3339 timestamp; compared against committer dates
+    pub since: Option<String>,
+    /// RFC3339 timestamp; compared against committer dates
+    pub until: Option<String>,
+    /// Exact email, or a substring of the author's name or email
+    pub author: Option<String>,
+    /// Exact email, or a substring of the committer's name or email
+    pub committer: Option<String>,
+}
+
+impl CommitHistoryQuery {
+    /// Validated options, or the message for a 422 naming the bad parameter
+    fn options(&self) -> std::result::Result<HistoryOptions, String> {
+        let timestamp = |name: &str, value: &Option<String>| match value {
+            Some(value) => chrono::DateTime::parse_from_rfc3339(value)
+                .map(|t| Some(t.with_timezone(&chrono::Utc)))
+                .map_err(|_| format!("Invalid '{}': expected an RFC3339 timestamp", name)),
+            None => Ok(None),
+        };
+        let identity = |name: &str, value: &Option<String>| match value.as_deref().map(str::trim) {
+            Some("") => Err(format!("Invalid '{}': must not be empty", name)),
+            Some(value) => Ok(Some(value.to_string())),
+            None => Ok(None),
+        };
+
+        let since = timestamp("since", &self.since)?;
+        let until = timestamp("until", &self.until)?;
+        if let (Some(since), Some(until)) = (since, until) {
+            if since > until {
+                return Err("Invalid 'until': must not be before 'since'".to_string());
+            }
+        }
+
+        Ok(HistoryOptions {
+            limit: self.limit,
+            cursor: self.cursor.clone(),
+            first_parent: self.first_parent.unwrap_or(false),
+            merges_only: self.merges_only.unwrap_or(false),
+            since,
+            until,
+            author: identity("author", &self.author)?,
+            committer: identity("committer", &self.committer)?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NotesQuery {
+    /// Notes ref, either in full or below `refs/notes/`; `refs/notes/commits` by default
+    #[serde(rename = "ref")]
+    pub notes_ref: Option<String>,
+}
+
+impl NotesQuery {
+    fn notes_ref(&self) -> String {
+        match self.notes_ref.as_deref() {
+            None => DEFAULT_NOTES_REF.to_string(),
+            Some(name) if name.starts_with("refs/") => name.to_string(),
+            Some(name) => format!("refs/notes/{}", name),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetNoteRequest {
+    pub note: String,
+    /// Signature of the notes commit, `Name <email> <seconds> <tz>`
+    pub author: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AmendCommitRequest {
+    /// New message, or `None` to keep the tip's
+    pub message: Option<String>,
+    /// New tree id, or `None` to keep the tip's
+    pub tree: Option<String>,
+    /// Committer signature of the amended commit; the original author is kept
+    pub author: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CommitFilesRequest {
+    /// Created with a root commit if it doesn't exist yet
+    pub branch: String,
+    /// Signature used as author and committer, `Name <email> <seconds> <tz>`
+    pub author: String,
+    pub message: String,
+    pub files: Vec<FileChangeRequest>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FileChangeRequest {
+    pub path: String,
+    /// New UTF-8 content of the file
+    pub content: Option<String>,
+    /// Remove the file instead
+    pub delete: Option<bool>,
+}
+
+/// Most objects a single batch request may ask for
+pub const MAX_BATCH_OBJECTS: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchObjectsRequest {
+    pub ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ObjectBatch {
+    pub objects: Vec<BatchObject>,
+    /// Requested ids the repository does not have
+    pub missing: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchObject {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub object_type: String,
+    pub size: usize,
+    /// Base64 (standard alphabet, padded)
+    pub content: String,
+    /// `is_binary` and `line_ending`, for blobs only
+    #[serde(flatten, default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<TextInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateCommitStatusRequest {
+    /// `pending`, `success`, `failure` or `error`
+    pub state: String,
+    /// Name of the check, `default` when omitted
+    pub context: Option<String>,
+    pub description: Option<String>,
+    pub target_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    pub from: String,
+    pub to: String,
+    /// `json` (default), `patch`, `name-status` or `stat`
+    pub format: Option<String>,
+    /// Report a deleted and an added file as a rename when their content matches
+    pub detect_renames: Option<bool>,
+    /// Similarity (percent) needed for an edited file to count as renamed; 100 for exact only
+    pub rename_threshold: Option<u8>,
+    /// Ignore `\r\n` versus `\n` when comparing text
+    pub normalize_eol: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct CrossCompareQuery {
+    /// Branch, tag or commit id in the base repository
+    pub base: String,
+    /// Branch, tag or commit id in the head repository
+    pub head: String,
+}
+
+#[derive(Deserialize)]
+pub struct DiffSinceQuery {
+    /// Commit to diff from; must be in the branch's history
+    pub commit: String,
+}
+
+#[derive(Deserialize)]
+pub struct GraphQuery {
+    /// Branch, tag, full ref name or commit id to start from; `HEAD` by default
+    #[serde(rename = "ref")]
+    pub ref_name: Option<String>,
+    /// Commits to include, capped at `MAX_GRAPH_NODES`
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct ReadmeQuery {
+    /// Branch, tag, full ref name or commit id to look in; `HEAD` or the default
+    /// branch by default
+    #[serde(rename = "ref")]
+    pub ref_name: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ForkRepositoryRequest {
+    /// Name of the fork, `<source>-<username>` when not given
+    pub name: Option<String>,
+}
+
+/// Fork a repository the caller can read into a new repository they own
+#[post("/repositories/{repo_id}/fork")]
+pub async fn fork_repository(
+    path: web::Path<String>,
+    body: Option<ApiJson<ForkRepositoryRequest>>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let user = match state.user_service.get_user_by_id(user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Authentication required".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to load user: {}", e),
+            }));
+        }
+    };
+
+    // Private repositories the caller can't see are reported as missing
+    let source = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repo)) if repo.readable_by(Some(&user)) => repo,
+        Ok(_) => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    };
+
+    let name = body
+        .and_then(|body| body.into_inner().name)
+        .unwrap_or_else(|| format!("{}-{}", source.name, user.username));
+    match state.repository_service.get_repository_by_name(&name).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return Ok(HttpResponse::Conflict().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Repository {} already exists", name),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    }
+
+    match quota::owner_quota(&state, user_id).await {
+        Ok((quota, usage)) => {
+            if let Err(hit) = quota.check_new_repository(&usage) {
+                return Ok(hit.response());
+            }
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    }
+
+    match state.repository_service.fork_repository(source.id, name, user_id).await {
+        Ok(fork) => Ok(HttpResponse::Created().json(ApiResponse {
+            success: true,
+            data: Some(RepositoryResponse::from_model(fork, &user.username, Vec::new(), &state.config)),
+            message: format!("Forked {}", source.name),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Failed to fork repository: {}", e),
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TransferRepositoryRequest {
+    /// Username of the new owner
+    pub new_owner: String,
+    /// Name to give the repository under its new owner, its current one when not given
+    pub new_name: Option<String>,
+}
+
+/// Hand a repository the caller administers to another user
+#[post("/repositories/{repo_id}/transfer")]
+pub async fn transfer_repository(
+    path: web::Path<String>,
+    body: ApiJson<TransferRepositoryRequest>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+    let body = body.into_inner();
+
+    let user = match state.user_service.get_user_by_id(user_id).await {
+        Ok(user) => user,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to load user: {}", e),
+            }));
+        }
+    };
+    let repo = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repo)) if repo.administered_by(user.as_ref()) => repo,
+        Ok(Some(repo)) if repo.readable_by(user.as_ref()) => {
+            return Ok(HttpResponse::Forbidden().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Only the owner or an admin can transfer a repository".to_string(),
+            }));
+        }
+        Ok(_) => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    };
+
+    let new_owner = match state.user_service.get_user_by_username(&body.new_owner).await {
+        Ok(Some(owner)) => owner,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("User {} not found", body.new_owner),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Database error: {}", e),
+            }));
+        }
+    };
+    if new_owner.id != repo.owner_id {
+        match quota::owner_quota(&state, new_owner.id).await {
+            Ok((quota, usage)) => {
+                if let Err(hit) = quota.check_new_repository(&usage) {
+                    return Ok(hit.response());
+                }
+            }
+            Err(e) => {
+                return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    message: format!("Database error: {}", e),
+                }));
+            }
+        }
+    }
+
+    match state
+        .repository_service
+        .transfer_ownership(repo.id, new_owner.id, body.new_name, Some(user_id))
+        .await
+    {
+        Ok(moved) => {
+            let topics = state.repository_service.get_topics(moved.id).await.unwrap_or_default();
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                message: format!("Transferred {} to {}", repo.name, new_owner.username),
+                data: Some(RepositoryResponse::from_model(moved, &new_owner.username, topics, &state.config)),
+            }))
+        }
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::RepositoryNameTaken { name }) => Ok(HttpResponse::Conflict().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Repository {} already exists", name),
+            })),
+            Some(StorageError::RepositoryNotFound { .. }) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            })),
+            Some(StorageError::UserNotFound { .. }) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("User {} not found", new_owner.username),
+            })),
+            _ => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to transfer repository: {}", e),
+            })),
+        },
+    }
+}
+
+/// Copy a repository's commits, trees and tags from `git_objects` into the typed tables
+#[post("/admin/repositories/{repo_id}/backfill-typed-tables")]
+pub async fn backfill_typed_tables(
+    path: web::Path<String>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(user_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => {
+            return Ok(HttpResponse::Forbidden().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Admin access required".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to load user: {}", e),
+            }));
+        }
+    }
+
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to load repository: {}", e),
+            }));
+        }
+    }
+
+    match state.repository_service.backfill_typed_tables(repo_id).await {
+        Ok(report) => Ok(HttpResponse::Ok().json(ApiResponse::<BackfillReport> {
+            success: true,
+            data: Some(report),
+            message: "Backfill completed".to_string(),
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("Backfill failed: {}", e),
+        })),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RebuildProjectionsQuery {
+    /// Report the rows that differ without writing
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Bring a repository's commits, trees, tags and branches tables back in line
+/// with its objects and refs, see `RepositoryService::rebuild_projections`
+#[post("/admin/repositories/{repo_id}/rebuild-projections")]
+pub async fn rebuild_projections(
+    path: web::Path<String>,
+    query: web::Query<RebuildProjectionsQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(user_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => {
+            return Ok(HttpResponse::Forbidden().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Admin access required".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to load user: {}", e),
+            }));
+        }
+    }
+
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let result = state
+        .repository_service
+        .rebuild_projections(repo_id, query.dry_run, |progress| {
+            tracing::info!(
+                repository_id = %repo_id,
+                table = ?progress.table,
+                rows_checked = progress.rows_checked,
+                differences = progress.differences,
+                "Rebuilding projections"
+            );
+        })
+        .await;
+    match result {
+        Ok(report) => {
+            let message = if report.dry_run {
+                format!("Dry run found {} differing rows", report.differences.len())
+            } else {
+                format!("Repaired {} rows", report.differences.len())
+            };
+            Ok(HttpResponse::Ok().json(ApiResponse::<ProjectionReport> {
+                success: true,
+                data: Some(report),
+                message,
+            }))
+        }
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::RepositoryNotFound { .. }) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            })),
+            _ => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Rebuild failed: {}", e),
+            })),
+        },
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ConsistencyCheckQuery {
+    /// Check one repository; without it every repository and the blob store
+    pub repository_id: Option<String>,
+    /// Apply the safe repairs, as `git-server check --fix` does
+    #[serde(default)]
+    pub fix: bool,
+}
+
+/// Check stored data for consistency, see `RepositoryService::check_consistency`
+#[post("/admin/check")]
+pub async fn check_consistency(
+    query: web::Query<ConsistencyCheckQuery>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(user_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => {
+            return Ok(HttpResponse::Forbidden().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Admin access required".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to load user: {}", e),
+            }));
+        }
+    }
+
+    let repo_id = match query.repository_id.as_deref().map(Uuid::parse_str).transpose() {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let options = CheckOptions {
+        fix: query.fix,
+        ..CheckOptions::default()
+    };
+    match state.repository_service.check_consistency(repo_id, &options).await {
+        Ok(report) => {
+            let message = if report.has_errors() {
+                "Check found errors"
+            } else {
+                "Check passed"
+            };
+            Ok(HttpResponse::Ok().json(ApiResponse::<CheckReport> {
+                success: true,
+                data: Some(report),
+                message: message.to_string(),
+            }))
+        }
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::RepositoryNotFound { .. }) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            })),
+            _ => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Check failed: {}", e),
+            })),
+        },
+    }
+}
+
+/// A repository's read guardrail overrides. `null` uses the server setting and
+/// `0` lifts the limit for this repository.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReadLimitSettings {
+    pub max_commits_walked: Option<i32>,
+    pub max_tree_entries: Option<i32>,
+    pub read_timeout_secs: Option<i32>,
+}
+
+/// Replace a repository's read guardrail overrides
+#[put("/admin/repositories/{repo_id}/read-limits")]
+pub async fn set_read_limits(
+    path: web::Path<String>,
+    body: ApiJson<ReadLimitSettings>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(user_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => {
+            return Ok(HttpResponse::Forbidden().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Admin access required".to_string(),
+            }));
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to load user: {}", e),
+            }));
+        }
+    }
+
+    let repo_id = match Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Invalid repository ID".to_string(),
+            }));
+        }
+    };
+
+    let settings = body.into_inner();
+    let negative = [
+        ("max_commits_walked", settings.max_commits_walked),
+        ("max_tree_entries", settings.max_tree_entries),
+        ("read_timeout_secs", settings.read_timeout_secs),
+    ]
+    .into_iter()
+    .find(|(_, value)| value.is_some_and(|v| v < 0));
+    if let Some((name, _)) = negative {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            message: format!("{} must not be negative", name),
+        }));
+    }
+
+    let overrides = ReadLimitOverrides {
+        max_commits_walked: settings.max_commits_walked,
+        max_tree_entries: settings.max_tree_entries,
+        read_timeout_secs: settings.read_timeout_secs,
+    };
+    match state.repository_service.set_read_limits(repo_id, overrides).await {
+        Ok(repo) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(ReadLimitSettings {
+                max_commits_walked: repo.max_commits_walked,
+                max_tree_entries: repo.max_tree_entries,
+                read_timeout_secs: repo.read_timeout_secs,
+            }),
+            message: "Read limits updated".to_string(),
+        })),
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::RepositoryNotFound { .. }) => Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: "Repository not found".to_string(),
+            })),
+            _ => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                message: format!("Failed to update read limits: {}", e),
+            })),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{basic_auth, create_test_state, create_user_with_password};
+    use crate::scopes::enforce_token_scopes;
+    use git_storage::entities::repository::Visibility;
+    use git_storage::Note;
+    use actix_web::{middleware::from_fn, test, App};
+    use git_protocol::objects::ObjectHandler;
+    use git_protocol::{GitProtocol, ObjectType};
+    use std::collections::HashMap;
+
+    #[actix_web::test]
+    async fn test_raw_blob_range_requests() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("big-files".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let blob = ObjectHandler::new().create_blob(b"0123456789abcdef").unwrap();
+        state
+            .repository_service
+            .store_object(repo.id, blob.id.clone(), ObjectKind::Blob, blob.size as i64, blob.content)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(get_raw_blob),
+            ),
+        )
+        .await;
+        let request = |range: Option<&str>| {
+            let mut req = test::TestRequest::get()
+                .uri(&format!("/api/repositories/{}/blobs/{}/raw", repo.id, blob.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+            if let Some(range) = range {
+                req = req.insert_header((header::RANGE, range.to_string()));
+            }
+            req.to_request()
+        };
+
+        let resp = test::call_service(&app, request(None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::ACCEPT_RANGES).unwrap(), "bytes");
+        assert_eq!(test::read_body(resp).await.as_ref(), b"0123456789abcdef");
+
+        let resp = test::call_service(&app, request(Some("bytes=4-9"))).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 4-9/16");
+        assert_eq!(test::read_body(resp).await.as_ref(), b"456789");
+
+        // Resuming from an offset and asking for the tail both work
+        let resp = test::call_service(&app, request(Some("bytes=12-"))).await;
+        assert_eq!(test::read_body(resp).await.as_ref(), b"cdef");
+        let resp = test::call_service(&app, request(Some("bytes=-3"))).await;
+        assert_eq!(test::read_body(resp).await.as_ref(), b"def");
+
+        let resp = test::call_service(&app, request(Some("bytes=100-200"))).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers().get(header::CONTENT_RANGE).unwrap(), "bytes */16");
+        let resp = test::call_service(&app, request(Some("lines=1-2"))).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+
+    #[actix_web::test]
+    async fn test_text_blobs_report_binary_and_line_endings() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("texts".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let utf16: Vec<u8> = [0xFF, 0xFE]
+            .into_iter()
+            .chain("hi\r\n".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        let files = [
+            ("utf8.txt", "grüße\n".as_bytes().to_vec()),
+            ("utf16.txt", utf16),
+            ("mixed.txt", b"one\ntwo\r\nthree\r\n".to_vec()),
+        ];
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        let write = |path: &str, content: Vec<u8>| (path.to_string(), FileChange::Write(content));
+        let base = git_ops
+            .commit_files(
+                state.repository_service.get_db(),
+                repo.id,
+                "main",
+                files.iter().map(|(path, content)| write(path, content.clone())).collect(),
+                "Owner <owner@example.com> 1700000000 +0000".to_string(),
+                "Add files".to_string(),
+            )
+            .await
+            .unwrap();
+        let crlf_only = git_ops
+            .commit_files(
+                state.repository_service.get_db(),
+                repo.id,
+                "main",
+                vec![write("mixed.txt", b"one\r\ntwo\r\nthree\r\n".to_vec())],
+                "Owner <owner@example.com> 1700000000 +0000".to_string(),
+                "Use CRLF throughout".to_string(),
+            )
+            .await
+            .unwrap();
+        let handler = ObjectHandler::new();
+        let ids: Vec<String> = files.iter().map(|(_, content)| handler.create_blob(content).unwrap().id).collect();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(from_fn(enforce_token_scopes))
+                    .service(get_raw_blob)
+                    .service(batch_get_objects)
+                    .service(get_diff),
+            ),
+        )
+        .await;
+        let get = |uri: String| {
+            test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+        let raw = |id: &str, query: &str| format!("/api/repositories/{}/blobs/{}/raw{}", repo.id, id, query);
+
+        let resp = test::call_service(&app, get(raw(&ids[0], ""))).await;
+        assert_eq!(resp.headers().get(BINARY_HEADER).unwrap(), "false");
+        assert_eq!(resp.headers().get(LINE_ENDING_HEADER).unwrap(), "lf");
+
+        // UTF-16 is binary by git's heuristic and normalizing leaves it alone
+        let resp = test::call_service(&app, get(raw(&ids[1], "?normalize_eol=true"))).await;
+        assert_eq!(resp.headers().get(BINARY_HEADER).unwrap(), "true");
+        assert!(resp.headers().get(LINE_ENDING_HEADER).is_none());
+        assert_eq!(test::read_body(resp).await.as_ref(), files[1].1.as_slice());
+
+        let resp = test::call_service(&app, get(raw(&ids[2], ""))).await;
+        assert_eq!(resp.headers().get(LINE_ENDING_HEADER).unwrap(), "mixed");
+        assert_eq!(test::read_body(resp).await.as_ref(), b"one\ntwo\r\nthree\r\n");
+        let resp = test::call_service(&app, get(raw(&ids[2], "?normalize_eol=true"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(header::ACCEPT_RANGES).is_none());
+        assert_eq!(test::read_body(resp).await.as_ref(), b"one\ntwo\nthree\n");
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/repositories/{}/objects/batch", repo.id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .set_json(serde_json::json!({ "ids": ids }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let objects = body["data"]["objects"].as_array().unwrap();
+        let flags: Vec<_> = objects.iter().map(|o| (o["is_binary"].clone(), o["line_ending"].clone())).collect();
+        assert_eq!(
+            flags,
+            vec![
+                (serde_json::json!(false), serde_json::json!("lf")),
+                (serde_json::json!(true), serde_json::Value::Null),
+                (serde_json::json!(false), serde_json::json!("mixed")),
+            ]
+        );
+
+        // A change of line endings alone leaves no hunks once normalized
+        let diff = |query: &str| format!("/api/repositories/{}/diff?from={}&to={}{}", repo.id, base, crlf_only, query);
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get(diff(""))).await;
+        assert_eq!(body["data"][0]["additions"], 1);
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get(diff("&normalize_eol=true"))).await;
+        assert_eq!(body["data"][0]["additions"], 0);
+        assert_eq!(body["data"][0]["hunks"], serde_json::json!([]));
+    }
+
+    #[actix_web::test]
+    async fn test_large_blob_download_resumes_from_a_range() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("assets".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let content: Vec<u8> = (0..5 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let blob = ObjectHandler::new().create_blob(&content).unwrap();
+        state
+            .repository_service
+            .store_object(repo.id, blob.id.clone(), ObjectKind::Blob, blob.size as i64, blob.content)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(get_raw_blob),
+            ),
+        )
+        .await;
+        let request = |range: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/api/repositories/{}/blobs/{}/raw", repo.id, blob.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .insert_header((header::RANGE, range.to_string()))
+                .to_request()
+        };
+
+        // A download cut off after 3 MB picks up where it stopped
+        let split = 3 * 1024 * 1024;
+        let resp = test::call_service(&app, request(&format!("bytes=0-{}", split - 1))).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_RANGE).unwrap().to_str().unwrap(),
+            format!("bytes 0-{}/{}", split - 1, content.len())
+        );
+        let mut downloaded = test::read_body(resp).await.to_vec();
+
+        let resp = test::call_service(&app, request(&format!("bytes={}-", split))).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_RANGE).unwrap().to_str().unwrap(),
+            format!("bytes {}-{}/{}", split, content.len() - 1, content.len())
+        );
+        downloaded.extend_from_slice(&test::read_body(resp).await);
+        assert!(downloaded == content);
+
+        // Several ranges at once aren't served
+        let resp = test::call_service(&app, request("bytes=0-9,20-29")).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_RANGE).unwrap().to_str().unwrap(),
+            format!("bytes */{}", content.len())
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_fork_copies_branches_and_head() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let forker = state
+            .user_service
+            .create_user("forker".to_string(), "forker@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("upstream".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let handler = ObjectHandler::new();
+        let tree = handler.parse_object(ObjectType::Tree, b"").unwrap();
+        let commit = |message: &str, parents: Vec<String>| {
+            handler
+                .create_commit(&git_protocol::objects::Commit {
+                    tree: tree.id.clone(),
+                    parents,
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: message.to_string(),
+                    author_date: chrono::Utc::now(),
+                    commit_date: chrono::Utc::now(),
+                    encoding: None,
+                })
+                .unwrap()
+        };
+        let main = commit("Initial commit\n", vec![]);
+        let feature = commit("Feature work\n", vec![main.id.clone()]);
+        for (obj, object_type) in [(&tree, ObjectKind::Tree), (&main, ObjectKind::Commit), (&feature, ObjectKind::Commit)] {
+            state
+                .repository_service
+                .store_object(repo.id, obj.id.clone(), object_type, obj.size as i64, obj.content.clone())
+                .await
+                .unwrap();
+        }
+        for (name, target, is_symbolic) in [
+            ("refs/heads/main", main.id.as_str(), false),
+            ("refs/heads/feature", feature.id.as_str(), false),
+            ("HEAD", "refs/heads/main", true),
+        ] {
+            state
+                .repository_service
+                .store_ref(repo.id, name.to_string(), target.to_string(), is_symbolic)
+                .await
+                .unwrap();
+        }
+        let (_, token) = state
+            .token_service
+            .create_token(forker.id, "test".to_string(), &["repo:write".to_string()], None)
+            .await
+            .unwrap();
+        let repository_service = state.repository_service.clone();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(fork_repository),
+            ),
+        )
+        .await;
+        let fork_request = || {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/fork", repo.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, fork_request()).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: ApiResponse<RepositoryResponse> = test::read_body_json(resp).await;
+        let fork = body.data.unwrap();
+        assert_eq!(fork.name, "upstream-forker");
+        assert_eq!(fork.owner_id, forker.id.to_string());
+        assert_eq!(fork.default_branch, "main");
+        assert_eq!(fork.forked_from, Some(repo.id.to_string()));
+
+        let fork_id = Uuid::parse_str(&fork.id).unwrap();
+        for (name, target, is_symbolic) in [
+            ("refs/heads/main", &main.id, false),
+            ("refs/heads/feature", &feature.id, false),
+            ("HEAD", &"refs/heads/main".to_string(), true),
+        ] {
+            let git_ref = repository_service.get_ref(fork_id, name).await.unwrap().unwrap();
+            assert_eq!((&git_ref.target, git_ref.is_symbolic), (target, is_symbolic), "{}", name);
+        }
+        for obj in [&tree, &main, &feature] {
+            assert!(repository_service.object_exists(fork_id, &obj.id).await.unwrap());
+        }
+
+        // The default name is taken now
+        let resp = test::call_service(&app, fork_request()).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_web::test]
+    async fn test_transfer_hands_repository_to_another_user() {
+        let state = create_test_state().await;
+        let mut users = Vec::new();
+        for name in ["owner", "recipient", "bystander"] {
+            let user = state
+                .user_service
+                .create_user(name.to_string(), format!("{}@example.com", name), "hash".to_string(), None, false)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let (owner, recipient, bystander) = (&users[0], &users[1], &users[2]);
+        let repo = state
+            .repository_service
+            .create_repository("project".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        state
+            .repository_service
+            .create_repository("recipient-project".to_string(), None, "main".to_string(), recipient.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let mut tokens = Vec::new();
+        for user in [owner, bystander] {
+            let (_, token) = state
+                .token_service
+                .create_token(user.id, "test".to_string(), &["repo:admin".to_string()], None)
+                .await
+                .unwrap();
+            tokens.push(token);
+        }
+        let repository_service = state.repository_service.clone();
+        let event_service = state.event_service.clone();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(transfer_repository),
+            ),
+        )
+        .await;
+        let transfer = |token: &str, body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/transfer", repo.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(body)
+                .to_request()
+        };
+
+        // Only the owner (or an admin) may give it away
+        let resp = test::call_service(&app, transfer(&tokens[1], serde_json::json!({"new_owner": "bystander"}))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // A rename onto a name the recipient already has is a conflict, and nothing moves
+        let resp = test::call_service(
+            &app,
+            transfer(&tokens[0], serde_json::json!({"new_owner": "recipient", "new_name": "recipient-project"})),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(repository_service.get_repository_by_id(repo.id).await.unwrap().unwrap().owner_id, owner.id);
+
+        let resp = test::call_service(&app, transfer(&tokens[0], serde_json::json!({"new_owner": "nobody"}))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = test::call_service(&app, transfer(&tokens[0], serde_json::json!({"new_owner": "recipient"}))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"]["name"], "project");
+        let moved = repository_service.get_repository_by_id(repo.id).await.unwrap().unwrap();
+        assert_eq!(moved.owner_id, recipient.id);
+        let events = event_service.list_by_repository(repo.id, 10).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, "transferred");
+        assert_eq!(events[0].actor_id, Some(owner.id));
+
+        // The old owner no longer administers it
+        let resp = test::call_service(&app, transfer(&tokens[0], serde_json::json!({"new_owner": "owner"}))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_hidden_refs_are_left_out_of_the_advertisement() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("reviews".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .update_repository(
+                repo.id,
+                repo.version,
+                git_storage::RepositoryUpdate {
+                    hidden_ref_prefixes: Some(vec!["refs/pull/*".to_string()]),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let visible_tip = "1".repeat(40);
+        let hidden_tip = "2".repeat(40);
+        for (name, target) in [("refs/heads/main", &visible_tip), ("refs/pull/1/head", &hidden_tip)] {
+            state
+                .repository_service
+                .store_ref(repo.id, name.to_string(), target.clone(), false)
+                .await
+                .unwrap();
+        }
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(list_refs))
+                .service(
+                    web::scope("/git")
+                        .service(crate::http::info_refs)
+                        .service(crate::http::upload_pack),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/git/reviews/info/refs?service=git-upload-pack")
+            .to_request();
+        let advertisement = test::read_body(test::call_service(&app, req).await).await;
+        let advertisement = String::from_utf8_lossy(&advertisement);
+        assert!(advertisement.contains("refs/heads/main"));
+        assert!(!advertisement.contains("refs/pull/"));
+
+        // Fetching the hidden tip by id is refused
+        let body = format!("0032want {}\n00000009done\n", hidden_tip);
+        let req = test::TestRequest::post()
+            .uri("/git/reviews/git-upload-pack")
+            .set_payload(body)
+            .to_request();
+        let response = test::read_body(test::call_service(&app, req).await).await;
+        assert!(String::from_utf8_lossy(&response).contains("ERR upload-pack: not our ref"));
+
+        let list = |include_hidden: bool| {
+            test::TestRequest::get()
+                .uri(&format!("/api/repositories/{}/refs?include_hidden={}", repo.id, include_hidden))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+        let body: ApiResponse<Vec<RefInfo>> = test::call_and_read_body_json(&app, list(false)).await;
+        let names: Vec<_> = body.data.unwrap().into_iter().map(|r| r.name).collect();
+        assert_eq!(names, vec!["refs/heads/main"]);
+
+        let body: ApiResponse<Vec<RefInfo>> = test::call_and_read_body_json(&app, list(true)).await;
+        let refs = body.data.unwrap();
+        assert!(refs.iter().any(|r| r.name == "refs/pull/1/head" && r.hidden));
+    }
+
+    #[actix_web::test]
+    async fn test_history_filter_validation_names_the_parameter() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("history".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(get_commit_history),
+            ),
+        )
+        .await;
+
+        for (query, parameter) in [
+            ("since=yesterday", "'since'"),
+            ("until=2024-13-01T00:00:00Z", "'until'"),
+            ("since=2024-02-01T00:00:00Z&until=2024-01-01T00:00:00Z", "'until'"),
+            ("author=%20", "'author'"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/repositories/{}/branches/main/commits?{}", repo.id, query))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", query);
+            let body: ApiResponse<()> = test::read_body_json(resp).await;
+            assert!(body.message.contains(parameter), "{}: {}", query, body.message);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_commit_downloads_as_a_format_patch() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("patches".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        let mut commits = Vec::new();
+        for (content, message) in [("hi\n", "Add a greeting"), ("hello\n", "Fix the greeting\n\nIt was too short.")] {
+            let sha = git_ops
+                .commit_files(
+                    state.repository_service.get_db(),
+                    repo.id,
+                    "main",
+                    vec![("greeting.txt".to_string(), FileChange::Write(content.as_bytes().to_vec()))],
+                    "Owner <owner@example.com>".to_string(),
+                    message.to_string(),
+                )
+                .await
+                .unwrap();
+            commits.push(sha);
+        }
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(from_fn(enforce_token_scopes))
+                    .service(get_commit_patch)
+                    .service(get_commit),
+            ),
+        )
+        .await;
+        let request = |uri: String| {
+            test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request(format!("/api/repositories/{}/commits/{}.patch", repo.id, commits[1]))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/x-patch"));
+        let patch = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(patch.starts_with(&format!("From {} Mon Sep 17 00:00:00 2001\n", commits[1])));
+        assert!(patch.contains("\nFrom: Owner <owner@example.com>\n"));
+        assert!(patch.contains("\nSubject: [PATCH] Fix the greeting\n\nIt was too short.\n\n---\n"));
+        assert!(patch.contains("\ndiff --git a/greeting.txt b/greeting.txt\nindex "));
+        assert!(patch.contains("--- a/greeting.txt\n+++ b/greeting.txt\n@@ -1 +1 @@\n-hi\n+hello\n"));
+        assert!(patch.ends_with(&format!("-- \n{}\n\n", env!("CARGO_PKG_VERSION"))));
+
+        // A root commit is diffed against the empty tree
+        let resp = test::call_service(&app, request(format!("/api/repositories/{}/commits/{}.patch", repo.id, commits[0]))).await;
+        let patch = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(patch.contains("new file mode 100644\n"));
+        assert!(patch.contains("--- /dev/null\n+++ b/greeting.txt\n@@ -0,0 +1 @@\n+hi\n"));
+
+        // The plain commit route still answers with JSON
+        let resp = test::call_service(&app, request(format!("/api/repositories/{}/commits/{}", repo.id, commits[1]))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"]["hash"], commits[1].as_str());
+
+        let resp = test::call_service(&app, request(format!("/api/repositories/{}/commits/{}.patch", repo.id, "0".repeat(40)))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_commit_and_compare_diffs() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("diffs".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        let db = state.repository_service.get_db();
+        let commit = |branch: &'static str, path: &str, content: &str| {
+            git_ops.commit_files(
+                db,
+                repo.id,
+                branch,
+                vec![(path.to_string(), FileChange::Write(content.as_bytes().to_vec()))],
+                "Owner <owner@example.com>".to_string(),
+                format!("Write {}", path),
+            )
+        };
+        let base = commit("main", "greeting.txt", "hi\n").await.unwrap();
+        git_ops.create_branch(db, repo.id, "feature".to_string(), base.clone()).await.unwrap();
+        let feature = commit("feature", "feature.txt", "new feature\n").await.unwrap();
+        // Moves on after the fork, so it stays out of main...feature
+        commit("main", "greeting.txt", "hello\n").await.unwrap();
+        commit("unrelated", "other.txt", "elsewhere\n").await.unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(from_fn(enforce_token_scopes))
+                    .service(get_commit_diff)
+                    .service(get_commit)
+                    .service(compare_diff)
+                    .service(diff_since),
+            ),
+        )
+        .await;
+        let request = |uri: String| {
+            test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request(format!("/api/repositories/{}/commits/{}.diff", repo.id, feature))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/x-diff"));
+        let diff = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(diff.starts_with("diff --git a/feature.txt b/feature.txt\nnew file mode 100644\n"), "{}", diff);
+        assert!(diff.ends_with("--- /dev/null\n+++ b/feature.txt\n@@ -0,0 +1 @@\n+new feature\n"));
+
+        let resp = test::call_service(&app, request(format!("/api/repositories/{}/compare/main...feature.diff", repo.id))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let compared = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(compared, diff);
+
+        // The other way round shows main's change since the fork
+        let resp = test::call_service(&app, request(format!("/api/repositories/{}/compare/feature...main.diff", repo.id))).await;
+        let compared = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(compared.contains("@@ -1 +1 @@\n-hi\n+hello\n"), "{}", compared);
+        assert!(!compared.contains("feature.txt"));
+
+        for (range, status) in [
+            ("main...missing", StatusCode::NOT_FOUND),
+            ("main..feature", StatusCode::BAD_REQUEST),
+            ("main...unrelated", StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            let resp = test::call_service(&app, request(format!("/api/repositories/{}/compare/{}.diff", repo.id, range))).await;
+            assert_eq!(resp.status(), status, "{}", range);
+        }
+        let resp = test::call_service(&app, request(format!("/api/repositories/{}/commits/{}.diff", repo.id, "0".repeat(40)))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Since a commit in main's history: only main's own change; feature's tip isn't in it
+        let resp = test::call_service(&app, request(format!("/api/repositories/{}/branches/main/diff-since?commit={}", repo.id, base))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let paths: Vec<_> = body["data"].as_array().unwrap().iter().map(|d| d["path"].clone()).collect();
+        assert_eq!(paths, vec!["greeting.txt"]);
+        for (branch, since, status) in [
+            ("main", feature.as_str(), StatusCode::UNPROCESSABLE_ENTITY),
+            ("missing", base.as_str(), StatusCode::NOT_FOUND),
+        ] {
+            let uri = format!("/api/repositories/{}/branches/{}/diff-since?commit={}", repo.id, branch, since);
+            assert_eq!(test::call_service(&app, request(uri)).await.status(), status, "{}", branch);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compare_fork_branch_against_upstream() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let upstream = state
+            .repository_service
+            .create_repository("upstream".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let elsewhere = state
+            .repository_service
+            .create_repository("elsewhere".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        let db = state.repository_service.get_db();
+        let commit = |repo_id: Uuid, branch: &'static str, path: &str, content: &str| {
+            git_ops.commit_files(
+                db,
+                repo_id,
+                branch,
+                vec![(path.to_string(), FileChange::Write(content.as_bytes().to_vec()))],
+                "Owner <owner@example.com>".to_string(),
+                format!("Write {}", path),
+            )
+        };
+        let base = commit(upstream.id, "main", "greeting.txt", "hi\n").await.unwrap();
+        commit(elsewhere.id, "main", "other.txt", "unrelated\n").await.unwrap();
+        let fork = state
+            .repository_service
+            .fork_repository(upstream.id, "fork".to_string(), owner.id)
+            .await
+            .unwrap();
+        git_ops.create_branch(db, fork.id, "feature".to_string(), base).await.unwrap();
+        commit(fork.id, "feature", "feature.txt", "new feature\n").await.unwrap();
+        // Upstream moves on after the fork, so it stays out of the comparison
+        commit(upstream.id, "main", "greeting.txt", "hello\n").await.unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(from_fn(enforce_token_scopes))
+                    .service(compare_diff)
+                    .service(compare_across_repositories),
+            ),
+        )
+        .await;
+        let compare = |base_repo: Uuid, base: &str, head_repo: Uuid, head: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/api/repositories/{}/compare/{}?base={}&head={}", base_repo, head_repo, base, head))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+        let paths = |body: serde_json::Value| -> Vec<serde_json::Value> {
+            body["data"].as_array().unwrap().iter().map(|d| d["path"].clone()).collect()
+        };
+
+        let resp = test::call_service(&app, compare(upstream.id, "main", fork.id, "feature")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(paths(test::read_body_json(resp).await), vec!["feature.txt"]);
+
+        // The other way round shows upstream's change since the fork
+        let resp = test::call_service(&app, compare(fork.id, "feature", upstream.id, "main")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(paths(test::read_body_json(resp).await), vec!["greeting.txt"]);
+
+        // Each ref is looked up in its own repository
+        let resp = test::call_service(&app, compare(upstream.id, "feature", fork.id, "feature")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, compare(elsewhere.id, "main", fork.id, "feature")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[actix_web::test]
+    async fn test_pushed_and_posted_notes_read_back() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("notes".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let commit = GitOperations::new(state.repository_service.as_ref().clone())
+            .commit_files(
+                state.repository_service.get_db(),
+                repo.id,
+                "main",
+                vec![("README".to_string(), FileChange::Write(b"hi\n".to_vec()))],
+                "Owner <owner@example.com> 1700000000 +0000".to_string(),
+                "Initial commit".to_string(),
+            )
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:write".to_string()], None)
+            .await
+            .unwrap();
+
+        // A notes commit as `git notes add` builds it, pushed like any other ref
+        let handler = ObjectHandler::new();
+        let blob = handler.create_blob(b"pushed note\n").unwrap();
+        let tree = handler
+            .create_tree(&git_protocol::objects::Tree {
+                entries: vec![git_protocol::objects::TreeEntry {
+                    mode: "100644".to_string(),
+                    name: commit.clone(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let notes_commit = handler
+            .create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Notes added by 'git notes add'\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let protocol = git_protocol::ProtocolHandler::new();
+        let command = format!("{} {} refs/notes/commits\0report-status", git_protocol::ZERO_ID, notes_commit.id);
+        let mut push = protocol.create_pkt_line(&[command.as_str()]);
+        push.extend(protocol.create_pack(&[blob, tree, notes_commit.clone()]).unwrap());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(
+                    web::scope("/api")
+                        .wrap(from_fn(enforce_token_scopes))
+                        .service(get_commit_note)
+                        .service(set_commit_note),
+                )
+                .service(web::scope("/git").service(crate::http::receive_pack)),
+        )
+        .await;
+        let report = test::call_and_read_body(
+            &app,
+            test::TestRequest::post()
+                .uri("/git/notes/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(push)
+                .to_request(),
+        )
+        .await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/notes/commits"));
+
+        let notes_uri = |sha: &str, query: &str| format!("/api/repositories/{}/commits/{}/notes{}", repo.id, sha, query);
+        let get = |uri: String| {
+            test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+        let post = |uri: String, note: &str| {
+            test::TestRequest::post()
+                .uri(&uri)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({"note": note, "author": "Owner <owner@example.com> 1700000100 +0000"}))
+                .to_request()
+        };
+
+        let body: ApiResponse<Note> = test::call_and_read_body_json(&app, get(notes_uri(&commit, ""))).await;
+        assert_eq!(body.data.unwrap().content, "pushed note\n");
+
+        // Posting replaces the note with a commit on top of the pushed one
+        let resp = test::call_service(&app, post(notes_uri(&commit, ""), "Tested-by: CI")).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: ApiResponse<Note> = test::call_and_read_body_json(&app, get(notes_uri(&commit, ""))).await;
+        let note = body.data.unwrap();
+        assert_eq!((note.notes_ref.as_str(), note.content.as_str()), ("refs/notes/commits", "Tested-by: CI\n"));
+
+        // Other notes refs are separate
+        let resp = test::call_service(&app, get(notes_uri(&commit, "?ref=review"))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, post(notes_uri(&commit, "?ref=review"), "Looks good")).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: ApiResponse<Note> =
+            test::call_and_read_body_json(&app, get(notes_uri(&commit, "?ref=refs/notes/review"))).await;
+        assert_eq!(body.data.unwrap().content, "Looks good\n");
+
+        let resp = test::call_service(&app, post(notes_uri(&"0".repeat(40), ""), "nothing")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, post(notes_uri(&commit, "?ref=refs/heads/main"), "not a notes ref")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_deep_history_trips_the_commit_guardrail() {
+        let mut state = create_test_state().await;
+        state.config = std::sync::Arc::new(crate::config::Config {
+            max_commits_walked: Some(5),
+            ..(*state.config).clone()
+        });
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let admin = state
+            .user_service
+            .create_user("admin".to_string(), "admin@example.com".to_string(), "hash".to_string(), None, true)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("deep".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        for i in 0..30 {
+            git_ops
+                .commit_files(
+                    state.repository_service.get_db(),
+                    repo.id,
+                    "main",
+                    vec![("counter.txt".to_string(), FileChange::Write(i.to_string().into_bytes()))],
+                    "Owner <owner@example.com>".to_string(),
+                    format!("Commit {}", i),
+                )
+                .await
+                .unwrap();
+        }
+        let (_, reader_token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+        let (_, admin_token) = state
+            .token_service
+            .create_token(admin.id, "test".to_string(), &["admin".to_string()], None)
+            .await
+            .unwrap();
+        let metrics = state.guardrail_metrics.clone();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(from_fn(enforce_token_scopes))
+                    .service(get_commit_history)
+                    .service(set_read_limits),
+            ),
+        )
+        .await;
+        let history = || {
+            test::TestRequest::get()
+                .uri(&format!("/api/repositories/{}/branches/main/commits", repo.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", reader_token)))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, history()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["success"], false);
+        assert_eq!(body["code"], "commit_limit_exceeded");
+        assert_eq!(body["limit"], 5);
+        assert_eq!(metrics.snapshot().commit_limit_exceeded, 1);
+
+        // Owners can't lift the limit, admins can
+        let lift = |token: &str| {
+            test::TestRequest::put()
+                .uri(&format!("/api/admin/repositories/{}/read-limits", repo.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(ReadLimitSettings { max_commits_walked: Some(0), ..Default::default() })
+                .to_request()
+        };
+        let resp = test::call_service(&app, lift(&reader_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, lift(&admin_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(&app, history()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: ApiResponse<git_storage::CommitHistory> = test::read_body_json(resp).await;
+        assert_eq!(body.data.unwrap().commits.len(), 30);
+        assert_eq!(metrics.snapshot().commit_limit_exceeded, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_object_batch_binary_encoding() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("tooling".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let handler = ObjectHandler::new();
+        let binary: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+        let objects = vec![
+            handler.create_blob(&binary).unwrap(),
+            handler.parse_object(ObjectType::Tree, b"").unwrap(),
+        ];
+        for obj in &objects {
+            let object_type = ObjectKind::from(&obj.obj_type);
+            state
+                .repository_service
+                .store_object(repo.id, obj.id.clone(), object_type, obj.size as i64, obj.content.clone())
+                .await
+                .unwrap();
+        }
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(batch_get_objects),
+            ),
+        )
+        .await;
+        let missing = "0".repeat(40);
+        let request = |accept: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/objects/batch", repo.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .insert_header((header::ACCEPT, accept.to_string()))
+                .set_json(BatchObjectsRequest {
+                    ids: vec![objects[0].id.clone(), missing.clone(), objects[1].id.clone()],
+                })
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request("application/x-git-objects, application/json;q=0.5")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), BATCH_MEDIA_TYPE);
+        let binary_body = test::read_body(resp).await;
+        let entries = git_protocol::batch::decode_batch(&binary_body).unwrap();
+        assert_eq!(entries.len(), 3);
+        match (&entries[0], &entries[1], &entries[2]) {
+            (BatchEntry::Found(blob), BatchEntry::Missing(id), BatchEntry::Found(tree)) => {
+                assert_eq!(blob.id, objects[0].id);
+                assert_eq!(blob.obj_type, ObjectType::Blob);
+                assert_eq!(blob.content, binary);
+                assert_eq!(id, &missing);
+                assert_eq!(tree.id, objects[1].id);
+                assert!(tree.content.is_empty());
+            }
+            other => panic!("unexpected entries: {:?}", other),
+        }
+
+        // JSON stays the default, and costs a third more for the same content
+        let resp = test::call_service(&app, request("application/json")).await;
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        let json_body = test::read_body(resp).await;
+        let batch: ApiResponse<ObjectBatch> = serde_json::from_slice(&json_body).unwrap();
+        let batch = batch.data.unwrap();
+        assert_eq!(batch.missing, vec![missing]);
+        assert_eq!(BASE64_STANDARD.decode(&batch.objects[0].content).unwrap(), binary);
+        assert!(binary_body.len() * 4 < json_body.len() * 3);
+    }
+
+    #[actix_web::test]
+    async fn test_json_bodies_are_limited_and_validated() {
+        use crate::payload::{json_config, API_JSON_LIMIT, CONTENTS_JSON_LIMIT};
+
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("uploads".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:write".to_string()], None)
+            .await
+            .unwrap();
+        let repository_service = state.repository_service.clone();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(from_fn(enforce_token_scopes))
+                    .app_data(json_config(API_JSON_LIMIT))
+                    .service(create_branch)
+                    .service(
+                        web::scope("")
+                            .app_data(json_config(CONTENTS_JSON_LIMIT))
+                            .app_data(web::PayloadConfig::new(1024 * 1024))
+                            .service(commit_files)
+                            .service(put_raw_contents),
+                    ),
+            ),
+        )
+        .await;
+        let bearer = (header::AUTHORIZATION, format!("Bearer {}", token));
+        let error = |resp| async move {
+            let resp: actix_web::dev::ServiceResponse = resp;
+            let status = resp.status();
+            let body: ApiResponse<()> = test::read_body_json(resp).await;
+            assert!(!body.success);
+            (status, body.message)
+        };
+
+        // Over the API limit: refused before anything is parsed
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/repositories/{}/branches", repo.id))
+            .insert_header(bearer.clone())
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload(format!(r#"{{"name":"{}","start_commit":"x"}}"#, "a".repeat(API_JSON_LIMIT)))
+            .to_request();
+        let (status, message) = error(test::call_service(&app, req).await).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(message.contains(&API_JSON_LIMIT.to_string()), "{}", message);
+
+        // Not JSON
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/repositories/{}/branches", repo.id))
+            .insert_header(bearer.clone())
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .set_payload(r#"{"name":"topic","start_commit":"x"}"#)
+            .to_request();
+        let (status, message) = error(test::call_service(&app, req).await).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(message, "Content-Type must be application/json");
+
+        // Valid JSON of the wrong shape names the field
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/repositories/{}/contents", repo.id))
+            .insert_header(bearer.clone())
+            .set_json(serde_json::json!({
+                "branch": "main",
+                "author": "A U Thor <author@example.com> 1700000000 +0000",
+                "message": "Add files",
+                "files": [{"path": "README", "delete": "yes"}],
+            }))
+            .to_request();
+        let (status, message) = error(test::call_service(&app, req).await).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(message.contains("'files[0].delete'"), "{}", message);
+
+        // Contents bodies get the larger limit
+        let large = "x".repeat(API_JSON_LIMIT * 2);
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/repositories/{}/contents", repo.id))
+            .insert_header(bearer.clone())
+            .set_json(serde_json::json!({
+                "branch": "main",
+                "author": "A U Thor <author@example.com> 1700000000 +0000",
+                "message": "Add a large file",
+                "files": [{"path": "large.txt", "content": large}],
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        // ...and raw uploads skip JSON altogether
+        let binary: Vec<u8> = (0..=255u8).cycle().take(API_JSON_LIMIT * 2).collect();
+        let req = test::TestRequest::put()
+            .uri(&format!(
+                "/api/repositories/{}/contents/assets/logo.bin?branch=main&message=Add%20logo&author={}",
+                repo.id, "A%20U%20Thor%20%3Cauthor%40example.com%3E%201700000000%20%2B0000"
+            ))
+            .insert_header(bearer.clone())
+            .insert_header((header::CONTENT_TYPE, "application/octet-stream"))
+            .set_payload(binary.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: ApiResponse<String> = test::read_body_json(resp).await;
+        let commit = repository_service.get_commit_object(repo.id, &body.data.unwrap()).await.unwrap().unwrap();
+        let root = repository_service.get_tree_object(repo.id, &commit.tree).await.unwrap().unwrap();
+        let assets = root.entries.iter().find(|e| e.name == "assets").unwrap();
+        let assets = repository_service.get_tree_object(repo.id, &assets.hash).await.unwrap().unwrap();
+        assert_eq!(assets.entries[0].name, "logo.bin");
+        let size = repository_service.get_blob_size(repo.id, &assets.entries[0].hash).await.unwrap();
+        assert_eq!(size, Some(binary.len() as u64));
+    }
+
+    #[actix_web::test]
+    async fn test_repository_permissions_per_caller() {
+        let state = create_test_state().await;
+        let mut users = Vec::new();
+        for (name, is_admin) in [("owner", false), ("stranger", false), ("root", true)] {
+            let user = state
+                .user_service
+                .create_user(name.to_string(), format!("{}@example.com", name), "hash".to_string(), None, is_admin)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let mut tokens = HashMap::new();
+        for user in &users {
+            let (_, token) = state
+                .token_service
+                .create_token(user.id, "test".to_string(), &["repo:admin".to_string()], None)
+                .await
+                .unwrap();
+            tokens.insert(user.username.clone(), token);
+        }
+        let (_, read_only) = state
+            .token_service
+            .create_token(users[0].id, "ci".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+        tokens.insert("owner (repo:read)".to_string(), read_only);
+        let open = state
+            .repository_service
+            .create_repository("open".to_string(), None, "main".to_string(), users[0].id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let secret = state
+            .repository_service
+            .create_repository("secret".to_string(), None, "main".to_string(), users[0].id, Visibility::Private, None)
+            .await
+            .unwrap();
+        let shelved = state
+            .repository_service
+            .create_repository("shelved".to_string(), None, "main".to_string(), users[0].id, Visibility::Public, None)
+            .await
+            .unwrap();
+        state
+            .repository_service
+            .update_repository(
+                shelved.id,
+                shelved.version,
+                git_storage::RepositoryUpdate {
+                    is_archived: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(from_fn(enforce_token_scopes))
+                    .service(get_repository_permissions),
+            ),
+        )
+        .await;
+        let permissions = |caller: Option<&str>, repo_id: Uuid| {
+            let mut req = test::TestRequest::get().uri(&format!("/api/repositories/{}/permissions", repo_id));
+            if let Some(caller) = caller {
+                req = req.insert_header((header::AUTHORIZATION, format!("Bearer {}", tokens[caller])));
+            }
+            let app = &app;
+            async move {
+                let resp = test::call_service(app, req.to_request()).await;
+                let status = resp.status();
+                let body: serde_json::Value = test::read_body_json(resp).await;
+                (status, serde_json::from_value::<Permissions>(body["data"].clone()).ok())
+            }
+        };
+        let allowed = |read, write, admin| Some(Permissions { read, write, admin });
+
+        for (caller, repo_id, expected) in [
+            (Some("owner"), open.id, allowed(true, true, true)),
+            (Some("stranger"), open.id, allowed(true, false, false)),
+            (None, open.id, allowed(true, false, false)),
+            (Some("root"), open.id, allowed(true, true, true)),
+            (Some("root"), secret.id, allowed(true, true, true)),
+            (Some("owner"), shelved.id, allowed(true, false, true)),
+            (Some("owner (repo:read)"), open.id, allowed(true, false, false)),
+        ] {
+            let (status, permissions) = permissions(caller, repo_id).await;
+            assert_eq!(status, StatusCode::OK, "{:?} on {}", caller, repo_id);
+            assert_eq!(permissions, expected, "{:?} on {}", caller, repo_id);
+        }
+
+        // Repositories the caller can't read don't exist for them
+        assert_eq!(permissions(Some("stranger"), secret.id).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(permissions(None, secret.id).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(permissions(Some("owner"), Uuid::new_v4()).await.0, StatusCode::NOT_FOUND);
+        let req = test::TestRequest::get().uri("/api/repositories/not-a-uuid/permissions").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_merges_into_linear_branches_must_fast_forward() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:write".to_string()], None)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("linear".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .update_repository(
+                repo.id,
+                repo.version,
+                git_storage::RepositoryUpdate {
+                    linear_history_branches: Some(vec!["main".to_string()]),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let service = state.repository_service.clone();
+        let git_ops = GitOperations::new(service.as_ref().clone());
+        let commit = |branch: &'static str, message: &'static str| {
+            let (git_ops, service) = (&git_ops, &service);
+            async move {
+                git_ops
+                    .commit_files(
+                        service.get_db(),
+                        repo.id,
+                        branch,
+                        vec![(format!("{}.txt", branch), git_storage::FileChange::Write(message.as_bytes().to_vec()))],
+                        "Owner <owner@example.com>".to_string(),
+                        message.to_string(),
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+        let base = commit("main", "Initial commit").await;
+        for branch in ["diverged", "ahead"] {
+            service.store_ref(repo.id, format!("refs/heads/{}", branch), base.clone(), false).await.unwrap();
+        }
+        commit("diverged", "Work on an old main").await;
+        let moved = commit("main", "Move main on").await;
+        service.store_ref(repo.id, "refs/heads/ahead".to_string(), moved.clone(), false).await.unwrap();
+        let ahead = commit("ahead", "Work on top of main").await;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(merge_branches)),
+        )
+        .await;
+        let merge = |source: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/merge", repo.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({
+                    "source_branch": source,
+                    "target_branch": "main",
+                    "author": "Owner <owner@example.com>",
+                    "message": "Merge",
+                }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, merge("diverged")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body: ApiResponse<()> = test::read_body_json(resp).await;
+        assert!(body.message.contains("rebase or squash"), "{}", body.message);
+        let main = service.get_ref(repo.id, "refs/heads/main").await.unwrap().unwrap();
+        assert_eq!(main.target, moved);
+
+        let resp = test::call_service(&app, merge("ahead")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let main = service.get_ref(repo.id, "refs/heads/main").await.unwrap().unwrap();
+        assert_eq!(main.target, ahead);
+    }
+
+    #[actix_web::test]
+    async fn test_readme_is_found_at_the_root_of_a_ref() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("landing".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        let write = |content: &str| FileChange::Write(content.as_bytes().to_vec());
+        for (branch, files) in [
+            (
+                "main",
+                vec![
+                    ("README.md", "# Landing\n\nWelcome.\n"),
+                    ("readme.txt", "Plain fallback\n"),
+                    ("docs/README", "Not at the root\n"),
+                ],
+            ),
+            ("bare", vec![("docs/README", "Not at the root\n")]),
+        ] {
+            git_ops
+                .commit_files(
+                    state.repository_service.get_db(),
+                    repo.id,
+                    branch,
+                    files.into_iter().map(|(path, content)| (path.to_string(), write(content))).collect(),
+                    "Owner <owner@example.com>".to_string(),
+                    "Add files".to_string(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(get_readme)),
+        )
+        .await;
+        let readme = |query: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/api/repositories/{}/readme{}", repo.id, query))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, readme("")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: ApiResponse<git_storage::Readme> = test::read_body_json(resp).await;
+        let found = body.data.unwrap();
+        assert_eq!(found.name, "README.md");
+        assert_eq!(found.format, git_storage::ReadmeFormat::Markdown);
+        assert_eq!(found.content, "# Landing\n\nWelcome.\n");
+
+        let resp = test::call_service(&app, readme("?ref=bare")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["message"], "no readme");
+        assert_eq!(body["data"]["no_readme"], true);
+
+        let resp = test::call_service(&app, readme("?ref=missing")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["message"], "Ref not found");
+    }
+
+    #[actix_web::test]
+    async fn test_rev_parse_resolves_paths_in_a_commit() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("paths".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        let commit = git_ops
+            .commit_files(
+                state.repository_service.get_db(),
+                repo.id,
+                "main",
+                vec![("src/lib/mod.rs".to_string(), FileChange::Write(b"pub mod lib;\n".to_vec()))],
+                "Owner <owner@example.com>".to_string(),
+                "Add files".to_string(),
+            )
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(rev_parse_path)),
+        )
+        .await;
+        let rev_parse = |rest: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/api/repositories/{}/rev-parse/{}", repo.id, rest))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, rev_parse("main/src/lib/mod.rs")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"]["kind"], "blob");
+        assert_eq!(body["data"]["path"], "src/lib/mod.rs");
+        let file = body["data"]["sha"].as_str().unwrap().to_string();
+
+        let resp = test::call_service(&app, rev_parse(&format!("{}/src/lib", commit))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"]["kind"], "tree");
+        assert_ne!(body["data"]["sha"], file.as_str());
+
+        let resp = test::call_service(&app, rev_parse("main/")).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"]["kind"], "tree");
+        assert_eq!(body["data"]["path"], "");
+
+        let resp = test::call_service(&app, rev_parse("main/src/missing.rs")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["message"], "path src/missing.rs does not exist");
+
+        let resp = test::call_service(&app, rev_parse("missing/src")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["message"], "Ref not found");
+    }
+
+    #[actix_web::test]
+    async fn test_merge_strategies_on_diverged_branches() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:write".to_string()], None)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("strategies".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let service = state.repository_service.clone();
+        let git_ops = GitOperations::new(service.as_ref().clone());
+        let commit = |branch: &'static str, path: &'static str, content: &'static str, message: &'static str| {
+            let (git_ops, service) = (&git_ops, &service);
+            async move {
+                git_ops
+                    .commit_files(
+                        service.get_db(),
+                        repo.id,
+                        branch,
+                        vec![(path.to_string(), FileChange::Write(content.as_bytes().to_vec()))],
+                        "Owner <owner@example.com>".to_string(),
+                        message.to_string(),
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+        let base = commit("main", "a.txt", "a\n", "Initial commit").await;
+        for branch in ["feature", "clash"] {
+            service.store_ref(repo.id, format!("refs/heads/{}", branch), base.clone(), false).await.unwrap();
+        }
+        commit("feature", "b.txt", "b\n", "Add b").await;
+        let feature = commit("feature", "b.txt", "b, tweaked\n", "Tweak b").await;
+        commit("clash", "a.txt", "a, their way\n", "Change a one way").await;
+        let main = commit("main", "a.txt", "a, our way\n", "Change a another way").await;
+        for branch in ["ff-only", "merged", "squashed"] {
+            service.store_ref(repo.id, format!("refs/heads/{}", branch), main.clone(), false).await.unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(merge_branches)),
+        )
+        .await;
+        let merge = |source: &str, target: &str, strategy: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/merge", repo.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({
+                    "source_branch": source,
+                    "target_branch": target,
+                    "author": "Owner <owner@example.com>",
+                    "strategy": strategy,
+                }))
+                .to_request()
+        };
+        let tip = |branch: &'static str| {
+            let service = &service;
+            async move { service.get_ref(repo.id, &format!("refs/heads/{}", branch)).await.unwrap().unwrap().target }
+        };
+
+        let resp = test::call_service(&app, merge("feature", "ff-only", "fast-forward-only")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(tip("ff-only").await, main);
+
+        let resp = test::call_service(&app, merge("feature", "merged", "merge")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let merged: ApiResponse<git_storage::MergeResult> = test::read_body_json(resp).await;
+        let merged = merged.data.unwrap();
+        assert_eq!(merged.strategy, git_storage::MergeStrategy::Merge);
+        assert!(!merged.fast_forward);
+        assert_eq!(tip("merged").await, merged.commit);
+        let merge_commit = service.get_commit_object(repo.id, &merged.commit).await.unwrap().unwrap();
+        assert_eq!(merge_commit.parents, vec![main.clone(), feature.clone()]);
+        assert_eq!(merge_commit.message, "Merge branch 'feature' into merged");
+
+        let resp = test::call_service(&app, merge("feature", "squashed", "squash")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let squashed: ApiResponse<git_storage::MergeResult> = test::read_body_json(resp).await;
+        let squashed = squashed.data.unwrap();
+        assert_eq!(squashed.strategy, git_storage::MergeStrategy::Squash);
+        let squash_commit = service.get_commit_object(repo.id, &squashed.commit).await.unwrap().unwrap();
+        assert_eq!(squash_commit.parents, vec![main.clone()]);
+        assert_eq!(squash_commit.message, "Add b\nTweak b");
+        // Both take the same three-way merge of the two sides
+        assert_eq!(squash_commit.tree, merge_commit.tree);
+        let root = service.get_tree_object(repo.id, &squash_commit.tree).await.unwrap().unwrap();
+        let names: Vec<&str> = root.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+
+        // Once merged, the target already has everything
+        let resp = test::call_service(&app, merge("feature", "merged", "merge")).await;
+        let again: ApiResponse<git_storage::MergeResult> = test::read_body_json(resp).await;
+        assert_eq!(again.data.unwrap().commit, merged.commit);
+
+        for strategy in ["merge", "squash"] {
+            let resp = test::call_service(&app, merge("clash", "ff-only", strategy)).await;
+            assert_eq!(resp.status(), StatusCode::CONFLICT, "{}", strategy);
+            let body: ApiResponse<()> = test::read_body_json(resp).await;
+            assert!(body.message.contains("merge conflict in a.txt"), "{}", body.message);
+        }
+        assert_eq!(tip("ff-only").await, main);
+    }
+
+    #[actix_web::test]
+    async fn test_branch_create_and_push_show_in_the_activity_feed() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:write".to_string()], None)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("activity".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let event_service = state.event_service.clone();
+
+        let handler = ObjectHandler::new();
+        let tree = handler.create_tree(&git_protocol::objects::Tree { entries: vec![] }).unwrap();
+        let commit = handler
+            .create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let protocol = git_protocol::ProtocolHandler::new();
+        let command = format!("{} {} refs/heads/main\0report-status", git_protocol::ZERO_ID, commit.id);
+        let mut push = protocol.create_pkt_line(&[command.as_str()]);
+        push.extend(protocol.create_pack(&[tree, commit.clone()]).unwrap());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(
+                    web::scope("/api")
+                        .wrap(from_fn(enforce_token_scopes))
+                        .service(create_branch)
+                        .service(list_repository_events),
+                )
+                .service(web::scope("/git").service(crate::http::receive_pack)),
+        )
+        .await;
+        let report = test::call_and_read_body(
+            &app,
+            test::TestRequest::post()
+                .uri("/git/activity/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(push)
+                .to_request(),
+        )
+        .await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/main"));
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/repositories/{}/branches", repo.id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .set_json(serde_json::json!({"name": "feature", "start_commit": commit.id}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        // One row each, both knowing who made them
+        let rows = event_service.list_by_repository(repo.id, 10).await.unwrap();
+        let rows: Vec<_> = rows.iter().map(|e| (e.event_type.as_str(), e.transport.as_str(), e.actor_id)).collect();
+        assert_eq!(rows, [("branch_created", "api", Some(owner.id)), ("push", "http", Some(owner.id))]);
+
+        // Anonymous readers of a public repository page through the feed, newest first
+        let feed = |query: String| {
+            let app = &app;
+            async move {
+                let req = test::TestRequest::get()
+                    .uri(&format!("/api/repositories/{}/events{}", repo.id, query))
+                    .to_request();
+                let body: ApiResponse<serde_json::Value> = test::call_and_read_body_json(app, req).await;
+                body.data.unwrap()
+            }
+        };
+        let first = feed("?limit=1".to_string()).await;
+        let event = &first["events"][0];
+        assert_eq!(event["kind"], "branch_created");
+        assert_eq!(event["actor"], "owner");
+        assert_eq!(event["payload"], serde_json::json!({"ref": "refs/heads/feature", "target": commit.id}));
+        let cursor = first["next_cursor"].as_str().unwrap().to_string();
+
+        let second = feed(format!("?limit=1&before={}", cursor)).await;
+        let event = &second["events"][0];
+        assert_eq!(event["kind"], "push");
+        assert_eq!(event["actor"], "owner");
+        let pushed = &event["payload"]["refs"][0];
+        assert_eq!(pushed["ref"], "refs/heads/main");
+        assert_eq!(pushed["created"], true);
+        assert_eq!(pushed["commits"], serde_json::json!([commit.id]));
+        assert_eq!(second["next_cursor"], serde_json::Value::Null);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/repositories/{}/events?before=yesterday", repo.id))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_commits_map_email_aliases_and_hide_emails() {
+        let state = create_test_state().await;
+        let alice = state
+            .user_service
+            .create_user("alice".to_string(), "alice@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(alice.id, "test".to_string(), &["repo:read".to_string(), "user:write".to_string()], None)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("identities".to_string(), None, "main".to_string(), alice.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let (_, verification) = state.identity_service.add_alias(alice.id, "alice@work.example").await.unwrap();
+
+        let service = state.repository_service.clone();
+        let sha = GitOperations::new(service.as_ref().clone())
+            .commit_files(
+                service.get_db(),
+                repo.id,
+                "main",
+                vec![("notes.txt".to_string(), FileChange::Write(b"notes\n".to_vec()))],
+                "Alice <alice@work.example>".to_string(),
+                "Add notes\n\nSigned-off-by: Alice <alice@work.example>\n".to_string(),
+            )
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(from_fn(enforce_token_scopes))
+                    .service(
+                        web::scope("/auth")
+                            .service(crate::auth::verify_email)
+                            .service(crate::auth::update_current_user),
+                    )
+                    .service(get_commit_history)
+                    .service(get_commit),
+            ),
+        )
+        .await;
+        let bearer = (header::AUTHORIZATION, format!("Bearer {}", token));
+        let commit = || {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/repositories/{}/commits/{}", repo.id, sha))
+                .insert_header(bearer.clone())
+                .to_request();
+            async { test::call_and_read_body(&app, req).await }
+        };
+
+        // An unverified alias maps nothing
+        let body: ApiResponse<git_storage::CommitDetail> = serde_json::from_slice(&commit().await).unwrap();
+        assert!(body.data.unwrap().author_user.is_none());
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/emails/verify")
+            .insert_header(bearer.clone())
+            .set_json(serde_json::json!({ "token": verification }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let body: ApiResponse<git_storage::CommitDetail> = serde_json::from_slice(&commit().await).unwrap();
+        let detail = body.data.unwrap();
+        assert_eq!(detail.author_user.as_ref().map(|u| u.username.as_str()), Some("alice"));
+        assert_eq!(detail.committer_user.map(|u| u.id), Some(alice.id));
+        assert!(detail.commit.author.starts_with("Alice <alice@work.example>"));
+
+        let req = test::TestRequest::patch()
+            .uri("/api/auth/me")
+            .insert_header(bearer.clone())
+            .set_json(serde_json::json!({ "hide_email": true }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["user"]["hide_email"], true);
+
+        // The address is gone from everything shown, trailers and message included
+        let raw = commit().await;
+        assert!(!String::from_utf8_lossy(&raw).contains("alice@work.example"));
+        let body: ApiResponse<git_storage::CommitDetail> = serde_json::from_slice(&raw).unwrap();
+        let detail = body.data.unwrap();
+        assert_eq!(detail.hash, sha);
+        assert_eq!(detail.author_user.map(|u| u.id), Some(alice.id));
+        assert!(detail.commit.author.starts_with("Alice <alice@users.noreply.example.com>"));
+        assert_eq!(detail.trailers[0].value, "Alice <alice@users.noreply.example.com>");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/repositories/{}/branches/main/commits", repo.id))
+            .insert_header(bearer.clone())
+            .to_request();
+        let raw = test::call_and_read_body(&app, req).await;
+        assert!(!String::from_utf8_lossy(&raw).contains("alice@work.example"));
+        let body: ApiResponse<git_storage::CommitHistory> = serde_json::from_slice(&raw).unwrap();
+        let history = body.data.unwrap();
+        assert_eq!(history.commits[0].author_user.as_ref().map(|u| u.id), Some(alice.id));
+
+        // The stored commit keeps the address it was made with
+        let stored = service.get_commit_object(repo.id, &sha).await.unwrap().unwrap();
+        assert!(stored.author.starts_with("Alice <alice@work.example>"));
+    }
+
+    #[actix_web::test]
+    async fn test_private_objects_are_not_served_to_other_users() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let mallory = state
+            .user_service
+            .create_user("mallory".to_string(), "mallory@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let secret = state
+            .repository_service
+            .create_repository("secret".to_string(), None, "main".to_string(), owner.id, Visibility::Private, None)
+            .await
+            .unwrap();
+        let open = state
+            .repository_service
+            .create_repository("open".to_string(), None, "main".to_string(), mallory.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        let mut commits = Vec::new();
+        for (repo, content) in [(&secret, "top secret\n"), (&open, "nothing to see\n")] {
+            let commit = git_ops
+                .commit_files(
+                    state.repository_service.get_db(),
+                    repo.id,
+                    "main",
+                    vec![("README.md".to_string(), FileChange::Write(content.as_bytes().to_vec()))],
+                    "Someone <someone@example.com>".to_string(),
+                    "Add README".to_string(),
+                )
+                .await
+                .unwrap();
+            commits.push(commit);
+        }
+        let secret_commit = commits[0].clone();
+        let secret_blob = ObjectHandler::new().create_blob(b"top secret\n").unwrap().id;
+        let token = |user_id| {
+            let token_service = state.token_service.clone();
+            async move {
+                token_service
+                    .create_token(user_id, "test".to_string(), &["repo:read".to_string()], None)
+                    .await
+                    .unwrap()
+                    .1
+            }
+        };
+        let (owner_token, mallory_token) = (token(owner.id).await, token(mallory.id).await);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(crate::http::upload_pack))
+                .service(
+                    web::scope("/api")
+                        .wrap(from_fn(enforce_token_scopes))
+                        .service(get_raw_blob)
+                        .service(batch_get_objects)
+                        .service(get_commit_patch)
+                        .service(get_commit_note)
+                        .service(get_commit)
+                        .service(get_readme)
+                        .service(list_branches)
+                        .service(list_tags)
+                        .service(get_commit_history)
+                        .service(get_diff)
+                        .service(get_commit_graph)
+                        .service(list_commit_statuses),
+                ),
+        )
+        .await;
+        let get = |uri: String, token: &str| {
+            test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        // The owner reads the blob; to anyone else every object route says the
+        // repository doesn't exist
+        let raw = format!("/api/repositories/{}/blobs/{}/raw", secret.id, secret_blob);
+        let resp = test::call_service(&app, get(raw.clone(), &owner_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        for uri in [
+            raw,
+            format!("/api/repositories/{}/commits/{}", secret.id, secret_commit),
+            format!("/api/repositories/{}/commits/{}.patch", secret.id, secret_commit),
+            format!("/api/repositories/{}/commits/{}/notes", secret.id, secret_commit),
+            format!("/api/repositories/{}/readme", secret.id),
+            format!("/api/repositories/{}/branches", secret.id),
+            format!("/api/repositories/{}/tags", secret.id),
+            format!("/api/repositories/{}/branches/main/commits", secret.id),
+            format!("/api/repositories/{0}/diff?from={1}&to={1}", secret.id, secret_commit),
+            format!("/api/repositories/{}/graph", secret.id),
+            format!("/api/repositories/{}/statuses/{}", secret.id, secret_commit),
+        ] {
+            let resp = test::call_service(&app, get(uri.clone(), &mallory_token)).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/repositories/{}/objects/batch", secret.id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", mallory_token)))
+            .set_json(serde_json::json!({ "ids": [secret_blob.clone(), secret_commit.clone()] }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+        // Known ids don't cross over through a repository the caller can read
+        let resp = test::call_service(
+            &app,
+            get(format!("/api/repositories/{}/blobs/{}/raw", open.id, secret_blob), &mallory_token),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(
+            &app,
+            get(format!("/api/repositories/{}/commits/{}", open.id, secret_commit), &mallory_token),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let fetch = |repo: &str, want: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/git/{}/git-upload-pack", repo))
+                .set_payload(format!("0032want {}\n00000009done\n", want))
+                .to_request()
+        };
+        let body = test::call_and_read_body(&app, fetch("secret", &secret_commit)).await;
+        assert_eq!(body, b"001dERR repository not found\n0000".as_slice());
+        let body = test::call_and_read_body(&app, fetch("open", &secret_commit)).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains(&format!("not our ref {}", secret_commit)), "{body}");
+        let body = test::call_and_read_body(&app, fetch("open", &commits[1])).await;
+        assert!(!String::from_utf8_lossy(&body).contains("not our ref"));
+    }
+
+    /// A public and a private repository of `owner`, each with one commit on main,
+    /// and `repo:write` tokens for the owner and for a stranger, for checking that
+    /// write routes refuse everyone but the owner
+    struct WriteFixture {
+        public: repository::Model,
+        public_commit: String,
+        private: repository::Model,
+        owner_token: String,
+        stranger_token: String,
+    }
+
+    async fn write_fixture(state: &AppState) -> WriteFixture {
+        let mut users = Vec::new();
+        for name in ["owner", "stranger"] {
+            let user = state
+                .user_service
+                .create_user(name.to_string(), format!("{}@example.com", name), "hash".to_string(), None, false)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        let mut repos = Vec::new();
+        for (name, visibility) in [("public", Visibility::Public), ("private", Visibility::Private)] {
+            let repo = state
+                .repository_service
+                .create_repository(name.to_string(), None, "main".to_string(), users[0].id, visibility, None)
+                .await
+                .unwrap();
+            let commit = git_ops
+                .commit_files(
+                    state.repository_service.get_db(),
+                    repo.id,
+                    "main",
+                    vec![("README.md".to_string(), FileChange::Write(b"hello\n".to_vec()))],
+                    "Owner <owner@example.com>".to_string(),
+                    "Add README".to_string(),
+                )
+                .await
+                .unwrap();
+            repos.push((repo, commit));
+        }
+        let mut tokens = Vec::new();
+        for user in &users {
+            let (_, token) = state
+                .token_service
+                .create_token(user.id, "test".to_string(), &["repo:write".to_string()], None)
+                .await
+                .unwrap();
+            tokens.push(token);
+        }
+        let [(public, public_commit), (private, _)]: [_; 2] = repos.try_into().unwrap();
+        let [owner_token, stranger_token]: [_; 2] = tokens.try_into().unwrap();
+        WriteFixture { public, public_commit, private, owner_token, stranger_token }
+    }
+
+    #[actix_web::test]
+    async fn test_commit_files_needs_write_access() {
+        let state = create_test_state().await;
+        let fixture = write_fixture(&state).await;
+        let repository_service = state.repository_service.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(commit_files)),
+        )
+        .await;
+        let commit = |repo_id: Uuid, token: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/contents", repo_id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({
+                    "branch": "main",
+                    "author": "A U Thor <author@example.com> 1700000000 +0000",
+                    "message": "Rewrite the README",
+                    "files": [{"path": "README.md", "content": "changed\n"}],
+                }))
+                .to_request()
+        };
+
+        // Readers who don't own the repository are refused; private ones stay hidden
+        let resp = test::call_service(&app, commit(fixture.public.id, &fixture.stranger_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, commit(fixture.private.id, &fixture.stranger_token)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let main = repository_service.get_ref(fixture.public.id, "refs/heads/main").await.unwrap().unwrap();
+        assert_eq!(main.target, fixture.public_commit);
+
+        let resp = test::call_service(&app, commit(fixture.public.id, &fixture.owner_token)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    #[actix_web::test]
+    async fn test_amend_commit_needs_write_access() {
+        let state = create_test_state().await;
+        let fixture = write_fixture(&state).await;
+        let repository_service = state.repository_service.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(amend_commit)),
+        )
+        .await;
+        let amend = |repo_id: Uuid, token: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/branches/main/amend", repo_id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({
+                    "message": "Reworded\n",
+                    "author": "A U Thor <author@example.com> 1700000000 +0000",
+                }))
+                .to_request()
+        };
+
+        // Readers who don't own the repository are refused; private ones stay hidden
+        let resp = test::call_service(&app, amend(fixture.public.id, &fixture.stranger_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, amend(fixture.private.id, &fixture.stranger_token)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let main = repository_service.get_ref(fixture.public.id, "refs/heads/main").await.unwrap().unwrap();
+        assert_eq!(main.target, fixture.public_commit);
+
+        let resp = test::call_service(&app, amend(fixture.public.id, &fixture.owner_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let main = repository_service.get_ref(fixture.public.id, "refs/heads/main").await.unwrap().unwrap();
+        assert_ne!(main.target, fixture.public_commit);
+    }
+
+    #[actix_web::test]
+    async fn test_raw_contents_upload_needs_write_access() {
+        let state = create_test_state().await;
+        let fixture = write_fixture(&state).await;
+        let repository_service = state.repository_service.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(put_raw_contents)),
+        )
+        .await;
+        let upload = |repo_id: Uuid, token: &str| {
+            test::TestRequest::put()
+                .uri(&format!(
+                    "/api/repositories/{}/contents/logo.bin?branch=main&message=Add%20logo&author={}",
+                    repo_id, "A%20U%20Thor%20%3Cauthor%40example.com%3E%201700000000%20%2B0000"
+                ))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .insert_header((header::CONTENT_TYPE, "application/octet-stream"))
+                .set_payload(vec![0u8, 1, 2, 255])
+                .to_request()
+        };
+
+        // Readers who don't own the repository are refused; private ones stay hidden
+        let resp = test::call_service(&app, upload(fixture.public.id, &fixture.stranger_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, upload(fixture.private.id, &fixture.stranger_token)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let main = repository_service.get_ref(fixture.public.id, "refs/heads/main").await.unwrap().unwrap();
+        assert_eq!(main.target, fixture.public_commit);
+
+        let resp = test::call_service(&app, upload(fixture.public.id, &fixture.owner_token)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    #[actix_web::test]
+    async fn test_set_commit_note_needs_write_access() {
+        let state = create_test_state().await;
+        let fixture = write_fixture(&state).await;
+        let repository_service = state.repository_service.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(set_commit_note)),
+        )
+        .await;
+        let annotate = |repo_id: Uuid, token: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/commits/{}/notes", repo_id, fixture.public_commit))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({
+                    "note": "Reviewed\n",
+                    "author": "A U Thor <author@example.com> 1700000000 +0000",
+                }))
+                .to_request()
+        };
+
+        // Readers who don't own the repository are refused; private ones stay hidden
+        let resp = test::call_service(&app, annotate(fixture.public.id, &fixture.stranger_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, annotate(fixture.private.id, &fixture.stranger_token)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let notes = repository_service.get_ref(fixture.public.id, "refs/notes/commits").await.unwrap();
+        assert!(notes.is_none());
+
+        let resp = test::call_service(&app, annotate(fixture.public.id, &fixture.owner_token)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+}
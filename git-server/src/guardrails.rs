@@ -0,0 +1,196 @@
+use actix_web::{http::header, HttpResponse};
+use git_storage::{ReadLimits, StorageError};
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::time::Duration;
+
+/// Seconds clients are asked to wait before retrying a read stopped by a guardrail
+pub const GUARDRAIL_RETRY_AFTER_SECS: u64 = 60;
+
+/// Limits applied to one expensive read (history, diffs, branch comparison)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadGuardrails {
+    pub limits: ReadLimits,
+    /// Wall-clock budget for the whole read, `None` to let it run
+    pub timeout: Option<Duration>,
+}
+
+/// Why a read was stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardrailHit {
+    CommitLimit { max: usize },
+    TreeEntryLimit { max: usize },
+    Deadline { secs: u64 },
+}
+
+impl GuardrailHit {
+    /// Stable code clients can match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            GuardrailHit::CommitLimit { .. } => "commit_limit_exceeded",
+            GuardrailHit::TreeEntryLimit { .. } => "tree_entry_limit_exceeded",
+            GuardrailHit::Deadline { .. } => "deadline_exceeded",
+        }
+    }
+
+    fn limit(&self) -> u64 {
+        match *self {
+            GuardrailHit::CommitLimit { max } | GuardrailHit::TreeEntryLimit { max } => max as u64,
+            GuardrailHit::Deadline { secs } => secs,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            GuardrailHit::CommitLimit { max } => format!(
+                "This request would walk more than {} commits; narrow it with filters or a limit",
+                max
+            ),
+            GuardrailHit::TreeEntryLimit { max } => format!(
+                "This request would read more than {} tree entries; compare smaller trees",
+                max
+            ),
+            GuardrailHit::Deadline { secs } => {
+                format!("This request did not finish within {} seconds", secs)
+            }
+        }
+    }
+
+    /// 503 with `Retry-After` and a body naming the guardrail
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, GUARDRAIL_RETRY_AFTER_SECS.to_string()))
+            .json(GuardrailResponse {
+                success: false,
+                code: self.code(),
+                message: self.message(),
+                limit: self.limit(),
+            })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GuardrailResponse {
+    success: bool,
+    code: &'static str,
+    message: String,
+    /// The limit that was hit: a count, or seconds for `deadline_exceeded`
+    limit: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GuardrailMetricsSnapshot {
+    pub commit_limit_exceeded: u64,
+    pub tree_entry_limit_exceeded: u64,
+    pub deadline_exceeded: u64,
+}
+
+/// Reads stopped by each guardrail since the server started
+#[derive(Default)]
+pub struct GuardrailMetrics {
+    commit_limit_exceeded: AtomicU64,
+    tree_entry_limit_exceeded: AtomicU64,
+    deadline_exceeded: AtomicU64,
+}
+
+impl GuardrailMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, hit: GuardrailHit) {
+        let counter = match hit {
+            GuardrailHit::CommitLimit { .. } => &self.commit_limit_exceeded,
+            GuardrailHit::TreeEntryLimit { .. } => &self.tree_entry_limit_exceeded,
+            GuardrailHit::Deadline { .. } => &self.deadline_exceeded,
+        };
+        counter.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> GuardrailMetricsSnapshot {
+        GuardrailMetricsSnapshot {
+            commit_limit_exceeded: self.commit_limit_exceeded.load(AtomicOrdering::Relaxed),
+            tree_entry_limit_exceeded: self.tree_entry_limit_exceeded.load(AtomicOrdering::Relaxed),
+            deadline_exceeded: self.deadline_exceeded.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+impl ReadGuardrails {
+    /// Run `read` under the deadline. A read stopped by a guardrail is counted in
+    /// `metrics` and comes back as `Err` with the 503 to send; any other outcome,
+    /// failures included, is handed back for the caller to map as before.
+    pub async fn run<T>(
+        &self,
+        metrics: &GuardrailMetrics,
+        read: impl Future<Output = anyhow::Result<T>>,
+    ) -> std::result::Result<anyhow::Result<T>, HttpResponse> {
+        let result = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, read).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::Error::new(DeadlineElapsed(timeout.as_secs()))),
+            },
+            None => read.await,
+        };
+
+        let hit = match &result {
+            Err(e) => match (e.downcast_ref::<StorageError>(), e.downcast_ref::<DeadlineElapsed>()) {
+                (Some(StorageError::CommitLimitExceeded { max }), _) => {
+                    Some(GuardrailHit::CommitLimit { max: *max })
+                }
+                (Some(StorageError::TreeEntryLimitExceeded { max }), _) => {
+                    Some(GuardrailHit::TreeEntryLimit { max: *max })
+                }
+                (_, Some(DeadlineElapsed(secs))) => Some(GuardrailHit::Deadline { secs: *secs }),
+                _ => None,
+            },
+            Ok(_) => None,
+        };
+
+        match hit {
+            Some(hit) => {
+                metrics.record(hit);
+                Err(hit.response())
+            }
+            None => Ok(result),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("read did not finish within {0} seconds")]
+struct DeadlineElapsed(u64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_deadline_stops_slow_reads() {
+        let metrics = GuardrailMetrics::new();
+        let guardrails = ReadGuardrails {
+            limits: ReadLimits::default(),
+            timeout: Some(Duration::from_millis(10)),
+        };
+
+        let response = guardrails
+            .run(&metrics, async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(response.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "60");
+        assert_eq!(metrics.snapshot().deadline_exceeded, 1);
+
+        // Ordinary failures are left to the caller
+        let result = guardrails
+            .run(&metrics, async { Err::<(), _>(anyhow::anyhow!("boom")) })
+            .await
+            .unwrap();
+        assert!(result.is_err());
+        assert_eq!(metrics.snapshot().deadline_exceeded, 1);
+    }
+}
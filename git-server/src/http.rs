@@ -1,16 +1,58 @@
+use crate::authorized_keys::{self, KeyImportLine, KeyImportReport, KeyImportStatus};
+use crate::avatars;
+use crate::client::ClientInfo;
+use crate::clone_limit::CloneLimiter;
+use crate::config::{Config, HiddenRefs, ProtocolSettings};
+use crate::pack_cache::{CachedPackBody, PackRequest};
+use crate::permissions::Permissions;
+use crate::payload::ApiJson;
+use crate::post_receive::{self, PostReceiveContext};
+use crate::quota::{self, QuotaHit, QuotaReport};
+use crate::scopes::{AuthenticatedUser, Caller, Scope};
 use crate::AppState;
 use actix_web::{
-    get, post, web, HttpResponse, Result,
+    delete, get, http::{header, StatusCode}, patch, post, put, web, HttpRequest, HttpResponse, Result,
+};
+use git_protocol::objects::ObjectHandler;
+use git_protocol::{
+    Capabilities, GitObject, GitProtocol, HashAlgorithm, Negotiation, ObjectFilter, ObjectType, ProtocolError, ProtocolHandler,
+    ReceivePackRequest, RefCommand,
+};
+use git_storage::entities::repository::{ObjectFormat, Visibility};
+use git_storage::entities::{idempotency_key, repository, user};
+use git_storage::{
+    avatar_url, AvatarStore, FetchOptions, GitOperations, ImportedUser, noreply_email, NewRepositoryEvent, PushEventPayload, PushedRef, RefUpdate, RepositoryEventType, RepositoryUpdate, ShallowUpdate, StorageError, TipCommit, WriteGuard,
 };
-use git_protocol::{GitProtocol, ProtocolHandler};
 use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+/// Why writes to an archived repository are refused, to pushes and over the API
+pub const ARCHIVED_MESSAGE: &str = "repository is archived";
+
+/// Capabilities advertised for git-upload-pack
+pub const UPLOAD_PACK_CAPABILITIES: &[&str] = &["multi_ack", "side-band-64k", "ofs-delta"];
+
+/// Capabilities advertised for git-receive-pack
+pub const RECEIVE_PACK_CAPABILITIES: &[&str] =
+    &["report-status", "delete-refs", "ofs-delta", "side-band-64k", "atomic"];
+
+/// Reported for the refs of an `atomic` push that failed because of another ref
+const ATOMIC_FAILURE: &str = "atomic transaction failed";
+
+/// Header clients set to make create requests safe to retry
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateRepositoryRequest {
     pub name: String,
     pub description: Option<String>,
+    /// Defaults to public
+    pub visibility: Option<Visibility>,
+    /// Older form of `visibility`: `true` for private, `false` for public
     pub is_private: Option<bool>,
     pub owner_id: Option<String>, // UUID as string
+    /// `sha1` or `sha256`; defaults to the server's `DEFAULT_OBJECT_FORMAT`
+    pub object_format: Option<ObjectFormat>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,8 +62,204 @@ pub struct RepositoryResponse {
     pub description: Option<String>,
     pub default_branch: String,
     pub owner_id: String,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Older form of `visibility`, true unless anyone can read the repository
     pub is_private: bool,
     pub created_at: String,
+    pub http_clone_url: String,
+    pub ssh_clone_url: String,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// Send back with settings updates, see `PATCH /api/repositories/{name}`
+    #[serde(default)]
+    pub version: i32,
+    /// This repository's hidden ref prefixes, on top of the server-wide ones
+    #[serde(default)]
+    pub hidden_refs: Vec<String>,
+    /// Readable but refusing pushes and API writes
+    #[serde(default)]
+    pub is_archived: bool,
+    /// Hash naming the repository's objects
+    #[serde(default)]
+    pub object_format: ObjectFormat,
+    /// Id of the repository this one was forked from
+    #[serde(default)]
+    pub forked_from: Option<String>,
+    /// Template shown to pushers after each ref update
+    #[serde(default)]
+    pub push_message: Option<String>,
+    /// Branches that refuse merge commits and non-fast-forward merges
+    #[serde(default)]
+    pub linear_history_branches: Vec<String>,
+    /// Whether anonymous callers may clone it, unless the server requires sign-in
+    #[serde(default = "default_true")]
+    pub allow_anonymous_clone: bool,
+    /// Listings with `?include=counts` only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch_count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_count: Option<u64>,
+    /// Latest ref change, or the latest settings change when the reflog has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_activity: Option<String>,
+    /// Default branch tip, listings with `?include=tip` only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tip: Option<TipCommit>,
+    /// The caller's permissions; listings carry them with `?include=permissions`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Permissions>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl RepositoryResponse {
+    /// `owner` is the owner's username, which the clone URLs start with
+    pub fn from_model(repo: repository::Model, owner: &str, topics: Vec<String>, config: &Config) -> Self {
+        let hidden_refs = repo.hidden_ref_prefixes().into_iter().map(str::to_string).collect();
+        let linear_history_branches = repo.linear_history_branches().into_iter().map(str::to_string).collect();
+        Self {
+            id: repo.id.to_string(),
+            http_clone_url: config.http_clone_url(owner, &repo.name),
+            ssh_clone_url: config.ssh_clone_url(owner, &repo.name),
+            name: repo.name,
+            description: repo.description,
+            default_branch: repo.default_branch,
+            owner_id: repo.owner_id.to_string(),
+            visibility: repo.visibility,
+            is_private: repo.visibility != Visibility::Public,
+            created_at: repo.created_at.to_string(),
+            topics,
+            version: repo.version,
+            hidden_refs,
+            is_archived: repo.is_archived,
+            object_format: repo.object_format,
+            forked_from: repo.forked_from.map(|id| id.to_string()),
+            push_message: repo.push_message,
+            linear_history_branches,
+            allow_anonymous_clone: repo.allow_anonymous_clone,
+            branch_count: None,
+            tag_count: None,
+            last_activity: None,
+            tip: None,
+            permissions: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RepositoryListQuery {
+    /// Only list repositories tagged with this topic
+    pub topic: Option<String>,
+    /// `true`, `false` or `all` (default)
+    pub archived: Option<String>,
+    /// Comma-separated extras per repository: `counts`, `tip` and `permissions`
+    pub include: Option<String>,
+}
+
+/// Extras requested with `?include=`, each costing at most a couple of queries per page
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ListIncludes {
+    /// Branch and tag counts and last activity
+    counts: bool,
+    /// Default branch tip commit
+    tip: bool,
+    /// The caller's permissions, from the account the listing already loaded
+    permissions: bool,
+}
+
+impl RepositoryListQuery {
+    /// Whether to keep only archived (`Some(true)`) or unarchived repositories,
+    /// or the message for a 400
+    fn archived(&self) -> std::result::Result<Option<bool>, String> {
+        match self.archived.as_deref() {
+            None | Some("all") => Ok(None),
+            Some("true") => Ok(Some(true)),
+            Some("false") => Ok(Some(false)),
+            Some(other) => Err(format!("archived must be true, false or all, not '{}'", other)),
+        }
+    }
+
+    /// Extras named by `include`, or the message for a 400
+    fn includes(&self) -> std::result::Result<ListIncludes, String> {
+        let mut includes = ListIncludes::default();
+        for name in self.include.iter().flat_map(|include| include.split(',')).map(str::trim) {
+            match name {
+                "" => {}
+                "counts" => includes.counts = true,
+                "tip" => includes.tip = true,
+                "permissions" => includes.permissions = true,
+                other => return Err(format!("include takes counts, tip and permissions, not '{}'", other)),
+            }
+        }
+        Ok(includes)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateRepositoryRequest {
+    pub description: Option<String>,
+    pub visibility: Option<Visibility>,
+    /// Older form of `visibility`, ignored when that is sent
+    pub is_private: Option<bool>,
+    /// Ref prefixes to hide from clients, e.g. `["refs/pull/*"]`; replaces the current list
+    pub hidden_refs: Option<Vec<String>>,
+    /// Owner or admin only
+    pub is_archived: Option<bool>,
+    /// Shown to pushers after each ref update, with `{ref}`, `{repo}` and `{pusher}`
+    /// filled in; an empty string removes it
+    pub push_message: Option<String>,
+    /// Branches to keep free of merge commits, e.g. `["main"]`; replaces the current list
+    pub linear_history_branches: Option<Vec<String>>,
+    /// Let anonymous callers clone the repository; the server's `REQUIRE_AUTH` wins
+    pub allow_anonymous_clone: Option<bool>,
+    /// Version the client read; an `If-Match` header may be sent instead
+    pub version: Option<i32>,
+}
+
+/// Body of a 409 on a stale settings update, carrying what is stored now
+#[derive(Serialize, Deserialize)]
+pub struct RepositoryConflictResponse {
+    pub message: String,
+    pub current: RepositoryResponse,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetTopicsRequest {
+    pub topics: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ServerMetaResponse {
+    pub version: String,
+    pub external_url: String,
+    pub protocol: ProtocolSupport,
+    pub ssh: SshMeta,
+    /// Scopes that can be granted to access tokens
+    #[serde(default)]
+    pub token_scopes: Vec<String>,
+}
+
+/// Optional Git protocol features and whether this server supports them
+#[derive(Serialize, Deserialize)]
+pub struct ProtocolSupport {
+    pub v2: bool,
+    pub lfs: bool,
+    pub shallow: bool,
+    pub filter: bool,
+    #[serde(default)]
+    pub delete_refs: bool,
+    pub upload_pack_capabilities: Vec<String>,
+    pub receive_pack_capabilities: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SshMeta {
+    pub host: String,
+    pub port: u16,
+    pub host_key_fingerprints: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -44,54 +282,203 @@ pub struct UserResponse {
     pub created_at: String,
 }
 
+/// Where a git request failed, which decides how the error reaches the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProtocolPhase {
+    /// Before any pkt-lines are exchanged, as for info/refs: git prints a
+    /// plain-text body after its own message for the status
+    Handshake(StatusCode),
+    /// Inside an upload-pack exchange, where the client reads an `ERR` line
+    UploadPack,
+    /// Inside a receive-pack exchange; side-band clients read errors on band 3
+    ReceivePack { sideband: bool },
+}
+
+/// Report `message` the way git shows it verbatim. Mid-exchange errors go out
+/// with a 200 as an `ERR` pkt-line or on side-band 3, since any other status only
+/// gets a generic "unable to access" from the client.
+pub(crate) fn protocol_error_response(phase: ProtocolPhase, message: &str) -> HttpResponse {
+    let protocol = ProtocolHandler::new();
+    let (content_type, body) = match phase {
+        ProtocolPhase::Handshake(status) => {
+            return HttpResponse::build(status)
+                .content_type("text/plain")
+                .body(format!("{}\n", message));
+        }
+        ProtocolPhase::UploadPack => ("application/x-git-upload-pack-result", protocol.create_error(message)),
+        ProtocolPhase::ReceivePack { sideband: true } => {
+            ("application/x-git-receive-pack-result", protocol.create_sideband_error(message))
+        }
+        ProtocolPhase::ReceivePack { sideband: false } => {
+            ("application/x-git-receive-pack-result", protocol.create_error(message))
+        }
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .body(body)
+}
+
+/// Protocol error for a storage failure. A busy store still answers 503 with
+/// `Retry-After`, which clients and proxies know to back off from.
+fn storage_protocol_error(phase: ProtocolPhase, err: &anyhow::Error) -> HttpResponse {
+    match err.downcast_ref::<StorageError>() {
+        Some(StorageError::ResourceBusy) => storage_error_response(err),
+        Some(StorageError::RepositoryNotFound { .. }) => protocol_error_response(phase, "repository not found"),
+        _ => {
+            tracing::error!(error = %err, "git request failed");
+            protocol_error_response(phase, "internal server error")
+        }
+    }
+}
+
+/// The repository a git URL names, as `name` or, like the clone URLs, `owner/name`.
+/// A repository under another owner is treated as missing.
+async fn repository_by_path(state: &AppState, path: &str) -> anyhow::Result<Option<repository::Model>> {
+    let (owner, name) = match path.split_once('/') {
+        Some((owner, name)) => (Some(owner), name),
+        None => (None, path),
+    };
+    let Some(repository) = state.repository_service.get_repository_by_name(name).await? else {
+        return Ok(None);
+    };
+    match owner {
+        Some(owner) if owner_username(state, &repository).await? != owner => Ok(None),
+        _ => Ok(Some(repository)),
+    }
+}
+
+/// The repository at `name` (see [`repository_by_path`]), or a not-found error
+/// when it doesn't exist or the caller can't read it, so clients can't probe for
+/// repositories they don't know about
+async fn readable_repository(
+    state: &AppState,
+    caller: &Caller,
+    name: &str,
+    phase: ProtocolPhase,
+) -> std::result::Result<repository::Model, HttpResponse> {
+    let not_found = |phase| match phase {
+        ProtocolPhase::Handshake(_) => ProtocolPhase::Handshake(StatusCode::NOT_FOUND),
+        phase => phase,
+    };
+    let failed = |phase| match phase {
+        ProtocolPhase::Handshake(_) => ProtocolPhase::Handshake(StatusCode::INTERNAL_SERVER_ERROR),
+        phase => phase,
+    };
+    let repository = match repository_by_path(state, name).await {
+        Ok(Some(repo)) => repo,
+        Ok(None) => return Err(protocol_error_response(not_found(phase), "repository not found")),
+        Err(e) => return Err(storage_protocol_error(failed(phase), &e)),
+    };
+    match can_read(state, caller.user_id(), &repository).await {
+        Ok(true) => Ok(repository),
+        Ok(false) => Err(protocol_error_response(not_found(phase), "repository not found")),
+        Err(e) => Err(storage_protocol_error(failed(phase), &e)),
+    }
+}
+
+/// A 401 when an anonymous caller may not clone `repository`: the server requires
+/// sign-in everywhere, or the repository has anonymous clones turned off. Only
+/// upload-pack and its advertisement are covered. It stays a 401 even mid-exchange,
+/// since that is what makes git ask for credentials.
+fn anonymous_clone_refusal(state: &AppState, caller: &Caller, repository: &repository::Model) -> Option<HttpResponse> {
+    let allowed =
+        caller.user_id().is_some() || (!state.config.require_auth && repository.allow_anonymous_clone);
+    (!allowed).then(|| protocol_error_response(ProtocolPhase::Handshake(StatusCode::UNAUTHORIZED), "authentication required"))
+}
+
+/// A 401 for anonymous pushes, whatever the clone settings. Refusing the
+/// receive-pack advertisement already is what makes git ask for credentials
+/// before it builds a pack.
+fn anonymous_push_refusal(caller: &Caller) -> Option<HttpResponse> {
+    caller
+        .user()
+        .is_none()
+        .then(|| protocol_error_response(ProtocolPhase::Handshake(StatusCode::UNAUTHORIZED), "authentication required"))
+}
+
 /// Handle Git info/refs request
-#[get("/{repo}/info/refs")]
+#[get("/{repo:[^/]+(?:/[^/]+)?}/info/refs")]
 pub async fn info_refs(
     path: web::Path<String>,
     query: web::Query<std::collections::HashMap<String, String>>,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
     let repo_name = path.into_inner();
     let service = query.get("service").cloned();
 
-    // Get repository from database
-    let repository = match state.repository_service.get_repository_by_name(&repo_name).await {
-        Ok(Some(repo)) => repo,
-        Ok(None) => {
-            return Ok(HttpResponse::NotFound().json("Repository not found"));
-        }
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().json("Database error"));
-        }
+    let repository = match readable_repository(&state, &caller, &repo_name, ProtocolPhase::Handshake(StatusCode::OK)).await {
+        Ok(repo) => repo,
+        Err(response) => return Ok(response),
     };
+    let refusal = match service.as_deref() {
+        Some("git-receive-pack") => anonymous_push_refusal(&caller),
+        _ => anonymous_clone_refusal(&state, &caller, &repository),
+    };
+    if let Some(response) = refusal {
+        return Ok(response);
+    }
 
-    // Get references
-    let refs = match state.repository_service.get_refs_by_repository(repository.id).await {
+    // Get references, symbolic ones resolved to the object they end at
+    let refs = match state.repository_service.get_resolved_refs(repository.id).await {
         Ok(refs) => refs,
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().json("Failed to get references"));
-        }
+        Err(e) => return Ok(storage_protocol_error(ProtocolPhase::Handshake(StatusCode::INTERNAL_SERVER_ERROR), &e)),
     };
 
-    let protocol = ProtocolHandler::new();
-    let ref_pairs: Vec<(String, String)> = refs
-        .into_iter()
-        .map(|r| (r.name, r.target))
-        .collect();
+    let protocol = state.protocol_handler(repository.object_format.hash_algorithm());
+
+    // HEAD is stored symbolically; advertise it first, resolved to its branch tip
+    let head = refs.iter().find(|r| r.name == "HEAD");
+    let head_target = head.and_then(|r| r.symref_target.clone());
+    let head_id = head.map(|r| r.target.clone());
 
-    let capabilities = match service.as_deref() {
-        Some("git-upload-pack") => vec!["multi_ack", "side-band-64k", "ofs-delta"],
-        Some("git-receive-pack") => vec!["report-status", "delete-refs", "ofs-delta"],
-        _ => vec![],
+    let hidden = state.config.hidden_refs(&repository);
+    let mut ref_pairs: Vec<(String, String)> = Vec::new();
+    if let Some(head_id) = head_id.clone() {
+        ref_pairs.push(("HEAD".to_string(), head_id));
+    }
+    ref_pairs.extend(
+        refs.into_iter()
+            .filter(|r| r.name != "HEAD" && !hidden.is_hidden(&r.name))
+            .map(|r| (r.name, r.target)),
+    );
+
+    let mut capabilities: Vec<String> = match service.as_deref() {
+        Some("git-upload-pack") => state.config.protocol.upload_pack_capabilities(),
+        Some("git-receive-pack") => state.config.protocol.receive_pack_capabilities(),
+        _ => Vec::new(),
     };
+    if let (Some(target), Some(_), Some("git-upload-pack")) = (&head_target, &head_id, service.as_deref()) {
+        capabilities.push(format!("symref=HEAD:{}", target));
+    }
+    // SHA-1 is assumed when unadvertised; anything else has to be named
+    if service.is_some() && protocol.hash_algorithm() != HashAlgorithm::Sha1 {
+        capabilities.push(format!("object-format={}", protocol.hash_algorithm().name()));
+    }
+    let capabilities: Vec<&str> = capabilities.iter().map(|c| c.as_str()).collect();
 
-    let response_data = protocol.create_ref_advertisement(&ref_pairs, &capabilities);
+    // Every ref is still served; the warning points at repositories that slow down clones
+    if ref_pairs.len() > state.config.max_advertised_refs {
+        tracing::warn!(
+            repo = %repo_name,
+            refs = ref_pairs.len(),
+            limit = state.config.max_advertised_refs,
+            "Ref advertisement exceeds MAX_ADVERTISED_REFS"
+        );
+    }
 
-    let content_type = match service.as_deref() {
-        Some("git-upload-pack") => "application/x-git-upload-pack-advertisement",
-        Some("git-receive-pack") => "application/x-git-receive-pack-advertisement",
-        _ => "text/plain",
+    let (content_type, mut response_data) = match service.as_deref() {
+        Some(name @ "git-upload-pack") => {
+            ("application/x-git-upload-pack-advertisement", protocol.create_service_announcement(name))
+        }
+        Some(name @ "git-receive-pack") => {
+            ("application/x-git-receive-pack-advertisement", protocol.create_service_announcement(name))
+        }
+        _ => ("text/plain", Vec::new()),
     };
+    // Smart clients expect the service announcement before the refs
+    response_data.extend(protocol.create_ref_advertisement(&ref_pairs, &capabilities));
 
     Ok(HttpResponse::Ok()
         .content_type(content_type)
@@ -99,335 +486,4929 @@ pub async fn info_refs(
 }
 
 /// Handle Git upload-pack request
-#[post("/{repo}/git-upload-pack")]
+#[post("/{repo:[^/]+(?:/[^/]+)?}/git-upload-pack")]
 pub async fn upload_pack(
     path: web::Path<String>,
+    http_req: HttpRequest,
     body: web::Bytes,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
     let repo_name = path.into_inner();
-    
-    // Get repository from database
-    let _repository = match state.repository_service.get_repository_by_name(&repo_name).await {
-        Ok(Some(repo)) => repo,
-        Ok(None) => {
-            return Ok(HttpResponse::NotFound().json("Repository not found"));
-        }
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().json("Database error"));
-        }
+
+    let repository = match readable_repository(&state, &caller, &repo_name, ProtocolPhase::UploadPack).await {
+        Ok(repo) => repo,
+        Err(response) => return Ok(response),
     };
+    if let Some(response) = anonymous_clone_refusal(&state, &caller, &repository) {
+        return Ok(response);
+    }
 
-    let protocol = ProtocolHandler::new();
+    let protocol = state.protocol_handler(repository.object_format.hash_algorithm());
     
     // Parse the request
-    let pkt_lines = match protocol.parse_pkt_line(&body) {
+    let pkt_lines = match protocol.parse_pkt_sections(&body) {
         Ok(lines) => lines,
-        Err(_) => {
-            return Ok(HttpResponse::BadRequest().json("Invalid pkt-line format"));
+        Err(_) => return Ok(protocol_error_response(ProtocolPhase::UploadPack, "upload-pack: invalid pkt-line format")),
+    };
+
+    let negotiation = match protocol.parse_negotiation(&body) {
+        Ok(negotiation) => negotiation,
+        Err(_) => return Ok(protocol_error_response(ProtocolPhase::UploadPack, "upload-pack: invalid want/have format")),
+    };
+    let wants = negotiation.wants.clone();
+    let haves = negotiation.haves();
+
+    let filters: Vec<String> = pkt_lines
+        .iter()
+        .filter_map(|line| line.trim().strip_prefix("filter "))
+        .map(str::to_string)
+        .collect();
+    let options = match fetch_options(&state.config.protocol, &pkt_lines, &negotiation, &filters) {
+        Ok(options) => options,
+        Err(message) => return Ok(protocol_error_response(ProtocolPhase::UploadPack, &message)),
+    };
+
+    if !state.config.allow_hidden_ref_wants {
+        match hidden_want(&state, &repository, &wants).await {
+            Ok(Some(want)) => {
+                let message = format!("upload-pack: not our ref {}", want);
+                return Ok(protocol_error_response(ProtocolPhase::UploadPack, &message));
+            }
+            Ok(None) => {}
+            Err(e) => return Ok(storage_protocol_error(ProtocolPhase::UploadPack, &e)),
+        }
+    }
+
+    // Wants are looked up by this repository's id, so an object id from another
+    // repository is never ours even when the same object is stored there
+    match foreign_want(&state, repository.id, &wants).await {
+        Ok(Some(want)) => {
+            let message = format!("upload-pack: not our ref {}", want);
+            return Ok(protocol_error_response(ProtocolPhase::UploadPack, &message));
         }
+        Ok(None) => {}
+        Err(e) => return Ok(storage_protocol_error(ProtocolPhase::UploadPack, &e)),
+    }
+
+    let capabilities = protocol.parse_want_capabilities(&pkt_lines);
+    let client = ClientInfo::from_http(&http_req, &capabilities);
+    let span = client_span("upload_pack", &repo_name, &client);
+    span.in_scope(|| {
+        tracing::info!(
+            wants = wants.len(),
+            haves = haves.len(),
+            rounds = negotiation.rounds.len(),
+            done = negotiation.done,
+            "fetch"
+        )
+    });
+    // Negotiation rounds before `done` are part of the same fetch, so only the last one counts
+    if negotiation.done {
+        record_client_event(&state, repository.id, RepositoryEventType::Fetch, &client)
+            .instrument(span.clone())
+            .await;
+    }
+
+    let shallow: Vec<String> = pkt_lines
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| line.starts_with("shallow ") || line.starts_with("deepen"))
+        .map(str::to_string)
+        .collect();
+    let request = PackRequest {
+        repository_id: repository.id,
+        ref_generation: repository.ref_generation,
+        wants: &wants,
+        haves: &haves,
+        filters: &filters,
+        shallow: &shallow,
+        capabilities: capabilities.iter().collect(),
+    };
+    // Held until the pack is built; queued clones wait here rather than all building at once
+    let Some(_permit) = state.clone_limiter.acquire(repository.id).await else {
+        span.in_scope(|| tracing::warn!("upload-pack queue timed out"));
+        return Ok(CloneLimiter::busy_response());
     };
 
-    let (_wants, _haves) = match protocol.parse_want_have(&pkt_lines) {
-        Ok(wh) => wh,
-        Err(_) => {
-            return Ok(HttpResponse::BadRequest().json("Invalid want/have format"));
+    // Shallow clients are told where their history now stops before anything else
+    let mut response = Vec::new();
+    if options.depth.is_some() || !options.client_shallow.is_empty() {
+        let update = match options.depth {
+            Some(depth) => {
+                let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+                let db = state.repository_service.get_db();
+                match git_ops.shallow_update(db, repository.id, &wants, depth, &options.client_shallow).await {
+                    Ok(update) => update,
+                    Err(e) => return Ok(storage_protocol_error(ProtocolPhase::UploadPack, &e)),
+                }
+            }
+            None => ShallowUpdate::default(),
+        };
+        response.extend(protocol.create_shallow_info(&update.shallow, &update.unshallow));
+    }
+
+    // Until the client says done it only learns which haves we share; the pack
+    // comes in the request that ends with done
+    if !negotiation.done {
+        let multi_ack = capabilities.has("multi_ack");
+        match negotiation_acks(&state, repository.id, &protocol, &negotiation, multi_ack).await {
+            Ok(acks) => response.extend(acks),
+            Err(e) => return Ok(storage_protocol_error(ProtocolPhase::UploadPack, &e)),
         }
+        return Ok(HttpResponse::Ok()
+            .content_type("application/x-git-upload-pack-result")
+            .body(response));
+    }
+
+    let pack = match fetch_pack(&state, &protocol, &request, &options, &capabilities).instrument(span).await {
+        Ok(pack) => pack,
+        Err(e) => return Ok(storage_protocol_error(ProtocolPhase::UploadPack, &e)),
     };
 
-    // For now, just return NAK (no objects to send)
-    // In a full implementation, we would:
-    // 1. Calculate which objects the client needs
-    // 2. Create a pack file with those objects
-    // 3. Send the pack file back
-    let nak_response = protocol.create_nak();
+    let sideband = capabilities.has("side-band-64k") || capabilities.has("side-band");
+    response.extend(protocol.create_nak());
+    let pack = match pack {
+        FetchPack::Cached(file) => {
+            return Ok(HttpResponse::Ok()
+                .content_type("application/x-git-upload-pack-result")
+                .body(CachedPackBody::new(response, file, sideband.then_some(protocol))));
+        }
+        FetchPack::Built(pack) => pack,
+    };
+    if sideband {
+        response.extend(protocol.create_sideband(1, &pack));
+    } else {
+        response.extend(pack);
+    }
 
     Ok(HttpResponse::Ok()
         .content_type("application/x-git-upload-pack-result")
-        .body(nak_response))
+        .body(response))
 }
 
-/// Handle Git receive-pack request
-#[post("/{repo}/git-receive-pack")]
-pub async fn receive_pack(
-    path: web::Path<String>,
-    _body: web::Bytes,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let repo_name = path.into_inner();
-    
-    // Get repository from database
-    let _repository = match state.repository_service.get_repository_by_name(&repo_name).await {
-        Ok(Some(repo)) => repo,
-        Ok(None) => {
-            return Ok(HttpResponse::NotFound().json("Repository not found"));
-        }
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().json("Database error"));
-        }
-    };
+/// What an upload-pack request asks to leave out, or the error line refusing it.
+/// Features switched off in `settings`, and deepen forms the server doesn't offer,
+/// are refused rather than ignored, since the client would misread a full pack.
+fn fetch_options(
+    settings: &ProtocolSettings,
+    pkt_lines: &[String],
+    negotiation: &Negotiation,
+    filters: &[String],
+) -> std::result::Result<FetchOptions, String> {
+    if let Some(line) = pkt_lines.iter().map(|line| line.trim()).find(|line| line.starts_with("deepen-")) {
+        let name = line.split_whitespace().next().unwrap_or(line);
+        return Err(format!("upload-pack: {} is not supported", name));
+    }
+    if !settings.allow_shallow && (negotiation.depth.is_some() || !negotiation.shallow.is_empty()) {
+        return Err("upload-pack: shallow fetches are disabled on this server".to_string());
+    }
+    if !settings.allow_filters && !filters.is_empty() {
+        return Err("upload-pack: filtering is disabled on this server".to_string());
+    }
 
-    // For now, just accept the push
-    // In a full implementation, we would:
-    // 1. Parse the pack file
-    // 2. Store the objects in the database
-    // 3. Update the references
-    // 4. Return appropriate status
+    let mut blob_limit = None;
+    for spec in filters {
+        let filter = ObjectFilter::parse(spec).map_err(|_| format!("upload-pack: invalid filter-spec '{}'", spec))?;
+        blob_limit = Some(blob_limit.map_or(filter.blob_limit(), |limit: u64| limit.min(filter.blob_limit())));
+    }
+    Ok(FetchOptions {
+        depth: negotiation.depth.map(|depth| depth as usize),
+        client_shallow: negotiation.shallow.clone(),
+        blob_limit,
+    })
+}
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/x-git-receive-pack-result")
-        .body("unpack ok\n"))
+/// A fetch's pack: streamed back from the pack cache, or just built
+enum FetchPack {
+    Cached(std::fs::File),
+    Built(Vec<u8>),
 }
 
-/// List all repositories
-#[get("/repositories")]
-pub async fn list_repositories(state: web::Data<AppState>) -> Result<HttpResponse> {
-    match state.repository_service.list_repositories().await {
-        Ok(repos) => {
-            let response: Vec<RepositoryResponse> = repos
-                .into_iter()
-                .map(|repo| RepositoryResponse {
-                    id: repo.id.to_string(),
-                    name: repo.name,
-                    description: repo.description,
-                    default_branch: repo.default_branch,
-                    owner_id: repo.owner_id.to_string(),
-                    is_private: repo.is_private,
-                    created_at: repo.created_at.to_string(),
-                })
-                .collect();
-            Ok(HttpResponse::Ok().json(response))
+/// Pack of the objects a fetch needs, served from the pack cache when the same
+/// request was answered before at the same ref generation. The cache holds the
+/// raw pack; side-band framing is added per response.
+async fn fetch_pack(
+    state: &AppState,
+    protocol: &ProtocolHandler,
+    request: &PackRequest<'_>,
+    options: &FetchOptions,
+    capabilities: &Capabilities,
+) -> anyhow::Result<FetchPack> {
+    let key = request.key();
+    if let Some(file) = state.pack_cache.open(&key) {
+        tracing::debug!(key, "pack cache hit");
+        return Ok(FetchPack::Cached(file));
+    }
+
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+    let objects = git_ops
+        .objects_for_fetch(
+            state.repository_service.get_db(),
+            request.repository_id,
+            request.wants,
+            request.haves,
+            options,
+        )
+        .await?;
+    let pack = protocol.create_fetch_pack(&objects, capabilities)?;
+    state.pack_cache.insert(&key, request.repository_id, &pack);
+    Ok(FetchPack::Built(pack))
+}
+
+/// First want that only a hidden ref points at, if any
+async fn hidden_want(
+    state: &AppState,
+    repository: &repository::Model,
+    wants: &[String],
+) -> anyhow::Result<Option<String>> {
+    let hidden = state.config.hidden_refs(repository);
+    // Resolved, so a want advertised through a symbolic ref counts as visible
+    let refs = state.repository_service.get_resolved_refs(repository.id).await?;
+    let (hidden_refs, visible_refs): (Vec<_>, Vec<_>) = refs.iter().partition(|r| hidden.is_hidden(&r.name));
+
+    Ok(wants
+        .iter()
+        .find(|want| {
+            hidden_refs.iter().any(|r| &r.target == *want)
+                && !visible_refs.iter().any(|r| &r.target == *want)
+        })
+        .cloned())
+}
+
+/// The first want that isn't an object of `repository_id`
+async fn foreign_want(
+    state: &AppState,
+    repository_id: uuid::Uuid,
+    wants: &[String],
+) -> anyhow::Result<Option<String>> {
+    for want in wants {
+        if !state.repository_service.object_exists(repository_id, want).await? {
+            return Ok(Some(want.clone()));
         }
-        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
     }
+    Ok(None)
 }
 
-/// Get a specific repository
-#[get("/repositories/{name}")]
-pub async fn get_repository(
-    path: web::Path<String>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let repo_name = path.into_inner();
-    
-    match state.repository_service.get_repository_by_name(&repo_name).await {
-        Ok(Some(repo)) => {
-            let response = RepositoryResponse {
-                id: repo.id.to_string(),
-                name: repo.name,
-                description: repo.description,
-                default_branch: repo.default_branch,
-                owner_id: repo.owner_id.to_string(),
-                is_private: repo.is_private,
-                created_at: repo.created_at.to_string(),
-            };
-            Ok(HttpResponse::Ok().json(response))
+/// ACK/NAK lines answering negotiation rounds that didn't end in done, the way
+/// git's upload-pack does: every common have is acknowledged under multi_ack, only
+/// the first one otherwise, and each round closes with NAK unless a plain ACK
+/// already told the client where to stop.
+async fn negotiation_acks(
+    state: &AppState,
+    repository_id: uuid::Uuid,
+    protocol: &ProtocolHandler,
+    negotiation: &Negotiation,
+    multi_ack: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let mut acks = Vec::new();
+    let mut common = 0;
+    for round in &negotiation.rounds {
+        for have in round {
+            if !state.repository_service.object_exists(repository_id, have).await? {
+                continue;
+            }
+            common += 1;
+            if multi_ack {
+                acks.extend(protocol.create_ack_continue(have));
+            } else if common == 1 {
+                acks.extend(protocol.create_ack(have));
+            }
+        }
+        if common == 0 || multi_ack {
+            acks.extend(protocol.create_nak());
         }
-        Ok(None) => Ok(HttpResponse::NotFound().json("Repository not found")),
-        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
     }
+    // A bare flush, with no haves yet, is still a round the client waits on
+    if negotiation.rounds.is_empty() {
+        acks.extend(protocol.create_nak());
+    }
+    Ok(acks)
 }
 
-/// Create a new repository
-#[post("/repositories")]
-pub async fn create_repository(
-    body: web::Json<CreateRepositoryRequest>,
+/// Handle Git receive-pack request
+#[post("/{repo:[^/]+(?:/[^/]+)?}/git-receive-pack")]
+pub async fn receive_pack(
+    path: web::Path<String>,
+    http_req: HttpRequest,
+    body: web::Bytes,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let req = body.into_inner();
-    
-    // Parse owner_id if provided, otherwise use a default admin user (for demo)
-    let owner_id = if let Some(owner_id_str) = req.owner_id {
-        match uuid::Uuid::parse_str(&owner_id_str) {
-            Ok(id) => id,
-            Err(_) => return Ok(HttpResponse::BadRequest().json("Invalid owner_id format")),
+    let repo_name = path.into_inner();
+
+    // Parsed first so errors can go out on side-band when the client asked for it.
+    // Command ids are in the repository's object format, so that's looked up ahead
+    // of the access check; an unknown repository is refused right after
+    let hash = match repository_by_path(&state, &repo_name).await {
+        Ok(Some(repository)) => repository.object_format.hash_algorithm(),
+        _ => HashAlgorithm::default(),
+    };
+    let protocol = state.protocol_handler(hash);
+    let request = match protocol.parse_receive_pack_request(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let phase = ProtocolPhase::ReceivePack { sideband: false };
+            return Ok(protocol_error_response(phase, &format!("receive-pack: invalid request: {}", e)));
         }
+    };
+    let phase = ProtocolPhase::ReceivePack { sideband: request.uses_sideband() };
+
+    let repository = match readable_repository(&state, &caller, &repo_name, phase).await {
+        Ok(repo) => repo,
+        Err(response) => return Ok(response),
+    };
+    // Pushing takes a signed-in user who may write to the repository; archived
+    // repositories refuse every push below with their own message
+    if let Some(response) = anonymous_push_refusal(&caller) {
+        return Ok(response);
+    }
+    if !repository.is_archived && !Permissions::effective(&repository, caller.user(), &caller).write {
+        return Ok(protocol_error_response(phase, "permission denied"));
+    }
+
+    let client = ClientInfo::from_http(&http_req, &request.capabilities);
+    let span = client_span("receive_pack", &repo_name, &client);
+
+    let response_data = if repository.is_archived {
+        span.in_scope(|| tracing::info!("push to archived repository rejected"));
+        rejected_push_response(&protocol, &request, ARCHIVED_MESSAGE, ARCHIVED_MESSAGE)
+    } else if let Err(message) = state.client_policy.check_push(&client) {
+        span.in_scope(|| tracing::warn!(reason = %message, "push rejected by client policy"));
+        state.client_metrics.record_rejected_push();
+        rejected_push_response(&protocol, &request, &message, "rejected by client policy")
     } else {
-        // For demo purposes, create a default admin user if none exists
-        // In production, you'd want proper authentication
-        match state.user_service.get_user_by_username("admin").await {
-            Ok(Some(user)) => user.id,
-            Ok(None) => {
-                // Create default admin user
-                match state
-                    .user_service
-                    .create_user(
-                        "admin".to_string(),
-                        "admin@example.com".to_string(),
-                        "password_hash".to_string(), // In production, use proper password hashing
-                        Some("Administrator".to_string()),
-                        true,
-                    )
-                    .await
-                {
-                    Ok(admin_user) => admin_user.id,
-                    Err(_) => return Ok(HttpResponse::InternalServerError().json("Failed to create default admin user")),
-                }
+        state.client_metrics.record(RepositoryEventType::Push, &client);
+        // Only admins may push into hidden namespaces; skip the lookup for ordinary pushes
+        let hidden = state.config.hidden_refs(&repository);
+        let touches_hidden = request.commands.iter().any(|c| hidden.is_hidden(&c.ref_name));
+        let is_admin = match caller.user_id() {
+            Some(user_id) if touches_hidden => matches!(
+                state.user_service.get_user_by_id(user_id).await,
+                Ok(Some(user)) if user.is_admin
+            ),
+            _ => false,
+        };
+        let hidden = if is_admin { HiddenRefs::default() } else { hidden };
+        // Post-receive messages name the pusher, and only side-band clients see them
+        let pusher = match caller.user_id() {
+            Some(user_id) if request.uses_sideband() => match state.user_service.get_user_by_id(user_id).await {
+                Ok(Some(user)) => Some(user.username),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        match apply_push(&state, &repository, &protocol, &request, &hidden, pusher.as_deref())
+            .instrument(span.clone())
+            .await
+        {
+            Ok((response, payload)) => {
+                record_push_event(&state, repository.id, &client, caller.user_id(), &payload)
+                    .instrument(span)
+                    .await;
+                response
             }
-            Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+            Err(e) => return Ok(storage_protocol_error(phase, &e)),
         }
     };
-    
-    match state
-        .repository_service
-        .create_repository(
-            req.name,
-            req.description,
-            "main".to_string(),
-            owner_id,
-            req.is_private.unwrap_or(false),
-        )
-        .await
-    {
-        Ok(repo) => {
-            let response = RepositoryResponse {
-                id: repo.id.to_string(),
-                name: repo.name,
-                description: repo.description,
-                default_branch: repo.default_branch,
-                owner_id: repo.owner_id.to_string(),
-                is_private: repo.is_private,
-                created_at: repo.created_at.to_string(),
-            };
-            Ok(HttpResponse::Created().json(response))
-        }
-        Err(_) => Ok(HttpResponse::InternalServerError().json("Failed to create repository")),
-    }
+
+    // The whole report is known up front, so it goes out with its length rather
+    // than chunked, and never from a cache
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-git-receive-pack-result")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .no_chunking(response_data.len() as u64)
+        .body(response_data))
 }
 
-// User Management API Endpoints
+/// Unpack a push and apply its ref commands, returning the report-status (if requested)
+/// and the push event payload describing the refs that changed. Commands on `hidden`
+/// refs are refused. Side-band clients also get the post-receive hooks' messages.
+async fn apply_push(
+    state: &AppState,
+    repository: &repository::Model,
+    protocol: &ProtocolHandler,
+    request: &ReceivePackRequest,
+    hidden: &HiddenRefs,
+    pusher: Option<&str>,
+) -> anyhow::Result<(Vec<u8>, PushEventPayload)> {
+    // Serialize with other pushes to this repository for the duration of the update
+    let write_guard = state.repository_service.lock_writes(repository.id).await?;
 
-/// Create a new user
-#[post("/users")]
-pub async fn create_user(
-    body: web::Json<CreateUserRequest>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let req = body.into_inner();
-    
-    // Check if username or email already exists
-    if let Ok(true) = state.user_service.username_exists(&req.username).await {
-        return Ok(HttpResponse::Conflict().json("Username already exists"));
+    // What each ref reports when the pack can't be unpacked; oversized objects
+    // are named there too, since clients show ref reasons more readily
+    let mut unpacker_error = "unpacker error".to_string();
+    let unpack_result = if request.pack.is_empty() {
+        Ok(())
+    } else {
+        // Checked against the cached sizes of the owner's repositories
+        let (quota, usage) = quota::owner_quota(state, repository.owner_id).await?;
+        unpack_objects(state, &write_guard, protocol, &request.pack, quota.remaining_bytes(&usage))
+            .await
+            .map_err(|e| match (e.downcast_ref::<StorageError>(), e.downcast_ref::<ProtocolError>()) {
+                (Some(&StorageError::QuotaExceeded { needed, available }), _) => {
+                    QuotaHit::DiskQuota { needed, available }.unpack_status()
+                }
+                (_, Some(size @ (ProtocolError::ObjectTooLarge { .. } | ProtocolError::ObjectSizeMismatch { .. }))) => {
+                    unpacker_error = size.to_string();
+                    format!("error {}", e)
+                }
+                _ => format!("error {}", e),
+            })
+    };
+
+    let mut prepared = Vec::new();
+    for command in &request.commands {
+        let result = match unpack_result {
+            Ok(()) if hidden.is_hidden(&command.ref_name) => Err("deny updating a hidden ref".to_string()),
+            Ok(()) if command.is_delete() && !state.config.protocol.allow_deletes => {
+                Err("deletion prohibited".to_string())
+            }
+            Ok(()) => prepare_ref_command(state, &write_guard, repository, command).await,
+            Err(_) => Err(unpacker_error.clone()),
+        };
+        prepared.push(result);
     }
-    
-    if let Ok(true) = state.user_service.email_exists(&req.email).await {
-        return Ok(HttpResponse::Conflict().json("Email already exists"));
+
+    let atomic = request.has_capability("atomic");
+    let results = if atomic {
+        apply_ref_updates_atomically(state, &write_guard, prepared).await
+    } else {
+        let mut results = Vec::with_capacity(prepared.len());
+        for result in prepared {
+            results.push(match result {
+                Ok(update) => state
+                    .repository_service
+                    .update_refs(&write_guard, vec![update.clone()])
+                    .await
+                    .map(|()| update)
+                    .map_err(|e| ref_update_failure(&e)),
+                Err(message) => Err(message),
+            });
+        }
+        results
+    };
+    let atomic_failed = atomic && results.iter().any(Result::is_err);
+
+    let mut ref_results = Vec::new();
+    let mut applied = Vec::new();
+    for (command, result) in request.commands.iter().zip(results) {
+        let result = match result {
+            Ok(update) => {
+                applied.push(update);
+                Ok(())
+            }
+            Err(message) => Err(message),
+        };
+        ref_results.push((command.ref_name.clone(), result));
     }
-    
-    // In production, hash the password properly
-    let password_hash = match state.user_service.hash_password(&req.password) {
-        Ok(hash) => hash,
-        Err(_) => return Ok(HttpResponse::InternalServerError().json("Failed to process password")),
+    let payload = push_event_payload(state, repository.id, applied).await;
+
+    // Symbolic ref changes run last so HEAD can move onto a branch created by this push
+    for (name, target) in request.symref_updates() {
+        let result = if unpack_result.is_err() {
+            Err(unpacker_error.clone())
+        } else if atomic_failed {
+            Err(ATOMIC_FAILURE.to_string())
+        } else if name != "HEAD" {
+            Err("only HEAD can be updated as a symbolic ref".to_string())
+        } else {
+            state
+                .repository_service
+                .set_head(&write_guard, &target)
+                .await
+                .map_err(|e| e.to_string())
+        };
+        ref_results.push((name, result));
+    }
+
+    // Packs built against the old refs can't be asked for again
+    if ref_results.iter().any(|(_, result)| result.is_ok()) {
+        state.pack_cache.invalidate_repository(repository.id);
+    }
+
+    if !request.has_capability("report-status") {
+        return Ok((Vec::new(), payload));
+    }
+
+    let report = protocol.create_report_status(&unpack_result, &ref_results);
+    if !request.uses_sideband() {
+        return Ok((report, payload));
+    }
+
+    let mut response = Vec::new();
+    protocol.write_sideband(&mut response, 1, &report);
+    let push = PostReceiveContext {
+        repository,
+        pusher,
+        refs: &payload.refs,
+        config: &state.config,
     };
-    
-    match state
-        .user_service
-        .create_user(
-            req.username,
-            req.email,
-            password_hash,
-            req.full_name,
-            req.is_admin.unwrap_or(false),
-        )
+    let messages = state.post_receive_hooks.messages(&push).await;
+    post_receive::write_messages(protocol, &mut response, &messages);
+    response.extend_from_slice(b"0000");
+    Ok((response, payload))
+}
+
+/// Describe the ref updates a push applied. Old targets come from the updates
+/// themselves, which `update_refs` verified, since the refs may have moved again
+/// by the time anyone reads them. The refs are already updated, so a failed
+/// commit walk only leaves that ref's commit list empty.
+async fn push_event_payload(
+    state: &AppState,
+    repository_id: uuid::Uuid,
+    applied: Vec<RefUpdate>,
+) -> PushEventPayload {
+    let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+    let db = state.repository_service.get_db();
+    let mut refs = Vec::with_capacity(applied.len());
+    for update in applied {
+        let commits = match &update.new_target {
+            Some(after) => git_ops
+                .commits_between(db, repository_id, after, update.old_target.as_deref())
+                .await
+                .unwrap_or_else(|e| {
+                    tracing::warn!(ref_name = %update.name, error = %e, "failed to list pushed commits");
+                    Vec::new()
+                }),
+            None => Vec::new(),
+        };
+        refs.push(PushedRef::new(
+            update.name,
+            update.old_target.as_deref(),
+            update.new_target.as_deref(),
+            commits,
+        ));
+    }
+    PushEventPayload { refs }
+}
+
+/// Store the event for a push that changed at least one ref, made by `actor_id`
+/// when the pusher signed in. Failing to store it is logged rather than failing
+/// the push.
+async fn record_push_event(
+    state: &AppState,
+    repository_id: uuid::Uuid,
+    client: &ClientInfo,
+    actor_id: Option<uuid::Uuid>,
+    payload: &PushEventPayload,
+) {
+    if payload.refs.is_empty() {
+        return;
+    }
+    let mut event = client.event(repository_id, RepositoryEventType::Push);
+    event.payload = serde_json::to_string(payload).ok();
+    event.actor_id = actor_id;
+    if let Err(e) = state.event_service.record(event).await {
+        tracing::warn!("Failed to record push event: {}", e);
+    }
+}
+
+/// Tell a client its push was refused: a side-band error where possible, otherwise
+/// a failed report-status with `ref_reason` on every ref
+fn rejected_push_response(
+    protocol: &ProtocolHandler,
+    request: &ReceivePackRequest,
+    message: &str,
+    ref_reason: &str,
+) -> Vec<u8> {
+    if request.uses_sideband() {
+        protocol.create_sideband_error(message)
+    } else if request.has_capability("report-status") {
+        let ref_results: Vec<_> = request
+            .commands
+            .iter()
+            .map(|c| (c.ref_name.clone(), Err(ref_reason.to_string())))
+            .collect();
+        protocol.create_report_status(&Err(message.to_string()), &ref_results)
+    } else {
+        Vec::new()
+    }
+}
+
+/// Span carrying the client details for a protocol request
+fn client_span(operation: &'static str, repo_name: &str, client: &ClientInfo) -> tracing::Span {
+    tracing::info_span!(
+        "git_request",
+        operation,
+        repo = %repo_name,
+        transport = client.transport.as_str(),
+        protocol_version = client.protocol_version,
+        agent = client.agent.as_deref().unwrap_or_default(),
+        user_agent = client.user_agent.as_deref().unwrap_or_default(),
+    )
+}
+
+/// Count the client in metrics and store a push/fetch event. Failing to store the
+/// event is logged rather than failing the request.
+pub async fn record_client_event(
+    state: &AppState,
+    repository_id: uuid::Uuid,
+    event_type: RepositoryEventType,
+    client: &ClientInfo,
+) {
+    state.client_metrics.record(event_type, client);
+    if let Err(e) = state
+        .event_service
+        .record(client.event(repository_id, event_type))
         .await
     {
-        Ok(user) => {
-            let response = UserResponse {
-                id: user.id.to_string(),
-                username: user.username,
-                email: user.email,
-                full_name: user.full_name,
-                is_active: user.is_active,
-                is_admin: user.is_admin,
-                created_at: user.created_at.to_string(),
-            };
-            Ok(HttpResponse::Created().json(response))
+        tracing::warn!("Failed to record {} event: {}", event_type.as_str(), e);
+    }
+}
+
+/// Store every object in a pushed pack, refusing it if its new objects add up to
+/// more than `allowance` bytes
+async fn unpack_objects(
+    state: &AppState,
+    guard: &WriteGuard,
+    protocol: &ProtocolHandler,
+    pack: &[u8],
+    allowance: Option<u64>,
+) -> anyhow::Result<()> {
+    let handler = protocol.object_handler();
+    let objects = protocol
+        .parse_pack(pack)?
+        .into_iter()
+        .map(|entry| handler.parse_object(entry.object_type, &entry.data))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    check_commit_dates(state, &handler, &objects)?;
+
+    state.repository_service.store_objects_within(guard, objects, allowance).await
+}
+
+/// Flag pushed commits dated beyond the allowed clock skew: logged, or refused
+/// when `reject_future_commits` is set
+fn check_commit_dates(state: &AppState, handler: &ObjectHandler, objects: &[GitObject]) -> anyhow::Result<()> {
+    let now = chrono::Utc::now();
+    let max_skew = chrono::TimeDelta::seconds(state.config.max_commit_date_skew_secs.try_into().unwrap_or(i64::MAX));
+    for object in objects.iter().filter(|o| o.obj_type == ObjectType::Commit) {
+        if !handler.parse_commit(&object.content)?.is_future_dated(now, max_skew) {
+            continue;
         }
-        Err(_) => Ok(HttpResponse::InternalServerError().json("Failed to create user")),
+        if state.config.reject_future_commits {
+            anyhow::bail!("commit {} is dated in the future", object.id);
+        }
+        tracing::warn!(commit = %object.id, "pushed commit is dated in the future");
     }
+    Ok(())
 }
 
-/// List all users
-#[get("/users")]
-pub async fn list_users(state: web::Data<AppState>) -> Result<HttpResponse> {
-    match state.user_service.list_users().await {
-        Ok(users) => {
-            let response: Vec<UserResponse> = users
-                .into_iter()
-                .map(|user| UserResponse {
-                    id: user.id.to_string(),
-                    username: user.username,
-                    email: user.email,
-                    full_name: user.full_name,
-                    is_active: user.is_active,
-                    is_admin: user.is_admin,
-                    created_at: user.created_at.to_string(),
-                })
-                .collect();
-            Ok(HttpResponse::Ok().json(response))
+/// Check one receive-pack command and turn it into the ref update to apply; a
+/// command on `HEAD` updates the branch HEAD points at
+async fn prepare_ref_command(
+    state: &AppState,
+    guard: &WriteGuard,
+    repository: &repository::Model,
+    command: &RefCommand,
+) -> std::result::Result<RefUpdate, String> {
+    let service = &state.repository_service;
+
+    let name = if command.ref_name == "HEAD" {
+        service
+            .get_head_target(guard.repository_id())
+            .await
+            .map_err(|e| e.to_string())?
+    } else {
+        command.ref_name.clone()
+    };
+
+    if !command.is_delete() && !service.object_exists(guard.repository_id(), &command.new_id).await.map_err(|e| e.to_string())? {
+        return Err("missing necessary objects".to_string());
+    }
+
+    let update = RefUpdate {
+        name,
+        old_target: (!command.is_create()).then(|| command.old_id.clone()),
+        new_target: (!command.is_delete()).then(|| command.new_id.clone()),
+    };
+    if let Some(new_target) = &update.new_target {
+        if repository.requires_linear_history(&update.name) {
+            GitOperations::new(service.as_ref().clone())
+                .check_linear_history(service.get_db(), guard.repository_id(), new_target, update.old_target.as_deref())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(update)
+}
+
+/// Report-status reason for a ref update that storage refused. A ref no longer at
+/// the old id the client sent was moved by a concurrent write, such as another push
+/// or a branch deleted through the API; fetching and pushing again resolves it.
+fn ref_update_failure(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<StorageError>() {
+        Some(StorageError::StaleRef { .. }) => "ref changed concurrently".to_string(),
+        _ => e.to_string(),
+    }
+}
+
+/// Apply the updates of an `atomic` push in one transaction, or none of them. A
+/// command that failed keeps its own reason; the rest report `ATOMIC_FAILURE`.
+async fn apply_ref_updates_atomically(
+    state: &AppState,
+    guard: &WriteGuard,
+    prepared: Vec<std::result::Result<RefUpdate, String>>,
+) -> Vec<std::result::Result<RefUpdate, String>> {
+    let mut failed_ref = None;
+    let mut failure = None;
+    if prepared.iter().all(Result::is_ok) {
+        let updates = prepared.iter().flatten().cloned().collect();
+        match state.repository_service.update_refs(guard, updates).await {
+            Ok(()) => return prepared,
+            Err(e) => {
+                failed_ref = match e.downcast_ref::<StorageError>() {
+                    Some(
+                        StorageError::StaleRef { name }
+                        | StorageError::SymbolicRef { name }
+                        | StorageError::RefNameConflict { name, .. },
+                    ) => Some(name.clone()),
+                    _ => None,
+                };
+                failure = Some(ref_update_failure(&e));
+            }
+        }
+    }
+
+    prepared
+        .into_iter()
+        .map(|result| match result {
+            Err(message) => Err(message),
+            Ok(update) if failed_ref.as_ref() == Some(&update.name) => {
+                Err(failure.clone().unwrap_or_else(|| ATOMIC_FAILURE.to_string()))
+            }
+            Ok(_) => Err(ATOMIC_FAILURE.to_string()),
+        })
+        .collect()
+}
+
+/// Value of the `Idempotency-Key` header, if set
+pub fn idempotency_key(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Replay the response stored for an earlier request with the same key
+pub fn replay_response(record: &idempotency_key::Model) -> HttpResponse {
+    let status = StatusCode::from_u16(record.response_status as u16).unwrap_or(StatusCode::OK);
+    HttpResponse::build(status)
+        .content_type("application/json")
+        .body(record.response_body.clone())
+}
+
+/// Remember a create response under its idempotency key. The resource already
+/// exists at this point, so failing to record only costs retry protection.
+pub async fn record_idempotent_response<T: Serialize>(
+    state: &AppState,
+    scope: &str,
+    key: &str,
+    resource_id: String,
+    status: StatusCode,
+    response: &T,
+) {
+    let body = match serde_json::to_string(response) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::warn!("Failed to serialize response for idempotency key: {}", e);
+            return;
         }
+    };
+
+    if let Err(e) = state
+        .idempotency_service
+        .record(scope, key, resource_id, status.as_u16(), body)
+        .await
+    {
+        tracing::warn!("Failed to record idempotency key: {}", e);
+    }
+}
+
+/// Server version, protocol support and clone endpoints
+#[get("/meta")]
+pub async fn server_meta(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let config = &state.config;
+    let response = ServerMetaResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        external_url: config.external_url.clone(),
+        protocol: ProtocolSupport {
+            // Only v0 is spoken, whatever `enable_v2` says
+            v2: false,
+            lfs: false,
+            shallow: config.protocol.allow_shallow,
+            filter: config.protocol.allow_filters,
+            delete_refs: config.protocol.allow_deletes,
+            upload_pack_capabilities: config.protocol.upload_pack_capabilities(),
+            receive_pack_capabilities: config.protocol.receive_pack_capabilities(),
+        },
+        ssh: SshMeta {
+            host: config.external_host(),
+            port: config.ssh_port(),
+            host_key_fingerprints: state.ssh_host_key_fingerprints.clone(),
+        },
+        token_scopes: Scope::ALL.iter().map(|scope| scope.as_str().to_string()).collect(),
+    };
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Write lock contention metrics for the storage layer
+#[get("/metrics/storage")]
+pub async fn storage_metrics(
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+    Ok(HttpResponse::Ok().json(state.repository_service.write_metrics()))
+}
+
+/// Protocol request counts per client agent
+#[get("/metrics/clients")]
+pub async fn client_metrics(
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+    Ok(HttpResponse::Ok().json(state.client_metrics.snapshot()))
+}
+
+/// Last pruning run of the reflog and events tables, `null` before the first run
+#[get("/metrics/retention")]
+pub async fn retention_metrics(
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+    Ok(HttpResponse::Ok().json(state.retention_service.last_report()))
+}
+
+/// Reads stopped by the history and diff guardrails, per guardrail
+#[get("/metrics/guardrails")]
+pub async fn guardrail_metrics(
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+    Ok(HttpResponse::Ok().json(state.guardrail_metrics.snapshot()))
+}
+
+/// Fetch packs served from the pack cache versus built
+#[get("/metrics/pack-cache")]
+pub async fn pack_cache_metrics(
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+    Ok(HttpResponse::Ok().json(state.pack_cache.snapshot()))
+}
+
+/// Map storage failures during git operations, telling clients to retry when the store is busy
+fn storage_error_response(err: &anyhow::Error) -> HttpResponse {
+    match err.downcast_ref::<StorageError>() {
+        Some(StorageError::ResourceBusy) => HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", "1"))
+            .content_type("text/plain")
+            .body("resource busy, retry\n"),
+        Some(StorageError::RepositoryNotFound { .. }) => HttpResponse::NotFound().json("Repository not found"),
+        _ => HttpResponse::InternalServerError().json("Database error"),
+    }
+}
+
+/// Username of a repository's owner, for its clone URLs. The ID stands in for an
+/// owner whose account is gone.
+pub(crate) async fn owner_username(state: &AppState, repo: &repository::Model) -> anyhow::Result<String> {
+    let owner = state.user_service.get_user_by_id(repo.owner_id).await?;
+    Ok(owner.map_or_else(|| repo.owner_id.to_string(), |owner| owner.username))
+}
+
+/// Build list responses, loading the topics of all repositories in one query
+/// and each requested extra in a couple more. `reader` is the caller's account.
+async fn repository_responses(
+    state: &AppState,
+    repos: Vec<repository::Model>,
+    includes: ListIncludes,
+    reader: Option<&user::Model>,
+    caller: &Caller,
+) -> anyhow::Result<Vec<RepositoryResponse>> {
+    let ids: Vec<uuid::Uuid> = repos.iter().map(|repo| repo.id).collect();
+    let mut topics = state.repository_service.get_topics_for_repositories(&ids).await?;
+    let owner_ids: Vec<uuid::Uuid> = repos.iter().map(|repo| repo.owner_id).collect();
+    let owners = state.user_service.usernames_by_ids(&owner_ids).await?;
+    let mut counts = if includes.counts {
+        Some(state.repository_service.ref_counts_for_repositories(&ids).await?)
+    } else {
+        None
+    };
+    let mut tips = if includes.tip {
+        Some(state.repository_service.default_branch_tips(&repos).await?)
+    } else {
+        None
+    };
+
+    Ok(repos
+        .into_iter()
+        .map(|repo| {
+            let id = repo.id;
+            let updated_at = repo.updated_at;
+            let permissions = includes.permissions.then(|| Permissions::effective(&repo, reader, caller));
+            let repo_topics = topics.remove(&id).unwrap_or_default();
+            let owner = owners.get(&repo.owner_id).cloned().unwrap_or_else(|| repo.owner_id.to_string());
+            let mut response = RepositoryResponse::from_model(repo, &owner, repo_topics, &state.config);
+            if let Some(counts) = counts.as_mut() {
+                let repo_counts = counts.remove(&id).unwrap_or_default();
+                response.branch_count = Some(repo_counts.branches);
+                response.tag_count = Some(repo_counts.tags);
+                response.last_activity = Some(
+                    repo_counts
+                        .last_ref_change
+                        .map(|at| at.fixed_offset())
+                        .unwrap_or(updated_at)
+                        .to_string(),
+                );
+            }
+            if let Some(tips) = tips.as_mut() {
+                response.tip = tips.remove(&id);
+            }
+            response.permissions = permissions;
+            response
+        })
+        .collect())
+}
+
+/// The signed-in caller, `None` when anonymous
+pub(crate) async fn caller_user(state: &AppState, caller: &Caller) -> anyhow::Result<Option<user::Model>> {
+    match caller.user_id() {
+        Some(user_id) => state.user_service.get_user_by_id(user_id).await,
+        None => Ok(None),
+    }
+}
+
+/// Whether `user_id` (`None` when anonymous) may read `repo` and its objects: the
+/// one check behind every path that serves repository content, over git or the
+/// API. Public repositories skip the user lookup.
+pub(crate) async fn can_read(
+    state: &AppState,
+    user_id: Option<uuid::Uuid>,
+    repo: &repository::Model,
+) -> anyhow::Result<bool> {
+    if repo.visibility == Visibility::Public {
+        return Ok(true);
+    }
+    let user = match user_id {
+        Some(user_id) => state.user_service.get_user_by_id(user_id).await?,
+        None => None,
+    };
+    Ok(repo.readable_by(user.as_ref()))
+}
+
+/// Apply an `archived` list filter
+fn retain_archived(mut repos: Vec<repository::Model>, archived: Option<bool>) -> Vec<repository::Model> {
+    if let Some(archived) = archived {
+        repos.retain(|repo| repo.is_archived == archived);
+    }
+    repos
+}
+
+/// List the repositories the caller can see, optionally filtered by `?topic=` and
+/// `?archived=`, with `?include=counts,tip,permissions` extras
+#[get("/repositories")]
+pub async fn list_repositories(
+    query: web::Query<RepositoryListQuery>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (archived, includes) = match query.archived().and_then(|archived| Ok((archived, query.includes()?))) {
+        Ok(filters) => filters,
+        Err(message) => return Ok(HttpResponse::BadRequest().json(message)),
+    };
+    let reader = match caller_user(&state, &caller).await {
+        Ok(reader) => reader,
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+    let repos = match state
+        .repository_service
+        .list_visible_repositories(reader.as_ref(), None, query.topic.as_deref())
+        .await
+    {
+        Ok(repos) => retain_archived(repos, archived),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+
+    let response = repository_responses(&state, repos, includes, reader.as_ref(), &caller).await;
+    match response {
+        Ok(response) => Ok(HttpResponse::Ok().json(response)),
         Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
     }
 }
 
-/// Get a specific user by username
-#[get("/users/{username}")]
-pub async fn get_user(
+/// Get a specific repository; ones the caller can't read are reported as missing
+#[get("/repositories/{name}")]
+pub async fn get_repository(
     path: web::Path<String>,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let username = path.into_inner();
+    let repo_name = path.into_inner();
     
-    match state.user_service.get_user_by_username(&username).await {
-        Ok(Some(user)) => {
-            let response = UserResponse {
-                id: user.id.to_string(),
-                username: user.username,
-                email: user.email,
-                full_name: user.full_name,
-                is_active: user.is_active,
-                is_admin: user.is_admin,
-                created_at: user.created_at.to_string(),
+    match state.repository_service.get_repository_by_name(&repo_name).await {
+        Ok(Some(repo)) => {
+            let reader = match caller_user(&state, &caller).await {
+                Ok(reader) => reader,
+                Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
             };
-            Ok(HttpResponse::Ok().json(response))
+            if !repo.readable_by(reader.as_ref()) {
+                return Ok(HttpResponse::NotFound().json("Repository not found"));
+            }
+            let topics = match state.repository_service.get_topics(repo.id).await {
+                Ok(topics) => topics,
+                Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+            };
+            let owner = match owner_username(&state, &repo).await {
+                Ok(owner) => owner,
+                Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+            };
+            let permissions = Permissions::effective(&repo, reader.as_ref(), &caller);
+            let mut response = RepositoryResponse::from_model(repo, &owner, topics, &state.config);
+            response.permissions = Some(permissions);
+            Ok(HttpResponse::Ok()
+                .insert_header((header::ETAG, version_etag(response.version)))
+                .json(response))
         }
-        Ok(None) => Ok(HttpResponse::NotFound().json("User not found")),
+        Ok(None) => Ok(HttpResponse::NotFound().json("Repository not found")),
         Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
     }
 }
 
-/// Get repositories by user
-#[get("/users/{username}/repositories")]
-pub async fn get_user_repositories(
+/// The visibility a create or update asks for, reading the older `is_private`
+/// flag when `visibility` isn't sent
+fn requested_visibility(visibility: Option<Visibility>, is_private: Option<bool>) -> Option<Visibility> {
+    visibility.or(is_private.map(Visibility::from_is_private))
+}
+
+fn version_etag(version: i32) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Version from `If-Match: "3"` (as sent back from the ETag), if present
+fn if_match_version(req: &HttpRequest) -> Option<i32> {
+    let value = req.headers().get(header::IF_MATCH)?.to_str().ok()?;
+    value.trim().trim_start_matches("W/").trim_matches('"').parse().ok()
+}
+
+/// Update repository settings. The client must send the version it read, and
+/// gets 409 with the current settings if someone else changed them since.
+#[patch("/repositories/{name}")]
+pub async fn update_repository(
+    http_req: HttpRequest,
     path: web::Path<String>,
+    body: ApiJson<UpdateRepositoryRequest>,
+    _: AuthenticatedUser,
+    caller: Caller,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let username = path.into_inner();
-    
-    // Get user first
-    let user = match state.user_service.get_user_by_username(&username).await {
-        Ok(Some(user)) => user,
-        Ok(None) => return Ok(HttpResponse::NotFound().json("User not found")),
+    let repo_name = path.into_inner();
+    let req = body.into_inner();
+
+    let Some(expected_version) = req.version.or_else(|| if_match_version(&http_req)) else {
+        return Ok(HttpResponse::build(StatusCode::PRECONDITION_REQUIRED)
+            .json("Send the repository version as If-Match or in the body"));
+    };
+
+    // Repositories the caller can't read are reported as missing
+    let repo = match state.repository_service.get_repository_by_name(&repo_name).await {
+        Ok(Some(repo)) if repo.readable_by(caller.user()) => repo,
+        Ok(_) => return Ok(HttpResponse::NotFound().json("Repository not found")),
         Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
     };
-    
-    // Get user's repositories
-    match state.repository_service.list_repositories_by_owner(user.id).await {
-        Ok(repos) => {
-            let response: Vec<RepositoryResponse> = repos
-                .into_iter()
-                .map(|repo| RepositoryResponse {
-                    id: repo.id.to_string(),
-                    name: repo.name,
-                    description: repo.description,
-                    default_branch: repo.default_branch,
-                    owner_id: repo.owner_id.to_string(),
-                    is_private: repo.is_private,
-                    created_at: repo.created_at.to_string(),
-                })
-                .collect();
-            Ok(HttpResponse::Ok().json(response))
-        }
-        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    if !repo.administered_by(caller.user()) {
+        return Ok(HttpResponse::Forbidden().json("Only the repository owner or an admin can change its settings"));
+    }
+    // The only change an archived repository takes is being unarchived
+    if repo.is_archived && req.is_archived != Some(false) {
+        return Ok(HttpResponse::Forbidden().json(ARCHIVED_MESSAGE));
     }
-}
\ No newline at end of file
+
+    let hidden_ref_prefixes = match req.hidden_refs {
+        Some(prefixes) => match prefixes
+            .iter()
+            .map(|p| HiddenRefs::normalize_prefix(p))
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(prefixes) => Some(prefixes),
+            None => return Ok(HttpResponse::BadRequest().json("Hidden ref prefixes must start with refs/")),
+        },
+        None => None,
+    };
+
+    let linear_history_branches = match req.linear_history_branches {
+        Some(branches) => match branches
+            .iter()
+            .map(|branch| {
+                let branch = branch.strip_prefix("refs/heads/").unwrap_or(branch);
+                (!branch.is_empty() && !branch.contains(char::is_whitespace)).then(|| branch.to_string())
+            })
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(branches) => Some(branches),
+            None => return Ok(HttpResponse::BadRequest().json("Linear history branches must be branch names")),
+        },
+        None => None,
+    };
+
+    let update = RepositoryUpdate {
+        description: req.description,
+        visibility: requested_visibility(req.visibility, req.is_private),
+        hidden_ref_prefixes,
+        is_archived: req.is_archived,
+        push_message: req.push_message,
+        linear_history_branches,
+        allow_anonymous_clone: req.allow_anonymous_clone,
+    };
+    let result = state
+        .repository_service
+        .update_repository(repo.id, expected_version, update)
+        .await;
+
+    let (repo, conflict) = match result {
+        Ok(repo) => (repo, None),
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::VersionConflict { .. }) => {
+                match state.repository_service.get_repository_by_id(repo.id).await {
+                    Ok(Some(current)) => (current, Some(e.to_string())),
+                    Ok(None) => return Ok(HttpResponse::NotFound().json("Repository not found")),
+                    Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+                }
+            }
+            _ => return Ok(storage_error_response(&e)),
+        },
+    };
+
+    let topics = match state.repository_service.get_topics(repo.id).await {
+        Ok(topics) => topics,
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+    let owner = match owner_username(&state, &repo).await {
+        Ok(owner) => owner,
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+    let response = RepositoryResponse::from_model(repo, &owner, topics, &state.config);
+    let etag = version_etag(response.version);
+    Ok(match conflict {
+        None => HttpResponse::Ok().insert_header((header::ETAG, etag)).json(response),
+        Some(message) => HttpResponse::Conflict()
+            .insert_header((header::ETAG, etag))
+            .json(RepositoryConflictResponse { message, current: response }),
+    })
+}
+
+/// Replace the topics of a repository (owner or admin only)
+#[put("/repositories/{name}/topics")]
+pub async fn set_repository_topics(
+    path: web::Path<String>,
+    body: ApiJson<SetTopicsRequest>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let repo_name = path.into_inner();
+
+    // Repositories the caller can't read are reported as missing
+    let repo = match state.repository_service.get_repository_by_name(&repo_name).await {
+        Ok(Some(repo)) if repo.readable_by(caller.user()) => repo,
+        Ok(_) => return Ok(HttpResponse::NotFound().json("Repository not found")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+    if !repo.administered_by(caller.user()) {
+        return Ok(HttpResponse::Forbidden().json("Only the repository owner or an admin can set its topics"));
+    }
+    if repo.is_archived {
+        return Ok(HttpResponse::Forbidden().json(ARCHIVED_MESSAGE));
+    }
+
+    let topics = match state
+        .repository_service
+        .set_topics(repo.id, body.into_inner().topics)
+        .await
+    {
+        Ok(topics) => topics,
+        Err(e) => {
+            return Ok(match e.downcast_ref::<StorageError>() {
+                Some(StorageError::InvalidTopic { .. } | StorageError::TooManyTopics { .. }) => {
+                    HttpResponse::BadRequest().json(e.to_string())
+                }
+                _ => HttpResponse::InternalServerError().json("Database error"),
+            })
+        }
+    };
+
+    let event = NewRepositoryEvent::api(repo.id, RepositoryEventType::TopicsUpdated, caller.user_id(), &topics);
+    if let Err(e) = state.event_service.record(event).await {
+        tracing::warn!("Failed to record topics_updated event: {}", e);
+    }
+
+    let owner = match owner_username(&state, &repo).await {
+        Ok(owner) => owner,
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+    Ok(HttpResponse::Ok().json(RepositoryResponse::from_model(repo, &owner, topics, &state.config)))
+}
+
+/// Create a new repository
+#[post("/repositories")]
+pub async fn create_repository(
+    http_req: HttpRequest,
+    body: ApiJson<CreateRepositoryRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let req = body.into_inner();
+
+    // Parse owner_id if provided, otherwise use a default admin user (for demo)
+    let owner_id = if let Some(owner_id_str) = req.owner_id {
+        match uuid::Uuid::parse_str(&owner_id_str) {
+            Ok(id) => id,
+            Err(_) => return Ok(HttpResponse::BadRequest().json("Invalid owner_id format")),
+        }
+    } else {
+        // For demo purposes, create a default admin user if none exists
+        // In production, you'd want proper authentication
+        match state.user_service.get_user_by_username("admin").await {
+            Ok(Some(user)) => user.id,
+            Ok(None) => {
+                // Create default admin user
+                match state
+                    .user_service
+                    .create_user(
+                        "admin".to_string(),
+                        "admin@example.com".to_string(),
+                        "password_hash".to_string(), // In production, use proper password hashing
+                        Some("Administrator".to_string()),
+                        true,
+                    )
+                    .await
+                {
+                    Ok(admin_user) => admin_user.id,
+                    Err(_) => return Ok(HttpResponse::InternalServerError().json("Failed to create default admin user")),
+                }
+            }
+            Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+        }
+    };
+
+    // A retried request with the same key gets the original response; keys are per owner
+    let idempotency_key = idempotency_key(&http_req);
+    let idempotency_scope = format!("create_repository:{}", owner_id);
+    if let Some(key) = &idempotency_key {
+        match state.idempotency_service.get(&idempotency_scope, key).await {
+            Ok(Some(record)) => return Ok(replay_response(&record)),
+            Ok(None) => {}
+            Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+        }
+    }
+
+    match quota::owner_quota(&state, owner_id).await {
+        Ok((quota, usage)) => {
+            if let Err(hit) = quota.check_new_repository(&usage) {
+                return Ok(hit.response());
+            }
+        }
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+    
+    match state
+        .repository_service
+        .create_repository(
+            req.name,
+            req.description,
+            "main".to_string(),
+            owner_id,
+            requested_visibility(req.visibility, req.is_private).unwrap_or_default(),
+            req.object_format.map(|format| format.hash_algorithm()),
+        )
+        .await
+    {
+        Ok(repo) => {
+            let owner = match owner_username(&state, &repo).await {
+                Ok(owner) => owner,
+                Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+            };
+            let response = RepositoryResponse::from_model(repo, &owner, Vec::new(), &state.config);
+            if let Some(key) = &idempotency_key {
+                record_idempotent_response(
+                    &state,
+                    &idempotency_scope,
+                    key,
+                    response.id.clone(),
+                    StatusCode::CREATED,
+                    &response,
+                )
+                .await;
+            }
+            Ok(HttpResponse::Created().json(response))
+        }
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Failed to create repository")),
+    }
+}
+
+/// A soft-deleted repository, restorable until `purge_after`
+#[derive(Serialize, Deserialize)]
+pub struct DeletedRepositoryResponse {
+    pub id: String,
+    /// The name it had, and gets back when restored
+    pub name: String,
+    pub owner_id: String,
+    pub deleted_at: String,
+    pub purge_after: String,
+}
+
+impl DeletedRepositoryResponse {
+    fn from_model(repo: repository::Model, config: &Config) -> Self {
+        let deleted_at = repo.deleted_at.map(|at| at.with_timezone(&chrono::Utc)).unwrap_or_default();
+        Self {
+            id: repo.id.to_string(),
+            name: repo.deleted_name.unwrap_or(repo.name),
+            owner_id: repo.owner_id.to_string(),
+            deleted_at: deleted_at.to_rfc3339(),
+            purge_after: config.purge_after(deleted_at).to_rfc3339(),
+        }
+    }
+}
+
+/// Soft-delete a repository (owner or admin only). It disappears from listings
+/// and git URLs at once, keeps its data until purged after the retention period,
+/// and its name can be reused meanwhile.
+#[delete("/repositories/{repo_id}")]
+pub async fn delete_repository(
+    path: web::Path<String>,
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Ok(repo_id) = uuid::Uuid::parse_str(&path) else {
+        return Ok(HttpResponse::BadRequest().json("Invalid repository ID"));
+    };
+    let repo = match state.repository_service.get_repository_by_id(repo_id).await {
+        Ok(Some(repo)) => repo,
+        Ok(None) => return Ok(HttpResponse::NotFound().json("Repository not found")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+
+    if user_id != repo.owner_id {
+        match state.user_service.get_user_by_id(user_id).await {
+            Ok(Some(user)) if user.is_admin => {}
+            Ok(_) => return Ok(HttpResponse::Forbidden().json("Only the repository owner or an admin can delete it")),
+            Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+        }
+    }
+
+    match state.repository_service.soft_delete_repository(repo.id).await {
+        Ok(deleted) => Ok(HttpResponse::Ok().json(DeletedRepositoryResponse::from_model(deleted, &state.config))),
+        Err(e) => Ok(storage_error_response(&e)),
+    }
+}
+
+/// Soft-deleted repositories awaiting purge, oldest deletion first (admins only)
+#[get("/admin/repositories/deleted")]
+pub async fn list_deleted_repositories(
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+
+    match state.repository_service.list_deleted_repositories().await {
+        Ok(repos) => Ok(HttpResponse::Ok().json(
+            repos
+                .into_iter()
+                .map(|repo| DeletedRepositoryResponse::from_model(repo, &state.config))
+                .collect::<Vec<_>>(),
+        )),
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+}
+
+/// Restore a soft-deleted repository under its old name (admins only); 409 when
+/// a repository created since has taken the name
+#[post("/admin/repositories/{repo_id}/restore")]
+pub async fn restore_repository(
+    path: web::Path<String>,
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+    let Ok(repo_id) = uuid::Uuid::parse_str(&path) else {
+        return Ok(HttpResponse::BadRequest().json("Invalid repository ID"));
+    };
+
+    let repo = match state.repository_service.restore_repository(repo_id).await {
+        Ok(repo) => repo,
+        Err(e) => {
+            return Ok(match e.downcast_ref::<StorageError>() {
+                Some(StorageError::RepositoryNameTaken { .. }) => HttpResponse::Conflict().json(e.to_string()),
+                _ => storage_error_response(&e),
+            })
+        }
+    };
+    let topics = match state.repository_service.get_topics(repo.id).await {
+        Ok(topics) => topics,
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+    let owner = match owner_username(&state, &repo).await {
+        Ok(owner) => owner,
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+    Ok(HttpResponse::Ok().json(RepositoryResponse::from_model(repo, &owner, topics, &state.config)))
+}
+
+// User Management API Endpoints
+
+/// Create a new user
+#[post("/users")]
+pub async fn create_user(
+    body: ApiJson<CreateUserRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let req = body.into_inner();
+    
+    // Check if username or email already exists
+    if let Ok(true) = state.user_service.username_exists(&req.username).await {
+        return Ok(HttpResponse::Conflict().json("Username already exists"));
+    }
+    
+    if let Ok(true) = state.user_service.email_exists(&req.email).await {
+        return Ok(HttpResponse::Conflict().json("Email already exists"));
+    }
+    
+    // In production, hash the password properly
+    let password_hash = match state.user_service.hash_password(&req.password) {
+        Ok(hash) => hash,
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Failed to process password")),
+    };
+    
+    match state
+        .user_service
+        .create_user(
+            req.username,
+            req.email,
+            password_hash,
+            req.full_name,
+            req.is_admin.unwrap_or(false),
+        )
+        .await
+    {
+        Ok(user) => {
+            let response = UserResponse {
+                id: user.id.to_string(),
+                username: user.username,
+                email: user.email,
+                full_name: user.full_name,
+                is_active: user.is_active,
+                is_admin: user.is_admin,
+                created_at: user.created_at.to_string(),
+            };
+            Ok(HttpResponse::Created().json(response))
+        }
+        Err(e) => match e.downcast_ref::<StorageError>() {
+            Some(StorageError::InvalidEmail { .. }) => Ok(HttpResponse::BadRequest().json(e.to_string())),
+            Some(StorageError::EmailTaken { .. }) => Ok(HttpResponse::Conflict().json("Email already exists")),
+            _ => Ok(HttpResponse::InternalServerError().json("Failed to create user")),
+        },
+    }
+}
+
+/// The email others see for `user`: a noreply address if they hide theirs
+fn shown_email(user: &user::Model, config: &Config) -> String {
+    if user.hide_email {
+        noreply_email(user, &config.external_host())
+    } else {
+        user.email.clone()
+    }
+}
+
+/// List all users
+#[get("/users")]
+pub async fn list_users(state: web::Data<AppState>) -> Result<HttpResponse> {
+    match state.user_service.list_users().await {
+        Ok(users) => {
+            let response: Vec<UserResponse> = users
+                .into_iter()
+                .map(|user| UserResponse {
+                    id: user.id.to_string(),
+                    email: shown_email(&user, &state.config),
+                    username: user.username,
+                    full_name: user.full_name,
+                    is_active: user.is_active,
+                    is_admin: user.is_admin,
+                    created_at: user.created_at.to_string(),
+                })
+                .collect();
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+}
+
+/// Get a specific user by username
+#[get("/users/{username}")]
+pub async fn get_user(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let username = path.into_inner();
+    
+    match state.user_service.get_user_by_username(&username).await {
+        Ok(Some(user)) => {
+            let response = UserResponse {
+                id: user.id.to_string(),
+                email: shown_email(&user, &state.config),
+                username: user.username,
+                full_name: user.full_name,
+                is_active: user.is_active,
+                is_admin: user.is_admin,
+                created_at: user.created_at.to_string(),
+            };
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json("User not found")),
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+}
+
+/// Get repositories by user, optionally filtered by `?topic=` and `?archived=`
+#[get("/users/{username}/repositories")]
+pub async fn get_user_repositories(
+    path: web::Path<String>,
+    query: web::Query<RepositoryListQuery>,
+    caller: Caller,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let username = path.into_inner();
+    let (archived, includes) = match query.archived().and_then(|archived| Ok((archived, query.includes()?))) {
+        Ok(filters) => filters,
+        Err(message) => return Ok(HttpResponse::BadRequest().json(message)),
+    };
+    
+    // Get user first
+    let user = match state.user_service.get_user_by_username(&username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(HttpResponse::NotFound().json("User not found")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+    
+    let reader = match caller_user(&state, &caller).await {
+        Ok(reader) => reader,
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+
+    // Get the user's repositories the caller can read
+    let repos = match state
+        .repository_service
+        .list_visible_repositories(reader.as_ref(), Some(user.id), query.topic.as_deref())
+        .await
+    {
+        Ok(repos) => retain_archived(repos, archived),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+
+    let response = repository_responses(&state, repos, includes, reader.as_ref(), &caller).await;
+    match response {
+        Ok(response) => Ok(HttpResponse::Ok().json(response)),
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+}
+
+/// The caller's quota and what their repositories take up
+#[get("/users/me/quota")]
+pub async fn get_my_quota(
+    AuthenticatedUser(user_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match quota::owner_quota(&state, user_id).await {
+        Ok((quota, usage)) => Ok(HttpResponse::Ok().json(QuotaReport::new(quota, usage))),
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+}
+
+/// A user's quota overrides. `null` uses the server setting and `0` lifts the
+/// limit for this user.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QuotaSettings {
+    pub max_repositories: Option<i32>,
+    pub max_total_bytes: Option<i64>,
+}
+
+/// Replace a user's quota overrides
+#[put("/admin/users/{user_id}/quota")]
+pub async fn set_user_quota(
+    path: web::Path<String>,
+    body: ApiJson<QuotaSettings>,
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+
+    let user_id = match uuid::Uuid::parse_str(&path) {
+        Ok(id) => id,
+        Err(_) => return Ok(HttpResponse::BadRequest().json("Invalid user ID")),
+    };
+
+    let settings = body.into_inner();
+    if settings.max_repositories.is_some_and(|v| v < 0) {
+        return Ok(HttpResponse::BadRequest().json("max_repositories must not be negative"));
+    }
+    if settings.max_total_bytes.is_some_and(|v| v < 0) {
+        return Ok(HttpResponse::BadRequest().json("max_total_bytes must not be negative"));
+    }
+
+    match state
+        .user_service
+        .set_quota(user_id, settings.max_repositories, settings.max_total_bytes)
+        .await
+    {
+        Ok(Some(user)) => Ok(HttpResponse::Ok().json(QuotaSettings {
+            max_repositories: user.max_repositories,
+            max_total_bytes: user.max_total_bytes,
+        })),
+        Ok(None) => Ok(HttpResponse::NotFound().json("User not found")),
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+}
+
+/// Register every key in an `authorized_keys` file, sent as the request body, for
+/// a user. Each line is reported on its own; bad lines don't stop the rest.
+#[post("/users/{username}/keys:import")]
+pub async fn import_ssh_keys(
+    path: web::Path<String>,
+    body: String,
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+
+    let user = match state.user_service.get_user_by_username(&path).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(HttpResponse::NotFound().json("User not found")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+
+    let mut report = KeyImportReport::default();
+    for (index, line) in body.lines().enumerate() {
+        let key = match authorized_keys::parse_line(line, &state.config.ssh_key_types) {
+            Ok(Some(key)) => key,
+            Ok(None) => continue,
+            Err(message) => {
+                report.push(KeyImportLine {
+                    line: index + 1,
+                    status: KeyImportStatus::Failed,
+                    fingerprint: None,
+                    comment: None,
+                    message: Some(message),
+                });
+                continue;
+            }
+        };
+
+        let (fingerprint, comment) = (key.fingerprint.clone(), key.comment.clone());
+        let (status, message) = match state.ssh_key_service.add_key(user.id, key).await {
+            Ok(Some(_)) => (KeyImportStatus::Imported, None),
+            Ok(None) => (KeyImportStatus::Duplicate, None),
+            Err(e) => match e.downcast_ref::<StorageError>() {
+                Some(StorageError::SshKeyInUse { .. }) => (KeyImportStatus::Failed, Some(e.to_string())),
+                _ => return Ok(HttpResponse::InternalServerError().json("Database error")),
+            },
+        };
+        report.push(KeyImportLine {
+            line: index + 1,
+            status,
+            fingerprint: Some(fingerprint),
+            comment,
+            message,
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// A registered SSH key as listed; the key data itself is left out, the
+/// fingerprint being what people compare
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SshKeyResponse {
+    pub id: uuid::Uuid,
+    /// The key's comment, usually naming the machine it lives on
+    pub title: Option<String>,
+    pub key_type: String,
+    /// `SHA256:<base64>`, as `ssh-keygen -l` prints it
+    pub fingerprint: String,
+    pub created_at: String,
+}
+
+/// The user named `username` when `caller_id` is them or an admin, otherwise
+/// the response to send
+async fn own_or_admin_user(
+    state: &AppState,
+    username: &str,
+    caller_id: uuid::Uuid,
+) -> std::result::Result<user::Model, HttpResponse> {
+    let user = match state.user_service.get_user_by_username(username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(HttpResponse::NotFound().json("User not found")),
+        Err(_) => return Err(HttpResponse::InternalServerError().json("Database error")),
+    };
+    if user.id != caller_id {
+        match state.user_service.get_user_by_id(caller_id).await {
+            Ok(Some(caller)) if caller.is_admin => {}
+            Ok(_) => return Err(HttpResponse::Forbidden().json("Users may only manage their own keys")),
+            Err(_) => return Err(HttpResponse::InternalServerError().json("Database error")),
+        }
+    }
+    Ok(user)
+}
+
+/// A user's registered SSH keys, oldest first (the user themselves or an admin)
+#[get("/users/{username}/keys")]
+pub async fn list_ssh_keys(
+    path: web::Path<String>,
+    AuthenticatedUser(caller_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user = match own_or_admin_user(&state, &path, caller_id).await {
+        Ok(user) => user,
+        Err(response) => return Ok(response),
+    };
+    match state.ssh_key_service.list_keys(user.id).await {
+        Ok(keys) => Ok(HttpResponse::Ok().json(
+            keys.into_iter()
+                .map(|key| SshKeyResponse {
+                    id: key.id,
+                    title: key.comment,
+                    key_type: key.key_type,
+                    fingerprint: key.fingerprint,
+                    created_at: key.created_at.to_string(),
+                })
+                .collect::<Vec<_>>(),
+        )),
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+}
+
+/// Unregister one of a user's SSH keys (the user themselves or an admin)
+#[delete("/users/{username}/keys/{key_id}")]
+pub async fn delete_ssh_key(
+    path: web::Path<(String, String)>,
+    AuthenticatedUser(caller_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (username, key_id) = path.into_inner();
+    let Ok(key_id) = uuid::Uuid::parse_str(&key_id) else {
+        return Ok(HttpResponse::NotFound().json("Key not found"));
+    };
+    let user = match own_or_admin_user(&state, &username, caller_id).await {
+        Ok(user) => user,
+        Err(response) => return Ok(response),
+    };
+    match state.ssh_key_service.delete_key(user.id, key_id).await {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(HttpResponse::NotFound().json("Key not found")),
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+}
+
+/// `?all_or_nothing=true` creates no user unless every row can be created
+#[derive(Debug, Deserialize)]
+pub struct ImportUsersQuery {
+    #[serde(default)]
+    pub all_or_nothing: bool,
+}
+
+/// Create the users in a JSON array in one go, for migrating from another server.
+/// Each row is reported on its own; a bad row doesn't stop the rest unless the
+/// import is all-or-nothing.
+#[post("/users:import")]
+pub async fn import_users(
+    body: ApiJson<Vec<ImportedUser>>,
+    query: web::Query<ImportUsersQuery>,
+    AuthenticatedUser(admin_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.user_service.get_user_by_id(admin_id).await {
+        Ok(Some(user)) if user.is_admin => {}
+        Ok(_) => return Ok(HttpResponse::Forbidden().json("Admin access required")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+
+    match state.user_service.import_users(body.into_inner(), query.all_or_nothing).await {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(_) => Ok(HttpResponse::InternalServerError().json("Database error")),
+    }
+}
+
+/// `?s=` is the size wanted in pixels; `?v=` the avatar version the URL was made
+/// for, which lets the response be cached for good when it's current
+#[derive(Debug, Deserialize)]
+pub struct AvatarQuery {
+    pub s: Option<u32>,
+    pub v: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AvatarResponse {
+    pub avatar_url: String,
+    pub avatar_version: i32,
+}
+
+/// A user's avatar as a PNG at the stored size nearest `?s=`: their upload, or
+/// else an identicon made from their username
+#[get("/users/{username}/avatar")]
+pub async fn get_user_avatar(
+    path: web::Path<String>,
+    query: web::Query<AvatarQuery>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user = match state.user_service.get_user_by_username(&path).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(HttpResponse::NotFound().json("User not found")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+
+    let size = avatars::canonical_size(query.s.unwrap_or(avatars::DEFAULT_AVATAR_SIZE));
+    let etag = format!("\"{}-{}\"", user.avatar_version, size);
+    // Versioned URLs change with every upload, so they never need revalidating
+    let cache_control = if query.v == Some(user.avatar_version) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "public, max-age=300"
+    };
+    let not_modified = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim().trim_start_matches("W/") == etag));
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, cache_control))
+            .finish());
+    }
+
+    let uploaded = match &user.avatar_blob_key {
+        Some(key) => match AvatarStore::new(state.repository_service.blob_storage_path()).read(key, size) {
+            Ok(png) => png,
+            Err(_) => return Ok(HttpResponse::InternalServerError().json("Storage error")),
+        },
+        None => None,
+    };
+    let png = uploaded.unwrap_or_else(|| avatars::identicon(&user.username, size));
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, cache_control))
+        .body(png))
+}
+
+/// Replace a user's avatar with the PNG or JPEG in the request body. Users may
+/// set their own; admins anyone's.
+#[put("/users/{username}/avatar")]
+pub async fn set_user_avatar(
+    path: web::Path<String>,
+    req: HttpRequest,
+    body: web::Payload,
+    AuthenticatedUser(caller_id): AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user = match state.user_service.get_user_by_username(&path).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(HttpResponse::NotFound().json("User not found")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+    };
+    if user.id != caller_id {
+        match state.user_service.get_user_by_id(caller_id).await {
+            Ok(Some(caller)) if caller.is_admin => {}
+            Ok(_) => return Ok(HttpResponse::Forbidden().json("Users may only change their own avatar")),
+            Err(_) => return Ok(HttpResponse::InternalServerError().json("Database error")),
+        }
+    }
+
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+    if !matches!(content_type.as_deref(), Some("image/png" | "image/jpeg")) {
+        let error = avatars::AvatarError::UnsupportedType;
+        return Ok(HttpResponse::build(error.status()).json(error.to_string()));
+    }
+
+    let data = match body.to_bytes_limited(avatars::MAX_AVATAR_UPLOAD_BYTES).await {
+        Ok(Ok(data)) => data,
+        Ok(Err(_)) => return Ok(HttpResponse::BadRequest().json("Could not read request body")),
+        Err(_) => {
+            let error = avatars::AvatarError::TooLarge;
+            return Ok(HttpResponse::build(error.status()).json(error.to_string()));
+        }
+    };
+    // Decoding and resizing are CPU-bound, so they're kept off the async workers
+    let images = match web::block(move || avatars::resize_upload(&data)).await {
+        Ok(Ok(images)) => images,
+        Ok(Err(error)) => return Ok(HttpResponse::build(error.status()).json(error.to_string())),
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Avatar processing failed")),
+    };
+
+    let store = AvatarStore::new(state.repository_service.blob_storage_path());
+    let key = match store.write(user.id, &images) {
+        Ok(key) => key,
+        Err(_) => return Ok(HttpResponse::InternalServerError().json("Storage error")),
+    };
+    let updated = match state.user_service.set_avatar(user.id, Some(key.clone())).await {
+        Ok(Some(updated)) => updated,
+        Ok(None) | Err(_) => {
+            let _ = store.remove(&key);
+            return Ok(HttpResponse::InternalServerError().json("Database error"));
+        }
+    };
+    if let Some(old) = &user.avatar_blob_key {
+        if let Err(e) = store.remove(old) {
+            tracing::warn!("Failed to remove old avatar {}: {}", old, e);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(AvatarResponse {
+        avatar_url: avatar_url(&updated),
+        avatar_version: updated.avatar_version,
+    }))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use crate::client::{ClientMetrics, ClientPolicy};
+    use crate::guardrails::GuardrailMetrics;
+    use crate::pack_cache::PackCache;
+    use crate::post_receive::PostReceiveHooks;
+    use git_storage::{
+        init_db, run_migrations, EventService, IdempotencyService, IdentityService, RepositoryService,
+        RetentionService, SshKeyService, StatusService, TokenService, UserImportReport,
+        UserImportStatus, UserService,
+    };
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    pub(crate) async fn create_test_state() -> AppState {
+        // Use a file-backed database so every pooled connection sees the same schema
+        let test_dir = std::env::temp_dir().join(format!("git-server-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&test_dir).unwrap();
+        let database_url = format!("sqlite:{}?mode=rwc", test_dir.join("test.db").display());
+        let db = init_db(&database_url).await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let blob_storage_path = test_dir.join("blobs");
+        AppState {
+            repository_service: Arc::new(RepositoryService::new(db.clone(), Some(blob_storage_path))),
+            user_service: Arc::new(UserService::new(db.clone()).with_password_hash_cost(4).unwrap()),
+            idempotency_service: Arc::new(IdempotencyService::new(db.clone())),
+            event_service: Arc::new(EventService::new(db.clone())),
+            retention_service: Arc::new(RetentionService::new(db.clone(), Default::default())),
+            token_service: Arc::new(TokenService::new(db.clone())),
+            identity_service: Arc::new(IdentityService::new(db.clone())),
+            ssh_key_service: Arc::new(SshKeyService::new(db.clone())),
+            status_service: Arc::new(StatusService::new(db)),
+            client_policy: Arc::new(ClientPolicy::default()),
+            client_metrics: Arc::new(ClientMetrics::new()),
+            guardrail_metrics: Arc::new(GuardrailMetrics::new()),
+            pack_cache: Arc::new(PackCache::new(test_dir.join("pack-cache"), 1024 * 1024)),
+            clone_limiter: Arc::new(CloneLimiter::new(None, std::time::Duration::ZERO)),
+            config: Arc::new(Config {
+                external_url: "https://example.com/git-server/".to_string(),
+                ..Default::default()
+            }),
+            ssh_host_key_fingerprints: vec!["SHA256:test".to_string()],
+            post_receive_hooks: PostReceiveHooks::default(),
+        }
+    }
+
+    /// A user who signs in with the password `s3cret`, for requests sent with [`basic_auth`]
+    pub(crate) async fn create_user_with_password(state: &AppState, username: &str) -> user::Model {
+        let password_hash = state.user_service.hash_password("s3cret").unwrap();
+        state
+            .user_service
+            .create_user(username.to_string(), format!("{}@example.com", username), password_hash, None, false)
+            .await
+            .unwrap()
+    }
+
+    /// An admin whose password is "s3cret"
+    pub(crate) async fn create_admin_with_password(state: &AppState, username: &str) -> user::Model {
+        let password_hash = state.user_service.hash_password("s3cret").unwrap();
+        state
+            .user_service
+            .create_user(username.to_string(), format!("{}@example.com", username), password_hash, None, true)
+            .await
+            .unwrap()
+    }
+
+    /// Basic credentials for a user made by [`create_user_with_password`], as git sends them
+    pub(crate) fn basic_auth(username: &str) -> (header::HeaderName, String) {
+        use base64::prelude::{Engine as _, BASE64_STANDARD};
+        (header::AUTHORIZATION, format!("Basic {}", BASE64_STANDARD.encode(format!("{}:s3cret", username))))
+    }
+
+    #[actix_web::test]
+    async fn test_anonymous_clones_follow_server_and_repository_settings() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        create_user_with_password(&state, "stranger").await;
+        for name in ["open", "closed"] {
+            state
+                .repository_service
+                .create_repository(name.to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+                .await
+                .unwrap();
+        }
+        let closed = state.repository_service.get_repository_by_name("closed").await.unwrap().unwrap();
+        let update = RepositoryUpdate { allow_anonymous_clone: Some(false), ..Default::default() };
+        state.repository_service.update_repository(closed.id, closed.version, update).await.unwrap();
+
+        let mut locked_down = state.clone();
+        locked_down.config = Arc::new(Config { require_auth: true, ..(*state.config).clone() });
+        let git_routes = || web::scope("/git").service(info_refs).service(upload_pack);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(git_routes())
+                .service(web::scope("/api").service(update_repository)),
+        )
+        .await;
+        let locked_down =
+            test::init_service(App::new().app_data(web::Data::new(locked_down)).service(git_routes())).await;
+
+        let advertisement = |repo: &str| {
+            test::TestRequest::get().uri(&format!("/git/{}/info/refs?service=git-upload-pack", repo)).to_request()
+        };
+        let fetch = |repo: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/git/{}/git-upload-pack", repo))
+                .set_payload(b"0000".to_vec())
+                .to_request()
+        };
+
+        // Allowed by both
+        assert_eq!(test::call_service(&app, advertisement("open")).await.status(), StatusCode::OK);
+        assert_ne!(test::call_service(&app, fetch("open")).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Blocked by the repository
+        assert_eq!(test::call_service(&app, advertisement("closed")).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(test::call_service(&app, fetch("closed")).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Blocked by the server, whatever the repository allows
+        assert_eq!(test::call_service(&locked_down, advertisement("open")).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(test::call_service(&locked_down, fetch("open")).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Push advertisements take credentials whatever the settings allow
+        let req = test::TestRequest::get().uri("/git/open/info/refs?service=git-receive-pack").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Only the owner or an admin can open a repository back up
+        let req = test::TestRequest::patch()
+            .uri("/api/repositories/closed")
+            .insert_header(basic_auth("stranger"))
+            .set_json(serde_json::json!({ "allow_anonymous_clone": true, "version": closed.version + 1 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, advertisement("closed")).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_info_refs_empty_repository() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        state
+            .repository_service
+            .create_repository("empty".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(info_refs)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/git/empty/info/refs?service=git-upload-pack")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+
+        // The service announcement and its flush come first, then the refs
+        let mut expected = b"001e# service=git-upload-pack\n0000".to_vec();
+        let first_line = b"0000000000000000000000000000000000000000 capabilities^{}\0multi_ack side-band-64k ofs-delta shallow filter\n";
+        expected.extend_from_slice(format!("{:04x}", first_line.len() + 4).as_bytes());
+        expected.extend_from_slice(first_line);
+        assert!(body.starts_with(&expected));
+
+        let req = test::TestRequest::get()
+            .uri("/git/empty/info/refs?service=git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-git-receive-pack-advertisement"
+        );
+        let body = test::read_body(resp).await;
+        assert!(body.starts_with(b"001f# service=git-receive-pack\n0000"));
+    }
+
+    #[actix_web::test]
+    async fn test_symbolic_refs_are_advertised_resolved_and_refuse_pushes() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("imported".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let blob = ObjectHandler::new().create_blob(b"tip\n").unwrap();
+        state
+            .repository_service
+            .store_object(repo.id, blob.id.clone(), git_storage::entities::git_object::ObjectKind::Blob, blob.size as i64, blob.content.clone())
+            .await
+            .unwrap();
+        for (name, target, is_symbolic) in [
+            ("refs/remotes/origin/main", blob.id.as_str(), false),
+            ("refs/remotes/origin/HEAD", "refs/remotes/origin/main", true),
+            ("refs/loop/a", "refs/loop/b", true),
+            ("refs/loop/b", "refs/loop/a", true),
+        ] {
+            state
+                .repository_service
+                .store_ref(repo.id, name.to_string(), target.to_string(), is_symbolic)
+                .await
+                .unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(info_refs).service(receive_pack)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/git/imported/info/refs?service=git-upload-pack")
+            .to_request();
+        let advertisement = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).into_owned();
+        assert!(advertisement.contains(&format!("{} refs/remotes/origin/HEAD", blob.id)));
+        assert!(!advertisement.contains("refs/remotes/origin/main refs/remotes/origin/HEAD"));
+        assert!(!advertisement.contains("refs/loop/"));
+
+        let protocol = ProtocolHandler::new();
+        let line = format!("{} {} refs/remotes/origin/HEAD\0report-status", blob.id, blob.id);
+        let req = test::TestRequest::post()
+            .uri("/git/imported/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .set_payload(protocol.create_pkt_line(&[&line]))
+            .to_request();
+        let report = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&report)
+            .contains("ng refs/remotes/origin/HEAD cannot update symbolic ref refs/remotes/origin/HEAD"));
+    }
+
+    #[actix_web::test]
+    async fn test_server_meta_and_clone_urls() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        state
+            .repository_service
+            .create_repository("my-repo".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").service(server_meta).service(get_repository))
+                .service(web::scope("/git").service(info_refs))
+                .service(info_refs),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/api/meta").to_request();
+        let meta: ServerMetaResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(meta.external_url, "https://example.com/git-server/");
+        assert_eq!(meta.ssh.host, "example.com");
+        assert_eq!(meta.ssh.port, 2222);
+        assert_eq!(meta.ssh.host_key_fingerprints, vec!["SHA256:test"]);
+        assert!(meta.token_scopes.contains(&"status:write".to_string()));
+
+        let req = test::TestRequest::get().uri("/api/repositories/my-repo").to_request();
+        let repo: RepositoryResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(repo.http_clone_url, "https://example.com/git-server/owner/my-repo");
+        assert_eq!(repo.ssh_clone_url, "ssh://git@example.com:2222/owner/my-repo");
+
+        // Git is served at the clone URL's path, under the right owner only, and still under /git
+        for (path, status) in [
+            ("/owner/my-repo", StatusCode::OK),
+            ("/someone/my-repo", StatusCode::NOT_FOUND),
+            ("/git/my-repo", StatusCode::OK),
+            ("/git/owner/my-repo", StatusCode::OK),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("{}/info/refs?service=git-upload-pack", path))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status, "{}", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_receive_pack_updates_head_symref() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("pushed".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let repository_service = state.repository_service.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(info_refs).service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let blob = handler.create_blob(b"hello\n").unwrap();
+        let tree = handler
+            .create_tree(&git_protocol::objects::Tree {
+                entries: vec![git_protocol::objects::TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "hello.txt".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = handler
+            .create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let commit_id = commit.id.clone();
+
+        // Create main and dev, and move HEAD onto dev in the same push
+        let first = format!(
+            "{} {} refs/heads/main\0report-status symref=HEAD:refs/heads/dev",
+            git_protocol::ZERO_ID, commit_id
+        );
+        let second = format!("{} {} refs/heads/dev", git_protocol::ZERO_ID, commit_id);
+        let mut body = protocol.create_pkt_line(&[&first, &second]);
+        body.extend(protocol.create_pack(&[blob, tree, commit]).unwrap());
+
+        let req = test::TestRequest::post()
+            .uri("/git/pushed/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .set_payload(body)
+            .to_request();
+        let report = test::call_and_read_body(&app, req).await;
+        let report = String::from_utf8_lossy(&report);
+        assert!(report.contains("unpack ok"));
+        assert!(report.contains("ok refs/heads/dev"));
+        assert!(report.contains("ok HEAD"));
+
+        assert_eq!(repository_service.get_head_target(repo.id).await.unwrap(), "refs/heads/dev");
+
+        let req = test::TestRequest::get()
+            .uri("/git/pushed/info/refs?service=git-upload-pack")
+            .to_request();
+        let advertisement = test::call_and_read_body(&app, req).await;
+        let advertisement = String::from_utf8_lossy(&advertisement);
+        let refs = advertisement.strip_prefix("001e# service=git-upload-pack\n0000").unwrap();
+        assert!(refs[4..].starts_with(&format!("{} HEAD\0", commit_id)));
+        assert!(advertisement.contains("symref=HEAD:refs/heads/dev"));
+
+        // Pointing HEAD at a branch that doesn't exist is rejected and HEAD stays put
+        let line = format!(
+            "{} {} refs/heads/main\0report-status symref=HEAD:refs/heads/missing",
+            commit_id, commit_id
+        );
+        let req = test::TestRequest::post()
+            .uri("/git/pushed/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .set_payload(protocol.create_pkt_line(&[&line]))
+            .to_request();
+        let report = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&report).contains("ng HEAD ref refs/heads/missing does not exist"));
+        assert_eq!(repository_service.get_head_target(repo.id).await.unwrap(), "refs/heads/dev");
+    }
+
+    #[actix_web::test]
+    async fn test_sha256_repository_accepts_sha256_pushes() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let repository_service = state.repository_service.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").service(create_repository))
+                .service(web::scope("/git").service(info_refs).service(receive_pack)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/repositories")
+            .set_json(serde_json::json!({
+                "name": "modern",
+                "owner_id": owner.id.to_string(),
+                "object_format": "sha256",
+            }))
+            .to_request();
+        let created: RepositoryResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(created.object_format, ObjectFormat::Sha256);
+        let repo_id = uuid::Uuid::parse_str(&created.id).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/repositories")
+            .set_json(serde_json::json!({ "name": "odd", "object_format": "md5" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Clients learn the format from the advertisement, zero id included
+        let zero = HashAlgorithm::Sha256.zero_id();
+        let req = test::TestRequest::get()
+            .uri("/git/modern/info/refs?service=git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .to_request();
+        let advertisement = test::call_and_read_body(&app, req).await;
+        let advertisement = String::from_utf8_lossy(&advertisement);
+        assert!(advertisement.contains(&format!("{} capabilities^{{}}", zero)));
+        assert!(advertisement.contains("object-format=sha256"));
+
+        let protocol = ProtocolHandler::new().with_hash_algorithm(HashAlgorithm::Sha256);
+        let handler = protocol.object_handler();
+        let blob = handler.create_blob(b"hello\n").unwrap();
+        let tree = handler
+            .create_tree(&git_protocol::objects::Tree {
+                entries: vec![git_protocol::objects::TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "hello.txt".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = handler
+            .create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let commit_id = commit.id.clone();
+        assert_eq!(commit_id.len(), 64);
+
+        let line = format!("{} {} refs/heads/main\0report-status", zero, commit_id);
+        let mut body = protocol.create_pkt_line(&[&line]);
+        body.extend(protocol.create_pack(&[blob.clone(), tree, commit]).unwrap());
+        let req = test::TestRequest::post()
+            .uri("/git/modern/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .set_payload(body)
+            .to_request();
+        let report = test::call_and_read_body(&app, req).await;
+        let report = String::from_utf8_lossy(&report);
+        assert!(report.contains("unpack ok"), "{}", report);
+        assert!(report.contains("ok refs/heads/main"));
+
+        let main = repository_service.get_ref(repo_id, "refs/heads/main").await.unwrap().unwrap();
+        assert_eq!(main.target, commit_id);
+        let stored = repository_service.get_object(repo_id, &blob.id).await.unwrap().unwrap();
+        assert_eq!(stored.content, b"hello\n");
+
+        // A SHA-1 push doesn't name anything here
+        let sha1 = ProtocolHandler::new();
+        let sha1_blob = ObjectHandler::new().create_blob(b"hello\n").unwrap();
+        let line = format!("{} {} refs/heads/old\0report-status", git_protocol::ZERO_ID, sha1_blob.id);
+        let mut body = sha1.create_pkt_line(&[&line]);
+        body.extend(sha1.create_pack(&[sha1_blob]).unwrap());
+        let req = test::TestRequest::post()
+            .uri("/git/modern/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .set_payload(body)
+            .to_request();
+        let report = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&report).contains("invalid request"));
+        assert!(repository_service.get_ref(repo_id, "refs/heads/old").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_future_dated_commits_are_rejected_when_guarded() {
+        let mut state = create_test_state().await;
+        state.config = Arc::new(Config {
+            reject_future_commits: true,
+            max_commit_date_skew_secs: 3600,
+            ..(*state.config).clone()
+        });
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("dated".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let repository_service = state.repository_service.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let push = |seconds: i64, ref_name: &str| {
+            let signature = format!("A U Thor <author@example.com> {} +0000", seconds);
+            let commit = handler
+                .create_commit(&git_protocol::objects::Commit {
+                    tree: "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+                    parents: vec![],
+                    author: signature.clone(),
+                    committer: signature,
+                    message: "Dated\n".to_string(),
+                    author_date: chrono::Utc::now(),
+                    commit_date: chrono::Utc::now(),
+                    encoding: None,
+                })
+                .unwrap();
+            let line = format!("{} {} {}\0report-status", git_protocol::ZERO_ID, commit.id, ref_name);
+            let mut body = protocol.create_pkt_line(&[&line]);
+            body.extend(protocol.create_pack(&[commit]).unwrap());
+            test::TestRequest::post()
+                .uri("/git/dated/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        // Within the skew is fine
+        let soon = chrono::Utc::now().timestamp() + 60;
+        let report = test::call_and_read_body(&app, push(soon, "refs/heads/main")).await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/main"));
+
+        let far = chrono::Utc::now().timestamp() + 10 * 365 * 24 * 3600;
+        let report = test::call_and_read_body(&app, push(far, "refs/heads/future")).await;
+        let report = String::from_utf8_lossy(&report);
+        assert!(report.contains("is dated in the future"), "{report}");
+        assert!(report.contains("ng refs/heads/future unpacker error"));
+        assert!(repository_service.get_ref(repo.id, "refs/heads/future").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_pushes_with_oversized_objects_are_refused() {
+        let mut state = create_test_state().await;
+        state.config = Arc::new(Config {
+            max_object_bytes: Some(1024),
+            ..(*state.config).clone()
+        });
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("sized".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let repository_service = state.repository_service.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let blob = handler.create_blob(&[b'x'; 2048]).unwrap();
+        let blob_id = blob.id.clone();
+        let tree = handler
+            .create_tree(&git_protocol::objects::Tree {
+                entries: vec![git_protocol::objects::TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "big.bin".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = handler
+            .create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Add a big file\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let line = format!("{} {} refs/heads/main\0report-status", git_protocol::ZERO_ID, commit.id);
+        let mut body = protocol.create_pkt_line(&[&line]);
+        body.extend(protocol.create_pack(&[blob, tree, commit]).unwrap());
+
+        let req = test::TestRequest::post()
+            .uri("/git/sized/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .set_payload(body)
+            .to_request();
+        let report = test::call_and_read_body(&app, req).await;
+        let report = String::from_utf8_lossy(&report);
+        let reason = format!("object {} is 2048 bytes, over the 1024 byte limit", blob_id);
+        assert!(report.contains(&format!("unpack error {}", reason)), "{report}");
+        assert!(report.contains(&format!("ng refs/heads/main {}", reason)), "{report}");
+        assert!(!repository_service.object_exists(repo.id, &blob_id).await.unwrap());
+    }
+
+    /// Split a side-band response into the payloads of each band
+    fn demux_sideband(mut response: &[u8]) -> HashMap<u8, Vec<u8>> {
+        let mut bands: HashMap<u8, Vec<u8>> = HashMap::new();
+        while response.len() >= 4 {
+            let length = usize::from_str_radix(std::str::from_utf8(&response[..4]).unwrap(), 16).unwrap();
+            if length == 0 {
+                response = &response[4..];
+                continue;
+            }
+            bands.entry(response[4]).or_default().extend_from_slice(&response[5..length]);
+            response = &response[length..];
+        }
+        bands
+    }
+
+    #[actix_web::test]
+    async fn test_post_receive_messages_go_to_sideband_clients() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("pushed".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let update = RepositoryUpdate {
+            push_message: Some("Pushed {ref} to {repo} as {pusher}".to_string()),
+            ..Default::default()
+        };
+        state.repository_service.update_repository(repo.id, repo.version, update).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let tree = handler.create_tree(&git_protocol::objects::Tree { entries: vec![] }).unwrap();
+        let commit = handler
+            .create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let push = |branches: &[&str], capabilities: &str, pack: Vec<u8>| {
+            let lines: Vec<String> = branches
+                .iter()
+                .enumerate()
+                .map(|(i, branch)| {
+                    let line = format!("{} {} refs/heads/{}", git_protocol::ZERO_ID, commit.id, branch);
+                    if i == 0 { format!("{}\0{}", line, capabilities) } else { line }
+                })
+                .collect();
+            let mut body = protocol.create_pkt_line(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+            body.extend(pack);
+            test::TestRequest::post()
+                .uri("/git/pushed/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        // The default branch gets no merge request hint, the new feature branch does
+        let pack = protocol.create_pack(&[tree.clone(), commit.clone()]).unwrap();
+        let req = push(&["main", "feature/a+b"], "report-status side-band-64k", pack);
+        let response = test::call_and_read_body(&app, req).await;
+        let bands = demux_sideband(&response);
+        assert!(String::from_utf8_lossy(&bands[&1]).contains("ok refs/heads/feature/a+b"));
+        assert_eq!(
+            String::from_utf8(bands[&2].clone()).unwrap(),
+            "\nTo create a merge request for feature/a+b, visit:\n  \
+             https://example.com/git-server/pushed/merge_requests/new?source_branch=feature/a%2Bb\n\n\
+             Pushed refs/heads/main to pushed as owner\n\n\
+             Pushed refs/heads/feature/a+b to pushed as owner\n\n"
+        );
+        assert!(response.ends_with(b"0000"));
+
+        // Without side-band the report is all there is
+        let response = test::call_and_read_body(&app, push(&["other"], "report-status", Vec::new())).await;
+        let report = String::from_utf8(response.to_vec()).unwrap();
+        assert!(!report.contains("merge request"));
+        let expected = protocol.create_report_status(&Ok(()), &[("refs/heads/other".to_string(), Ok(()))]);
+        assert_eq!(report.as_bytes(), expected);
+    }
+
+    #[actix_web::test]
+    async fn test_receive_pack_report_status_is_framed() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        state
+            .repository_service
+            .create_repository("framed".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let tree = handler.create_tree(&git_protocol::objects::Tree { entries: vec![] }).unwrap();
+        let commit = handler
+            .create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let push = |branch: &str, capabilities: &str| {
+            let line = format!("{} {} refs/heads/{}\0{}", git_protocol::ZERO_ID, commit.id, branch, capabilities);
+            let mut body = protocol.create_pkt_line(&[line.as_str()]);
+            body.extend(protocol.create_pack(&[tree.clone(), commit.clone()]).unwrap());
+            test::TestRequest::post()
+                .uri("/git/framed/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, push("main", "report-status")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-git-receive-pack-result"
+        );
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "no-cache");
+        let expected: &[u8] = b"000eunpack ok\n0017ok refs/heads/main\n0000";
+        assert_eq!(
+            resp.headers().get(header::CONTENT_LENGTH).unwrap(),
+            expected.len().to_string().as_str()
+        );
+        assert_eq!(test::read_body(resp).await, expected);
+
+        // With side-band the same report travels in band 1, before the closing flush
+        let response = test::call_and_read_body(&app, push("topic", "report-status side-band-64k")).await;
+        let report = b"000eunpack ok\n0018ok refs/heads/topic\n0000";
+        let mut framed = format!("{:04x}\x01", report.len() + 5).into_bytes();
+        framed.extend_from_slice(report);
+        assert!(response.starts_with(&framed));
+        assert!(response.ends_with(b"0000"));
+    }
+
+    #[actix_web::test]
+    async fn test_pushes_take_the_owner_or_an_admin() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        create_user_with_password(&state, "stranger").await;
+        let repo = state
+            .repository_service
+            .create_repository("open".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let repository_service = state.repository_service.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(info_refs).service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let blob = handler.create_blob(b"open\n").unwrap();
+        let line = format!("{} {} refs/heads/main\0report-status", git_protocol::ZERO_ID, blob.id);
+        let mut body = protocol.create_pkt_line(&[&line]);
+        body.extend(protocol.create_pack(std::slice::from_ref(&blob)).unwrap());
+        let push = |user: Option<&str>| {
+            let mut req = test::TestRequest::post().uri("/git/open/git-receive-pack").set_payload(body.clone());
+            if let Some(user) = user {
+                req = req.insert_header(basic_auth(user));
+            }
+            req.to_request()
+        };
+
+        // Anonymous pushers are asked for credentials at the advertisement already
+        let req = test::TestRequest::get().uri("/git/open/info/refs?service=git-receive-pack").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get()
+            .uri("/git/open/info/refs?service=git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let resp = test::call_service(&app, push(None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(test::read_body(resp).await, "authentication required\n");
+        let report = test::call_and_read_body(&app, push(Some("stranger"))).await;
+        assert_eq!(report, b"001aERR permission denied\n0000".as_slice());
+        assert!(repository_service.get_ref(repo.id, "refs/heads/main").await.unwrap().is_none());
+
+        let report = test::call_and_read_body(&app, push(Some("owner"))).await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/main"), "{:?}", report);
+    }
+
+    #[actix_web::test]
+    async fn test_receive_pack_client_policy() {
+        let mut state = create_test_state().await;
+        state.client_policy = Arc::new(ClientPolicy::new(Some("git/2.30.0")).unwrap());
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("policy".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let event_service = state.event_service.clone();
+        let repository_service = state.repository_service.clone();
+        let metrics = state.client_metrics.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(receive_pack)),
+        )
+        .await;
+
+        let protocol = ProtocolHandler::new();
+        let delete = |agent: &str| {
+            let line = format!(
+                "{} {} refs/heads/gone\0report-status side-band-64k agent={}",
+                "1".repeat(40),
+                git_protocol::ZERO_ID,
+                agent
+            );
+            protocol.create_pkt_line(&[&line])
+        };
+
+        // An old agent gets the requirement on the error band and nothing is recorded
+        let req = test::TestRequest::post()
+            .uri("/git/policy/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .insert_header(("User-Agent", "git/2.17.1"))
+            .set_payload(delete("git/2.17.1"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let message = "git/2.17.1 is not supported for pushes to this server; please upgrade to git/2.30.0 or newer\n";
+        let mut expected = format!("{:04x}", message.len() + 5).into_bytes();
+        expected.push(3);
+        expected.extend_from_slice(message.as_bytes());
+        expected.extend_from_slice(b"0000");
+        assert_eq!(body, expected);
+        assert!(event_service.list_by_repository(repo.id, 10).await.unwrap().is_empty());
+        assert_eq!(metrics.snapshot().rejected_pushes, 1);
+
+        // A current agent is let through and its push is recorded with both identifiers
+        repository_service
+            .store_ref(repo.id, "refs/heads/gone".to_string(), "1".repeat(40), false)
+            .await
+            .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/git/policy/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .insert_header(("User-Agent", "git/2.43.0"))
+            .set_payload(delete("git/2.43.0"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body[4], 1);
+
+        let events = event_service.list_by_repository(repo.id, 10).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, "push");
+        assert_eq!(events[0].transport, "http");
+        assert_eq!(events[0].agent.as_deref(), Some("git/2.43.0"));
+        assert_eq!(events[0].user_agent.as_deref(), Some("git/2.43.0"));
+    }
+
+    #[actix_web::test]
+    async fn test_push_events_describe_each_changed_ref() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("events".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let event_service = state.event_service.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let blob = handler.create_blob(b"events\n").unwrap();
+        let tree = handler
+            .create_tree(&git_protocol::objects::Tree {
+                entries: vec![git_protocol::objects::TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "README".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = |message: &str, parents: Vec<String>| {
+            handler
+                .create_commit(&git_protocol::objects::Commit {
+                    tree: tree.id.clone(),
+                    parents,
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: message.to_string(),
+                    author_date: chrono::Utc::now(),
+                    commit_date: chrono::Utc::now(),
+                    encoding: None,
+                })
+                .unwrap()
+        };
+        let push = |commands: &[(&str, &str, &str)], objects: &[git_protocol::GitObject]| {
+            let lines: Vec<String> = commands
+                .iter()
+                .enumerate()
+                .map(|(i, (old, new, name))| match i {
+                    0 => format!("{} {} {}\0report-status", old, new, name),
+                    _ => format!("{} {} {}", old, new, name),
+                })
+                .collect();
+            let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+            let mut body = protocol.create_pkt_line(&lines);
+            if !objects.is_empty() {
+                body.extend(protocol.create_pack(objects).unwrap());
+            }
+            test::TestRequest::post()
+                .uri("/git/events/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request()
+        };
+        let latest_payload = || async {
+            let events = event_service.list_by_repository(repo.id, 100).await.unwrap();
+            let payload: PushEventPayload = serde_json::from_str(events[0].payload.as_deref().unwrap()).unwrap();
+            (events.len(), payload)
+        };
+        let zero = git_protocol::ZERO_ID;
+
+        // Create: before is the zero id and every commit is new
+        let first = commit("First\n", vec![]);
+        let second = commit("Second\n", vec![first.id.clone()]);
+        let objects = [blob.clone(), tree.clone(), first.clone(), second.clone()];
+        test::call_and_read_body(&app, push(&[(zero, &second.id, "refs/heads/main")], &objects)).await;
+        let (count, payload) = latest_payload().await;
+        assert_eq!(count, 1);
+        assert_eq!(
+            payload.refs,
+            vec![PushedRef {
+                name: "refs/heads/main".to_string(),
+                before: zero.to_string(),
+                after: second.id.clone(),
+                created: true,
+                deleted: false,
+                commits: vec![first.id.clone(), second.id.clone()],
+                total_commits: 2,
+            }]
+        );
+
+        // Update: only the commits not reachable from the old target
+        let third = commit("Third\n", vec![second.id.clone()]);
+        test::call_and_read_body(&app, push(&[(&second.id, &third.id, "refs/heads/main")], std::slice::from_ref(&third))).await;
+        let (count, payload) = latest_payload().await;
+        assert_eq!(count, 2);
+        assert_eq!(payload.refs.len(), 1);
+        assert_eq!((payload.refs[0].before.as_str(), payload.refs[0].after.as_str()), (second.id.as_str(), third.id.as_str()));
+        assert!(!payload.refs[0].created && !payload.refs[0].deleted);
+        assert_eq!(payload.refs[0].commits, vec![third.id.clone()]);
+
+        // Delete: after is the zero id, with no commits
+        test::call_and_read_body(&app, push(&[(zero, &first.id, "refs/heads/old")], &[])).await;
+        test::call_and_read_body(&app, push(&[(&first.id, zero, "refs/heads/old")], &[])).await;
+        let (count, payload) = latest_payload().await;
+        assert_eq!(count, 4);
+        assert_eq!(
+            payload.refs,
+            vec![PushedRef {
+                name: "refs/heads/old".to_string(),
+                before: first.id.clone(),
+                after: zero.to_string(),
+                created: false,
+                deleted: true,
+                commits: vec![],
+                total_commits: 0,
+            }]
+        );
+
+        // Three refs in one push make one event with three entries, in command order
+        test::call_and_read_body(&app, push(&[(zero, &first.id, "refs/heads/topic")], &[])).await;
+        let fourth = commit("Fourth\n", vec![third.id.clone()]);
+        let mixed = [
+            (zero, second.id.as_str(), "refs/heads/feature"),
+            (third.id.as_str(), fourth.id.as_str(), "refs/heads/main"),
+            (first.id.as_str(), zero, "refs/heads/topic"),
+        ];
+        let report = test::call_and_read_body(&app, push(&mixed, std::slice::from_ref(&fourth))).await;
+        assert_eq!(String::from_utf8_lossy(&report).matches("ok refs/heads/").count(), 3);
+        let (count, payload) = latest_payload().await;
+        assert_eq!(count, 6);
+        let summary: Vec<(&str, bool, bool, usize)> = payload
+            .refs
+            .iter()
+            .map(|r| (r.name.as_str(), r.created, r.deleted, r.commits.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("refs/heads/feature", true, false, 2),
+                ("refs/heads/main", false, false, 1),
+                ("refs/heads/topic", false, true, 0),
+            ]
+        );
+
+        // Retrying the same push changes nothing and records nothing
+        let report = test::call_and_read_body(&app, push(&mixed, std::slice::from_ref(&fourth))).await;
+        assert!(!String::from_utf8_lossy(&report).contains("ok refs/heads/"));
+        assert_eq!(latest_payload().await.0, 6);
+    }
+
+    #[actix_web::test]
+    async fn test_atomic_push_applies_all_refs_or_none() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("atomic".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let repository_service = state.repository_service.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(info_refs).service(receive_pack)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/git/atomic/info/refs?service=git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .to_request();
+        let advertisement = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&advertisement).contains(" atomic"));
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let tree = handler.create_tree(&git_protocol::objects::Tree { entries: vec![] }).unwrap();
+        let commit = handler
+            .create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        repository_service
+            .store_ref(repo.id, "refs/heads/stable".to_string(), commit.id.clone(), false)
+            .await
+            .unwrap();
+
+        // The second command expects stable at the wrong commit
+        let push = |capabilities: &str| {
+            let first = format!(
+                "{} {} refs/heads/feature\0report-status {}",
+                git_protocol::ZERO_ID, commit.id, capabilities
+            );
+            let second = format!("{} {} refs/heads/stable", "1".repeat(40), commit.id);
+            let mut body = protocol.create_pkt_line(&[&first, &second]);
+            body.extend(protocol.create_pack(&[tree.clone(), commit.clone()]).unwrap());
+            test::TestRequest::post()
+                .uri("/git/atomic/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let report = test::call_and_read_body(&app, push("atomic")).await;
+        let report = String::from_utf8_lossy(&report);
+        assert!(report.contains("unpack ok"));
+        assert!(report.contains("ng refs/heads/feature atomic transaction failed"));
+        assert!(report.contains("ng refs/heads/stable ref changed concurrently"));
+        assert!(repository_service.get_ref(repo.id, "refs/heads/feature").await.unwrap().is_none());
+
+        // Without the capability each ref stands alone
+        let report = test::call_and_read_body(&app, push("")).await;
+        let report = String::from_utf8_lossy(&report);
+        assert!(report.contains("ok refs/heads/feature"));
+        assert!(report.contains("ng refs/heads/stable"));
+        assert!(repository_service.get_ref(repo.id, "refs/heads/feature").await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_linear_history_branches_refuse_pushed_merge_commits() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("linear".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let repository_service = state.repository_service.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(receive_pack))
+                .service(web::scope("/api").service(update_repository)),
+        )
+        .await;
+
+        let req = test::TestRequest::patch()
+            .uri("/api/repositories/linear")
+            .insert_header(basic_auth("owner"))
+            .set_json(serde_json::json!({ "linear_history_branches": ["refs/heads/main"], "version": repo.version }))
+            .to_request();
+        let updated: RepositoryResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(updated.linear_history_branches, vec!["main"]);
+        let req = test::TestRequest::patch()
+            .uri("/api/repositories/linear")
+            .insert_header(basic_auth("owner"))
+            .set_json(serde_json::json!({ "linear_history_branches": ["two words"], "version": updated.version }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let tree = handler.create_tree(&git_protocol::objects::Tree { entries: vec![] }).unwrap();
+        let commit = |parents: Vec<String>, message: &str| {
+            handler
+                .create_commit(&git_protocol::objects::Commit {
+                    tree: tree.id.clone(),
+                    parents,
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: format!("{}\n", message),
+                    author_date: chrono::Utc::now(),
+                    commit_date: chrono::Utc::now(),
+                    encoding: None,
+                })
+                .unwrap()
+        };
+        let base = commit(vec![], "Initial commit");
+        let next = commit(vec![base.id.clone()], "Fast-forward");
+        let side = commit(vec![base.id.clone()], "Side work");
+        let merge = commit(vec![next.id.clone(), side.id.clone()], "Merge side work");
+        repository_service
+            .store_ref(repo.id, "refs/heads/main".to_string(), base.id.clone(), false)
+            .await
+            .unwrap();
+
+        let push = |ref_name: &str, old: &str, new: &str| {
+            let command = format!("{} {} {}\0report-status", old, new, ref_name);
+            let mut body = protocol.create_pkt_line(&[&command]);
+            let objects = [tree.clone(), base.clone(), next.clone(), side.clone(), merge.clone()];
+            body.extend(protocol.create_pack(&objects).unwrap());
+            let req = test::TestRequest::post()
+                .uri("/git/linear/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request();
+            let app = &app;
+            async move { String::from_utf8_lossy(&test::call_and_read_body(app, req).await).into_owned() }
+        };
+
+        let report = push("refs/heads/main", &base.id, &next.id).await;
+        assert!(report.contains("ok refs/heads/main"), "{}", report);
+
+        let report = push("refs/heads/main", &next.id, &merge.id).await;
+        assert!(
+            report.contains(&format!("ng refs/heads/main non-linear history: {} is a merge commit", merge.id)),
+            "{}",
+            report
+        );
+        let main = repository_service.get_ref(repo.id, "refs/heads/main").await.unwrap().unwrap();
+        assert_eq!(main.target, next.id);
+
+        // Other branches still take merges
+        let report = push("refs/heads/topic", git_protocol::ZERO_ID, &merge.id).await;
+        assert!(report.contains("ok refs/heads/topic"), "{}", report);
+    }
+
+    #[actix_web::test]
+    async fn test_create_repository_is_idempotent() {
+        let state = create_test_state().await;
+        let repository_service = state.repository_service.clone();
+        let owners = [create_user_with_password(&state, "alice").await, create_user_with_password(&state, "bob").await];
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").service(create_repository)),
+        )
+        .await;
+
+        let create = || {
+            test::TestRequest::post()
+                .uri("/api/repositories")
+                .insert_header((IDEMPOTENCY_KEY_HEADER, "retry-123"))
+                .set_json(serde_json::json!({ "name": "once" }))
+                .to_request()
+        };
+
+        let first = test::call_service(&app, create()).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        let first_body = test::read_body(first).await;
+
+        let second = test::call_service(&app, create()).await;
+        assert_eq!(second.status(), StatusCode::CREATED);
+        let second_body = test::read_body(second).await;
+
+        assert_eq!(first_body, second_body);
+        assert_eq!(repository_service.list_repositories().await.unwrap().len(), 1);
+
+        // Another owner reusing the key gets a repository of their own
+        for owner in &owners {
+            let req = test::TestRequest::post()
+                .uri("/api/repositories")
+                .insert_header((IDEMPOTENCY_KEY_HEADER, "retry-123"))
+                .set_json(serde_json::json!({ "name": format!("{}-once", owner.username), "owner_id": owner.id }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            let created: RepositoryResponse = test::read_body_json(resp).await;
+            assert_eq!(created.owner_id, owner.id.to_string());
+        }
+        assert_eq!(repository_service.list_repositories().await.unwrap().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_topics_filter_repository_listing() {
+        let state = create_test_state().await;
+        let event_service = state.event_service.clone();
+        let repository_service = state.repository_service.clone();
+        let owner = create_user_with_password(&state, "owner").await;
+        let other = create_user_with_password(&state, "other").await;
+        let tagged = state
+            .repository_service
+            .create_repository("tagged".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        for (name, owner_id) in [("plain", owner.id), ("elsewhere", other.id)] {
+            state
+                .repository_service
+                .create_repository(name.to_string(), None, "main".to_string(), owner_id, Visibility::Public, None)
+                .await
+                .unwrap();
+        }
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .service(list_repositories)
+                    .service(set_repository_topics)
+                    .service(get_user_repositories),
+            ),
+        )
+        .await;
+
+        let put_topics = |user: &str, repo: &str, topics: &[&str]| {
+            test::TestRequest::put()
+                .uri(&format!("/api/repositories/{}/topics", repo))
+                .insert_header(basic_auth(user))
+                .set_json(serde_json::json!({ "topics": topics }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, put_topics("owner", "tagged", &["Rust", "git-server"])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let repo: RepositoryResponse = test::read_body_json(resp).await;
+        assert_eq!(repo.topics, vec!["git-server", "rust"]);
+
+        let resp = test::call_service(&app, put_topics("other", "elsewhere", &["rust"])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(&app, put_topics("owner", "tagged", &["no spaces"])).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get().uri("/api/repositories?topic=RUST").to_request();
+        let repos: Vec<RepositoryResponse> = test::call_and_read_body_json(&app, req).await;
+        let mut names: Vec<&str> = repos.iter().map(|r| r.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["elsewhere", "tagged"]);
+
+        let req = test::TestRequest::get().uri("/api/repositories").to_request();
+        let repos: Vec<RepositoryResponse> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(repos.len(), 3);
+        let plain = repos.iter().find(|r| r.name == "plain").unwrap();
+        assert!(plain.topics.is_empty());
+
+        let req = test::TestRequest::get()
+            .uri("/api/users/owner/repositories?topic=rust")
+            .to_request();
+        let repos: Vec<RepositoryResponse> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(repos.len(), 1);
+        assert_eq!(repos[0].name, "tagged");
+        assert_eq!(repos[0].topics, vec!["git-server", "rust"]);
+
+        // Only the accepted change is recorded
+        let events = event_service.list_by_repository(tagged.id, 10).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, "topics_updated");
+        assert_eq!(events[0].payload.as_deref(), Some(r#"["git-server","rust"]"#));
+        assert_eq!(events[0].actor_id, Some(owner.id));
+
+        // Only the owner or an admin sets topics, and private repositories stay hidden
+        let resp = test::call_service(&app, put_topics("other", "tagged", &["spam"])).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::put()
+            .uri("/api/repositories/tagged/topics")
+            .set_json(serde_json::json!({ "topics": ["spam"] }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        repository_service
+            .create_repository("secret".to_string(), None, "main".to_string(), owner.id, Visibility::Private, None)
+            .await
+            .unwrap();
+        let resp = test::call_service(&app, put_topics("other", "secret", &["spam"])).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_repository_list_includes_counts_and_tips_in_a_fixed_number_of_queries() {
+        let mut state = create_test_state().await;
+        let queries = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut db = state.repository_service.get_db().clone();
+        let counter = queries.clone();
+        db.set_metric_callback(move |_| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        });
+        let blob_storage_path = state.repository_service.blob_storage_path().to_path_buf();
+        state.repository_service = Arc::new(RepositoryService::new(db, Some(blob_storage_path)));
+
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let other = state
+            .user_service
+            .create_user("other".to_string(), "other@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let service = state.repository_service.clone();
+        let busy = service
+            .create_repository("busy".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        for name in ["empty-1", "empty-2", "empty-3"] {
+            service
+                .create_repository(name.to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+                .await
+                .unwrap();
+        }
+        service
+            .create_repository("secret".to_string(), None, "main".to_string(), other.id, Visibility::Private, None)
+            .await
+            .unwrap();
+
+        let git_ops = GitOperations::new(service.as_ref().clone());
+        let mut tip = String::new();
+        for message in ["First commit", "Second commit\n\nWith a body"] {
+            tip = git_ops
+                .commit_files(
+                    service.get_db(),
+                    busy.id,
+                    "main",
+                    vec![("README".to_string(), git_storage::FileChange::Write(message.as_bytes().to_vec()))],
+                    "Owner <owner@example.com>".to_string(),
+                    message.to_string(),
+                )
+                .await
+                .unwrap();
+        }
+        for name in ["refs/heads/topic", "refs/tags/v1", "refs/tags/v2"] {
+            service.store_ref(busy.id, name.to_string(), tip.clone(), false).await.unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").service(list_repositories)),
+        )
+        .await;
+        let list = |uri: &'static str| {
+            let app = &app;
+            let queries = queries.clone();
+            async move {
+                let before = queries.load(std::sync::atomic::Ordering::SeqCst);
+                let resp = test::call_service(app, test::TestRequest::get().uri(uri).to_request()).await;
+                let status = resp.status();
+                let body = test::read_body(resp).await;
+                (status, body, queries.load(std::sync::atomic::Ordering::SeqCst) - before)
+            }
+        };
+
+        // The plain listing is the repositories and their topics, nothing more
+        let (status, body, plain_queries) = list("/api/repositories").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(plain_queries, 2);
+        let repos: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(repos.len(), 4, "the private repository is filtered out");
+        assert!(repos.iter().all(|repo| repo.get("branch_count").is_none() && repo.get("tip").is_none()));
+
+        let (status, body, enriched_queries) = list("/api/repositories?include=counts,tip").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(enriched_queries, plain_queries + 4);
+        let repos: Vec<RepositoryResponse> = serde_json::from_slice(&body).unwrap();
+        assert!(repos.iter().all(|repo| repo.name != "secret"));
+        let busy = repos.iter().find(|repo| repo.name == "busy").unwrap();
+        assert_eq!((busy.branch_count, busy.tag_count), (Some(2), Some(2)));
+        assert!(busy.last_activity.is_some());
+        let busy_tip = busy.tip.as_ref().unwrap();
+        assert_eq!(busy_tip.sha, tip);
+        assert_eq!(busy_tip.subject, "Second commit");
+        assert_eq!(busy_tip.author, "Owner <owner@example.com>");
+        let empty = repos.iter().find(|repo| repo.name == "empty-1").unwrap();
+        assert_eq!((empty.branch_count, empty.tag_count), (Some(0), Some(0)));
+        assert!(empty.tip.is_none());
+        assert!(repos.iter().all(|repo| repo.permissions.is_none()));
+
+        // Permissions come from the caller's account, which the listing loads anyway
+        let (status, body, permission_queries) = list("/api/repositories?include=permissions").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(permission_queries, plain_queries);
+        let repos: Vec<RepositoryResponse> = serde_json::from_slice(&body).unwrap();
+        assert!(repos.iter().all(|repo| repo.permissions
+            == Some(Permissions { read: true, write: false, admin: false })));
+
+        let (status, _, _) = list("/api/repositories?include=counts,stars").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_take_an_admin() {
+        let state = create_test_state().await;
+        create_user_with_password(&state, "user").await;
+        create_admin_with_password(&state, "admin").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(
+                    web::scope("/api")
+                        .service(storage_metrics)
+                        .service(client_metrics)
+                        .service(retention_metrics)
+                        .service(guardrail_metrics)
+                        .service(pack_cache_metrics),
+                ),
+        )
+        .await;
+
+        for uri in [
+            "/api/metrics/storage",
+            "/api/metrics/clients",
+            "/api/metrics/retention",
+            "/api/metrics/guardrails",
+            "/api/metrics/pack-cache",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+            let req = test::TestRequest::get().uri(uri).insert_header(basic_auth("user")).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN, "{}", uri);
+            let req = test::TestRequest::get().uri(uri).insert_header(basic_auth("admin")).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_retention_metrics_report_last_run() {
+        let state = create_test_state().await;
+        create_admin_with_password(&state, "admin").await;
+        let retention_service = state.retention_service.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").service(retention_metrics)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/metrics/retention")
+            .insert_header(basic_auth("admin"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body.is_null());
+
+        retention_service.run().await.unwrap();
+        let req = test::TestRequest::get()
+            .uri("/api/metrics/retention")
+            .insert_header(basic_auth("admin"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let tables: Vec<&str> = body["tables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["table"].as_str().unwrap())
+            .collect();
+        assert_eq!(tables, vec!["ref_log", "repository_events"]);
+    }
+
+    #[actix_web::test]
+    async fn test_conflicting_repository_updates() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        state
+            .repository_service
+            .create_repository("settings".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").service(get_repository).service(update_repository)),
+        )
+        .await;
+
+        // Two of the owner's sessions read version 1
+        let req = test::TestRequest::get().uri("/api/repositories/settings").to_request();
+        let resp = test::call_service(&app, req).await;
+        let etag = resp.headers().get(header::ETAG).unwrap().to_str().unwrap().to_string();
+        assert_eq!(etag, "\"1\"");
+
+        let req = test::TestRequest::patch()
+            .uri("/api/repositories/settings")
+            .insert_header(basic_auth("owner"))
+            .insert_header((header::IF_MATCH, etag.clone()))
+            .set_json(serde_json::json!({ "description": "from the first session" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let updated: RepositoryResponse = test::read_body_json(resp).await;
+        assert_eq!(updated.version, 2);
+
+        let req = test::TestRequest::patch()
+            .uri("/api/repositories/settings")
+            .insert_header(basic_auth("owner"))
+            .set_json(serde_json::json!({ "description": "from the second session", "is_private": true, "version": 1 }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let conflict: RepositoryConflictResponse = test::read_body_json(resp).await;
+        assert_eq!(conflict.current.description.as_deref(), Some("from the first session"));
+        assert!(!conflict.current.is_private);
+        assert_eq!(conflict.current.version, 2);
+
+        // Without a version the update is refused outright
+        let req = test::TestRequest::patch()
+            .uri("/api/repositories/settings")
+            .insert_header(basic_auth("owner"))
+            .set_json(serde_json::json!({ "is_private": true }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::PRECONDITION_REQUIRED);
+    }
+
+    #[actix_web::test]
+    async fn test_repository_settings_take_the_owner_or_an_admin() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        create_user_with_password(&state, "stranger").await;
+        create_admin_with_password(&state, "admin").await;
+        for (name, visibility) in [("settings", Visibility::Public), ("secret", Visibility::Private)] {
+            state
+                .repository_service
+                .create_repository(name.to_string(), None, "main".to_string(), owner.id, visibility, None)
+                .await
+                .unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/api").service(update_repository)),
+        )
+        .await;
+        let patch = |name: &str, body: serde_json::Value| {
+            test::TestRequest::patch().uri(&format!("/api/repositories/{}", name)).set_json(body)
+        };
+
+        let mut version = 1;
+        for body in [
+            serde_json::json!({ "description": "changed" }),
+            serde_json::json!({ "visibility": "internal" }),
+            serde_json::json!({ "hidden_refs": ["refs/internal/"] }),
+            serde_json::json!({ "linear_history_branches": ["main"] }),
+            serde_json::json!({ "push_message": "hello" }),
+            serde_json::json!({ "allow_anonymous_clone": false }),
+            serde_json::json!({ "is_archived": false }),
+        ] {
+            let mut body = body;
+            body["version"] = version.into();
+
+            let req = patch("settings", body.clone()).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED, "{}", body);
+            let req = patch("settings", body.clone()).insert_header(basic_auth("stranger")).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN, "{}", body);
+
+            let req = patch("settings", body.clone()).insert_header(basic_auth("owner")).to_request();
+            let updated: RepositoryResponse = test::call_and_read_body_json(&app, req).await;
+            body["version"] = updated.version.into();
+            let req = patch("settings", body.clone()).insert_header(basic_auth("admin")).to_request();
+            let updated: RepositoryResponse = test::call_and_read_body_json(&app, req).await;
+            version = updated.version;
+        }
+
+        // Repositories the caller can't read stay hidden
+        let req = patch("secret", serde_json::json!({ "description": "changed", "version": 1 }))
+            .insert_header(basic_auth("stranger"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_visibility_levels_per_viewer() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let stranger = create_user_with_password(&state, "stranger").await;
+        let mut tokens = HashMap::new();
+        for user in [&owner, &stranger] {
+            let (_, token) = state
+                .token_service
+                .create_token(user.id, "test".to_string(), &["repo:write".to_string()], None)
+                .await
+                .unwrap();
+            tokens.insert(user.username.clone(), token);
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(info_refs).service(upload_pack))
+                .service(
+                    web::scope("/api")
+                        .wrap(actix_web::middleware::from_fn(crate::scopes::enforce_token_scopes))
+                        .service(list_repositories)
+                        .service(create_repository)
+                        .service(get_repository)
+                        .service(update_repository),
+                ),
+        )
+        .await;
+
+        // Creates take a visibility or, from older clients, is_private
+        for (name, body) in [
+            ("open", serde_json::json!({})),
+            ("company", serde_json::json!({ "visibility": "internal" })),
+            ("secret", serde_json::json!({ "is_private": true })),
+        ] {
+            let mut body = body;
+            body["name"] = name.into();
+            body["owner_id"] = owner.id.to_string().into();
+            let req = test::TestRequest::post().uri("/api/repositories").set_json(body).to_request();
+            let created: RepositoryResponse = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(created.is_private, name != "open");
+        }
+
+        let viewers = [
+            (None, vec!["open"]),
+            (Some("stranger"), vec!["company", "open"]),
+            (Some("owner"), vec!["company", "open", "secret"]),
+        ];
+        for (viewer, readable) in viewers {
+            let authorized = |req: test::TestRequest| match viewer {
+                Some(username) => req.insert_header((header::AUTHORIZATION, format!("Bearer {}", tokens[username]))),
+                None => req,
+            };
+            let who = viewer.unwrap_or("anonymous");
+
+            let req = authorized(test::TestRequest::get().uri("/api/repositories")).to_request();
+            let repos: Vec<RepositoryResponse> = test::call_and_read_body_json(&app, req).await;
+            let mut listed: Vec<&str> = repos.iter().map(|repo| repo.name.as_str()).collect();
+            listed.sort();
+            assert_eq!(listed, readable, "listed for {}", who);
+
+            for (name, visibility) in [
+                ("open", Visibility::Public),
+                ("company", Visibility::Internal),
+                ("secret", Visibility::Private),
+            ] {
+                let req = authorized(test::TestRequest::get().uri(&format!("/api/repositories/{}", name))).to_request();
+                let resp = test::call_service(&app, req).await;
+                if readable.contains(&name) {
+                    assert_eq!(resp.status(), StatusCode::OK, "{} reading {}", who, name);
+                    let repo: RepositoryResponse = test::read_body_json(resp).await;
+                    assert_eq!(repo.visibility, visibility);
+                    let permissions = repo.permissions.unwrap();
+                    assert!(permissions.read);
+                    assert_eq!(permissions.write, viewer == Some("owner"), "{} writing {}", who, name);
+                    assert!(!permissions.admin, "repo:write tokens never administer");
+                } else {
+                    assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{} reading {}", who, name);
+                }
+            }
+        }
+
+        // Only the owner changes the visibility; others can't even see a private repository
+        for (name, status) in [("company", StatusCode::FORBIDDEN), ("secret", StatusCode::NOT_FOUND)] {
+            let req = test::TestRequest::patch()
+                .uri(&format!("/api/repositories/{}", name))
+                .insert_header(basic_auth("stranger"))
+                .set_json(serde_json::json!({ "visibility": "public", "version": 1 }))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status, "opening up {}", name);
+        }
+        let req = test::TestRequest::get()
+            .uri("/api/repositories/secret")
+            .insert_header(basic_auth("owner"))
+            .to_request();
+        let repo: RepositoryResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(repo.visibility, Visibility::Private);
+
+        // Anonymous clones only reach public repositories
+        for (name, status) in [
+            ("open", StatusCode::OK),
+            ("company", StatusCode::NOT_FOUND),
+            ("secret", StatusCode::NOT_FOUND),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/git/{}/info/refs?service=git-upload-pack", name))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status, "cloning {}", name);
+            let req = test::TestRequest::post()
+                .uri(&format!("/git/{}/git-upload-pack", name))
+                .set_payload("0000")
+                .to_request();
+            let body = test::call_and_read_body(&app, req).await;
+            let refused = body.starts_with(b"001dERR repository not found\n");
+            assert_eq!(refused, status == StatusCode::NOT_FOUND, "fetching {}", name);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_git_errors_reach_clients_as_protocol_messages() {
+        use base64::prelude::{Engine as _, BASE64_STANDARD};
+
+        let state = create_test_state().await;
+        let password_hash = state.user_service.hash_password("s3cret").unwrap();
+        let mut users = Vec::new();
+        for name in ["owner", "stranger"] {
+            let user = state
+                .user_service
+                .create_user(name.to_string(), format!("{}@example.com", name), password_hash.clone(), None, false)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        state
+            .repository_service
+            .create_repository("shared".to_string(), None, "main".to_string(), users[0].id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(info_refs).service(receive_pack)),
+        )
+        .await;
+
+        // Before the exchange a real status is fine, with a body git can print
+        let req = test::TestRequest::get().uri("/git/missing/info/refs?service=git-upload-pack").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "text/plain");
+        assert_eq!(test::read_body(resp).await, "repository not found\n");
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let blob = handler.create_blob(b"shared\n").unwrap();
+        let push = |user: &str, capabilities: &str| {
+            let line = format!("{} {} refs/heads/main\0{}", git_protocol::ZERO_ID, blob.id, capabilities);
+            let mut body = protocol.create_pkt_line(&[&line]);
+            body.extend(protocol.create_pack(std::slice::from_ref(&blob)).unwrap());
+            let credentials = BASE64_STANDARD.encode(format!("{}:s3cret", user));
+            test::TestRequest::post()
+                .uri("/git/shared/git-receive-pack")
+                .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+                .set_payload(body)
+                .to_request()
+        };
+
+        // Mid-exchange the refusal is a 200 the client reads as a protocol message
+        let resp = test::call_service(&app, push("stranger", "report-status")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, b"001aERR permission denied\n0000".as_slice());
+        let resp = test::call_service(&app, push("stranger", "report-status side-band-64k")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, b"0017\x03permission denied\n0000".as_slice());
+
+        let report = test::call_and_read_body(&app, push("owner", "report-status")).await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/main"), "{:?}", report);
+    }
+
+    #[actix_web::test]
+    async fn test_archived_repository_refuses_pushes_but_serves_fetches() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        state
+            .repository_service
+            .create_repository("frozen".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["repo:admin".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(info_refs).service(upload_pack).service(receive_pack))
+                .service(
+                    web::scope("/api")
+                        .wrap(actix_web::middleware::from_fn(crate::scopes::enforce_token_scopes))
+                        .service(list_repositories)
+                        .service(update_repository),
+                ),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let tree = handler.parse_object(git_protocol::ObjectType::Tree, b"").unwrap();
+        let commit = |message: &str| {
+            handler
+                .create_commit(&git_protocol::objects::Commit {
+                    tree: tree.id.clone(),
+                    parents: vec![],
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: message.to_string(),
+                    author_date: chrono::Utc::now(),
+                    commit_date: chrono::Utc::now(),
+                    encoding: None,
+                })
+                .unwrap()
+        };
+        let push = |ref_name: &str, commit: &git_protocol::GitObject| {
+            let line = format!("{} {} {}\0report-status side-band-64k", git_protocol::ZERO_ID, commit.id, ref_name);
+            let mut body = protocol.create_pkt_line(&[&line]);
+            body.extend(protocol.create_pack(&[tree.clone(), commit.clone()]).unwrap());
+            test::TestRequest::post()
+                .uri("/git/frozen/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request()
+        };
+        let set_archived = |archived: bool, version: i32| {
+            test::TestRequest::patch()
+                .uri("/api/repositories/frozen")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({ "is_archived": archived, "version": version }))
+                .to_request()
+        };
+
+        let first = commit("Initial commit\n");
+        let report = test::call_and_read_body(&app, push("refs/heads/main", &first)).await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/main"));
+
+        let resp = test::call_service(&app, set_archived(true, 1)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let repo: RepositoryResponse = test::read_body_json(resp).await;
+        assert!(repo.is_archived);
+
+        // Pushes are refused with the reason on the error band
+        let second = commit("Too late\n");
+        let report = test::call_and_read_body(&app, push("refs/heads/late", &second)).await;
+        assert_eq!(report[4], 3);
+        assert!(String::from_utf8_lossy(&report).contains(ARCHIVED_MESSAGE));
+
+        // Fetches still work
+        let req = test::TestRequest::get().uri("/git/frozen/info/refs?service=git-upload-pack").to_request();
+        let advertisement = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&advertisement).contains(&first.id));
+        let req = test::TestRequest::post()
+            .uri("/git/frozen/git-upload-pack")
+            .set_payload(format!("0032want {}\n00000009done\n", first.id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let negotiation = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(&negotiation).contains("NAK"));
+
+        // Listings can pick archived repositories out
+        for (filter, expected) in [("true", 1), ("false", 0), ("all", 1)] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/repositories?archived={}", filter))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request();
+            let repos: Vec<RepositoryResponse> = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(repos.len(), expected, "archived={}", filter);
+        }
+
+        // Unarchiving lets pushes through again
+        let resp = test::call_service(&app, set_archived(false, 2)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report = test::call_and_read_body(&app, push("refs/heads/late", &second)).await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/late"));
+    }
+
+    #[actix_web::test]
+    async fn test_identical_clones_share_one_pack_build() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("cached".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let pack_cache = state.pack_cache.clone();
+        let repository_service = state.repository_service.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(upload_pack).service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let blob = handler.create_blob(b"cached\n").unwrap();
+        let tree = handler
+            .create_tree(&git_protocol::objects::Tree {
+                entries: vec![git_protocol::objects::TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "README".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = |message: &str, parents: Vec<String>| {
+            handler
+                .create_commit(&git_protocol::objects::Commit {
+                    tree: tree.id.clone(),
+                    parents,
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: message.to_string(),
+                    author_date: chrono::Utc::now(),
+                    commit_date: chrono::Utc::now(),
+                    encoding: None,
+                })
+                .unwrap()
+        };
+        let push = |ref_name: &str, old: &str, objects: &[git_protocol::GitObject]| {
+            let line = format!("{} {} {}\0report-status", old, objects[objects.len() - 1].id, ref_name);
+            let mut body = protocol.create_pkt_line(&[&line]);
+            body.extend(protocol.create_pack(objects).unwrap());
+            test::TestRequest::post()
+                .uri("/git/cached/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request()
+        };
+        let fetch = |want: &str, have: Option<&str>| {
+            let mut body = format!("0032want {}\n0000", want);
+            if let Some(have) = have {
+                body.push_str(&format!("0032have {}\n", have));
+            }
+            body.push_str("0009done\n");
+            test::TestRequest::post().uri("/git/cached/git-upload-pack").set_payload(body).to_request()
+        };
+        // NAK pkt-line, then the pack
+        let pack_ids = |response: &[u8]| -> Vec<String> {
+            assert_eq!(&response[..8], b"0008NAK\n");
+            let mut ids: Vec<String> = protocol
+                .parse_pack(&response[8..])
+                .unwrap()
+                .into_iter()
+                .map(|entry| handler.calculate_hash(entry.object_type, &entry.data).unwrap())
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        let first = commit("Initial commit\n", vec![]);
+        let report = test::call_and_read_body(
+            &app,
+            push("refs/heads/main", git_protocol::ZERO_ID, &[blob.clone(), tree.clone(), first.clone()]),
+        )
+        .await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/main"));
+
+        let mut expected = vec![blob.id.clone(), tree.id.clone(), first.id.clone()];
+        expected.sort();
+        let clone = test::call_and_read_body(&app, fetch(&first.id, None)).await;
+        assert_eq!(pack_ids(&clone), expected);
+        let again = test::call_and_read_body(&app, fetch(&first.id, None)).await;
+        assert_eq!(again, clone);
+        let metrics = pack_cache.snapshot();
+        assert_eq!((metrics.builds, metrics.hits, metrics.entries), (1, 1, 1));
+        assert_eq!(metrics.bytes_saved, (clone.len() - 8) as u64);
+
+        // The cache holds the raw pack, so a side-band client shares the entry and
+        // gets it framed in band 1
+        let mut body = protocol.create_pkt_line(&[&format!("want {} side-band-64k", first.id)]);
+        body.extend_from_slice(b"0009done\n");
+        let request = test::TestRequest::post().uri("/git/cached/git-upload-pack").set_payload(body).to_request();
+        let framed = test::call_and_read_body(&app, request).await;
+        assert_eq!(&framed[..8], b"0008NAK\n");
+        assert!(framed.ends_with(b"0000"));
+        assert_eq!(demux_sideband(&framed[8..])[&1], clone[8..]);
+        let metrics = pack_cache.snapshot();
+        assert_eq!((metrics.builds, metrics.hits), (1, 2));
+
+        // Any ref change, not just a push, moves the repository to a new generation
+        repository_service
+            .store_ref(repo.id, "refs/tags/v1".to_string(), first.id.clone(), false)
+            .await
+            .unwrap();
+        let retagged = test::call_and_read_body(&app, fetch(&first.id, None)).await;
+        assert_eq!(retagged, clone);
+        assert_eq!(pack_cache.snapshot().builds, 2);
+
+        // A push drops the repository's cached packs; the next fetch only gets what's new
+        let second = commit("Second commit\n", vec![first.id.clone()]);
+        let report = test::call_and_read_body(&app, push("refs/heads/main", &first.id, std::slice::from_ref(&second))).await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/main"));
+        assert_eq!(pack_cache.snapshot().entries, 0);
+
+        let update = test::call_and_read_body(&app, fetch(&second.id, Some(&first.id))).await;
+        assert_eq!(pack_ids(&update), vec![second.id.clone()]);
+        let metrics = pack_cache.snapshot();
+        assert_eq!((metrics.builds, metrics.hits), (3, 2));
+    }
+
+    #[actix_web::test]
+    async fn test_negotiation_rounds_get_no_pack_before_done() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        state
+            .repository_service
+            .create_repository("talks".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(upload_pack).service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let blob = handler.create_blob(b"talks\n").unwrap();
+        let tree = handler
+            .create_tree(&git_protocol::objects::Tree {
+                entries: vec![git_protocol::objects::TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "README".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = |message: &str, parents: Vec<String>| {
+            handler
+                .create_commit(&git_protocol::objects::Commit {
+                    tree: tree.id.clone(),
+                    parents,
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: message.to_string(),
+                    author_date: chrono::Utc::now(),
+                    commit_date: chrono::Utc::now(),
+                    encoding: None,
+                })
+                .unwrap()
+        };
+        let first = commit("Initial commit\n", vec![]);
+        let second = commit("Second commit\n", vec![first.id.clone()]);
+        let line = format!("{} {} refs/heads/main\0report-status", git_protocol::ZERO_ID, second.id);
+        let mut body = protocol.create_pkt_line(&[&line]);
+        body.extend(protocol.create_pack(&[blob, tree.clone(), first.clone(), second.clone()]).unwrap());
+        let push = test::TestRequest::post()
+            .uri("/git/talks/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .set_payload(body)
+            .to_request();
+        assert!(String::from_utf8_lossy(&test::call_and_read_body(&app, push).await).contains("ok refs/heads/main"));
+
+        let fetch = |capabilities: &str, haves: &[&str], done: bool| {
+            let mut body = protocol.create_pkt_line(&[&format!("want {}{}", second.id, capabilities)]);
+            let mut lines: Vec<String> = haves.iter().map(|have| format!("have {}", have)).collect();
+            if done {
+                lines.push("done".to_string());
+            }
+            body.extend(protocol.create_pkt_line(&lines.iter().map(String::as_str).collect::<Vec<_>>()));
+            test::TestRequest::post().uri("/git/talks/git-upload-pack").set_payload(body).to_request()
+        };
+        let unknown = "1".repeat(40);
+
+        // A round without done is answered with ACK/NAK lines and nothing else
+        let round = test::call_and_read_body(&app, fetch("", &[&unknown, &first.id], false)).await;
+        assert_eq!(round, protocol.create_ack(&first.id));
+        let round = test::call_and_read_body(&app, fetch("", &[&unknown], false)).await;
+        assert_eq!(round, protocol.create_nak());
+        let round = test::call_and_read_body(&app, fetch(" multi_ack", &[&first.id, &unknown], false)).await;
+        let mut expected = protocol.create_ack_continue(&first.id);
+        expected.extend(protocol.create_nak());
+        assert_eq!(round, expected);
+
+        // The pack waits for done
+        let fetched = test::call_and_read_body(&app, fetch("", &[&first.id], true)).await;
+        assert_eq!(&fetched[..8], b"0008NAK\n");
+        let ids: Vec<String> = protocol
+            .parse_pack(&fetched[8..])
+            .unwrap()
+            .into_iter()
+            .map(|entry| handler.calculate_hash(entry.object_type, &entry.data).unwrap())
+            .collect();
+        assert_eq!(ids, vec![second.id.clone()]);
+    }
+
+    #[actix_web::test]
+    async fn test_clones_past_the_concurrency_limit_queue_then_get_503() {
+        let mut state = create_test_state().await;
+        state.clone_limiter = Arc::new(CloneLimiter::new(Some(2), std::time::Duration::from_millis(300)));
+        let owner = create_user_with_password(&state, "owner").await;
+        let repo = state
+            .repository_service
+            .create_repository("popular".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let clone_limiter = state.clone_limiter.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(upload_pack).service(receive_pack)),
+        )
+        .await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let blob = handler.create_blob(b"popular\n").unwrap();
+        let tree = handler
+            .create_tree(&git_protocol::objects::Tree {
+                entries: vec![git_protocol::objects::TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "README".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = handler
+            .create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let line = format!("{} {} refs/heads/main\0report-status", git_protocol::ZERO_ID, commit.id);
+        let mut body = protocol.create_pkt_line(&[&line]);
+        body.extend(protocol.create_pack(&[blob, tree, commit.clone()]).unwrap());
+        let push = test::TestRequest::post()
+            .uri("/git/popular/git-receive-pack")
+            .insert_header(basic_auth("owner"))
+            .set_payload(body)
+            .to_request();
+        let report = test::call_and_read_body(&app, push).await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/main"));
+
+        let fetch = || {
+            test::TestRequest::post()
+                .uri("/git/popular/git-upload-pack")
+                .set_payload(format!("0032want {}\n00000009done\n", commit.id))
+                .to_request()
+        };
+
+        // Two clones are running, so a third waits for one of them to finish
+        let first = clone_limiter.acquire(repo.id).await.unwrap();
+        let second = clone_limiter.acquire(repo.id).await.unwrap();
+        let (resp, _) = tokio::join!(test::call_service(&app, fetch()), async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            drop(first);
+        });
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // With both slots held past the queue timeout it is turned away
+        let _third = clone_limiter.acquire(repo.id).await.unwrap();
+        let resp = test::call_service(&app, fetch()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "10");
+
+        drop(second);
+        let resp = test::call_service(&app, fetch()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_protocol_features_can_be_switched_off() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        state
+            .repository_service
+            .create_repository("features".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let mut restricted = state.clone();
+        restricted.config = Arc::new(Config {
+            protocol: ProtocolSettings {
+                allow_shallow: false,
+                allow_deletes: false,
+                ..ProtocolSettings::default()
+            },
+            ..(*state.config).clone()
+        });
+        let git_routes = || web::scope("/git").service(info_refs).service(upload_pack).service(receive_pack);
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).service(git_routes())).await;
+        let restricted =
+            test::init_service(App::new().app_data(web::Data::new(restricted)).service(git_routes())).await;
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let blob = handler.create_blob(b"features\n").unwrap();
+        let tree = handler
+            .create_tree(&git_protocol::objects::Tree {
+                entries: vec![git_protocol::objects::TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "README".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = |message: &str, parents: Vec<String>| {
+            handler
+                .create_commit(&git_protocol::objects::Commit {
+                    tree: tree.id.clone(),
+                    parents,
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: message.to_string(),
+                    author_date: chrono::Utc::now(),
+                    commit_date: chrono::Utc::now(),
+                    encoding: None,
+                })
+                .unwrap()
+        };
+        let first = commit("first\n", vec![]);
+        let second = commit("second\n", vec![first.id.clone()]);
+        let push = |commands: &[String], objects: &[git_protocol::GitObject]| {
+            let mut lines = commands.to_vec();
+            lines[0] = format!("{}\0report-status", lines[0]);
+            let mut body = protocol.create_pkt_line(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+            if !objects.is_empty() {
+                body.extend(protocol.create_pack(objects).unwrap());
+            }
+            test::TestRequest::post()
+                .uri("/git/features/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request()
+        };
+        let create = |name: &str, id: &str| format!("{} {} refs/heads/{}", git_protocol::ZERO_ID, id, name);
+        let objects = [blob.clone(), tree.clone(), first.clone(), second.clone()];
+        let report = test::call_and_read_body(&app, push(&[create("main", &second.id), create("doomed", &first.id)], &objects)).await;
+        assert_eq!(String::from_utf8_lossy(&report).matches("ok refs/heads/").count(), 2);
+
+        // Capabilities are the part of the first ref line after the NUL
+        let advertised = |body: &[u8]| -> Vec<String> {
+            let text = String::from_utf8_lossy(body);
+            let after_nul = text.split_once('\0').unwrap().1;
+            after_nul.split('\n').next().unwrap().split_whitespace().map(String::from).collect()
+        };
+        let advertisement = |service: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/git/features/info/refs?service={}", service))
+                .insert_header(basic_auth("owner"))
+                .to_request()
+        };
+        let upload = advertised(&test::call_and_read_body(&app, advertisement("git-upload-pack")).await);
+        assert!(upload.contains(&"shallow".to_string()) && upload.contains(&"filter".to_string()));
+        let upload = advertised(&test::call_and_read_body(&restricted, advertisement("git-upload-pack")).await);
+        assert!(!upload.contains(&"shallow".to_string()) && upload.contains(&"filter".to_string()));
+        let receive = advertised(&test::call_and_read_body(&restricted, advertisement("git-receive-pack")).await);
+        assert!(!receive.contains(&"delete-refs".to_string()) && receive.contains(&"report-status".to_string()));
+
+        let fetch = |extra: &str| {
+            let mut body = protocol.create_pkt_line(&[&format!("want {} shallow", second.id), extra]);
+            body.extend_from_slice(b"0009done\n");
+            test::TestRequest::post().uri("/git/features/git-upload-pack").set_payload(body).to_request()
+        };
+        let response = test::call_and_read_body(&restricted, fetch("deepen 1")).await;
+        assert_eq!(response, protocol.create_error("upload-pack: shallow fetches are disabled on this server"));
+        let response = test::call_and_read_body(&app, fetch("deepen-since 1700000000")).await;
+        assert_eq!(response, protocol.create_error("upload-pack: deepen-since is not supported"));
+
+        // Where shallow fetches are allowed, depth 1 is the tip alone
+        let pack_ids = |pack: &[u8]| -> Vec<String> {
+            let mut ids: Vec<String> = protocol
+                .parse_pack(pack)
+                .unwrap()
+                .into_iter()
+                .map(|entry| handler.calculate_hash(entry.object_type, &entry.data).unwrap())
+                .collect();
+            ids.sort();
+            ids
+        };
+        let response = test::call_and_read_body(&app, fetch("deepen 1")).await;
+        let mut prefix = protocol.create_shallow_info(std::slice::from_ref(&second.id), &[]);
+        prefix.extend(protocol.create_nak());
+        assert!(response.starts_with(&prefix));
+        let mut expected = vec![blob.id.clone(), tree.id.clone(), second.id.clone()];
+        expected.sort();
+        assert_eq!(pack_ids(&response[prefix.len()..]), expected);
+
+        let response = test::call_and_read_body(&app, fetch("filter blob:none")).await;
+        let mut expected = vec![tree.id.clone(), first.id.clone(), second.id.clone()];
+        expected.sort();
+        assert_eq!(pack_ids(&response[8..]), expected);
+
+        let delete = [format!("{} {} refs/heads/doomed", first.id, git_protocol::ZERO_ID)];
+        let report = test::call_and_read_body(&restricted, push(&delete, &[])).await;
+        assert!(String::from_utf8_lossy(&report).contains("ng refs/heads/doomed deletion prohibited"));
+        let report = test::call_and_read_body(&app, push(&delete, &[])).await;
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/doomed"));
+    }
+
+    #[actix_web::test]
+    async fn test_deleted_repositories_are_hidden_until_restored() {
+        let state = create_test_state().await;
+        let owner = state
+            .user_service
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let admin = state
+            .user_service
+            .create_user("root".to_string(), "root@example.com".to_string(), "hash".to_string(), None, true)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("doomed".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let token = |user_id: uuid::Uuid, scope: &str| {
+            let token_service = state.token_service.clone();
+            let scope = scope.to_string();
+            async move { token_service.create_token(user_id, "test".to_string(), &[scope], None).await.unwrap().1 }
+        };
+        let owner_token = token(owner.id, "repo:admin").await;
+        let admin_token = token(admin.id, "admin").await;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(info_refs))
+                .service(
+                    web::scope("/api")
+                        .wrap(actix_web::middleware::from_fn(crate::scopes::enforce_token_scopes))
+                        .service(list_deleted_repositories)
+                        .service(restore_repository)
+                        .service(list_repositories)
+                        .service(get_repository)
+                        .service(create_repository)
+                        .service(delete_repository),
+                ),
+        )
+        .await;
+
+        let visible = |app_token: &str| {
+            test::TestRequest::get()
+                .uri("/api/repositories/doomed")
+                .insert_header(("Authorization", format!("Bearer {}", app_token)))
+                .to_request()
+        };
+        let clone = || test::TestRequest::get().uri("/git/doomed/info/refs?service=git-upload-pack").to_request();
+        let delete = |app_token: &str| {
+            test::TestRequest::delete()
+                .uri(&format!("/api/repositories/{}", repo.id))
+                .insert_header(("Authorization", format!("Bearer {}", app_token)))
+                .to_request()
+        };
+
+        // Only the owner or an admin may delete it
+        let outsider = test::TestRequest::delete().uri(&format!("/api/repositories/{}", repo.id)).to_request();
+        assert_eq!(test::call_service(&app, outsider).await.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, delete(&owner_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let deleted: DeletedRepositoryResponse = test::read_body_json(resp).await;
+        assert_eq!(deleted.name, "doomed");
+        let deleted_at = chrono::DateTime::parse_from_rfc3339(&deleted.deleted_at).unwrap();
+        let purge_after = chrono::DateTime::parse_from_rfc3339(&deleted.purge_after).unwrap();
+        assert_eq!(purge_after - deleted_at, chrono::Duration::days(7));
+
+        // Gone from lookups, listings and git URLs
+        assert_eq!(test::call_service(&app, visible(&owner_token)).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test::call_service(&app, clone()).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test::call_service(&app, delete(&owner_token)).await.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::get()
+            .uri("/api/repositories")
+            .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+            .to_request();
+        let listed: Vec<RepositoryResponse> = test::call_and_read_body_json(&app, req).await;
+        assert!(listed.is_empty());
+
+        let list_deleted = |app_token: &str| {
+            test::TestRequest::get()
+                .uri("/api/admin/repositories/deleted")
+                .insert_header(("Authorization", format!("Bearer {}", app_token)))
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, list_deleted(&owner_token)).await.status(), StatusCode::FORBIDDEN);
+        let listed: Vec<DeletedRepositoryResponse> = test::call_and_read_body_json(&app, list_deleted(&admin_token)).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, repo.id.to_string());
+
+        // A new repository may take the name, which blocks restoring until it is deleted too
+        let req = test::TestRequest::post()
+            .uri("/api/repositories")
+            .set_json(serde_json::json!({ "name": "doomed", "owner_id": owner.id.to_string() }))
+            .to_request();
+        let replacement: RepositoryResponse = test::call_and_read_body_json(&app, req).await;
+        assert_ne!(replacement.id, repo.id.to_string());
+        let restore = || {
+            test::TestRequest::post()
+                .uri(&format!("/api/admin/repositories/{}/restore", repo.id))
+                .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, restore()).await.status(), StatusCode::CONFLICT);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/repositories/{}", replacement.id))
+            .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let restored: RepositoryResponse = test::call_and_read_body_json(&app, restore()).await;
+        assert_eq!(restored.id, repo.id.to_string());
+        assert_eq!(restored.name, "doomed");
+        assert_eq!(test::call_service(&app, visible(&owner_token)).await.status(), StatusCode::OK);
+        assert_eq!(test::call_service(&app, clone()).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_quotas_limit_repository_count_and_pushed_bytes() {
+        let mut state = create_test_state().await;
+        state.config = Arc::new(Config {
+            max_repositories_per_user: Some(2),
+            max_bytes_per_user: Some(1000),
+            ..(*state.config).clone()
+        });
+        let owner = create_user_with_password(&state, "owner").await;
+        let admin = state
+            .user_service
+            .create_user("root".to_string(), "root@example.com".to_string(), "hash".to_string(), None, true)
+            .await
+            .unwrap();
+        let (_, owner_token) = state
+            .token_service
+            .create_token(owner.id, "test".to_string(), &["user:read".to_string()], None)
+            .await
+            .unwrap();
+        let (_, admin_token) = state
+            .token_service
+            .create_token(admin.id, "test".to_string(), &["admin".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/git").service(receive_pack))
+                .service(
+                    web::scope("/api")
+                        .wrap(actix_web::middleware::from_fn(crate::scopes::enforce_token_scopes))
+                        .service(create_repository)
+                        .service(get_my_quota)
+                        .service(set_user_quota),
+                ),
+        )
+        .await;
+
+        let create = |name: &str, owner_id: uuid::Uuid| {
+            test::TestRequest::post()
+                .uri("/api/repositories")
+                .set_json(serde_json::json!({ "name": name, "owner_id": owner_id.to_string() }))
+                .to_request()
+        };
+        let quota = || {
+            test::TestRequest::get()
+                .uri("/api/users/me/quota")
+                .insert_header(("Authorization", format!("Bearer {}", owner_token)))
+                .to_request()
+        };
+
+        // The third repository is over the count
+        for name in ["one", "two"] {
+            assert_eq!(test::call_service(&app, create(name, owner.id)).await.status(), StatusCode::CREATED);
+        }
+        let resp = test::call_service(&app, create("three", owner.id)).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "repository_quota_exceeded");
+
+        // Admins have no quota
+        for name in ["a", "b", "c"] {
+            assert_eq!(test::call_service(&app, create(name, admin.id)).await.status(), StatusCode::CREATED);
+        }
+
+        let handler = ObjectHandler::new();
+        let protocol = ProtocolHandler::new();
+        let push = |content: &[u8], ref_name: &str| {
+            let blob = handler.create_blob(content).unwrap();
+            let tree = handler
+                .create_tree(&git_protocol::objects::Tree {
+                    entries: vec![git_protocol::objects::TreeEntry {
+                        mode: "100644".to_string(),
+                        name: "file.bin".to_string(),
+                        hash: blob.id.clone(),
+                    }],
+                })
+                .unwrap();
+            let commit = handler
+                .create_commit(&git_protocol::objects::Commit {
+                    tree: tree.id.clone(),
+                    parents: vec![],
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: "Add file\n".to_string(),
+                    author_date: chrono::Utc::now(),
+                    commit_date: chrono::Utc::now(),
+                    encoding: None,
+                })
+                .unwrap();
+            let line = format!("{} {} {}\0report-status", git_protocol::ZERO_ID, commit.id, ref_name);
+            let mut body = protocol.create_pkt_line(&[&line]);
+            body.extend(protocol.create_pack(&[blob, tree, commit]).unwrap());
+            test::TestRequest::post()
+                .uri("/git/one/git-receive-pack")
+                .insert_header(basic_auth("owner"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let report = test::call_and_read_body(&app, push(b"small\n", "refs/heads/main")).await;
+        assert!(String::from_utf8_lossy(&report).contains("unpack ok"));
+        let usage: serde_json::Value = test::call_and_read_body_json(&app, quota()).await;
+        assert_eq!(usage["repositories"], 2);
+        assert_eq!(usage["max_repositories"], 2);
+        assert_eq!(usage["max_total_bytes"], 1000);
+        let used = usage["total_bytes"].as_u64().unwrap();
+        assert!(used > 0 && used < 1000);
+
+        // A pack that would take the owner over their bytes is refused whole
+        let report = test::call_and_read_body(&app, push(&[b'x'; 2000], "refs/heads/big")).await;
+        let report = String::from_utf8_lossy(&report);
+        assert!(report.contains("unpack disk_quota_exceeded"), "{report}");
+        assert!(report.contains("ng refs/heads/big"));
+        let usage: serde_json::Value = test::call_and_read_body_json(&app, quota()).await;
+        assert_eq!(usage["total_bytes"].as_u64(), Some(used));
+
+        // An admin override of 0 lifts the limit
+        let req = test::TestRequest::put()
+            .uri(&format!("/api/admin/users/{}/quota", owner.id))
+            .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+            .set_json(serde_json::json!({ "max_repositories": 0 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(test::call_service(&app, create("three", owner.id)).await.status(), StatusCode::CREATED);
+        let usage: serde_json::Value = test::call_and_read_body_json(&app, quota()).await;
+        assert_eq!(usage["max_repositories"], serde_json::Value::Null);
+    }
+
+    #[actix_web::test]
+    async fn test_import_users_reports_each_row() {
+        let state = create_test_state().await;
+        let admin = state
+            .user_service
+            .create_user("root".to_string(), "root@example.com".to_string(), "hash".to_string(), None, true)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(admin.id, "test".to_string(), &["admin".to_string()], None)
+            .await
+            .unwrap();
+        let user_service = state.user_service.clone();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(actix_web::middleware::from_fn(crate::scopes::enforce_token_scopes))
+                    .service(import_users),
+            ),
+        )
+        .await;
+        let import = |query: &str, users: serde_json::Value| {
+            test::TestRequest::post()
+                .uri(&format!("/api/users:import{}", query))
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(users)
+                .to_request()
+        };
+        let bcrypt_hash = user_service.hash_password("secret1").unwrap();
+
+        // All-or-nothing leaves every row out when one fails
+        let batch = serde_json::json!([
+            {"username": "alice", "email": "alice@example.com", "password_hash": bcrypt_hash, "full_name": "Alice"},
+            {"username": "bob", "email": "ROOT@example.com"},
+        ]);
+        let report: UserImportReport = test::call_and_read_body_json(&app, import("?all_or_nothing=true", batch)).await;
+        assert_eq!((report.created, report.failed, report.skipped), (0, 1, 1));
+        assert_eq!(report.rows[0].status, UserImportStatus::Skipped);
+        assert!(user_service.get_user_by_username("alice").await.unwrap().is_none());
+
+        let batch = serde_json::json!([
+            {"username": "alice", "email": "alice@example.com", "password_hash": bcrypt_hash, "full_name": "Alice"},
+            {"username": "bob", "email": "bob@example.com", "password_hash": "hashed_legacy", "is_admin": true},
+            {"username": "alice", "email": "alice2@example.com"},
+            {"username": "carol", "email": "carol@example.com", "password_hash": "plaintext"},
+            {"username": "dave", "email": "dave@example.com"},
+        ]);
+        let report: UserImportReport = test::call_and_read_body_json(&app, import("", batch)).await;
+        assert_eq!((report.created, report.failed, report.skipped), (3, 2, 0));
+        let statuses: Vec<_> = report.rows.iter().map(|row| row.status).collect();
+        use UserImportStatus::{Created, Failed};
+        assert_eq!(statuses, vec![Created, Created, Failed, Failed, Created]);
+        assert_eq!(report.rows[2].message.as_deref(), Some("username alice appears earlier in the import"));
+
+        // Imported hashes sign in without being hashed again
+        let alice = user_service.authenticate("alice", "secret1").await.unwrap().unwrap();
+        assert_eq!(Some(alice.id), report.rows[0].id);
+        assert_eq!(alice.full_name.as_deref(), Some("Alice"));
+        assert!(user_service.authenticate("bob", "legacy").await.unwrap().unwrap().is_admin);
+        assert!(user_service.get_user_by_username("dave").await.unwrap().is_some());
+        assert!(user_service.authenticate("dave", "").await.unwrap().is_none());
+
+        // Rows against existing users fail on their own
+        let batch = serde_json::json!([{"username": "dave", "email": "dave3@example.com"}]);
+        let report: UserImportReport = test::call_and_read_body_json(&app, import("", batch)).await;
+        assert_eq!(report.rows[0].message.as_deref(), Some("username dave already exists"));
+    }
+
+    #[actix_web::test]
+    async fn test_ssh_keys_are_listed_with_openssh_fingerprints() {
+        let state = create_test_state().await;
+        let alice = state
+            .user_service
+            .create_user("alice".to_string(), "alice@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let bob = state
+            .user_service
+            .create_user("bob".to_string(), "bob@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let mut tokens = Vec::new();
+        for user in [&alice, &bob] {
+            let scopes = ["user:read".to_string(), "user:write".to_string()];
+            tokens.push(state.token_service.create_token(user.id, "test".to_string(), &scopes, None).await.unwrap().1);
+        }
+        // Fingerprints as `ssh-keygen -lf` prints them for these keys
+        let keys = [
+            (
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIDpigkdpRKMP2h3tDCpXoEWNXMKBDeb69FjBTWJII7IV alice@laptop",
+                "SHA256:bd0Q2E4PFNyaPki1V3OwHZ365AvB1PpBn5cw7J0wl98",
+            ),
+            (
+                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQDV1iZC+iosuC9tT+B0GQB9FphgvOm0asY4FWCYeiXQ+VJ+IhYeEjkQ+pw4HxDhre05au6NgpGVoWvMCHm5T9nUHVdgxJWMTVcfWfNJNcnNHFhol1FN0tK/CwOf0U//HfNx/h7flzTteTD/l3Qa7K1AfkZLZeruWvDUn35Duyg3sQ== rsa",
+                "SHA256:s9696mg2C+lHyCPE/fHcUcrQv538H40YZpSLkB1NOEc",
+            ),
+        ];
+        let allowed = state.config.ssh_key_types.clone();
+        for (line, _) in keys {
+            let key = authorized_keys::parse_line(line, &allowed).unwrap().unwrap();
+            state.ssh_key_service.add_key(alice.id, key).await.unwrap().unwrap();
+        }
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(actix_web::middleware::from_fn(crate::scopes::enforce_token_scopes))
+                    .service(list_ssh_keys)
+                    .service(delete_ssh_key),
+            ),
+        )
+        .await;
+        let request = |req: test::TestRequest, token: &str| {
+            req.insert_header(("Authorization", format!("Bearer {}", token))).to_request()
+        };
+
+        let listed: Vec<SshKeyResponse> =
+            test::call_and_read_body_json(&app, request(test::TestRequest::get().uri("/api/users/alice/keys"), &tokens[0]))
+                .await;
+        let fingerprints: Vec<_> = listed.iter().map(|key| key.fingerprint.as_str()).collect();
+        assert_eq!(fingerprints, vec![keys[0].1, keys[1].1]);
+        assert_eq!(listed[0].title.as_deref(), Some("alice@laptop"));
+        assert_eq!(listed[1].key_type, "ssh-rsa");
+
+        // Only alice (or an admin) sees and removes her keys
+        let resp = test::call_service(&app, request(test::TestRequest::get().uri("/api/users/alice/keys"), &tokens[1])).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let delete = |id: uuid::Uuid| test::TestRequest::delete().uri(&format!("/api/users/alice/keys/{}", id));
+        let resp = test::call_service(&app, request(delete(listed[0].id), &tokens[1])).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(&app, request(delete(listed[0].id), &tokens[0])).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, request(delete(listed[0].id), &tokens[0])).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let listed: Vec<SshKeyResponse> =
+            test::call_and_read_body_json(&app, request(test::TestRequest::get().uri("/api/users/alice/keys"), &tokens[0]))
+                .await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].fingerprint, keys[1].1);
+    }
+
+    #[actix_web::test]
+    async fn test_import_authorized_keys() {
+        use russh_keys::PublicKeyBase64;
+
+        let state = create_test_state().await;
+        let admin = state
+            .user_service
+            .create_user("root".to_string(), "root@example.com".to_string(), "hash".to_string(), None, true)
+            .await
+            .unwrap();
+        let alice = state
+            .user_service
+            .create_user("alice".to_string(), "alice@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(admin.id, "test".to_string(), &["admin".to_string()], None)
+            .await
+            .unwrap();
+        let ssh_key_service = state.ssh_key_service.clone();
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(actix_web::middleware::from_fn(crate::scopes::enforce_token_scopes))
+                    .service(import_ssh_keys),
+            ),
+        )
+        .await;
+
+        let keys: Vec<_> = (0..2)
+            .map(|_| russh_keys::key::KeyPair::generate_ed25519().unwrap().clone_public_key().unwrap())
+            .collect();
+        let authorized_keys = format!(
+            "# laptop and desktop\n\
+             ssh-ed25519 {} alice@laptop\n\
+             \n\
+             ssh-ed25519 {}\n\
+             ssh-ed25519 not-base64!\n\
+             ssh-rsa {} mislabeled\n\
+             no-pty ssh-ed25519 {} restricted\n\
+             ssh-ed25519 {} alice@laptop again\n",
+            keys[0].public_key_base64(),
+            keys[1].public_key_base64(),
+            keys[0].public_key_base64(),
+            keys[0].public_key_base64(),
+            keys[0].public_key_base64(),
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/api/users/alice/keys:import")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_payload(authorized_keys)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: KeyImportReport = test::read_body_json(resp).await;
+        assert_eq!((report.imported, report.duplicates, report.failed), (2, 1, 3));
+        let statuses: Vec<_> = report.lines.iter().map(|l| (l.line, l.status)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (2, KeyImportStatus::Imported),
+                (4, KeyImportStatus::Imported),
+                (5, KeyImportStatus::Failed),
+                (6, KeyImportStatus::Failed),
+                (7, KeyImportStatus::Failed),
+                (8, KeyImportStatus::Duplicate),
+            ]
+        );
+        assert_eq!(report.lines[3].message.as_deref(), Some("key data is a ssh-ed25519 key, not ssh-rsa"));
+        assert_eq!(report.lines[4].message.as_deref(), Some("key options are not supported"));
+
+        let registered = ssh_key_service.list_keys(alice.id).await.unwrap();
+        assert_eq!(registered.len(), 2);
+        let fingerprints: Vec<_> = registered.iter().map(|k| k.fingerprint.clone()).collect();
+        assert_eq!(
+            fingerprints,
+            keys.iter().map(|k| format!("SHA256:{}", k.fingerprint())).collect::<Vec<_>>()
+        );
+        assert_eq!(registered[0].comment.as_deref(), Some("alice@laptop"));
+        assert_eq!(registered[1].comment, None);
+
+        // A key can only belong to one account
+        let req = test::TestRequest::post()
+            .uri("/api/users/root/keys:import")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_payload(format!("ssh-ed25519 {}\n", keys[1].public_key_base64()))
+            .to_request();
+        let report: KeyImportReport = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report.failed, 1);
+        assert!(ssh_key_service.list_keys(admin.id).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_avatar_upload_sizes_and_identicon_fallback() {
+        let state = create_test_state().await;
+        let mut tokens = Vec::new();
+        for name in ["alice", "bob"] {
+            let user = state
+                .user_service
+                .create_user(name.to_string(), format!("{}@example.com", name), "hash".to_string(), None, false)
+                .await
+                .unwrap();
+            let (_, token) = state
+                .token_service
+                .create_token(user.id, "test".to_string(), &["user:write".to_string()], None)
+                .await
+                .unwrap();
+            tokens.push(token);
+        }
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api")
+                    .wrap(actix_web::middleware::from_fn(crate::scopes::enforce_token_scopes))
+                    .service(get_user_avatar)
+                    .service(set_user_avatar),
+            ),
+        )
+        .await;
+        let fetch = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+        let upload = |token: &str, content_type: &str, body: Vec<u8>| {
+            test::TestRequest::put()
+                .uri("/api/users/alice/avatar")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .insert_header((header::CONTENT_TYPE, content_type.to_string()))
+                .set_payload(body)
+                .to_request()
+        };
+        let png = |width: u32, height: u32| {
+            let image = image::RgbImage::from_pixel(width, height, image::Rgb([200, 30, 30]));
+            let mut data = Vec::new();
+            image
+                .write_to(&mut std::io::Cursor::new(&mut data), image::ImageFormat::Png)
+                .unwrap();
+            data
+        };
+        let dimensions = |data: &[u8]| image::load_from_memory(data).unwrap().to_rgb8().dimensions();
+
+        // Without an upload everyone gets an identicon, the same one every time
+        let resp = test::call_service(&app, fetch("/api/users/alice/avatar")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "image/png");
+        let identicon = test::read_body(resp).await;
+        assert_eq!(dimensions(&identicon), (64, 64));
+        assert_eq!(test::call_and_read_body(&app, fetch("/api/users/alice/avatar")).await, identicon);
+        assert_ne!(test::call_and_read_body(&app, fetch("/api/users/bob/avatar")).await, identicon);
+        let resp = test::call_service(&app, fetch("/api/users/nobody/avatar")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Uploads are checked before anything is stored
+        let resp = test::call_service(&app, upload(&tokens[0], "text/plain", png(10, 10))).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let resp = test::call_service(&app, upload(&tokens[0], "image/png", b"not an image".to_vec())).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let too_big = vec![0; avatars::MAX_AVATAR_UPLOAD_BYTES + 1];
+        let resp = test::call_service(&app, upload(&tokens[0], "image/png", too_big)).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let too_wide = png(avatars::MAX_AVATAR_DIMENSION + 1, 1);
+        let resp = test::call_service(&app, upload(&tokens[0], "image/png", too_wide)).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp = test::call_service(&app, upload(&tokens[1], "image/png", png(10, 10))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(&app, upload(&tokens[0], "image/png", png(300, 200))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let uploaded: AvatarResponse = test::read_body_json(resp).await;
+        assert_eq!(uploaded.avatar_version, 1);
+        assert_eq!(uploaded.avatar_url, "/api/users/alice/avatar?v=1");
+
+        // Served cropped square, at the stored size covering the one asked for
+        let resp = test::call_service(&app, fetch("/api/users/alice/avatar?s=64&v=1")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=31536000, immutable"
+        );
+        let etag = resp.headers().get(header::ETAG).unwrap().clone();
+        let small = test::read_body(resp).await;
+        assert_eq!(dimensions(&small), (64, 64));
+        assert_ne!(small, identicon);
+        let large = test::call_and_read_body(&app, fetch("/api/users/alice/avatar?s=200")).await;
+        assert_eq!(dimensions(&large), (256, 256));
+
+        // A stale version is only cached briefly, and unchanged images revalidate
+        let resp = test::call_service(&app, fetch("/api/users/alice/avatar?s=64&v=0")).await;
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "public, max-age=300");
+        let req = test::TestRequest::get()
+            .uri("/api/users/alice/avatar?s=64")
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_MODIFIED);
+    }
+}
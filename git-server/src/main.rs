@@ -1,23 +1,71 @@
+mod admin;
+mod clone_limit;
+mod authorized_keys;
+mod avatars;
 mod config;
 mod http;
 mod ssh;
 mod auth;
+mod client;
 mod git_api;
+mod guardrails;
+mod pack_cache;
+mod permissions;
+mod payload;
+mod post_receive;
+mod quota;
+mod scopes;
 
 use actix_files::Files;
-use actix_web::{web, App, HttpServer};
+use actix_web::{middleware::from_fn, web, App, HttpServer};
 use actix_session::{config::PersistentSession, storage::CookieSessionStore, SessionMiddleware};
 use actix_web::cookie::{Key, time::Duration};
 use anyhow::Context;
-use git_storage::{init_db, run_migrations, RepositoryService, UserService};
+use client::{ClientMetrics, ClientPolicy};
+use config::Config;
+use guardrails::GuardrailMetrics;
+use pack_cache::PackCache;
+use clone_limit::CloneLimiter;
+use post_receive::PostReceiveHooks;
+use git_protocol::{HashAlgorithm, ProtocolHandler};
+use git_storage::{
+    init_db_with_busy_timeout, pending_migrations, run_migrations, EventService, IdempotencyService,
+    IdentityService, RepositoryService, RetentionService, SshKeyService, StatusService, TokenService,
+    UserService,
+};
 use std::sync::Arc;
-use tracing::{info, Level};
-use tracing_subscriber;
+use tracing::{info, warn, Level};
 
 #[derive(Clone)]
 pub struct AppState {
     pub repository_service: Arc<RepositoryService>,
     pub user_service: Arc<UserService>,
+    pub idempotency_service: Arc<IdempotencyService>,
+    pub event_service: Arc<EventService>,
+    pub retention_service: Arc<RetentionService>,
+    pub token_service: Arc<TokenService>,
+    pub identity_service: Arc<IdentityService>,
+    pub ssh_key_service: Arc<SshKeyService>,
+    pub status_service: Arc<StatusService>,
+    pub client_policy: Arc<ClientPolicy>,
+    pub client_metrics: Arc<ClientMetrics>,
+    pub guardrail_metrics: Arc<GuardrailMetrics>,
+    pub pack_cache: Arc<PackCache>,
+    pub clone_limiter: Arc<CloneLimiter>,
+    pub config: Arc<Config>,
+    pub ssh_host_key_fingerprints: Vec<String>,
+    pub post_receive_hooks: PostReceiveHooks,
+}
+
+impl AppState {
+    /// Protocol handler configured from the server settings, for a repository whose
+    /// objects are named with `hash`
+    pub fn protocol_handler(&self, hash: HashAlgorithm) -> ProtocolHandler {
+        ProtocolHandler::new()
+            .with_pack_compression_level(self.config.pack_compression_level)
+            .with_max_object_size(self.config.max_object_bytes.map(|max| max as usize))
+            .with_hash_algorithm(hash)
+    }
 }
 
 #[tokio::main]
@@ -29,48 +77,167 @@ async fn main() -> anyhow::Result<()> {
 
     info!("Starting Git Server...");
 
+    let config = Arc::new(Config::from_env());
+
     // Initialize database
-    let database_url = std::env::var("DATABASE_URL")
-        .unwrap_or_else(|_| "sqlite:./git_server.db".to_string());
-    
-    let db = init_db(&database_url)
+    let sqlite_busy_timeout = std::time::Duration::from_millis(config.sqlite_busy_timeout_ms);
+    let db = init_db_with_busy_timeout(&config.database_url, sqlite_busy_timeout)
         .await
         .context("Failed to initialize database")?;
 
-    // Run migrations
-    run_migrations(&db)
-        .await
-        .context("Failed to run migrations")?;
+    // Run migrations, unless they are left to `git-server migrate`
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if config.migrate_on_start || args.first().is_some_and(|command| command == "migrate") {
+        run_migrations(&db)
+            .await
+            .context("Failed to run migrations")?;
+    } else {
+        let pending = pending_migrations(&db).await.context("Failed to check migrations")?;
+        if !pending.is_empty() {
+            anyhow::bail!(
+                "{} pending migrations ({}); run `git-server migrate` first",
+                pending.len(),
+                pending.join(", ")
+            );
+        }
+    }
 
     // Create services
     let blob_storage_path = std::env::var("BLOB_STORAGE_PATH")
-        .map(|p| std::path::PathBuf::from(p))
+        .map(std::path::PathBuf::from)
         .ok();
     
-    let repository_service = Arc::new(RepositoryService::new(db.clone(), blob_storage_path));
-    let user_service = Arc::new(UserService::new(db.clone()));
+    let repository_service = Arc::new(
+        RepositoryService::new(db.clone(), blob_storage_path)
+            .with_write_lock(
+                config.write_lock_scope,
+                std::time::Duration::from_millis(config.write_lock_timeout_ms),
+            )
+            .with_case_insensitive_refs(config.case_insensitive_refs)
+            .with_packed_refs(config.packed_refs)
+            .with_blob_shard_depth(config.blob_shard_depth)
+            .with_default_object_format(config.default_object_format.hash_algorithm()),
+    );
+    let user_service = Arc::new(
+        UserService::new(db.clone())
+            .with_password_hash_cost(config.password_hash_cost)
+            .context("Invalid PASSWORD_HASH_COST")?,
+    );
+    let idempotency_service = Arc::new(
+        IdempotencyService::new(db.clone())
+            .with_ttl(std::time::Duration::from_secs(config.idempotency_key_ttl_secs)),
+    );
+
+    let event_service = Arc::new(EventService::new(db.clone()));
+    let retention_service = Arc::new(RetentionService::new(db.clone(), config.retention_config()));
+    let client_policy = Arc::new(
+        ClientPolicy::new(config.min_push_agent.as_deref()).context("Invalid MIN_PUSH_AGENT")?,
+    );
 
+    // Admin subcommands (migrate/export/import/check) run against the database and exit
+    if let Some(command) = args.first() {
+        return admin::run(command, &args[1..], &repository_service, &user_service).await;
+    }
+
+    // Generate server host key
+    let ssh_host_key = russh_keys::key::KeyPair::generate_ed25519()
+        .ok_or_else(|| anyhow::anyhow!("Failed to generate server key"))?;
+    let ssh_host_key_fingerprints = vec![format!(
+        "SHA256:{}",
+        ssh_host_key.clone_public_key()?.fingerprint()
+    )];
+
+    let ssh_key_service = Arc::new(SshKeyService::new(db.clone()));
     let app_state = AppState {
         repository_service: repository_service.clone(),
         user_service: user_service.clone(),
+        idempotency_service: idempotency_service.clone(),
+        event_service,
+        retention_service: retention_service.clone(),
+        token_service: Arc::new(TokenService::new(db.clone())),
+        identity_service: Arc::new(IdentityService::new(db.clone())),
+        ssh_key_service: ssh_key_service.clone(),
+        status_service: Arc::new(StatusService::new(db.clone())),
+        client_policy: client_policy.clone(),
+        client_metrics: Arc::new(ClientMetrics::new()),
+        guardrail_metrics: Arc::new(GuardrailMetrics::new()),
+        pack_cache: Arc::new(PackCache::new(
+            repository_service.blob_storage_path().join("pack-cache"),
+            config.pack_cache_max_bytes,
+        )),
+        clone_limiter: Arc::new(CloneLimiter::new(
+            config.max_concurrent_clones.map(|max| usize::try_from(max).unwrap_or(usize::MAX)),
+            std::time::Duration::from_secs(config.clone_queue_timeout_secs),
+        )),
+        config: config.clone(),
+        ssh_host_key_fingerprints,
+        post_receive_hooks: PostReceiveHooks::default(),
     };
 
     // Start SSH server in background
     let ssh_repository_service = repository_service.clone();
     let ssh_user_service = user_service.clone();
+    let ssh_bind_address = config.ssh_bind_address.clone();
+    let ssh_protocol_settings = config.protocol.clone();
     tokio::spawn(async move {
-        if let Err(e) = ssh::start_ssh_server(ssh_repository_service, ssh_user_service).await {
+        if let Err(e) = ssh::start_ssh_server(
+            ssh_repository_service,
+            ssh_user_service,
+            ssh_key_service,
+            client_policy,
+            ssh_protocol_settings,
+            ssh_bind_address,
+            ssh_host_key,
+        )
+        .await
+        {
             eprintln!("SSH server error: {}", e);
         }
     });
 
+    // Prune the reflog, events and expired idempotency keys, and purge repositories
+    // deleted longer ago than the retention, in the background
+    if config.retention_interval_secs > 0 {
+        let interval = std::time::Duration::from_secs(config.retention_interval_secs);
+        let purge_repository_service = repository_service.clone();
+        let purge_config = config.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match retention_service.run().await {
+                    Ok(report) => {
+                        for table in &report.tables {
+                            info!(
+                                table = table.table,
+                                deleted = table.deleted,
+                                complete = table.complete,
+                                "Retention pruning"
+                            );
+                        }
+                    }
+                    Err(e) => warn!("Retention pruning failed: {}", e),
+                }
+                if let Err(e) = idempotency_service.purge_expired().await {
+                    warn!("Failed to purge expired idempotency keys: {}", e);
+                }
+                let cutoff = purge_config.purge_cutoff(chrono::Utc::now());
+                match purge_repository_service.purge_deleted_repositories(cutoff).await {
+                    Ok(purged) if !purged.is_empty() => info!(purged = purged.len(), "Purged deleted repositories"),
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to purge deleted repositories: {}", e),
+                }
+            }
+        });
+    }
+
     // Start HTTP server
-    let bind_address = std::env::var("BIND_ADDRESS")
-        .unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    let bind_address = config.http_bind_address.clone();
 
     info!("Starting HTTP server on {}", bind_address);
 
-    HttpServer::new(move || {
+    let upload_limit = config.max_upload_bytes;
+    let mut server = HttpServer::new(move || {
         // Create session key (in production, this should be loaded from env or config)
         let secret_key = Key::generate();
         
@@ -92,39 +259,118 @@ async fn main() -> anyhow::Result<()> {
             // API routes
             .service(
                 web::scope("/api")
+                    // Access tokens must carry the scope each route declares
+                    .wrap(from_fn(scopes::enforce_token_scopes))
                     // Authentication routes
+                    .app_data(payload::json_config(payload::API_JSON_LIMIT))
                     .service(
                         web::scope("/auth")
+                            .app_data(payload::json_config(payload::AUTH_JSON_LIMIT))
                             .service(auth::login)
                             .service(auth::register)
                             .service(auth::logout)
                             .service(auth::get_current_user)
+                            .service(auth::update_current_user)
+                            .service(auth::list_emails)
+                            .service(auth::add_email)
+                            .service(auth::verify_email)
+                            .service(auth::remove_email)
+                            .service(auth::create_token)
+                            .service(auth::list_tokens)
+                            .service(auth::revoke_token)
                     )
+                    .service(http::server_meta)
+                    .service(http::storage_metrics)
+                    .service(http::client_metrics)
+                    .service(http::retention_metrics)
+                    .service(http::guardrail_metrics)
+                    .service(http::pack_cache_metrics)
                     // Git operations routes
                     .service(git_api::list_branches)
                     .service(git_api::create_branch)
                     .service(git_api::delete_branch)
+                    .service(git_api::get_repository_permissions)
+                    .service(git_api::list_repository_events)
+                    .service(git_api::list_refs)
+                    .service(git_api::list_unreachable_objects)
                     .service(git_api::list_tags)
                     .service(git_api::create_tag)
                     .service(git_api::create_commit)
+                    .service(git_api::amend_commit)
                     .service(git_api::merge_branches)
+                    .service(git_api::fork_repository)
+                    .service(git_api::transfer_repository)
                     .service(git_api::get_commit_history)
+                    // Before get_commit, whose {sha} would also match `<sha>.patch`
+                    .service(git_api::get_commit_patch)
+                    .service(git_api::get_commit_diff)
+                    .service(git_api::get_commit_note)
+                    .service(git_api::set_commit_note)
+                    .service(git_api::get_commit)
+                    .service(git_api::get_diff)
+                    .service(git_api::compare_diff)
+                    .service(git_api::compare_across_repositories)
+                    .service(git_api::diff_since)
+                    .service(git_api::get_commit_graph)
+                    .service(git_api::get_readme)
+                    .service(git_api::rev_parse_path)
+                    .service(git_api::get_raw_blob)
+                    .service(git_api::batch_get_objects)
+                    .service(git_api::create_commit_status)
+                    .service(git_api::list_commit_statuses)
+                    // Admin routes
+                    .service(git_api::backfill_typed_tables)
+                    .service(git_api::rebuild_projections)
+                    .service(git_api::check_consistency)
+                    .service(git_api::set_read_limits)
+                    .service(http::set_user_quota)
+                    .service(http::list_deleted_repositories)
+                    .service(http::restore_repository)
                     // Repository routes
                     .service(http::list_repositories)
                     .service(http::get_repository)
+                    .service(http::update_repository)
+                    .service(http::set_repository_topics)
                     .service(http::create_repository)
+                    .service(http::delete_repository)
                     .service(http::get_user_repositories)
                     // User routes
                     .service(http::create_user)
                     .service(http::list_users)
+                    .service(http::get_my_quota)
+                    .service(http::import_users)
+                    .service(http::import_ssh_keys)
+                    .service(http::list_ssh_keys)
+                    .service(http::delete_ssh_key)
+                    .service(http::get_user_avatar)
+                    .service(http::set_user_avatar)
                     .service(http::get_user)
+                    // Contents uploads carry whole files. Registered last: this
+                    // scope takes every path that reaches it.
+                    .service(
+                        web::scope("")
+                            .app_data(payload::json_config(payload::CONTENTS_JSON_LIMIT))
+                            .app_data(web::PayloadConfig::new(upload_limit))
+                            .service(git_api::commit_files)
+                            .service(git_api::put_raw_contents)
+                    )
             )
+            // Git HTTP protocol at the clone URLs, `/{owner}/{repo}`
+            .service(http::info_refs)
+            .service(http::upload_pack)
+            .service(http::receive_pack)
             // Static files for frontend
             .service(Files::new("/", "./frontend/dist").index_file("index.html"))
     })
-    .bind(&bind_address)?
-    .run()
-    .await?;
+    // actix treats a zero timeout as none at all
+    .client_request_timeout(config.client_request_timeout.unwrap_or_default())
+    .client_disconnect_timeout(config.client_disconnect_timeout.unwrap_or_default())
+    .keep_alive(config.keep_alive);
+    if let Some(workers) = config.http_workers {
+        server = server.workers(workers);
+    }
+
+    server.bind(&bind_address)?.run().await?;
 
     Ok(())
 }
@@ -0,0 +1,384 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::web::Bytes;
+use git_protocol::ProtocolHandler;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use uuid::Uuid;
+
+/// Capabilities that don't change the pack a fetch gets back. Side-band framing
+/// is applied per response, so it doesn't either.
+const PACK_NEUTRAL_CAPABILITIES: &[&str] = &["agent", "session-id", "side-band", "side-band-64k"];
+
+/// Bytes of a cached pack read per chunk, one full side-band-64k packet
+const STREAM_CHUNK_LEN: usize = 65515;
+
+/// Everything that decides the bytes of a fetch's pack
+pub struct PackRequest<'a> {
+    pub repository_id: Uuid,
+    /// The repository's `ref_generation`, so any ref change changes the key
+    pub ref_generation: i64,
+    pub wants: &'a [String],
+    pub haves: &'a [String],
+    pub filters: &'a [String],
+    /// `shallow` and `deepen*` lines, as sent
+    pub shallow: &'a [String],
+    pub capabilities: Vec<&'a str>,
+}
+
+impl PackRequest<'_> {
+    /// Content address of the pack: SHA-1 over the sorted inputs, each section
+    /// length-prefixed so values can't run into the next section
+    pub fn key(&self) -> String {
+        fn section<'s>(hasher: &mut Sha1, label: &str, values: impl IntoIterator<Item = &'s str>) {
+            let mut values: Vec<&str> = values.into_iter().collect();
+            values.sort_unstable();
+            values.dedup();
+            hasher.update(format!("{} {}\n", label, values.len()));
+            for value in values {
+                hasher.update(value);
+                hasher.update("\n");
+            }
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.update(self.repository_id.as_bytes());
+        hasher.update(self.ref_generation.to_be_bytes());
+        section(&mut hasher, "wants", self.wants.iter().map(String::as_str));
+        section(&mut hasher, "haves", self.haves.iter().map(String::as_str));
+        section(&mut hasher, "filters", self.filters.iter().map(String::as_str));
+        section(&mut hasher, "shallow", self.shallow.iter().map(String::as_str));
+        section(
+            &mut hasher,
+            "capabilities",
+            self.capabilities.iter().copied().filter(|cap| {
+                let name = cap.split_once('=').map_or(*cap, |(name, _)| name);
+                !PACK_NEUTRAL_CAPABILITIES.contains(&name)
+            }),
+        );
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PackCacheMetricsSnapshot {
+    pub hits: u64,
+    pub builds: u64,
+    /// Share of fetches answered from the cache, 0 before the first fetch
+    pub hit_ratio: f64,
+    /// Pack bytes served from the cache instead of being built again
+    pub bytes_saved: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+struct CacheEntry {
+    repository_id: Uuid,
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    bytes: u64,
+    clock: u64,
+}
+
+/// Built packs stored on disk by `PackRequest::key`, evicting the least recently
+/// used once they add up to more than `max_bytes`. Keys cover the repository's
+/// ref generation, so any ref change makes older entries unreachable;
+/// `invalidate_repository` also frees their space straight away.
+pub struct PackCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<CacheIndex>,
+    hits: AtomicU64,
+    builds: AtomicU64,
+    bytes_saved: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl PackCache {
+    /// Cache under `dir`, holding at most `max_bytes` of packs; 0 disables it
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            index: Mutex::new(CacheIndex::default()),
+            hits: AtomicU64::new(0),
+            builds: AtomicU64::new(0),
+            bytes_saved: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(format!("{}.pack", &key[2..]))
+    }
+
+    /// The cached pack for `key`, opened for streaming. An open file stays readable
+    /// even if the entry is evicted meanwhile.
+    pub fn open(&self, key: &str) -> Option<File> {
+        let size = {
+            let mut index = self.index.lock().unwrap();
+            index.clock += 1;
+            let clock = index.clock;
+            let entry = index.entries.get_mut(key)?;
+            entry.last_used = clock;
+            entry.size
+        };
+
+        match File::open(self.path(key)) {
+            Ok(file) => {
+                self.hits.fetch_add(1, AtomicOrdering::Relaxed);
+                self.bytes_saved.fetch_add(size, AtomicOrdering::Relaxed);
+                Some(file)
+            }
+            Err(e) => {
+                // Removed from under us; forget it and build again
+                tracing::warn!(key, error = %e, "cached pack unreadable");
+                self.forget(key);
+                None
+            }
+        }
+    }
+
+    /// Record a freshly built pack. Packs larger than the whole cache are not stored.
+    pub fn insert(&self, key: &str, repository_id: Uuid, pack: &[u8]) {
+        self.builds.fetch_add(1, AtomicOrdering::Relaxed);
+        let size = pack.len() as u64;
+        if size > self.max_bytes {
+            return;
+        }
+
+        let path = self.path(key);
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, pack));
+        if let Err(e) = written {
+            tracing::warn!(key, error = %e, "failed to cache pack");
+            return;
+        }
+
+        let mut evicted = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            index.clock += 1;
+            let entry = CacheEntry {
+                repository_id,
+                size,
+                last_used: index.clock,
+            };
+            if let Some(previous) = index.entries.insert(key.to_string(), entry) {
+                index.bytes -= previous.size;
+            }
+            index.bytes += size;
+
+            while index.bytes > self.max_bytes {
+                let Some(oldest) = index
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone())
+                else {
+                    break;
+                };
+                if let Some(entry) = index.entries.remove(&oldest) {
+                    index.bytes -= entry.size;
+                }
+                evicted.push(oldest);
+            }
+        }
+
+        self.evictions.fetch_add(evicted.len() as u64, AtomicOrdering::Relaxed);
+        for key in evicted {
+            let _ = std::fs::remove_file(self.path(&key));
+        }
+    }
+
+    /// Drop every cached pack of a repository, after its refs or objects change
+    pub fn invalidate_repository(&self, repository_id: Uuid) {
+        let stale: Vec<String> = {
+            let mut index = self.index.lock().unwrap();
+            let stale: Vec<String> = index
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.repository_id == repository_id)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &stale {
+                if let Some(entry) = index.entries.remove(key) {
+                    index.bytes -= entry.size;
+                }
+            }
+            stale
+        };
+
+        for key in stale {
+            let _ = std::fs::remove_file(self.path(&key));
+        }
+    }
+
+    fn forget(&self, key: &str) {
+        let mut index = self.index.lock().unwrap();
+        if let Some(entry) = index.entries.remove(key) {
+            index.bytes -= entry.size;
+        }
+    }
+
+    pub fn snapshot(&self) -> PackCacheMetricsSnapshot {
+        let index = self.index.lock().unwrap();
+        let hits = self.hits.load(AtomicOrdering::Relaxed);
+        let builds = self.builds.load(AtomicOrdering::Relaxed);
+        PackCacheMetricsSnapshot {
+            hits,
+            builds,
+            hit_ratio: if hits + builds == 0 { 0.0 } else { hits as f64 / (hits + builds) as f64 },
+            bytes_saved: self.bytes_saved.load(AtomicOrdering::Relaxed),
+            evictions: self.evictions.load(AtomicOrdering::Relaxed),
+            entries: index.entries.len(),
+            bytes: index.bytes,
+        }
+    }
+}
+
+/// Response body for a cache hit: `prefix` (the NAK), then the pack read from
+/// disk a chunk at a time. Side-band clients get each chunk as a band 1 packet
+/// and a flush-pkt at the end, the same framing as a freshly built pack.
+/// Reads go through `tokio::fs`, so a slow disk never blocks the worker thread.
+pub struct CachedPackBody {
+    prefix: Option<Bytes>,
+    file: tokio::fs::File,
+    chunk: Box<[u8]>,
+    sideband: Option<ProtocolHandler>,
+    done: bool,
+}
+
+impl CachedPackBody {
+    pub fn new(prefix: Vec<u8>, file: File, sideband: Option<ProtocolHandler>) -> Self {
+        Self {
+            prefix: Some(Bytes::from(prefix)),
+            file: tokio::fs::File::from_std(file),
+            chunk: vec![0; STREAM_CHUNK_LEN].into_boxed_slice(),
+            sideband,
+            done: false,
+        }
+    }
+}
+
+impl MessageBody for CachedPackBody {
+    type Error = std::io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(prefix) = this.prefix.take() {
+            return Poll::Ready(Some(Ok(prefix)));
+        }
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        let mut buf = ReadBuf::new(&mut this.chunk);
+        if let Err(e) = ready!(Pin::new(&mut this.file).poll_read(cx, &mut buf)) {
+            return Poll::Ready(Some(Err(e)));
+        }
+        let chunk = buf.filled();
+        if chunk.is_empty() {
+            this.done = true;
+            return Poll::Ready(this.sideband.as_ref().map(|_| Ok(Bytes::from_static(b"0000"))));
+        }
+
+        Poll::Ready(Some(Ok(match &this.sideband {
+            Some(protocol) => {
+                let mut framed = Vec::with_capacity(chunk.len() + 5);
+                protocol.write_sideband(&mut framed, 1, chunk);
+                Bytes::from(framed)
+            }
+            None => Bytes::copy_from_slice(chunk),
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_least_recently_used_pack_is_evicted() {
+        let dir = std::env::temp_dir().join(format!("pack-cache-test-{}", Uuid::new_v4()));
+        let cache = PackCache::new(dir, 10);
+        let repository_id = Uuid::new_v4();
+        let key = |want: &str| {
+            PackRequest {
+                repository_id,
+                ref_generation: 0,
+                wants: &[want.to_string()],
+                haves: &[],
+                filters: &[],
+                shallow: &[],
+                capabilities: vec![],
+            }
+            .key()
+        };
+        let get = |key: &str| {
+            cache.open(key).map(|mut file| {
+                let mut pack = Vec::new();
+                file.read_to_end(&mut pack).unwrap();
+                pack
+            })
+        };
+
+        cache.insert(&key("a"), repository_id, b"aaaa");
+        cache.insert(&key("b"), repository_id, b"bbbb");
+        assert_eq!(get(&key("a")).unwrap(), b"aaaa");
+        cache.insert(&key("c"), repository_id, b"cccc");
+
+        assert!(get(&key("b")).is_none());
+        assert!(get(&key("a")).is_some());
+        assert!(get(&key("c")).is_some());
+        let metrics = cache.snapshot();
+        assert_eq!((metrics.entries, metrics.bytes, metrics.evictions), (2, 8, 1));
+
+        cache.invalidate_repository(repository_id);
+        assert!(get(&key("a")).is_none());
+        assert_eq!(cache.snapshot().bytes, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_cached_pack_larger_than_a_chunk_streams_whole() {
+        let dir = std::env::temp_dir().join(format!("pack-cache-test-{}", Uuid::new_v4()));
+        let cache = PackCache::new(dir, 1 << 20);
+        let repository_id = Uuid::new_v4();
+        let pack: Vec<u8> = (0..STREAM_CHUNK_LEN * 2 + 100).map(|i| (i % 251) as u8).collect();
+        cache.insert("big", repository_id, &pack);
+
+        let body = CachedPackBody::new(b"0008NAK\n".to_vec(), cache.open("big").unwrap(), None);
+        let streamed = actix_web::body::to_bytes(body).await.unwrap();
+        assert_eq!(&streamed[..8], b"0008NAK\n");
+        assert!(streamed[8..] == pack[..]);
+
+        // Side-band framing holds up across chunk boundaries too
+        let protocol = ProtocolHandler::new();
+        let body = CachedPackBody::new(Vec::new(), cache.open("big").unwrap(), Some(protocol.clone()));
+        let streamed = actix_web::body::to_bytes(body).await.unwrap();
+        let mut expected = Vec::new();
+        protocol.write_sideband(&mut expected, 1, &pack);
+        expected.extend_from_slice(b"0000");
+        assert!(streamed[..] == expected[..]);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::git_api::ApiResponse;
+use actix_web::dev::Payload;
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, FromRequest, HttpRequest, HttpResponse};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+
+/// Largest JSON body the `/api/auth` routes read
+pub const AUTH_JSON_LIMIT: usize = 16 * 1024;
+
+/// Largest JSON body the other `/api` routes read
+pub const API_JSON_LIMIT: usize = 256 * 1024;
+
+/// Largest JSON body of a contents commit, which carries whole files; bigger files
+/// go through the raw contents upload instead
+pub const CONTENTS_JSON_LIMIT: usize = 16 * 1024 * 1024;
+
+/// JSON body settings for a scope: at most `limit` bytes, `application/json` only,
+/// and failures answered like every other API error
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .content_type_required(true)
+        .error_handler(json_error)
+}
+
+fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> Error {
+    let (status, message) = match &err {
+        JsonPayloadError::OverflowKnownLength { length, limit } => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Request body is {} bytes, more than the {} byte limit", length, limit),
+        ),
+        JsonPayloadError::Overflow { limit } => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Request body is more than the {} byte limit", limit),
+        ),
+        JsonPayloadError::ContentType => (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/json".to_string(),
+        ),
+        JsonPayloadError::Deserialize(e) => (StatusCode::BAD_REQUEST, format!("Malformed JSON: {}", e)),
+        _ => (StatusCode::BAD_REQUEST, format!("Invalid request body: {}", err)),
+    };
+    error_response(err, status, message)
+}
+
+fn error_response<E>(cause: E, status: StatusCode, message: String) -> Error
+where
+    E: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    let response = HttpResponse::build(status).json(ApiResponse::<()> {
+        success: false,
+        data: None,
+        message,
+    });
+    InternalError::from_response(cause, response).into()
+}
+
+/// A JSON request body. Size and content type follow the scope's `json_config`;
+/// a body that is valid JSON but doesn't fit `T` gets 422 naming the field, e.g.
+/// `files[0].delete`.
+pub struct ApiJson<T>(pub T);
+
+impl<T> ApiJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for ApiJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for ApiJson<T> {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<serde_json::Value>::from_request(req, payload);
+        Box::pin(async move {
+            let value = json.await?.into_inner();
+            serde_path_to_error::deserialize(value).map(ApiJson).map_err(|e| {
+                let message = match e.path().to_string().as_str() {
+                    "." => format!("Invalid request body: {}", e.inner()),
+                    path => format!("Invalid value for '{}': {}", path, e.inner()),
+                };
+                error_response(e, StatusCode::UNPROCESSABLE_ENTITY, message)
+            })
+        })
+    }
+}
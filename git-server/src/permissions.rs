@@ -0,0 +1,41 @@
+use crate::scopes::{grants, Caller, Scope};
+use git_storage::entities::{repository, user};
+use serde::{Deserialize, Serialize};
+
+/// What a caller may do with one repository, for UIs deciding what to offer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permissions {
+    pub read: bool,
+    /// Push and change refs; never for archived repositories
+    pub write: bool,
+    /// Change settings, archive, list hidden refs
+    pub admin: bool,
+}
+
+impl Permissions {
+    /// The caller's effective permissions on `repo`. `user` is the caller's account,
+    /// already loaded, so computing this for a whole listing costs no queries.
+    /// Owners and server admins administer and write; everyone else at most reads.
+    /// Token callers are further limited to what their scopes grant.
+    pub fn effective(repo: &repository::Model, user: Option<&user::Model>, caller: &Caller) -> Self {
+        Self::effective_for(repo, user, caller, Scope::RepoWrite)
+    }
+
+    /// As [`effective`](Self::effective), but a token needs `write_scope` for
+    /// `write` rather than `repo:write`, for writes with a narrower scope of
+    /// their own such as commit statuses
+    pub fn effective_for(
+        repo: &repository::Model,
+        user: Option<&user::Model>,
+        caller: &Caller,
+        write_scope: Scope,
+    ) -> Self {
+        let scoped = |scope: Scope| caller.token_scopes().is_none_or(|held| grants(held, scope));
+        let admin = repo.administered_by(user);
+        Self {
+            read: repo.readable_by(user) && scoped(Scope::RepoRead),
+            write: admin && !repo.is_archived && scoped(write_scope),
+            admin: admin && scoped(Scope::RepoAdmin),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::config::Config;
+use async_trait::async_trait;
+use git_protocol::ProtocolHandler;
+use git_storage::entities::repository;
+use git_storage::PushedRef;
+use std::sync::Arc;
+
+/// Shown in `{pusher}` when nobody is signed in
+const ANONYMOUS_PUSHER: &str = "anonymous";
+
+/// A push after its ref transaction: the refs that changed and who changed them
+pub struct PostReceiveContext<'a> {
+    pub repository: &'a repository::Model,
+    /// Username of the pusher, `None` for anonymous pushes
+    pub pusher: Option<&'a str>,
+    pub refs: &'a [PushedRef],
+    pub config: &'a Config,
+}
+
+/// Runs once a push has updated its refs. Whatever it returns is shown to the
+/// pusher as `remote:` lines, when the client negotiated side-band.
+#[async_trait]
+pub trait PostReceiveHook: Send + Sync {
+    /// Messages for the pusher; each may span several lines
+    async fn post_receive(&self, push: &PostReceiveContext<'_>) -> Vec<String>;
+}
+
+/// Points the pusher of a new branch at where a merge request for it starts
+pub struct NewBranchHint;
+
+#[async_trait]
+impl PostReceiveHook for NewBranchHint {
+    async fn post_receive(&self, push: &PostReceiveContext<'_>) -> Vec<String> {
+        push.refs
+            .iter()
+            .filter(|pushed| pushed.created)
+            .filter_map(|pushed| pushed.name.strip_prefix("refs/heads/"))
+            .filter(|branch| *branch != push.repository.default_branch)
+            .map(|branch| {
+                let url = push.config.absolute_url(&format!(
+                    "{}/merge_requests/new?source_branch={}",
+                    push.repository.name,
+                    query_escape(branch)
+                ));
+                format!("To create a merge request for {}, visit:\n  {}", branch, url)
+            })
+            .collect()
+    }
+}
+
+/// The repository's own `push_message`, once for every ref the push created or
+/// moved, with `{ref}`, `{repo}` and `{pusher}` filled in
+pub struct RepositoryPushMessage;
+
+#[async_trait]
+impl PostReceiveHook for RepositoryPushMessage {
+    async fn post_receive(&self, push: &PostReceiveContext<'_>) -> Vec<String> {
+        let Some(template) = push.repository.push_message.as_deref() else {
+            return Vec::new();
+        };
+        push.refs
+            .iter()
+            .filter(|pushed| !pushed.deleted)
+            .map(|pushed| {
+                template
+                    .replace("{ref}", &pushed.name)
+                    .replace("{repo}", &push.repository.name)
+                    .replace("{pusher}", push.pusher.unwrap_or(ANONYMOUS_PUSHER))
+            })
+            .collect()
+    }
+}
+
+/// Hooks run after every push, in order
+#[derive(Clone)]
+pub struct PostReceiveHooks {
+    hooks: Vec<Arc<dyn PostReceiveHook>>,
+}
+
+impl PostReceiveHooks {
+    /// No hooks at all
+    pub fn empty() -> Self {
+        Self { hooks: Vec::new() }
+    }
+
+    pub fn with_hook(mut self, hook: impl PostReceiveHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Every hook's messages, in hook order
+    pub async fn messages(&self, push: &PostReceiveContext<'_>) -> Vec<String> {
+        let mut messages = Vec::new();
+        for hook in &self.hooks {
+            messages.extend(hook.post_receive(push).await);
+        }
+        messages
+    }
+}
+
+impl Default for PostReceiveHooks {
+    /// The built-in messages: new branch hints and the repository's push message
+    fn default() -> Self {
+        Self::empty().with_hook(NewBranchHint).with_hook(RepositoryPushMessage)
+    }
+}
+
+/// Append messages as side-band progress (band 2), which git prints as `remote:`
+/// lines, each message set off by a blank line
+pub fn write_messages(protocol: &ProtocolHandler, out: &mut Vec<u8>, messages: &[String]) {
+    if messages.is_empty() {
+        return;
+    }
+    let mut text = String::from("\n");
+    for message in messages {
+        text.push_str(message.trim_end());
+        text.push_str("\n\n");
+    }
+    protocol.write_sideband(out, 2, text.as_bytes());
+}
+
+/// Percent-encode a query parameter value
+fn query_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,108 @@
+use crate::AppState;
+use actix_web::HttpResponse;
+use git_storage::OwnerUsage;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Limits on what one user's repositories may take up; `None` is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct UserQuota {
+    pub max_repositories: Option<u64>,
+    pub max_total_bytes: Option<u64>,
+}
+
+impl UserQuota {
+    /// Refuse a new repository once the owner has `max_repositories`
+    pub fn check_new_repository(&self, usage: &OwnerUsage) -> Result<(), QuotaHit> {
+        match self.max_repositories {
+            Some(max) if usage.repositories >= max => Err(QuotaHit::RepositoryLimit { max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Bytes the owner's repositories may still grow by, `None` when unlimited
+    pub fn remaining_bytes(&self, usage: &OwnerUsage) -> Option<u64> {
+        self.max_total_bytes.map(|max| max.saturating_sub(usage.bytes))
+    }
+}
+
+/// The quota of the user `owner_id` and what their repositories take up, from the
+/// cached repository sizes. An unknown owner has no quota.
+pub async fn owner_quota(state: &AppState, owner_id: Uuid) -> anyhow::Result<(UserQuota, OwnerUsage)> {
+    let quota = match state.user_service.get_user_by_id(owner_id).await? {
+        Some(owner) => state.config.user_quota(&owner),
+        None => UserQuota::default(),
+    };
+    let usage = state.repository_service.owner_usage(owner_id).await?;
+    Ok((quota, usage))
+}
+
+/// Why a write was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaHit {
+    RepositoryLimit { max: u64 },
+    DiskQuota { needed: u64, available: u64 },
+}
+
+impl QuotaHit {
+    /// Stable code clients can match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            QuotaHit::RepositoryLimit { .. } => "repository_quota_exceeded",
+            QuotaHit::DiskQuota { .. } => "disk_quota_exceeded",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            QuotaHit::RepositoryLimit { max } => {
+                format!("The owner already has the {} repositories their quota allows", max)
+            }
+            QuotaHit::DiskQuota { needed, available } => format!(
+                "This push needs {} bytes but only {} are left in the owner's disk quota",
+                needed, available
+            ),
+        }
+    }
+
+    /// 409 with a body naming the quota
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::Conflict().json(QuotaResponse {
+            success: false,
+            code: self.code(),
+            message: self.message(),
+        })
+    }
+
+    /// Reason for a refused pack in report-status, led by the code
+    pub fn unpack_status(&self) -> String {
+        format!("{}: {}", self.code(), self.message())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct QuotaResponse {
+    success: bool,
+    code: &'static str,
+    message: String,
+}
+
+/// A user's quota next to what their repositories take up
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaReport {
+    pub repositories: u64,
+    pub max_repositories: Option<u64>,
+    pub total_bytes: u64,
+    pub max_total_bytes: Option<u64>,
+}
+
+impl QuotaReport {
+    pub fn new(quota: UserQuota, usage: OwnerUsage) -> Self {
+        Self {
+            repositories: usage.repositories,
+            max_repositories: quota.max_repositories,
+            total_bytes: usage.bytes,
+            max_total_bytes: quota.max_total_bytes,
+        }
+    }
+}
@@ -0,0 +1,725 @@
+use crate::git_api::ApiResponse;
+use crate::AppState;
+use actix_session::{Session, SessionExt};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ResourceDef, ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use anyhow::anyhow;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use git_storage::entities::user;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// What an access token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    RepoRead,
+    RepoWrite,
+    RepoAdmin,
+    UserRead,
+    UserWrite,
+    Admin,
+    WebhookWrite,
+    StatusWrite,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 8] = [
+        Scope::RepoRead,
+        Scope::RepoWrite,
+        Scope::RepoAdmin,
+        Scope::UserRead,
+        Scope::UserWrite,
+        Scope::Admin,
+        Scope::WebhookWrite,
+        Scope::StatusWrite,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::RepoRead => "repo:read",
+            Scope::RepoWrite => "repo:write",
+            Scope::RepoAdmin => "repo:admin",
+            Scope::UserRead => "user:read",
+            Scope::UserWrite => "user:write",
+            Scope::Admin => "admin",
+            Scope::WebhookWrite => "webhook:write",
+            Scope::StatusWrite => "status:write",
+        }
+    }
+
+    /// Whether holding this scope grants `required`. `admin` grants everything,
+    /// the repo and user scopes grant the weaker scopes of their family, and
+    /// writing to a repository includes reporting statuses on it.
+    pub fn implies(&self, required: Scope) -> bool {
+        use Scope::*;
+        *self == required
+            || matches!(
+                (self, required),
+                (Admin, _)
+                    | (RepoAdmin, RepoWrite | RepoRead | StatusWrite | WebhookWrite)
+                    | (RepoWrite, RepoRead | StatusWrite)
+                    | (UserWrite, UserRead)
+            )
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| anyhow!("Unknown scope: {}", s))
+    }
+}
+
+/// Whether any of `held` grants `required`
+pub fn grants(held: &[Scope], required: Scope) -> bool {
+    held.iter().any(|scope| scope.implies(required))
+}
+
+/// What a route requires of token callers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Open to anyone, tokens are not checked
+    Public,
+    Scoped(Scope),
+}
+
+/// Scope needed for every `/api` route. A routed path without an entry here is
+/// answered with 500, so a new endpoint cannot ship without choosing its scope.
+const ROUTE_SCOPES: &[(&str, &str, Access)] = &[
+    ("POST", "/api/auth/login", Access::Public),
+    ("POST", "/api/auth/register", Access::Public),
+    ("POST", "/api/auth/logout", Access::Public),
+    ("GET", "/api/auth/me", Access::Scoped(Scope::UserRead)),
+    ("PATCH", "/api/auth/me", Access::Scoped(Scope::UserWrite)),
+    ("GET", "/api/auth/emails", Access::Scoped(Scope::UserRead)),
+    ("POST", "/api/auth/emails", Access::Scoped(Scope::UserWrite)),
+    ("POST", "/api/auth/emails/verify", Access::Scoped(Scope::UserWrite)),
+    ("DELETE", "/api/auth/emails/{email_id}", Access::Scoped(Scope::UserWrite)),
+    ("GET", "/api/auth/tokens", Access::Scoped(Scope::UserRead)),
+    ("POST", "/api/auth/tokens", Access::Scoped(Scope::UserWrite)),
+    ("DELETE", "/api/auth/tokens/{token_id}", Access::Scoped(Scope::UserWrite)),
+    ("GET", "/api/meta", Access::Public),
+    ("GET", "/api/metrics/storage", Access::Scoped(Scope::Admin)),
+    ("GET", "/api/metrics/clients", Access::Scoped(Scope::Admin)),
+    ("GET", "/api/metrics/retention", Access::Scoped(Scope::Admin)),
+    ("GET", "/api/metrics/guardrails", Access::Scoped(Scope::Admin)),
+    ("GET", "/api/metrics/pack-cache", Access::Scoped(Scope::Admin)),
+    ("GET", "/api/repositories/{repo_id}/branches", Access::Scoped(Scope::RepoRead)),
+    ("POST", "/api/repositories/{repo_id}/branches", Access::Scoped(Scope::RepoWrite)),
+    ("DELETE", "/api/repositories/{repo_id}/branches/{branch_name}", Access::Scoped(Scope::RepoWrite)),
+    ("GET", "/api/repositories/{repo_id}/branches/{branch_name}/commits", Access::Scoped(Scope::RepoRead)),
+    ("POST", "/api/repositories/{repo_id}/branches/{branch}/amend", Access::Scoped(Scope::RepoWrite)),
+    ("GET", "/api/repositories/{repo_id}/branches/{branch}/diff-since", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/permissions", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/events", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/refs", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/readme", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/rev-parse/{commit}/{path:.*}", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/unreachable", Access::Scoped(Scope::RepoAdmin)),
+    ("GET", "/api/repositories/{repo_id}/tags", Access::Scoped(Scope::RepoRead)),
+    ("POST", "/api/repositories/{repo_id}/tags", Access::Scoped(Scope::RepoWrite)),
+    ("POST", "/api/repositories/{repo_id}/commits", Access::Scoped(Scope::RepoWrite)),
+    ("GET", "/api/repositories/{repo_id}/commits/{sha}.patch", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/commits/{sha}.diff", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/commits/{sha}/notes", Access::Scoped(Scope::RepoRead)),
+    ("POST", "/api/repositories/{repo_id}/commits/{sha}/notes", Access::Scoped(Scope::RepoWrite)),
+    ("GET", "/api/repositories/{repo_id}/commits/{sha}", Access::Scoped(Scope::RepoRead)),
+    ("POST", "/api/repositories/{repo_id}/contents", Access::Scoped(Scope::RepoWrite)),
+    ("PUT", "/api/repositories/{repo_id}/contents/{file_path:.*}", Access::Scoped(Scope::RepoWrite)),
+    ("POST", "/api/repositories/{repo_id}/merge", Access::Scoped(Scope::RepoWrite)),
+    ("GET", "/api/repositories/{repo_id}/diff", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/compare/{range}.diff", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{base_id}/compare/{head_id}", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/graph", Access::Scoped(Scope::RepoRead)),
+    ("POST", "/api/repositories/{repo_id}/objects/batch", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/blobs/{sha}/raw", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/repositories/{repo_id}/statuses/{sha}", Access::Scoped(Scope::RepoRead)),
+    ("POST", "/api/repositories/{repo_id}/statuses/{sha}", Access::Scoped(Scope::StatusWrite)),
+    ("POST", "/api/admin/repositories/{repo_id}/backfill-typed-tables", Access::Scoped(Scope::Admin)),
+    ("POST", "/api/admin/repositories/{repo_id}/rebuild-projections", Access::Scoped(Scope::Admin)),
+    ("POST", "/api/admin/check", Access::Scoped(Scope::Admin)),
+    ("PUT", "/api/admin/repositories/{repo_id}/read-limits", Access::Scoped(Scope::Admin)),
+    ("GET", "/api/admin/repositories/deleted", Access::Scoped(Scope::Admin)),
+    ("POST", "/api/admin/repositories/{repo_id}/restore", Access::Scoped(Scope::Admin)),
+    ("PUT", "/api/admin/users/{user_id}/quota", Access::Scoped(Scope::Admin)),
+    ("GET", "/api/repositories", Access::Scoped(Scope::RepoRead)),
+    ("POST", "/api/repositories", Access::Scoped(Scope::RepoWrite)),
+    ("POST", "/api/repositories/{repo_id}/fork", Access::Scoped(Scope::RepoWrite)),
+    ("POST", "/api/repositories/{repo_id}/transfer", Access::Scoped(Scope::RepoAdmin)),
+    ("GET", "/api/repositories/{name}", Access::Scoped(Scope::RepoRead)),
+    ("PATCH", "/api/repositories/{name}", Access::Scoped(Scope::RepoAdmin)),
+    ("DELETE", "/api/repositories/{repo_id}", Access::Scoped(Scope::RepoAdmin)),
+    ("PUT", "/api/repositories/{name}/topics", Access::Scoped(Scope::RepoAdmin)),
+    ("GET", "/api/users", Access::Scoped(Scope::UserRead)),
+    ("POST", "/api/users", Access::Scoped(Scope::Admin)),
+    ("GET", "/api/users/me/quota", Access::Scoped(Scope::UserRead)),
+    ("GET", "/api/users/{username}", Access::Scoped(Scope::UserRead)),
+    ("GET", "/api/users/{username}/repositories", Access::Scoped(Scope::RepoRead)),
+    ("GET", "/api/users/{username}/avatar", Access::Public),
+    ("PUT", "/api/users/{username}/avatar", Access::Scoped(Scope::UserWrite)),
+    ("POST", "/api/users:import", Access::Scoped(Scope::Admin)),
+    ("POST", "/api/users/{username}/keys:import", Access::Scoped(Scope::Admin)),
+    ("GET", "/api/users/{username}/keys", Access::Scoped(Scope::UserRead)),
+    ("DELETE", "/api/users/{username}/keys/{key_id}", Access::Scoped(Scope::UserWrite)),
+];
+
+fn route_table() -> &'static [(Method, ResourceDef, Access)] {
+    static TABLE: OnceLock<Vec<(Method, ResourceDef, Access)>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        ROUTE_SCOPES
+            .iter()
+            .map(|(method, pattern, access)| {
+                (Method::from_str(method).expect("valid method"), ResourceDef::new(*pattern), *access)
+            })
+            .collect()
+    })
+}
+
+/// Look up what a request to `path` with `method` requires
+pub fn route_access(method: &Method, path: &str) -> Option<Access> {
+    route_table()
+        .iter()
+        .find(|(m, def, _)| m == method && def.is_match(path))
+        .map(|(_, _, access)| *access)
+}
+
+/// The token a request authenticated with, stored in request extensions
+#[derive(Debug, Clone)]
+pub struct TokenCaller {
+    pub user_id: Uuid,
+    pub scopes: Vec<Scope>,
+}
+
+/// Token from `Authorization: Bearer <token>` or `Authorization: token <token>`
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    (scheme.eq_ignore_ascii_case("bearer") || scheme.eq_ignore_ascii_case("token"))
+        .then(|| token.trim().to_string())
+}
+
+fn scope_error(status: actix_web::http::StatusCode, message: String, missing: Option<Scope>) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({
+        "success": false,
+        "message": message,
+        "missing_scope": missing.map(|scope| scope.as_str()),
+    }))
+}
+
+/// Middleware for the `/api` scope: authenticates access tokens and rejects
+/// token requests lacking the route's scope. Session and anonymous requests
+/// pass through, handlers still decide whether they need a signed-in user.
+pub async fn enforce_token_scopes(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let access = route_access(req.method(), req.path());
+    let routed = req
+        .request()
+        .resource_map()
+        .match_pattern(req.path())
+        .is_some_and(|pattern| pattern.starts_with("/api/"));
+
+    let required = match access {
+        Some(Access::Public) => None,
+        Some(Access::Scoped(scope)) => Some(scope),
+        None if routed => {
+            tracing::error!(method = %req.method(), path = req.path(), "Route has no declared scope");
+            let response = scope_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Route has no declared scope".to_string(),
+                None,
+            );
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+        None => None,
+    };
+
+    // A signed-in session takes precedence, so its token isn't checked
+    let signed_in = session_user_id(&req.get_session()).is_some();
+    if let (Some(required), Some(token), false) = (required, bearer_token(&req), signed_in) {
+        let state = req
+            .app_data::<web::Data<AppState>>()
+            .expect("AppState is registered")
+            .clone();
+
+        let token = match state.token_service.authenticate(&token).await {
+            Ok(Some(token)) => token,
+            Ok(None) => {
+                let response = scope_error(StatusCode::UNAUTHORIZED, "Invalid or expired token".to_string(), None);
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            Err(e) => {
+                let response = scope_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to check token: {}", e),
+                    None,
+                );
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+        };
+
+        let scopes: Vec<Scope> = token.scope_list().into_iter().filter_map(|s| s.parse().ok()).collect();
+        if !grants(&scopes, required) {
+            let response = scope_error(
+                StatusCode::FORBIDDEN,
+                format!("Token is missing the {} scope", required.as_str()),
+                Some(required),
+            );
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+
+        req.extensions_mut().insert(TokenCaller {
+            user_id: token.user_id,
+            scopes,
+        });
+    }
+
+    // Filled in by the `Caller` extractor once it knows who is calling
+    let span = tracing::info_span!(
+        "api_request",
+        method = %req.method(),
+        path = req.path(),
+        principal = tracing::field::Empty,
+        auth = tracing::field::Empty,
+    );
+    Ok(next.call(req).instrument(span).await?.map_into_left_body())
+}
+
+/// Who is making a request, with their user loaded once per request. Credentials
+/// are taken in order: the session cookie, an access token checked by
+/// [`enforce_token_scopes`], then `Authorization: Basic` username and password.
+/// Sessions and passwords carry every scope of their user.
+#[derive(Clone)]
+pub struct Caller {
+    user: Option<user::Model>,
+    token: Option<TokenCaller>,
+}
+
+impl Caller {
+    pub fn user_id(&self) -> Option<Uuid> {
+        self.user.as_ref().map(|user| user.id)
+    }
+
+    /// The signed-in user, `None` for anonymous requests
+    pub fn user(&self) -> Option<&user::Model> {
+        self.user.as_ref()
+    }
+
+    /// Scopes of the token, `None` for session requests
+    pub fn token_scopes(&self) -> Option<&[Scope]> {
+        self.token.as_ref().map(|token| token.scopes.as_slice())
+    }
+}
+
+impl Caller {
+    async fn from_http(req: &HttpRequest) -> Result<Self, Error> {
+        if let Some(caller) = req.extensions().get::<Caller>() {
+            return Ok(caller.clone());
+        }
+
+        let token = req.extensions().get::<TokenCaller>().cloned();
+        let (user, via) = if let Some(user_id) = session_user_id(&req.get_session()) {
+            (load_user(req, user_id).await?, "session")
+        } else if let Some(token) = &token {
+            (load_user(req, token.user_id).await?, "token")
+        } else if let Some((username, password)) = basic_credentials(req) {
+            match app_state(req).user_service.authenticate(&username, &password).await {
+                Ok(Some(user)) => (Some(user), "password"),
+                Ok(None) => return Err(rejection(StatusCode::UNAUTHORIZED, "Invalid credentials".to_string())),
+                Err(e) => {
+                    return Err(rejection(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to check credentials: {}", e)));
+                }
+            }
+        } else {
+            (None, "anonymous")
+        };
+
+        if let Some(user) = &user {
+            if !user.is_active {
+                return Err(rejection(StatusCode::FORBIDDEN, "Account is not active".to_string()));
+            }
+            let span = tracing::Span::current();
+            span.record("principal", user.username.as_str());
+            span.record("auth", via);
+        }
+
+        let caller = Caller {
+            token: token.filter(|_| via == "token"),
+            user,
+        };
+        req.extensions_mut().insert(caller.clone());
+        Ok(caller)
+    }
+}
+
+fn app_state(req: &HttpRequest) -> &web::Data<AppState> {
+    req.app_data::<web::Data<AppState>>().expect("AppState is registered")
+}
+
+/// A user that no longer exists is treated as anonymous
+async fn load_user(req: &HttpRequest, user_id: Uuid) -> Result<Option<user::Model>, Error> {
+    app_state(req)
+        .user_service
+        .get_user_by_id(user_id)
+        .await
+        .map_err(|e| rejection(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load user: {}", e)))
+}
+
+fn session_user_id(session: &Session) -> Option<Uuid> {
+    session
+        .get::<String>("user_id")
+        .ok()
+        .flatten()
+        .and_then(|user_id_str| Uuid::parse_str(&user_id_str).ok())
+}
+
+/// Username and password from `Authorization: Basic <base64 of user:password>`
+fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+fn rejection(status: StatusCode, message: String) -> Error {
+    let response = HttpResponse::build(status).json(ApiResponse::<()> {
+        success: false,
+        data: None,
+        message: message.clone(),
+    });
+    InternalError::from_response(message, response).into()
+}
+
+impl FromRequest for Caller {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { Caller::from_http(&req).await })
+    }
+}
+
+/// The id of a signed-in caller, for handlers that need one: anonymous requests
+/// are answered with 401 before the handler runs. Handlers open to anonymous
+/// callers take a [`Caller`] instead.
+pub struct AuthenticatedUser(pub Uuid);
+
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            match Caller::from_http(&req).await?.user_id() {
+                Some(user_id) => Ok(AuthenticatedUser(user_id)),
+                None => Err(rejection(StatusCode::UNAUTHORIZED, "Authentication required".to_string())),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_api::{create_branch, create_commit_status, list_branches};
+    use crate::http::tests::create_test_state;
+    use actix_web::http::StatusCode;
+    use actix_web::{middleware::from_fn, test, App};
+    use git_storage::{CreateCommitRequest, GitOperations};
+    use git_storage::entities::repository::Visibility;
+
+    #[actix_web::test]
+    async fn test_scope_implications() {
+        assert!(Scope::Admin.implies(Scope::RepoWrite));
+        assert!(Scope::RepoAdmin.implies(Scope::RepoRead));
+        assert!(Scope::RepoWrite.implies(Scope::StatusWrite));
+        assert!(!Scope::StatusWrite.implies(Scope::RepoWrite));
+        assert!(!Scope::RepoRead.implies(Scope::RepoWrite));
+        assert!(!Scope::UserWrite.implies(Scope::RepoRead));
+        assert_eq!("status:write".parse::<Scope>().unwrap(), Scope::StatusWrite);
+        assert!("repo:delete".parse::<Scope>().is_err());
+
+        assert_eq!(
+            route_access(&Method::POST, "/api/repositories/abc/branches"),
+            Some(Access::Scoped(Scope::RepoWrite))
+        );
+        assert_eq!(
+            route_access(&Method::GET, "/api/repositories/abc/branches/main/commits"),
+            Some(Access::Scoped(Scope::RepoRead))
+        );
+        assert_eq!(route_access(&Method::GET, "/api/meta"), Some(Access::Public));
+        assert_eq!(route_access(&Method::PATCH, "/api/meta"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_token_scopes_per_endpoint() {
+        let state = create_test_state().await;
+        let user = state
+            .user_service
+            .create_user("ci".to_string(), "ci@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = state
+            .repository_service
+            .create_repository("scoped".to_string(), None, "main".to_string(), user.id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let git_ops = GitOperations::new(state.repository_service.as_ref().clone());
+        let commit = state
+            .repository_service
+            .transaction(move |txn| {
+                Box::pin(async move {
+                    git_ops
+                        .create_commit(
+                            txn,
+                            repo.id,
+                            CreateCommitRequest {
+                                tree_hash: "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+                                parent_hashes: vec![],
+                                author: "CI <ci@example.com> 1700000000 +0000".to_string(),
+                                committer: "CI <ci@example.com> 1700000000 +0000".to_string(),
+                                message: "Initial commit".to_string(),
+                                author_date: None,
+                                commit_date: None,
+                            },
+                        )
+                        .await
+                })
+            })
+            .await
+            .unwrap();
+
+        let issue = |scopes: &[&str]| {
+            let scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+            let tokens = state.token_service.clone();
+            async move { tokens.create_token(user.id, "test".to_string(), &scopes, None).await.unwrap().1 }
+        };
+        let status_token = issue(&["status:write"]).await;
+        let write_token = issue(&["repo:write"]).await;
+        let read_token = issue(&["repo:read"]).await;
+
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state.clone())).service(
+                web::scope("/api")
+                    .wrap(from_fn(enforce_token_scopes))
+                    .service(list_branches)
+                    .service(create_branch)
+                    .service(create_commit_status),
+            ),
+        )
+        .await;
+
+        let post_status = |token: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/statuses/{}", repo.id, commit))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({ "state": "success", "context": "ci/build" }))
+                .to_request()
+        };
+        let post_branch = |token: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/repositories/{}/branches", repo.id))
+                .insert_header((header::AUTHORIZATION, format!("token {}", token)))
+                .set_json(serde_json::json!({ "name": "feature", "start_commit": commit }))
+                .to_request()
+        };
+
+        // A status-only token can report statuses but not create branches
+        let resp = test::call_service(&app, post_status(&status_token)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = test::call_service(&app, post_branch(&status_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["missing_scope"], "repo:write");
+
+        // Read-only tokens can list but neither write nor report statuses
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/repositories/{}/branches", repo.id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", read_token)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let resp = test::call_service(&app, post_status(&read_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["missing_scope"], "status:write");
+
+        // repo:write covers both
+        let resp = test::call_service(&app, post_branch(&write_token)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = test::call_service(&app, post_status(&write_token)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let statuses = state.status_service.list(repo.id, &commit).await.unwrap();
+        assert_eq!(statuses.len(), 1);
+
+        // Unknown tokens are rejected outright
+        let resp = test::call_service(&app, post_branch("gst_unknown")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_routes_without_declared_scope_fail() {
+        #[actix_web::get("/undeclared")]
+        async fn undeclared() -> HttpResponse {
+            HttpResponse::Ok().finish()
+        }
+
+        let state = create_test_state().await;
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(undeclared),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/api/undeclared").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let req = test::TestRequest::get().uri("/api/nowhere").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_authenticated_user_extractor_rejects_anonymous_requests() {
+        #[actix_web::get("/repositories/{repo_id}/branches")]
+        async fn whoami(AuthenticatedUser(user_id): AuthenticatedUser) -> HttpResponse {
+            HttpResponse::Ok().body(user_id.to_string())
+        }
+
+        let state = create_test_state().await;
+        let user = state
+            .user_service
+            .create_user("ci".to_string(), "ci@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(user.id, "test".to_string(), &["repo:read".to_string()], None)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(whoami),
+            ),
+        )
+        .await;
+
+        let uri = format!("/api/repositories/{}/branches", Uuid::new_v4());
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: ApiResponse<()> = test::read_body_json(resp).await;
+        assert!(!body.success);
+        assert_eq!(body.message, "Authentication required");
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, user.id.to_string());
+    }
+
+    #[actix_web::test]
+    async fn test_caller_credential_sources_and_precedence() {
+        use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+        use actix_web::cookie::Key;
+
+        let state = create_test_state().await;
+        let password_hash = state.user_service.hash_password("s3cret").unwrap();
+        let mut users = Vec::new();
+        for name in ["session", "token", "basic", "inactive"] {
+            let user = state
+                .user_service
+                .create_user(name.to_string(), format!("{}@example.com", name), password_hash.clone(), None, false)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        state
+            .user_service
+            .update_user(users[3].id, None, None, None, None, Some(false), None)
+            .await
+            .unwrap();
+        let (_, token) = state
+            .token_service
+            .create_token(users[1].id, "test".to_string(), &["user:read".to_string()], None)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+                .service(
+                    web::scope("/api").wrap(from_fn(enforce_token_scopes)).service(
+                        web::scope("/auth")
+                            .service(crate::auth::login)
+                            .service(crate::auth::get_current_user),
+                    ),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/login")
+            .set_json(serde_json::json!({"username_or_email": "session", "password": "s3cret"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+
+        let bearer = (header::AUTHORIZATION, format!("Bearer {}", token));
+        let basic = |user: &str, password: &str| {
+            (header::AUTHORIZATION, format!("Basic {}", BASE64_STANDARD.encode(format!("{}:{}", user, password))))
+        };
+        let me = || test::TestRequest::get().uri("/api/auth/me");
+        let whoami = |req: test::TestRequest| {
+            let app = &app;
+            async move {
+                let resp = test::call_service(app, req.to_request()).await;
+                let status = resp.status();
+                let body: serde_json::Value = test::read_body_json(resp).await;
+                (status, body["user"]["username"].as_str().map(str::to_string))
+            }
+        };
+        let signed_in = |name: &str| (StatusCode::OK, Some(name.to_string()));
+
+        // Each source on its own
+        assert_eq!(whoami(me().cookie(cookie.clone())).await, signed_in("session"));
+        assert_eq!(whoami(me().insert_header(bearer.clone())).await, signed_in("token"));
+        assert_eq!(whoami(me().insert_header(basic("basic", "s3cret"))).await, signed_in("basic"));
+        assert_eq!(whoami(me()).await, (StatusCode::UNAUTHORIZED, None));
+
+        // The session wins over a token or a password, even an invalid one
+        assert_eq!(whoami(me().cookie(cookie.clone()).insert_header(bearer.clone())).await, signed_in("session"));
+        assert_eq!(
+            whoami(me().cookie(cookie.clone()).insert_header(basic("basic", "s3cret"))).await,
+            signed_in("session")
+        );
+        let bad_token = (header::AUTHORIZATION, "Bearer not-a-token".to_string());
+        assert_eq!(whoami(me().cookie(cookie.clone()).insert_header(bad_token)).await, signed_in("session"));
+
+        // Wrong passwords and inactive accounts are refused
+        assert_eq!(whoami(me().insert_header(basic("basic", "wrong"))).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(whoami(me().insert_header(basic("inactive", "s3cret"))).await.0, StatusCode::FORBIDDEN);
+    }
+}
@@ -1,6 +1,8 @@
-use git_storage::{RepositoryService, UserService};
+use crate::client::{ClientInfo, ClientPolicy};
+use crate::config::ProtocolSettings;
+use git_storage::{RepositoryService, SshKeyService, UserService};
 use git_protocol::{GitProtocol, ProtocolHandler};
-use russh::server::{Auth, Msg, Session, Server};
+use russh::server::{Auth, Msg, Session};
 use russh::{Channel, ChannelId, CryptoVec};
 use russh_keys::key;
 use async_trait::async_trait;
@@ -9,33 +11,75 @@ use std::sync::Arc;
 use tracing::{info, debug, error, warn};
 use tokio::sync::Mutex;
 
+/// Exit status of a failed command, the one git's `die()` uses
+const FAILURE_EXIT_STATUS: u32 = 128;
+
 /// SSH Git server implementation
+// Fields are only consumed by the russh `Server` impl, which is disabled below
+#[allow(dead_code)]
 #[derive(Clone)]
 pub struct GitSshServer {
     repository_service: Arc<RepositoryService>,
     user_service: Arc<UserService>,
+    ssh_key_service: Arc<SshKeyService>,
     protocol_handler: ProtocolHandler,
+    client_policy: Arc<ClientPolicy>,
+    protocol_settings: ProtocolSettings,
     sessions: Arc<Mutex<HashMap<usize, GitSshSession>>>,
 }
 
 /// Individual SSH session for Git operations
+#[allow(dead_code)]
 pub struct GitSshSession {
     session_id: usize,
     authenticated_user: Option<String>,
     current_command: Option<String>,
+    /// `GIT_PROTOCOL` sent by the client before its exec request
+    git_protocol: Option<String>,
     repository_service: Arc<RepositoryService>,
+    user_service: Arc<UserService>,
+    ssh_key_service: Arc<SshKeyService>,
     protocol_handler: ProtocolHandler,
+    client_policy: Arc<ClientPolicy>,
+    protocol_settings: ProtocolSettings,
 }
 
 impl GitSshServer {
-    pub fn new(repository_service: Arc<RepositoryService>, user_service: Arc<UserService>) -> Self {
+    pub fn new(
+        repository_service: Arc<RepositoryService>,
+        user_service: Arc<UserService>,
+        ssh_key_service: Arc<SshKeyService>,
+        client_policy: Arc<ClientPolicy>,
+        protocol_settings: ProtocolSettings,
+    ) -> Self {
         Self {
             repository_service,
             user_service,
+            ssh_key_service,
             protocol_handler: ProtocolHandler::new(),
+            client_policy,
+            protocol_settings,
             sessions: Arc::new(Mutex::new(HashMap::new())),
         }
     }
+
+    /// A fresh, unauthenticated session for a new connection
+    // Only called by the disabled `Server` impl below, and by tests
+    #[allow(dead_code)]
+    fn session(&self, session_id: usize) -> GitSshSession {
+        GitSshSession {
+            session_id,
+            authenticated_user: None,
+            current_command: None,
+            git_protocol: None,
+            repository_service: Arc::clone(&self.repository_service),
+            user_service: Arc::clone(&self.user_service),
+            ssh_key_service: Arc::clone(&self.ssh_key_service),
+            protocol_handler: ProtocolHandler::new(),
+            client_policy: Arc::clone(&self.client_policy),
+            protocol_settings: self.protocol_settings.clone(),
+        }
+    }
 }
 
 // TODO: Properly implement russh Server trait once API compatibility is resolved
@@ -52,13 +96,7 @@ impl russh::server::Server for GitSshServer {
         let session_id = rand::random::<usize>();
         info!("New SSH client connected with session ID: {}", session_id);
 
-        GitSshSession {
-            session_id,
-            authenticated_user: None,
-            current_command: None,
-            repository_service: Arc::clone(&self.repository_service),
-            protocol_handler: ProtocolHandler::new(),
-        }
+        self.session(session_id)
     }
 }
 */
@@ -79,28 +117,57 @@ impl russh::server::Handler for GitSshSession {
     async fn auth_publickey(
         &mut self,
         user: &str,
-        _public_key: &key::PublicKey,
+        public_key: &key::PublicKey,
     ) -> Result<Auth, Self::Error> {
         info!("SSH public key authentication attempt for user: {}", user);
-        
-        // For now, accept any public key - in production you'd verify against stored keys
-        self.authenticated_user = Some(user.to_string());
-        Ok(Auth::Accept)
+
+        // Clients connect as a shared user such as `git`, so the key alone says who
+        // they are: it must be registered to an active account
+        let fingerprint = format!("SHA256:{}", public_key.fingerprint());
+        let owner = match self.ssh_key_service.get_key_by_fingerprint(&fingerprint).await? {
+            Some(key) => self.user_service.get_user_by_id(key.user_id).await?,
+            None => None,
+        };
+        match owner.filter(|owner| owner.is_active) {
+            Some(owner) => Ok(self.accept(owner.username)),
+            None => {
+                warn!("Rejecting unknown SSH key {}", fingerprint);
+                Ok(Auth::Reject { proceed_with_methods: None })
+            }
+        }
     }
 
     async fn auth_password(
         &mut self,
         user: &str,
-        _password: &str,
+        password: &str,
     ) -> Result<Auth, Self::Error> {
         info!("SSH password authentication attempt for user: {}", user);
         
         // Note: In production, you would not typically allow password auth for Git
         // but we'll support it for development purposes
         warn!("Password authentication is not recommended for Git SSH access");
-        
-        self.authenticated_user = Some(user.to_string());
-        Ok(Auth::Accept)
+
+        match self.user_service.authenticate(user, password).await? {
+            Some(account) if account.is_active => Ok(self.accept(account.username)),
+            _ => {
+                warn!("Rejecting SSH password for {}", user);
+                Ok(Auth::Reject { proceed_with_methods: None })
+            }
+        }
+    }
+
+    async fn env_request(
+        &mut self,
+        _channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if variable_name == "GIT_PROTOCOL" {
+            self.git_protocol = Some(variable_value.to_string());
+        }
+        Ok(())
     }
 
     async fn exec_request(
@@ -121,9 +188,7 @@ impl russh::server::Handler for GitSshSession {
             self.handle_upload_pack(channel, &command, session).await?;
         } else {
             error!("Unsupported command: {}", command);
-            session.data(channel, CryptoVec::from_slice(b"Unsupported command\n"));
-            session.eof(channel);
-            session.close(channel);
+            fail(channel, session, &format!("unsupported command: {}", command));
         }
 
         Ok(())
@@ -149,6 +214,11 @@ impl russh::server::Handler for GitSshSession {
 }
 
 impl GitSshSession {
+    fn accept(&mut self, username: String) -> Auth {
+        self.authenticated_user = Some(username);
+        Auth::Accept
+    }
+
     /// Handle git-receive-pack (push) operations
     async fn handle_receive_pack(
         &mut self,
@@ -167,7 +237,8 @@ impl GitSshSession {
             ("refs/heads/main".to_string(), "0000000000000000000000000000000000000000".to_string()),
         ];
         
-        let capabilities = ["report-status", "delete-refs", "ofs-delta", "side-band-64k"];
+        let capabilities = self.protocol_settings.receive_pack_capabilities();
+        let capabilities: Vec<&str> = capabilities.iter().map(String::as_str).collect();
         let advertisement = self.protocol_handler.create_ref_advertisement(&refs, &capabilities);
         
         session.data(channel, CryptoVec::from_slice(&advertisement));
@@ -183,7 +254,15 @@ impl GitSshSession {
         session: &mut Session,
     ) -> Result<(), anyhow::Error> {
         info!("Handling git-upload-pack: {}", command);
-        
+
+        // Sessions authenticate before any exec, so SSH has no anonymous clones to
+        // allow; refuse one that gets here unauthenticated whatever the settings say
+        if self.authenticated_user.is_none() {
+            warn!("Refusing unauthenticated git-upload-pack");
+            fail(channel, session, "authentication required");
+            return Ok(());
+        }
+
         // Extract repository path from command
         let repo_path = self.extract_repo_path(command)?;
         info!("Repository path: {}", repo_path);
@@ -193,7 +272,8 @@ impl GitSshSession {
             ("refs/heads/main".to_string(), "1234567890abcdef1234567890abcdef12345678".to_string()),
         ];
         
-        let capabilities = ["multi_ack", "ofs-delta", "side-band-64k", "thin-pack"];
+        let capabilities = self.protocol_settings.upload_pack_capabilities();
+        let capabilities: Vec<&str> = capabilities.iter().map(String::as_str).collect();
         let advertisement = self.protocol_handler.create_ref_advertisement(&refs, &capabilities);
         
         session.data(channel, CryptoVec::from_slice(&advertisement));
@@ -204,22 +284,34 @@ impl GitSshSession {
     /// Handle incoming pack data
     async fn handle_pack_data(
         &mut self,
-        _channel: ChannelId,
+        channel: ChannelId,
         data: &[u8],
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<(), anyhow::Error> {
         debug!("Processing pack data: {} bytes", data.len());
         
         // Parse pkt-line format
-        match self.protocol_handler.parse_pkt_line(data) {
-            Ok(lines) => {
-                for line in lines {
-                    debug!("Pack line: {}", line);
-                    // Process Git protocol messages
-                    if line.starts_with("want") || line.starts_with("have") {
-                        // Handle want/have negotiation
-                        debug!("Negotiation: {}", line);
-                    }
+        match self.protocol_handler.parse_receive_pack_request(data) {
+            Ok(request) => {
+                let client = ClientInfo::from_ssh(self.git_protocol.as_deref(), &request.capabilities);
+                info!(
+                    agent = client.agent.as_deref().unwrap_or_default(),
+                    protocol_version = client.protocol_version,
+                    "SSH push"
+                );
+
+                if let Err(message) = self.client_policy.check_push(&client) {
+                    warn!("Push rejected by client policy: {}", message);
+                    let response = if request.uses_sideband() {
+                        self.protocol_handler.create_sideband_error(&message)
+                    } else {
+                        self.protocol_handler.create_pkt_line(&[&format!("ERR {}", message)])
+                    };
+                    // The client is mid-exchange and reads the error from the protocol stream
+                    session.data(channel, CryptoVec::from_slice(&response));
+                    session.exit_status_request(channel, FAILURE_EXIT_STATUS);
+                    session.eof(channel);
+                    session.close(channel);
                 }
             }
             Err(e) => {
@@ -255,16 +347,14 @@ impl GitSshSession {
 pub async fn start_ssh_server(
     repository_service: Arc<RepositoryService>,
     user_service: Arc<UserService>,
+    ssh_key_service: Arc<SshKeyService>,
+    client_policy: Arc<ClientPolicy>,
+    protocol_settings: ProtocolSettings,
+    bind_address: String,
+    server_key: key::KeyPair,
 ) -> anyhow::Result<()> {
-    let bind_address = std::env::var("SSH_BIND_ADDRESS")
-        .unwrap_or_else(|_| "127.0.0.1:2222".to_string());
-
     info!("Starting SSH Git server on {}", bind_address);
 
-    // Generate or load server keys
-    let server_key = russh_keys::key::KeyPair::generate_ed25519()
-        .ok_or_else(|| anyhow::anyhow!("Failed to generate server key"))?;
-
     // Create SSH server configuration
     let _config = russh::server::Config {
         keys: vec![server_key],
@@ -272,7 +362,7 @@ pub async fn start_ssh_server(
     };
 
     // Create the SSH server
-    let _server = GitSshServer::new(repository_service, user_service);
+    let _server = GitSshServer::new(repository_service, user_service, ssh_key_service, client_policy, protocol_settings);
 
     // Start listening
     info!("SSH server would listen on {}", bind_address);
@@ -289,4 +379,65 @@ pub async fn start_ssh_server(
     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// End a command that failed before the exchange began: the message goes to the
+/// channel's stderr, which git prints verbatim, and the exit status is non-zero
+fn fail(channel: ChannelId, session: &mut Session, message: &str) {
+    session.extended_data(channel, 1, CryptoVec::from_slice(format!("fatal: {}\n", message).as_bytes()));
+    session.exit_status_request(channel, FAILURE_EXIT_STATUS);
+    session.eof(channel);
+    session.close(channel);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{create_test_state, create_user_with_password};
+    use git_storage::NewSshKey;
+    use russh::server::Handler;
+    use russh_keys::PublicKeyBase64;
+
+    #[tokio::test]
+    async fn test_only_registered_keys_and_real_passwords_authenticate() {
+        let state = create_test_state().await;
+        let owner = create_user_with_password(&state, "owner").await;
+        let registered = key::KeyPair::generate_ed25519().unwrap().clone_public_key().unwrap();
+        state
+            .ssh_key_service
+            .add_key(
+                owner.id,
+                NewSshKey {
+                    key_type: "ssh-ed25519".to_string(),
+                    public_key: registered.public_key_base64(),
+                    fingerprint: format!("SHA256:{}", registered.fingerprint()),
+                    comment: None,
+                },
+            )
+            .await
+            .unwrap();
+        let server = GitSshServer::new(
+            state.repository_service.clone(),
+            state.user_service.clone(),
+            state.ssh_key_service.clone(),
+            state.client_policy.clone(),
+            state.config.protocol.clone(),
+        );
+
+        // Clients log in as `git`; the key decides who they are
+        let mut session = server.session(1);
+        assert!(matches!(session.auth_publickey("git", &registered).await.unwrap(), Auth::Accept));
+        assert_eq!(session.authenticated_user.as_deref(), Some("owner"));
+
+        let unknown = key::KeyPair::generate_ed25519().unwrap().clone_public_key().unwrap();
+        let mut session = server.session(2);
+        assert!(matches!(session.auth_publickey("owner", &unknown).await.unwrap(), Auth::Reject { .. }));
+        assert_eq!(session.authenticated_user, None);
+
+        let mut session = server.session(3);
+        assert!(matches!(session.auth_password("owner", "wrong").await.unwrap(), Auth::Reject { .. }));
+        assert_eq!(session.authenticated_user, None);
+        assert!(matches!(session.auth_password("owner", "s3cret").await.unwrap(), Auth::Accept));
+        assert_eq!(session.authenticated_user.as_deref(), Some("owner"));
+    }
+}
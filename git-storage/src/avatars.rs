@@ -0,0 +1,66 @@
+use anyhow::{bail, Result};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Directory under the blob store root holding uploaded avatars. It isn't a
+/// two-hex-digit shard, so blob checks pass over it.
+const AVATAR_DIR: &str = "avatars";
+
+/// Uploaded avatar images, kept beside the blob store. Each upload gets a fresh
+/// key naming a directory with one PNG per canonical size, so a new upload never
+/// changes files a cached URL may still point at.
+pub struct AvatarStore {
+    root: PathBuf,
+}
+
+impl AvatarStore {
+    pub fn new(blob_storage_path: impl AsRef<Path>) -> Self {
+        Self { root: blob_storage_path.as_ref().to_path_buf() }
+    }
+
+    /// Store `images`, each a size and its PNG bytes, and return their key
+    pub fn write(&self, user_id: Uuid, images: &[(u32, Vec<u8>)]) -> Result<String> {
+        let key = format!("{}/{}/{}", AVATAR_DIR, user_id, Uuid::new_v4());
+        let dir = self.root.join(&key);
+        fs::create_dir_all(&dir)?;
+        for (size, png) in images {
+            let path = dir.join(format!("{}.png", size));
+            // Written beside the final path and renamed, so readers never see a partial file
+            let partial = path.with_extension(format!("tmp-{}", Uuid::new_v4()));
+            fs::write(&partial, png)?;
+            fs::rename(&partial, &path)?;
+        }
+        Ok(key)
+    }
+
+    /// The PNG stored under `key` at `size`, `None` if there isn't one
+    pub fn read(&self, key: &str, size: u32) -> Result<Option<Vec<u8>>> {
+        let path = self.dir(key)?.join(format!("{}.png", size));
+        match fs::read(path) {
+            Ok(png) => Ok(Some(png)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove every image stored under `key`
+    pub fn remove(&self, key: &str) -> Result<()> {
+        match fs::remove_dir_all(self.dir(key)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Keys come from the database, but are still kept to the avatar directory
+    fn dir(&self, key: &str) -> Result<PathBuf> {
+        let mut parts = key.split('/');
+        let valid = parts.next() == Some(AVATAR_DIR)
+            && parts.all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_hexdigit() || c == '-'));
+        if !valid {
+            bail!("invalid avatar key {:?}", key);
+        }
+        Ok(self.root.join(key))
+    }
+}
@@ -0,0 +1,316 @@
+use crate::entities::git_object::ObjectKind;
+use crate::entities::{commit, git_object, tag, tree};
+use crate::RepositoryService;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use git_protocol::objects::ObjectHandler;
+use git_protocol::HashAlgorithm;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, EntityTrait, PaginatorTrait, QueryFilter,
+    QueryOrder, QuerySelect,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Objects read from `git_objects` per transaction
+const BACKFILL_PAGE_SIZE: u64 = 500;
+
+/// Outcome of copying a repository's commits, trees and tags into their typed tables
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackfillReport {
+    pub commits: u64,
+    pub trees: u64,
+    pub tags: u64,
+    /// Objects already present in their typed table
+    pub skipped: u64,
+    /// Objects that could not be parsed, left only in `git_objects`
+    pub failed: Vec<String>,
+}
+
+impl RepositoryService {
+    /// Copy commit, tree and tag objects stored in `git_objects` into the `commits`,
+    /// `trees` and `tags` tables. Rows already present are skipped, so the backfill
+    /// can be re-run or resumed after a failure. The `git_objects` rows stay in place
+    /// since reads still go through them.
+    pub async fn backfill_typed_tables(&self, repository_id: Uuid) -> Result<BackfillReport> {
+        let mut report = BackfillReport::default();
+        let mut after: Option<String> = None;
+
+        loop {
+            let mut query = git_object::Entity::find()
+                .filter(git_object::Column::RepositoryId.eq(repository_id))
+                .filter(git_object::Column::ObjectType.is_in([ObjectKind::Commit, ObjectKind::Tree, ObjectKind::Tag]))
+                .order_by_asc(git_object::Column::Id)
+                .limit(BACKFILL_PAGE_SIZE);
+            if let Some(after) = &after {
+                query = query.filter(git_object::Column::Id.gt(after.as_str()));
+            }
+            let page = query.all(self.get_db()).await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(last.id.clone());
+            let full_page = page.len() as u64 == BACKFILL_PAGE_SIZE;
+
+            let objects = page
+                .into_iter()
+                .map(|obj| self.load_object_content(obj))
+                .collect::<Result<Vec<_>>>()?;
+            let page_report = self
+                .transaction(move |txn| {
+                    Box::pin(async move {
+                        let mut report = BackfillReport::default();
+                        for obj in objects {
+                            backfill_object(txn, repository_id, &obj.id, obj.object_type, &obj.content, &mut report)
+                                .await?;
+                        }
+                        Ok(report)
+                    })
+                })
+                .await?;
+
+            report.commits += page_report.commits;
+            report.trees += page_report.trees;
+            report.tags += page_report.tags;
+            report.skipped += page_report.skipped;
+            report.failed.extend(page_report.failed);
+
+            if !full_page {
+                break;
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+async fn backfill_object<C: ConnectionTrait>(
+    db: &C,
+    repository_id: Uuid,
+    id: &str,
+    object_type: ObjectKind,
+    content: &[u8],
+    report: &mut BackfillReport,
+) -> Result<()> {
+    let now = Utc::now();
+
+    match object_type {
+        ObjectKind::Commit => {
+            if commit::Entity::find_by_id(id).count(db).await? > 0 {
+                report.skipped += 1;
+                return Ok(());
+            }
+            let Some(row) = commit_row(repository_id, id, content, now)? else {
+                report.failed.push(id.to_string());
+                return Ok(());
+            };
+            commit::ActiveModel::from(row).insert(db).await?;
+            report.commits += 1;
+        }
+        ObjectKind::Tree => {
+            if tree::Entity::find_by_id(id).count(db).await? > 0 {
+                report.skipped += 1;
+                return Ok(());
+            }
+            let Some(row) = tree_row(repository_id, id, content, now)? else {
+                report.failed.push(id.to_string());
+                return Ok(());
+            };
+            tree::ActiveModel::from(row).insert(db).await?;
+            report.trees += 1;
+        }
+        ObjectKind::Tag => {
+            let exists = tag::Entity::find()
+                .filter(tag::Column::RepositoryId.eq(repository_id))
+                .filter(tag::Column::TagObjectId.eq(id))
+                .count(db)
+                .await?
+                > 0;
+            if exists {
+                report.skipped += 1;
+                return Ok(());
+            }
+            let Some(row) = annotated_tag_row(repository_id, id, content, now) else {
+                report.failed.push(id.to_string());
+                return Ok(());
+            };
+            tag::ActiveModel::from(row).insert(db).await?;
+            report.tags += 1;
+        }
+        ObjectKind::Blob => {}
+    }
+
+    Ok(())
+}
+
+/// The `commits` row for a commit object, `None` if it doesn't parse
+pub(crate) fn commit_row(
+    repository_id: Uuid,
+    id: &str,
+    content: &[u8],
+    now: DateTime<Utc>,
+) -> Result<Option<commit::Model>> {
+    let Ok(parsed) = ObjectHandler::new().parse_commit(content) else {
+        return Ok(None);
+    };
+    let (author_name, author_email) = split_signature(&parsed.author);
+    let (committer_name, committer_email) = split_signature(&parsed.committer);
+
+    Ok(Some(commit::Model {
+        id: id.to_string(),
+        repository_id,
+        parent_ids: Some(serde_json::to_string(&parsed.parents)?),
+        tree_id: parsed.tree,
+        author_name,
+        author_email,
+        author_date: parsed.author_date.into(),
+        committer_name,
+        committer_email,
+        committer_date: parsed.commit_date.into(),
+        message: parsed.message,
+        content: content.to_vec(),
+        created_at: now.into(),
+    }))
+}
+
+/// The `trees` row for a tree object, `None` if it doesn't parse
+pub(crate) fn tree_row(
+    repository_id: Uuid,
+    id: &str,
+    content: &[u8],
+    now: DateTime<Utc>,
+) -> Result<Option<tree::Model>> {
+    // A tree's entries are named in the same format as the tree itself
+    let hash = HashAlgorithm::from_hex_len(id.len()).unwrap_or_default();
+    let Ok(parsed) = ObjectHandler::new().with_hash_algorithm(hash).parse_tree(content) else {
+        return Ok(None);
+    };
+    let entries: Vec<serde_json::Value> = parsed
+        .entries
+        .iter()
+        .map(|e| serde_json::json!({ "mode": e.mode, "name": e.name, "sha": e.hash }))
+        .collect();
+
+    Ok(Some(tree::Model {
+        id: id.to_string(),
+        repository_id,
+        entries: serde_json::to_string(&entries)?,
+        size: content.len() as i64,
+        content: content.to_vec(),
+        created_at: now.into(),
+    }))
+}
+
+/// The `tags` row for an annotated tag object, named as the object names it;
+/// `None` if it doesn't parse
+pub(crate) fn annotated_tag_row(
+    repository_id: Uuid,
+    id: &str,
+    content: &[u8],
+    now: DateTime<Utc>,
+) -> Option<tag::Model> {
+    let parsed = ObjectHandler::new().parse_tag(content).ok()?;
+    let (tagger_name, tagger_email) = split_signature(&parsed.tagger);
+    let tagger_date = parsed.tagger_date.map(Into::into);
+
+    Some(tag::Model {
+        id: Uuid::new_v4(),
+        repository_id,
+        name: parsed.tag_name,
+        target_id: parsed.object,
+        target_type: ObjectKind::from(&parsed.obj_type),
+        tag_object_id: Some(id.to_string()),
+        tagger_name: Some(tagger_name).filter(|n| !n.is_empty()),
+        tagger_email: Some(tagger_email).filter(|e| !e.is_empty()),
+        tagger_date,
+        message: Some(parsed.message),
+        content: Some(content.to_vec()),
+        is_lightweight: false,
+        created_at: now.into(),
+        updated_at: now.into(),
+    })
+}
+
+/// Split `Name <email> 1700000000 +0000` into its name and email
+fn split_signature(signature: &str) -> (String, String) {
+    match (signature.find('<'), signature.find('>')) {
+        (Some(start), Some(end)) if start < end => (
+            signature[..start].trim().to_string(),
+            signature[start + 1..end].to_string(),
+        ),
+        _ => (signature.trim().to_string(), String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_db, run_migrations, UserService};
+    use crate::entities::repository::Visibility;
+    use git_protocol::objects::Commit;
+
+    #[tokio::test]
+    async fn test_backfill_copies_commits_into_typed_table() {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let blob_storage_path = std::env::temp_dir().join(format!("git-storage-test-{}", Uuid::new_v4()));
+        let service = RepositoryService::new(db.clone(), Some(blob_storage_path));
+        let owner = UserService::new(db.clone())
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = service
+            .create_repository("legacy".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        // Seed the way objects were stored before the typed tables existed
+        let handler = ObjectHandler::new();
+        let tree = handler.parse_object(git_protocol::ObjectType::Tree, b"").unwrap();
+        let commit = handler
+            .create_commit(&Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "C O Mitter <committer@example.com> 1700000100 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: Utc::now(),
+                commit_date: Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let blob = handler.create_blob(b"hello").unwrap();
+        for obj in [&tree, &commit, &blob] {
+            let object_type = ObjectKind::from(&obj.obj_type);
+            service
+                .store_object(repo.id, obj.id.clone(), object_type, obj.size as i64, obj.content.clone())
+                .await
+                .unwrap();
+        }
+        assert_eq!(commit::Entity::find().count(&db).await.unwrap(), 0);
+
+        let report = service.backfill_typed_tables(repo.id).await.unwrap();
+        assert_eq!(
+            report,
+            BackfillReport { commits: 1, trees: 1, tags: 0, skipped: 0, failed: vec![] }
+        );
+
+        let row = commit::Entity::find_by_id(commit.id.as_str()).one(&db).await.unwrap().unwrap();
+        assert_eq!(row.repository_id, repo.id);
+        assert_eq!(row.tree_id, tree.id);
+        assert_eq!(row.parent_ids.as_deref(), Some("[]"));
+        assert_eq!(row.author_name, "A U Thor");
+        assert_eq!(row.author_email, "author@example.com");
+        assert_eq!(row.committer_name, "C O Mitter");
+        assert_eq!(row.committer_date.timestamp(), 1700000100);
+        assert_eq!(row.message, "Initial commit\n");
+        assert_eq!(row.content, commit.content);
+
+        // Running again changes nothing
+        let report = service.backfill_typed_tables(repo.id).await.unwrap();
+        assert_eq!(report.skipped, 2);
+        assert_eq!(report.commits + report.trees, 0);
+        assert_eq!(commit::Entity::find().count(&db).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,491 @@
+use crate::entities::{blob_ref, branch, git_object, repository, tag};
+use crate::repository::{resolve_ref, RepositoryService, MAX_BLOB_SHARD_DEPTH};
+use crate::StorageError;
+use anyhow::Result;
+use chrono::Utc;
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, QuerySelect, Set};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Orphaned blob files younger than this may belong to a write still in flight
+pub const DEFAULT_ORPHAN_MIN_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Expected drift, e.g. typed tables not yet backfilled
+    Info,
+    /// Inconsistent but nothing is served wrongly
+    Warning,
+    /// Reads or fetches will fail
+    Error,
+}
+
+/// One problem found by `check_consistency`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Stable code, e.g. `ref_target_missing`
+    pub code: String,
+    /// `None` for findings about the shared blob store
+    pub repository_id: Option<Uuid>,
+    /// Ref name, object id or file the finding is about
+    pub subject: String,
+    pub message: String,
+    /// Repaired by `--fix`
+    #[serde(default)]
+    pub fixed: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CheckReport {
+    pub repositories_checked: u64,
+    pub findings: Vec<Finding>,
+}
+
+impl Finding {
+    fn new(severity: Severity, code: &str, repository_id: Option<Uuid>, subject: &str, message: String) -> Self {
+        Self {
+            severity,
+            code: code.to_string(),
+            repository_id,
+            subject: subject.to_string(),
+            message,
+            fixed: false,
+        }
+    }
+}
+
+impl CheckReport {
+    /// Whether any unrepaired finding is an error
+    pub fn has_errors(&self) -> bool {
+        self.findings.iter().any(|f| f.severity == Severity::Error && !f.fixed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckOptions {
+    /// Apply the safe repairs: recreate missing `branches` rows and delete orphaned
+    /// blob files older than `orphan_min_age`
+    pub fix: bool,
+    pub orphan_min_age: Duration,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        Self {
+            fix: false,
+            orphan_min_age: DEFAULT_ORPHAN_MIN_AGE,
+        }
+    }
+}
+
+impl RepositoryService {
+    /// Check stored data for consistency: ref targets, blob files, the `branches` and
+    /// `tags` tables against the refs, and each default branch. Without a repository
+    /// every repository is checked and the blob store is scanned for orphaned files.
+    pub async fn check_consistency(
+        &self,
+        repository_id: Option<Uuid>,
+        options: &CheckOptions,
+    ) -> Result<CheckReport> {
+        let repositories = match repository_id {
+            Some(id) => vec![self
+                .get_repository_by_id(id)
+                .await?
+                .ok_or(StorageError::RepositoryNotFound { id })?],
+            None => self.list_repositories().await?,
+        };
+
+        let mut report = CheckReport::default();
+        for repo in &repositories {
+            self.check_repository(repo, options, &mut report.findings).await?;
+            report.repositories_checked += 1;
+        }
+        if repository_id.is_none() {
+            self.check_orphaned_blob_files(options, &mut report.findings).await?;
+        }
+        Ok(report)
+    }
+
+    async fn check_repository(
+        &self,
+        repo: &repository::Model,
+        options: &CheckOptions,
+        findings: &mut Vec<Finding>,
+    ) -> Result<()> {
+        let refs = self.get_refs_by_repository(repo.id).await?;
+        let object_types: HashMap<String, String> = git_object::Entity::find()
+            .select_only()
+            .columns([git_object::Column::Id, git_object::Column::ObjectType])
+            .filter(git_object::Column::RepositoryId.eq(repo.id))
+            .into_tuple::<(String, String)>()
+            .all(self.get_db())
+            .await?
+            .into_iter()
+            .collect();
+
+        for git_ref in &refs {
+            if git_ref.is_symbolic {
+                match resolve_ref(&refs, &git_ref.name) {
+                    Err(_) => {
+                        findings.push(Finding::new(
+                            Severity::Error,
+                            "symbolic_ref_cycle",
+                            Some(repo.id),
+                            &git_ref.name,
+                            format!("symbolic ref {} loops back on itself", git_ref.name),
+                        ));
+                    }
+                    // HEAD on an unborn default branch is how every repository starts
+                    Ok(None) if git_ref.name != "HEAD" => {
+                        findings.push(Finding::new(
+                            Severity::Warning,
+                            "symbolic_ref_dangling",
+                            Some(repo.id),
+                            &git_ref.name,
+                            format!("symbolic ref {} points at missing ref {}", git_ref.name, git_ref.target),
+                        ));
+                    }
+                    Ok(_) => {}
+                }
+                continue;
+            }
+
+            let acceptable: &[&str] = if git_ref.name.starts_with("refs/heads/") {
+                &["commit"]
+            } else if git_ref.name.starts_with("refs/tags/") {
+                &["commit", "tag", "tree", "blob"]
+            } else {
+                &["commit", "tag"]
+            };
+            match object_types.get(&git_ref.target) {
+                None => {
+                    findings.push(Finding::new(
+                        Severity::Error,
+                        "ref_target_missing",
+                        Some(repo.id),
+                        &git_ref.name,
+                        format!("{} points at {}, which is not stored", git_ref.name, git_ref.target),
+                    ));
+                }
+                Some(object_type) if !acceptable.contains(&object_type.as_str()) => {
+                    findings.push(Finding::new(
+                        Severity::Error,
+                        "ref_target_type",
+                        Some(repo.id),
+                        &git_ref.name,
+                        format!("{} points at a {} ({})", git_ref.name, object_type, git_ref.target),
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+
+        let blobs = git_object::Entity::find()
+            .select_only()
+            .columns([git_object::Column::Id, git_object::Column::Size, git_object::Column::BlobPath])
+            .filter(git_object::Column::RepositoryId.eq(repo.id))
+            .filter(git_object::Column::BlobPath.is_not_null())
+            .into_tuple::<(String, i64, String)>()
+            .all(self.get_db())
+            .await?;
+        for (id, size, blob_path) in blobs {
+            match fs::metadata(self.locate_blob_file(&blob_path, &id)) {
+                Err(_) => {
+                    findings.push(Finding::new(
+                        Severity::Error,
+                        "blob_file_missing",
+                        Some(repo.id),
+                        &id,
+                        format!("blob file {} is missing or unreadable", blob_path),
+                    ));
+                }
+                Ok(metadata) if metadata.len() != size as u64 => {
+                    findings.push(Finding::new(
+                        Severity::Error,
+                        "blob_file_size",
+                        Some(repo.id),
+                        &id,
+                        format!("blob file {} has {} bytes, expected {}", blob_path, metadata.len(), size),
+                    ));
+                }
+                Ok(_) => {}
+            }
+        }
+
+        let branch_refs: HashMap<&str, &str> = refs
+            .iter()
+            .filter(|r| !r.is_symbolic)
+            .filter_map(|r| Some((r.name.strip_prefix("refs/heads/")?, r.target.as_str())))
+            .collect();
+        let branch_rows = branch::Entity::find()
+            .filter(branch::Column::RepositoryId.eq(repo.id))
+            .all(self.get_db())
+            .await?;
+        let mut has_row = HashSet::new();
+        for row in &branch_rows {
+            has_row.insert(row.name.as_str());
+            match branch_refs.get(row.name.as_str()) {
+                Some(target) if *target == row.commit_id => {}
+                Some(target) => {
+                    findings.push(Finding::new(
+                        Severity::Warning,
+                        "branch_row_stale",
+                        Some(repo.id),
+                        &row.name,
+                        format!("branches row says {}, the ref is at {}", row.commit_id, target),
+                    ));
+                }
+                None => {
+                    findings.push(Finding::new(
+                        Severity::Warning,
+                        "branch_row_stale",
+                        Some(repo.id),
+                        &row.name,
+                        format!("branches row for {} has no matching ref", row.name),
+                    ));
+                }
+            }
+        }
+        let mut missing_rows: Vec<(&str, &str)> = branch_refs
+            .iter()
+            .filter(|(name, _)| !has_row.contains(**name))
+            .map(|(name, target)| (*name, *target))
+            .collect();
+        missing_rows.sort_unstable();
+        for (name, target) in missing_rows {
+            let mut finding = Finding::new(
+                Severity::Info,
+                "branch_row_missing",
+                Some(repo.id),
+                name,
+                format!("branch {} has no branches row", name),
+            );
+            if options.fix {
+                let now = Utc::now();
+                branch::ActiveModel {
+                    id: Set(Uuid::new_v4()),
+                    repository_id: Set(repo.id),
+                    name: Set(name.to_string()),
+                    commit_id: Set(target.to_string()),
+                    is_default: Set(name == repo.default_branch),
+                    created_at: Set(now.into()),
+                    updated_at: Set(now.into()),
+                }
+                .insert(self.get_db())
+                .await?;
+                finding.fixed = true;
+            }
+            findings.push(finding);
+        }
+
+        let tag_refs: HashMap<&str, &str> = refs
+            .iter()
+            .filter(|r| !r.is_symbolic)
+            .filter_map(|r| Some((r.name.strip_prefix("refs/tags/")?, r.target.as_str())))
+            .collect();
+        let tag_rows = tag::Entity::find()
+            .filter(tag::Column::RepositoryId.eq(repo.id))
+            .all(self.get_db())
+            .await?;
+        for row in &tag_rows {
+            let expected = row.tag_object_id.as_deref().unwrap_or(&row.target_id);
+            if tag_refs.get(row.name.as_str()) != Some(&expected) {
+                findings.push(Finding::new(
+                    Severity::Warning,
+                    "tag_row_stale",
+                    Some(repo.id),
+                    &row.name,
+                    format!("tags row for {} doesn't match refs/tags/{}", row.name, row.name),
+                ));
+            }
+        }
+
+        if !branch_refs.is_empty() && !branch_refs.contains_key(repo.default_branch.as_str()) {
+            findings.push(Finding::new(
+                Severity::Error,
+                "default_branch_missing",
+                Some(repo.id),
+                &repo.default_branch,
+                format!("default branch {} does not exist", repo.default_branch),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Files in the blob store that no `blob_refs` row counts, including partial
+    /// writes left behind by a crash
+    async fn check_orphaned_blob_files(&self, options: &CheckOptions, findings: &mut Vec<Finding>) -> Result<()> {
+        let root = self.blob_storage_path();
+        let Ok(dirs) = fs::read_dir(root) else {
+            return Ok(());
+        };
+        let known: HashSet<String> = blob_ref::Entity::find()
+            .select_only()
+            .column(blob_ref::Column::BlobId)
+            .into_tuple::<String>()
+            .all(self.get_db())
+            .await?
+            .into_iter()
+            .collect();
+
+        // Blob files live in two-hex-digit directories, one level per shard; anything
+        // else at the top isn't ours
+        let is_shard = |name: &str| name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit());
+        let mut orphans = Vec::new();
+        let mut pending: Vec<(PathBuf, String)> = dirs
+            .flatten()
+            .filter(|dir| dir.path().is_dir())
+            .map(|dir| (dir.path(), dir.file_name().to_string_lossy().into_owned()))
+            .filter(|(_, prefix)| is_shard(prefix))
+            .collect();
+        while let Some((dir, prefix)) = pending.pop() {
+            for entry in fs::read_dir(&dir)?.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let id = format!("{}{}", prefix, name);
+                if entry.path().is_dir() && is_shard(&name) && prefix.len() < 2 * MAX_BLOB_SHARD_DEPTH {
+                    pending.push((entry.path(), id));
+                } else if !known.contains(&id) {
+                    orphans.push(entry.path());
+                }
+            }
+        }
+        orphans.sort();
+
+        let now = SystemTime::now();
+        for path in orphans {
+            let relative = path.strip_prefix(root).unwrap_or(&path).display().to_string();
+            let old_enough = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .map(|modified| now.duration_since(modified).unwrap_or_default() >= options.orphan_min_age)
+                .unwrap_or(false);
+            let mut finding = Finding::new(
+                Severity::Warning,
+                "orphaned_blob_file",
+                None,
+                &relative,
+                format!("{} is not referenced by any repository", relative),
+            );
+            finding.fixed = options.fix && old_enough && fs::remove_file(&path).is_ok();
+            findings.push(finding);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::git_object::ObjectKind;
+    use crate::entities::git_ref;
+    use crate::entities::repository::Visibility;
+    use crate::{init_db, run_migrations, UserService};
+    use git_protocol::objects::{Commit, ObjectHandler, Tree, TreeEntry};
+    use sea_orm::PaginatorTrait;
+
+    #[tokio::test]
+    async fn test_check_reports_seeded_corruption() {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let blob_storage_path = std::env::temp_dir().join(format!("git-storage-test-{}", Uuid::new_v4()));
+        let service = RepositoryService::new(db.clone(), Some(blob_storage_path.clone()));
+        let owner = UserService::new(db.clone())
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = service
+            .create_repository("seeded".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let handler = ObjectHandler::new();
+        let blob = handler.create_blob(b"hello\n").unwrap();
+        let tree = handler
+            .create_tree(&Tree {
+                entries: vec![TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "hello.txt".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = handler
+            .create_commit(&Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: Utc::now(),
+                commit_date: Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        for (obj, object_type) in [(&blob, ObjectKind::Blob), (&tree, ObjectKind::Tree), (&commit, ObjectKind::Commit)] {
+            service
+                .store_object(repo.id, obj.id.clone(), object_type, obj.size as i64, obj.content.clone())
+                .await
+                .unwrap();
+        }
+        for name in ["refs/heads/main", "refs/heads/topic"] {
+            service.store_ref(repo.id, name.to_string(), commit.id.clone(), false).await.unwrap();
+        }
+
+        // A healthy repository only lacks its (lazily filled) branches rows
+        let report = service.check_consistency(None, &CheckOptions::default()).await.unwrap();
+        assert!(!report.has_errors());
+        assert!(report.findings.iter().all(|f| f.code == "branch_row_missing"));
+
+        // 1. a branch pointing at an object that was never stored
+        let topic = service.get_ref(repo.id, "refs/heads/topic").await.unwrap().unwrap();
+        let mut topic: git_ref::ActiveModel = topic.into();
+        topic.target = Set("f".repeat(40));
+        topic.update(&db).await.unwrap();
+        // 2. a blob file lost from disk
+        fs::remove_file(blob_storage_path.join(&blob.id[..2]).join(&blob.id[2..])).unwrap();
+        // 3. a default branch renamed without its ref
+        let mut settings: repository::ActiveModel = repo.clone().into();
+        settings.default_branch = Set("develop".to_string());
+        settings.update(&db).await.unwrap();
+        // ...and a stray file in the blob store
+        fs::create_dir_all(blob_storage_path.join("ab")).unwrap();
+        fs::write(blob_storage_path.join("ab").join("c".repeat(38)), b"stray").unwrap();
+
+        let options = CheckOptions {
+            fix: true,
+            orphan_min_age: Duration::ZERO,
+        };
+        let report = service.check_consistency(None, &options).await.unwrap();
+        assert!(report.has_errors());
+        let errors: Vec<(&str, &str)> = report
+            .findings
+            .iter()
+            .filter(|f| f.severity == Severity::Error)
+            .map(|f| (f.code.as_str(), f.subject.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("ref_target_missing", "refs/heads/topic"),
+                ("blob_file_missing", blob.id.as_str()),
+                ("default_branch_missing", "develop"),
+            ]
+        );
+
+        // --fix only applies the safe repairs
+        let orphan = report.findings.iter().find(|f| f.code == "orphaned_blob_file").unwrap();
+        assert_eq!(orphan.subject, format!("ab/{}", "c".repeat(38)));
+        assert!(orphan.fixed);
+        assert!(!blob_storage_path.join("ab").join("c".repeat(38)).exists());
+        assert!(report.findings.iter().filter(|f| f.code == "branch_row_missing").all(|f| f.fixed));
+        assert_eq!(branch::Entity::find().count(&db).await.unwrap(), 2);
+        assert!(report.findings.iter().filter(|f| f.severity == Severity::Error).all(|f| !f.fixed));
+    }
+}
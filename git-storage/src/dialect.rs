@@ -0,0 +1,75 @@
+use sea_orm::sea_query::{Alias, Func, SimpleExpr};
+use sea_orm::{ConnectionTrait, DatabaseBackend};
+
+/// SQL that has to differ between the database backends storage runs on.
+/// Queries that behave the same everywhere don't need it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dialect {
+    backend: DatabaseBackend,
+}
+
+impl Dialect {
+    pub fn new(backend: DatabaseBackend) -> Self {
+        Self { backend }
+    }
+
+    /// The dialect of the database `db` talks to
+    pub fn of<C: ConnectionTrait>(db: &C) -> Self {
+        Self::new(db.get_database_backend())
+    }
+
+    pub fn backend(&self) -> DatabaseBackend {
+        self.backend
+    }
+
+    /// `SUM(expr)` read back as a 64-bit integer. Postgres and MySQL widen the sum
+    /// of a bigint column to a decimal type, which won't decode as `i64`.
+    pub fn sum_i64(&self, expr: impl Into<SimpleExpr>) -> SimpleExpr {
+        let sum = SimpleExpr::from(Func::sum(expr.into()));
+        match self.backend {
+            DatabaseBackend::Sqlite => sum,
+            DatabaseBackend::Postgres => sum.cast_as(Alias::new("BIGINT")),
+            DatabaseBackend::MySql => sum.cast_as(Alias::new("SIGNED")),
+        }
+    }
+
+    /// Lowercase `value` as the backend's `LOWER()` does, for comparing against a
+    /// lowercased column: SQLite only folds ASCII letters, the others fold Unicode.
+    pub fn lowercase(&self, value: &str) -> String {
+        match self.backend {
+            DatabaseBackend::Sqlite => value.to_ascii_lowercase(),
+            DatabaseBackend::Postgres | DatabaseBackend::MySql => value.to_lowercase(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::repository;
+    use sea_orm::{ColumnTrait, EntityTrait, QuerySelect, QueryTrait};
+
+    #[test]
+    fn test_dialect_follows_the_backend() {
+        let usage = |backend: DatabaseBackend| {
+            repository::Entity::find()
+                .select_only()
+                .column_as(Dialect::new(backend).sum_i64(repository::Column::SizeBytes.into_expr()), "bytes")
+                .build(backend)
+                .to_string()
+        };
+        assert_eq!(usage(DatabaseBackend::Sqlite), r#"SELECT SUM("repositories"."size_bytes") AS "bytes" FROM "repositories""#);
+        assert_eq!(
+            usage(DatabaseBackend::Postgres),
+            r#"SELECT CAST(SUM("repositories"."size_bytes") AS BIGINT) AS "bytes" FROM "repositories""#
+        );
+        assert_eq!(
+            usage(DatabaseBackend::MySql),
+            "SELECT CAST(SUM(`repositories`.`size_bytes`) AS SIGNED) AS `bytes` FROM `repositories`"
+        );
+
+        assert_eq!(Dialect::new(DatabaseBackend::Sqlite).lowercase("Refs/Heads/ÉTÉ"), "refs/heads/ÉtÉ");
+        assert_eq!(Dialect::new(DatabaseBackend::Postgres).lowercase("Refs/Heads/ÉTÉ"), "refs/heads/été");
+        assert_eq!(Dialect::new(DatabaseBackend::MySql).lowercase("Refs/Heads/ÉTÉ"), "refs/heads/été");
+    }
+}
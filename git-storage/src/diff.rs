@@ -0,0 +1,798 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset, Utc};
+use git_protocol::objects::Commit;
+pub use git_protocol::text::is_binary;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Lines of unchanged context around each hunk, as in `git diff`
+pub const DEFAULT_CONTEXT_LINES: usize = 3;
+
+/// Longest section heading shown after a hunk's range, as in git
+const MAX_SECTION_HEADING_LEN: usize = 80;
+
+/// Starts the line that replaces the files a size-capped patch leaves out
+pub const TRUNCATED_MARKER: &str = "# diff truncated:";
+
+/// Minimum similarity (percent) for an edited file to count as renamed, as in git
+pub const DEFAULT_RENAME_THRESHOLD: u8 = 50;
+
+/// Above this many delete/add pairs only exact renames are looked for
+pub const MAX_RENAME_CANDIDATES: usize = 1000;
+
+/// What happened to a file between two trees
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Deleted,
+    Modified,
+    /// `similarity` is 100 for an exact rename, lower when the content was edited too
+    Renamed { from: String, to: String, similarity: u8 },
+}
+
+impl ChangeKind {
+    /// Status as printed by `git diff --name-status`, e.g. `M` or `R085`
+    pub fn status(&self) -> String {
+        match self {
+            ChangeKind::Added => "A".to_string(),
+            ChangeKind::Deleted => "D".to_string(),
+            ChangeKind::Modified => "M".to_string(),
+            ChangeKind::Renamed { similarity, .. } => format!("R{:03}", similarity),
+        }
+    }
+}
+
+/// Options for computing a tree diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// Pair deleted and added files as renames
+    pub detect_renames: bool,
+    /// Similarity (percent) needed to pair files whose content differs;
+    /// 100 limits detection to exact renames
+    pub rename_threshold: u8,
+    /// Compare text with `\r\n` turned into `\n`, so line ending changes don't show
+    pub normalize_eol: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            detect_renames: false,
+            rename_threshold: DEFAULT_RENAME_THRESHOLD,
+            normalize_eol: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineKind {
+    Context,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffLine {
+    pub kind: LineKind,
+    /// Line content without its trailing newline
+    pub content: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_newline_at_eof: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_lines: usize,
+    pub new_start: usize,
+    pub new_lines: usize,
+    /// Nearest line above the hunk that looks like the start of a definition,
+    /// shown after the `@@` range as git does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>,
+    pub lines: Vec<DiffLine>,
+}
+
+/// Changes to a single file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDiff {
+    pub path: String,
+    pub change: ChangeKind,
+    pub old_id: Option<String>,
+    pub new_id: Option<String>,
+    pub old_mode: Option<String>,
+    pub new_mode: Option<String>,
+    /// Blob sizes in bytes, `None` on the side where the file doesn't exist
+    #[serde(default)]
+    pub old_size: Option<u64>,
+    #[serde(default)]
+    pub new_size: Option<u64>,
+    pub binary: bool,
+    /// Text over the diff size limit; like binary files it gets no hunks
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub too_large: bool,
+    pub additions: usize,
+    pub deletions: usize,
+    pub hunks: Vec<Hunk>,
+}
+
+/// Output shape for a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiffFormat {
+    /// Structured `FileDiff` list
+    #[default]
+    Json,
+    /// Unified patch text accepted by `git apply`
+    Patch,
+    /// `git diff --name-status`
+    NameStatus,
+    /// `git diff --stat`
+    Stat,
+}
+
+impl FromStr for DiffFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(DiffFormat::Json),
+            "patch" => Ok(DiffFormat::Patch),
+            "name-status" => Ok(DiffFormat::NameStatus),
+            "stat" => Ok(DiffFormat::Stat),
+            other => Err(anyhow!("Unknown diff format: {} (expected json, patch, name-status or stat)", other)),
+        }
+    }
+}
+
+impl FileDiff {
+    /// Binary or too large: summarized by ids and sizes instead of line hunks
+    pub fn is_summarized(&self) -> bool {
+        self.binary || self.too_large
+    }
+}
+
+/// Build the diff for one file from its old and new content. Binary content, and
+/// content over `max_bytes` on either side, is summarized without line-diffing.
+pub fn diff_file(
+    path: String,
+    old: Option<(&str, &str, &[u8])>,
+    new: Option<(&str, &str, &[u8])>,
+    context: usize,
+    max_bytes: Option<usize>,
+) -> FileDiff {
+    let change = match (&old, &new) {
+        (None, Some(_)) => ChangeKind::Added,
+        (Some(_), None) => ChangeKind::Deleted,
+        _ => ChangeKind::Modified,
+    };
+    let old_content = old.map(|(_, _, content)| content).unwrap_or_default();
+    let new_content = new.map(|(_, _, content)| content).unwrap_or_default();
+    let binary = is_binary(old_content) || is_binary(new_content);
+    let too_large = !binary
+        && max_bytes.is_some_and(|max| old_content.len() > max || new_content.len() > max);
+
+    let hunks = if binary || too_large {
+        Vec::new()
+    } else {
+        diff_lines(
+            &String::from_utf8_lossy(old_content),
+            &String::from_utf8_lossy(new_content),
+            context,
+        )
+    };
+    let count = |kind: LineKind| {
+        hunks
+            .iter()
+            .flat_map(|h| &h.lines)
+            .filter(|l| l.kind == kind)
+            .count()
+    };
+
+    FileDiff {
+        path,
+        change,
+        old_id: old.map(|(id, _, _)| id.to_string()),
+        new_id: new.map(|(id, _, _)| id.to_string()),
+        old_mode: old.map(|(_, mode, _)| normalize_mode(mode)),
+        new_mode: new.map(|(_, mode, _)| normalize_mode(mode)),
+        old_size: old.map(|(_, _, content)| content.len() as u64),
+        new_size: new.map(|(_, _, content)| content.len() as u64),
+        binary,
+        too_large,
+        additions: count(LineKind::Added),
+        deletions: count(LineKind::Removed),
+        hunks,
+    }
+}
+
+/// Percentage of lines kept between two versions of a file, relative to the longer one.
+/// Binary content only counts as similar when identical.
+pub fn similarity(old: &[u8], new: &[u8]) -> u8 {
+    if old == new {
+        return 100;
+    }
+    if is_binary(old) || is_binary(new) {
+        return 0;
+    }
+
+    let old = String::from_utf8_lossy(old);
+    let new = String::from_utf8_lossy(new);
+    let a: Vec<&str> = old.split_inclusive('\n').collect();
+    let b: Vec<&str> = new.split_inclusive('\n').collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 100;
+    }
+
+    let kept = myers(&a, &b).iter().filter(|e| **e == Edit::Equal).count();
+    (kept * 100 / longest) as u8
+}
+
+/// Line diff of two texts as unified hunks with `context` lines around changes
+pub fn diff_lines(old: &str, new: &str, context: usize) -> Vec<Hunk> {
+    let a: Vec<&str> = old.split_inclusive('\n').collect();
+    let b: Vec<&str> = new.split_inclusive('\n').collect();
+    let edits = myers(&a, &b);
+
+    // Lines consumed from each side before every edit
+    let mut positions = Vec::with_capacity(edits.len());
+    let (mut x, mut y) = (0, 0);
+    for edit in &edits {
+        positions.push((x, y));
+        match edit {
+            Edit::Equal => {
+                x += 1;
+                y += 1;
+            }
+            Edit::Delete => x += 1,
+            Edit::Insert => y += 1,
+        }
+    }
+
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, edit)| **edit != Edit::Equal)
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut hunks = Vec::new();
+    let mut i = 0;
+    while i < changes.len() {
+        // Merge changes whose separating context would overlap
+        let mut j = i;
+        while j + 1 < changes.len() && changes[j + 1] - changes[j] <= 2 * context + 1 {
+            j += 1;
+        }
+
+        let start = changes[i].saturating_sub(context);
+        let end = (changes[j] + context + 1).min(edits.len());
+        let (x, y) = positions[start];
+
+        let mut lines = Vec::new();
+        let (mut old_lines, mut new_lines) = (0, 0);
+        let (mut ox, mut ny) = (x, y);
+        for edit in &edits[start..end] {
+            let (kind, text) = match edit {
+                Edit::Equal => {
+                    old_lines += 1;
+                    new_lines += 1;
+                    ox += 1;
+                    ny += 1;
+                    (LineKind::Context, a[ox - 1])
+                }
+                Edit::Delete => {
+                    old_lines += 1;
+                    ox += 1;
+                    (LineKind::Removed, a[ox - 1])
+                }
+                Edit::Insert => {
+                    new_lines += 1;
+                    ny += 1;
+                    (LineKind::Added, b[ny - 1])
+                }
+            };
+            lines.push(DiffLine {
+                kind,
+                content: text.strip_suffix('\n').unwrap_or(text).to_string(),
+                no_newline_at_eof: !text.ends_with('\n'),
+            });
+        }
+
+        // An empty side points at the line before the hunk, as in git
+        hunks.push(Hunk {
+            old_start: if old_lines == 0 { x } else { x + 1 },
+            old_lines,
+            new_start: if new_lines == 0 { y } else { y + 1 },
+            new_lines,
+            section: section_heading(&a[..x]),
+            lines,
+        });
+        i = j + 1;
+    }
+
+    hunks
+}
+
+/// Render diffs in the requested text format. `Json` is handled by the caller.
+pub fn render(diffs: &[FileDiff], format: DiffFormat) -> String {
+    match format {
+        DiffFormat::Json => serde_json::to_string(diffs).unwrap_or_default(),
+        DiffFormat::Patch => render_patch(diffs),
+        DiffFormat::NameStatus => render_name_status(diffs),
+        DiffFormat::Stat => render_stat(diffs),
+    }
+}
+
+/// Unified patch in `git diff` format
+pub fn render_patch(diffs: &[FileDiff]) -> String {
+    render_patch_within(diffs, None)
+}
+
+/// Unified patch that stops before the first file that would take it over
+/// `max_bytes`, ending instead with a line saying how many files were left out
+pub fn render_patch_within(diffs: &[FileDiff], max_bytes: Option<usize>) -> String {
+    let mut out = String::new();
+
+    for (shown, diff) in diffs.iter().enumerate() {
+        let mut file = String::new();
+        write_file_patch(&mut file, diff);
+        if max_bytes.is_some_and(|max| out.len() + file.len() > max) {
+            let _ = writeln!(
+                out,
+                "{} {} of {} files not shown, the diff is over {} bytes",
+                TRUNCATED_MARKER,
+                diffs.len() - shown,
+                diffs.len(),
+                max_bytes.unwrap_or_default()
+            );
+            break;
+        }
+        out.push_str(&file);
+    }
+
+    out
+}
+
+/// One file's stanza of a unified patch
+fn write_file_patch(out: &mut String, diff: &FileDiff) {
+    let old_path = match &diff.change {
+        ChangeKind::Renamed { from, .. } => from.as_str(),
+        _ => diff.path.as_str(),
+    };
+    let _ = writeln!(out, "diff --git a/{} b/{}", old_path, diff.path);
+    let old_id = abbreviate(diff.old_id.as_deref());
+    let new_id = abbreviate(diff.new_id.as_deref());
+    match &diff.change {
+        ChangeKind::Added => {
+            let _ = writeln!(out, "new file mode {}", diff.new_mode.as_deref().unwrap_or("100644"));
+            let _ = writeln!(out, "index {}..{}", old_id, new_id);
+        }
+        ChangeKind::Deleted => {
+            let _ = writeln!(out, "deleted file mode {}", diff.old_mode.as_deref().unwrap_or("100644"));
+            let _ = writeln!(out, "index {}..{}", old_id, new_id);
+        }
+        ChangeKind::Modified | ChangeKind::Renamed { .. } => {
+            if let ChangeKind::Renamed { from, to, similarity } = &diff.change {
+                let _ = writeln!(out, "similarity index {}%", similarity);
+                let _ = writeln!(out, "rename from {}", from);
+                let _ = writeln!(out, "rename to {}", to);
+                if diff.old_id == diff.new_id && diff.old_mode == diff.new_mode {
+                    return;
+                }
+            }
+            if diff.old_mode != diff.new_mode {
+                let _ = writeln!(out, "old mode {}", diff.old_mode.as_deref().unwrap_or("100644"));
+                let _ = writeln!(out, "new mode {}", diff.new_mode.as_deref().unwrap_or("100644"));
+                let _ = writeln!(out, "index {}..{}", old_id, new_id);
+            } else {
+                let _ = writeln!(
+                    out,
+                    "index {}..{} {}",
+                    old_id,
+                    new_id,
+                    diff.new_mode.as_deref().unwrap_or("100644")
+                );
+            }
+        }
+    }
+
+    let old_name = match diff.change {
+        ChangeKind::Added => "/dev/null".to_string(),
+        _ => format!("a/{}", old_path),
+    };
+    let new_name = match diff.change {
+        ChangeKind::Deleted => "/dev/null".to_string(),
+        _ => format!("b/{}", diff.path),
+    };
+
+    if diff.binary {
+        let _ = writeln!(out, "Binary files {} and {} differ", old_name, new_name);
+        return;
+    }
+    if diff.too_large {
+        let _ = writeln!(
+            out,
+            "Files {} and {} differ ({} -> {} bytes, too large to show)",
+            old_name,
+            new_name,
+            diff.old_size.unwrap_or(0),
+            diff.new_size.unwrap_or(0)
+        );
+        return;
+    }
+    if diff.hunks.is_empty() {
+        return;
+    }
+
+    let _ = writeln!(out, "--- {}", old_name);
+    let _ = writeln!(out, "+++ {}", new_name);
+    for hunk in &diff.hunks {
+        let _ = write!(
+            out,
+            "@@ -{} +{} @@",
+            hunk_range(hunk.old_start, hunk.old_lines),
+            hunk_range(hunk.new_start, hunk.new_lines)
+        );
+        match &hunk.section {
+            Some(section) => {
+                let _ = writeln!(out, " {}", section);
+            }
+            None => out.push('\n'),
+        }
+        for line in &hunk.lines {
+            let prefix = match line.kind {
+                LineKind::Context => ' ',
+                LineKind::Added => '+',
+                LineKind::Removed => '-',
+            };
+            let _ = writeln!(out, "{}{}", prefix, line.content);
+            if line.no_newline_at_eof {
+                out.push_str("\\ No newline at end of file\n");
+            }
+        }
+    }
+}
+
+/// A commit as a `git format-patch` mbox message: headers from the author, the
+/// message, a diffstat and the patch against `diffs`' base, signed with `version`.
+/// Merges get a note naming their parents, since only the first is diffed against.
+/// The patch is capped at `max_bytes` as in `render_patch_within`.
+pub fn render_format_patch(
+    hash: &str,
+    commit: &Commit,
+    diffs: &[FileDiff],
+    version: &str,
+    max_bytes: Option<usize>,
+) -> String {
+    let mut paragraphs = commit.message.trim().splitn(2, "\n\n");
+    let subject = paragraphs
+        .next()
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let body = paragraphs.next().map(str::trim).unwrap_or_default();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "From {} Mon Sep 17 00:00:00 2001", hash);
+    let _ = writeln!(out, "From: {}", signature_identity(&commit.author));
+    let _ = writeln!(out, "Date: {}", signature_date(&commit.author, commit.author_date));
+    let _ = writeln!(out, "Subject: [PATCH] {}", subject);
+    out.push('\n');
+    if !body.is_empty() {
+        let _ = writeln!(out, "{}\n", body);
+    }
+    out.push_str("---\n");
+    if commit.parents.len() > 1 {
+        let _ = writeln!(
+            out,
+            "Merge of {}; the diff below is against the first parent only.\n",
+            commit.parents.join(" and ")
+        );
+    }
+    out.push_str(&render_stat(diffs));
+    out.push('\n');
+    out.push_str(&render_patch_within(diffs, max_bytes));
+    let _ = writeln!(out, "-- \n{}\n", version);
+
+    out
+}
+
+/// `Name <email>` from a `Name <email> <seconds> <tz>` signature
+pub(crate) fn signature_identity(signature: &str) -> &str {
+    signature.rsplitn(3, ' ').nth(2).unwrap_or(signature)
+}
+
+/// RFC 2822 date in the signature's own timezone, UTC if it has none
+fn signature_date(signature: &str, date: DateTime<Utc>) -> String {
+    let offset = signature
+        .rsplit(' ')
+        .next()
+        .filter(|tz| tz.len() == 5)
+        .and_then(|tz| {
+            let sign = match &tz[..1] {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let hours: i32 = tz[1..3].parse().ok()?;
+            let minutes: i32 = tz[3..].parse().ok()?;
+            FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+        })
+        .unwrap_or_else(|| FixedOffset::east_opt(0).expect("zero offset"));
+    date.with_timezone(&offset).to_rfc2822()
+}
+
+/// One `<status>\t<path>` line per file
+pub fn render_name_status(diffs: &[FileDiff]) -> String {
+    diffs
+        .iter()
+        .map(|d| match &d.change {
+            ChangeKind::Renamed { from, to, .. } => format!("{}\t{}\t{}\n", d.change.status(), from, to),
+            _ => format!("{}\t{}\n", d.change.status(), d.path),
+        })
+        .collect()
+}
+
+/// Per-file change counts and a summary line
+pub fn render_stat(diffs: &[FileDiff]) -> String {
+    const MAX_BAR: usize = 50;
+
+    let names: Vec<String> = diffs
+        .iter()
+        .map(|d| match &d.change {
+            ChangeKind::Renamed { from, to, .. } => format!("{} => {}", from, to),
+            _ => d.path.clone(),
+        })
+        .collect();
+    let name_width = names.iter().map(|n| n.len()).max().unwrap_or(0);
+    let max_changes = diffs
+        .iter()
+        .map(|d| d.additions + d.deletions)
+        .max()
+        .unwrap_or(0);
+    let count_width = max_changes.to_string().len();
+
+    let mut out = String::new();
+    for (diff, name) in diffs.iter().zip(&names) {
+        if diff.is_summarized() {
+            // As git prints binary files, with sizes in place of line counts
+            let _ = writeln!(
+                out,
+                " {:<name_width$} | {} {} -> {} bytes",
+                name,
+                if diff.binary { "Bin" } else { "Large" },
+                diff.old_size.unwrap_or(0),
+                diff.new_size.unwrap_or(0)
+            );
+            continue;
+        }
+
+        let total = diff.additions + diff.deletions;
+        let (plus, minus) = if max_changes > MAX_BAR {
+            (diff.additions * MAX_BAR / max_changes, diff.deletions * MAX_BAR / max_changes)
+        } else {
+            (diff.additions, diff.deletions)
+        };
+        let _ = writeln!(
+            out,
+            " {:<name_width$} | {:>count_width$} {}{}",
+            name,
+            total,
+            "+".repeat(plus),
+            "-".repeat(minus)
+        );
+    }
+
+    let insertions: usize = diffs.iter().map(|d| d.additions).sum();
+    let deletions: usize = diffs.iter().map(|d| d.deletions).sum();
+    let _ = write!(
+        out,
+        " {} file{} changed",
+        diffs.len(),
+        if diffs.len() == 1 { "" } else { "s" }
+    );
+    if insertions > 0 {
+        let _ = write!(out, ", {} insertion{}(+)", insertions, if insertions == 1 { "" } else { "s" });
+    }
+    if deletions > 0 {
+        let _ = write!(out, ", {} deletion{}(-)", deletions, if deletions == 1 { "" } else { "s" });
+    }
+    out.push('\n');
+
+    out
+}
+
+/// Mode as git prints it (tree objects may omit the leading zero)
+fn normalize_mode(mode: &str) -> String {
+    format!("{:0>6}", mode)
+}
+
+fn abbreviate(id: Option<&str>) -> String {
+    match id {
+        Some(id) => id.chars().take(7).collect(),
+        None => "0000000".to_string(),
+    }
+}
+
+fn hunk_range(start: usize, lines: usize) -> String {
+    if lines == 1 {
+        start.to_string()
+    } else {
+        format!("{},{}", start, lines)
+    }
+}
+
+/// git's default funcname rule: the last of `lines` starting with a letter, `_`
+/// or `$`, cut to 80 bytes and stripped of trailing whitespace
+fn section_heading(lines: &[&str]) -> Option<String> {
+    let line = lines
+        .iter()
+        .rev()
+        .find(|line| line.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$'))?;
+    let mut end = line.len().min(MAX_SECTION_HEADING_LEN);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(line[..end].trim_end().to_string())
+}
+
+/// One step of an edit script, consuming a line from the old side, the new side or both
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Shortest edit script between two line lists (Myers' algorithm). Hunks and
+/// rename similarity are built on it; anything else that needs to line up two
+/// versions of a file, like blame, can use it directly.
+pub fn myers(a: &[&str], b: &[&str]) -> Vec<Edit> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = n + m;
+    let offset = max;
+    let mut v = vec![0isize; 2 * max as usize + 2];
+    let mut trace = Vec::new();
+
+    'search: for d in 0..=max {
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+            k += 2;
+        }
+    }
+
+    // Walk the trace backwards to recover the edits
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let idx = (k + offset) as usize;
+        let prev_k = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            edits.push(if x == prev_x { Edit::Insert } else { Edit::Delete });
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+
+    edits.reverse();
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_lines_builds_git_style_hunks() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+
+        let hunks = diff_lines(old, new, 3);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!((hunks[0].old_start, hunks[0].old_lines), (1, 5));
+        assert_eq!((hunks[0].new_start, hunks[0].new_lines), (1, 5));
+        assert_eq!((hunks[1].old_start, hunks[1].old_lines), (8, 3));
+        assert_eq!((hunks[1].new_start, hunks[1].new_lines), (8, 4));
+
+        // Additions to an empty file start at line 0 on the old side
+        let hunks = diff_lines("", "x\n", 3);
+        assert_eq!((hunks[0].old_start, hunks[0].old_lines), (0, 0));
+        assert_eq!((hunks[0].new_start, hunks[0].new_lines), (1, 1));
+
+        assert!(diff_lines("same\n", "same\n", 3).is_empty());
+    }
+
+    #[test]
+    fn test_binary_and_oversized_files_are_summarized() {
+        let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+        image.extend([0u8; 64]);
+        let binary = diff_file(
+            "logo.png".to_string(),
+            Some(("aaaaaaaa", "100644", b"text before\n")),
+            Some(("bbbbbbbb", "100644", &image)),
+            DEFAULT_CONTEXT_LINES,
+            None,
+        );
+        assert!(binary.binary && !binary.too_large);
+        assert!(binary.hunks.is_empty());
+        assert_eq!((binary.old_size, binary.new_size), (Some(12), Some(72)));
+        assert_eq!((binary.additions, binary.deletions), (0, 0));
+
+        let large_text = "line\n".repeat(100);
+        let large = diff_file(
+            "big.txt".to_string(),
+            None,
+            Some(("cccccccc", "100644", large_text.as_bytes())),
+            DEFAULT_CONTEXT_LINES,
+            Some(100),
+        );
+        assert!(large.too_large && !large.binary);
+        assert!(large.hunks.is_empty());
+        assert_eq!((large.old_size, large.new_size), (None, Some(500)));
+        assert_eq!(large.new_id.as_deref(), Some("cccccccc"));
+
+        let patch = render_patch(&[binary.clone(), large.clone()]);
+        assert!(patch.contains("Binary files a/logo.png and b/logo.png differ\n"));
+        assert!(patch.contains("index 0000000..ccccccc\nFiles /dev/null and b/big.txt differ (0 -> 500 bytes, too large to show)\n"));
+        assert!(!patch.contains("+line"));
+        let stat = render_stat(&[binary, large]);
+        assert!(stat.contains(" big.txt  | Large 0 -> 500 bytes\n"), "{}", stat);
+        assert!(stat.contains(" logo.png | Bin 12 -> 72 bytes\n"), "{}", stat);
+
+        // Under the limit the same text is diffed line by line
+        let small = diff_file(
+            "big.txt".to_string(),
+            None,
+            Some(("cccccccc", "100644", large_text.as_bytes())),
+            DEFAULT_CONTEXT_LINES,
+            Some(500),
+        );
+        assert!(!small.is_summarized());
+        assert_eq!(small.additions, 100);
+    }
+
+    #[test]
+    fn test_missing_trailing_newline_is_a_change() {
+        let hunks = diff_lines("a\n", "a", 3);
+        assert_eq!(hunks[0].lines.len(), 2);
+        assert!(hunks[0].lines[1].no_newline_at_eof);
+    }
+}
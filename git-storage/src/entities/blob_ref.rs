@@ -0,0 +1,18 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// How many repositories hold a blob from the shared blob store
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "blob_refs")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub blob_id: String,
+    pub ref_count: i64,
+    pub created_at: ChronoDateTimeWithTimeZone,
+    pub updated_at: ChronoDateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,38 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "commit_statuses")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    pub repository_id: Uuid,
+    pub commit_id: String,
+    /// `pending`, `success`, `failure` or `error`
+    pub state: String,
+    /// Which check reported this, e.g. `ci/build`
+    pub context: String,
+    pub description: Option<String>,
+    pub target_url: Option<String>,
+    pub creator_id: Option<Uuid>,
+    pub created_at: ChronoDateTimeWithTimeZone,
+    pub updated_at: ChronoDateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::repository::Entity",
+        from = "Column::RepositoryId",
+        to = "super::repository::Column::Id"
+    )]
+    Repository,
+}
+
+impl Related<super::repository::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Repository.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
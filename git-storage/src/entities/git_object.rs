@@ -1,17 +1,25 @@
+use crate::error::StorageError;
+use chrono::Utc;
+use git_protocol::{GitObject, ObjectType};
 use sea_orm::entity::prelude::*;
+use sea_orm::Set;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
 #[sea_orm(table_name = "git_objects")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
-    pub id: String, // SHA-1 hash
     pub repository_id: Uuid,
-    pub object_type: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String, // SHA-1 hash
+    pub object_type: ObjectKind,
     pub size: i64,
     // For blobs, content is stored in filesystem; for commits/trees/tags, stored in DB
     pub content: Option<Vec<u8>>,
-    // Path to blob file in local storage (only for blob objects)
+    // Key of the blob file under the blob storage root, shared by every repository
+    // holding the blob (only for blob objects)
     pub blob_path: Option<String>,
     pub created_at: ChronoDateTimeWithTimeZone,
 }
@@ -32,4 +40,162 @@ impl Related<super::repository::Entity> for Entity {
     }
 }
 
-impl ActiveModelBehavior for ActiveModel {}
\ No newline at end of file
+impl ActiveModelBehavior for ActiveModel {}
+
+impl ActiveModel {
+    /// A new row for `object` in `repository_id`, with the content in the row.
+    /// `RepositoryService` moves blob content out to the blob store on insert.
+    pub fn from_git_object(repository_id: Uuid, object: GitObject) -> Self {
+        Self {
+            repository_id: Set(repository_id),
+            object_type: Set(ObjectKind::from(&object.obj_type)),
+            size: Set(object.size as i64),
+            id: Set(object.id),
+            content: Set(Some(object.content)),
+            blob_path: Set(None),
+            created_at: Set(Utc::now().into()),
+        }
+    }
+}
+/// Value of `object_type`. A CHECK constraint limits the column to these, and a
+/// row holding anything else fails to load.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "String(None)")]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectKind {
+    #[sea_orm(string_value = "commit")]
+    Commit,
+    #[sea_orm(string_value = "tree")]
+    Tree,
+    #[sea_orm(string_value = "blob")]
+    Blob,
+    #[sea_orm(string_value = "tag")]
+    Tag,
+}
+
+impl ObjectKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObjectKind::Commit => "commit",
+            ObjectKind::Tree => "tree",
+            ObjectKind::Blob => "blob",
+            ObjectKind::Tag => "tag",
+        }
+    }
+}
+
+impl FromStr for ObjectKind {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "commit" => Ok(ObjectKind::Commit),
+            "tree" => Ok(ObjectKind::Tree),
+            "blob" => Ok(ObjectKind::Blob),
+            "tag" => Ok(ObjectKind::Tag),
+            other => Err(StorageError::UnknownObjectType {
+                value: other.to_string(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<&ObjectType> for ObjectKind {
+    fn from(obj_type: &ObjectType) -> Self {
+        match obj_type {
+            ObjectType::Commit => ObjectKind::Commit,
+            ObjectType::Tree => ObjectKind::Tree,
+            ObjectType::Blob => ObjectKind::Blob,
+            ObjectType::Tag => ObjectKind::Tag,
+        }
+    }
+}
+
+impl From<ObjectType> for ObjectKind {
+    fn from(obj_type: ObjectType) -> Self {
+        ObjectKind::from(&obj_type)
+    }
+}
+
+impl From<ObjectKind> for ObjectType {
+    fn from(kind: ObjectKind) -> Self {
+        match kind {
+            ObjectKind::Commit => ObjectType::Commit,
+            ObjectKind::Tree => ObjectType::Tree,
+            ObjectKind::Blob => ObjectType::Blob,
+            ObjectKind::Tag => ObjectType::Tag,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git_protocol::objects::ObjectHandler;
+    use sea_orm::{ActiveValue, Iterable};
+
+    #[test]
+    fn test_object_types_round_trip_through_the_storage_forms() {
+        for kind in ObjectKind::iter() {
+            let obj_type = ObjectType::from(kind);
+            assert_eq!(ObjectKind::from(&obj_type), kind);
+            assert_eq!(obj_type.as_str(), kind.as_str());
+            assert_eq!(kind.as_str().parse::<ObjectKind>().unwrap(), kind);
+            assert_eq!(kind.to_value(), kind.as_str());
+            assert_eq!(ObjectKind::try_from_value(&kind.as_str().to_string()).unwrap(), kind);
+        }
+        assert!(matches!(
+            "Commit".parse::<ObjectKind>(),
+            Err(StorageError::UnknownObjectType { value }) if value == "Commit"
+        ));
+
+        let handler = ObjectHandler::new();
+        let repository_id = Uuid::new_v4();
+        let blob = handler.create_blob(b"hello").unwrap();
+        let tree = handler.create_tree(&git_protocol::objects::Tree { entries: vec![] }).unwrap();
+        let objects = [
+            handler.create_commit(&git_protocol::objects::Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: chrono::Utc::now(),
+                commit_date: chrono::Utc::now(),
+                encoding: None,
+            }),
+            handler.parse_object(
+                ObjectType::Tag,
+                format!(
+                    "object {}\ntype blob\ntag v1\ntagger A U Thor <author@example.com> 1700000000 +0000\n\nVersion 1\n",
+                    blob.id
+                )
+                .as_bytes(),
+            ),
+        ];
+        let mut objects: Vec<GitObject> = objects.into_iter().map(Result::unwrap).collect();
+        objects.extend([blob, tree]);
+        assert_eq!(
+            objects.iter().map(|o| ObjectKind::from(&o.obj_type)).collect::<std::collections::HashSet<_>>().len(),
+            ObjectKind::iter().count()
+        );
+
+        for object in objects {
+            let model = ActiveModel::from_git_object(repository_id, object.clone());
+            assert_eq!(model.repository_id, ActiveValue::Set(repository_id));
+            assert_eq!(model.id, ActiveValue::Set(object.id.clone()));
+            assert_eq!(model.size, ActiveValue::Set(object.size as i64));
+            assert_eq!(model.content, ActiveValue::Set(Some(object.content.clone())));
+            let ActiveValue::Set(kind) = model.object_type else {
+                panic!("object type not set");
+            };
+            assert_eq!(ObjectType::from(kind), object.obj_type);
+        }
+    }
+}
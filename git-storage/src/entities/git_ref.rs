@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
 #[sea_orm(table_name = "git_refs")]
 pub struct Model {
-    #[sea_orm(primary_key)]
+    #[sea_orm(primary_key, auto_increment = false)]
     pub id: Uuid,
     pub repository_id: Uuid,
     pub name: String,
@@ -30,4 +30,13 @@ impl Related<super::repository::Entity> for Entity {
     }
 }
 
-impl ActiveModelBehavior for ActiveModel {}
\ No newline at end of file
+impl ActiveModelBehavior for ActiveModel {}
+impl From<Model> for git_protocol::GitRef {
+    fn from(model: Model) -> Self {
+        Self {
+            name: model.name,
+            target: model.target,
+            is_symbolic: model.is_symbolic,
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "idempotency_keys")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    /// Endpoint (and caller) the key belongs to
+    pub scope: String,
+    pub key: String,
+    pub resource_id: String,
+    pub response_status: i32,
+    #[sea_orm(column_type = "Text")]
+    pub response_body: String,
+    pub created_at: ChronoDateTimeWithTimeZone,
+    pub expires_at: ChronoDateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
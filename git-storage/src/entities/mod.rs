@@ -1,17 +1,37 @@
+pub mod access_token;
+pub mod blob_ref;
 pub mod branch;
 pub mod commit;
+pub mod commit_status;
+pub mod email_alias;
 pub mod git_object;
 pub mod git_ref;
+pub mod idempotency_key;
+pub mod packed_refs;
+pub mod ref_log;
 pub mod repository;
+pub mod repository_event;
+pub mod repository_topic;
+pub mod ssh_key;
 pub mod tag;
 pub mod tree;
 pub mod user;
 
+pub use access_token::Entity as AccessToken;
+pub use blob_ref::Entity as BlobRef;
 pub use branch::Entity as Branch;
 pub use commit::Entity as Commit;
+pub use commit_status::Entity as CommitStatus;
+pub use email_alias::Entity as EmailAlias;
 pub use git_object::Entity as GitObject;
 pub use git_ref::Entity as GitRef;
+pub use idempotency_key::Entity as IdempotencyKey;
+pub use packed_refs::Entity as PackedRefs;
+pub use ref_log::Entity as RefLog;
 pub use repository::Entity as Repository;
+pub use repository_event::Entity as RepositoryEvent;
+pub use repository_topic::Entity as RepositoryTopic;
+pub use ssh_key::Entity as SshKey;
 pub use tag::Entity as Tag;
 pub use tree::Entity as Tree;
 pub use user::Entity as User;
\ No newline at end of file
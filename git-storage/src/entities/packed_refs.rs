@@ -0,0 +1,33 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A repository's refs in packed-refs form, see `RepositoryService::with_packed_refs`
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "packed_refs")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub repository_id: Uuid,
+    /// `repositories.ref_generation` the content was built at; stale once it moves on
+    pub ref_generation: i64,
+    #[sea_orm(column_type = "Text")]
+    pub content: String,
+    pub updated_at: ChronoDateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::repository::Entity",
+        from = "Column::RepositoryId",
+        to = "super::repository::Column::Id"
+    )]
+    Repository,
+}
+
+impl Related<super::repository::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Repository.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
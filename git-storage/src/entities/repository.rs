@@ -1,26 +1,145 @@
+use git_protocol::HashAlgorithm;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
 #[sea_orm(table_name = "repositories")]
 pub struct Model {
-    #[sea_orm(primary_key)]
+    #[sea_orm(primary_key, auto_increment = false)]
     pub id: Uuid,
     pub name: String,
     pub description: Option<String>,
     pub default_branch: String,
     pub owner_id: Uuid,
-    pub is_private: bool,
+    pub visibility: Visibility,
+    /// Optimistic lock for settings updates, bumped on every change
+    pub version: i32,
+    /// Space-separated ref prefixes hidden from clients, e.g. `refs/pull refs/notes`
+    pub hidden_ref_prefixes: Option<String>,
+    /// Admin overrides of the server's read guardrails, `None` uses the server default
+    pub max_commits_walked: Option<i32>,
+    pub max_tree_entries: Option<i32>,
+    pub read_timeout_secs: Option<i32>,
+    /// Read-only: pushes and API writes are refused until it is unarchived
+    pub is_archived: bool,
+    /// Repository this one was forked from
+    pub forked_from: Option<Uuid>,
+    /// Message shown to clients after each pushed ref update, with `{ref}`, `{repo}`
+    /// and `{pusher}` filled in
+    pub push_message: Option<String>,
+    /// Bumped by every ref change, so caches keyed on it never outlive the refs
+    pub ref_generation: i64,
+    /// Space-separated branch names that refuse merge commits, e.g. `main release`
+    pub linear_history_branches: Option<String>,
+    /// Total size of the repository's objects, kept current as they are stored so
+    /// quotas can be checked without scanning them
+    pub size_bytes: i64,
+    /// Whether anonymous callers may clone and fetch it when they can read it; the
+    /// server's `REQUIRE_AUTH` overrides this
+    pub allow_anonymous_clone: bool,
+    /// Set while the repository is soft-deleted: hidden everywhere but kept until
+    /// purged, with its name freed and remembered in `deleted_name`
+    pub deleted_at: Option<ChronoDateTimeWithTimeZone>,
+    pub deleted_name: Option<String>,
+    /// Hash naming the repository's objects, fixed when it is created
+    pub object_format: ObjectFormat,
     pub created_at: ChronoDateTimeWithTimeZone,
     pub updated_at: ChronoDateTimeWithTimeZone,
 }
 
+/// Who may read a repository; writes always need its owner or an admin.
+/// A CHECK constraint limits the column to these.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Hash, EnumIter, DeriveActiveEnum, Serialize, Deserialize,
+)]
+#[sea_orm(rs_type = "String", db_type = "String(None)")]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Anyone, signed in or not
+    #[default]
+    #[sea_orm(string_value = "public")]
+    Public,
+    /// Any signed-in user
+    #[sea_orm(string_value = "internal")]
+    Internal,
+    /// The owner and admins
+    #[sea_orm(string_value = "private")]
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Internal => "internal",
+            Visibility::Private => "private",
+        }
+    }
+
+    /// The level an `is_private` flag from before visibility levels stands for
+    pub fn from_is_private(is_private: bool) -> Self {
+        if is_private {
+            Visibility::Private
+        } else {
+            Visibility::Public
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Hash a repository names its objects with. A CHECK constraint limits the
+/// column to these.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Hash, EnumIter, DeriveActiveEnum, Serialize, Deserialize,
+)]
+#[sea_orm(rs_type = "String", db_type = "String(None)")]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectFormat {
+    #[default]
+    #[sea_orm(string_value = "sha1")]
+    Sha1,
+    #[sea_orm(string_value = "sha256")]
+    Sha256,
+}
+
+impl ObjectFormat {
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        match self {
+            ObjectFormat::Sha1 => HashAlgorithm::Sha1,
+            ObjectFormat::Sha256 => HashAlgorithm::Sha256,
+        }
+    }
+}
+
+impl From<HashAlgorithm> for ObjectFormat {
+    fn from(hash: HashAlgorithm) -> Self {
+        match hash {
+            HashAlgorithm::Sha1 => ObjectFormat::Sha1,
+            HashAlgorithm::Sha256 => ObjectFormat::Sha256,
+        }
+    }
+}
+
+impl fmt::Display for ObjectFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.hash_algorithm().name())
+    }
+}
+
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "super::git_object::Entity")]
     GitObjects,
     #[sea_orm(has_many = "super::git_ref::Entity")]
     GitRefs,
+    #[sea_orm(has_many = "super::repository_topic::Entity")]
+    Topics,
     #[sea_orm(
         belongs_to = "super::user::Entity",
         from = "Column::OwnerId",
@@ -41,6 +160,12 @@ impl Related<super::git_ref::Entity> for Entity {
     }
 }
 
+impl Related<super::repository_topic::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Topics.def()
+    }
+}
+
 impl Related<super::user::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::User.def()
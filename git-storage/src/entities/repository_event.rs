@@ -0,0 +1,43 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "repository_events")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    pub repository_id: Uuid,
+    /// A `RepositoryEventType`, e.g. `push`, `fetch` or `branch_created`
+    pub event_type: String,
+    /// `http`, `ssh` or `api`
+    pub transport: String,
+    pub protocol_version: i32,
+    /// Value of the client's `agent=` capability
+    pub agent: Option<String>,
+    /// HTTP User-Agent header
+    pub user_agent: Option<String>,
+    /// JSON details for events outside the git protocol, e.g. the new topic list
+    #[sea_orm(column_type = "Text", nullable)]
+    pub payload: Option<String>,
+    /// User who made the change, when known
+    pub actor_id: Option<Uuid>,
+    pub created_at: ChronoDateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::repository::Entity",
+        from = "Column::RepositoryId",
+        to = "super::repository::Column::Id"
+    )]
+    Repository,
+}
+
+impl Related<super::repository::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Repository.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,5 +1,6 @@
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
+use super::git_object::ObjectKind;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
 #[sea_orm(table_name = "tags")]
@@ -9,7 +10,7 @@ pub struct Model {
     pub repository_id: Uuid,
     pub name: String, // Tag name (e.g., "v1.0.0")
     pub target_id: String, // SHA-1 of the object this tag points to (usually a commit)
-    pub target_type: String, // Type of object: "commit", "tree", "blob", or "tag"
+    pub target_type: ObjectKind, // Type of the object the tag points to
     pub tag_object_id: Option<String>, // SHA-1 of the tag object itself (for annotated tags)
     pub tagger_name: Option<String>, // For annotated tags
     pub tagger_email: Option<String>, // For annotated tags
@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
 #[sea_orm(table_name = "user")]
 pub struct Model {
-    #[sea_orm(primary_key)]
+    #[sea_orm(primary_key, auto_increment = false)]
     pub id: Uuid,
     pub username: String,
     pub email: String,
@@ -12,6 +12,15 @@ pub struct Model {
     pub full_name: Option<String>,
     pub is_active: bool,
     pub is_admin: bool,
+    /// Show a noreply address instead of `email` and this user's commit emails
+    pub hide_email: bool,
+    /// Admin overrides of the server's quotas, `None` uses the server default
+    pub max_repositories: Option<i32>,
+    pub max_total_bytes: Option<i64>,
+    /// Directory of this user's uploaded avatar images, `None` for an identicon
+    pub avatar_blob_key: Option<String>,
+    /// Bumped on every avatar change so versioned avatar URLs never go stale
+    pub avatar_version: i32,
     pub created_at: ChronoDateTimeWithTimeZone,
     pub updated_at: ChronoDateTimeWithTimeZone,
 }
@@ -0,0 +1,71 @@
+use thiserror::Error;
+
+/// Storage errors callers may want to match on, carried inside `anyhow::Error`
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("expected {expected}, found {found} for {id}")]
+    ObjectTypeMismatch {
+        id: String,
+        expected: String,
+        found: String,
+    },
+    #[error("unknown object type '{value}'")]
+    UnknownObjectType { value: String },
+    #[error("repository {id} not found")]
+    RepositoryNotFound { id: uuid::Uuid },
+    #[error("repository {id} was updated concurrently: expected version {expected}, now at {current}")]
+    VersionConflict { id: uuid::Uuid, expected: i32, current: i32 },
+    #[error("resource busy, retry")]
+    ResourceBusy,
+    #[error("ref {name} has moved")]
+    StaleRef { name: String },
+    #[error("ref {name} does not exist")]
+    RefNotFound { name: String },
+    #[error("path {path} does not exist")]
+    PathNotFound { path: String },
+    #[error("cannot update symbolic ref {name}")]
+    SymbolicRef { name: String },
+    #[error("symbolic ref {name} is part of a cycle")]
+    SymbolicRefCycle { name: String },
+    #[error("ref {name} differs only in case from existing ref {existing}")]
+    RefNameConflict { name: String, existing: String },
+    #[error("invalid topic '{topic}': {reason}")]
+    InvalidTopic { topic: String, reason: String },
+    #[error("a repository can have at most {max} topics")]
+    TooManyTopics { max: usize },
+    #[error("walked more than {max} commits")]
+    CommitLimitExceeded { max: usize },
+    #[error("visited more than {max} tree entries")]
+    TreeEntryLimitExceeded { max: usize },
+    #[error("non-linear history: {commit} is a merge commit")]
+    NonLinearHistory { commit: String },
+    #[error("{name} can only be fast-forwarded; rebase or squash the changes onto it")]
+    NotFastForward { name: String },
+    #[error("{commit} is not an ancestor of {branch}")]
+    NotAncestor { commit: String, branch: String },
+    #[error("merge conflict in {}", paths.join(", "))]
+    MergeConflict { paths: Vec<String> },
+    #[error("invalid email address '{email}': {reason}")]
+    InvalidEmail { email: String, reason: String },
+    #[error("this push needs {needed} bytes but only {available} are left in the disk quota")]
+    QuotaExceeded { needed: u64, available: u64 },
+    #[error("SSH key {fingerprint} is already in use")]
+    SshKeyInUse { fingerprint: String },
+    #[error("{email} is already in use")]
+    EmailTaken { email: String },
+    #[error("a repository named {name} already exists")]
+    RepositoryNameTaken { name: String },
+    #[error("{id} is not a {format} object id")]
+    ObjectFormatMismatch { id: String, format: String },
+    #[error("user {id} not found")]
+    UserNotFound { id: uuid::Uuid },
+    #[error("unsupported database URL scheme '{scheme}'; use sqlite: or postgres://")]
+    UnsupportedDatabase { scheme: String },
+    #[error("migration {migration} failed: {error}; the schema may be half-migrated, so repair it by hand, then delete the row in migration_lock to retry")]
+    MigrationFailed { migration: String, error: String },
+    #[error(
+        "migrations are locked by {holder} since {since}{}; if no other instance is migrating, that run was interrupted: check the schema, then delete the row in migration_lock",
+        applying.as_ref().map(|m| format!(" while applying {}", m)).unwrap_or_default()
+    )]
+    MigrationLockHeld { holder: String, since: String, applying: Option<String> },
+}
@@ -0,0 +1,306 @@
+use crate::entities::repository_event;
+use crate::git_ops::MergeResult;
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset, Utc};
+use git_protocol::ZERO_ID;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepositoryEventType {
+    Push,
+    Fetch,
+    TopicsUpdated,
+    BranchCreated,
+    BranchDeleted,
+    TagCreated,
+    TagDeleted,
+    Merge,
+    Transferred,
+}
+
+impl RepositoryEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RepositoryEventType::Push => "push",
+            RepositoryEventType::Fetch => "fetch",
+            RepositoryEventType::TopicsUpdated => "topics_updated",
+            RepositoryEventType::BranchCreated => "branch_created",
+            RepositoryEventType::BranchDeleted => "branch_deleted",
+            RepositoryEventType::TagCreated => "tag_created",
+            RepositoryEventType::TagDeleted => "tag_deleted",
+            RepositoryEventType::Merge => "merge",
+            RepositoryEventType::Transferred => "transferred",
+        }
+    }
+}
+
+/// Event types shown in a repository's activity feed. Fetches and settings
+/// changes are kept for operators but aren't part of the feed.
+pub const ACTIVITY_EVENT_TYPES: [RepositoryEventType; 6] = [
+    RepositoryEventType::Push,
+    RepositoryEventType::BranchCreated,
+    RepositoryEventType::BranchDeleted,
+    RepositoryEventType::TagCreated,
+    RepositoryEventType::TagDeleted,
+    RepositoryEventType::Merge,
+];
+
+/// Activity feed entries per page when the caller doesn't say
+pub const DEFAULT_ACTIVITY_LIMIT: u64 = 30;
+
+/// Most activity feed entries a single page may hold
+pub const MAX_ACTIVITY_LIMIT: u64 = 100;
+
+/// An event to record; pushes and fetches carry what we know about the client
+#[derive(Debug, Clone)]
+pub struct NewRepositoryEvent {
+    pub repository_id: Uuid,
+    pub event_type: RepositoryEventType,
+    pub transport: String,
+    pub protocol_version: u8,
+    pub agent: Option<String>,
+    pub user_agent: Option<String>,
+    pub payload: Option<String>,
+    pub actor_id: Option<Uuid>,
+}
+
+impl NewRepositoryEvent {
+    /// An event for a change `actor_id` made through the REST API
+    pub fn api<T: Serialize>(
+        repository_id: Uuid,
+        event_type: RepositoryEventType,
+        actor_id: Option<Uuid>,
+        payload: &T,
+    ) -> Self {
+        Self {
+            repository_id,
+            event_type,
+            transport: "api".to_string(),
+            protocol_version: 0,
+            agent: None,
+            user_agent: None,
+            payload: serde_json::to_string(payload).ok(),
+            actor_id,
+        }
+    }
+}
+
+/// Most commits listed per ref in a push event; `total_commits` still counts all of them
+pub const MAX_PUSH_EVENT_COMMITS: usize = 20;
+
+/// Payload of a push event: one entry per ref the push changed, in command order.
+/// A push that changes no refs (e.g. a retry of one already applied) records no event.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushEventPayload {
+    pub refs: Vec<PushedRef>,
+}
+
+/// One ref changed by a push, with git's zero id standing in for a missing side
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushedRef {
+    #[serde(rename = "ref")]
+    pub name: String,
+    pub before: String,
+    pub after: String,
+    pub created: bool,
+    pub deleted: bool,
+    /// Commits reachable from `after` and not from `before`, oldest first; only the
+    /// newest `MAX_PUSH_EVENT_COMMITS` are listed
+    pub commits: Vec<String>,
+    pub total_commits: usize,
+}
+
+impl PushedRef {
+    /// Entry for a ref moved from `before` to `after`, `None` meaning it didn't
+    /// exist. `commits` are all the commits the update added.
+    pub fn new(name: String, before: Option<&str>, after: Option<&str>, mut commits: Vec<String>) -> Self {
+        let total_commits = commits.len();
+        commits.drain(..total_commits.saturating_sub(MAX_PUSH_EVENT_COMMITS));
+        Self {
+            name,
+            before: before.unwrap_or(ZERO_ID).to_string(),
+            after: after.unwrap_or(ZERO_ID).to_string(),
+            created: before.is_none(),
+            deleted: after.is_none(),
+            commits,
+            total_commits,
+        }
+    }
+}
+
+/// Payload of a branch or tag created or deleted through the API; `target` is
+/// what the ref pointed at
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefEventPayload {
+    #[serde(rename = "ref")]
+    pub name: String,
+    pub target: String,
+}
+
+/// Payload of a merge through the API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeEventPayload {
+    pub source_branch: String,
+    pub target_branch: String,
+    #[serde(flatten)]
+    pub result: MergeResult,
+}
+
+/// Payload of an ownership transfer; the names differ when the repository was
+/// renamed on the way
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferEventPayload {
+    pub from_owner: Uuid,
+    pub to_owner: Uuid,
+    pub from_name: String,
+    pub to_name: String,
+}
+
+/// What an activity feed entry records, tagged with its `kind`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "snake_case")]
+pub enum Activity {
+    Push(PushEventPayload),
+    BranchCreated(RefEventPayload),
+    BranchDeleted(RefEventPayload),
+    TagCreated(RefEventPayload),
+    TagDeleted(RefEventPayload),
+    Merge(MergeEventPayload),
+}
+
+impl Activity {
+    /// Parse a stored event, `None` for event types outside the feed and for
+    /// payloads that can't be read
+    fn from_event(event: &repository_event::Model) -> Option<Self> {
+        let payload: serde_json::Value = serde_json::from_str(event.payload.as_deref()?).ok()?;
+        serde_json::from_value(serde_json::json!({ "kind": event.event_type, "payload": payload })).ok()
+    }
+}
+
+/// One entry of a repository's activity feed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActivityEvent {
+    pub id: Uuid,
+    pub actor_id: Option<Uuid>,
+    #[serde(flatten)]
+    pub activity: Activity,
+    pub created_at: DateTime<FixedOffset>,
+}
+
+/// A page of the activity feed, newest first
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActivityPage {
+    pub events: Vec<ActivityEvent>,
+    /// Pass as `before` to get the next page; `None` on the last one
+    pub next_cursor: Option<Uuid>,
+}
+
+/// Stores push, fetch and settings events per repository
+pub struct EventService {
+    db: DatabaseConnection,
+}
+
+impl EventService {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Record an event
+    pub async fn record(&self, event: NewRepositoryEvent) -> Result<repository_event::Model> {
+        Ok(insert_event(&self.db, event).await?)
+    }
+
+    /// Most recent events for a repository, newest first
+    pub async fn list_by_repository(
+        &self,
+        repository_id: Uuid,
+        limit: u64,
+    ) -> Result<Vec<repository_event::Model>> {
+        let events = repository_event::Entity::find()
+            .filter(repository_event::Column::RepositoryId.eq(repository_id))
+            .order_by_desc(repository_event::Column::CreatedAt)
+            .limit(limit)
+            .all(&self.db)
+            .await?;
+        Ok(events)
+    }
+
+    /// A page of the activity feed: events of `ACTIVITY_EVENT_TYPES`, newest first,
+    /// older than the event `before` when given. Retention prunes the oldest events
+    /// first, so a cursor whose event is gone has nothing older and gets an empty page.
+    pub async fn activity(&self, repository_id: Uuid, limit: u64, before: Option<Uuid>) -> Result<ActivityPage> {
+        let limit = limit.clamp(1, MAX_ACTIVITY_LIMIT);
+        let kinds = ACTIVITY_EVENT_TYPES.iter().map(|kind| kind.as_str());
+        let mut query = repository_event::Entity::find()
+            .filter(repository_event::Column::RepositoryId.eq(repository_id))
+            .filter(repository_event::Column::EventType.is_in(kinds));
+        if let Some(before) = before {
+            let Some(cursor) = repository_event::Entity::find_by_id(before)
+                .filter(repository_event::Column::RepositoryId.eq(repository_id))
+                .one(&self.db)
+                .await?
+            else {
+                return Ok(ActivityPage { events: Vec::new(), next_cursor: None });
+            };
+            // Events recorded in the same instant are ordered by id
+            query = query.filter(
+                Condition::any()
+                    .add(repository_event::Column::CreatedAt.lt(cursor.created_at))
+                    .add(
+                        Condition::all()
+                            .add(repository_event::Column::CreatedAt.eq(cursor.created_at))
+                            .add(repository_event::Column::Id.lt(cursor.id)),
+                    ),
+            );
+        }
+        let mut rows = query
+            .order_by_desc(repository_event::Column::CreatedAt)
+            .order_by_desc(repository_event::Column::Id)
+            .limit(limit + 1)
+            .all(&self.db)
+            .await?;
+
+        let has_more = rows.len() as u64 > limit;
+        rows.truncate(limit as usize);
+        let next_cursor = rows.last().filter(|_| has_more).map(|last| last.id);
+        let events = rows
+            .iter()
+            .filter_map(|event| {
+                Some(ActivityEvent {
+                    id: event.id,
+                    actor_id: event.actor_id,
+                    activity: Activity::from_event(event)?,
+                    created_at: event.created_at,
+                })
+            })
+            .collect();
+        Ok(ActivityPage { events, next_cursor })
+    }
+}
+
+/// Insert an event on `conn`, so it can be written in the same transaction as the
+/// change it records
+pub(crate) async fn insert_event<C: ConnectionTrait>(
+    conn: &C,
+    event: NewRepositoryEvent,
+) -> Result<repository_event::Model, DbErr> {
+    let record = repository_event::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        repository_id: Set(event.repository_id),
+        event_type: Set(event.event_type.as_str().to_string()),
+        transport: Set(event.transport),
+        protocol_version: Set(event.protocol_version as i32),
+        agent: Set(event.agent),
+        user_agent: Set(event.user_agent),
+        payload: Set(event.payload),
+        actor_id: Set(event.actor_id),
+        created_at: Set(Utc::now().into()),
+    };
+    record.insert(conn).await
+}
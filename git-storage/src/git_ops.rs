@@ -1,19 +1,112 @@
+use crate::diff::{
+    self, ChangeKind, DiffFormat, DiffOptions, FileDiff, DEFAULT_CONTEXT_LINES, MAX_RENAME_CANDIDATES,
+};
+use crate::entities::git_object::ObjectKind;
 use crate::entities::{git_object, git_ref};
+use crate::identities::CommitUser;
+use crate::repository::{RefUpdate, ResolvedRef};
 use crate::RepositoryService;
 use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
-use git_protocol::objects::{Commit, ObjectHandler};
-use git_protocol::{GitObject, ObjectType};
+use git_protocol::hash::is_object_id;
+use git_protocol::objects::{parse_signature_date, Commit, ObjectHandler, Trailer};
+use git_protocol::{text, GitObject};
+use crate::entities::repository;
+use crate::error::StorageError;
+use git_protocol::objects::{Tree, TreeEntry};
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set,
+    ColumnTrait, ConnectionTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
 use uuid::Uuid;
 
-/// Advanced Git operations service
+/// Commits per history page when no limit is given
+pub const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+/// Largest history page a caller can ask for
+pub const MAX_HISTORY_LIMIT: usize = 1000;
+
+/// Commits in a graph when no limit is given
+pub const DEFAULT_GRAPH_LIMIT: usize = 100;
+
+/// Most commits one graph request can lay out
+pub const MAX_GRAPH_NODES: usize = 1000;
+
+/// Ref notes are read from and written to unless another is given
+pub const DEFAULT_NOTES_REF: &str = "refs/notes/commits";
+
+/// Message of the commits `set_note` adds to a notes ref, as `git notes` writes it
+const NOTES_COMMIT_MESSAGE: &str = "Notes added by 'git notes add'\n";
+
+/// Id of the empty tree, which git never needs to store
+pub const EMPTY_TREE_ID: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+/// Advanced Git operations service.
+///
+/// Every method runs against the connection it is given, so several calls can share
+/// one transaction from `RepositoryService::transaction`.
 pub struct GitOperations {
     repository_service: RepositoryService,
     object_handler: ObjectHandler,
+    limits: ReadLimits,
+}
+
+/// Caps on how much of a repository one read may visit; `None` is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadLimits {
+    /// Commits loaded by a history or ancestry walk
+    pub max_commits: Option<usize>,
+    /// Entries read from one tree, subtrees included
+    pub max_tree_entries: Option<usize>,
+    /// Blob size above which a diff summarizes the file instead of line-diffing it
+    pub max_diff_bytes: Option<usize>,
+    /// Size of rendered patch text past which the remaining files are left out
+    pub max_diff_total_bytes: Option<usize>,
+}
+
+/// Whether `GitOperations::walk_objects` goes on into what an object points at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkStep {
+    Descend,
+    /// Skip the object's tree and parents, entries or tag target
+    Prune,
+}
+
+/// Called once for each object a walk reaches, with its type and depth: the
+/// parent links between it and the nearest root, its trees and blobs counting
+/// as part of their commit
+pub trait ObjectVisitor: Send {
+    fn visit(&mut self, object_id: &str, kind: ObjectKind, depth: usize) -> WalkStep;
+}
+
+impl<F: FnMut(&str, ObjectKind, usize) -> WalkStep + Send> ObjectVisitor for F {
+    fn visit(&mut self, object_id: &str, kind: ObjectKind, depth: usize) -> WalkStep {
+        self(object_id, kind, depth)
+    }
+}
+
+/// What a fetch leaves out of the history it sends
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchOptions {
+    /// Commits to send along each line of history from the wants, `None` for all
+    pub depth: Option<usize>,
+    /// Commits a shallow client's history already stops at: the server treats
+    /// them as having no parents, as the client does
+    pub client_shallow: Vec<String>,
+    /// Leave out blobs of this many bytes or more, unless they are wanted by id
+    pub blob_limit: Option<u64>,
+}
+
+/// How a deepened fetch moves a client's shallow boundary
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShallowUpdate {
+    /// Commits sent without (all of) their parents
+    pub shallow: Vec<String>,
+    /// Commits the client had as shallow whose parents are now sent
+    pub unshallow: Vec<String>,
 }
 
 /// Branch information
@@ -25,6 +118,11 @@ pub struct BranchInfo {
     pub message: String,
     pub created_at: DateTime<Utc>,
     pub is_default: bool,
+    /// Commits on this branch that are not on the default branch (only when compared;
+    /// `None` if the histories are unrelated)
+    pub ahead: Option<usize>,
+    /// Commits on the default branch that are not on this branch
+    pub behind: Option<usize>,
 }
 
 /// Tag information
@@ -52,6 +150,173 @@ pub struct CreateCommitRequest {
     pub author: String,
     pub committer: String,
     pub message: String,
+    /// Dates written into the author and committer signatures in place of any
+    /// they carry; left out, the signatures are kept as given
+    #[serde(default)]
+    pub author_date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub commit_date: Option<DateTime<Utc>>,
+}
+
+/// A change to one path in `GitOperations::commit_files`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileChange {
+    /// Add the file or replace its content
+    Write(Vec<u8>),
+    Delete,
+}
+
+/// Commit history query
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryOptions {
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+    /// Follow only the first parent of each commit, like `git log --first-parent`
+    pub first_parent: bool,
+    /// Return only commits with two or more parents
+    pub merges_only: bool,
+    /// Only commits with a committer date at or after this
+    pub since: Option<DateTime<Utc>>,
+    /// Only commits with a committer date at or before this
+    pub until: Option<DateTime<Utc>>,
+    /// Author to match, see `identity_matches`
+    pub author: Option<String>,
+    /// Committer to match, see `identity_matches`
+    pub committer: Option<String>,
+}
+
+impl HistoryOptions {
+    /// Whether a commit passes the date and identity filters. These only decide
+    /// what is returned; the walk always continues through a filtered commit's
+    /// parents, since with clock skew an old commit can have newer ancestors.
+    fn matches(&self, commit: &Commit) -> bool {
+        self.since.is_none_or(|since| commit.commit_date >= since)
+            && self.until.is_none_or(|until| commit.commit_date <= until)
+            && self.author.as_deref().is_none_or(|a| identity_matches(&commit.author, a))
+            && self.committer.as_deref().is_none_or(|c| identity_matches(&commit.committer, c))
+    }
+}
+
+/// A commit in a history listing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryCommit {
+    pub hash: String,
+    #[serde(flatten)]
+    pub commit: Commit,
+    /// From the message's `Co-authored-by` trailers
+    pub co_authors: Vec<String>,
+    /// Accounts the author and committer emails belong to, filled in by `identify`
+    #[serde(default)]
+    pub author_user: Option<CommitUser>,
+    #[serde(default)]
+    pub committer_user: Option<CommitUser>,
+}
+
+/// A single commit with its parsed trailers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitDetail {
+    pub hash: String,
+    #[serde(flatten)]
+    pub commit: Commit,
+    pub trailers: Vec<Trailer>,
+    pub co_authors: Vec<String>,
+    /// Accounts the author and committer emails belong to, filled in by `identify`
+    #[serde(default)]
+    pub author_user: Option<CommitUser>,
+    #[serde(default)]
+    pub committer_user: Option<CommitUser>,
+}
+
+/// One page of commit history, echoing the filters that produced it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitHistory {
+    pub commits: Vec<HistoryCommit>,
+    pub next_cursor: Option<String>,
+    pub first_parent: bool,
+    pub merges_only: bool,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub author: Option<String>,
+    pub committer: Option<String>,
+}
+
+/// A commit placed for drawing, see `GitOperations::commit_graph`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphCommit {
+    pub hash: String,
+    pub subject: String,
+    pub parents: Vec<String>,
+    /// Refs pointing at this commit, e.g. `HEAD`, `refs/heads/main`, `refs/tags/v1`
+    pub refs: Vec<String>,
+    /// Column the commit is drawn in, 0 being leftmost
+    pub lane: usize,
+}
+
+/// Commits in topological order, children before parents, with their lanes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitGraph {
+    pub commits: Vec<GraphCommit>,
+    /// Columns needed to draw every commit and the edges running past it
+    pub lanes: usize,
+    /// Older commits were left out to keep to the limit; parents missing from
+    /// `commits` continue past the bottom of the graph
+    pub truncated: bool,
+}
+
+/// README names looked for at the root of a tree, case-insensitively, in order
+/// of preference
+pub const README_NAMES: [&str; 3] = ["readme.md", "readme", "readme.txt"];
+
+/// How a README's content is meant to be shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadmeFormat {
+    Markdown,
+    Plain,
+}
+
+/// The README at the root of a commit's tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Readme {
+    /// File name as stored, e.g. `README.md`
+    pub name: String,
+    /// Blob id
+    pub id: String,
+    pub format: ReadmeFormat,
+    /// Raw content; invalid UTF-8 is replaced
+    pub content: String,
+}
+
+/// What a tree entry points at, going by its mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TreeEntryKind {
+    Blob,
+    Tree,
+    /// A gitlink: a commit in another repository
+    Submodule,
+}
+
+impl TreeEntryKind {
+    pub fn from_mode(mode: &str) -> Self {
+        match mode.trim_start_matches('0') {
+            "40000" => TreeEntryKind::Tree,
+            "160000" => TreeEntryKind::Submodule,
+            _ => TreeEntryKind::Blob,
+        }
+    }
+}
+
+/// A note attached to a commit under a notes ref
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Note {
+    /// Commit the note is about
+    pub commit: String,
+    pub notes_ref: String,
+    /// Id of the blob holding the note
+    pub id: String,
+    pub content: String,
 }
 
 /// Merge operation request
@@ -60,7 +325,34 @@ pub struct MergeRequest {
     pub source_branch: String,
     pub target_branch: String,
     pub author: String,
+    /// Message of the commit the merge creates; empty for the strategy's default
+    #[serde(default)]
     pub message: String,
+    #[serde(default)]
+    pub strategy: MergeStrategy,
+}
+
+/// How a merge brings the source branch's changes onto the target
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    /// Fast-forward when possible, otherwise a merge commit with both tips as parents
+    #[default]
+    Merge,
+    /// Only ever fast-forward, failing with `StorageError::NotFastForward`
+    FastForwardOnly,
+    /// One new commit on the target with the merged tree; the source isn't a parent
+    Squash,
+}
+
+/// What a merge did to the target branch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeResult {
+    pub strategy: MergeStrategy,
+    /// The target branch's tip after the merge
+    pub commit: String,
+    /// The target moved to the source tip without a new commit
+    pub fast_forward: bool,
 }
 
 impl GitOperations {
@@ -68,38 +360,275 @@ impl GitOperations {
         Self {
             repository_service,
             object_handler: ObjectHandler::new(),
+            limits: ReadLimits::default(),
+        }
+    }
+
+    /// Fail reads that go past `limits` with `StorageError::CommitLimitExceeded`
+    /// or `StorageError::TreeEntryLimitExceeded`
+    pub fn with_limits(mut self, limits: ReadLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Helper: Check how many commits a walk has loaded
+    fn check_commit_limit(&self, walked: usize) -> Result<()> {
+        match self.limits.max_commits {
+            Some(max) if walked > max => Err(StorageError::CommitLimitExceeded { max }.into()),
+            _ => Ok(()),
         }
     }
 
     /// Create a new commit
-    pub async fn create_commit(
+    pub async fn create_commit<C: ConnectionTrait>(
         &self,
+        db: &C,
         repository_id: Uuid,
         request: CreateCommitRequest,
     ) -> Result<String> {
-        // Create commit object
+        let author = match request.author_date {
+            Some(date) => stamp_signature(&request.author, date),
+            None => request.author,
+        };
+        let committer = match request.commit_date {
+            Some(date) => stamp_signature(&request.committer, date),
+            None => request.committer,
+        };
+        // Dates the signatures don't carry fall back to now
         let commit = Commit {
             tree: request.tree_hash,
             parents: request.parent_hashes,
-            author: request.author.clone(),
-            committer: request.committer,
+            author_date: parse_signature_date(&author).unwrap_or_else(Utc::now),
+            commit_date: parse_signature_date(&committer).unwrap_or_else(Utc::now),
+            author,
+            committer,
             message: request.message,
-            author_date: Utc::now(),
-            commit_date: Utc::now(),
+            encoding: None,
         };
 
-        let commit_object = self.object_handler.create_commit(&commit)?;
+        let commit_object = self.object_handler(db, repository_id).await?.create_commit(&commit)?;
         let commit_hash = commit_object.id.clone();
 
         // Store the commit object
-        self.store_git_object(repository_id, commit_object).await?;
+        self.store_git_object(db, repository_id, commit_object).await?;
+
+        Ok(commit_hash)
+    }
+
+    /// Commit file changes on top of a branch without the caller building trees.
+    ///
+    /// Paths are relative to the repository root. Unchanged entries, subtrees included,
+    /// keep their ids; directories left empty are dropped. A missing branch is created
+    /// with a root commit. The branch only moves if it still points at the tip the
+    /// changes were applied to, otherwise `StorageError::StaleRef` is returned.
+    pub async fn commit_files<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        branch: &str,
+        files: Vec<(String, FileChange)>,
+        author: String,
+        message: String,
+    ) -> Result<String> {
+        if files.is_empty() {
+            return Err(anyhow!("No file changes given"));
+        }
+        let mut changes = Vec::with_capacity(files.len());
+        for (path, change) in files {
+            let components: Vec<String> = path.split('/').map(str::to_string).collect();
+            if components
+                .iter()
+                .any(|c| c.is_empty() || c == "." || c == ".." || c == ".git")
+            {
+                return Err(anyhow!("Invalid path '{}'", path));
+            }
+            changes.push((components, change));
+        }
+
+        let ref_name = format!("refs/heads/{}", branch);
+        let tip = self.get_ref(db, repository_id, &ref_name).await?;
+        let base_tree = match &tip {
+            Some(tip) => Some(self.get_commit_info(db, repository_id, &tip.target).await?.tree),
+            None => None,
+        };
+
+        let handler = self.object_handler(db, repository_id).await?;
+        let tree_id = self
+            .write_tree(db, &handler, repository_id, base_tree, changes)
+            .await?
+            .unwrap_or_else(|| handler.empty_tree_id());
+        let commit_hash = self
+            .create_commit(
+                db,
+                repository_id,
+                CreateCommitRequest {
+                    tree_hash: tree_id,
+                    parent_hashes: tip.iter().map(|t| t.target.clone()).collect(),
+                    author: author.clone(),
+                    committer: author,
+                    message,
+                    author_date: None,
+                    commit_date: None,
+                },
+            )
+            .await?;
+
+        self.advance_ref(db, repository_id, &ref_name, tip, &commit_hash).await?;
+
+        Ok(commit_hash)
+    }
+
+    /// The note `notes_ref` attaches to `commit`, if any. Notes are found whether the
+    /// notes tree names them by the full commit id or fans them out into
+    /// directories (`ab/cdef...`) as git does for large notes trees.
+    pub async fn get_note<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        notes_ref: &str,
+        commit: &str,
+    ) -> Result<Option<Note>> {
+        let Some(tip) = self.get_ref(db, repository_id, notes_ref).await? else {
+            return Ok(None);
+        };
+        let tree = self.get_commit_info(db, repository_id, &tip.target).await?.tree;
+        let Some((_, id)) = self.find_note(db, repository_id, &tree, commit).await? else {
+            return Ok(None);
+        };
+        let content = self.get_object_content(db, repository_id, &id, ObjectKind::Blob).await?;
+        Ok(Some(Note {
+            commit: commit.to_string(),
+            notes_ref: notes_ref.to_string(),
+            id,
+            content: String::from_utf8_lossy(&content).into_owned(),
+        }))
+    }
+
+    /// Attach `content` to `commit` under `notes_ref`, replacing any note it has
+    /// there, with a new commit on the notes ref by `author`. Like `git notes add`,
+    /// the note is stored ending in a newline. `None` if the commit doesn't exist;
+    /// `StorageError::StaleRef` if the notes ref moved meanwhile.
+    pub async fn set_note<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        notes_ref: &str,
+        commit: &str,
+        content: &str,
+        author: String,
+    ) -> Result<Option<Note>> {
+        if !notes_ref.starts_with("refs/notes/") || notes_ref.len() == "refs/notes/".len() {
+            return Err(anyhow!("Notes refs live under refs/notes/, not '{}'", notes_ref));
+        }
+        if self.get_commit(db, repository_id, commit).await?.is_none() {
+            return Ok(None);
+        }
+
+        let mut content = content.to_string();
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        let handler = self.object_handler(db, repository_id).await?;
+        let id = handler.create_blob(content.as_bytes())?.id;
+
+        let tip = self.get_ref(db, repository_id, notes_ref).await?;
+        let base_tree = match &tip {
+            Some(tip) => Some(self.get_commit_info(db, repository_id, &tip.target).await?.tree),
+            None => None,
+        };
+        // Keep an existing note where it is, so fanned out trees stay fanned out
+        let path = match &base_tree {
+            Some(tree) => self.find_note(db, repository_id, tree, commit).await?.map(|(path, _)| path),
+            None => None,
+        };
+        let path = path.unwrap_or_else(|| commit.to_string());
+        let changes = vec![(
+            path.split('/').map(str::to_string).collect(),
+            FileChange::Write(content.clone().into_bytes()),
+        )];
+        let tree_id = self
+            .write_tree(db, &handler, repository_id, base_tree, changes)
+            .await?
+            .unwrap_or_else(|| handler.empty_tree_id());
+
+        let commit_hash = self
+            .create_commit(
+                db,
+                repository_id,
+                CreateCommitRequest {
+                    tree_hash: tree_id,
+                    parent_hashes: tip.iter().map(|t| t.target.clone()).collect(),
+                    author: author.clone(),
+                    committer: author,
+                    message: NOTES_COMMIT_MESSAGE.to_string(),
+                    author_date: None,
+                    commit_date: None,
+                },
+            )
+            .await?;
+        self.advance_ref(db, repository_id, notes_ref, tip, &commit_hash).await?;
+
+        Ok(Some(Note {
+            commit: commit.to_string(),
+            notes_ref: notes_ref.to_string(),
+            id,
+            content,
+        }))
+    }
+
+    /// Replace the message and/or tree of a branch's tip commit, keeping its parents
+    /// and author, and move the branch to the new commit. `committer` is who amends.
+    /// Like `commit_files`, the branch only moves if its tip hasn't changed meanwhile,
+    /// otherwise `StorageError::StaleRef` is returned.
+    pub async fn amend_commit<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        branch: &str,
+        new_message: Option<String>,
+        new_tree: Option<String>,
+        committer: String,
+    ) -> Result<String> {
+        if new_message.is_none() && new_tree.is_none() {
+            return Err(anyhow!("Nothing to amend: give a new message or tree"));
+        }
+
+        let ref_name = format!("refs/heads/{}", branch);
+        let tip = self
+            .get_ref(db, repository_id, &ref_name)
+            .await?
+            .ok_or_else(|| StorageError::RefNotFound { name: ref_name.clone() })?;
+        let commit = self.get_commit_info(db, repository_id, &tip.target).await?;
+        if let Some(tree) = &new_tree {
+            if !is_empty_tree(&self.object_handler(db, repository_id).await?, tree) {
+                self.get_object_content(db, repository_id, tree, ObjectKind::Tree).await?;
+            }
+        }
+
+        let commit_hash = self
+            .create_commit(
+                db,
+                repository_id,
+                CreateCommitRequest {
+                    tree_hash: new_tree.unwrap_or(commit.tree),
+                    parent_hashes: commit.parents,
+                    author: commit.author,
+                    committer,
+                    message: new_message.unwrap_or(commit.message),
+                    author_date: None,
+                    commit_date: None,
+                },
+            )
+            .await?;
+        self.swap_ref(db, repository_id, &tip, &commit_hash).await?;
 
         Ok(commit_hash)
     }
 
     /// Create a new branch
-    pub async fn create_branch(
+    pub async fn create_branch<C: ConnectionTrait>(
         &self,
+        db: &C,
         repository_id: Uuid,
         branch_name: String,
         start_commit: String,
@@ -107,25 +636,14 @@ impl GitOperations {
         let full_ref_name = format!("refs/heads/{}", branch_name);
 
         // Check if branch already exists
-        if self.get_ref(repository_id, &full_ref_name).await?.is_some() {
+        if self.get_ref(db, repository_id, &full_ref_name).await?.is_some() {
             return Err(anyhow!("Branch '{}' already exists", branch_name));
         }
-
-        // Create the reference
-        let git_ref = git_ref::ActiveModel {
-            id: Set(Uuid::new_v4()),
-            repository_id: Set(repository_id),
-            name: Set(full_ref_name),
-            target: Set(start_commit.clone()),
-            is_symbolic: Set(false),
-            created_at: Set(Utc::now().into()),
-            updated_at: Set(Utc::now().into()),
-        };
-
-        git_ref.insert(self.repository_service.get_db()).await?;
+        self.write_ref(db, repository_id, &full_ref_name, None, Some(&start_commit))
+            .await?;
 
         // Get commit info for the branch
-        let commit_info = self.get_commit_info(repository_id, &start_commit).await?;
+        let commit_info = self.get_commit_info(db, repository_id, &start_commit).await?;
 
         Ok(BranchInfo {
             name: branch_name,
@@ -134,46 +652,83 @@ impl GitOperations {
             message: commit_info.message,
             created_at: Utc::now(),
             is_default: false,
+            ahead: None,
+            behind: None,
         })
     }
 
-    /// Delete a branch
-    pub async fn delete_branch(&self, repository_id: Uuid, branch_name: String) -> Result<()> {
+    /// Delete a branch, returning the commit it pointed at; `None` if there was no
+    /// such branch
+    pub async fn delete_branch<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        branch_name: String,
+    ) -> Result<Option<String>> {
         let full_ref_name = format!("refs/heads/{}", branch_name);
 
         // Check if it's the default branch
-        let repo = self.repository_service.get_repository_by_id(repository_id).await?
-            .ok_or_else(|| anyhow!("Repository not found"))?;
+        let repo = self.get_repository(db, repository_id).await?;
 
         if repo.default_branch == branch_name {
             return Err(anyhow!("Cannot delete the default branch"));
         }
 
         // Delete the reference
-        git_ref::Entity::delete_many()
-            .filter(git_ref::Column::RepositoryId.eq(repository_id))
-            .filter(git_ref::Column::Name.eq(full_ref_name))
-            .exec(self.repository_service.get_db())
+        let Some(existing) = self.get_ref(db, repository_id, &full_ref_name).await? else {
+            return Ok(None);
+        };
+        // Fails with `StaleRef` if a push moved the branch since it was read
+        self.write_ref(db, repository_id, &full_ref_name, Some(&existing.target), None)
             .await?;
 
-        Ok(())
+        Ok(Some(existing.target))
     }
 
-    /// List branches in a repository
-    pub async fn list_branches(&self, repository_id: Uuid) -> Result<Vec<BranchInfo>> {
+    /// List branches in a repository, optionally with ahead/behind counts
+    /// relative to the default branch
+    pub async fn list_branches<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        compare: bool,
+    ) -> Result<Vec<BranchInfo>> {
         let refs = git_ref::Entity::find()
             .filter(git_ref::Column::RepositoryId.eq(repository_id))
             .filter(git_ref::Column::Name.like("refs/heads/%"))
-            .all(self.repository_service.get_db())
+            .all(db)
             .await?;
 
-        let repo = self.repository_service.get_repository_by_id(repository_id).await?
-            .ok_or_else(|| anyhow!("Repository not found"))?;
+        let repo = self.get_repository(db, repository_id).await?;
+
+        // Walk the default branch once and compare every branch against that set
+        let default_ref = format!("refs/heads/{}", repo.default_branch);
+        let default_ancestors = match refs.iter().find(|r| r.name == default_ref) {
+            Some(default_branch) if compare => {
+                Some(self.get_ancestors(db, repository_id, &default_branch.target).await?)
+            }
+            _ => None,
+        };
 
         let mut branches = Vec::new();
         for ref_model in refs {
-            let branch_name = ref_model.name[11..].to_string(); // Remove "refs/heads/"
-            let commit_info = self.get_commit_info(repository_id, &ref_model.target).await?;
+            let branch_name = ref_model.name.strip_prefix("refs/heads/").unwrap_or(&ref_model.name).to_string();
+            let commit_info = self.get_commit_info(db, repository_id, &ref_model.target).await?;
+
+            let (ahead, behind) = match &default_ancestors {
+                Some(default_ancestors) => {
+                    let ancestors = self.get_ancestors(db, repository_id, &ref_model.target).await?;
+                    if ancestors.is_disjoint(default_ancestors) {
+                        (None, None)
+                    } else {
+                        (
+                            Some(ancestors.difference(default_ancestors).count()),
+                            Some(default_ancestors.difference(&ancestors).count()),
+                        )
+                    }
+                }
+                None => (None, None),
+            };
 
             branches.push(BranchInfo {
                 name: branch_name.clone(),
@@ -182,6 +737,8 @@ impl GitOperations {
                 message: commit_info.message,
                 created_at: ref_model.created_at.into(),
                 is_default: branch_name == repo.default_branch,
+                ahead,
+                behind,
             });
         }
 
@@ -189,8 +746,9 @@ impl GitOperations {
     }
 
     /// Create a lightweight tag
-    pub async fn create_lightweight_tag(
+    pub async fn create_lightweight_tag<C: ConnectionTrait>(
         &self,
+        db: &C,
         repository_id: Uuid,
         tag_name: String,
         target_commit: String,
@@ -198,22 +756,11 @@ impl GitOperations {
         let full_ref_name = format!("refs/tags/{}", tag_name);
 
         // Check if tag already exists
-        if self.get_ref(repository_id, &full_ref_name).await?.is_some() {
+        if self.get_ref(db, repository_id, &full_ref_name).await?.is_some() {
             return Err(anyhow!("Tag '{}' already exists", tag_name));
         }
-
-        // Create the reference
-        let git_ref = git_ref::ActiveModel {
-            id: Set(Uuid::new_v4()),
-            repository_id: Set(repository_id),
-            name: Set(full_ref_name),
-            target: Set(target_commit.clone()),
-            is_symbolic: Set(false),
-            created_at: Set(Utc::now().into()),
-            updated_at: Set(Utc::now().into()),
-        };
-
-        git_ref.insert(self.repository_service.get_db()).await?;
+        self.write_ref(db, repository_id, &full_ref_name, None, Some(&target_commit))
+            .await?;
 
         Ok(TagInfo {
             name: tag_name,
@@ -226,16 +773,16 @@ impl GitOperations {
     }
 
     /// List tags in a repository
-    pub async fn list_tags(&self, repository_id: Uuid) -> Result<Vec<TagInfo>> {
+    pub async fn list_tags<C: ConnectionTrait>(&self, db: &C, repository_id: Uuid) -> Result<Vec<TagInfo>> {
         let refs = git_ref::Entity::find()
             .filter(git_ref::Column::RepositoryId.eq(repository_id))
             .filter(git_ref::Column::Name.like("refs/tags/%"))
-            .all(self.repository_service.get_db())
+            .all(db)
             .await?;
 
         let mut tags = Vec::new();
         for ref_model in refs {
-            let tag_name = ref_model.name[10..].to_string(); // Remove "refs/tags/"
+            let tag_name = ref_model.name.strip_prefix("refs/tags/").unwrap_or(&ref_model.name).to_string();
 
             tags.push(TagInfo {
                 name: tag_name,
@@ -250,109 +797,3325 @@ impl GitOperations {
         Ok(tags)
     }
 
-    /// Perform a simple merge (fast-forward only for now)
-    pub async fn merge_branch(
+    /// Merge the source branch into the target with the request's strategy.
+    ///
+    /// Merge commits and squashes take a file-level three-way merge of both tips
+    /// against their merge base; a file changed differently on both sides fails
+    /// with `StorageError::MergeConflict`. After criss-cross merges, where the tips
+    /// have several merge bases, those are merged into one first, as git's recursive
+    /// strategy does. A target that already contains the source is left as it is.
+    pub async fn merge_branch<C: ConnectionTrait>(
         &self,
+        db: &C,
         repository_id: Uuid,
         request: MergeRequest,
-    ) -> Result<String> {
+    ) -> Result<MergeResult> {
         let source_ref = format!("refs/heads/{}", request.source_branch);
         let target_ref = format!("refs/heads/{}", request.target_branch);
 
-        // Get current commits
-        let source_commit = self.get_ref(repository_id, &source_ref).await?
-            .ok_or_else(|| anyhow!("Source branch '{}' not found", request.source_branch))?;
+        let source = self.get_ref(db, repository_id, &source_ref).await?
+            .ok_or_else(|| anyhow!("Source branch '{}' not found", request.source_branch))?
+            .target;
+        let target = self.get_ref(db, repository_id, &target_ref).await?
+            .ok_or_else(|| anyhow!("Target branch '{}' not found", request.target_branch))?
+            .target;
 
-        let _target_commit = self.get_ref(repository_id, &target_ref).await?
-            .ok_or_else(|| anyhow!("Target branch '{}' not found", request.target_branch))?;
+        let strategy = request.strategy;
+        if self.get_ancestors(db, repository_id, &target).await?.contains(&source) {
+            return Ok(MergeResult { strategy, commit: target, fast_forward: false });
+        }
+        let fast_forward = self.get_ancestors(db, repository_id, &source).await?.contains(&target);
+        if fast_forward && strategy != MergeStrategy::Squash {
+            self.write_ref(db, repository_id, &target_ref, Some(&target), Some(&source)).await?;
+            return Ok(MergeResult { strategy, commit: source, fast_forward: true });
+        }
+        if strategy == MergeStrategy::FastForwardOnly {
+            return Err(StorageError::NotFastForward { name: request.target_branch }.into());
+        }
+
+        let bases = self.find_merge_bases(db, repository_id, &target, &source).await?;
+        let tree = self.merge_trees(db, repository_id, &bases, &target, &source).await?;
+        let squash = strategy == MergeStrategy::Squash;
+        let message = if !request.message.is_empty() {
+            request.message
+        } else if squash {
+            // The subjects of the squashed commits, oldest first
+            let mut subjects = Vec::new();
+            for id in self.commits_between(db, repository_id, &source, Some(&target)).await? {
+                let commit = self.get_commit_info(db, repository_id, &id).await?;
+                subjects.push(commit.message.lines().next().unwrap_or_default().to_string());
+            }
+            subjects.join("\n")
+        } else {
+            format!("Merge branch '{}' into {}", request.source_branch, request.target_branch)
+        };
+        let parents = if squash { vec![target.clone()] } else { vec![target.clone(), source] };
+        let commit = self
+            .create_commit(
+                db,
+                repository_id,
+                CreateCommitRequest {
+                    tree_hash: tree,
+                    parent_hashes: parents,
+                    author: request.author.clone(),
+                    committer: request.author,
+                    message,
+                    author_date: None,
+                    commit_date: None,
+                },
+            )
+            .await?;
+        self.write_ref(db, repository_id, &target_ref, Some(&target), Some(&commit)).await?;
+
+        Ok(MergeResult { strategy, commit, fast_forward: false })
+    }
+
+    /// The newest of the merge bases of two commits (see `find_merge_bases`),
+    /// `None` for unrelated histories
+    async fn merge_base<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        ours: &str,
+        theirs: &str,
+    ) -> Result<Option<String>> {
+        Ok(self.find_merge_bases(db, repository_id, ours, theirs).await?.into_iter().next())
+    }
+
+    /// Every best common ancestor of two commits: those no other common ancestor
+    /// descends from. Criss-cross merges leave several; they come newest first,
+    /// ties broken by id. Empty for unrelated histories.
+    pub async fn find_merge_bases<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        ours: &str,
+        theirs: &str,
+    ) -> Result<Vec<String>> {
+        self.merge_bases_of(db, (repository_id, &[ours.to_string()]), (repository_id, &[theirs.to_string()]))
+            .await
+    }
+
+    /// Helper: `find_merge_bases` of two sets of tips, each standing in for a
+    /// commit merging them, walked in the repository paired with them. Common
+    /// ancestors are read from `theirs`' repository.
+    async fn merge_bases_of<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        (ours_repository, ours): (Uuid, &[String]),
+        (theirs_repository, theirs): (Uuid, &[String]),
+    ) -> Result<Vec<String>> {
+        let mut ours_ancestors = HashSet::new();
+        for tip in ours {
+            ours_ancestors.extend(self.get_ancestors(db, ours_repository, tip).await?);
+        }
+        let mut common = HashSet::new();
+        for tip in theirs {
+            let ancestors = self.get_ancestors(db, theirs_repository, tip).await?;
+            common.extend(ancestors.into_iter().filter(|id| ours_ancestors.contains(id)));
+        }
+
+        // Common ancestors include all of their own ancestors, so the ones another
+        // descends from are exactly those that are a parent of one
+        let mut bases = Vec::new();
+        let mut redundant = HashSet::new();
+        for id in common {
+            if let Ok(commit) = self.get_commit_info(db, theirs_repository, &id).await {
+                redundant.extend(commit.parents);
+                bases.push((commit.commit_date, id));
+            }
+        }
+        bases.retain(|(_, id)| !redundant.contains(id));
+        bases.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        Ok(bases.into_iter().map(|(_, id)| id).collect())
+    }
 
-        // For now, just do a fast-forward merge (update target to source)
-        // In a full implementation, this would check if fast-forward is possible
-        // and create a merge commit if necessary
-        self.update_ref(repository_id, &target_ref, &source_commit.target).await?;
+    /// Tree id of a file-level three-way merge of two commits against their merge
+    /// `bases`: each path takes whichever side changed it, and paths both sides
+    /// changed differently are conflicts
+    async fn merge_trees<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        bases: &[String],
+        ours: &str,
+        theirs: &str,
+    ) -> Result<String> {
+        let base = self.merge_base_files(db, repository_id, bases.to_vec()).await?;
+        let ours = self.commit_tree_files(db, repository_id, ours).await?;
+        let theirs = self.commit_tree_files(db, repository_id, theirs).await?;
+        let (merged, conflicts) = three_way_files(&base, &ours, &theirs);
+        if !conflicts.is_empty() {
+            return Err(StorageError::MergeConflict { paths: conflicts }.into());
+        }
+
+        let handler = self.object_handler(db, repository_id).await?;
+        Ok(self
+            .write_files_tree(db, &handler, repository_id, merged.into_iter().collect())
+            .await?
+            .unwrap_or_else(|| handler.empty_tree_id()))
+    }
+
+    /// Helper: Files of the base a merge compares both sides against. Several
+    /// merge bases are folded into one virtual base, each pair merged against its
+    /// own merge bases; paths that conflict there keep the older version, so the
+    /// outer merge still reports them.
+    fn merge_base_files<'a, C: ConnectionTrait>(
+        &'a self,
+        db: &'a C,
+        repository_id: Uuid,
+        bases: Vec<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<TreeFiles>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut bases = bases.into_iter();
+            let Some(first) = bases.next() else {
+                return Ok(BTreeMap::new());
+            };
+            let mut files = self.commit_tree_files(db, repository_id, &first).await?;
+            let mut folded = vec![first];
+            for next in bases {
+                let inner = self
+                    .merge_bases_of(db, (repository_id, &folded), (repository_id, std::slice::from_ref(&next)))
+                    .await?;
+                let inner = self.merge_base_files(db, repository_id, inner).await?;
+                let next_files = self.commit_tree_files(db, repository_id, &next).await?;
+                let (mut merged, conflicts) = three_way_files(&inner, &files, &next_files);
+                for path in conflicts {
+                    if let Some(entry) = inner.get(&path) {
+                        merged.insert(path, entry.clone());
+                    }
+                }
+                files = merged;
+                folded.push(next);
+            }
+            Ok(files)
+        })
+    }
+
+    /// Helper: `tree_files` of a commit's tree, submodules included
+    async fn commit_tree_files<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        commit: &str,
+    ) -> Result<TreeFiles> {
+        let tree = self.get_commit_info(db, repository_id, commit).await?.tree;
+        self.tree_files(db, repository_id, &tree, true).await
+    }
+
+    /// The README at the root of `commit`'s tree, `None` when there is none. Of
+    /// several, the first of `README_NAMES` wins. A commit that isn't stored is
+    /// `StorageError::RefNotFound`.
+    pub async fn readme<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        commit: &str,
+    ) -> Result<Option<Readme>> {
+        let Ok(commit) = self.get_commit_info(db, repository_id, commit).await else {
+            return Err(StorageError::RefNotFound { name: commit.to_string() }.into());
+        };
+        let handler = self.object_handler(db, repository_id).await?;
+        if is_empty_tree(&handler, &commit.tree) {
+            return Ok(None);
+        }
+        let content = self
+            .get_object_content(db, repository_id, &commit.tree, ObjectKind::Tree)
+            .await?;
+        let tree = handler.parse_tree(&content)?;
+        let files: Vec<_> = tree
+            .entries
+            .into_iter()
+            .filter(|entry| !matches!(entry.mode.trim_start_matches('0'), "40000" | "160000"))
+            .collect();
+        let Some(entry) = README_NAMES
+            .iter()
+            .find_map(|name| files.iter().find(|entry| entry.name.eq_ignore_ascii_case(name)))
+        else {
+            return Ok(None);
+        };
+
+        let content = self
+            .get_object_content(db, repository_id, &entry.hash, ObjectKind::Blob)
+            .await?;
+        let format = if entry.name.to_ascii_lowercase().ends_with(".md") {
+            ReadmeFormat::Markdown
+        } else {
+            ReadmeFormat::Plain
+        };
+        Ok(Some(Readme {
+            name: entry.name.clone(),
+            id: entry.hash.clone(),
+            format,
+            content: String::from_utf8_lossy(&content).into_owned(),
+        }))
+    }
+
+    /// Id and kind of the entry at `path` in `commit`'s tree, as `git rev-parse
+    /// <commit>:<path>` prints it, without reading the entry itself. An empty path
+    /// is the root tree. A commit that isn't stored is `StorageError::RefNotFound`
+    /// and a path its tree doesn't have `StorageError::PathNotFound`.
+    pub async fn object_sha_at_path<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        commit: &str,
+        path: &str,
+    ) -> Result<(String, TreeEntryKind)> {
+        let Ok(commit) = self.get_commit_info(db, repository_id, commit).await else {
+            return Err(StorageError::RefNotFound { name: commit.to_string() }.into());
+        };
+        let not_found = || StorageError::PathNotFound { path: path.to_string() };
+        let handler = self.object_handler(db, repository_id).await?;
 
-        Ok(source_commit.target)
+        let mut entry = (commit.tree, TreeEntryKind::Tree);
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let (tree_id, TreeEntryKind::Tree) = &entry else {
+                return Err(not_found().into());
+            };
+            if is_empty_tree(&handler, tree_id) {
+                return Err(not_found().into());
+            }
+            let content = self
+                .get_object_content(db, repository_id, tree_id, ObjectKind::Tree)
+                .await?;
+            let found = handler
+                .parse_tree(&content)?
+                .entries
+                .into_iter()
+                .find(|e| e.name == name)
+                .ok_or_else(not_found)?;
+            entry = (found.hash, TreeEntryKind::from_mode(&found.mode));
+        }
+        Ok(entry)
     }
 
-    /// Get commit history for a branch
-    pub async fn get_commit_history(
+    /// Get a page of commit history for a branch, newest first.
+    ///
+    /// The cursor names the tip the walk started from, so later pages keep
+    /// following the same history even if the branch moves in between.
+    pub async fn get_commit_history<C: ConnectionTrait>(
         &self,
+        db: &C,
         repository_id: Uuid,
         branch_name: String,
-        _limit: Option<usize>,
-    ) -> Result<Vec<Commit>> {
-        let ref_name = format!("refs/heads/{}", branch_name);
-        let branch_ref = self.get_ref(repository_id, &ref_name).await?
-            .ok_or_else(|| anyhow!("Branch '{}' not found", branch_name))?;
+        options: &HistoryOptions,
+    ) -> Result<CommitHistory> {
+        let limit = options.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, MAX_HISTORY_LIMIT);
+
+        let (tip, after) = match &options.cursor {
+            Some(cursor) => {
+                let (tip, after) = cursor
+                    .split_once(':')
+                    .filter(|(tip, after)| is_object_id(tip) && is_object_id(after))
+                    .ok_or_else(|| anyhow!("Invalid history cursor"))?;
+                (tip.to_string(), Some(after.to_string()))
+            }
+            None => {
+                let ref_name = format!("refs/heads/{}", branch_name);
+                let branch_ref = self.get_ref(db, repository_id, &ref_name).await?
+                    .ok_or_else(|| anyhow!("Branch '{}' not found", branch_name))?;
+                (branch_ref.target, None)
+            }
+        };
+
+        // A first-parent walk is a single chain, so it can resume right at the cursor;
+        // otherwise the walk is replayed from the tip and skipped up to the cursor
+        let (start, mut skipping) = match &after {
+            Some(after) if options.first_parent => (after.clone(), true),
+            Some(_) => (tip.clone(), true),
+            None => (tip.clone(), false),
+        };
+
+        // Newest commit date first, ties broken by hash so the order is repeatable
+        let mut seen = HashSet::from([start.clone()]);
+        let mut loaded = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let start_commit = self.get_commit_info(db, repository_id, &start).await?;
+        queue.push((start_commit.commit_date, start.clone()));
+        loaded.insert(start, start_commit);
+
+        let mut commits = Vec::new();
+        let mut has_more = false;
+        while let Some((_, hash)) = queue.pop() {
+            let Some(commit) = loaded.remove(&hash) else {
+                continue;
+            };
+            let parents: &[String] = if options.first_parent {
+                &commit.parents[..commit.parents.len().min(1)]
+            } else {
+                &commit.parents
+            };
+            for parent in parents {
+                if !seen.insert(parent.clone()) {
+                    continue;
+                }
+                self.check_commit_limit(seen.len())?;
+                // Parents missing from storage end the walk along that path
+                if let Ok(parent_commit) = self.get_commit_info(db, repository_id, parent).await {
+                    queue.push((parent_commit.commit_date, parent.clone()));
+                    loaded.insert(parent.clone(), parent_commit);
+                }
+            }
+
+            if skipping {
+                skipping = after.as_deref() != Some(hash.as_str());
+                continue;
+            }
+            if options.merges_only && commit.parents.len() < 2 {
+                continue;
+            }
+            if !options.matches(&commit) {
+                continue;
+            }
+            if commits.len() == limit {
+                has_more = true;
+                break;
+            }
+            let co_authors = commit.co_authors();
+            commits.push(HistoryCommit {
+                hash,
+                commit,
+                co_authors,
+                author_user: None,
+                committer_user: None,
+            });
+        }
 
-        // For now, just return the single commit
-        // In a full implementation, this would traverse the commit history
-        let commit_info = self.get_commit_info(repository_id, &branch_ref.target).await?;
-        Ok(vec![commit_info])
+        let next_cursor = match commits.last() {
+            Some(last) if has_more => Some(format!("{}:{}", tip, last.hash)),
+            _ => None,
+        };
+
+        Ok(CommitHistory {
+            commits,
+            next_cursor,
+            first_parent: options.first_parent,
+            merges_only: options.merges_only,
+            since: options.since,
+            until: options.until,
+            author: options.author.clone(),
+            committer: options.committer.clone(),
+        })
     }
 
-    /// Helper: Store a Git object in the database
-    async fn store_git_object(&self, repository_id: Uuid, obj: GitObject) -> Result<()> {
-        let git_obj = git_object::ActiveModel {
-            id: Set(obj.id),
-            repository_id: Set(repository_id),
-            object_type: Set(match obj.obj_type {
-                ObjectType::Commit => "commit".to_string(),
-                ObjectType::Tree => "tree".to_string(),
-                ObjectType::Blob => "blob".to_string(),
-                ObjectType::Tag => "tag".to_string(),
-            }),
-            size: Set(obj.size as i64),
-            content: Set(Some(obj.content)),
-            blob_path: Set(None),
-            created_at: Set(Utc::now().into()),
-        };
-
-        git_obj.insert(self.repository_service.get_db()).await?;
-        Ok(())
+    /// The commit graph from `start` laid out for drawing. `start` is a full ref
+    /// name, a branch or tag name or a commit id, and `HEAD` when `None`; `None` is
+    /// returned when it names nothing. `refs` decorate the commits they point at.
+    ///
+    /// The newest `limit` commits by committer date are loaded, then ordered so
+    /// every commit comes before its parents, newest first among those that are
+    /// ready. Each commit takes the lane its first child reserved for it, and the
+    /// same input always gives the same layout.
+    pub async fn commit_graph<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        start: Option<&str>,
+        limit: Option<usize>,
+        refs: &[ResolvedRef],
+    ) -> Result<Option<CommitGraph>> {
+        let limit = limit.unwrap_or(DEFAULT_GRAPH_LIMIT).clamp(1, MAX_GRAPH_NODES);
+        let Some(tip) = resolve_revision(start.unwrap_or("HEAD"), refs) else {
+            return Ok(None);
+        };
+        let Ok(tip_commit) = self.get_commit_info(db, repository_id, &tip).await else {
+            return Ok(None);
+        };
+
+        // Newest first, as in history listings
+        let mut seen = HashSet::from([tip.clone()]);
+        let mut loaded = HashMap::from([(tip.clone(), tip_commit)]);
+        let mut queue = BinaryHeap::from([(loaded[&tip].commit_date, tip)]);
+        let mut included = HashMap::new();
+        while included.len() < limit {
+            let Some((_, hash)) = queue.pop() else {
+                break;
+            };
+            let commit = loaded.remove(&hash).expect("queued commits are loaded");
+            for parent in &commit.parents {
+                if !seen.insert(parent.clone()) {
+                    continue;
+                }
+                self.check_commit_limit(seen.len())?;
+                // Parents missing from storage end the walk along that path
+                if let Ok(parent_commit) = self.get_commit_info(db, repository_id, parent).await {
+                    queue.push((parent_commit.commit_date, parent.clone()));
+                    loaded.insert(parent.clone(), parent_commit);
+                }
+            }
+            included.insert(hash, commit);
+        }
+        let truncated = !queue.is_empty();
+
+        // Topological order: a commit is ready once all its children are placed
+        let mut children: HashMap<&str, usize> = HashMap::new();
+        for commit in included.values() {
+            for parent in commit.parents.iter().filter(|p| included.contains_key(*p)) {
+                *children.entry(parent.as_str()).or_default() += 1;
+            }
+        }
+        let mut ready: BinaryHeap<_> = included
+            .iter()
+            .filter(|(hash, _)| !children.contains_key(hash.as_str()))
+            .map(|(hash, commit)| (commit.commit_date, hash.as_str()))
+            .collect();
+        let mut order = Vec::with_capacity(included.len());
+        while let Some((_, hash)) = ready.pop() {
+            order.push(hash);
+            for parent in &included[hash].parents {
+                if let Some(remaining) = children.get_mut(parent.as_str()) {
+                    *remaining -= 1;
+                    if *remaining == 0 {
+                        ready.push((included[parent].commit_date, parent.as_str()));
+                    }
+                }
+            }
+        }
+
+        let mut decorations: HashMap<&str, Vec<String>> = HashMap::new();
+        for r in refs {
+            decorations.entry(r.target.as_str()).or_default().push(r.name.clone());
+        }
+
+        // Each lane holds the commit it is waiting for
+        let mut lanes: Vec<Option<&str>> = Vec::new();
+        let mut width = 0;
+        let mut commits = Vec::with_capacity(order.len());
+        for hash in order {
+            let commit = &included[hash];
+            let lane = match lanes.iter().position(|waiting| *waiting == Some(hash)) {
+                Some(lane) => lane,
+                None => free_lane(&mut lanes),
+            };
+            // Other children's lanes end here
+            for waiting in lanes.iter_mut().filter(|waiting| **waiting == Some(hash)) {
+                *waiting = None;
+            }
+            let mut parents = commit.parents.iter().map(String::as_str);
+            if let Some(first) = parents.next() {
+                if !lanes.contains(&Some(first)) {
+                    lanes[lane] = Some(first);
+                }
+            }
+            for parent in parents {
+                if !lanes.contains(&Some(parent)) {
+                    let slot = free_lane(&mut lanes);
+                    lanes[slot] = Some(parent);
+                }
+            }
+            width = width.max(lanes.len()).max(lane + 1);
+            while lanes.last() == Some(&None) {
+                lanes.pop();
+            }
+
+            let mut refs = decorations.remove(hash).unwrap_or_default();
+            refs.sort();
+            commits.push(GraphCommit {
+                hash: hash.to_string(),
+                subject: commit.message.lines().next().unwrap_or_default().to_string(),
+                parents: commit.parents.clone(),
+                refs,
+                lane,
+            });
+        }
+
+        Ok(Some(CommitGraph {
+            commits,
+            lanes: width,
+            truncated,
+        }))
     }
 
-    /// Helper: Get a reference by name
-    async fn get_ref(&self, repository_id: Uuid, ref_name: &str) -> Result<Option<git_ref::Model>> {
-        let git_ref = git_ref::Entity::find()
+    /// Every object reachable from the repository's refs: commits and their
+    /// parents, trees, blobs and annotated tag targets. Objects missing from
+    /// storage end the walk along that path, and submodule entries are skipped
+    /// since their commits live in another repository.
+    pub async fn reachable_objects<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+    ) -> Result<HashSet<String>> {
+        let refs = git_ref::Entity::find()
             .filter(git_ref::Column::RepositoryId.eq(repository_id))
-            .filter(git_ref::Column::Name.eq(ref_name))
-            .one(self.repository_service.get_db())
+            .filter(git_ref::Column::IsSymbolic.eq(false))
+            .all(db)
             .await?;
 
-        Ok(git_ref)
+        self.objects_reachable_from(db, repository_id, refs.into_iter().map(|r| r.target).collect())
+            .await
     }
 
-    /// Helper: Update a reference
-    async fn update_ref(&self, repository_id: Uuid, ref_name: &str, new_hash: &str) -> Result<()> {
-        let git_ref = git_ref::Entity::find()
-            .filter(git_ref::Column::RepositoryId.eq(repository_id))
-            .filter(git_ref::Column::Name.eq(ref_name))
-            .one(self.repository_service.get_db())
-            .await?
-            .ok_or_else(|| anyhow!("Reference '{}' not found", ref_name))?;
+    /// Every object reachable from `starts`, walked as in `reachable_objects`
+    async fn objects_reachable_from<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        starts: Vec<String>,
+    ) -> Result<HashSet<String>> {
+        let mut reachable = HashSet::new();
+        let mut collect = |object_id: &str, _: ObjectKind, _: usize| {
+            reachable.insert(object_id.to_string());
+            WalkStep::Descend
+        };
+        self.walk_objects(db, repository_id, &starts, &mut collect).await?;
+        Ok(reachable)
+    }
+
+    /// Walk everything reachable from `roots` (commits and their parents, trees,
+    /// blobs and annotated tag targets), showing `visitor` each object once at
+    /// the smallest depth it is reached at. Pruning an object skips whatever only
+    /// it leads to. Objects missing from storage end the walk along that path
+    /// unvisited; blobs are visited as their trees name them, without being looked
+    /// up, and submodule entries are skipped since their commits live in another
+    /// repository.
+    pub async fn walk_objects<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        roots: &[String],
+        visitor: &mut dyn ObjectVisitor,
+    ) -> Result<()> {
+        let handler = self.object_handler(db, repository_id).await?;
+        let mut seen = HashSet::new();
+        // (object, whether a tree named it as a blob, depth). What an object holds
+        // goes to the front and parents to the back, so depths come out smallest first.
+        let mut pending: VecDeque<(String, bool, usize)> =
+            roots.iter().map(|root| (root.clone(), false, 0)).collect();
+
+        while let Some((object_id, is_blob, depth)) = pending.pop_front() {
+            if !seen.insert(object_id.clone()) {
+                continue;
+            }
+            if is_blob {
+                visitor.visit(&object_id, ObjectKind::Blob, depth);
+                continue;
+            }
 
-        let mut active_ref: git_ref::ActiveModel = git_ref.into();
-        active_ref.target = Set(new_hash.to_string());
-        active_ref.updated_at = Set(Utc::now().into());
+            let Some(git_obj) = git_object::Entity::find()
+                .filter(git_object::Column::RepositoryId.eq(repository_id))
+                .filter(git_object::Column::Id.eq(object_id.as_str()))
+                .one(db)
+                .await?
+            else {
+                continue;
+            };
+            let kind = git_obj.object_type;
+            if visitor.visit(&object_id, kind, depth) == WalkStep::Prune || kind == ObjectKind::Blob {
+                continue;
+            }
+
+            let content = self.repository_service.load_object_content(git_obj)?.content;
+            match kind {
+                ObjectKind::Commit => {
+                    let commit = self.object_handler.parse_commit(&content)?;
+                    pending.push_front((commit.tree, false, depth));
+                    pending.extend(commit.parents.into_iter().map(|parent| (parent, false, depth + 1)));
+                }
+                ObjectKind::Tree => {
+                    for entry in handler.parse_tree(&content)?.entries {
+                        match entry.mode.trim_start_matches('0') {
+                            "160000" => {}
+                            "40000" => pending.push_front((entry.hash, false, depth)),
+                            _ => pending.push_front((entry.hash, true, depth)),
+                        }
+                    }
+                }
+                ObjectKind::Tag => {
+                    pending.push_front((self.object_handler.parse_tag(&content)?.object, false, depth));
+                }
+                ObjectKind::Blob => {}
+            }
+        }
 
-        active_ref.update(self.repository_service.get_db()).await?;
         Ok(())
     }
 
-    /// Helper: Get commit information
-    async fn get_commit_info(&self, repository_id: Uuid, commit_hash: &str) -> Result<Commit> {
-        let git_obj = git_object::Entity::find()
-            .filter(git_object::Column::RepositoryId.eq(repository_id))
-            .filter(git_object::Column::Id.eq(commit_hash))
-            .filter(git_object::Column::ObjectType.eq("commit"))
-            .one(self.repository_service.get_db())
-            .await?
-            .ok_or_else(|| anyhow!("Commit '{}' not found", commit_hash))?;
+    /// Objects a fetch of `wants` has to send to a client that already has `haves`:
+    /// everything reachable from the wants minus everything reachable from the haves,
+    /// ordered by id, cut down as `options` says. Haves the repository doesn't know
+    /// are ignored.
+    pub async fn objects_for_fetch<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        wants: &[String],
+        haves: &[String],
+        options: &FetchOptions,
+    ) -> Result<Vec<GitObject>> {
+        let client_shallow: HashSet<&str> = options.client_shallow.iter().map(String::as_str).collect();
+        // A deepened fetch may go past the client's shallow commits; any other
+        // fetch stops at them
+        let mut needed = HashSet::new();
+        let mut collect = |object_id: &str, kind: ObjectKind, depth: usize| {
+            if kind == ObjectKind::Commit {
+                let stop = match options.depth {
+                    Some(max) => depth >= max,
+                    None => client_shallow.contains(object_id),
+                };
+                if stop {
+                    return WalkStep::Prune;
+                }
+            }
+            needed.insert(object_id.to_string());
+            WalkStep::Descend
+        };
+        self.walk_objects(db, repository_id, wants, &mut collect).await?;
 
-        match &git_obj.content {
-            Some(content) => self.object_handler.parse_commit(content),
-            None => Err(anyhow!("Commit content is empty")),
+        if !haves.is_empty() {
+            let mut common = HashSet::new();
+            let mut collect = |object_id: &str, kind: ObjectKind, _: usize| {
+                common.insert(object_id.to_string());
+                if kind == ObjectKind::Commit && client_shallow.contains(object_id) {
+                    WalkStep::Prune
+                } else {
+                    WalkStep::Descend
+                }
+            };
+            self.walk_objects(db, repository_id, haves, &mut collect).await?;
+            needed.retain(|id| !common.contains(id));
+        }
+
+        let mut ids: Vec<String> = needed.into_iter().collect();
+        ids.sort();
+        let mut objects = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(git_obj) = git_object::Entity::find_by_id((repository_id, id)).one(db).await? {
+                let filtered = options.blob_limit.is_some_and(|limit| {
+                    git_obj.object_type == ObjectKind::Blob && git_obj.size as u64 >= limit
+                });
+                if filtered && !wants.contains(&git_obj.id) {
+                    continue;
+                }
+                objects.push(self.repository_service.load_object_content(git_obj)?.into_git_object()?);
+            }
         }
+        Ok(objects)
     }
-}
\ No newline at end of file
+
+    /// The shallow boundary of a fetch of `wants` to `depth`: commits sent without
+    /// a parent, and the client's shallow commits that are now sent with theirs.
+    /// Only commits are walked, so this is cheap next to building the pack.
+    pub async fn shallow_update<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        wants: &[String],
+        depth: usize,
+        client_shallow: &[String],
+    ) -> Result<ShallowUpdate> {
+        let mut sent = HashSet::new();
+        let mut edge = Vec::new();
+        let mut collect = |object_id: &str, kind: ObjectKind, commit_depth: usize| match kind {
+            ObjectKind::Commit if commit_depth >= depth => WalkStep::Prune,
+            ObjectKind::Commit => {
+                sent.insert(object_id.to_string());
+                if commit_depth + 1 == depth {
+                    edge.push(object_id.to_string());
+                }
+                WalkStep::Descend
+            }
+            ObjectKind::Tag => WalkStep::Descend,
+            ObjectKind::Tree | ObjectKind::Blob => WalkStep::Prune,
+        };
+        self.walk_objects(db, repository_id, wants, &mut collect).await?;
+
+        // Commits nearer the wants have every parent within the depth
+        let mut boundary = HashSet::new();
+        for id in edge {
+            let commit = self.get_commit_info(db, repository_id, &id).await?;
+            if commit.parents.iter().any(|parent| !sent.contains(parent)) {
+                boundary.insert(id);
+            }
+        }
+
+        let mut update = ShallowUpdate {
+            shallow: boundary.iter().filter(|id| !client_shallow.contains(id)).cloned().collect(),
+            unshallow: client_shallow
+                .iter()
+                .filter(|id| sent.contains(*id) && !boundary.contains(*id))
+                .cloned()
+                .collect(),
+        };
+        update.shallow.sort();
+        update.unshallow.sort();
+        Ok(update)
+    }
+
+    /// Commits reachable from `after` but not from `before`: what a ref update
+    /// from `before` to `after` adds, parents before children. Commits missing
+    /// from storage end the walk along that path.
+    pub async fn commits_between<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        after: &str,
+        before: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let known = match before {
+            Some(before) => self.commit_ancestry(db, repository_id, before, &HashSet::new()).await?,
+            None => Vec::new(),
+        };
+        let known: HashSet<String> = known.into_iter().collect();
+        self.commit_ancestry(db, repository_id, after, &known).await
+    }
+
+    /// Fail with `StorageError::NonLinearHistory` naming the first merge commit an
+    /// update from `before` to `after` adds, for branches that keep a linear history
+    pub async fn check_linear_history<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        after: &str,
+        before: Option<&str>,
+    ) -> Result<()> {
+        for id in self.commits_between(db, repository_id, after, before).await? {
+            let commit = self.get_commit_info(db, repository_id, &id).await?;
+            if commit.parents.len() > 1 {
+                return Err(StorageError::NonLinearHistory { commit: id }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that merging `request` keeps its target branch linear: the target must
+    /// fast-forward to the source (`StorageError::NotFastForward`) without taking in
+    /// merge commits (`StorageError::NonLinearHistory`)
+    pub async fn check_linear_merge<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        request: &MergeRequest,
+    ) -> Result<()> {
+        let source_ref = format!("refs/heads/{}", request.source_branch);
+        let target_ref = format!("refs/heads/{}", request.target_branch);
+        let source = self.get_ref(db, repository_id, &source_ref).await?
+            .ok_or_else(|| anyhow!("Source branch '{}' not found", request.source_branch))?;
+        let target = self.get_ref(db, repository_id, &target_ref).await?
+            .ok_or_else(|| anyhow!("Target branch '{}' not found", request.target_branch))?;
+
+        let ancestry = self.commit_ancestry(db, repository_id, &source.target, &HashSet::new()).await?;
+        if !ancestry.contains(&target.target) {
+            return Err(StorageError::NotFastForward { name: request.target_branch.clone() }.into());
+        }
+        self.check_linear_history(db, repository_id, &source.target, Some(&target.target)).await
+    }
+
+    /// `start` and its ancestors, not walking into `stop`, each commit after its
+    /// parents (first parent's history first)
+    async fn commit_ancestry<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        start: &str,
+        stop: &HashSet<String>,
+    ) -> Result<Vec<String>> {
+        let mut seen = HashSet::new();
+        let mut commits = Vec::new();
+        // (commit, whether its parents have been queued already)
+        let mut pending = vec![(start.to_string(), false)];
+        while let Some((id, expanded)) = pending.pop() {
+            if expanded {
+                commits.push(id);
+                continue;
+            }
+            if stop.contains(&id) || !seen.insert(id.clone()) {
+                continue;
+            }
+            let Ok(commit) = self.get_commit_info(db, repository_id, &id).await else {
+                continue;
+            };
+            pending.push((id, true));
+            pending.extend(commit.parents.into_iter().rev().map(|parent| (parent, false)));
+        }
+        Ok(commits)
+    }
+
+    /// Objects stored in the repository that no ref reaches, as (id, type, size)
+    /// ordered by id. This is what a garbage collection would delete.
+    pub async fn list_unreachable_objects<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+    ) -> Result<Vec<(String, ObjectKind, i64)>> {
+        let reachable = self.reachable_objects(db, repository_id).await?;
+
+        let objects: Vec<(String, ObjectKind, i64)> = git_object::Entity::find()
+            .select_only()
+            .columns([git_object::Column::Id, git_object::Column::ObjectType, git_object::Column::Size])
+            .filter(git_object::Column::RepositoryId.eq(repository_id))
+            .order_by_asc(git_object::Column::Id)
+            .into_tuple()
+            .all(db)
+            .await?;
+
+        Ok(objects
+            .into_iter()
+            .filter(|(id, _, _)| !reachable.contains(id))
+            .collect())
+    }
+
+    /// Look up one commit, `None` if the repository has no such commit. Trailers
+    /// are parsed from the stored message, which is returned unchanged.
+    pub async fn get_commit<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        commit_hash: &str,
+    ) -> Result<Option<CommitDetail>> {
+        let git_obj = git_object::Entity::find()
+            .filter(git_object::Column::RepositoryId.eq(repository_id))
+            .filter(git_object::Column::Id.eq(commit_hash))
+            .filter(git_object::Column::ObjectType.eq(ObjectKind::Commit))
+            .one(db)
+            .await?;
+        let Some(git_obj) = git_obj else {
+            return Ok(None);
+        };
+
+        let content = self.repository_service.load_object_content(git_obj)?.content;
+        let commit = self.object_handler.parse_commit(&content)?;
+        Ok(Some(CommitDetail {
+            hash: commit_hash.to_string(),
+            trailers: commit.trailers(),
+            co_authors: commit.co_authors(),
+            commit,
+            author_user: None,
+            committer_user: None,
+        }))
+    }
+
+    /// Compute per-file changes from one commit's tree to another's
+    pub async fn diff_commits<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        from_commit: &str,
+        to_commit: &str,
+        options: &DiffOptions,
+    ) -> Result<Vec<FileDiff>> {
+        let from = self.get_commit_info(db, repository_id, from_commit).await?;
+        let to = self.get_commit_info(db, repository_id, to_commit).await?;
+
+        self.diff_trees(db, repository_id, &from.tree, &to.tree, options).await
+    }
+
+    /// Compute per-file changes between two trees, sorted by (new) path
+    pub async fn diff_trees<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        old_tree: &str,
+        new_tree: &str,
+        options: &DiffOptions,
+    ) -> Result<Vec<FileDiff>> {
+        let old_files = self.flatten_tree(db, repository_id, old_tree).await?;
+        let new_files = self.flatten_tree(db, repository_id, new_tree).await?;
+
+        let mut modified = Vec::new();
+        let mut deleted = Vec::new();
+        let mut added = Vec::new();
+        for (path, entry) in &old_files {
+            match new_files.get(path) {
+                Some(new_entry) if new_entry == entry => {}
+                Some(_) => modified.push(path),
+                None => deleted.push(path),
+            }
+        }
+        added.extend(new_files.keys().filter(|path| !old_files.contains_key(*path)));
+
+        // (old path, new path, similarity)
+        let mut renames: Vec<(&String, &String, u8)> = Vec::new();
+        if options.detect_renames {
+            // Exact renames: same blob on both sides
+            for old_path in &deleted {
+                let old_id = &old_files[*old_path].1;
+                let target = added.iter().find(|new_path| {
+                    new_files[**new_path].1 == *old_id && !renames.iter().any(|(_, to, _)| to == *new_path)
+                });
+                if let Some(new_path) = target {
+                    renames.push((old_path, new_path, 100));
+                }
+            }
+
+            let remaining_deleted: Vec<&String> = deleted
+                .iter()
+                .copied()
+                .filter(|path| !renames.iter().any(|(from, _, _)| from == path))
+                .collect();
+            let remaining_added: Vec<&String> = added
+                .iter()
+                .copied()
+                .filter(|path| !renames.iter().any(|(_, to, _)| to == path))
+                .collect();
+
+            if options.rename_threshold < 100
+                && remaining_deleted.len() * remaining_added.len() <= MAX_RENAME_CANDIDATES
+            {
+                let mut contents = HashMap::new();
+                for path in remaining_added.iter() {
+                    let id = &new_files[*path].1;
+                    contents.insert(id.clone(), self.get_object_content(db, repository_id, id, ObjectKind::Blob).await?);
+                }
+                for path in remaining_deleted.iter() {
+                    let id = &old_files[*path].1;
+                    contents.insert(id.clone(), self.get_object_content(db, repository_id, id, ObjectKind::Blob).await?);
+                }
+
+                // Files too large to diff only pair up as exact renames
+                let too_large = |content: &Vec<u8>| self.limits.max_diff_bytes.is_some_and(|max| content.len() > max);
+                let mut candidates = Vec::new();
+                for old_path in &remaining_deleted {
+                    for new_path in &remaining_added {
+                        let old_content = &contents[&old_files[*old_path].1];
+                        let new_content = &contents[&new_files[*new_path].1];
+                        if too_large(old_content) || too_large(new_content) {
+                            continue;
+                        }
+                        let score = diff::similarity(old_content, new_content);
+                        if score >= options.rename_threshold {
+                            candidates.push((*old_path, *new_path, score));
+                        }
+                    }
+                }
+
+                // Best matches first; each path is paired at most once
+                candidates.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(b.0)).then_with(|| a.1.cmp(b.1)));
+                for (old_path, new_path, score) in candidates {
+                    if !renames.iter().any(|(from, to, _)| *from == old_path || *to == new_path) {
+                        renames.push((old_path, new_path, score));
+                    }
+                }
+            }
+
+            deleted.retain(|path| !renames.iter().any(|(from, _, _)| from == path));
+            added.retain(|path| !renames.iter().any(|(_, to, _)| to == path));
+        }
+
+        let mut diffs = Vec::new();
+        let changes = modified
+            .iter()
+            .map(|path| (Some(*path), Some(*path), None))
+            .chain(deleted.iter().map(|path| (Some(*path), None, None)))
+            .chain(added.iter().map(|path| (None, Some(*path), None)))
+            .chain(renames.iter().map(|(from, to, score)| (Some(*from), Some(*to), Some(*score))));
+
+        for (old_path, new_path, similarity) in changes {
+            let old = old_path.map(|path| &old_files[path]);
+            let new = new_path.map(|path| &new_files[path]);
+
+            let old_content = match old {
+                Some((_, id)) => self.get_object_content(db, repository_id, id, ObjectKind::Blob).await?,
+                None => Vec::new(),
+            };
+            let new_content = match new {
+                Some((_, id)) => self.get_object_content(db, repository_id, id, ObjectKind::Blob).await?,
+                None => Vec::new(),
+            };
+            let (old_content, new_content) = if options.normalize_eol
+                && !diff::is_binary(&old_content)
+                && !diff::is_binary(&new_content)
+            {
+                (text::normalize_eol(&old_content).into_owned(), text::normalize_eol(&new_content).into_owned())
+            } else {
+                (old_content, new_content)
+            };
+
+            let path = new_path.or(old_path).cloned().unwrap_or_default();
+            let mut file_diff = diff::diff_file(
+                path,
+                old.map(|(mode, id)| (id.as_str(), mode.as_str(), old_content.as_slice())),
+                new.map(|(mode, id)| (id.as_str(), mode.as_str(), new_content.as_slice())),
+                DEFAULT_CONTEXT_LINES,
+                self.limits.max_diff_bytes,
+            );
+            if let (Some(from), Some(to), Some(similarity)) = (old_path, new_path, similarity) {
+                file_diff.change = ChangeKind::Renamed {
+                    from: from.clone(),
+                    to: to.clone(),
+                    similarity,
+                };
+            }
+            diffs.push(file_diff);
+        }
+
+        diffs.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(diffs)
+    }
+
+    /// Render computed diffs in the requested format, patches capped at
+    /// `max_diff_total_bytes`
+    pub fn render_diff(&self, diffs: &[FileDiff], format: DiffFormat) -> String {
+        match format {
+            DiffFormat::Patch => diff::render_patch_within(diffs, self.limits.max_diff_total_bytes),
+            _ => diff::render(diffs, format),
+        }
+    }
+
+    /// Per-file changes a commit made against its first parent or, for a root
+    /// commit, the empty tree, with renames detected as git does by default.
+    /// `None` if there is no such commit.
+    pub async fn commit_diff<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        commit_hash: &str,
+    ) -> Result<Option<(CommitDetail, Vec<FileDiff>)>> {
+        let Some(detail) = self.get_commit(db, repository_id, commit_hash).await? else {
+            return Ok(None);
+        };
+        let base_tree = match detail.commit.parents.first() {
+            Some(parent) => self.get_commit_info(db, repository_id, parent).await?.tree,
+            None => EMPTY_TREE_ID.to_string(),
+        };
+        let options = DiffOptions {
+            detect_renames: true,
+            ..DiffOptions::default()
+        };
+        let diffs = self
+            .diff_trees(db, repository_id, &base_tree, &detail.commit.tree, &options)
+            .await?;
+        Ok(Some((detail, diffs)))
+    }
+
+    /// Changes on `head` since it forked from `base`, as `git diff base...head`:
+    /// from their merge base to `head`. `None` when the histories are unrelated.
+    pub async fn compare<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        base: &str,
+        head: &str,
+        options: &DiffOptions,
+    ) -> Result<Option<Vec<FileDiff>>> {
+        let Some(merge_base) = self.merge_base(db, repository_id, base, head).await? else {
+            return Ok(None);
+        };
+        self.diff_commits(db, repository_id, &merge_base, head, options)
+            .await
+            .map(Some)
+    }
+
+    /// Changes on `head` in `head_repository` since it forked from `base` in
+    /// `base_repository`, as `compare` within one repository: typically a fork's
+    /// branch against its upstream. Both are commit ids. Forks copy their source's
+    /// objects, so the merge base, an ancestor of `head`, is read from the head
+    /// repository. `None` when the histories are unrelated.
+    pub async fn diff_across_repos<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        base_repository: Uuid,
+        base: &str,
+        head_repository: Uuid,
+        head: &str,
+        options: &DiffOptions,
+    ) -> Result<Option<Vec<FileDiff>>> {
+        let bases = self
+            .merge_bases_of(db, (base_repository, &[base.to_string()]), (head_repository, &[head.to_string()]))
+            .await?;
+        let Some(merge_base) = bases.into_iter().next() else {
+            return Ok(None);
+        };
+        self.diff_commits(db, head_repository, &merge_base, head, options)
+            .await
+            .map(Some)
+    }
+
+    /// Everything `branch` changed since `since_commit`, which must be an ancestor
+    /// of its tip (`StorageError::NotAncestor` otherwise): the cumulative diff from
+    /// that commit to the tip, with renames detected
+    pub async fn diff_since<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        branch: &str,
+        since_commit: &str,
+    ) -> Result<Vec<FileDiff>> {
+        let ref_name = format!("refs/heads/{}", branch);
+        let tip = self
+            .get_ref(db, repository_id, &ref_name)
+            .await?
+            .ok_or(StorageError::RefNotFound { name: ref_name })?;
+
+        let ancestry = self.commit_ancestry(db, repository_id, &tip.target, &HashSet::new()).await?;
+        if !ancestry.iter().any(|id| id == since_commit) {
+            return Err(StorageError::NotAncestor {
+                commit: since_commit.to_string(),
+                branch: branch.to_string(),
+            }
+            .into());
+        }
+
+        let options = DiffOptions {
+            detect_renames: true,
+            ..DiffOptions::default()
+        };
+        self.diff_commits(db, repository_id, since_commit, &tip.target, &options).await
+    }
+
+    /// A commit as `git format-patch` output, diffed against its first parent or,
+    /// for a root commit, the empty tree. `None` if there is no such commit.
+    pub async fn format_patch<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        commit_hash: &str,
+        version: &str,
+    ) -> Result<Option<String>> {
+        let Some((detail, diffs)) = self.commit_diff(db, repository_id, commit_hash).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(diff::render_format_patch(
+            &detail.hash,
+            &detail.commit,
+            &diffs,
+            version,
+            self.limits.max_diff_total_bytes,
+        )))
+    }
+
+    /// Helper: Object handler naming objects in the repository's object format.
+    /// Commits and tags read the same in either format, so only work touching
+    /// object ids or trees needs it over `object_handler`.
+    async fn object_handler<C: ConnectionTrait>(&self, db: &C, repository_id: Uuid) -> Result<ObjectHandler> {
+        let hash = self.repository_service.object_format_in(db, repository_id).await?;
+        Ok(ObjectHandler::new().with_hash_algorithm(hash))
+    }
+
+    /// Helper: Store a Git object in the database
+    async fn store_git_object<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        obj: GitObject,
+    ) -> Result<()> {
+        // Insert through the repository service so blobs land in blob storage
+        self.repository_service.insert_object(db, repository_id, obj).await?;
+        Ok(())
+    }
+
+    /// Helper: Apply path changes to a tree, storing the blobs and trees that change.
+    /// Returns `None` when nothing is left in the tree.
+    fn write_tree<'a, C: ConnectionTrait>(
+        &'a self,
+        db: &'a C,
+        handler: &'a ObjectHandler,
+        repository_id: Uuid,
+        tree_id: Option<String>,
+        changes: Vec<(Vec<String>, FileChange)>,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut entries: BTreeMap<String, TreeEntry> = BTreeMap::new();
+            if let Some(tree_id) = tree_id.filter(|id| !is_empty_tree(handler, id)) {
+                let content = self.get_object_content(db, repository_id, &tree_id, ObjectKind::Tree).await?;
+                for entry in handler.parse_tree(&content)?.entries {
+                    entries.insert(entry.name.clone(), entry);
+                }
+            }
+
+            let mut subdirs: BTreeMap<String, Vec<(Vec<String>, FileChange)>> = BTreeMap::new();
+            for (mut components, change) in changes {
+                let name = components.remove(0);
+                if !components.is_empty() {
+                    subdirs.entry(name).or_default().push((components, change));
+                    continue;
+                }
+
+                let existing_mode = entries.get(&name).map(|e| e.mode.trim_start_matches('0').to_string());
+                match change {
+                    FileChange::Write(content) => {
+                        let mode = match existing_mode.as_deref() {
+                            None => "100644".to_string(),
+                            Some(mode @ ("100644" | "100755")) => mode.to_string(),
+                            Some(_) => return Err(anyhow!("'{}' is not a regular file", name)),
+                        };
+                        let blob = handler.create_blob(&content)?;
+                        let hash = blob.id.clone();
+                        self.store_if_missing(db, repository_id, blob).await?;
+                        entries.insert(name.clone(), TreeEntry { mode, name, hash });
+                    }
+                    FileChange::Delete => {
+                        if entries.remove(&name).is_none() {
+                            return Err(anyhow!("'{}' does not exist", name));
+                        }
+                    }
+                }
+            }
+
+            for (name, changes) in subdirs {
+                let subtree = match entries.get(&name) {
+                    Some(entry) if entry.mode.trim_start_matches('0') == "40000" => Some(entry.hash.clone()),
+                    Some(_) => return Err(anyhow!("'{}' is not a directory", name)),
+                    None => None,
+                };
+                match self.write_tree(db, handler, repository_id, subtree, changes).await? {
+                    Some(hash) => {
+                        entries.insert(name.clone(), TreeEntry { mode: "40000".to_string(), name, hash });
+                    }
+                    None => {
+                        entries.remove(&name);
+                    }
+                }
+            }
+
+            self.store_tree(db, handler, repository_id, entries.into_values().collect()).await
+        })
+    }
+
+    /// Helper: Store the trees holding `files`, paths mapped to (mode, id) as
+    /// `tree_files` gives them. Returns `None` when there are no files.
+    fn write_files_tree<'a, C: ConnectionTrait>(
+        &'a self,
+        db: &'a C,
+        handler: &'a ObjectHandler,
+        repository_id: Uuid,
+        files: Vec<(String, (String, String))>,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut entries = Vec::new();
+            let mut subdirs: BTreeMap<String, Vec<_>> = BTreeMap::new();
+            for (path, (mode, hash)) in files {
+                match path.split_once('/') {
+                    Some((dir, rest)) => subdirs.entry(dir.to_string()).or_default().push((rest.to_string(), (mode, hash))),
+                    None => entries.push(TreeEntry { mode, name: path, hash }),
+                }
+            }
+            for (name, files) in subdirs {
+                if entries.iter().any(|e| e.name == name) {
+                    return Err(StorageError::MergeConflict { paths: vec![name] }.into());
+                }
+                if let Some(hash) = self.write_files_tree(db, handler, repository_id, files).await? {
+                    entries.push(TreeEntry { mode: "40000".to_string(), name, hash });
+                }
+            }
+            self.store_tree(db, handler, repository_id, entries).await
+        })
+    }
+
+    /// Helper: Store a tree of `entries` in git's order, `None` when there are none
+    async fn store_tree<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        handler: &ObjectHandler,
+        repository_id: Uuid,
+        mut entries: Vec<TreeEntry>,
+    ) -> Result<Option<String>> {
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        // Git orders directories as if their names ended in '/'
+        entries.sort_by_cached_key(|e| {
+            let mut key = e.name.clone().into_bytes();
+            if e.mode.trim_start_matches('0') == "40000" {
+                key.push(b'/');
+            }
+            key
+        });
+        let tree = handler.create_tree(&Tree { entries })?;
+        let hash = tree.id.clone();
+        self.store_if_missing(db, repository_id, tree).await?;
+        Ok(Some(hash))
+    }
+
+    /// Helper: Store an object unless the repository already has it
+    async fn store_if_missing<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        obj: GitObject,
+    ) -> Result<()> {
+        let exists = git_object::Entity::find()
+            .filter(git_object::Column::RepositoryId.eq(repository_id))
+            .filter(git_object::Column::Id.eq(obj.id.as_str()))
+            .count(db)
+            .await?
+            > 0;
+        if !exists {
+            self.store_git_object(db, repository_id, obj).await?;
+        }
+        Ok(())
+    }
+
+    /// Helper: Get the repository row
+    async fn get_repository<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+    ) -> Result<repository::Model> {
+        repository::Entity::find_by_id(repository_id)
+            .one(db)
+            .await?
+            .ok_or_else(|| anyhow!("Repository not found"))
+    }
+
+    /// Helper: Get a reference by name
+    async fn get_ref<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        ref_name: &str,
+    ) -> Result<Option<git_ref::Model>> {
+        let git_ref = git_ref::Entity::find()
+            .filter(git_ref::Column::RepositoryId.eq(repository_id))
+            .filter(git_ref::Column::Name.eq(ref_name))
+            .one(db)
+            .await?;
+
+        Ok(git_ref)
+    }
+
+    /// Helper: Point `tip` at `new_hash` if it still has the target it was read
+    /// with, whether or not the move is a fast-forward
+    async fn swap_ref<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        tip: &git_ref::Model,
+        new_hash: &str,
+    ) -> Result<()> {
+        self.write_ref(db, repository_id, &tip.name, Some(&tip.target), Some(new_hash))
+            .await
+    }
+
+    /// Helper: Move `tip` to `new_hash` as `swap_ref` does, or create `ref_name`
+    /// there when it has no tip yet
+    async fn advance_ref<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        ref_name: &str,
+        tip: Option<git_ref::Model>,
+        new_hash: &str,
+    ) -> Result<()> {
+        let old_target = tip.map(|tip| tip.target);
+        self.write_ref(db, repository_id, ref_name, old_target.as_deref(), Some(new_hash))
+            .await
+    }
+
+    /// Helper: Path and blob id of the note for `commit` in a notes tree
+    async fn find_note<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        tree_id: &str,
+        commit: &str,
+    ) -> Result<Option<(String, String)>> {
+        let files = self.flatten_tree(db, repository_id, tree_id).await?;
+        Ok(files
+            .into_iter()
+            .find(|(path, _)| path.replace('/', "") == commit)
+            .map(|(path, (_, id))| (path, id)))
+    }
+
+    /// Helper: Move `ref_name` from `old_target` to `new_target` (`None` for absent)
+    /// with the compare-and-swap every ref write goes through
+    async fn write_ref<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        ref_name: &str,
+        old_target: Option<&str>,
+        new_target: Option<&str>,
+    ) -> Result<()> {
+        let update = RefUpdate {
+            name: ref_name.to_string(),
+            old_target: old_target.map(str::to_string),
+            new_target: new_target.map(str::to_string),
+        };
+        self.repository_service.apply_ref_update(db, repository_id, &update).await
+    }
+
+    /// Helper: Every commit reachable from `start`, including itself.
+    /// Commits missing from storage end the walk along that path.
+    async fn get_ancestors<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        start: &str,
+    ) -> Result<HashSet<String>> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![start.to_string()];
+
+        while let Some(commit_hash) = pending.pop() {
+            if !seen.insert(commit_hash.clone()) {
+                continue;
+            }
+            self.check_commit_limit(seen.len())?;
+
+            match self.get_commit_info(db, repository_id, &commit_hash).await {
+                Ok(commit) => pending.extend(commit.parents),
+                Err(_) => continue,
+            }
+        }
+
+        Ok(seen)
+    }
+
+    /// Helper: Map every file path under a tree to its (mode, blob id).
+    /// Submodule entries are skipped since their content lives elsewhere.
+    async fn flatten_tree<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        tree_id: &str,
+    ) -> Result<BTreeMap<String, (String, String)>> {
+        self.tree_files(db, repository_id, tree_id, false).await
+    }
+
+    /// Helper: Map every path under a tree that isn't a directory to its (mode, id),
+    /// submodules included when asked for
+    async fn tree_files<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        tree_id: &str,
+        submodules: bool,
+    ) -> Result<BTreeMap<String, (String, String)>> {
+        let handler = self.object_handler(db, repository_id).await?;
+        let mut files = BTreeMap::new();
+        let mut pending = vec![(String::new(), tree_id.to_string())];
+        let mut visited = 0;
+
+        while let Some((prefix, tree_id)) = pending.pop() {
+            if is_empty_tree(&handler, &tree_id) {
+                continue;
+            }
+
+            let content = self
+                .get_object_content(db, repository_id, &tree_id, ObjectKind::Tree)
+                .await?;
+            let tree = handler.parse_tree(&content)?;
+            visited += tree.entries.len();
+            if let Some(max) = self.limits.max_tree_entries.filter(|max| visited > *max) {
+                return Err(StorageError::TreeEntryLimitExceeded { max }.into());
+            }
+
+            for entry in tree.entries {
+                let path = format!("{}{}", prefix, entry.name);
+                match entry.mode.trim_start_matches('0') {
+                    "40000" => pending.push((format!("{}/", path), entry.hash)),
+                    "160000" if !submodules => {}
+                    _ => {
+                        files.insert(path, (entry.mode, entry.hash));
+                    }
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Helper: Load an object's content, checking its type
+    async fn get_object_content<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        object_id: &str,
+        object_type: ObjectKind,
+    ) -> Result<Vec<u8>> {
+        let git_obj = git_object::Entity::find()
+            .filter(git_object::Column::RepositoryId.eq(repository_id))
+            .filter(git_object::Column::Id.eq(object_id))
+            .filter(git_object::Column::ObjectType.eq(object_type))
+            .one(db)
+            .await?
+            .ok_or_else(|| anyhow!("{} '{}' not found", object_type, object_id))?;
+
+        Ok(self.repository_service.load_object_content(git_obj)?.content)
+    }
+
+    /// Helper: Get commit information
+    async fn get_commit_info<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        commit_hash: &str,
+    ) -> Result<Commit> {
+        let git_obj = git_object::Entity::find()
+            .filter(git_object::Column::RepositoryId.eq(repository_id))
+            .filter(git_object::Column::Id.eq(commit_hash))
+            .filter(git_object::Column::ObjectType.eq(ObjectKind::Commit))
+            .one(db)
+            .await?
+            .ok_or_else(|| anyhow!("Commit '{}' not found", commit_hash))?;
+
+        match &git_obj.content {
+            Some(content) => self.object_handler.parse_commit(content),
+            None => Err(anyhow!("Commit content is empty")),
+        }
+    }
+}
+/// Match a `Name <email> <seconds> <tz>` signature against a filter: a filter
+/// containing `@` must equal the email, anything else is a substring of the name
+/// or email. Both ignore ASCII case.
+fn identity_matches(signature: &str, filter: &str) -> bool {
+    let identity = signature.split_once('>').map_or(signature, |(identity, _)| identity);
+    let (name, email) = identity.split_once('<').unwrap_or((identity, ""));
+    let filter = filter.trim().to_ascii_lowercase();
+    if filter.contains('@') {
+        email.trim().eq_ignore_ascii_case(filter.trim_start_matches('<').trim_end_matches('>'))
+    } else {
+        name.to_ascii_lowercase().contains(&filter) || email.to_ascii_lowercase().contains(&filter)
+    }
+}
+
+/// `signature` with its date replaced by `date`, keeping its timezone, or with
+/// `date` in UTC appended when it has none
+fn stamp_signature(signature: &str, date: DateTime<Utc>) -> String {
+    let (identity, timezone) = match parse_signature_date(signature) {
+        Some(_) => {
+            let mut parts = signature.rsplitn(3, ' ');
+            let timezone = parts.next().unwrap_or("+0000");
+            let _seconds = parts.next();
+            (parts.next().unwrap_or_default(), timezone)
+        }
+        None => (signature.trim_end(), "+0000"),
+    };
+    format!("{} {} {}", identity, date.timestamp(), timezone)
+}
+
+/// Paths under a tree mapped to their (mode, id), as `tree_files` gives them
+type TreeFiles = BTreeMap<String, (String, String)>;
+
+/// Three-way merge of file maps: each path takes whichever side changed it from
+/// `base`. Paths both sides changed differently are left out and returned as
+/// conflicts.
+fn three_way_files(base: &TreeFiles, ours: &TreeFiles, theirs: &TreeFiles) -> (TreeFiles, Vec<String>) {
+    let paths: BTreeSet<&String> = ours.keys().chain(theirs.keys()).collect();
+    let mut merged = BTreeMap::new();
+    let mut conflicts = Vec::new();
+    for path in paths {
+        let (before, ours, theirs) = (base.get(path), ours.get(path), theirs.get(path));
+        let side = if ours == theirs || theirs == before {
+            ours
+        } else if ours == before {
+            theirs
+        } else {
+            conflicts.push(path.clone());
+            continue;
+        };
+        if let Some(entry) = side {
+            merged.insert(path.clone(), entry.clone());
+        }
+    }
+    (merged, conflicts)
+}
+
+/// Whether `id` is the empty tree, which is never stored: SHA-1's stands for "no
+/// tree" in every repository, and the handler's own in its format
+fn is_empty_tree(handler: &ObjectHandler, id: &str) -> bool {
+    id == EMPTY_TREE_ID || id == handler.empty_tree_id()
+}
+
+/// The commit `revision` names: a full ref name, a branch or tag name, or a
+/// commit id. `None` when no ref in `refs` matches and it isn't an id.
+pub fn resolve_revision(revision: &str, refs: &[ResolvedRef]) -> Option<String> {
+    let named = [revision.to_string(), format!("refs/heads/{}", revision), format!("refs/tags/{}", revision)];
+    match named.iter().find_map(|name| refs.iter().find(|r| &r.name == name)) {
+        Some(r) => Some(r.target.clone()),
+        None if is_object_id(revision) => Some(revision.to_string()),
+        None => None,
+    }
+}
+
+/// Index of the leftmost free lane, adding one on the right when all are taken
+fn free_lane(lanes: &mut Vec<Option<&str>>) -> usize {
+    match lanes.iter().position(Option::is_none) {
+        Some(lane) => lane,
+        None => {
+            lanes.push(None);
+            lanes.len() - 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::repository::Visibility;
+    use crate::{init_db, run_migrations, RefUpdate, StorageError, UserService};
+
+    async fn setup() -> (RepositoryService, Uuid, String, String) {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let blob_storage_path = std::env::temp_dir().join(format!("git-storage-test-{}", Uuid::new_v4()));
+        let service = RepositoryService::new(db.clone(), Some(blob_storage_path));
+        let owner = UserService::new(db)
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = service
+            .create_repository("test-repo".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let git_ops = GitOperations::new(service.clone());
+        let mut commits = Vec::new();
+        for message in ["first\n", "second\n"] {
+            let request = CreateCommitRequest {
+                tree_hash: "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+                parent_hashes: commits.clone(),
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: message.to_string(),
+                author_date: None,
+                commit_date: None,
+            };
+            commits.push(git_ops.create_commit(service.get_db(), repo.id, request).await.unwrap());
+        }
+
+        let guard = service.lock_writes(repo.id).await.unwrap();
+        service
+            .update_refs(
+                &guard,
+                vec![
+                    RefUpdate {
+                        name: "refs/heads/main".to_string(),
+                        old_target: None,
+                        new_target: Some(commits[0].clone()),
+                    },
+                    RefUpdate {
+                        name: "refs/heads/feature".to_string(),
+                        old_target: None,
+                        new_target: Some(commits[1].clone()),
+                    },
+                ],
+            )
+            .await
+            .unwrap();
+
+        (service, repo.id, commits[0].clone(), commits[1].clone())
+    }
+
+    #[tokio::test]
+    async fn test_racing_pushes_and_branch_deletes_stay_consistent() {
+        let (service, repo_id, main_commit, feature_commit) = setup().await;
+        let name = "refs/heads/feature";
+        let branch_row = || async {
+            crate::entities::branch::Entity::find()
+                .filter(crate::entities::branch::Column::RepositoryId.eq(repo_id))
+                .filter(crate::entities::branch::Column::Name.eq("feature"))
+                .one(service.get_db())
+                .await
+                .unwrap()
+                .map(|row| row.commit_id)
+        };
+        assert_eq!(branch_row().await, Some(feature_commit.clone()));
+
+        // A push that read the tip before a delete removed it is refused, not resurrected
+        let stale = service.get_ref(repo_id, name).await.unwrap().unwrap().target;
+        GitOperations::new(service.clone())
+            .delete_branch(service.get_db(), repo_id, "feature".to_string())
+            .await
+            .unwrap();
+        let guard = service.lock_writes(repo_id).await.unwrap();
+        let err = service
+            .update_refs(
+                &guard,
+                vec![RefUpdate {
+                    name: name.to_string(),
+                    old_target: Some(stale),
+                    new_target: Some(main_commit.clone()),
+                }],
+            )
+            .await
+            .unwrap_err();
+        drop(guard);
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::StaleRef { .. })));
+        assert!(service.get_ref(repo_id, name).await.unwrap().is_none());
+        assert_eq!(branch_row().await, None);
+
+        let tips = [main_commit, feature_commit];
+        for round in 0..20 {
+            let old_target = service.get_ref(repo_id, name).await.unwrap().map(|r| r.target);
+            let push = async {
+                let guard = service.lock_writes(repo_id).await?;
+                let update = RefUpdate {
+                    name: name.to_string(),
+                    old_target: old_target.clone(),
+                    new_target: Some(tips[round % 2].clone()),
+                };
+                service.update_refs(&guard, vec![update]).await
+            };
+            let git_ops = GitOperations::new(service.clone());
+            let delete = service.transaction(move |txn| {
+                Box::pin(async move { git_ops.delete_branch(txn, repo_id, "feature".to_string()).await })
+            });
+            let (pushed, deleted) = tokio::join!(push, delete);
+
+            // Whichever loses gets a conflict it can retry
+            for err in pushed.err().into_iter().chain(deleted.err()) {
+                assert!(
+                    matches!(
+                        err.downcast_ref::<StorageError>(),
+                        Some(StorageError::StaleRef { .. } | StorageError::ResourceBusy)
+                    ),
+                    "round {}: {}",
+                    round,
+                    err
+                );
+            }
+            let target = service.get_ref(repo_id, name).await.unwrap().map(|r| r.target);
+            assert_eq!(target, branch_row().await, "round {}", round);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_merge_transaction_persists_nothing() {
+        let (service, repo_id, main_commit, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+
+        let result: Result<String> = service
+            .transaction(move |txn| {
+                Box::pin(async move {
+                    git_ops
+                        .merge_branch(
+                            txn,
+                            repo_id,
+                            MergeRequest {
+                                source_branch: "feature".to_string(),
+                                target_branch: "main".to_string(),
+                                author: "A U Thor <author@example.com>".to_string(),
+                                message: "Merge feature".to_string(),
+                                strategy: MergeStrategy::Merge,
+                            },
+                        )
+                        .await?;
+                    Err(anyhow!("forced failure after the ref update"))
+                })
+            })
+            .await;
+        assert!(result.is_err());
+
+        let main = service.get_ref(repo_id, "refs/heads/main").await.unwrap().unwrap();
+        assert_eq!(main.target, main_commit);
+    }
+
+    #[tokio::test]
+    async fn test_failed_merge_rolls_back_new_objects_and_blob_files() {
+        let (service, repo_id, main_commit, feature_commit) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let content = b"written during the merge\n".to_vec();
+        let blob_id = ObjectHandler::new().create_blob(&content).unwrap().id;
+
+        let merge_commit = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let created = merge_commit.clone();
+        let result: Result<MergeResult> = service
+            .transaction(move |txn| {
+                Box::pin(async move {
+                    let commit = git_ops
+                        .create_commit(
+                            txn,
+                            repo_id,
+                            CreateCommitRequest {
+                                tree_hash: EMPTY_TREE_ID.to_string(),
+                                parent_hashes: vec![main_commit, feature_commit],
+                                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                                message: "Merge feature\n".to_string(),
+                                author_date: None,
+                                commit_date: None,
+                            },
+                        )
+                        .await?;
+                    *created.lock().unwrap() = Some(commit);
+                    git_ops
+                        .commit_files(
+                            txn,
+                            repo_id,
+                            "feature",
+                            vec![("notes.txt".to_string(), FileChange::Write(content))],
+                            "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                            "Resolve\n".to_string(),
+                        )
+                        .await?;
+                    git_ops
+                        .merge_branch(
+                            txn,
+                            repo_id,
+                            MergeRequest {
+                                source_branch: "feature".to_string(),
+                                target_branch: "missing".to_string(),
+                                author: "A U Thor <author@example.com>".to_string(),
+                                message: "Merge feature".to_string(),
+                                strategy: MergeStrategy::Merge,
+                            },
+                        )
+                        .await
+                })
+            })
+            .await;
+        assert!(result.is_err());
+
+        let merge_commit = merge_commit.lock().unwrap().clone().unwrap();
+        assert!(!service.object_exists(repo_id, &merge_commit).await.unwrap());
+        assert!(!service.object_exists(repo_id, &blob_id).await.unwrap());
+        let feature = service.get_ref(repo_id, "refs/heads/feature").await.unwrap().unwrap();
+        assert_ne!(feature.target, merge_commit);
+
+        // The blob was staged and never moved into the shared store
+        let blob_dir = service.blob_storage_path().join(&blob_id[..2]);
+        assert_eq!(fs_entries(&blob_dir), 0);
+    }
+
+    fn fs_entries(dir: &std::path::Path) -> usize {
+        std::fs::read_dir(dir).map(|entries| entries.count()).unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_create_branch_rolls_back_when_commit_is_missing() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+
+        // The ref is inserted before the start commit is looked up
+        let result = service
+            .transaction(move |txn| {
+                Box::pin(async move {
+                    git_ops
+                        .create_branch(txn, repo_id, "broken".to_string(), "0".repeat(40))
+                        .await
+                })
+            })
+            .await;
+        assert!(result.is_err());
+        assert!(service.get_ref(repo_id, "refs/heads/broken").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_case_only_branch_names() {
+        let (service, repo_id, c1, _) = setup().await;
+
+        // Git's default: `Feature` and `feature` are different branches
+        let git_ops = GitOperations::new(service.clone());
+        git_ops
+            .create_branch(service.get_db(), repo_id, "Feature".to_string(), c1.clone())
+            .await
+            .unwrap();
+        assert!(service.get_ref(repo_id, "refs/heads/Feature").await.unwrap().is_some());
+
+        let service = service.with_case_insensitive_refs(true);
+        let git_ops = GitOperations::new(service.clone());
+        let err = git_ops
+            .create_branch(service.get_db(), repo_id, "FEATURE".to_string(), c1.clone())
+            .await
+            .unwrap_err();
+        match err.downcast_ref::<StorageError>() {
+            Some(StorageError::RefNameConflict { name, existing }) => {
+                assert_eq!(name, "refs/heads/FEATURE");
+                assert!(existing.eq_ignore_ascii_case("refs/heads/feature"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(service.get_ref(repo_id, "refs/heads/FEATURE").await.unwrap().is_none());
+
+        // Pushes creating a case-only variant are refused too; new names still work
+        let guard = service.lock_writes(repo_id).await.unwrap();
+        let push = |name: &str| RefUpdate {
+            name: name.to_string(),
+            old_target: None,
+            new_target: Some(c1.clone()),
+        };
+        let err = service.update_refs(&guard, vec![push("refs/heads/MAIN")]).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::RefNameConflict { .. })));
+        service.update_refs(&guard, vec![push("refs/heads/other")]).await.unwrap();
+        git_ops
+            .create_lightweight_tag(service.get_db(), repo_id, "v1".to_string(), c1.clone())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_criss_cross_merge_uses_every_merge_base() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+        let author = "A U Thor <author@example.com> 1700000000 +0000".to_string();
+        async fn write(git_ops: &GitOperations, repo_id: Uuid, branch: &str, path: &str, content: &str) -> String {
+            let change = (path.to_string(), FileChange::Write(content.as_bytes().to_vec()));
+            let author = "A U Thor <author@example.com> 1700000000 +0000".to_string();
+            git_ops
+                .commit_files(git_ops.repository_service.get_db(), repo_id, branch, vec![change], author, format!("{}\n", branch))
+                .await
+                .unwrap()
+        }
+        let branch = |name: &str, target: &str| RefUpdate {
+            name: format!("refs/heads/{}", name),
+            old_target: None,
+            new_target: Some(target.to_string()),
+        };
+        let merge = |source: &str, target: &str| MergeRequest {
+            source_branch: source.to_string(),
+            target_branch: target.to_string(),
+            author: author.clone(),
+            message: String::new(),
+            strategy: MergeStrategy::Merge,
+        };
+
+        // base: a = b = "base"; x changes a, y changes b, then each merges the other's
+        // first commit, so x2 and y2 both have x1 and y1 as merge bases
+        write(&git_ops, repo_id, "base", "a.txt", "base").await;
+        let base = write(&git_ops, repo_id, "base", "b.txt", "base").await;
+        let guard = service.lock_writes(repo_id).await.unwrap();
+        service.update_refs(&guard, vec![branch("x", &base), branch("y", &base)]).await.unwrap();
+        drop(guard);
+        let x1 = write(&git_ops, repo_id, "x", "a.txt", "x").await;
+        let y1 = write(&git_ops, repo_id, "y", "b.txt", "y").await;
+        let guard = service.lock_writes(repo_id).await.unwrap();
+        service.update_refs(&guard, vec![branch("x1", &x1), branch("y1", &y1)]).await.unwrap();
+        drop(guard);
+        git_ops.merge_branch(db, repo_id, merge("y1", "x")).await.unwrap();
+        git_ops.merge_branch(db, repo_id, merge("x1", "y")).await.unwrap();
+        let x3 = write(&git_ops, repo_id, "x", "a.txt", "x3").await;
+        let y3 = write(&git_ops, repo_id, "y", "b.txt", "y3").await;
+
+        let mut bases = git_ops.find_merge_bases(db, repo_id, &x3, &y3).await.unwrap();
+        let mut expected = vec![x1.clone(), y1.clone()];
+        assert_eq!(bases, git_ops.find_merge_bases(db, repo_id, &y3, &x3).await.unwrap());
+        bases.sort();
+        expected.sort();
+        assert_eq!(bases, expected);
+        assert_eq!(git_ops.find_merge_bases(db, repo_id, &x1, &y1).await.unwrap(), vec![base.clone()]);
+
+        // Either base alone sees the other side's change as a conflict; merged into
+        // one virtual base (a = "x", b = "y") each side keeps its own change
+        let result = git_ops.merge_branch(db, repo_id, merge("y", "x")).await.unwrap();
+        assert!(!result.fast_forward);
+        let commit = git_ops.get_commit_info(db, repo_id, &result.commit).await.unwrap();
+        assert_eq!(commit.parents, vec![x3, y3]);
+        let files = git_ops.tree_files(db, repo_id, &commit.tree, false).await.unwrap();
+        let blob_id = |content: &str| ObjectHandler::new().create_blob(content.as_bytes()).unwrap().id;
+        assert_eq!(files["a.txt"].1, blob_id("x3"));
+        assert_eq!(files["b.txt"].1, blob_id("y3"));
+    }
+
+    async fn commit_on(
+        git_ops: &GitOperations,
+        service: &RepositoryService,
+        repo_id: Uuid,
+        parent: Option<&str>,
+        message: &str,
+    ) -> String {
+        let parents: Vec<&str> = parent.into_iter().collect();
+        commit_at(git_ops, service, repo_id, &parents, message, 1700000000).await
+    }
+
+    async fn commit_at(
+        git_ops: &GitOperations,
+        service: &RepositoryService,
+        repo_id: Uuid,
+        parents: &[&str],
+        message: &str,
+        time: i64,
+    ) -> String {
+        let signature = format!("A U Thor <author@example.com> {} +0000", time);
+        let request = CreateCommitRequest {
+            tree_hash: EMPTY_TREE_ID.to_string(),
+            parent_hashes: parents.iter().map(|p| p.to_string()).collect(),
+            author: signature.clone(),
+            committer: signature,
+            message: format!("{}\n", message),
+            author_date: None,
+            commit_date: None,
+        };
+        git_ops.create_commit(service.get_db(), repo_id, request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_list_branches_with_ahead_behind() {
+        let (service, repo_id, c1, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+
+        // main: c1 - c2; ahead: c2 - a1 - a2; diverged: c1 - d1 - d2 - d3; orphan: unrelated root
+        let c2 = commit_on(&git_ops, &service, repo_id, Some(&c1), "c2").await;
+        let a1 = commit_on(&git_ops, &service, repo_id, Some(&c2), "a1").await;
+        let a2 = commit_on(&git_ops, &service, repo_id, Some(&a1), "a2").await;
+        let d1 = commit_on(&git_ops, &service, repo_id, Some(&c1), "d1").await;
+        let d2 = commit_on(&git_ops, &service, repo_id, Some(&d1), "d2").await;
+        let d3 = commit_on(&git_ops, &service, repo_id, Some(&d2), "d3").await;
+        let orphan = commit_on(&git_ops, &service, repo_id, None, "orphan").await;
+
+        let guard = service.lock_writes(repo_id).await.unwrap();
+        service
+            .update_refs(
+                &guard,
+                vec![
+                    RefUpdate {
+                        name: "refs/heads/main".to_string(),
+                        old_target: Some(c1.clone()),
+                        new_target: Some(c2),
+                    },
+                    RefUpdate {
+                        name: "refs/heads/ahead".to_string(),
+                        old_target: None,
+                        new_target: Some(a2),
+                    },
+                    RefUpdate {
+                        name: "refs/heads/diverged".to_string(),
+                        old_target: None,
+                        new_target: Some(d3),
+                    },
+                    RefUpdate {
+                        name: "refs/heads/orphan".to_string(),
+                        old_target: None,
+                        new_target: Some(orphan),
+                    },
+                ],
+            )
+            .await
+            .unwrap();
+
+        let branches = git_ops.list_branches(service.get_db(), repo_id, true).await.unwrap();
+        let counts = |name: &str| {
+            let branch = branches.iter().find(|b| b.name == name).unwrap();
+            (branch.ahead, branch.behind)
+        };
+        assert_eq!(counts("main"), (Some(0), Some(0)));
+        assert_eq!(counts("ahead"), (Some(2), Some(0)));
+        assert_eq!(counts("diverged"), (Some(3), Some(1)));
+        assert_eq!(counts("orphan"), (None, None));
+
+        // Without compare no counts are computed
+        let branches = git_ops.list_branches(service.get_db(), repo_id, false).await.unwrap();
+        assert!(branches.iter().all(|b| b.ahead.is_none() && b.behind.is_none()));
+    }
+
+    /// Store `files` as blobs and nested trees, returning a commit of them
+    async fn commit_files(
+        git_ops: &GitOperations,
+        service: &RepositoryService,
+        repo_id: Uuid,
+        files: &[(&str, &str)],
+    ) -> (String, HashMap<String, String>) {
+        use git_protocol::objects::{Tree, TreeEntry};
+
+        let handler = ObjectHandler::new();
+        let mut blob_ids = HashMap::new();
+        let mut dirs: BTreeMap<String, Vec<TreeEntry>> = BTreeMap::new();
+        for (path, content) in files {
+            let blob = handler.create_blob(content.as_bytes()).unwrap();
+            blob_ids.insert(path.to_string(), blob.id.clone());
+            let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+            dirs.entry(dir.to_string()).or_default().push(TreeEntry {
+                mode: "100644".to_string(),
+                name: name.to_string(),
+                hash: blob.id.clone(),
+            });
+            // Unchanged files share their blob with the earlier commit
+            if !service.object_exists(repo_id, &blob.id).await.unwrap() {
+                git_ops.store_git_object(service.get_db(), repo_id, blob).await.unwrap();
+            }
+        }
+
+        // One level of nesting is enough for these tests
+        let mut root = dirs.remove("").unwrap_or_default();
+        for (dir, entries) in dirs {
+            let tree = handler.create_tree(&Tree { entries }).unwrap();
+            root.push(TreeEntry {
+                mode: "40000".to_string(),
+                name: dir,
+                hash: tree.id.clone(),
+            });
+            if !service.object_exists(repo_id, &tree.id).await.unwrap() {
+                git_ops.store_git_object(service.get_db(), repo_id, tree).await.unwrap();
+            }
+        }
+        root.sort_by(|a, b| a.name.cmp(&b.name));
+        let tree = handler.create_tree(&Tree { entries: root }).unwrap();
+        let tree_id = tree.id.clone();
+        git_ops.store_git_object(service.get_db(), repo_id, tree).await.unwrap();
+
+        let request = CreateCommitRequest {
+            tree_hash: tree_id,
+            parent_hashes: Vec::new(),
+            author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+            committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+            message: "files\n".to_string(),
+            author_date: None,
+            commit_date: None,
+        };
+        let commit = git_ops.create_commit(service.get_db(), repo_id, request).await.unwrap();
+        (commit, blob_ids)
+    }
+
+    #[tokio::test]
+    async fn test_diff_formats() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+
+        let (from, old_ids) = commit_files(
+            &git_ops,
+            &service,
+            repo_id,
+            &[
+                ("README.md", "hello\nworld\n"),
+                ("old.txt", "gone\n"),
+                ("src/lib.rs", "fn a() {}\n"),
+            ],
+        )
+        .await;
+        let (to, new_ids) = commit_files(
+            &git_ops,
+            &service,
+            repo_id,
+            &[
+                ("README.md", "hello\nrust\n"),
+                ("src/lib.rs", "fn a() {}\n"),
+                ("src/new.txt", "one\ntwo"),
+            ],
+        )
+        .await;
+
+        let diffs = git_ops
+            .diff_commits(service.get_db(), repo_id, &from, &to, &DiffOptions::default())
+            .await
+            .unwrap();
+        let short = |id: &String| id[..7].to_string();
+
+        // json: the structured list, unchanged files left out
+        let paths: Vec<_> = diffs.iter().map(|d| (d.path.as_str(), d.change.clone())).collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("README.md", ChangeKind::Modified),
+                ("old.txt", ChangeKind::Deleted),
+                ("src/new.txt", ChangeKind::Added),
+            ]
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&git_ops.render_diff(&diffs, DiffFormat::Json)).unwrap();
+        assert_eq!(json[0]["change"], "modified");
+        assert_eq!(json[0]["hunks"][0]["lines"][1]["kind"], "removed");
+        assert_eq!(json[2]["hunks"][0]["lines"][1]["no_newline_at_eof"], true);
+
+        assert_eq!(
+            git_ops.render_diff(&diffs, DiffFormat::NameStatus),
+            "M\tREADME.md\nD\told.txt\nA\tsrc/new.txt\n"
+        );
+
+        assert_eq!(
+            git_ops.render_diff(&diffs, DiffFormat::Stat),
+            concat!(
+                " README.md   | 2 +-\n",
+                " old.txt     | 1 -\n",
+                " src/new.txt | 2 ++\n",
+                " 3 files changed, 3 insertions(+), 2 deletions(-)\n",
+            )
+        );
+
+        let expected_patch = format!(
+            concat!(
+                "diff --git a/README.md b/README.md\n",
+                "index {}..{} 100644\n",
+                "--- a/README.md\n",
+                "+++ b/README.md\n",
+                "@@ -1,2 +1,2 @@\n",
+                " hello\n",
+                "-world\n",
+                "+rust\n",
+                "diff --git a/old.txt b/old.txt\n",
+                "deleted file mode 100644\n",
+                "index {}..0000000\n",
+                "--- a/old.txt\n",
+                "+++ /dev/null\n",
+                "@@ -1 +0,0 @@\n",
+                "-gone\n",
+                "diff --git a/src/new.txt b/src/new.txt\n",
+                "new file mode 100644\n",
+                "index 0000000..{}\n",
+                "--- /dev/null\n",
+                "+++ b/src/new.txt\n",
+                "@@ -0,0 +1,2 @@\n",
+                "+one\n",
+                "+two\n",
+                "\\ No newline at end of file\n",
+            ),
+            short(&old_ids["README.md"]),
+            short(&new_ids["README.md"]),
+            short(&old_ids["old.txt"]),
+            short(&new_ids["src/new.txt"]),
+        );
+        assert_eq!(git_ops.render_diff(&diffs, DiffFormat::Patch), expected_patch);
+    }
+
+    #[tokio::test]
+    async fn test_commit_diff_matches_git_show() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+        let commit = |files: Vec<(&str, FileChange)>| {
+            let files = files.into_iter().map(|(path, change)| (path.to_string(), change)).collect();
+            git_ops.commit_files(db, repo_id, "main", files, "A <a@example.com>".to_string(), "fixture\n".to_string())
+        };
+        let write = |content: &[u8]| FileChange::Write(content.to_vec());
+
+        // The fixture `git show --format=` printed testdata/commit.diff for
+        let readme: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+        commit(vec![
+            ("README.md", write(readme.as_bytes())),
+            ("src/lib.rs", write(b"pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\npub fn sub(a: i32, b: i32) -> i32 {\n    a - b\n}\n")),
+            ("old.txt", write(b"going away\n")),
+            ("docs/guide.md", write(b"A guide\nwith a few lines\nthat moves\n")),
+            ("logo.png", write(b"\x89PNG\r\n\x1a\n\0\0\0\x01")),
+            ("notes.txt", write(b"last line")),
+        ])
+        .await
+        .unwrap();
+        let readme = readme.replace("line 2\n", "line two\n").replace("line 18\n", "line eighteen\n") + "line 21\n";
+        let head = commit(vec![
+            ("README.md", write(readme.as_bytes())),
+            ("src/lib.rs", write(b"pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\npub fn mul(a: i32, b: i32) -> i32 {\n    a * b\n}\n\npub fn sub(a: i32, b: i32) -> i32 {\n    a - b\n}\n")),
+            ("old.txt", FileChange::Delete),
+            ("docs/guide.md", FileChange::Delete),
+            ("guide.md", write(b"A guide\nwith a few lines\nthat moves\n")),
+            ("logo.png", write(b"\x89PNG\r\n\x1a\n\0\0\0\x02")),
+            ("notes.txt", write(b"last line\nand another")),
+            ("new.txt", write(b"one\ntwo")),
+        ])
+        .await
+        .unwrap();
+
+        let expected = include_str!("testdata/commit.diff");
+        let (_, diffs) = git_ops.commit_diff(db, repo_id, &head).await.unwrap().unwrap();
+        assert_eq!(git_ops.render_diff(&diffs, DiffFormat::Patch), expected);
+        let patch = git_ops.format_patch(db, repo_id, &head, "test").await.unwrap().unwrap();
+        assert!(patch.contains(expected), "{}", patch);
+
+        // The cap drops whole files from the end and says how many
+        let capped = GitOperations::new(service.clone()).with_limits(ReadLimits {
+            max_diff_total_bytes: Some(expected.find("diff --git a/new.txt").unwrap()),
+            ..ReadLimits::default()
+        });
+        let truncated = capped.render_diff(&diffs, DiffFormat::Patch);
+        assert!(truncated.starts_with(&expected[..expected.find("diff --git a/new.txt").unwrap()]));
+        assert!(truncated.ends_with("# diff truncated: 4 of 7 files not shown, the diff is over 481 bytes\n"), "{}", truncated);
+    }
+
+    #[tokio::test]
+    async fn test_diff_summarizes_binary_and_oversized_files() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone()).with_limits(ReadLimits {
+            max_diff_bytes: Some(64),
+            ..ReadLimits::default()
+        });
+
+        let long = "a long line of text\n".repeat(10);
+        let (from, old_ids) = commit_files(
+            &git_ops,
+            &service,
+            repo_id,
+            &[("data.bin", "\0\x01\x02"), ("notes.txt", "short\n"), ("small.txt", "one\n")],
+        )
+        .await;
+        let (to, new_ids) = commit_files(
+            &git_ops,
+            &service,
+            repo_id,
+            &[("data.bin", "\0\x01\x02\x03"), ("notes.txt", &long), ("small.txt", "two\n")],
+        )
+        .await;
+
+        let diffs = git_ops
+            .diff_commits(service.get_db(), repo_id, &from, &to, &DiffOptions::default())
+            .await
+            .unwrap();
+        let summaries: Vec<_> = diffs
+            .iter()
+            .map(|d| (d.path.as_str(), d.binary, d.too_large, d.hunks.len(), d.old_size, d.new_size))
+            .collect();
+        assert_eq!(
+            summaries,
+            vec![
+                ("data.bin", true, false, 0, Some(3), Some(4)),
+                ("notes.txt", false, true, 0, Some(6), Some(200)),
+                ("small.txt", false, false, 1, Some(4), Some(4)),
+            ]
+        );
+        assert_eq!(diffs[1].old_id.as_ref(), Some(&old_ids["notes.txt"]));
+        assert_eq!(diffs[1].new_id.as_ref(), Some(&new_ids["notes.txt"]));
+    }
+
+    #[tokio::test]
+    async fn test_diff_detects_renames() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let body = "one\ntwo\nthree\nfour\nfive\n";
+        let edited = "one\ntwo\nthree\nfour\nFIVE\n";
+
+        let (from, _) = commit_files(
+            &git_ops,
+            &service,
+            repo_id,
+            &[("a.txt", body), ("notes/draft.md", "first\nsecond\nthird\n")],
+        )
+        .await;
+        let (to, _) = commit_files(
+            &git_ops,
+            &service,
+            repo_id,
+            &[("b.txt", body), ("docs/final.md", "first\nsecond\nthird\nfourth\n")],
+        )
+        .await;
+        let (edit, _) = commit_files(&git_ops, &service, repo_id, &[("c.txt", edited)]).await;
+
+        // Without detection a rename is a delete plus an add
+        let diffs = git_ops
+            .diff_commits(service.get_db(), repo_id, &from, &to, &DiffOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            git_ops.render_diff(&diffs, DiffFormat::NameStatus),
+            "D\ta.txt\nA\tb.txt\nA\tdocs/final.md\nD\tnotes/draft.md\n"
+        );
+
+        let options = DiffOptions {
+            detect_renames: true,
+            ..Default::default()
+        };
+        let diffs = git_ops
+            .diff_commits(service.get_db(), repo_id, &from, &to, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            diffs[0].change,
+            ChangeKind::Renamed {
+                from: "a.txt".to_string(),
+                to: "b.txt".to_string(),
+                similarity: 100,
+            }
+        );
+        assert!(diffs[0].hunks.is_empty());
+        assert_eq!(
+            git_ops.render_diff(&diffs, DiffFormat::NameStatus),
+            "R100\ta.txt\tb.txt\nR075\tnotes/draft.md\tdocs/final.md\n"
+        );
+        assert!(git_ops.render_diff(&diffs, DiffFormat::Patch).starts_with(concat!(
+            "diff --git a/a.txt b/b.txt\n",
+            "similarity index 100%\n",
+            "rename from a.txt\n",
+            "rename to b.txt\n",
+            "diff --git a/notes/draft.md b/docs/final.md\n",
+            "similarity index 75%\n",
+            "rename from notes/draft.md\n",
+            "rename to docs/final.md\n",
+        )));
+
+        // An edited rename needs to clear the threshold
+        let (renamed, _) = commit_files(&git_ops, &service, repo_id, &[("a.txt", body)]).await;
+        let diffs = git_ops
+            .diff_commits(service.get_db(), repo_id, &renamed, &edit, &options)
+            .await
+            .unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].change.status(), "R080");
+        assert_eq!((diffs[0].additions, diffs[0].deletions), (1, 1));
+
+        let strict = DiffOptions {
+            detect_renames: true,
+            rename_threshold: 90,
+            ..Default::default()
+        };
+        let diffs = git_ops
+            .diff_commits(service.get_db(), repo_id, &renamed, &edit, &strict)
+            .await
+            .unwrap();
+        assert_eq!(git_ops.render_diff(&diffs, DiffFormat::NameStatus), "D\ta.txt\nA\tc.txt\n");
+    }
+
+    async fn move_branch(service: &RepositoryService, repo_id: Uuid, name: &str, from: &str, to: &str) {
+        let guard = service.lock_writes(repo_id).await.unwrap();
+        let update = RefUpdate {
+            name: name.to_string(),
+            old_target: Some(from.to_string()),
+            new_target: Some(to.to_string()),
+        };
+        service.update_refs(&guard, vec![update]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_first_parent_and_merges_only() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+
+        // root - m1 - m2 - merge - top, with feature f1 - f2 merged in from root
+        let root = commit_at(&git_ops, &service, repo_id, &[], "root", 100).await;
+        let m1 = commit_at(&git_ops, &service, repo_id, &[&root], "m1", 200).await;
+        let f1 = commit_at(&git_ops, &service, repo_id, &[&root], "f1", 150).await;
+        let f2 = commit_at(&git_ops, &service, repo_id, &[&f1], "f2", 250).await;
+        let m2 = commit_at(&git_ops, &service, repo_id, &[&m1], "m2", 300).await;
+        let merge = commit_at(&git_ops, &service, repo_id, &[&m2, &f2], "merge", 400).await;
+        let top = commit_at(&git_ops, &service, repo_id, &[&merge], "top", 500).await;
+
+        let guard = service.lock_writes(repo_id).await.unwrap();
+        service
+            .update_refs(
+                &guard,
+                vec![RefUpdate {
+                    name: "refs/heads/history".to_string(),
+                    old_target: None,
+                    new_target: Some(top.clone()),
+                }],
+            )
+            .await
+            .unwrap();
+        drop(guard);
+
+        let history = |options: HistoryOptions| {
+            let git_ops = &git_ops;
+            let service = &service;
+            async move {
+                git_ops
+                    .get_commit_history(service.get_db(), repo_id, "history".to_string(), &options)
+                    .await
+                    .unwrap()
+            }
+        };
+        let hashes = |page: &CommitHistory| page.commits.iter().map(|c| c.hash.clone()).collect::<Vec<_>>();
+
+        let all = history(HistoryOptions::default()).await;
+        assert_eq!(
+            hashes(&all),
+            vec![top.clone(), merge.clone(), m2.clone(), f2.clone(), m1.clone(), f1.clone(), root.clone()]
+        );
+
+        let first_parent = history(HistoryOptions {
+            first_parent: true,
+            ..Default::default()
+        })
+        .await;
+        assert!(first_parent.first_parent && !first_parent.merges_only);
+        assert_eq!(
+            hashes(&first_parent),
+            vec![top.clone(), merge.clone(), m2.clone(), m1.clone(), root.clone()]
+        );
+
+        for first_parent in [false, true] {
+            let merges = history(HistoryOptions {
+                merges_only: true,
+                first_parent,
+                ..Default::default()
+            })
+            .await;
+            assert!(merges.merges_only);
+            assert_eq!(hashes(&merges), vec![merge.clone()]);
+        }
+
+        // Paging in either mode reproduces the full listing, even after the branch moves
+        for (first_parent, expected) in [(false, hashes(&all)), (true, hashes(&first_parent))] {
+            let mut seen = Vec::new();
+            let mut cursor = None;
+            let mut moved_to = None;
+            loop {
+                let page = history(HistoryOptions {
+                    limit: Some(2),
+                    cursor: cursor.clone(),
+                    first_parent,
+                    ..Default::default()
+                })
+                .await;
+                seen.extend(hashes(&page));
+                if moved_to.is_none() {
+                    let message = format!("newer {}", first_parent);
+                    let newer = commit_at(&git_ops, &service, repo_id, &[&top], &message, 600).await;
+                    move_branch(&service, repo_id, "refs/heads/history", &top, &newer).await;
+                    moved_to = Some(newer);
+                }
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+            move_branch(&service, repo_id, "refs/heads/history", &moved_to.unwrap(), &top).await;
+            assert_eq!(seen, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_commit_graph_lanes() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+
+        // root - m1 - m2 - merge - top, with side branch f1 - f2 merged in from root
+        let root = commit_at(&git_ops, &service, repo_id, &[], "root", 100).await;
+        let m1 = commit_at(&git_ops, &service, repo_id, &[&root], "m1", 200).await;
+        let f1 = commit_at(&git_ops, &service, repo_id, &[&root], "f1", 150).await;
+        let f2 = commit_at(&git_ops, &service, repo_id, &[&f1], "f2", 250).await;
+        let m2 = commit_at(&git_ops, &service, repo_id, &[&m1], "m2", 300).await;
+        let merge = commit_at(&git_ops, &service, repo_id, &[&m2, &f2], "merge", 400).await;
+        let top = commit_at(&git_ops, &service, repo_id, &[&merge], "top", 500).await;
+
+        let guard = service.lock_writes(repo_id).await.unwrap();
+        service
+            .update_refs(
+                &guard,
+                vec![
+                    RefUpdate {
+                        name: "refs/heads/graph".to_string(),
+                        old_target: None,
+                        new_target: Some(top.clone()),
+                    },
+                    RefUpdate {
+                        name: "refs/heads/side".to_string(),
+                        old_target: None,
+                        new_target: Some(f2.clone()),
+                    },
+                    RefUpdate {
+                        name: "refs/tags/v1".to_string(),
+                        old_target: None,
+                        new_target: Some(f2.clone()),
+                    },
+                ],
+            )
+            .await
+            .unwrap();
+        drop(guard);
+        let refs = service.get_resolved_refs(repo_id).await.unwrap();
+
+        let graph = |start: &'static str, limit: Option<usize>| {
+            let (git_ops, service, refs) = (&git_ops, &service, &refs);
+            async move {
+                git_ops
+                    .commit_graph(service.get_db(), repo_id, Some(start), limit, refs)
+                    .await
+                    .unwrap()
+            }
+        };
+        let lanes = |graph: &CommitGraph| {
+            graph
+                .commits
+                .iter()
+                .map(|c| (c.subject.clone(), c.lane))
+                .collect::<Vec<_>>()
+        };
+        let expected = |rows: &[(&str, usize)]| rows.iter().map(|(s, l)| (s.to_string(), *l)).collect::<Vec<_>>();
+
+        let full = graph("graph", None).await.unwrap();
+        assert_eq!(
+            lanes(&full),
+            expected(&[("top", 0), ("merge", 0), ("m2", 0), ("f2", 1), ("m1", 0), ("f1", 1), ("root", 0)])
+        );
+        assert_eq!(full.lanes, 2);
+        assert!(!full.truncated);
+        assert_eq!(full.commits[0].refs, vec!["refs/heads/graph"]);
+        assert_eq!(full.commits[1].parents, vec![m2.clone(), f2.clone()]);
+        assert_eq!(full.commits[4].hash, m1);
+        assert_eq!(full.commits[3].refs, vec!["refs/heads/side", "refs/tags/v1"]);
+        assert!(full.commits[2].refs.is_empty());
+
+        // The same walk by full ref name or commit id lays out the same graph
+        assert_eq!(graph("refs/heads/graph", None).await.unwrap(), full);
+        let by_id = git_ops
+            .commit_graph(service.get_db(), repo_id, Some(&top), None, &refs)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_id, full);
+
+        let side = graph("side", None).await.unwrap();
+        assert_eq!(lanes(&side), expected(&[("f2", 0), ("f1", 0), ("root", 0)]));
+        assert_eq!(side.lanes, 1);
+
+        // The newest commits only, with the side branch's lane still open
+        let head = graph("graph", Some(3)).await.unwrap();
+        assert_eq!(lanes(&head), expected(&[("top", 0), ("merge", 0), ("m2", 0)]));
+        assert_eq!(head.lanes, 2);
+        assert!(head.truncated);
+
+        assert!(graph("missing", None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_notes_attach_to_commits() {
+        let (service, repo_id, first, second) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+        let author = "A U Thor <author@example.com> 1700000000 +0000".to_string();
+
+        assert!(git_ops.get_note(db, repo_id, DEFAULT_NOTES_REF, &first).await.unwrap().is_none());
+        let note = git_ops
+            .set_note(db, repo_id, DEFAULT_NOTES_REF, &first, "Reviewed-by: someone", author.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(note.content, "Reviewed-by: someone\n");
+        assert_eq!(git_ops.get_note(db, repo_id, DEFAULT_NOTES_REF, &first).await.unwrap(), Some(note.clone()));
+        assert!(git_ops.get_note(db, repo_id, DEFAULT_NOTES_REF, &second).await.unwrap().is_none());
+        assert!(git_ops.get_note(db, repo_id, "refs/notes/review", &first).await.unwrap().is_none());
+
+        // The notes ref is an ordinary commit chain whose tree names notes by commit id
+        let tip = service.get_ref(repo_id, DEFAULT_NOTES_REF).await.unwrap().unwrap();
+        let notes_commit = git_ops.get_commit(db, repo_id, &tip.target).await.unwrap().unwrap();
+        assert!(notes_commit.commit.parents.is_empty());
+        let tree = git_ops.flatten_tree(db, repo_id, &notes_commit.commit.tree).await.unwrap();
+        assert_eq!(tree.keys().collect::<Vec<_>>(), vec![&first]);
+
+        git_ops
+            .set_note(db, repo_id, DEFAULT_NOTES_REF, &second, "second\n", author.clone())
+            .await
+            .unwrap();
+        let replaced = git_ops
+            .set_note(db, repo_id, DEFAULT_NOTES_REF, &first, "Replaced\n", author.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(replaced.content, "Replaced\n");
+        let tip = service.get_ref(repo_id, DEFAULT_NOTES_REF).await.unwrap().unwrap();
+        let notes_commit = git_ops.get_commit(db, repo_id, &tip.target).await.unwrap().unwrap();
+        assert_eq!(notes_commit.commit.parents.len(), 1);
+        assert_eq!(
+            git_ops.get_note(db, repo_id, DEFAULT_NOTES_REF, &second).await.unwrap().unwrap().content,
+            "second\n"
+        );
+
+        // Trees written by git with fanout are read and updated in place
+        let fanned = git_ops
+            .write_tree(
+                db,
+                &ObjectHandler::new(),
+                repo_id,
+                None,
+                vec![(
+                    vec![second[..2].to_string(), second[2..].to_string()],
+                    FileChange::Write(b"fanned out\n".to_vec()),
+                )],
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        let fanned_commit = git_ops
+            .create_commit(
+                db,
+                repo_id,
+                CreateCommitRequest {
+                    tree_hash: fanned,
+                    parent_hashes: Vec::new(),
+                    author: author.clone(),
+                    committer: author.clone(),
+                    message: NOTES_COMMIT_MESSAGE.to_string(),
+                    author_date: None,
+                    commit_date: None,
+                },
+            )
+            .await
+            .unwrap();
+        service
+            .store_ref(repo_id, "refs/notes/fanned".to_string(), fanned_commit, false)
+            .await
+            .unwrap();
+        let fanned_note = git_ops.get_note(db, repo_id, "refs/notes/fanned", &second).await.unwrap().unwrap();
+        assert_eq!(fanned_note.content, "fanned out\n");
+        git_ops
+            .set_note(db, repo_id, "refs/notes/fanned", &second, "still fanned out", author.clone())
+            .await
+            .unwrap();
+        let tip = service.get_ref(repo_id, "refs/notes/fanned").await.unwrap().unwrap();
+        let tree = git_ops.get_commit(db, repo_id, &tip.target).await.unwrap().unwrap().commit.tree;
+        let paths = git_ops.flatten_tree(db, repo_id, &tree).await.unwrap();
+        assert_eq!(paths.keys().collect::<Vec<_>>(), vec![&format!("{}/{}", &second[..2], &second[2..])]);
+
+        assert!(git_ops
+            .set_note(db, repo_id, "refs/heads/main", &first, "no", author.clone())
+            .await
+            .is_err());
+        assert!(git_ops
+            .set_note(db, repo_id, DEFAULT_NOTES_REF, &"0".repeat(40), "no", author)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_commit_files_adds_and_deletes_in_one_commit() {
+        let (service, repo_id, c1, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+        let author = "A U Thor <author@example.com> 1700000000 +0000".to_string();
+        let write = |content: &str| FileChange::Write(content.as_bytes().to_vec());
+
+        let first = git_ops
+            .commit_files(
+                db,
+                repo_id,
+                "main",
+                vec![
+                    ("README.md".to_string(), write("hello\n")),
+                    ("src/lib.rs".to_string(), write("pub fn lib() {}\n")),
+                    ("src/bin/main.rs".to_string(), write("fn main() {}\n")),
+                ],
+                author.clone(),
+                "Add files\n".to_string(),
+            )
+            .await
+            .unwrap();
+        let first_tree = git_ops.get_commit_info(db, repo_id, &first).await.unwrap().tree;
+
+        let second = git_ops
+            .commit_files(
+                db,
+                repo_id,
+                "main",
+                vec![
+                    ("README.md".to_string(), write("hello again\n")),
+                    ("docs/guide.md".to_string(), write("# Guide\n")),
+                    ("src/bin/main.rs".to_string(), FileChange::Delete),
+                ],
+                author.clone(),
+                "Edit files\n".to_string(),
+            )
+            .await
+            .unwrap();
+
+        let commit = git_ops.get_commit_info(db, repo_id, &second).await.unwrap();
+        assert_eq!(commit.parents, vec![first.clone()]);
+        assert_eq!(git_ops.get_ref(db, repo_id, "refs/heads/main").await.unwrap().unwrap().target, second);
+        assert_eq!(
+            git_ops.get_commit_info(db, repo_id, &first).await.unwrap().parents,
+            vec![c1]
+        );
+
+        let handler = ObjectHandler::new();
+        let files = git_ops.flatten_tree(db, repo_id, &commit.tree).await.unwrap();
+        let blob = |content: &str| handler.create_blob(content.as_bytes()).unwrap().id;
+        assert_eq!(
+            files.into_iter().map(|(path, (_, id))| (path, id)).collect::<Vec<_>>(),
+            vec![
+                ("README.md".to_string(), blob("hello again\n")),
+                ("docs/guide.md".to_string(), blob("# Guide\n")),
+                ("src/lib.rs".to_string(), blob("pub fn lib() {}\n")),
+            ]
+        );
+
+        // The emptied src/bin directory is gone, and the tree sorts like git's
+        let root = handler
+            .parse_tree(&git_ops.get_object_content(db, repo_id, &commit.tree, ObjectKind::Tree).await.unwrap())
+            .unwrap();
+        let names: Vec<_> = root.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["README.md", "docs", "src"]);
+        let src = root.entries.iter().find(|e| e.name == "src").unwrap();
+        let src = handler
+            .parse_tree(&git_ops.get_object_content(db, repo_id, &src.hash, ObjectKind::Tree).await.unwrap())
+            .unwrap();
+        assert_eq!(src.entries.len(), 1);
+        assert_ne!(commit.tree, first_tree);
+
+        // Deleting a missing path or escaping the repository is refused
+        for (path, change) in [("nope.txt", FileChange::Delete), ("../etc/passwd", write("x"))] {
+            let result = git_ops
+                .commit_files(db, repo_id, "main", vec![(path.to_string(), change)], author.clone(), "bad\n".to_string())
+                .await;
+            assert!(result.is_err(), "{} should be rejected", path);
+        }
+        assert_eq!(git_ops.get_ref(db, repo_id, "refs/heads/main").await.unwrap().unwrap().target, second);
+    }
+
+    #[tokio::test]
+    async fn test_diff_since_an_ancestor_of_the_branch() {
+        let (service, repo_id, _, c2) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+        let author = "A U Thor <author@example.com> 1700000000 +0000".to_string();
+        let write = |content: &str| FileChange::Write(content.as_bytes().to_vec());
+
+        let mut commits = Vec::new();
+        for (changes, message) in [
+            (vec![("README.md", write("hello
+")), ("old.txt", write("gone soon
+"))], "Add files
+"),
+            (vec![("README.md", write("hello again
+"))], "Edit README
+"),
+            (vec![("old.txt", FileChange::Delete), ("new.txt", write("new
+"))], "Swap files
+"),
+        ] {
+            let changes = changes.into_iter().map(|(path, change)| (path.to_string(), change)).collect();
+            commits.push(
+                git_ops
+                    .commit_files(db, repo_id, "main", changes, author.clone(), message.to_string())
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        // Two commits ago: both later commits' changes, as one diff
+        let diffs = git_ops.diff_since(db, repo_id, "main", &commits[0]).await.unwrap();
+        let changes: Vec<_> = diffs.iter().map(|d| (d.path.as_str(), &d.change)).collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("README.md", &ChangeKind::Modified),
+                ("new.txt", &ChangeKind::Added),
+                ("old.txt", &ChangeKind::Deleted),
+            ]
+        );
+        assert_eq!(
+            diffs,
+            git_ops
+                .diff_commits(db, repo_id, &commits[0], &commits[2], &DiffOptions { detect_renames: true, ..DiffOptions::default() })
+                .await
+                .unwrap()
+        );
+        assert!(git_ops.diff_since(db, repo_id, "main", &commits[2]).await.unwrap().is_empty());
+
+        // A commit that isn't in the branch's history is refused
+        let err = git_ops.diff_since(db, repo_id, "main", &c2).await.unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<StorageError>(), Some(StorageError::NotAncestor { commit, branch }) if *commit == c2 && branch == "main"),
+            "{}",
+            err
+        );
+        let err = git_ops.diff_since(db, repo_id, "nope", &commits[0]).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::RefNotFound { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_history_date_and_identity_filters() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+
+        // A linear history alternating between two authors, one commit a day;
+        // Bob's commits are committed by Alice
+        let alice = "Alice Liddell <alice@example.com>";
+        let bob = "Bob Builder <bob@example.org>";
+        let day = |n: i64| 1700000000 + n * 86400;
+        let mut parent: Option<String> = None;
+        let mut hashes = Vec::new();
+        for (n, author) in [alice, bob, alice, bob, alice].into_iter().enumerate() {
+            let request = CreateCommitRequest {
+                tree_hash: EMPTY_TREE_ID.to_string(),
+                parent_hashes: parent.iter().cloned().collect(),
+                author: format!("{} {} +0000", author, day(n as i64)),
+                committer: format!("{} {} +0000", alice, day(n as i64)),
+                message: format!("day {}\n", n),
+                author_date: None,
+                commit_date: None,
+            };
+            let hash = git_ops.create_commit(service.get_db(), repo_id, request).await.unwrap();
+            parent = Some(hash.clone());
+            hashes.push(hash);
+        }
+        service
+            .store_ref(repo_id, "refs/heads/log".to_string(), hashes[4].clone(), false)
+            .await
+            .unwrap();
+
+        let history = |options: HistoryOptions| {
+            let git_ops = &git_ops;
+            let service = &service;
+            async move {
+                git_ops
+                    .get_commit_history(service.get_db(), repo_id, "log".to_string(), &options)
+                    .await
+                    .unwrap()
+            }
+        };
+        let days = |page: &CommitHistory| {
+            page.commits
+                .iter()
+                .map(|c| hashes.iter().position(|h| h == &c.hash).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let at = |n: i64| DateTime::from_timestamp(day(n), 0).unwrap();
+
+        let page = history(HistoryOptions { since: Some(at(2)), ..Default::default() }).await;
+        assert_eq!(days(&page), vec![4, 3, 2]);
+        let page = history(HistoryOptions { until: Some(at(1)), ..Default::default() }).await;
+        assert_eq!(days(&page), vec![1, 0]);
+
+        let page = history(HistoryOptions { author: Some("bob".to_string()), ..Default::default() }).await;
+        assert_eq!(days(&page), vec![3, 1]);
+        let page = history(HistoryOptions { author: Some("ALICE@example.com".to_string()), ..Default::default() }).await;
+        assert_eq!(days(&page), vec![4, 2, 0]);
+        // An email filter must match exactly, not as a substring
+        let page = history(HistoryOptions { author: Some("ice@example.com".to_string()), ..Default::default() }).await;
+        assert!(page.commits.is_empty());
+        let page = history(HistoryOptions { committer: Some("liddell".to_string()), ..Default::default() }).await;
+        assert_eq!(days(&page), vec![4, 3, 2, 1, 0]);
+
+        // Combined, and paged one commit at a time
+        let filters = HistoryOptions {
+            since: Some(at(1)),
+            until: Some(at(4)),
+            author: Some("alice".to_string()),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let first = history(filters.clone()).await;
+        assert_eq!(days(&first), vec![4]);
+        assert_eq!(first.author.as_deref(), Some("alice"));
+        let second = history(HistoryOptions { cursor: first.next_cursor.clone(), ..filters.clone() }).await;
+        assert_eq!(days(&second), vec![2]);
+        assert!(second.next_cursor.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_objects_exclude_everything_refs_reach() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+
+        let tip = git_ops
+            .commit_files(
+                db,
+                repo_id,
+                "main",
+                vec![("README.md".to_string(), FileChange::Write(b"reachable\n".to_vec()))],
+                "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                "Add readme\n".to_string(),
+            )
+            .await
+            .unwrap();
+        let reachable_blob = ObjectHandler::new().create_blob(b"reachable\n").unwrap();
+
+        // A blob and a commit nothing points at, as left behind by a force-push
+        let dangling_blob = ObjectHandler::new().create_blob(b"dangling\n").unwrap();
+        service
+            .store_object(repo_id, dangling_blob.id.clone(), ObjectKind::Blob, dangling_blob.size as i64, dangling_blob.content.clone())
+            .await
+            .unwrap();
+        let dangling_commit = git_ops
+            .create_commit(
+                db,
+                repo_id,
+                CreateCommitRequest {
+                    tree_hash: EMPTY_TREE_ID.to_string(),
+                    parent_hashes: vec![tip.clone()],
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: "Rewritten away\n".to_string(),
+                    author_date: None,
+                    commit_date: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        let unreachable = git_ops.list_unreachable_objects(db, repo_id).await.unwrap();
+        let mut expected = vec![
+            (dangling_blob.id.clone(), "blob"),
+            (dangling_commit.clone(), "commit"),
+        ];
+        expected.sort();
+        let listed: Vec<(String, &str)> =
+            unreachable.iter().map(|(id, object_type, _)| (id.clone(), object_type.as_str())).collect();
+        assert_eq!(listed, expected);
+        assert_eq!(unreachable[listed.iter().position(|o| o.1 == "blob").unwrap()].2, 9);
+
+        let reachable = git_ops.reachable_objects(db, repo_id).await.unwrap();
+        assert!(reachable.contains(&tip));
+        assert!(reachable.contains(&reachable_blob.id));
+        assert!(!reachable.contains(&dangling_commit));
+    }
+
+    #[tokio::test]
+    async fn test_walk_objects_visits_each_object_once() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+        let commit = |files: Vec<(&str, &[u8])>| {
+            let files = files
+                .into_iter()
+                .map(|(path, content)| (path.to_string(), FileChange::Write(content.to_vec())))
+                .collect();
+            git_ops.commit_files(db, repo_id, "walk", files, "A <a@example.com>".to_string(), "walk\n".to_string())
+        };
+        // Two commits sharing `dir` and its blob; only a.txt changes
+        let first = commit(vec![("a.txt", b"one\n"), ("dir/b.txt", b"shared\n")]).await.unwrap();
+        let second = commit(vec![("a.txt", b"two\n")]).await.unwrap();
+        let handler = ObjectHandler::new();
+        let shared_blob = handler.create_blob(b"shared\n").unwrap().id;
+        let first_tree = git_ops.get_commit_info(db, repo_id, &first).await.unwrap().tree;
+        let dir_tree = handler
+            .parse_tree(&git_ops.get_object_content(db, repo_id, &first_tree, ObjectKind::Tree).await.unwrap())
+            .unwrap()
+            .entries
+            .into_iter()
+            .find(|entry| entry.name == "dir")
+            .unwrap()
+            .hash;
+
+        #[derive(Default)]
+        struct Counter {
+            counts: HashMap<ObjectKind, usize>,
+            depths: HashMap<String, usize>,
+            prune: Option<String>,
+        }
+        impl ObjectVisitor for Counter {
+            fn visit(&mut self, object_id: &str, kind: ObjectKind, depth: usize) -> WalkStep {
+                *self.counts.entry(kind).or_default() += 1;
+                assert!(self.depths.insert(object_id.to_string(), depth).is_none(), "{} visited twice", object_id);
+                if self.prune.as_deref() == Some(object_id) {
+                    WalkStep::Prune
+                } else {
+                    WalkStep::Descend
+                }
+            }
+        }
+
+        let roots = [second.clone(), first.clone()];
+        let mut counter = Counter::default();
+        git_ops.walk_objects(db, repo_id, &roots, &mut counter).await.unwrap();
+        // Two root trees and `dir`; a.txt twice and the shared blob
+        assert_eq!(counter.counts[&ObjectKind::Commit], 2);
+        assert_eq!(counter.counts[&ObjectKind::Tree], 3);
+        assert_eq!(counter.counts[&ObjectKind::Blob], 3);
+        // Depth is the shortest way in, even though `first` is also second's parent
+        assert_eq!((counter.depths[&second], counter.depths[&first]), (0, 0));
+        let mut counter = Counter::default();
+        git_ops.walk_objects(db, repo_id, std::slice::from_ref(&second), &mut counter).await.unwrap();
+        assert_eq!((counter.depths[&second], counter.depths[&first]), (0, 1));
+        assert_eq!(counter.depths[&shared_blob], 0);
+
+        // Skipping a tree skips the blobs only it leads to
+        let mut counter = Counter {
+            prune: Some(dir_tree.clone()),
+            ..Counter::default()
+        };
+        git_ops.walk_objects(db, repo_id, &roots, &mut counter).await.unwrap();
+        assert!(counter.depths.contains_key(&dir_tree));
+        assert!(!counter.depths.contains_key(&shared_blob));
+        assert_eq!(counter.counts[&ObjectKind::Blob], 2);
+
+        // Pruning commits stops the walk at the roots
+        let mut commits_only = |_: &str, kind: ObjectKind, _: usize| match kind {
+            ObjectKind::Commit => WalkStep::Prune,
+            _ => panic!("walked past a pruned commit"),
+        };
+        git_ops.walk_objects(db, repo_id, &roots, &mut commits_only).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shallow_and_filtered_fetches() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+        let big = vec![b'x'; 2000];
+        let mut commits = Vec::new();
+        for (i, content) in ["one\n", "two\n", "three\n"].into_iter().enumerate() {
+            let mut files = vec![("a.txt".to_string(), FileChange::Write(content.as_bytes().to_vec()))];
+            if i == 0 {
+                files.push(("big.bin".to_string(), FileChange::Write(big.clone())));
+            }
+            let commit = git_ops
+                .commit_files(db, repo_id, "deep", files, "A <a@example.com>".to_string(), format!("{}\n", i))
+                .await
+                .unwrap();
+            commits.push(commit);
+        }
+        let handler = ObjectHandler::new();
+        let blob = |content: &[u8]| handler.create_blob(content).unwrap().id;
+        let tip = vec![commits[2].clone()];
+        let ids = |objects: Vec<GitObject>| objects.into_iter().map(|o| o.id).collect::<HashSet<_>>();
+        let fetch = |haves: Vec<String>, options: FetchOptions| {
+            let git_ops = &git_ops;
+            let tip = &tip;
+            async move { ids(git_ops.objects_for_fetch(db, repo_id, tip, &haves, &options).await.unwrap()) }
+        };
+
+        // Depth 1 is the tip and its files only
+        let depth = |depth, client_shallow: &[&String]| FetchOptions {
+            depth: Some(depth),
+            client_shallow: client_shallow.iter().map(|id| id.to_string()).collect(),
+            ..FetchOptions::default()
+        };
+        let sent = fetch(Vec::new(), depth(1, &[])).await;
+        assert!(sent.contains(&commits[2]) && !sent.contains(&commits[1]));
+        assert!(sent.contains(&blob(b"three\n")) && sent.contains(&blob(&big)));
+        assert!(!sent.contains(&blob(b"two\n")));
+        let update = git_ops.shallow_update(db, repo_id, &tip, 1, &[]).await.unwrap();
+        assert_eq!(update, ShallowUpdate { shallow: vec![commits[2].clone()], unshallow: vec![] });
+
+        // Deepening by one sends the parent and moves the boundary onto it
+        let sent = fetch(tip.clone(), depth(2, &[&commits[2]])).await;
+        assert!(sent.contains(&commits[1]) && sent.contains(&blob(b"two\n")));
+        assert!(!sent.contains(&commits[2]) && !sent.contains(&commits[0]));
+        let update = git_ops.shallow_update(db, repo_id, &tip, 2, &tip).await.unwrap();
+        assert_eq!(
+            update,
+            ShallowUpdate { shallow: vec![commits[1].clone()], unshallow: vec![commits[2].clone()] }
+        );
+
+        // A root commit at the edge has no parents to leave out
+        let update = git_ops.shallow_update(db, repo_id, &tip, 3, &[]).await.unwrap();
+        assert_eq!(update, ShallowUpdate::default());
+
+        // Without a depth a shallow client's boundary still holds
+        let sent = fetch(Vec::new(), FetchOptions { client_shallow: vec![commits[1].clone()], ..Default::default() }).await;
+        assert!(sent.contains(&commits[2]) && !sent.contains(&commits[1]) && !sent.contains(&commits[0]));
+
+        // Filters leave out blobs from the limit up, unless they are wanted by id
+        let limited = FetchOptions { blob_limit: Some(1000), ..FetchOptions::default() };
+        let sent = fetch(Vec::new(), limited).await;
+        assert!(sent.contains(&blob(b"one\n")) && !sent.contains(&blob(&big)));
+        let none = FetchOptions { blob_limit: Some(0), ..FetchOptions::default() };
+        let wanted = git_ops
+            .objects_for_fetch(db, repo_id, &[blob(&big)], &[], &none)
+            .await
+            .unwrap();
+        assert_eq!(ids(wanted), HashSet::from([blob(&big)]));
+    }
+
+    #[tokio::test]
+    async fn test_commit_trailers_leave_the_stored_message_alone() {
+        let (service, repo_id, main_commit, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+
+        let message = "Pair on the parser\n\nSigned-off-by: A U Thor <author@example.com>\nCo-authored-by: Pair <pair@example.com>\n";
+        let hash = git_ops
+            .create_commit(
+                db,
+                repo_id,
+                CreateCommitRequest {
+                    tree_hash: EMPTY_TREE_ID.to_string(),
+                    parent_hashes: vec![main_commit.clone()],
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: message.to_string(),
+                    author_date: None,
+                    commit_date: None,
+                },
+            )
+            .await
+            .unwrap();
+        git_ops.write_ref(db, repo_id, "refs/heads/main", Some(&main_commit), Some(&hash)).await.unwrap();
+
+        let detail = git_ops.get_commit(db, repo_id, &hash).await.unwrap().unwrap();
+        assert_eq!(detail.trailers.len(), 2);
+        assert_eq!(detail.trailers[0].key, "Signed-off-by");
+        assert_eq!(detail.co_authors, vec!["Pair <pair@example.com>"]);
+        // The stored object still hashes to the same id
+        let content = git_ops.get_object_content(db, repo_id, &hash, ObjectKind::Commit).await.unwrap();
+        assert_eq!(ObjectHandler::new().calculate_hash(git_protocol::ObjectType::Commit, &content).unwrap(), hash);
+        assert!(git_ops.get_commit(db, repo_id, &"0".repeat(40)).await.unwrap().is_none());
+
+        let history = git_ops
+            .get_commit_history(db, repo_id, "main".to_string(), &HistoryOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(history.commits[0].co_authors, vec!["Pair <pair@example.com>"]);
+        assert!(history.commits[1].co_authors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_amend_commit_keeps_parents() {
+        let (service, repo_id, main_commit, feature_commit) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+
+        let amended = git_ops
+            .amend_commit(
+                db,
+                repo_id,
+                "feature",
+                Some("second, reworded\n".to_string()),
+                None,
+                "C O Mitter <committer@example.com> 1700000500 +0000".to_string(),
+            )
+            .await
+            .unwrap();
+        assert_ne!(amended, feature_commit);
+
+        let original = git_ops.get_commit_info(db, repo_id, &feature_commit).await.unwrap();
+        let commit = git_ops.get_commit_info(db, repo_id, &amended).await.unwrap();
+        assert_eq!(commit.parents, vec![main_commit]);
+        assert_eq!(commit.tree, original.tree);
+        assert_eq!(commit.author, original.author);
+        assert!(commit.committer.starts_with("C O Mitter"));
+        assert_eq!(commit.message, "second, reworded\n");
+        let tip = git_ops.get_ref(db, repo_id, "refs/heads/feature").await.unwrap().unwrap();
+        assert_eq!(tip.target, amended);
+
+        let err = git_ops
+            .amend_commit(db, repo_id, "missing", Some("x".to_string()), None, "C O Mitter <c@example.com> 0 +0000".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::RefNotFound { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_object_sha_at_path_resolves_files_and_directories() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+        let files = vec![
+            ("README.md".to_string(), FileChange::Write(b"hello\n".to_vec())),
+            ("src/util/strings.rs".to_string(), FileChange::Write(b"pub fn trim() {}\n".to_vec())),
+        ];
+        let commit = git_ops
+            .commit_files(db, repo_id, "main", files, "A <a@example.com>".to_string(), "layout\n".to_string())
+            .await
+            .unwrap();
+
+        let handler = ObjectHandler::new();
+        let file = handler.create_blob(b"pub fn trim() {}\n").unwrap();
+        let util = handler
+            .create_tree(&Tree {
+                entries: vec![TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "strings.rs".to_string(),
+                    hash: file.id.clone(),
+                }],
+            })
+            .unwrap();
+        let at = |path: &'static str| git_ops.object_sha_at_path(db, repo_id, &commit, path);
+
+        assert_eq!(at("src/util/strings.rs").await.unwrap(), (file.id.clone(), TreeEntryKind::Blob));
+        assert_eq!(at("src/util").await.unwrap(), (util.id.clone(), TreeEntryKind::Tree));
+        assert_eq!(at("src/util/").await.unwrap(), (util.id, TreeEntryKind::Tree));
+        let root = git_ops.get_commit_info(db, repo_id, &commit).await.unwrap().tree;
+        assert_eq!(at("").await.unwrap(), (root, TreeEntryKind::Tree));
+
+        for missing in ["src/missing.rs", "README.md/child", "SRC/util"] {
+            let err = at(missing).await.unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<StorageError>(), Some(StorageError::PathNotFound { path }) if path == missing),
+                "{}: {}",
+                missing,
+                err
+            );
+        }
+        let err = git_ops
+            .object_sha_at_path(db, repo_id, &"0".repeat(40), "README.md")
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::RefNotFound { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_create_commit_honors_client_dates() {
+        let (service, repo_id, _, _) = setup().await;
+        let git_ops = GitOperations::new(service.clone());
+        let db = service.get_db();
+        let backdated = DateTime::from_timestamp(1_500_000_000, 0).unwrap();
+        let request = |author: &str, author_date, commit_date| CreateCommitRequest {
+            tree_hash: EMPTY_TREE_ID.to_string(),
+            parent_hashes: vec![],
+            author: author.to_string(),
+            committer: "C O Mitter <committer@example.com> 1700000000 +0200".to_string(),
+            message: "Backdated\n".to_string(),
+            author_date,
+            commit_date,
+        };
+
+        let id = git_ops
+            .create_commit(db, repo_id, request("A U Thor <author@example.com>", Some(backdated), Some(backdated)))
+            .await
+            .unwrap();
+        let commit = service.get_commit_object(repo_id, &id).await.unwrap().unwrap();
+        assert_eq!(commit.author, "A U Thor <author@example.com> 1500000000 +0000");
+        assert_eq!(commit.committer, "C O Mitter <committer@example.com> 1500000000 +0200");
+        assert_eq!((commit.author_date, commit.commit_date), (backdated, backdated));
+
+        // Without dates the signatures are stored as given
+        let id = git_ops
+            .create_commit(db, repo_id, request("A U Thor <author@example.com> 1600000000 -0500", None, None))
+            .await
+            .unwrap();
+        let commit = service.get_commit_object(repo_id, &id).await.unwrap().unwrap();
+        assert_eq!(commit.author, "A U Thor <author@example.com> 1600000000 -0500");
+        assert_eq!(commit.author_date, DateTime::from_timestamp(1_600_000_000, 0).unwrap());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::entities::idempotency_key;
+use anyhow::Result;
+use chrono::Utc;
+use sea_orm::{ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, Set};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long a stored result is replayed for a repeated key
+pub const DEFAULT_IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Remembers the result of create requests so retries with the same
+/// `Idempotency-Key` get the original response instead of a duplicate
+pub struct IdempotencyService {
+    db: DatabaseConnection,
+    ttl: Duration,
+}
+
+impl IdempotencyService {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self {
+            db,
+            ttl: DEFAULT_IDEMPOTENCY_KEY_TTL,
+        }
+    }
+
+    /// Set how long keys stay valid
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Get the stored result for a key, ignoring expired entries
+    pub async fn get(&self, scope: &str, key: &str) -> Result<Option<idempotency_key::Model>> {
+        let record = idempotency_key::Entity::find()
+            .filter(idempotency_key::Column::Scope.eq(scope))
+            .filter(idempotency_key::Column::Key.eq(key))
+            .filter(idempotency_key::Column::ExpiresAt.gt(Utc::now()))
+            .one(&self.db)
+            .await?;
+        Ok(record)
+    }
+
+    /// Store the result of a successful create under a key
+    pub async fn record(
+        &self,
+        scope: &str,
+        key: &str,
+        resource_id: String,
+        response_status: u16,
+        response_body: String,
+    ) -> Result<idempotency_key::Model> {
+        // An expired entry for the same key would violate the unique index
+        idempotency_key::Entity::delete_many()
+            .filter(idempotency_key::Column::Scope.eq(scope))
+            .filter(idempotency_key::Column::Key.eq(key))
+            .filter(idempotency_key::Column::ExpiresAt.lte(Utc::now()))
+            .exec(&self.db)
+            .await?;
+
+        let now = Utc::now();
+        let record = idempotency_key::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            scope: Set(scope.to_string()),
+            key: Set(key.to_string()),
+            resource_id: Set(resource_id),
+            response_status: Set(response_status as i32),
+            response_body: Set(response_body),
+            created_at: Set(now.into()),
+            expires_at: Set((now + chrono::Duration::from_std(self.ttl)?).into()),
+        };
+
+        let result = record.insert(&self.db).await?;
+        Ok(result)
+    }
+
+    /// Delete expired keys
+    pub async fn purge_expired(&self) -> Result<u64> {
+        let result = idempotency_key::Entity::delete_many()
+            .filter(idempotency_key::Column::ExpiresAt.lte(Utc::now()))
+            .exec(&self.db)
+            .await?;
+        Ok(result.rows_affected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_db, run_migrations};
+
+    #[tokio::test]
+    async fn test_expired_keys_are_ignored_and_replaced() {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+        let service = IdempotencyService::new(db).with_ttl(Duration::ZERO);
+
+        service
+            .record("scope", "key-1", "first".to_string(), 201, "{}".to_string())
+            .await
+            .unwrap();
+        assert!(service.get("scope", "key-1").await.unwrap().is_none());
+
+        // The expired entry doesn't block reusing the key
+        service
+            .record("scope", "key-1", "second".to_string(), 201, "{}".to_string())
+            .await
+            .unwrap();
+        assert_eq!(service.purge_expired().await.unwrap(), 1);
+    }
+}
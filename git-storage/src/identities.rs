@@ -0,0 +1,342 @@
+use crate::entities::{email_alias, user};
+use crate::error::StorageError;
+use crate::git_ops::{BranchInfo, CommitDetail, HistoryCommit};
+use crate::tokens::hash_token;
+use crate::user::normalize_email;
+use anyhow::Result;
+use chrono::Utc;
+use git_protocol::objects::Commit;
+use sea_orm::sea_query::{Expr, Func};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter,
+    QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use uuid::Uuid;
+
+/// Prefix on email verification tokens, as `gst_` is on access tokens
+pub const VERIFICATION_TOKEN_PREFIX: &str = "gsv_";
+
+/// Noreply addresses are `<username>@users.noreply.<host>`
+const NOREPLY_SUBDOMAIN: &str = "users.noreply";
+
+/// The account behind a commit's author or committer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitUser {
+    pub id: Uuid,
+    pub username: String,
+    pub avatar_url: String,
+}
+
+/// Where `user`'s avatar is served. The avatar version in the query changes
+/// with every upload, so responses to it can be cached for good.
+pub fn avatar_url(user: &user::Model) -> String {
+    format!("/api/users/{}/avatar?v={}", user.username, user.avatar_version)
+}
+
+/// Maps commit emails to the users they belong to: by a user's primary address
+/// or by one of their verified aliases. Unverified aliases map nothing, so
+/// nobody can claim someone else's commits by naming their address.
+pub struct IdentityService {
+    db: DatabaseConnection,
+}
+
+impl IdentityService {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Add an unverified alias for `user_id`. Returns it with the plaintext
+    /// verification token to send to the address; only a hash is stored.
+    pub async fn add_alias(&self, user_id: Uuid, email: &str) -> Result<(email_alias::Model, String)> {
+        let email = normalize_email(email)?;
+        if self.email_in_use(&email).await? {
+            return Err(StorageError::EmailTaken { email }.into());
+        }
+
+        let plaintext = format!(
+            "{}{}{}",
+            VERIFICATION_TOKEN_PREFIX,
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        );
+        let alias = email_alias::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            user_id: Set(user_id),
+            email: Set(email),
+            verified: Set(false),
+            verification_token_hash: Set(Some(hash_token(&plaintext))),
+            created_at: Set(Utc::now().into()),
+        }
+        .insert(&self.db)
+        .await?;
+
+        Ok((alias, plaintext))
+    }
+
+    /// Verify the alias `token` was issued for, if it belongs to `user_id`.
+    /// Tokens work once.
+    pub async fn verify_alias(&self, user_id: Uuid, token: &str) -> Result<Option<email_alias::Model>> {
+        let Some(alias) = email_alias::Entity::find()
+            .filter(email_alias::Column::VerificationTokenHash.eq(hash_token(token)))
+            .filter(email_alias::Column::UserId.eq(user_id))
+            .one(&self.db)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let mut active: email_alias::ActiveModel = alias.into();
+        active.verified = Set(true);
+        active.verification_token_hash = Set(None);
+        Ok(Some(active.update(&self.db).await?))
+    }
+
+    /// A user's aliases, verified or not
+    pub async fn list_aliases(&self, user_id: Uuid) -> Result<Vec<email_alias::Model>> {
+        let aliases = email_alias::Entity::find()
+            .filter(email_alias::Column::UserId.eq(user_id))
+            .order_by_asc(email_alias::Column::Email)
+            .all(&self.db)
+            .await?;
+        Ok(aliases)
+    }
+
+    /// Remove one of `user_id`'s aliases; `false` if they have no such alias
+    pub async fn remove_alias(&self, user_id: Uuid, alias_id: Uuid) -> Result<bool> {
+        let result = email_alias::Entity::delete_many()
+            .filter(email_alias::Column::Id.eq(alias_id))
+            .filter(email_alias::Column::UserId.eq(user_id))
+            .exec(&self.db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+
+    /// The users owning `emails`, in two queries however many there are
+    pub async fn resolve<'a>(&self, emails: impl IntoIterator<Item = &'a str>) -> Result<IdentityMap> {
+        let emails: BTreeSet<String> = emails.into_iter().map(str::to_lowercase).collect();
+        if emails.is_empty() {
+            return Ok(IdentityMap::default());
+        }
+
+        let mut users = HashMap::new();
+        let primary = user::Entity::find()
+            .filter(Expr::expr(Func::lower(Expr::col(user::Column::Email))).is_in(emails.iter().cloned()))
+            .all(&self.db)
+            .await?;
+        for user in primary {
+            users.insert(user.email.to_lowercase(), user);
+        }
+
+        let aliases = email_alias::Entity::find()
+            .filter(email_alias::Column::Email.is_in(emails.iter().filter(|e| !users.contains_key(*e)).cloned()))
+            .filter(email_alias::Column::Verified.eq(true))
+            .find_also_related(user::Entity)
+            .all(&self.db)
+            .await?;
+        for (alias, user) in aliases {
+            if let Some(user) = user {
+                users.insert(alias.email, user);
+            }
+        }
+
+        Ok(IdentityMap { users })
+    }
+
+    /// Whether a user has `email` as primary address or alias, in any case
+    async fn email_in_use(&self, email: &str) -> Result<bool> {
+        let primary = user::Entity::find()
+            .filter(Expr::expr(Func::lower(Expr::col(user::Column::Email))).eq(email))
+            .count(&self.db)
+            .await?;
+        let aliases = email_alias::Entity::find()
+            .filter(email_alias::Column::Email.eq(email))
+            .count(&self.db)
+            .await?;
+        Ok(primary + aliases > 0)
+    }
+}
+
+/// The emails between angle brackets in `text`, as in `Name <email>` signatures
+/// and trailers
+pub fn bracketed_emails(text: &str) -> impl Iterator<Item = &str> {
+    text.split('<').skip(1).filter_map(|part| {
+        let (email, _) = part.split_once('>')?;
+        email.contains('@').then_some(email)
+    })
+}
+
+/// Where a user hiding their email is shown as reachable instead
+pub fn noreply_email(user: &user::Model, host: &str) -> String {
+    format!("{}@{}.{}", user.username, NOREPLY_SUBDOMAIN, host)
+}
+
+/// Users found by `IdentityService::resolve`, keyed by lowercased email
+#[derive(Debug, Clone, Default)]
+pub struct IdentityMap {
+    users: HashMap<String, user::Model>,
+}
+
+impl IdentityMap {
+    /// The user owning `email`, if it was among those resolved
+    pub fn user(&self, email: &str) -> Option<&user::Model> {
+        self.users.get(&email.to_lowercase())
+    }
+
+    /// The user a `Name <email> ...` signature belongs to, as shown with commits
+    pub fn commit_user(&self, signature: &str) -> Option<CommitUser> {
+        let email = bracketed_emails(signature).next()?;
+        self.user(email).map(|user| CommitUser {
+            id: user.id,
+            username: user.username.clone(),
+            avatar_url: avatar_url(user),
+        })
+    }
+
+    /// `text` with the bracketed emails of users hiding theirs replaced by their
+    /// noreply address on `host`
+    pub fn redact(&self, text: &str, host: &str) -> String {
+        let mut parts = text.split('<');
+        let mut redacted = parts.next().unwrap_or_default().to_string();
+        for part in parts {
+            redacted.push('<');
+            match part.split_once('>') {
+                Some((email, rest)) => match self.user(email) {
+                    Some(user) if user.hide_email => {
+                        redacted.push_str(&noreply_email(user, host));
+                        redacted.push('>');
+                        redacted.push_str(rest);
+                    }
+                    _ => redacted.push_str(part),
+                },
+                None => redacted.push_str(part),
+            }
+        }
+        redacted
+    }
+
+    /// Redact every field of `commit` an email shows up in. Only this copy
+    /// changes; the stored object and its hash stay as they are.
+    fn redact_commit(&self, commit: &mut Commit, host: &str) {
+        commit.author = self.redact(&commit.author, host);
+        commit.committer = self.redact(&commit.committer, host);
+        commit.message = self.redact(&commit.message, host);
+    }
+}
+
+/// Emails worth resolving before showing `commit`: its signatures and those in
+/// its message's trailers
+pub fn commit_emails(commit: &Commit) -> impl Iterator<Item = &str> {
+    bracketed_emails(&commit.author)
+        .chain(bracketed_emails(&commit.committer))
+        .chain(bracketed_emails(&commit.message))
+}
+
+impl HistoryCommit {
+    /// Link the author and committer to their accounts and hide the emails of
+    /// users who asked to, see `IdentityMap::redact`
+    pub fn identify(&mut self, identities: &IdentityMap, host: &str) {
+        self.author_user = identities.commit_user(&self.commit.author);
+        self.committer_user = identities.commit_user(&self.commit.committer);
+        identities.redact_commit(&mut self.commit, host);
+        for co_author in &mut self.co_authors {
+            *co_author = identities.redact(co_author, host);
+        }
+    }
+}
+
+impl CommitDetail {
+    /// Link the author and committer to their accounts and hide the emails of
+    /// users who asked to, see `IdentityMap::redact`
+    pub fn identify(&mut self, identities: &IdentityMap, host: &str) {
+        self.author_user = identities.commit_user(&self.commit.author);
+        self.committer_user = identities.commit_user(&self.commit.committer);
+        identities.redact_commit(&mut self.commit, host);
+        for co_author in &mut self.co_authors {
+            *co_author = identities.redact(co_author, host);
+        }
+        for trailer in &mut self.trailers {
+            trailer.value = identities.redact(&trailer.value, host);
+        }
+    }
+}
+
+impl BranchInfo {
+    /// Hide the tip author's email if they asked to
+    pub fn identify(&mut self, identities: &IdentityMap, host: &str) {
+        self.author = identities.redact(&self.author, host);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_db, run_migrations, UserService};
+
+    #[tokio::test]
+    async fn test_verified_aliases_map_emails_to_users() {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let users = UserService::new(db.clone());
+        let alice = users
+            .create_user("alice".to_string(), "Alice@Example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let mallory = users
+            .create_user("mallory".to_string(), "mallory@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let service = IdentityService::new(db.clone());
+
+        let (alias, token) = service.add_alias(alice.id, " Alice@Work.example ").await.unwrap();
+        assert_eq!(alias.email, "alice@work.example");
+        assert!(!alias.verified);
+        assert!(token.starts_with(VERIFICATION_TOKEN_PREFIX));
+
+        // Nobody can claim an address in use, nor map it before it is verified
+        for email in ["alice@work.example", "alice@example.com"] {
+            let err = service.add_alias(mallory.id, email).await.unwrap_err();
+            assert!(matches!(err.downcast_ref(), Some(StorageError::EmailTaken { .. })), "{}", err);
+        }
+        let err = service.add_alias(mallory.id, "not an email").await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(StorageError::InvalidEmail { .. })));
+        let emails = ["alice@example.com", "ALICE@work.example", "nobody@example.com"];
+        let identities = service.resolve(emails).await.unwrap();
+        assert_eq!(identities.user("alice@example.com").map(|u| u.id), Some(alice.id));
+        assert!(identities.user("alice@work.example").is_none());
+
+        // Tokens only verify for the user they were issued to, and only once
+        assert!(service.verify_alias(mallory.id, &token).await.unwrap().is_none());
+        assert!(service.verify_alias(alice.id, &token).await.unwrap().unwrap().verified);
+        assert!(service.verify_alias(alice.id, &token).await.unwrap().is_none());
+
+        let identities = service.resolve(emails).await.unwrap();
+        assert_eq!(identities.user("Alice@Work.Example").map(|u| u.id), Some(alice.id));
+        assert!(identities.user("nobody@example.com").is_none());
+        assert_eq!(
+            identities.commit_user("Alice <alice@work.example> 1700000000 +0000"),
+            Some(CommitUser {
+                id: alice.id,
+                username: "alice".to_string(),
+                avatar_url: "/api/users/alice/avatar?v=0".to_string(),
+            })
+        );
+
+        // Only users hiding their email get it replaced
+        let text = "Alice <alice@work.example>, Bob <bob@example.com>";
+        assert_eq!(identities.redact(text, "git.example"), text);
+        let alice = users.set_hide_email(alice.id, true).await.unwrap().unwrap();
+        assert!(alice.hide_email);
+        let identities = service.resolve(emails).await.unwrap();
+        assert_eq!(
+            identities.redact(text, "git.example"),
+            "Alice <alice@users.noreply.git.example>, Bob <bob@example.com>"
+        );
+
+        assert!(!service.remove_alias(mallory.id, alias.id).await.unwrap());
+        assert!(service.remove_alias(alice.id, alias.id).await.unwrap());
+        assert!(service.list_aliases(alice.id).await.unwrap().is_empty());
+    }
+}
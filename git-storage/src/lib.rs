@@ -1,37 +1,95 @@
+pub mod avatars;
+pub mod backfill;
+pub mod check;
+pub mod dialect;
+pub mod diff;
 pub mod entities;
+pub mod error;
+pub mod events;
+pub mod identities;
+pub mod idempotency;
+pub mod migration_lock;
 pub mod migrations;
+pub mod projections;
 pub mod repository;
+pub mod retention;
+pub mod ssh_keys;
+pub mod statuses;
+pub mod tokens;
 pub mod user;
 pub mod git_ops;
+pub mod transfer;
+pub mod write_coordinator;
 
 use anyhow::Result;
-use sea_orm::{Database, DatabaseConnection};
+use sea_orm::{Database, DatabaseConnection, SqlxSqliteConnector};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use std::str::FromStr;
+use std::time::Duration;
 
+pub use avatars::*;
+pub use backfill::*;
+pub use check::*;
+pub use dialect::*;
+pub use error::*;
+pub use events::*;
+pub use identities::*;
+pub use idempotency::*;
+pub use migration_lock::*;
+pub use projections::*;
 pub use repository::*;
+pub use retention::*;
+pub use ssh_keys::*;
+pub use statuses::*;
+pub use tokens::*;
 pub use user::*;
 pub use git_ops::*;
+pub use write_coordinator::*;
+
+/// Default time SQLite waits on a locked database before returning SQLITE_BUSY
+pub const DEFAULT_SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Initialize the database connection
 pub async fn init_db(database_url: &str) -> Result<DatabaseConnection> {
+    init_db_with_busy_timeout(database_url, DEFAULT_SQLITE_BUSY_TIMEOUT).await
+}
+
+/// Initialize the database connection, setting SQLite's busy_timeout. SQLite and
+/// Postgres URLs are accepted, anything else is `StorageError::UnsupportedDatabase`.
+pub async fn init_db_with_busy_timeout(
+    database_url: &str,
+    busy_timeout: Duration,
+) -> Result<DatabaseConnection> {
+    if database_url.starts_with("sqlite:") {
+        let options = SqliteConnectOptions::from_str(database_url)?.busy_timeout(busy_timeout);
+        // Match SeaORM's default of a single pooled connection for SQLite
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?;
+        return Ok(SqlxSqliteConnector::from_sqlx_sqlite_pool(pool));
+    }
+
+    let scheme = database_url.split(':').next().unwrap_or_default();
+    if !matches!(scheme, "postgres" | "postgresql") {
+        return Err(StorageError::UnsupportedDatabase { scheme: scheme.to_string() }.into());
+    }
     let db = Database::connect(database_url).await?;
     Ok(db)
 }
 
-/// Run database migrations
+/// Run pending database migrations under the migration lock, see `run_migrations_locked`
 pub async fn run_migrations(db: &DatabaseConnection) -> Result<()> {
-    use migrations::Migrator;
-    use sea_orm_migration::MigratorTrait;
-    
-    Migrator::up(db, None).await?;
-    Ok(())
+    run_migrations_locked(db, DEFAULT_MIGRATION_LOCK_TIMEOUT).await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::entities::git_object::ObjectKind;
     use crate::entities::{branch, commit, tag, tree};
     use chrono::Utc;
-    use sea_orm::{EntityTrait, ActiveModelTrait, Set, ColumnTrait, QueryFilter};
+    use sea_orm::{EntityTrait, ActiveModelTrait, Set};
     use uuid::Uuid;
 
     #[tokio::test]
@@ -75,7 +133,7 @@ mod tests {
             repository_id: Set(repo_id),
             name: Set("v1.0.0".to_string()),
             target_id: Set("commit123".to_string()),
-            target_type: Set("commit".to_string()),
+            target_type: Set(ObjectKind::Commit),
             tag_object_id: Set(None),
             tagger_name: Set(Some("Test Tagger".to_string())),
             tagger_email: Set(Some("tagger@test.com".to_string())),
@@ -98,7 +156,6 @@ mod tests {
         };
 
         // If we get here without compiler errors, the entities are correctly defined
-        assert!(true);
     }
 
     #[tokio::test]
@@ -108,7 +165,79 @@ mod tests {
         run_migrations(&db).await.unwrap();
 
         // If we get here, the migrations worked
-        assert!(true);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_database_urls_are_refused() {
+        let err = init_db("mysql://root@localhost/git").await.unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<StorageError>(), Some(StorageError::UnsupportedDatabase { scheme }) if scheme == "mysql"),
+            "{}",
+            err
+        );
+        let db = init_db("sqlite::memory:").await.unwrap();
+        assert_eq!(Dialect::of(&db).backend(), sea_orm::DatabaseBackend::Sqlite);
+    }
+
+    #[tokio::test]
+    async fn test_visibility_migration_maps_is_private() {
+        use migrations::Migrator;
+        use sea_orm::{ConnectionTrait, Statement};
+        use sea_orm_migration::MigratorTrait;
+
+        let db = init_db("sqlite::memory:").await.unwrap();
+        let migrations = Migrator::migrations();
+        let before_visibility = migrations
+            .iter()
+            .position(|m| m.name() == "m20240121_000001_add_repository_visibility")
+            .unwrap() as u32;
+        Migrator::up(&db, Some(before_visibility)).await.unwrap();
+        let sql = |sql: &str| Statement::from_string(sea_orm::DatabaseBackend::Sqlite, sql.to_string());
+        db.execute(sql("PRAGMA foreign_keys = OFF")).await.unwrap();
+        for (name, is_private) in [("open", 0), ("closed", 1)] {
+            db.execute(sql(&format!(
+                "INSERT INTO repositories (id, name, default_branch, owner_id, is_private, created_at, updated_at) \
+                 VALUES ('{}', '{}', 'main', '{}', {}, '2024-01-01T00:00:00+00:00', '2024-01-01T00:00:00+00:00')",
+                Uuid::new_v4(),
+                name,
+                Uuid::new_v4(),
+                is_private
+            )))
+            .await
+            .unwrap();
+        }
+
+        Migrator::up(&db, None).await.unwrap();
+        let levels: Vec<(String, String)> = db
+            .query_all(sql("SELECT name, visibility FROM repositories ORDER BY name"))
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| (row.try_get("", "name").unwrap(), row.try_get("", "visibility").unwrap()))
+            .collect();
+        assert_eq!(
+            levels,
+            [("closed".to_string(), "private".to_string()), ("open".to_string(), "public".to_string())]
+        );
+        assert!(db
+            .execute(sql("UPDATE repositories SET visibility = 'secret' WHERE name = 'open'"))
+            .await
+            .is_err());
+
+        // Rolling back can only say private for an internal repository
+        db.execute(sql("UPDATE repositories SET visibility = 'internal' WHERE name = 'open'"))
+            .await
+            .unwrap();
+        Migrator::down(&db, Some(migrations.len() as u32 - before_visibility)).await.unwrap();
+        let rows = db
+            .query_all(sql("SELECT name, is_private FROM repositories ORDER BY name"))
+            .await
+            .unwrap();
+        let flags: Vec<(String, bool)> = rows
+            .iter()
+            .map(|row| (row.try_get("", "name").unwrap(), row.try_get("", "is_private").unwrap()))
+            .collect();
+        assert_eq!(flags, [("closed".to_string(), true), ("open".to_string(), true)]);
     }
 
     #[tokio::test]
@@ -182,7 +311,7 @@ mod tests {
             repository_id: Set(fake_repo_id),
             name: Set("v1.0.0".to_string()),
             target_id: Set("commit123abcdef".to_string()),
-            target_type: Set("commit".to_string()),
+            target_type: Set(ObjectKind::Commit),
             tag_object_id: Set(Some("tag123abcdef".to_string())),
             tagger_name: Set(Some("Test Tagger".to_string())),
             tagger_email: Set(Some("tagger@test.com".to_string())),
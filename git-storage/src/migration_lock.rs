@@ -0,0 +1,260 @@
+use crate::error::StorageError;
+use crate::migrations::Migrator;
+use anyhow::Result;
+use chrono::Utc;
+use sea_orm::sea_query::{self, ColumnDef, Expr, Iden, Query, Table};
+use sea_orm::{
+    ConnectionTrait, DatabaseBackend, DatabaseConnection, DatabaseTransaction, SqlErr, Statement,
+    TransactionTrait,
+};
+use sea_orm_migration::MigratorTrait;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How long `run_migrations` waits for another instance to finish migrating
+pub const DEFAULT_MIGRATION_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Key of the Postgres advisory lock held while migrating
+const ADVISORY_LOCK_KEY: i64 = 0x6769_745f_6d69_6772;
+
+const LOCK_ROW: i32 = 1;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Who holds the migration lock. The row lives in `migration_lock`, outside the
+/// migrations themselves, and is removed once every pending migration applied.
+/// A row left behind by a run that failed or died marks the schema as possibly
+/// half-migrated; it has to be checked and the row deleted by hand.
+#[derive(Iden)]
+enum MigrationLock {
+    Table,
+    Id,
+    Holder,
+    ClaimedAt,
+    /// The migration being applied
+    Applying,
+    /// Why that migration failed
+    Failure,
+}
+
+struct LockRow {
+    holder: String,
+    claimed_at: String,
+    applying: Option<String>,
+    failure: Option<String>,
+}
+
+/// Apply pending migrations while holding the migration lock, so instances
+/// starting together migrate once: the others wait, then find nothing to do.
+/// Postgres serializes on an advisory lock, SQLite on claiming the lock row.
+pub async fn run_migrations_locked(db: &DatabaseConnection, lock_timeout: Duration) -> Result<()> {
+    let backend = db.get_database_backend();
+    let advisory = match backend {
+        DatabaseBackend::Postgres => Some(advisory_lock(db).await?),
+        _ => None,
+    };
+
+    ensure_lock_table(db).await?;
+    let holder = format!("pid {} ({})", std::process::id(), Uuid::new_v4());
+    claim(db, &holder, lock_timeout, advisory.is_some()).await?;
+
+    for migration in Migrator::get_pending_migrations(db).await? {
+        set_lock_column(db, &holder, MigrationLock::Applying, migration.name()).await?;
+        if let Err(e) = Migrator::up(db, Some(1)).await {
+            // The row stays behind, refusing later runs until someone has looked
+            set_lock_column(db, &holder, MigrationLock::Failure, &e.to_string()).await?;
+            return Err(StorageError::MigrationFailed {
+                migration: migration.name().to_string(),
+                error: e.to_string(),
+            }
+            .into());
+        }
+    }
+
+    let release = Query::delete()
+        .from_table(MigrationLock::Table)
+        .and_where(Expr::col(MigrationLock::Id).eq(LOCK_ROW))
+        .and_where(Expr::col(MigrationLock::Holder).eq(holder))
+        .to_owned();
+    db.execute(backend.build(&release)).await?;
+    if let Some(advisory) = advisory {
+        advisory.commit().await?;
+    }
+    Ok(())
+}
+
+/// Names of migrations not applied yet, for instances that don't migrate on start
+pub async fn pending_migrations(db: &DatabaseConnection) -> Result<Vec<String>> {
+    Ok(Migrator::get_pending_migrations(db)
+        .await?
+        .iter()
+        .map(|migration| migration.name().to_string())
+        .collect())
+}
+
+/// A transaction holding the advisory lock until it ends; migrations run on
+/// other pooled connections meanwhile
+async fn advisory_lock(db: &DatabaseConnection) -> Result<DatabaseTransaction> {
+    let txn = db.begin().await?;
+    txn.execute(Statement::from_string(
+        DatabaseBackend::Postgres,
+        format!("SELECT pg_advisory_xact_lock({})", ADVISORY_LOCK_KEY),
+    ))
+    .await?;
+    Ok(txn)
+}
+
+async fn ensure_lock_table(db: &DatabaseConnection) -> Result<()> {
+    let create = Table::create()
+        .table(MigrationLock::Table)
+        .if_not_exists()
+        .col(ColumnDef::new(MigrationLock::Id).integer().not_null().primary_key())
+        .col(ColumnDef::new(MigrationLock::Holder).string().not_null())
+        .col(ColumnDef::new(MigrationLock::ClaimedAt).string().not_null())
+        .col(ColumnDef::new(MigrationLock::Applying).string())
+        .col(ColumnDef::new(MigrationLock::Failure).text())
+        .to_owned();
+    db.execute(db.get_database_backend().build(&create)).await?;
+    Ok(())
+}
+
+/// Insert the lock row, waiting up to `timeout` for a live holder to remove
+/// theirs. Under the advisory lock no other instance is migrating, so an
+/// existing row is always one left behind.
+async fn claim(db: &DatabaseConnection, holder: &str, timeout: Duration, exclusive: bool) -> Result<()> {
+    let backend = db.get_database_backend();
+    let insert = Query::insert()
+        .into_table(MigrationLock::Table)
+        .columns([MigrationLock::Id, MigrationLock::Holder, MigrationLock::ClaimedAt])
+        .values_panic([LOCK_ROW.into(), holder.into(), Utc::now().to_rfc3339().into()])
+        .to_owned();
+
+    let started = Instant::now();
+    loop {
+        match db.execute(backend.build(&insert)).await {
+            Ok(_) => return Ok(()),
+            Err(e) if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // Gone between the insert and this read: try again straight away
+        let Some(row) = lock_row(db).await? else {
+            continue;
+        };
+        if let Some(error) = row.failure {
+            return Err(StorageError::MigrationFailed {
+                migration: row.applying.unwrap_or_default(),
+                error,
+            }
+            .into());
+        }
+        if exclusive || started.elapsed() >= timeout {
+            return Err(StorageError::MigrationLockHeld {
+                holder: row.holder,
+                since: row.claimed_at,
+                applying: row.applying,
+            }
+            .into());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn lock_row(db: &DatabaseConnection) -> Result<Option<LockRow>> {
+    let select = Query::select()
+        .columns([
+            MigrationLock::Holder,
+            MigrationLock::ClaimedAt,
+            MigrationLock::Applying,
+            MigrationLock::Failure,
+        ])
+        .from(MigrationLock::Table)
+        .and_where(Expr::col(MigrationLock::Id).eq(LOCK_ROW))
+        .to_owned();
+    let Some(row) = db.query_one(db.get_database_backend().build(&select)).await? else {
+        return Ok(None);
+    };
+    Ok(Some(LockRow {
+        holder: row.try_get("", "holder")?,
+        claimed_at: row.try_get("", "claimed_at")?,
+        applying: row.try_get("", "applying")?,
+        failure: row.try_get("", "failure")?,
+    }))
+}
+
+async fn set_lock_column(db: &DatabaseConnection, holder: &str, column: MigrationLock, value: &str) -> Result<()> {
+    let update = Query::update()
+        .table(MigrationLock::Table)
+        .value(column, value)
+        .and_where(Expr::col(MigrationLock::Id).eq(LOCK_ROW))
+        .and_where(Expr::col(MigrationLock::Holder).eq(holder))
+        .to_owned();
+    db.execute(db.get_database_backend().build(&update)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_db;
+
+    async fn applied(db: &DatabaseConnection) -> i64 {
+        let count = db
+            .query_one(Statement::from_string(
+                DatabaseBackend::Sqlite,
+                "SELECT COUNT(*) AS n FROM seaql_migrations".to_string(),
+            ))
+            .await
+            .unwrap()
+            .unwrap();
+        count.try_get("", "n").unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_runs_migrate_once() {
+        let db = init_db("sqlite::memory:").await.unwrap();
+
+        let (first, second) = tokio::join!(
+            run_migrations_locked(&db, DEFAULT_MIGRATION_LOCK_TIMEOUT),
+            run_migrations_locked(&db, DEFAULT_MIGRATION_LOCK_TIMEOUT),
+        );
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(applied(&db).await, Migrator::migrations().len() as i64);
+        assert!(pending_migrations(&db).await.unwrap().is_empty());
+        assert!(lock_row(&db).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_left_over_lock_rows_stop_migrations() {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        ensure_lock_table(&db).await.unwrap();
+        claim(&db, "crashed", Duration::ZERO, false).await.unwrap();
+        set_lock_column(&db, "crashed", MigrationLock::Applying, "m_half_done").await.unwrap();
+
+        // A holder that never lets go is reported once the wait runs out ...
+        let err = run_migrations_locked(&db, Duration::from_millis(100)).await.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<StorageError>(),
+                Some(StorageError::MigrationLockHeld { holder, applying: Some(applying), .. })
+                    if holder == "crashed" && applying == "m_half_done"
+            ),
+            "{}",
+            err
+        );
+
+        // ... and one whose migration failed is reported straight away
+        set_lock_column(&db, "crashed", MigrationLock::Failure, "table already exists").await.unwrap();
+        let err = run_migrations_locked(&db, DEFAULT_MIGRATION_LOCK_TIMEOUT).await.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<StorageError>(),
+                Some(StorageError::MigrationFailed { migration, error })
+                    if migration == "m_half_done" && error == "table already exists"
+            ),
+            "{}",
+            err
+        );
+        assert_eq!(pending_migrations(&db).await.unwrap().len(), Migrator::migrations().len());
+    }
+}
@@ -38,6 +38,5 @@ impl MigrationTrait for Migration {
 #[derive(Iden)]
 enum GitObject {
     Table,
-    Content,
     BlobPath,
 }
\ No newline at end of file
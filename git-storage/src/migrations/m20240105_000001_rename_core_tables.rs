@@ -0,0 +1,45 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// The initial migration derived table names from the `Iden` enums, which
+/// produced singular names that don't match the entity definitions.
+const RENAMES: [(&str, &str); 3] = [
+    ("repository", "repositories"),
+    ("git_object", "git_objects"),
+    ("git_ref", "git_refs"),
+];
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for (from, to) in RENAMES {
+            if manager.has_table(from).await? && !manager.has_table(to).await? {
+                manager
+                    .rename_table(
+                        Table::rename()
+                            .table(Alias::new(from), Alias::new(to))
+                            .to_owned(),
+                    )
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for (from, to) in RENAMES {
+            manager
+                .rename_table(
+                    Table::rename()
+                        .table(Alias::new(to), Alias::new(from))
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Results of create requests, keyed by the client's Idempotency-Key
+        manager
+            .create_table(
+                Table::create()
+                    .table(IdempotencyKeys::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(IdempotencyKeys::Id).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(IdempotencyKeys::Scope).string().not_null())
+                    .col(ColumnDef::new(IdempotencyKeys::Key).string().not_null())
+                    .col(ColumnDef::new(IdempotencyKeys::ResourceId).string().not_null())
+                    .col(ColumnDef::new(IdempotencyKeys::ResponseStatus).integer().not_null())
+                    .col(ColumnDef::new(IdempotencyKeys::ResponseBody).text().not_null())
+                    .col(ColumnDef::new(IdempotencyKeys::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(IdempotencyKeys::ExpiresAt).timestamp_with_time_zone().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_idempotency_keys_scope_key")
+                    .table(IdempotencyKeys::Table)
+                    .col(IdempotencyKeys::Scope)
+                    .col(IdempotencyKeys::Key)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(IdempotencyKeys::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum IdempotencyKeys {
+    Table,
+    Id,
+    Scope,
+    Key,
+    ResourceId,
+    ResponseStatus,
+    ResponseBody,
+    CreatedAt,
+    ExpiresAt,
+}
@@ -0,0 +1,74 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // One row per push or fetch, with the client that made it
+        manager
+            .create_table(
+                Table::create()
+                    .table(RepositoryEvents::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(RepositoryEvents::Id).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(RepositoryEvents::RepositoryId).uuid().not_null())
+                    .col(ColumnDef::new(RepositoryEvents::EventType).string().not_null())
+                    .col(ColumnDef::new(RepositoryEvents::Transport).string().not_null())
+                    .col(ColumnDef::new(RepositoryEvents::ProtocolVersion).integer().not_null())
+                    .col(ColumnDef::new(RepositoryEvents::Agent).string())
+                    .col(ColumnDef::new(RepositoryEvents::UserAgent).string())
+                    .col(ColumnDef::new(RepositoryEvents::CreatedAt).timestamp_with_time_zone().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-repository-events-repository")
+                            .from(RepositoryEvents::Table, RepositoryEvents::RepositoryId)
+                            .to(Repositories::Table, Repositories::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_repository_events_repository_created")
+                    .table(RepositoryEvents::Table)
+                    .col(RepositoryEvents::RepositoryId)
+                    .col(RepositoryEvents::CreatedAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(RepositoryEvents::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum RepositoryEvents {
+    Table,
+    Id,
+    RepositoryId,
+    EventType,
+    Transport,
+    ProtocolVersion,
+    Agent,
+    UserAgent,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    Id,
+}
@@ -0,0 +1,102 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(RepositoryTopics::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(RepositoryTopics::Id).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(RepositoryTopics::RepositoryId).uuid().not_null())
+                    .col(ColumnDef::new(RepositoryTopics::Topic).string().not_null())
+                    .col(ColumnDef::new(RepositoryTopics::CreatedAt).timestamp_with_time_zone().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-repository-topics-repository")
+                            .from(RepositoryTopics::Table, RepositoryTopics::RepositoryId)
+                            .to(Repositories::Table, Repositories::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_repository_topics_repository_topic")
+                    .table(RepositoryTopics::Table)
+                    .col(RepositoryTopics::RepositoryId)
+                    .col(RepositoryTopics::Topic)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        // Listing by topic looks rows up by topic alone
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_repository_topics_topic")
+                    .table(RepositoryTopics::Table)
+                    .col(RepositoryTopics::Topic)
+                    .to_owned(),
+            )
+            .await?;
+
+        // Events that don't come from the git protocol carry their details here
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RepositoryEvents::Table)
+                    .add_column(ColumnDef::new(RepositoryEvents::Payload).text())
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RepositoryEvents::Table)
+                    .drop_column(RepositoryEvents::Payload)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(RepositoryTopics::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum RepositoryTopics {
+    Table,
+    Id,
+    RepositoryId,
+    Topic,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum RepositoryEvents {
+    Table,
+    Payload,
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    Id,
+}
@@ -0,0 +1,107 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // One row per ref change; a missing old or new target is a create or delete
+        manager
+            .create_table(
+                Table::create()
+                    .table(RefLog::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(RefLog::Id).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(RefLog::RepositoryId).uuid().not_null())
+                    .col(ColumnDef::new(RefLog::RefName).string().not_null())
+                    .col(ColumnDef::new(RefLog::OldTarget).string())
+                    .col(ColumnDef::new(RefLog::NewTarget).string())
+                    .col(ColumnDef::new(RefLog::CreatedAt).timestamp_with_time_zone().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-ref-log-repository")
+                            .from(RefLog::Table, RefLog::RepositoryId)
+                            .to(Repositories::Table, Repositories::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_ref_log_repository_ref_created")
+                    .table(RefLog::Table)
+                    .col(RefLog::RepositoryId)
+                    .col(RefLog::RefName)
+                    .col(RefLog::CreatedAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        // Age-based pruning scans by time across repositories
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_ref_log_created")
+                    .table(RefLog::Table)
+                    .col(RefLog::CreatedAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_repository_events_created")
+                    .table(RepositoryEvents::Table)
+                    .col(RepositoryEvents::CreatedAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_repository_events_created")
+                    .table(RepositoryEvents::Table)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(RefLog::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum RefLog {
+    Table,
+    Id,
+    RepositoryId,
+    RefName,
+    OldTarget,
+    NewTarget,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum RepositoryEvents {
+    Table,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    Id,
+}
@@ -0,0 +1,73 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Personal access tokens; only a hash of the secret is kept
+        manager
+            .create_table(
+                Table::create()
+                    .table(AccessTokens::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(AccessTokens::Id).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(AccessTokens::UserId).uuid().not_null())
+                    .col(ColumnDef::new(AccessTokens::Name).string().not_null())
+                    .col(ColumnDef::new(AccessTokens::TokenHash).string().not_null().unique_key())
+                    .col(ColumnDef::new(AccessTokens::Scopes).string().not_null())
+                    .col(ColumnDef::new(AccessTokens::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(AccessTokens::LastUsedAt).timestamp_with_time_zone())
+                    .col(ColumnDef::new(AccessTokens::ExpiresAt).timestamp_with_time_zone())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-access-tokens-user")
+                            .from(AccessTokens::Table, AccessTokens::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_access_tokens_user")
+                    .table(AccessTokens::Table)
+                    .col(AccessTokens::UserId)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AccessTokens::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum AccessTokens {
+    Table,
+    Id,
+    UserId,
+    Name,
+    TokenHash,
+    Scopes,
+    CreatedAt,
+    LastUsedAt,
+    ExpiresAt,
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
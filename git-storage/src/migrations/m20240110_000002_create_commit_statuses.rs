@@ -0,0 +1,80 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Commit statuses reported by CI and other automations, one per context
+        manager
+            .create_table(
+                Table::create()
+                    .table(CommitStatuses::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(CommitStatuses::Id).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(CommitStatuses::RepositoryId).uuid().not_null())
+                    .col(ColumnDef::new(CommitStatuses::CommitId).string().not_null())
+                    .col(ColumnDef::new(CommitStatuses::State).string().not_null())
+                    .col(ColumnDef::new(CommitStatuses::Context).string().not_null())
+                    .col(ColumnDef::new(CommitStatuses::Description).string())
+                    .col(ColumnDef::new(CommitStatuses::TargetUrl).string())
+                    .col(ColumnDef::new(CommitStatuses::CreatorId).uuid())
+                    .col(ColumnDef::new(CommitStatuses::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(CommitStatuses::UpdatedAt).timestamp_with_time_zone().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-commit-statuses-repository")
+                            .from(CommitStatuses::Table, CommitStatuses::RepositoryId)
+                            .to(Repositories::Table, Repositories::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_commit_statuses_commit_context")
+                    .table(CommitStatuses::Table)
+                    .col(CommitStatuses::RepositoryId)
+                    .col(CommitStatuses::CommitId)
+                    .col(CommitStatuses::Context)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(CommitStatuses::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum CommitStatuses {
+    Table,
+    Id,
+    RepositoryId,
+    CommitId,
+    State,
+    Context,
+    Description,
+    TargetUrl,
+    CreatorId,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    Id,
+}
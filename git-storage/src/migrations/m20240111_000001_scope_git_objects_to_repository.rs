@@ -0,0 +1,20 @@
+use super::rebuild_git_objects::rebuild_git_objects;
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// `git_objects` was keyed by object id alone, so an object could only live in
+/// one repository and lookups crossed repository boundaries. SQLite can't change
+/// a primary key in place, so the table is rebuilt keyed by (repository_id, id).
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        rebuild_git_objects(manager, true, None).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Fails if the same object has since been stored in more than one repository
+        rebuild_git_objects(manager, false, None).await
+    }
+}
@@ -0,0 +1,40 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Bumped on every settings change; updates must name the version they read
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repositories::Table)
+                    .add_column(ColumnDef::new(Repositories::Version).integer().not_null().default(1))
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repositories::Table)
+                    .drop_column(Repositories::Version)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    Version,
+}
@@ -0,0 +1,84 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Blob files were already laid out by SHA alone, but each row stored the full path
+/// (storage root included) and nothing tracked how many repositories shared a file.
+/// Rows now store the key relative to the storage root, and `blob_refs` counts the
+/// repositories holding each blob so a file is only removed once none do.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(BlobRefs::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(BlobRefs::BlobId).string().not_null().primary_key())
+                    .col(ColumnDef::new(BlobRefs::RefCount).big_integer().not_null())
+                    .col(ColumnDef::new(BlobRefs::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(BlobRefs::UpdatedAt).timestamp_with_time_zone().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        let count = Query::insert()
+            .into_table(BlobRefs::Table)
+            .columns([BlobRefs::BlobId, BlobRefs::RefCount, BlobRefs::CreatedAt, BlobRefs::UpdatedAt])
+            .select_from(
+                Query::select()
+                    .column(GitObjects::Id)
+                    .expr(Expr::col(GitObjects::RepositoryId).count())
+                    .expr(Expr::col(GitObjects::CreatedAt).min())
+                    .expr(Expr::col(GitObjects::CreatedAt).max())
+                    .from(GitObjects::Table)
+                    .and_where(Expr::col(GitObjects::BlobPath).is_not_null())
+                    .group_by_col(GitObjects::Id)
+                    .to_owned(),
+            )
+            .map_err(|e| DbErr::Migration(e.to_string()))?
+            .to_owned();
+        manager.exec_stmt(count).await?;
+
+        // `<root>/ab/cdef...` becomes `ab/cdef...`; the files themselves already sit there
+        let relative = Query::update()
+            .table(GitObjects::Table)
+            .value(
+                GitObjects::BlobPath,
+                Expr::cust("substr(id, 1, 2) || '/' || substr(id, 3)"),
+            )
+            .and_where(Expr::col(GitObjects::BlobPath).is_not_null())
+            .to_owned();
+        manager.exec_stmt(relative).await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Blob paths stay relative: the storage root isn't known here
+        manager
+            .drop_table(Table::drop().table(BlobRefs::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum BlobRefs {
+    Table,
+    BlobId,
+    RefCount,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(Iden)]
+enum GitObjects {
+    Table,
+    Id,
+    RepositoryId,
+    CreatedAt,
+    BlobPath,
+}
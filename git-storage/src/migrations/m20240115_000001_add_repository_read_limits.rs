@@ -0,0 +1,55 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Per-repository overrides of the server-wide read guardrails; NULL keeps the default.
+        // SQLite only adds one column per ALTER TABLE.
+        for column in [
+            Repositories::MaxCommitsWalked,
+            Repositories::MaxTreeEntries,
+            Repositories::ReadTimeoutSecs,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Repositories::Table)
+                        .add_column(ColumnDef::new(column).integer())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            Repositories::MaxCommitsWalked,
+            Repositories::MaxTreeEntries,
+            Repositories::ReadTimeoutSecs,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Repositories::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    MaxCommitsWalked,
+    MaxTreeEntries,
+    ReadTimeoutSecs,
+}
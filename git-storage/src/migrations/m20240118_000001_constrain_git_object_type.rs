@@ -0,0 +1,37 @@
+use super::rebuild_git_objects::rebuild_git_objects;
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+const OBJECT_TYPES: [&str; 4] = ["commit", "tree", "blob", "tag"];
+
+/// Limits `git_objects.object_type` to the four object types with a CHECK
+/// constraint. SQLite can't add a constraint to an existing table, so the table
+/// is rebuilt. Types differing only in case are lowercased first; any other
+/// value fails the copy and has to be repaired by hand.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(GitObjects::Table)
+                    .value(GitObjects::ObjectType, Func::lower(Expr::col(GitObjects::ObjectType)))
+                    .to_owned(),
+            )
+            .await?;
+
+        rebuild_git_objects(manager, true, Some(&OBJECT_TYPES)).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        rebuild_git_objects(manager, true, None).await
+    }
+}
+
+#[derive(Iden)]
+enum GitObjects {
+    Table,
+    ObjectType,
+}
@@ -0,0 +1,85 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+const VISIBILITIES: [&str; 3] = ["public", "internal", "private"];
+
+/// Replaces `repositories.is_private` with a `visibility` level: private
+/// repositories stay private and the rest become public. Rolling back turns
+/// internal repositories private, the closest the flag can say.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repositories::Table)
+                    .add_column(
+                        ColumnDef::new(Repositories::Visibility)
+                            .string()
+                            .not_null()
+                            .default("public")
+                            .check(Expr::col(Repositories::Visibility).is_in(VISIBILITIES)),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(Repositories::Table)
+                    .value(Repositories::Visibility, "private")
+                    .and_where(Expr::col(Repositories::IsPrivate).eq(true))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repositories::Table)
+                    .drop_column(Repositories::IsPrivate)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repositories::Table)
+                    .add_column(ColumnDef::new(Repositories::IsPrivate).boolean().not_null().default(false))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(Repositories::Table)
+                    .value(Repositories::IsPrivate, true)
+                    .and_where(Expr::col(Repositories::Visibility).ne("public"))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repositories::Table)
+                    .drop_column(Repositories::Visibility)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    IsPrivate,
+    Visibility,
+}
@@ -0,0 +1,41 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // The user behind an event, shown in the activity feed; unknown for
+        // anonymous requests and for events recorded before this column
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RepositoryEvents::Table)
+                    .add_column(ColumnDef::new(RepositoryEvents::ActorId).uuid())
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RepositoryEvents::Table)
+                    .drop_column(RepositoryEvents::ActorId)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum RepositoryEvents {
+    Table,
+    ActorId,
+}
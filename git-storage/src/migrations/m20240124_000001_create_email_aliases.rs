@@ -0,0 +1,90 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Users who'd rather their email not appear in API responses get a
+        // noreply address in its place
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(ColumnDef::new(User::HideEmail).boolean().not_null().default(false))
+                    .to_owned(),
+            )
+            .await?;
+
+        // More addresses a user commits under; only verified ones map commits to
+        // the user, and only a hash of the pending verification token is kept
+        manager
+            .create_table(
+                Table::create()
+                    .table(EmailAliases::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(EmailAliases::Id).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(EmailAliases::UserId).uuid().not_null())
+                    .col(ColumnDef::new(EmailAliases::Email).string().not_null().unique_key())
+                    .col(ColumnDef::new(EmailAliases::Verified).boolean().not_null().default(false))
+                    .col(ColumnDef::new(EmailAliases::VerificationTokenHash).string().unique_key())
+                    .col(ColumnDef::new(EmailAliases::CreatedAt).timestamp_with_time_zone().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-email-aliases-user")
+                            .from(EmailAliases::Table, EmailAliases::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_email_aliases_user")
+                    .table(EmailAliases::Table)
+                    .col(EmailAliases::UserId)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(EmailAliases::Table).to_owned())
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(User::HideEmail)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum EmailAliases {
+    Table,
+    Id,
+    UserId,
+    Email,
+    Verified,
+    VerificationTokenHash,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+    HideEmail,
+}
@@ -0,0 +1,97 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Per-user quotas on repository count and disk use. Usage is the sum of each
+/// owned repository's `size_bytes`, kept up to date as objects are stored so
+/// quota checks never scan objects; existing repositories are filled in here.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repositories::Table)
+                    .add_column(ColumnDef::new(Repositories::SizeBytes).big_integer().not_null().default(0))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(Repositories::Table)
+                    .value(
+                        Repositories::SizeBytes,
+                        Expr::cust(
+                            "(SELECT COALESCE(SUM(size), 0) FROM git_objects \
+                             WHERE git_objects.repository_id = repositories.id)",
+                        ),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // Admin overrides of the server-wide quotas, `NULL` for the server setting
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(ColumnDef::new(User::MaxRepositories).integer())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(ColumnDef::new(User::MaxTotalBytes).big_integer())
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(User::MaxTotalBytes)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(User::MaxRepositories)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repositories::Table)
+                    .drop_column(Repositories::SizeBytes)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    SizeBytes,
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    MaxRepositories,
+    MaxTotalBytes,
+}
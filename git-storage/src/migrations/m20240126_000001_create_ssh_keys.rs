@@ -0,0 +1,72 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Public keys users sign in over SSH with. A key identifies one account,
+        // so its fingerprint is unique across all users
+        manager
+            .create_table(
+                Table::create()
+                    .table(SshKeys::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(SshKeys::Id).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(SshKeys::UserId).uuid().not_null())
+                    .col(ColumnDef::new(SshKeys::KeyType).string().not_null())
+                    .col(ColumnDef::new(SshKeys::PublicKey).text().not_null())
+                    .col(ColumnDef::new(SshKeys::Fingerprint).string().not_null().unique_key())
+                    .col(ColumnDef::new(SshKeys::Comment).string())
+                    .col(ColumnDef::new(SshKeys::CreatedAt).timestamp_with_time_zone().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-ssh-keys-user")
+                            .from(SshKeys::Table, SshKeys::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_ssh_keys_user")
+                    .table(SshKeys::Table)
+                    .col(SshKeys::UserId)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(SshKeys::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum SshKeys {
+    Table,
+    Id,
+    UserId,
+    KeyType,
+    PublicKey,
+    Fingerprint,
+    Comment,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
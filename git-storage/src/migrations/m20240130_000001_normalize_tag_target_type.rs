@@ -0,0 +1,31 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Tag target types are now read back as an enum that only accepts the four
+/// lowercase names. Older rows written with other casing are lowercased; any
+/// other value is left alone and reported as an unknown type when loaded.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(Tags::Table)
+                    .value(Tags::TargetType, Func::lower(Expr::col(Tags::TargetType)))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum Tags {
+    Table,
+    TargetType,
+}
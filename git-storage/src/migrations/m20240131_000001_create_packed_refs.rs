@@ -0,0 +1,56 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Cached packed-refs text per repository, tagged with the `ref_generation` it
+/// was built at, so ref advertisements read one row instead of every ref
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PackedRefs::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(PackedRefs::RepositoryId).uuid().not_null().primary_key())
+                    .col(ColumnDef::new(PackedRefs::RefGeneration).big_integer().not_null())
+                    .col(ColumnDef::new(PackedRefs::Content).text().not_null())
+                    .col(ColumnDef::new(PackedRefs::UpdatedAt).timestamp_with_time_zone().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-packed-refs-repository")
+                            .from(PackedRefs::Table, PackedRefs::RepositoryId)
+                            .to(Repositories::Table, Repositories::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PackedRefs::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum PackedRefs {
+    Table,
+    RepositoryId,
+    RefGeneration,
+    Content,
+    UpdatedAt,
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    Id,
+}
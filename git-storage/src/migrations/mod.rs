@@ -4,6 +4,36 @@ mod m20240101_000001_create_tables;
 mod m20240102_000001_add_users;
 mod m20240103_000001_update_git_objects;
 mod m20240104_000001_add_separate_git_tables;
+mod m20240105_000001_rename_core_tables;
+mod m20240106_000001_create_idempotency_keys;
+mod m20240107_000001_create_repository_events;
+mod m20240108_000001_create_repository_topics;
+mod m20240109_000001_create_ref_log;
+mod m20240110_000001_create_access_tokens;
+mod m20240110_000002_create_commit_statuses;
+mod m20240111_000001_scope_git_objects_to_repository;
+mod m20240112_000001_add_repository_version;
+mod m20240113_000001_add_hidden_ref_prefixes;
+mod m20240114_000001_create_blob_refs;
+mod m20240115_000001_add_repository_read_limits;
+mod m20240116_000001_add_repository_archived;
+mod m20240117_000001_add_repository_forked_from;
+mod m20240118_000001_constrain_git_object_type;
+mod m20240119_000001_add_repository_push_message;
+mod m20240120_000001_add_repository_ref_generation;
+mod m20240121_000001_add_repository_visibility;
+mod m20240122_000001_add_repository_linear_history_branches;
+mod m20240123_000001_add_repository_event_actor;
+mod m20240124_000001_create_email_aliases;
+mod m20240125_000001_add_quotas;
+mod m20240126_000001_create_ssh_keys;
+mod m20240127_000001_add_user_avatars;
+mod m20240128_000001_add_repository_allow_anonymous_clone;
+mod m20240129_000001_add_repository_deleted_at;
+mod m20240130_000001_normalize_tag_target_type;
+mod m20240131_000001_create_packed_refs;
+mod m20240201_000001_add_repository_object_format;
+mod rebuild_git_objects;
 
 pub struct Migrator;
 
@@ -15,6 +45,35 @@ impl MigratorTrait for Migrator {
             Box::new(m20240102_000001_add_users::Migration),
             Box::new(m20240103_000001_update_git_objects::Migration),
             Box::new(m20240104_000001_add_separate_git_tables::Migration),
+            Box::new(m20240105_000001_rename_core_tables::Migration),
+            Box::new(m20240106_000001_create_idempotency_keys::Migration),
+            Box::new(m20240107_000001_create_repository_events::Migration),
+            Box::new(m20240108_000001_create_repository_topics::Migration),
+            Box::new(m20240109_000001_create_ref_log::Migration),
+            Box::new(m20240110_000001_create_access_tokens::Migration),
+            Box::new(m20240110_000002_create_commit_statuses::Migration),
+            Box::new(m20240111_000001_scope_git_objects_to_repository::Migration),
+            Box::new(m20240112_000001_add_repository_version::Migration),
+            Box::new(m20240113_000001_add_hidden_ref_prefixes::Migration),
+            Box::new(m20240114_000001_create_blob_refs::Migration),
+            Box::new(m20240115_000001_add_repository_read_limits::Migration),
+            Box::new(m20240116_000001_add_repository_archived::Migration),
+            Box::new(m20240117_000001_add_repository_forked_from::Migration),
+            Box::new(m20240118_000001_constrain_git_object_type::Migration),
+            Box::new(m20240119_000001_add_repository_push_message::Migration),
+            Box::new(m20240120_000001_add_repository_ref_generation::Migration),
+            Box::new(m20240121_000001_add_repository_visibility::Migration),
+            Box::new(m20240122_000001_add_repository_linear_history_branches::Migration),
+            Box::new(m20240123_000001_add_repository_event_actor::Migration),
+            Box::new(m20240124_000001_create_email_aliases::Migration),
+            Box::new(m20240125_000001_add_quotas::Migration),
+            Box::new(m20240126_000001_create_ssh_keys::Migration),
+            Box::new(m20240127_000001_add_user_avatars::Migration),
+            Box::new(m20240128_000001_add_repository_allow_anonymous_clone::Migration),
+            Box::new(m20240129_000001_add_repository_deleted_at::Migration),
+            Box::new(m20240130_000001_normalize_tag_target_type::Migration),
+            Box::new(m20240131_000001_create_packed_refs::Migration),
+            Box::new(m20240201_000001_add_repository_object_format::Migration),
         ]
     }
 }
\ No newline at end of file
@@ -0,0 +1,93 @@
+use sea_orm_migration::prelude::*;
+
+/// Recreate `git_objects` with its rows copied over, for schema changes SQLite
+/// can't make in place. `per_repository` keys it by (repository_id, id) rather
+/// than id alone; `object_types` adds a CHECK constraint on `object_type`.
+pub(super) async fn rebuild_git_objects(
+    manager: &SchemaManager<'_>,
+    per_repository: bool,
+    object_types: Option<&[&str]>,
+) -> Result<(), DbErr> {
+    let mut object_type = ColumnDef::new(GitObjects::ObjectType);
+    object_type.string().not_null();
+    if let Some(object_types) = object_types {
+        object_type.check(Expr::col(GitObjects::ObjectType).is_in(object_types.iter().copied()));
+    }
+
+    let mut table = Table::create();
+    table
+        .table(GitObjectsRebuilt::Table)
+        .col(ColumnDef::new(GitObjects::Id).string().not_null())
+        .col(ColumnDef::new(GitObjects::RepositoryId).uuid().not_null())
+        .col(&mut object_type)
+        .col(ColumnDef::new(GitObjects::Size).big_integer().not_null())
+        .col(ColumnDef::new(GitObjects::Content).binary().not_null())
+        .col(ColumnDef::new(GitObjects::CreatedAt).timestamp_with_time_zone().not_null())
+        .col(ColumnDef::new(GitObjects::BlobPath).string())
+        .foreign_key(
+            ForeignKey::create()
+                .name("fk-gitobject-repository")
+                .from(GitObjectsRebuilt::Table, GitObjects::RepositoryId)
+                .to(Repositories::Table, Repositories::Id)
+                .on_delete(ForeignKeyAction::Cascade),
+        );
+    if per_repository {
+        table.primary_key(Index::create().col(GitObjects::RepositoryId).col(GitObjects::Id));
+    } else {
+        table.primary_key(Index::create().col(GitObjects::Id));
+    }
+    manager.create_table(table.to_owned()).await?;
+
+    let columns = [
+        GitObjects::Id,
+        GitObjects::RepositoryId,
+        GitObjects::ObjectType,
+        GitObjects::Size,
+        GitObjects::Content,
+        GitObjects::CreatedAt,
+        GitObjects::BlobPath,
+    ];
+    let copy = Query::insert()
+        .into_table(GitObjectsRebuilt::Table)
+        .columns(columns.clone())
+        .select_from(Query::select().columns(columns).from(GitObjects::Table).to_owned())
+        .map_err(|e| DbErr::Migration(e.to_string()))?
+        .to_owned();
+    manager.exec_stmt(copy).await?;
+
+    manager
+        .drop_table(Table::drop().table(GitObjects::Table).to_owned())
+        .await?;
+    manager
+        .rename_table(
+            Table::rename()
+                .table(GitObjectsRebuilt::Table, GitObjects::Table)
+                .to_owned(),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Iden, Clone)]
+enum GitObjects {
+    Table,
+    Id,
+    RepositoryId,
+    ObjectType,
+    Size,
+    Content,
+    CreatedAt,
+    BlobPath,
+}
+
+#[derive(Iden)]
+enum GitObjectsRebuilt {
+    Table,
+}
+
+#[derive(Iden)]
+enum Repositories {
+    Table,
+    Id,
+}
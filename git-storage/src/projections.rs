@@ -0,0 +1,660 @@
+use crate::backfill::{annotated_tag_row, commit_row, tree_row};
+use crate::entities::git_object::ObjectKind;
+use crate::entities::{branch, commit, git_object, tag, tree};
+use crate::{RepositoryService, StorageError};
+use anyhow::Result;
+use chrono::Utc;
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// Objects or rows compared per batch
+const REBUILD_PAGE_SIZE: u64 = 500;
+
+/// A table derived from `git_objects` and `git_refs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+    Commits,
+    Trees,
+    Tags,
+    Branches,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RowChange {
+    /// The object or ref has no row
+    Missing,
+    /// The row doesn't say what the object or ref does
+    Stale,
+    /// The row has no object or ref behind it
+    Extra,
+}
+
+/// One row that differs from what the objects and refs say it should be
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RowDifference {
+    pub table: Projection,
+    /// Object id for commits and trees, branch or tag name otherwise
+    pub key: String,
+    pub change: RowChange,
+}
+
+/// Outcome of `rebuild_projections`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProjectionReport {
+    /// Differences were only reported, not repaired
+    pub dry_run: bool,
+    pub rows_checked: u64,
+    pub differences: Vec<RowDifference>,
+    /// Objects that could not be parsed, left out of the tables
+    pub failed: Vec<String>,
+}
+
+/// Passed to the progress callback after each batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebuildProgress {
+    pub table: Projection,
+    /// Rows checked in this table so far
+    pub rows_checked: u64,
+    pub differences: u64,
+}
+
+impl ProjectionReport {
+    fn record(&mut self, table: Projection, key: &str, change: RowChange) {
+        self.differences.push(RowDifference {
+            table,
+            key: key.to_string(),
+            change,
+        });
+    }
+
+    fn progress(&self, table: Projection, rows_checked: u64) -> RebuildProgress {
+        RebuildProgress {
+            table,
+            rows_checked,
+            differences: self.differences.iter().filter(|d| d.table == table).count() as u64,
+        }
+    }
+}
+
+impl RepositoryService {
+    /// Bring the `commits`, `trees`, `tags` and `branches` tables of a repository back
+    /// in line with its `git_objects` and `git_refs` rows: missing rows are added,
+    /// stale ones rewritten and rows with nothing behind them removed. Objects are
+    /// read in batches, each batch repaired in its own transaction, and `progress`
+    /// is called after every batch. With `dry_run` the differences are only reported.
+    ///
+    /// Commit and tree rows are keyed by object id alone, so one already written for
+    /// another repository holding the same object is left to that repository.
+    pub async fn rebuild_projections(
+        &self,
+        repository_id: Uuid,
+        dry_run: bool,
+        mut progress: impl FnMut(RebuildProgress),
+    ) -> Result<ProjectionReport> {
+        let repo = self
+            .get_repository_by_id(repository_id)
+            .await?
+            .ok_or(StorageError::RepositoryNotFound { id: repository_id })?;
+        let mut report = ProjectionReport {
+            dry_run,
+            ..ProjectionReport::default()
+        };
+
+        for (kind, table) in [(ObjectKind::Commit, Projection::Commits), (ObjectKind::Tree, Projection::Trees)] {
+            let checked = self.rebuild_object_rows(repository_id, kind, dry_run, &mut report, &mut progress).await?;
+            let removed = self.remove_extra_object_rows(repository_id, kind, dry_run, &mut report).await?;
+            report.rows_checked += checked + removed;
+            progress(report.progress(table, checked + removed));
+        }
+
+        let refs = self.get_refs_by_repository(repository_id).await?;
+        let now = Utc::now();
+
+        let expected_branches: HashMap<String, branch::Model> = refs
+            .iter()
+            .filter(|r| !r.is_symbolic)
+            .filter_map(|r| {
+                let name = r.name.strip_prefix("refs/heads/")?;
+                Some((
+                    name.to_string(),
+                    branch::Model {
+                        id: Uuid::new_v4(),
+                        repository_id,
+                        name: name.to_string(),
+                        commit_id: r.target.clone(),
+                        is_default: name == repo.default_branch,
+                        created_at: now.into(),
+                        updated_at: now.into(),
+                    },
+                ))
+            })
+            .collect();
+        let existing_branches = branch::Entity::find()
+            .filter(branch::Column::RepositoryId.eq(repository_id))
+            .all(self.get_db())
+            .await?;
+        let branch_changes = diff_rows(
+            existing_branches,
+            expected_branches,
+            |row| row.name.clone(),
+            |row, expected| {
+                row.commit_id == expected.commit_id && row.is_default == expected.is_default
+            },
+        );
+        let branches_checked = branch_changes.checked;
+        report.rows_checked += branches_checked;
+        for (name, change) in &branch_changes.changes {
+            report.record(Projection::Branches, name, *change);
+        }
+        if !dry_run {
+            let changes = branch_changes;
+            self.transaction(move |txn| {
+                Box::pin(async move {
+                    for row in changes.remove {
+                        branch::Entity::delete_by_id(row.id).exec(txn).await?;
+                    }
+                    for (existing, expected) in changes.write {
+                        match existing {
+                            Some(existing) => {
+                                let row = branch::Model {
+                                    id: existing.id,
+                                    created_at: existing.created_at,
+                                    ..expected
+                                };
+                                branch::ActiveModel::from(row).reset_all().update(txn).await?;
+                            }
+                            None => {
+                                branch::ActiveModel::from(expected).insert(txn).await?;
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .await?;
+        }
+        progress(report.progress(Projection::Branches, branches_checked));
+
+        let mut expected_tags = HashMap::new();
+        for git_ref in refs.iter().filter(|r| !r.is_symbolic) {
+            let Some(name) = git_ref.name.strip_prefix("refs/tags/") else {
+                continue;
+            };
+            let target = git_object::Entity::find_by_id((repository_id, git_ref.target.clone()))
+                .one(self.get_db())
+                .await?;
+            let row = match target {
+                Some(obj) if obj.object_type == ObjectKind::Tag => {
+                    let obj = self.load_object_content(obj)?;
+                    match annotated_tag_row(repository_id, &obj.id, &obj.content, now) {
+                        Some(row) => tag::Model {
+                            name: name.to_string(),
+                            ..row
+                        },
+                        None => {
+                            report.failed.push(obj.id);
+                            continue;
+                        }
+                    }
+                }
+                target => tag::Model {
+                    id: Uuid::new_v4(),
+                    repository_id,
+                    name: name.to_string(),
+                    target_id: git_ref.target.clone(),
+                    target_type: target.map(|obj| obj.object_type).unwrap_or(ObjectKind::Commit),
+                    tag_object_id: None,
+                    tagger_name: None,
+                    tagger_email: None,
+                    tagger_date: None,
+                    message: None,
+                    content: None,
+                    is_lightweight: true,
+                    created_at: now.into(),
+                    updated_at: now.into(),
+                },
+            };
+            expected_tags.insert(name.to_string(), row);
+        }
+        let existing_tags = tag::Entity::find()
+            .filter(tag::Column::RepositoryId.eq(repository_id))
+            .all(self.get_db())
+            .await?;
+        let tag_changes = diff_rows(
+            existing_tags,
+            expected_tags,
+            |row| row.name.clone(),
+            |row, expected| {
+                tag::Model {
+                    id: row.id,
+                    created_at: row.created_at,
+                    updated_at: row.updated_at,
+                    ..expected.clone()
+                } == *row
+            },
+        );
+        let tags_checked = tag_changes.checked;
+        report.rows_checked += tags_checked;
+        for (name, change) in &tag_changes.changes {
+            report.record(Projection::Tags, name, *change);
+        }
+        if !dry_run {
+            let changes = tag_changes;
+            self.transaction(move |txn| {
+                Box::pin(async move {
+                    for row in changes.remove {
+                        tag::Entity::delete_by_id(row.id).exec(txn).await?;
+                    }
+                    for (existing, expected) in changes.write {
+                        match existing {
+                            Some(existing) => {
+                                let row = tag::Model {
+                                    id: existing.id,
+                                    created_at: existing.created_at,
+                                    ..expected
+                                };
+                                tag::ActiveModel::from(row).reset_all().update(txn).await?;
+                            }
+                            None => {
+                                tag::ActiveModel::from(expected).insert(txn).await?;
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .await?;
+        }
+        progress(report.progress(Projection::Tags, tags_checked));
+
+        Ok(report)
+    }
+
+    /// Compare and repair the commit or tree row of every object of `kind`, a batch
+    /// at a time. Returns how many objects were checked.
+    async fn rebuild_object_rows(
+        &self,
+        repository_id: Uuid,
+        kind: ObjectKind,
+        dry_run: bool,
+        report: &mut ProjectionReport,
+        progress: &mut impl FnMut(RebuildProgress),
+    ) -> Result<u64> {
+        let table = match kind {
+            ObjectKind::Commit => Projection::Commits,
+            _ => Projection::Trees,
+        };
+        let mut checked = 0;
+        let mut after: Option<String> = None;
+
+        loop {
+            let mut query = git_object::Entity::find()
+                .filter(git_object::Column::RepositoryId.eq(repository_id))
+                .filter(git_object::Column::ObjectType.eq(kind))
+                .order_by_asc(git_object::Column::Id)
+                .limit(REBUILD_PAGE_SIZE);
+            if let Some(after) = &after {
+                query = query.filter(git_object::Column::Id.gt(after.as_str()));
+            }
+            let page = query.all(self.get_db()).await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(last.id.clone());
+            let full_page = page.len() as u64 == REBUILD_PAGE_SIZE;
+            checked += page.len() as u64;
+
+            let now = Utc::now();
+            let ids: Vec<String> = page.iter().map(|obj| obj.id.clone()).collect();
+            let mut writes = Vec::new();
+            match kind {
+                ObjectKind::Commit => {
+                    let existing: HashMap<String, commit::Model> = commit::Entity::find()
+                        .filter(commit::Column::Id.is_in(ids))
+                        .all(self.get_db())
+                        .await?
+                        .into_iter()
+                        .map(|row| (row.id.clone(), row))
+                        .collect();
+                    for obj in page {
+                        let obj = self.load_object_content(obj)?;
+                        let Some(expected) = commit_row(repository_id, &obj.id, &obj.content, now)? else {
+                            report.failed.push(obj.id);
+                            continue;
+                        };
+                        match existing.get(&obj.id) {
+                            None => report.record(table, &obj.id, RowChange::Missing),
+                            Some(row) if row.repository_id != repository_id => continue,
+                            Some(row) if (commit::Model { created_at: row.created_at, ..expected.clone() }) == *row => continue,
+                            Some(_) => report.record(table, &obj.id, RowChange::Stale),
+                        }
+                        writes.push(ObjectRow::Commit(expected, existing.contains_key(&obj.id)));
+                    }
+                }
+                _ => {
+                    let existing: HashMap<String, tree::Model> = tree::Entity::find()
+                        .filter(tree::Column::Id.is_in(ids))
+                        .all(self.get_db())
+                        .await?
+                        .into_iter()
+                        .map(|row| (row.id.clone(), row))
+                        .collect();
+                    for obj in page {
+                        let obj = self.load_object_content(obj)?;
+                        let Some(expected) = tree_row(repository_id, &obj.id, &obj.content, now)? else {
+                            report.failed.push(obj.id);
+                            continue;
+                        };
+                        match existing.get(&obj.id) {
+                            None => report.record(table, &obj.id, RowChange::Missing),
+                            Some(row) if row.repository_id != repository_id => continue,
+                            Some(row) if (tree::Model { created_at: row.created_at, ..expected.clone() }) == *row => continue,
+                            Some(_) => report.record(table, &obj.id, RowChange::Stale),
+                        }
+                        writes.push(ObjectRow::Tree(expected, existing.contains_key(&obj.id)));
+                    }
+                }
+            }
+
+            if !dry_run && !writes.is_empty() {
+                self.transaction(move |txn| {
+                    Box::pin(async move {
+                        for write in writes {
+                            match write {
+                                ObjectRow::Commit(row, true) => {
+                                    commit::ActiveModel::from(row).reset_all().update(txn).await?;
+                                }
+                                ObjectRow::Commit(row, false) => {
+                                    commit::ActiveModel::from(row).insert(txn).await?;
+                                }
+                                ObjectRow::Tree(row, true) => {
+                                    tree::ActiveModel::from(row).reset_all().update(txn).await?;
+                                }
+                                ObjectRow::Tree(row, false) => {
+                                    tree::ActiveModel::from(row).insert(txn).await?;
+                                }
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .await?;
+            }
+            progress(report.progress(table, checked));
+
+            if !full_page {
+                break;
+            }
+        }
+
+        Ok(checked)
+    }
+
+    /// Find, and unless `dry_run` delete, this repository's commit or tree rows whose
+    /// object it doesn't hold. Returns how many were found.
+    async fn remove_extra_object_rows(
+        &self,
+        repository_id: Uuid,
+        kind: ObjectKind,
+        dry_run: bool,
+        report: &mut ProjectionReport,
+    ) -> Result<u64> {
+        let mut extra = Vec::new();
+        let mut after: Option<String> = None;
+
+        loop {
+            let page: Vec<String> = match kind {
+                ObjectKind::Commit => {
+                    let mut query = commit::Entity::find()
+                        .select_only()
+                        .column(commit::Column::Id)
+                        .filter(commit::Column::RepositoryId.eq(repository_id))
+                        .order_by_asc(commit::Column::Id)
+                        .limit(REBUILD_PAGE_SIZE);
+                    if let Some(after) = &after {
+                        query = query.filter(commit::Column::Id.gt(after.as_str()));
+                    }
+                    query.into_tuple().all(self.get_db()).await?
+                }
+                _ => {
+                    let mut query = tree::Entity::find()
+                        .select_only()
+                        .column(tree::Column::Id)
+                        .filter(tree::Column::RepositoryId.eq(repository_id))
+                        .order_by_asc(tree::Column::Id)
+                        .limit(REBUILD_PAGE_SIZE);
+                    if let Some(after) = &after {
+                        query = query.filter(tree::Column::Id.gt(after.as_str()));
+                    }
+                    query.into_tuple().all(self.get_db()).await?
+                }
+            };
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(last.clone());
+            let full_page = page.len() as u64 == REBUILD_PAGE_SIZE;
+
+            let held: HashSet<String> = git_object::Entity::find()
+                .select_only()
+                .column(git_object::Column::Id)
+                .filter(git_object::Column::RepositoryId.eq(repository_id))
+                .filter(git_object::Column::ObjectType.eq(kind))
+                .filter(git_object::Column::Id.is_in(page.clone()))
+                .into_tuple()
+                .all(self.get_db())
+                .await?
+                .into_iter()
+                .collect();
+            extra.extend(page.into_iter().filter(|id| !held.contains(id)));
+
+            if !full_page {
+                break;
+            }
+        }
+
+        let table = match kind {
+            ObjectKind::Commit => Projection::Commits,
+            _ => Projection::Trees,
+        };
+        for id in &extra {
+            report.record(table, id, RowChange::Extra);
+        }
+        if !dry_run {
+            // Deleted once the scan is done, so the keyset paging above isn't disturbed
+            for chunk in extra.chunks(REBUILD_PAGE_SIZE as usize) {
+                match kind {
+                    ObjectKind::Commit => {
+                        commit::Entity::delete_many()
+                            .filter(commit::Column::Id.is_in(chunk.iter().cloned()))
+                            .exec(self.get_db())
+                            .await?;
+                    }
+                    _ => {
+                        tree::Entity::delete_many()
+                            .filter(tree::Column::Id.is_in(chunk.iter().cloned()))
+                            .exec(self.get_db())
+                            .await?;
+                    }
+                }
+            }
+        }
+        Ok(extra.len() as u64)
+    }
+}
+
+/// A commit or tree row to write, and whether one already exists
+enum ObjectRow {
+    Commit(commit::Model, bool),
+    Tree(tree::Model, bool),
+}
+
+/// How a ref-derived table differs from the rows the refs call for
+struct RowChanges<M> {
+    checked: u64,
+    changes: Vec<(String, RowChange)>,
+    /// Rows to write, with the row they replace
+    write: Vec<(Option<M>, M)>,
+    remove: Vec<M>,
+}
+
+/// Compare existing rows with expected ones by key
+fn diff_rows<M: Clone>(
+    existing: Vec<M>,
+    mut expected: HashMap<String, M>,
+    key: impl Fn(&M) -> String,
+    matches: impl Fn(&M, &M) -> bool,
+) -> RowChanges<M> {
+    let mut changes = RowChanges {
+        checked: 0,
+        changes: Vec::new(),
+        write: Vec::new(),
+        remove: Vec::new(),
+    };
+    for row in existing {
+        changes.checked += 1;
+        let name = key(&row);
+        match expected.remove(&name) {
+            Some(want) if matches(&row, &want) => {}
+            Some(want) => {
+                changes.changes.push((name, RowChange::Stale));
+                changes.write.push((Some(row), want));
+            }
+            None => {
+                changes.changes.push((name, RowChange::Extra));
+                changes.remove.push(row);
+            }
+        }
+    }
+    let mut missing: Vec<(String, M)> = expected.into_iter().collect();
+    missing.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, want) in missing {
+        changes.checked += 1;
+        changes.changes.push((name, RowChange::Missing));
+        changes.write.push((None, want));
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::repository::Visibility;
+    use crate::{init_db, run_migrations, CreateCommitRequest, GitOperations, RefUpdate, UserService};
+    use sea_orm::sea_query::Expr;
+    use sea_orm::Set;
+
+    #[tokio::test]
+    async fn test_rebuild_repairs_drifted_projection_rows() {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+        let blob_storage_path = std::env::temp_dir().join(format!("git-storage-test-{}", Uuid::new_v4()));
+        let service = RepositoryService::new(db.clone(), Some(blob_storage_path));
+        let owner = UserService::new(db.clone())
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = service
+            .create_repository("drift".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let git_ops = GitOperations::new(service.clone());
+        let mut commits = Vec::new();
+        for message in ["first\n", "second\n"] {
+            let request = CreateCommitRequest {
+                tree_hash: "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+                parent_hashes: commits.clone(),
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: message.to_string(),
+                author_date: None,
+                commit_date: None,
+            };
+            commits.push(git_ops.create_commit(&db, repo.id, request).await.unwrap());
+        }
+        let guard = service.lock_writes(repo.id).await.unwrap();
+        let updates = [("refs/heads/main", &commits[1]), ("refs/heads/feature", &commits[0]), ("refs/tags/v1", &commits[0])]
+            .into_iter()
+            .map(|(name, target)| RefUpdate {
+                name: name.to_string(),
+                old_target: None,
+                new_target: Some(target.clone()),
+            })
+            .collect();
+        service.update_refs(&guard, updates).await.unwrap();
+        drop(guard);
+
+        // The lightweight tag has no row yet; a first rebuild adds it
+        let report = service.rebuild_projections(repo.id, false, |_| {}).await.unwrap();
+        assert!(report.differences.contains(&RowDifference {
+            table: Projection::Tags,
+            key: "v1".to_string(),
+            change: RowChange::Missing,
+        }));
+        let clean = service.rebuild_projections(repo.id, true, |_| {}).await.unwrap();
+        assert_eq!(clean.differences, vec![]);
+
+        // Drift: a branch row at the wrong commit, a row for no branch, a lost commit row
+        branch::Entity::update_many()
+            .col_expr(branch::Column::CommitId, Expr::value("f".repeat(40)))
+            .filter(branch::Column::Name.eq("feature"))
+            .exec(&db)
+            .await
+            .unwrap();
+        branch::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            repository_id: Set(repo.id),
+            name: Set("ghost".to_string()),
+            commit_id: Set(commits[0].clone()),
+            is_default: Set(false),
+            created_at: Set(Utc::now().into()),
+            updated_at: Set(Utc::now().into()),
+        }
+        .insert(&db)
+        .await
+        .unwrap();
+        commit::Entity::delete_by_id(commits[1].as_str()).exec(&db).await.unwrap();
+
+        let feature_row = || async {
+            branch::Entity::find()
+                .filter(branch::Column::Name.eq("feature"))
+                .one(&db)
+                .await
+                .unwrap()
+                .unwrap()
+                .commit_id
+        };
+        let expected = vec![
+            RowDifference { table: Projection::Commits, key: commits[1].clone(), change: RowChange::Missing },
+            RowDifference { table: Projection::Branches, key: "feature".to_string(), change: RowChange::Stale },
+            RowDifference { table: Projection::Branches, key: "ghost".to_string(), change: RowChange::Extra },
+        ];
+
+        // A dry run reports the rows and writes nothing
+        let mut batches = Vec::new();
+        let report = service.rebuild_projections(repo.id, true, |p| batches.push(p)).await.unwrap();
+        assert!(report.dry_run);
+        let mut differences = report.differences.clone();
+        differences.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut sorted_expected = expected.clone();
+        sorted_expected.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(differences, sorted_expected);
+        assert_eq!(feature_row().await, "f".repeat(40));
+        assert!(commit::Entity::find_by_id(commits[1].as_str()).one(&db).await.unwrap().is_none());
+        assert!(batches.iter().any(|p| p.table == Projection::Branches && p.differences == 2));
+
+        // A real run repairs them, after which the tables match the objects and refs
+        let report = service.rebuild_projections(repo.id, false, |_| {}).await.unwrap();
+        assert_eq!(report.differences.len(), expected.len());
+        assert_eq!(feature_row().await, commits[0]);
+        let row = commit::Entity::find_by_id(commits[1].as_str()).one(&db).await.unwrap().unwrap();
+        assert_eq!(row.message, "second\n");
+        let clean = service.rebuild_projections(repo.id, true, |_| {}).await.unwrap();
+        assert_eq!(clean.differences, vec![]);
+    }
+}
@@ -1,17 +1,219 @@
-use crate::entities::{git_object, git_ref, repository};
+use crate::entities::git_object::ObjectKind;
+use crate::entities::repository::{ObjectFormat, Visibility};
+use crate::entities::{blob_ref, branch, git_object, git_ref, packed_refs, ref_log, repository, repository_topic, user};
+use crate::dialect::Dialect;
+use crate::error::StorageError;
+use crate::events::{insert_event, NewRepositoryEvent, RepositoryEventType, TransferEventPayload};
+use crate::write_coordinator::{
+    map_busy_error, WriteCoordinator, WriteGuard, WriteLockScope, WriteMetrics,
+    DEFAULT_WRITE_LOCK_TIMEOUT,
+};
 use anyhow::{anyhow, Result};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use git_protocol::objects::{Commit, ObjectHandler, Tag, Tree};
+use git_protocol::refs::{parse_packed_refs, write_packed_refs};
+use git_protocol::{GitObject, GitRef, HashAlgorithm, ObjectType};
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter, Set,
+    ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DatabaseConnection, DatabaseTransaction,
+    EntityTrait, JoinType, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, RelationTrait,
+    Set, SqlErr, TransactionTrait,
 };
+use sea_orm::prelude::DateTimeWithTimeZone;
+use sea_orm::sea_query::{Expr, Func, OnConflict};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
+/// Most topics a single repository can carry
+pub const MAX_TOPICS_PER_REPOSITORY: usize = 20;
+
+/// Longest topic accepted, in characters
+pub const MAX_TOPIC_LEN: usize = 35;
+
+/// Name a soft-deleted repository holds while its own is free for reuse; no
+/// repository URL can address it
+fn deleted_placeholder_name(id: Uuid) -> String {
+    format!("deleted/{}", id)
+}
+
+/// Blob ids released per statement when a repository is deleted
+const BLOB_REF_BATCH_SIZE: usize = 500;
+
+/// Directory levels blob files are sharded into unless configured: `ab/cdef...`
+pub const DEFAULT_BLOB_SHARD_DEPTH: usize = 1;
+
+/// Deepest blob sharding supported: `ab/cd/ef/...`
+pub const MAX_BLOB_SHARD_DEPTH: usize = 3;
+
 #[derive(Clone)]
 pub struct RepositoryService {
     db: DatabaseConnection,
     blob_storage_path: PathBuf,
+    blob_shard_depth: usize,
+    write_coordinator: Arc<WriteCoordinator>,
+    case_insensitive_refs: bool,
+    packed_refs: bool,
+    default_object_format: ObjectFormat,
+}
+
+/// A single ref change applied by `update_refs`
+#[derive(Debug, Clone)]
+pub struct RefUpdate {
+    pub name: String,
+    /// Expected current target, `None` if the ref must not exist yet
+    pub old_target: Option<String>,
+    /// New target, `None` to delete the ref
+    pub new_target: Option<String>,
+}
+
+/// A ref as the protocol sees it, with any symbolic chain followed to an object id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedRef {
+    pub name: String,
+    pub target: String,
+    /// Ref a symbolic ref points at directly, `None` for ordinary refs
+    pub symref_target: Option<String>,
+}
+
+/// Follow `name` through symbolic refs to the object id it ends at. `None` when the
+/// chain ends at a ref that doesn't exist (an unborn branch), and
+/// `StorageError::SymbolicRefCycle` when it comes back to a ref already visited.
+pub fn resolve_ref(refs: &[git_ref::Model], name: &str) -> Result<Option<String>> {
+    follow_ref(name, |current| {
+        refs.iter()
+            .find(|r| r.name == current)
+            .map(|r| (r.is_symbolic, r.target.as_str()))
+    })
+}
+
+/// `resolve_ref` over any ref list: `lookup` gives whether a name is symbolic and
+/// its target
+fn follow_ref<'a>(name: &'a str, lookup: impl Fn(&str) -> Option<(bool, &'a str)>) -> Result<Option<String>> {
+    let mut current = name;
+    let mut visited = HashSet::new();
+
+    loop {
+        if !visited.insert(current) {
+            return Err(StorageError::SymbolicRefCycle { name: name.to_string() }.into());
+        }
+        match lookup(current) {
+            Some((true, target)) => current = target,
+            Some((false, target)) => return Ok(Some(target.to_string())),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Branch and tag counts of a repository, see `ref_counts_for_repositories`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefCounts {
+    pub branches: u64,
+    pub tags: u64,
+    /// When a ref last changed, from the reflog; `None` if it holds no entries
+    pub last_ref_change: Option<DateTime<Utc>>,
+}
+
+/// The commit at the tip of a repository's default branch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TipCommit {
+    pub sha: String,
+    /// First line of the message
+    pub subject: String,
+    /// `Name <email>`
+    pub author: String,
+    pub date: DateTime<Utc>,
+}
+
+/// Settings changed by `update_repository`; `None` leaves a field as it is
+#[derive(Debug, Clone, Default)]
+pub struct RepositoryUpdate {
+    pub description: Option<String>,
+    pub visibility: Option<Visibility>,
+    /// Replaces the repository's hidden ref prefixes; an empty list clears them
+    pub hidden_ref_prefixes: Option<Vec<String>>,
+    pub is_archived: Option<bool>,
+    /// Replaces the post-push message template; an empty string clears it
+    pub push_message: Option<String>,
+    /// Replaces the branches that must keep a linear history; an empty list clears them
+    pub linear_history_branches: Option<Vec<String>>,
+    pub allow_anonymous_clone: Option<bool>,
+}
+
+/// What a user's repositories take up, see `owner_usage`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnerUsage {
+    pub repositories: u64,
+    pub bytes: u64,
+}
+
+/// Per-repository overrides of the server's read guardrails; `None` falls back
+/// to the server-wide setting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadLimitOverrides {
+    pub max_commits_walked: Option<i32>,
+    pub max_tree_entries: Option<i32>,
+    pub read_timeout_secs: Option<i32>,
+}
+
+impl repository::Model {
+    /// Ref prefixes this repository hides from clients
+    pub fn hidden_ref_prefixes(&self) -> Vec<&str> {
+        self.hidden_ref_prefixes
+            .as_deref()
+            .map(|prefixes| prefixes.split_whitespace().collect())
+            .unwrap_or_default()
+    }
+
+    /// Branches that only take updates without merge commits
+    pub fn linear_history_branches(&self) -> Vec<&str> {
+        self.linear_history_branches
+            .as_deref()
+            .map(|branches| branches.split_whitespace().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether updates of `ref_name` must not introduce merge commits
+    pub fn requires_linear_history(&self, ref_name: &str) -> bool {
+        ref_name
+            .strip_prefix("refs/heads/")
+            .is_some_and(|branch| self.linear_history_branches().contains(&branch))
+    }
+
+    /// Whether `reader` (`None` when anonymous) may read this repository
+    pub fn readable_by(&self, reader: Option<&user::Model>) -> bool {
+        match (self.visibility, reader) {
+            (Visibility::Public, _) => true,
+            (_, None) => false,
+            (Visibility::Internal, Some(_)) => true,
+            (Visibility::Private, Some(user)) => user.id == self.owner_id || user.is_admin,
+        }
+    }
+
+    /// Whether `user` may change this repository's settings and refs: its owner and
+    /// server admins
+    pub fn administered_by(&self, user: Option<&user::Model>) -> bool {
+        user.is_some_and(|user| user.id == self.owner_id || user.is_admin)
+    }
+}
+
+/// The SQL counterpart of `repository::Model::readable_by`, `None` when nothing is filtered
+fn readable_condition(reader: Option<&user::Model>) -> Option<Condition> {
+    match reader {
+        None => Some(Condition::all().add(repository::Column::Visibility.eq(Visibility::Public))),
+        Some(user) if user.is_admin => None,
+        Some(user) => Some(
+            Condition::any()
+                .add(repository::Column::Visibility.ne(Visibility::Private))
+                .add(repository::Column::OwnerId.eq(user.id)),
+        ),
+    }
 }
 
 impl RepositoryService {
@@ -24,7 +226,108 @@ impl RepositoryService {
             std::fs::create_dir_all(&blob_storage_path).ok();
         }
 
-        Self { db, blob_storage_path }
+        let write_coordinator = Arc::new(WriteCoordinator::new(
+            db.get_database_backend(),
+            WriteLockScope::Repository,
+            DEFAULT_WRITE_LOCK_TIMEOUT,
+        ));
+
+        Self {
+            db,
+            blob_storage_path,
+            blob_shard_depth: DEFAULT_BLOB_SHARD_DEPTH,
+            write_coordinator,
+            case_insensitive_refs: false,
+            packed_refs: false,
+            default_object_format: ObjectFormat::Sha1,
+        }
+    }
+
+    /// Shard new blob files `depth` directory levels deep, two hex digits per level
+    /// (`ab/cd/ef...` for 2), clamped to 1..=`MAX_BLOB_SHARD_DEPTH`. Files already
+    /// stored under another depth stay where they are and are still found.
+    pub fn with_blob_shard_depth(mut self, depth: usize) -> Self {
+        self.blob_shard_depth = depth.clamp(1, MAX_BLOB_SHARD_DEPTH);
+        self
+    }
+
+    /// Refuse to create refs whose names differ from an existing ref only in case.
+    /// Off by default, matching Git; turn it on when refs end up on a case-insensitive
+    /// filesystem, where `Feature` and `feature` would shadow each other.
+    pub fn with_case_insensitive_refs(mut self, enabled: bool) -> Self {
+        self.case_insensitive_refs = enabled;
+        self
+    }
+
+    /// Serve `get_resolved_refs` from a packed-refs blob cached per repository,
+    /// read in one query instead of one row per ref. The blob is rebuilt by the
+    /// first read after any ref change (`ref_generation` moving on); single ref
+    /// lookups and writes still go to the ref table.
+    pub fn with_packed_refs(mut self, enabled: bool) -> Self {
+        self.packed_refs = enabled;
+        self
+    }
+
+    /// Object format of repositories created without one, SHA-1 unless set
+    pub fn with_default_object_format(mut self, hash: HashAlgorithm) -> Self {
+        self.default_object_format = hash.into();
+        self
+    }
+
+    /// Fail with `StorageError::RefNameConflict` if case-insensitive ref names are
+    /// enforced and another ref already uses `name` with different casing.
+    /// Names are folded as the database's `lower()` folds them.
+    pub async fn check_ref_name_available<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        name: &str,
+    ) -> Result<()> {
+        if !self.case_insensitive_refs {
+            return Ok(());
+        }
+
+        let existing = git_ref::Entity::find()
+            .filter(git_ref::Column::RepositoryId.eq(repository_id))
+            .filter(Expr::expr(Func::lower(Expr::col(git_ref::Column::Name))).eq(Dialect::of(db).lowercase(name)))
+            .filter(git_ref::Column::Name.ne(name))
+            .one(db)
+            .await
+            .map_err(map_busy_error)?;
+
+        match existing {
+            Some(existing) => Err(StorageError::RefNameConflict {
+                name: name.to_string(),
+                existing: existing.name,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the write coordinator settings (scope and lock wait timeout)
+    pub fn with_write_lock(mut self, scope: WriteLockScope, lock_timeout: Duration) -> Self {
+        self.write_coordinator = Arc::new(WriteCoordinator::new(
+            self.db.get_database_backend(),
+            scope,
+            lock_timeout,
+        ));
+        self
+    }
+
+    /// Serialize writes to a repository; hold the guard across a push's object and ref writes
+    pub async fn lock_writes(&self, repository_id: Uuid) -> Result<WriteGuard> {
+        Ok(self.write_coordinator.acquire(repository_id).await?)
+    }
+
+    /// Write lock wait-time metrics
+    pub fn write_metrics(&self) -> WriteMetrics {
+        self.write_coordinator.metrics()
+    }
+
+    /// Root directory of the shared blob store
+    pub fn blob_storage_path(&self) -> &Path {
+        &self.blob_storage_path
     }
 
     /// Get database connection (for internal use)
@@ -32,22 +335,80 @@ impl RepositoryService {
         &self.db
     }
 
-    /// Create a new repository
+    /// Run `f` inside one database transaction, committing if it returns `Ok` and
+    /// rolling back otherwise.
+    ///
+    /// Blob files written by `f` are staged beside their final location and only moved
+    /// into place if the transaction commits.
+    ///
+    /// `f` must do all of its work through the transaction it is handed. SQLite runs
+    /// with a single pooled connection, so reaching for the service's own connection
+    /// from inside `f` waits for the transaction to finish. Nested transactions are
+    /// supported by calling `begin()` on the handle, which SeaORM maps to a savepoint.
+    pub async fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: for<'c> FnOnce(
+                &'c DatabaseTransaction,
+            ) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'c>>
+            + Send,
+        T: Send,
+    {
+        let txn = self.db.begin().await.map_err(map_busy_error)?;
+        let staged = StagedBlobs::default();
+
+        match STAGED_BLOBS.scope(staged.clone(), f(&txn)).await {
+            Ok(value) => {
+                // Files go into place first: a failed commit then only leaves
+                // unreferenced files behind, never rows without content
+                if let Err(e) = staged.finalize() {
+                    txn.rollback().await.map_err(map_busy_error)?;
+                    return Err(e);
+                }
+                txn.commit().await.map_err(map_busy_error)?;
+                Ok(value)
+            }
+            Err(e) => {
+                staged.discard();
+                txn.rollback().await.map_err(map_busy_error)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Create a new repository naming its objects with `object_format`, the
+    /// service's default when `None`. The format can't change afterwards.
     pub async fn create_repository(
         &self,
         name: String,
         description: Option<String>,
         default_branch: String,
         owner_id: Uuid,
-        is_private: bool,
+        visibility: Visibility,
+        object_format: Option<HashAlgorithm>,
     ) -> Result<repository::Model> {
+        let object_format = object_format.map(ObjectFormat::from).unwrap_or(self.default_object_format);
         let repo = repository::ActiveModel {
             id: Set(Uuid::new_v4()),
             name: Set(name),
             description: Set(description),
             default_branch: Set(default_branch),
             owner_id: Set(owner_id),
-            is_private: Set(is_private),
+            visibility: Set(visibility),
+            version: Set(1),
+            hidden_ref_prefixes: Set(None),
+            max_commits_walked: Set(None),
+            max_tree_entries: Set(None),
+            read_timeout_secs: Set(None),
+            is_archived: Set(false),
+            forked_from: Set(None),
+            push_message: Set(None),
+            ref_generation: Set(0),
+            linear_history_branches: Set(None),
+            size_bytes: Set(0),
+            allow_anonymous_clone: Set(true),
+            deleted_at: Set(None),
+            deleted_name: Set(None),
+            object_format: Set(object_format),
             created_at: Set(Utc::now().into()),
             updated_at: Set(Utc::now().into()),
         };
@@ -65,6 +426,7 @@ impl RepositoryService {
         let repo = repository::Entity::find()
             .filter(repository::Column::Name.eq(name))
             .filter(repository::Column::OwnerId.eq(owner_id))
+            .filter(repository::Column::DeletedAt.is_null())
             .one(&self.db)
             .await?;
         Ok(repo)
@@ -74,21 +436,76 @@ impl RepositoryService {
     pub async fn get_repository_by_name(&self, name: &str) -> Result<Option<repository::Model>> {
         let repo = repository::Entity::find()
             .filter(repository::Column::Name.eq(name))
+            .filter(repository::Column::DeletedAt.is_null())
             .one(&self.db)
             .await?;
         Ok(repo)
     }
 
-    /// Get repository by ID
+    /// Get repository by ID; soft-deleted repositories aren't found
     pub async fn get_repository_by_id(&self, id: Uuid) -> Result<Option<repository::Model>> {
-        let repo = repository::Entity::find_by_id(id).one(&self.db).await?;
+        let repo = repository::Entity::find_by_id(id)
+            .filter(repository::Column::DeletedAt.is_null())
+            .one(&self.db)
+            .await?;
         Ok(repo)
     }
 
+    /// Hash the repository names its objects with
+    pub async fn object_format(&self, repository_id: Uuid) -> Result<HashAlgorithm> {
+        self.object_format_in(&self.db, repository_id).await
+    }
+
+    pub(crate) async fn object_format_in<C: ConnectionTrait>(&self, db: &C, repository_id: Uuid) -> Result<HashAlgorithm> {
+        let format: Option<ObjectFormat> = repository::Entity::find_by_id(repository_id)
+            .select_only()
+            .column(repository::Column::ObjectFormat)
+            .into_tuple()
+            .one(db)
+            .await
+            .map_err(map_busy_error)?;
+        let format = format.ok_or(StorageError::RepositoryNotFound { id: repository_id })?;
+        Ok(format.hash_algorithm())
+    }
+
+    /// Fail with `StorageError::ObjectFormatMismatch` unless every one of `ids` is
+    /// an id of the repository's object format, so no repository mixes formats
+    async fn check_object_format<'a, C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        ids: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        let hash = self.object_format_in(db, repository_id).await?;
+        match ids.into_iter().find(|id| id.len() != hash.hex_len()) {
+            Some(id) => Err(StorageError::ObjectFormatMismatch { id: id.to_string(), format: hash.name().to_string() }.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// How many repositories `owner_id` has and their combined cached size
+    pub async fn owner_usage(&self, owner_id: Uuid) -> Result<OwnerUsage> {
+        let (repositories, bytes): (i64, Option<i64>) = repository::Entity::find()
+            .select_only()
+            .column_as(repository::Column::Id.count(), "repositories")
+            .column_as(Dialect::of(&self.db).sum_i64(repository::Column::SizeBytes.into_expr()), "bytes")
+            .filter(repository::Column::OwnerId.eq(owner_id))
+            .filter(repository::Column::DeletedAt.is_null())
+            .into_tuple()
+            .one(&self.db)
+            .await?
+            .unwrap_or_default();
+        Ok(OwnerUsage {
+            repositories: repositories as u64,
+            bytes: bytes.unwrap_or(0) as u64,
+        })
+    }
+
     /// List repositories by owner
     pub async fn list_repositories_by_owner(&self, owner_id: Uuid) -> Result<Vec<repository::Model>> {
         let repos = repository::Entity::find()
             .filter(repository::Column::OwnerId.eq(owner_id))
+            .filter(repository::Column::DeletedAt.is_null())
             .all(&self.db)
             .await?;
         Ok(repos)
@@ -96,104 +513,1167 @@ impl RepositoryService {
 
     /// List all repositories
     pub async fn list_repositories(&self) -> Result<Vec<repository::Model>> {
-        let repos = repository::Entity::find().all(&self.db).await?;
+        let repos = repository::Entity::find()
+            .filter(repository::Column::DeletedAt.is_null())
+            .all(&self.db)
+            .await?;
+        Ok(repos)
+    }
+
+    /// List repositories tagged with `topic`, optionally limited to one owner
+    pub async fn list_repositories_by_topic(
+        &self,
+        topic: &str,
+        owner_id: Option<Uuid>,
+    ) -> Result<Vec<repository::Model>> {
+        let mut query = repository::Entity::find()
+            .join(JoinType::InnerJoin, repository::Relation::Topics.def())
+            .filter(repository_topic::Column::Topic.eq(topic.trim().to_lowercase()))
+            .filter(repository::Column::DeletedAt.is_null());
+        if let Some(owner_id) = owner_id {
+            query = query.filter(repository::Column::OwnerId.eq(owner_id));
+        }
+        let repos = query.all(&self.db).await?;
+        Ok(repos)
+    }
+
+    /// List the repositories `reader` may read (`None` when anonymous), optionally
+    /// only one owner's and only those tagged with `topic`
+    pub async fn list_visible_repositories(
+        &self,
+        reader: Option<&user::Model>,
+        owner_id: Option<Uuid>,
+        topic: Option<&str>,
+    ) -> Result<Vec<repository::Model>> {
+        let mut query = repository::Entity::find().filter(repository::Column::DeletedAt.is_null());
+        if let Some(condition) = readable_condition(reader) {
+            query = query.filter(condition);
+        }
+        if let Some(owner_id) = owner_id {
+            query = query.filter(repository::Column::OwnerId.eq(owner_id));
+        }
+        if let Some(topic) = topic {
+            query = query
+                .join(JoinType::InnerJoin, repository::Relation::Topics.def())
+                .filter(repository_topic::Column::Topic.eq(topic.trim().to_lowercase()));
+        }
+        let repos = query.all(&self.db).await?;
         Ok(repos)
     }
 
-    /// Delete repository
+    /// Topics of a repository, sorted
+    pub async fn get_topics(&self, repository_id: Uuid) -> Result<Vec<String>> {
+        let topics = repository_topic::Entity::find()
+            .filter(repository_topic::Column::RepositoryId.eq(repository_id))
+            .order_by_asc(repository_topic::Column::Topic)
+            .all(&self.db)
+            .await?;
+        Ok(topics.into_iter().map(|t| t.topic).collect())
+    }
+
+    /// Topics for several repositories at once, keyed by repository id
+    pub async fn get_topics_for_repositories(
+        &self,
+        repository_ids: &[Uuid],
+    ) -> Result<HashMap<Uuid, Vec<String>>> {
+        let mut topics: HashMap<Uuid, Vec<String>> = HashMap::new();
+        if repository_ids.is_empty() {
+            return Ok(topics);
+        }
+
+        let rows = repository_topic::Entity::find()
+            .filter(repository_topic::Column::RepositoryId.is_in(repository_ids.iter().copied()))
+            .order_by_asc(repository_topic::Column::Topic)
+            .all(&self.db)
+            .await?;
+        for row in rows {
+            topics.entry(row.repository_id).or_default().push(row.topic);
+        }
+        Ok(topics)
+    }
+
+    /// Branch and tag counts for several repositories in one grouped query, plus
+    /// one for their latest reflog entries. Repositories without refs are left out.
+    pub async fn ref_counts_for_repositories(&self, repository_ids: &[Uuid]) -> Result<HashMap<Uuid, RefCounts>> {
+        let mut counts: HashMap<Uuid, RefCounts> = HashMap::new();
+        if repository_ids.is_empty() {
+            return Ok(counts);
+        }
+
+        let dialect = Dialect::of(&self.db);
+        let count_prefix = |prefix: &str| {
+            dialect.sum_i64(Expr::case(Expr::col(git_ref::Column::Name).like(format!("{}%", prefix)), 1).finally(0))
+        };
+        let rows: Vec<(Uuid, i64, i64)> = git_ref::Entity::find()
+            .select_only()
+            .column(git_ref::Column::RepositoryId)
+            .column_as(count_prefix("refs/heads/"), "branches")
+            .column_as(count_prefix("refs/tags/"), "tags")
+            .filter(git_ref::Column::RepositoryId.is_in(repository_ids.iter().copied()))
+            .group_by(git_ref::Column::RepositoryId)
+            .into_tuple()
+            .all(&self.db)
+            .await?;
+        for (repository_id, branches, tags) in rows {
+            counts.insert(
+                repository_id,
+                RefCounts {
+                    branches: branches.max(0) as u64,
+                    tags: tags.max(0) as u64,
+                    last_ref_change: None,
+                },
+            );
+        }
+
+        let latest: Vec<(Uuid, Option<DateTimeWithTimeZone>)> = ref_log::Entity::find()
+            .select_only()
+            .column(ref_log::Column::RepositoryId)
+            .column_as(ref_log::Column::CreatedAt.max(), "last_ref_change")
+            .filter(ref_log::Column::RepositoryId.is_in(repository_ids.iter().copied()))
+            .group_by(ref_log::Column::RepositoryId)
+            .into_tuple()
+            .all(&self.db)
+            .await?;
+        for (repository_id, last_ref_change) in latest {
+            counts.entry(repository_id).or_default().last_ref_change =
+                last_ref_change.map(|at| at.with_timezone(&Utc));
+        }
+
+        Ok(counts)
+    }
+
+    /// The default branch tip of several repositories, from one query for their
+    /// default branch refs and one for the commits they point at. Repositories
+    /// whose default branch is unborn are left out.
+    pub async fn default_branch_tips(&self, repositories: &[repository::Model]) -> Result<HashMap<Uuid, TipCommit>> {
+        let mut tips = HashMap::new();
+        if repositories.is_empty() {
+            return Ok(tips);
+        }
+
+        let default_refs: HashMap<Uuid, String> = repositories
+            .iter()
+            .map(|repo| (repo.id, format!("refs/heads/{}", repo.default_branch)))
+            .collect();
+        let names: BTreeSet<&String> = default_refs.values().collect();
+        let refs = git_ref::Entity::find()
+            .filter(git_ref::Column::RepositoryId.is_in(default_refs.keys().copied()))
+            .filter(git_ref::Column::Name.is_in(names))
+            .filter(git_ref::Column::IsSymbolic.eq(false))
+            .all(&self.db)
+            .await?;
+        // Another repository's default branch name can match here too
+        let targets: HashMap<(Uuid, String), Uuid> = refs
+            .into_iter()
+            .filter(|r| default_refs.get(&r.repository_id) == Some(&r.name))
+            .map(|r| ((r.repository_id, r.target), r.repository_id))
+            .collect();
+        if targets.is_empty() {
+            return Ok(tips);
+        }
+
+        let commits = git_object::Entity::find()
+            .filter(git_object::Column::RepositoryId.is_in(targets.values().copied()))
+            .filter(git_object::Column::Id.is_in(targets.keys().map(|(_, sha)| sha.clone())))
+            .filter(git_object::Column::ObjectType.eq(ObjectKind::Commit))
+            .all(&self.db)
+            .await?;
+        let handler = ObjectHandler::new();
+        for object in commits {
+            let key = (object.repository_id, object.id.clone());
+            if !targets.contains_key(&key) {
+                continue;
+            }
+            let content = self.load_object_content(object)?.content;
+            let commit = handler.parse_commit(&content)?;
+            tips.insert(
+                key.0,
+                TipCommit {
+                    sha: key.1,
+                    subject: commit.message.lines().next().unwrap_or_default().to_string(),
+                    author: crate::diff::signature_identity(&commit.author).to_string(),
+                    date: commit.author_date,
+                },
+            );
+        }
+
+        Ok(tips)
+    }
+
+    /// Apply a settings update if the repository is still at `expected_version`.
+    /// The check and the write are one conditional UPDATE, so of two concurrent
+    /// editors that read the same version only the first wins; the second gets
+    /// `StorageError::VersionConflict` and should re-read and merge.
+    pub async fn update_repository(
+        &self,
+        repository_id: Uuid,
+        expected_version: i32,
+        update: RepositoryUpdate,
+    ) -> Result<repository::Model> {
+        let mut query = repository::Entity::update_many()
+            .col_expr(repository::Column::Version, Expr::value(expected_version + 1))
+            .col_expr(repository::Column::UpdatedAt, Expr::value(Utc::now()))
+            .filter(repository::Column::Id.eq(repository_id))
+            .filter(repository::Column::Version.eq(expected_version));
+        if let Some(description) = update.description {
+            query = query.col_expr(repository::Column::Description, Expr::value(description));
+        }
+        if let Some(visibility) = update.visibility {
+            query = query.col_expr(repository::Column::Visibility, Expr::value(visibility));
+        }
+        if let Some(is_archived) = update.is_archived {
+            query = query.col_expr(repository::Column::IsArchived, Expr::value(is_archived));
+        }
+        if let Some(allow) = update.allow_anonymous_clone {
+            query = query.col_expr(repository::Column::AllowAnonymousClone, Expr::value(allow));
+        }
+        if let Some(message) = update.push_message {
+            let message = (!message.trim().is_empty()).then_some(message);
+            query = query.col_expr(repository::Column::PushMessage, Expr::value(message));
+        }
+        if let Some(prefixes) = update.hidden_ref_prefixes {
+            let prefixes = (!prefixes.is_empty()).then(|| prefixes.join(" "));
+            query = query.col_expr(repository::Column::HiddenRefPrefixes, Expr::value(prefixes));
+        }
+        if let Some(branches) = update.linear_history_branches {
+            let branches = (!branches.is_empty()).then(|| branches.join(" "));
+            query = query.col_expr(repository::Column::LinearHistoryBranches, Expr::value(branches));
+        }
+        let result = query.exec(&self.db).await?;
+
+        let current = self
+            .get_repository_by_id(repository_id)
+            .await?
+            .ok_or(StorageError::RepositoryNotFound { id: repository_id })?;
+        if result.rows_affected == 0 {
+            return Err(StorageError::VersionConflict {
+                id: repository_id,
+                expected: expected_version,
+                current: current.version,
+            }
+            .into());
+        }
+        Ok(current)
+    }
+
+    /// Replace a repository's read guardrail overrides. Unlike `update_repository`
+    /// this is an admin operation and doesn't take an expected version, but it still
+    /// bumps it so concurrent settings editors notice the change.
+    pub async fn set_read_limits(
+        &self,
+        repository_id: Uuid,
+        overrides: ReadLimitOverrides,
+    ) -> Result<repository::Model> {
+        let result = repository::Entity::update_many()
+            .col_expr(repository::Column::MaxCommitsWalked, Expr::value(overrides.max_commits_walked))
+            .col_expr(repository::Column::MaxTreeEntries, Expr::value(overrides.max_tree_entries))
+            .col_expr(repository::Column::ReadTimeoutSecs, Expr::value(overrides.read_timeout_secs))
+            .col_expr(repository::Column::Version, Expr::col(repository::Column::Version).add(1))
+            .col_expr(repository::Column::UpdatedAt, Expr::value(Utc::now()))
+            .filter(repository::Column::Id.eq(repository_id))
+            .exec(&self.db)
+            .await?;
+        if result.rows_affected == 0 {
+            return Err(StorageError::RepositoryNotFound { id: repository_id }.into());
+        }
+
+        self.get_repository_by_id(repository_id)
+            .await?
+            .ok_or_else(|| StorageError::RepositoryNotFound { id: repository_id }.into())
+    }
+
+    /// Replace the whole topic set of a repository. Topics are normalized first and
+    /// the set is swapped in one transaction, so readers see either the old or the
+    /// new list. Returns the stored topics.
+    pub async fn set_topics(&self, repository_id: Uuid, topics: Vec<String>) -> Result<Vec<String>> {
+        let topics = normalize_topics(topics)?;
+
+        let stored = topics.clone();
+        self.transaction(move |txn| {
+            Box::pin(async move {
+                repository_topic::Entity::delete_many()
+                    .filter(repository_topic::Column::RepositoryId.eq(repository_id))
+                    .exec(txn)
+                    .await?;
+
+                if !topics.is_empty() {
+                    let now = Utc::now();
+                    let rows = topics.into_iter().map(|topic| repository_topic::ActiveModel {
+                        id: Set(Uuid::new_v4()),
+                        repository_id: Set(repository_id),
+                        topic: Set(topic),
+                        created_at: Set(now.into()),
+                    });
+                    repository_topic::Entity::insert_many(rows).exec(txn).await?;
+                }
+                Ok(())
+            })
+        })
+        .await?;
+
+        Ok(stored)
+    }
+
+    /// Soft-delete a repository: it drops out of every lookup and listing but keeps
+    /// its refs and objects until `purge_deleted_repositories` removes it. Its name
+    /// is freed for new repositories and taken back by `restore_repository`.
+    pub async fn soft_delete_repository(&self, id: Uuid) -> Result<repository::Model> {
+        let repo = self
+            .get_repository_by_id(id)
+            .await?
+            .ok_or(StorageError::RepositoryNotFound { id })?;
+        let result = repository::Entity::update_many()
+            .col_expr(repository::Column::DeletedAt, Expr::value(Utc::now()))
+            .col_expr(repository::Column::DeletedName, Expr::value(repo.name))
+            .col_expr(repository::Column::Name, Expr::value(deleted_placeholder_name(id)))
+            .filter(repository::Column::Id.eq(id))
+            .filter(repository::Column::DeletedAt.is_null())
+            .exec(&self.db)
+            .await
+            .map_err(map_busy_error)?;
+        if result.rows_affected == 0 {
+            return Err(StorageError::RepositoryNotFound { id }.into());
+        }
+        self.get_deleted_repository(id)
+            .await?
+            .ok_or_else(|| StorageError::RepositoryNotFound { id }.into())
+    }
+
+    /// A soft-deleted repository by id
+    pub async fn get_deleted_repository(&self, id: Uuid) -> Result<Option<repository::Model>> {
+        let repo = repository::Entity::find_by_id(id)
+            .filter(repository::Column::DeletedAt.is_not_null())
+            .one(&self.db)
+            .await?;
+        Ok(repo)
+    }
+
+    /// Soft-deleted repositories, oldest deletion first
+    pub async fn list_deleted_repositories(&self) -> Result<Vec<repository::Model>> {
+        let repos = repository::Entity::find()
+            .filter(repository::Column::DeletedAt.is_not_null())
+            .order_by_asc(repository::Column::DeletedAt)
+            .all(&self.db)
+            .await?;
+        Ok(repos)
+    }
+
+    /// Bring back a soft-deleted repository under its old name. Fails with
+    /// `StorageError::RepositoryNameTaken` when a repository created since has it.
+    pub async fn restore_repository(&self, id: Uuid) -> Result<repository::Model> {
+        let repo = self
+            .get_deleted_repository(id)
+            .await?
+            .ok_or(StorageError::RepositoryNotFound { id })?;
+        let name = repo.deleted_name.unwrap_or(repo.name);
+        if self.get_repository_by_name(&name).await?.is_some() {
+            return Err(StorageError::RepositoryNameTaken { name }.into());
+        }
+        let result = repository::Entity::update_many()
+            .col_expr(repository::Column::Name, Expr::value(name))
+            .col_expr(repository::Column::DeletedAt, Expr::value(Option::<DateTimeWithTimeZone>::None))
+            .col_expr(repository::Column::DeletedName, Expr::value(Option::<String>::None))
+            .col_expr(repository::Column::UpdatedAt, Expr::value(Utc::now()))
+            .filter(repository::Column::Id.eq(id))
+            .filter(repository::Column::DeletedAt.is_not_null())
+            .exec(&self.db)
+            .await
+            .map_err(map_busy_error)?;
+        if result.rows_affected == 0 {
+            return Err(StorageError::RepositoryNotFound { id }.into());
+        }
+        self.get_repository_by_id(id)
+            .await?
+            .ok_or_else(|| StorageError::RepositoryNotFound { id }.into())
+    }
+
+    /// Hand a repository to `new_owner_id`, optionally renaming it on the way, and
+    /// record a `transferred` event for `actor_id` in the same transaction. Names are
+    /// unique across owners, so a rename fails with `StorageError::RepositoryNameTaken`
+    /// rather than picking another name. Bumps the version so settings editors notice.
+    pub async fn transfer_ownership(
+        &self,
+        repository_id: Uuid,
+        new_owner_id: Uuid,
+        new_name: Option<String>,
+        actor_id: Option<Uuid>,
+    ) -> Result<repository::Model> {
+        let txn = self.db.begin().await.map_err(map_busy_error)?;
+
+        let repo = repository::Entity::find_by_id(repository_id)
+            .filter(repository::Column::DeletedAt.is_null())
+            .one(&txn)
+            .await
+            .map_err(map_busy_error)?
+            .ok_or(StorageError::RepositoryNotFound { id: repository_id })?;
+        if user::Entity::find_by_id(new_owner_id).one(&txn).await.map_err(map_busy_error)?.is_none() {
+            return Err(StorageError::UserNotFound { id: new_owner_id }.into());
+        }
+        let name = new_name.unwrap_or_else(|| repo.name.clone());
+        if name != repo.name {
+            let taken = repository::Entity::find()
+                .filter(repository::Column::Name.eq(name.as_str()))
+                .one(&txn)
+                .await
+                .map_err(map_busy_error)?
+                .is_some();
+            if taken {
+                return Err(StorageError::RepositoryNameTaken { name }.into());
+            }
+        }
+
+        repository::Entity::update_many()
+            .col_expr(repository::Column::OwnerId, Expr::value(new_owner_id))
+            .col_expr(repository::Column::Name, Expr::value(name.as_str()))
+            .col_expr(repository::Column::Version, Expr::col(repository::Column::Version).add(1))
+            .col_expr(repository::Column::UpdatedAt, Expr::value(Utc::now()))
+            .filter(repository::Column::Id.eq(repository_id))
+            .exec(&txn)
+            .await
+            .map_err(map_busy_error)?;
+        let payload = TransferEventPayload {
+            from_owner: repo.owner_id,
+            to_owner: new_owner_id,
+            from_name: repo.name,
+            to_name: name,
+        };
+        insert_event(&txn, NewRepositoryEvent::api(repository_id, RepositoryEventType::Transferred, actor_id, &payload))
+            .await
+            .map_err(map_busy_error)?;
+        txn.commit().await.map_err(map_busy_error)?;
+
+        self.get_repository_by_id(repository_id)
+            .await?
+            .ok_or_else(|| StorageError::RepositoryNotFound { id: repository_id }.into())
+    }
+
+    /// Permanently delete repositories soft-deleted before `deleted_before`, through
+    /// `delete_repository` so shared blobs are released. Returns the purged ids.
+    pub async fn purge_deleted_repositories(&self, deleted_before: DateTime<Utc>) -> Result<Vec<Uuid>> {
+        let ids: Vec<Uuid> = repository::Entity::find()
+            .select_only()
+            .column(repository::Column::Id)
+            .filter(repository::Column::DeletedAt.lt(deleted_before))
+            .into_tuple()
+            .all(&self.db)
+            .await?;
+        for &id in &ids {
+            self.delete_repository(id).await?;
+        }
+        Ok(ids)
+    }
+
+    /// Delete repository, releasing its references to shared blobs. Blob files are
+    /// removed once no repository references them.
     pub async fn delete_repository(&self, id: Uuid) -> Result<()> {
+        let txn = self.db.begin().await.map_err(map_busy_error)?;
+
+        let blob_ids: Vec<String> = git_object::Entity::find()
+            .select_only()
+            .column(git_object::Column::Id)
+            .filter(git_object::Column::RepositoryId.eq(id))
+            .filter(git_object::Column::BlobPath.is_not_null())
+            .into_tuple()
+            .all(&txn)
+            .await
+            .map_err(map_busy_error)?;
+
+        let mut released = Vec::new();
+        for chunk in blob_ids.chunks(BLOB_REF_BATCH_SIZE) {
+            blob_ref::Entity::update_many()
+                .col_expr(
+                    blob_ref::Column::RefCount,
+                    Expr::col(blob_ref::Column::RefCount).sub(1),
+                )
+                .col_expr(blob_ref::Column::UpdatedAt, Expr::value(Utc::now()))
+                .filter(blob_ref::Column::BlobId.is_in(chunk.iter().cloned()))
+                .exec(&txn)
+                .await
+                .map_err(map_busy_error)?;
+
+            let unreferenced = blob_ref::Entity::find()
+                .filter(blob_ref::Column::BlobId.is_in(chunk.iter().cloned()))
+                .filter(blob_ref::Column::RefCount.lte(0))
+                .all(&txn)
+                .await
+                .map_err(map_busy_error)?;
+            blob_ref::Entity::delete_many()
+                .filter(blob_ref::Column::BlobId.is_in(unreferenced.iter().map(|b| b.blob_id.clone())))
+                .exec(&txn)
+                .await
+                .map_err(map_busy_error)?;
+            released.extend(unreferenced.into_iter().map(|b| b.blob_id));
+        }
+
+        git_object::Entity::delete_many()
+            .filter(git_object::Column::RepositoryId.eq(id))
+            .exec(&txn)
+            .await
+            .map_err(map_busy_error)?;
         repository::Entity::delete_by_id(id)
-            .exec(&self.db)
+            .exec(&txn)
+            .await
+            .map_err(map_busy_error)?;
+        txn.commit().await.map_err(map_busy_error)?;
+
+        // Only after the commit, so a rolled back delete never loses content
+        self.remove_unreferenced_blobs(released).await
+    }
+
+    /// Fork a repository: the new repository gets the source's refs (HEAD included)
+    /// and objects, shares its blob files rather than copying them, and records the
+    /// source in `forked_from`.
+    pub async fn fork_repository(
+        &self,
+        source_id: Uuid,
+        name: String,
+        owner_id: Uuid,
+    ) -> Result<repository::Model> {
+        let txn = self.db.begin().await.map_err(map_busy_error)?;
+
+        let source = repository::Entity::find_by_id(source_id)
+            .filter(repository::Column::DeletedAt.is_null())
+            .one(&txn)
+            .await
+            .map_err(map_busy_error)?
+            .ok_or(StorageError::RepositoryNotFound { id: source_id })?;
+        let now = Utc::now();
+        let fork = repository::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            name: Set(name),
+            description: Set(source.description.clone()),
+            default_branch: Set(source.default_branch.clone()),
+            owner_id: Set(owner_id),
+            visibility: Set(source.visibility),
+            version: Set(1),
+            hidden_ref_prefixes: Set(source.hidden_ref_prefixes.clone()),
+            max_commits_walked: Set(source.max_commits_walked),
+            max_tree_entries: Set(source.max_tree_entries),
+            read_timeout_secs: Set(source.read_timeout_secs),
+            // A fork of an archived repository starts out writable
+            is_archived: Set(false),
+            forked_from: Set(Some(source_id)),
+            push_message: Set(source.push_message.clone()),
+            ref_generation: Set(0),
+            linear_history_branches: Set(source.linear_history_branches.clone()),
+            // The fork holds copies of all the source's objects
+            size_bytes: Set(source.size_bytes),
+            allow_anonymous_clone: Set(source.allow_anonymous_clone),
+            deleted_at: Set(None),
+            deleted_name: Set(None),
+            // Objects are copied as they are, so the fork can only name them the same way
+            object_format: Set(source.object_format),
+            created_at: Set(now.into()),
+            updated_at: Set(now.into()),
+        }
+        .insert(&txn)
+        .await
+        .map_err(map_busy_error)?;
+
+        let objects = git_object::Entity::find()
+            .filter(git_object::Column::RepositoryId.eq(source_id))
+            .all(&txn)
+            .await
+            .map_err(map_busy_error)?;
+        for obj in objects {
+            if obj.blob_path.is_some() {
+                self.add_blob_ref(&txn, &obj.id).await?;
+            }
+            git_object::ActiveModel {
+                repository_id: Set(fork.id),
+                created_at: Set(now.into()),
+                ..obj.into()
+            }
+            .insert(&txn)
+            .await
+            .map_err(map_busy_error)?;
+        }
+
+        let refs = git_ref::Entity::find()
+            .filter(git_ref::Column::RepositoryId.eq(source_id))
+            .all(&txn)
+            .await
+            .map_err(map_busy_error)?;
+        for git_ref in refs {
+            git_ref::ActiveModel {
+                id: Set(Uuid::new_v4()),
+                repository_id: Set(fork.id),
+                name: Set(git_ref.name),
+                target: Set(git_ref.target),
+                is_symbolic: Set(git_ref.is_symbolic),
+                created_at: Set(now.into()),
+                updated_at: Set(now.into()),
+            }
+            .insert(&txn)
+            .await
+            .map_err(map_busy_error)?;
+        }
+
+        txn.commit().await.map_err(map_busy_error)?;
+        Ok(fork)
+    }
+
+    /// Store a Git object (handles different storage for blobs vs other objects)
+    pub async fn store_object(
+        &self,
+        repository_id: Uuid,
+        object_id: String,
+        object_type: ObjectKind,
+        size: i64,
+        content: Vec<u8>,
+    ) -> Result<git_object::Model> {
+        let service = self.clone();
+        self.transaction(move |txn| {
+            Box::pin(async move {
+                service.check_object_format(txn, repository_id, [object_id.as_str()]).await?;
+                let object = GitObject {
+                    id: object_id,
+                    obj_type: object_type.into(),
+                    size: size as usize,
+                    content,
+                };
+                service.insert_object(txn, repository_id, object).await
+            })
+        })
+        .await
+    }
+
+    /// Store a batch of objects in one transaction under the repository write lock.
+    /// Objects that already exist are skipped.
+    pub async fn store_objects(&self, guard: &WriteGuard, objects: Vec<GitObject>) -> Result<()> {
+        self.store_objects_within(guard, objects, None).await
+    }
+
+    /// `store_objects`, refusing the whole batch with `StorageError::QuotaExceeded`
+    /// when the objects it doesn't already have add up to more than `allowance` bytes
+    pub async fn store_objects_within(
+        &self,
+        guard: &WriteGuard,
+        objects: Vec<GitObject>,
+        allowance: Option<u64>,
+    ) -> Result<()> {
+        let repository_id = guard.repository_id();
+        let service = self.clone();
+
+        self.transaction(move |txn| {
+            Box::pin(async move {
+                service
+                    .check_object_format(txn, repository_id, objects.iter().map(|obj| obj.id.as_str()))
+                    .await?;
+                let mut new_objects = Vec::with_capacity(objects.len());
+                for obj in objects {
+                    let exists = git_object::Entity::find_by_id((repository_id, obj.id.clone()))
+                        .count(txn)
+                        .await
+                        .map_err(map_busy_error)?
+                        > 0;
+                    if !exists {
+                        new_objects.push(obj);
+                    }
+                }
+
+                let needed: u64 = new_objects.iter().map(|obj| obj.size as u64).sum();
+                if let Some(available) = allowance.filter(|available| needed > *available) {
+                    return Err(StorageError::QuotaExceeded { needed, available }.into());
+                }
+
+                for obj in new_objects {
+                    service.insert_object(txn, repository_id, obj).await?;
+                }
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    /// Apply ref updates atomically under the repository write lock.
+    /// Fails with `StorageError::StaleRef` if any ref has moved from its expected target.
+    pub async fn update_refs(&self, guard: &WriteGuard, updates: Vec<RefUpdate>) -> Result<()> {
+        let repository_id = guard.repository_id();
+        let txn = self.db.begin().await.map_err(map_busy_error)?;
+
+        for update in updates {
+            self.apply_ref_update(&txn, repository_id, &update).await?;
+        }
+
+        txn.commit().await.map_err(map_busy_error)?;
+        Ok(())
+    }
+
+    /// Move, create or delete one ref if it is still at `update.old_target`. Every
+    /// branch and tag write, from pushes and the API alike, comes through here: the
+    /// check and the write are one compare-and-swap statement, so of two writers racing
+    /// on a ref one wins and the other fails with `StorageError::StaleRef`, which is
+    /// safe to retry. The reflog and a branch's `branches` row change on `db` with it.
+    pub(crate) async fn apply_ref_update<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        update: &RefUpdate,
+    ) -> Result<()> {
+        if let Some(new_target) = &update.new_target {
+            self.check_object_format(db, repository_id, [new_target.as_str()]).await?;
+        }
+        let find = || {
+            git_ref::Entity::find()
+                .filter(git_ref::Column::RepositoryId.eq(repository_id))
+                .filter(git_ref::Column::Name.eq(&update.name))
+        };
+        let swapped = match (&update.old_target, &update.new_target) {
+            (Some(old_target), Some(new_target)) => {
+                git_ref::Entity::update_many()
+                    .col_expr(git_ref::Column::Target, Expr::value(new_target.as_str()))
+                    .col_expr(git_ref::Column::UpdatedAt, Expr::value(Utc::now()))
+                    .filter(git_ref::Column::RepositoryId.eq(repository_id))
+                    .filter(git_ref::Column::Name.eq(&update.name))
+                    .filter(git_ref::Column::Target.eq(old_target.as_str()))
+                    .filter(git_ref::Column::IsSymbolic.eq(false))
+                    .exec(db)
+                    .await
+                    .map_err(map_busy_error)?
+                    .rows_affected
+                    > 0
+            }
+            (Some(old_target), None) => {
+                git_ref::Entity::delete_many()
+                    .filter(git_ref::Column::RepositoryId.eq(repository_id))
+                    .filter(git_ref::Column::Name.eq(&update.name))
+                    .filter(git_ref::Column::Target.eq(old_target.as_str()))
+                    .filter(git_ref::Column::IsSymbolic.eq(false))
+                    .exec(db)
+                    .await
+                    .map_err(map_busy_error)?
+                    .rows_affected
+                    > 0
+            }
+            (None, new_target) => {
+                // Looked up first: a failed insert would abort a Postgres transaction
+                if find().one(db).await.map_err(map_busy_error)?.is_some() {
+                    false
+                } else if let Some(new_target) = new_target {
+                    self.check_ref_name_available(db, repository_id, &update.name).await?;
+                    let now = Utc::now();
+                    let inserted = git_ref::ActiveModel {
+                        id: Set(Uuid::new_v4()),
+                        repository_id: Set(repository_id),
+                        name: Set(update.name.clone()),
+                        target: Set(new_target.clone()),
+                        is_symbolic: Set(false),
+                        created_at: Set(now.into()),
+                        updated_at: Set(now.into()),
+                    }
+                    .insert(db)
+                    .await;
+                    match inserted {
+                        Ok(_) => true,
+                        // Created by someone else since the lookup
+                        Err(e) if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => {
+                            return Err(StorageError::StaleRef { name: update.name.clone() }.into());
+                        }
+                        Err(e) => return Err(map_busy_error(e)),
+                    }
+                } else {
+                    return Ok(());
+                }
+            }
+        };
+
+        if !swapped {
+            // A symbolic ref's target is a ref name; writing an object id over it would corrupt it
+            if find().one(db).await.map_err(map_busy_error)?.is_some_and(|r| r.is_symbolic) {
+                return Err(StorageError::SymbolicRef { name: update.name.clone() }.into());
+            }
+            return Err(StorageError::StaleRef { name: update.name.clone() }.into());
+        }
+
+        record_ref_change(
+            db,
+            repository_id,
+            &update.name,
+            update.old_target.as_deref(),
+            update.new_target.as_deref(),
+        )
+        .await
+        .map_err(map_busy_error)?;
+        if let Some(branch) = update.name.strip_prefix("refs/heads/") {
+            sync_branch_row(db, repository_id, branch, update.new_target.as_deref())
+                .await
+                .map_err(map_busy_error)?;
+        }
+        Ok(())
+    }
+
+    /// Ref HEAD points at, falling back to the repository's default branch
+    pub async fn get_head_target(&self, repository_id: Uuid) -> Result<String> {
+        if let Some(head) = self.get_ref(repository_id, "HEAD").await? {
+            if head.is_symbolic {
+                return Ok(head.target);
+            }
+        }
+
+        let repository = self
+            .get_repository_by_id(repository_id)
+            .await?
+            .ok_or_else(|| anyhow!("Repository not found"))?;
+        Ok(format!("refs/heads/{}", repository.default_branch))
+    }
+
+    /// Point HEAD at an existing branch under the repository write lock.
+    /// The repository's default branch follows HEAD.
+    pub async fn set_head(&self, guard: &WriteGuard, target: &str) -> Result<()> {
+        let repository_id = guard.repository_id();
+        let branch = target
+            .strip_prefix("refs/heads/")
+            .ok_or_else(|| anyhow!("HEAD must point at a branch, got {}", target))?;
+
+        let txn = self.db.begin().await.map_err(map_busy_error)?;
+
+        let branch_exists = git_ref::Entity::find()
+            .filter(git_ref::Column::RepositoryId.eq(repository_id))
+            .filter(git_ref::Column::Name.eq(target))
+            .filter(git_ref::Column::IsSymbolic.eq(false))
+            .one(&txn)
+            .await
+            .map_err(map_busy_error)?
+            .is_some();
+        if !branch_exists {
+            return Err(StorageError::RefNotFound { name: target.to_string() }.into());
+        }
+
+        let head = git_ref::Entity::find()
+            .filter(git_ref::Column::RepositoryId.eq(repository_id))
+            .filter(git_ref::Column::Name.eq("HEAD"))
+            .one(&txn)
+            .await
+            .map_err(map_busy_error)?;
+
+        match head {
+            Some(head) => {
+                let mut ref_active: git_ref::ActiveModel = head.into();
+                ref_active.target = Set(target.to_string());
+                ref_active.is_symbolic = Set(true);
+                ref_active.updated_at = Set(Utc::now().into());
+                ref_active.update(&txn).await.map_err(map_busy_error)?;
+            }
+            None => {
+                let git_ref = git_ref::ActiveModel {
+                    id: Set(Uuid::new_v4()),
+                    repository_id: Set(repository_id),
+                    name: Set("HEAD".to_string()),
+                    target: Set(target.to_string()),
+                    is_symbolic: Set(true),
+                    created_at: Set(Utc::now().into()),
+                    updated_at: Set(Utc::now().into()),
+                };
+                git_ref.insert(&txn).await.map_err(map_busy_error)?;
+            }
+        }
+        bump_ref_generation(&txn, repository_id).await.map_err(map_busy_error)?;
+
+        if let Some(repository) = repository::Entity::find_by_id(repository_id)
+            .one(&txn)
+            .await
+            .map_err(map_busy_error)?
+        {
+            let version = repository.version;
+            let mut repo_active: repository::ActiveModel = repository.into();
+            repo_active.default_branch = Set(branch.to_string());
+            repo_active.version = Set(version + 1);
+            repo_active.updated_at = Set(Utc::now().into());
+            repo_active.update(&txn).await.map_err(map_busy_error)?;
+        }
+
+        txn.commit().await.map_err(map_busy_error)?;
+        Ok(())
+    }
+
+    /// Insert an object row for a repository. Blob content goes to the shared blob
+    /// store, where a file is written once and then only gains references.
+    ///
+    /// Whether the shared store already has a blob is never used to skip work or
+    /// answer a lookup: the caller still supplies the content, and reads only see
+    /// the repository's own rows.
+    pub(crate) async fn insert_object<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        repository_id: Uuid,
+        object: GitObject,
+    ) -> Result<git_object::Model> {
+        let size = object.size as i64;
+        let blob_key = if object.obj_type == ObjectType::Blob {
+            self.add_blob_ref(db, &object.id).await?;
+            // A file stored under another shard depth is shared rather than copied
+            let key = self
+                .blob_keys(&object.id)
+                .find(|key| self.blob_file(key).exists())
+                .unwrap_or_else(|| blob_key(&object.id, self.blob_shard_depth));
+            // Written after the reference is counted, so a concurrent delete of the
+            // last other reference can't remove the file out from under this row
+            self.write_blob_file(&key, &object.content)?;
+            Some(key)
+        } else {
+            None
+        };
+
+        // Commit, tree and tag content stays in the row
+        let mut model = git_object::ActiveModel::from_git_object(repository_id, object);
+        if let Some(key) = blob_key {
+            model.content = Set(Some(Vec::new()));
+            model.blob_path = Set(Some(key));
+        }
+        let model = model.insert(db).await?;
+
+        repository::Entity::update_many()
+            .col_expr(repository::Column::SizeBytes, Expr::col(repository::Column::SizeBytes).add(size))
+            .filter(repository::Column::Id.eq(repository_id))
+            .exec(db)
             .await?;
+        Ok(model)
+    }
+
+    /// Count one more repository holding a blob
+    async fn add_blob_ref<C: ConnectionTrait>(&self, db: &C, blob_id: &str) -> Result<()> {
+        let now = Utc::now();
+        blob_ref::Entity::insert(blob_ref::ActiveModel {
+            blob_id: Set(blob_id.to_string()),
+            ref_count: Set(1),
+            created_at: Set(now.into()),
+            updated_at: Set(now.into()),
+        })
+        .on_conflict(
+            OnConflict::column(blob_ref::Column::BlobId)
+                .value(
+                    blob_ref::Column::RefCount,
+                    Expr::col((blob_ref::Entity, blob_ref::Column::RefCount)).add(1),
+                )
+                .value(blob_ref::Column::UpdatedAt, Expr::value(now))
+                .to_owned(),
+        )
+        .exec_without_returning(db)
+        .await
+        .map_err(map_busy_error)?;
+        Ok(())
+    }
+
+    /// Write a blob file unless it is already in the store. Inside `transaction` the
+    /// file is staged until commit; otherwise it is moved into place right away.
+    fn write_blob_file(&self, key: &str, content: &[u8]) -> Result<()> {
+        let path = self.blob_file(key);
+        if path.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Written beside the final path and renamed, so readers never see a partial file
+        let partial = path.with_extension(format!("tmp-{}", Uuid::new_v4()));
+        fs::write(&partial, content)?;
+
+        let blob = StagedBlob { partial, path };
+        match STAGED_BLOBS.try_with(|staged| staged.push(blob.clone())) {
+            Ok(()) => Ok(()),
+            Err(_) => blob.finalize(),
+        }
+    }
+
+    /// Remove the files of blobs no repository references any more.
+    /// Each blob is re-checked first, since it may have been stored again meanwhile.
+    async fn remove_unreferenced_blobs(&self, blob_ids: Vec<String>) -> Result<()> {
+        for blob_id in blob_ids {
+            let referenced = blob_ref::Entity::find_by_id(blob_id.as_str())
+                .count(&self.db)
+                .await
+                .map_err(map_busy_error)?
+                > 0;
+            if referenced {
+                continue;
+            }
+            for key in self.blob_keys(&blob_id) {
+                match fs::remove_file(self.blob_file(&key)) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
         Ok(())
     }
 
-    /// Store a Git object (handles different storage for blobs vs other objects)
-    pub async fn store_object(
+    /// Get a Git object from a repository (handles reading from filesystem for blobs).
+    /// Returns `None` when the repository does not have the object, even if another
+    /// repository does, and `StorageError::RepositoryNotFound` for an unknown repository.
+    pub async fn get_object(
+        &self,
+        repository_id: Uuid,
+        object_id: &str,
+    ) -> Result<Option<GitObjectWithContent>> {
+        match self.find_object(repository_id, object_id).await? {
+            Some(obj) => Ok(Some(self.load_object_content(obj)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Look up an object row within a repository
+    async fn find_object(&self, repository_id: Uuid, object_id: &str) -> Result<Option<git_object::Model>> {
+        let obj = git_object::Entity::find_by_id((repository_id, object_id.to_string()))
+            .one(&self.db)
+            .await?;
+        if obj.is_none() && self.get_repository_by_id(repository_id).await?.is_none() {
+            return Err(StorageError::RepositoryNotFound { id: repository_id }.into());
+        }
+        Ok(obj)
+    }
+
+    /// Get a commit object, failing fast if the stored object is not a commit
+    pub async fn get_commit_object(&self, repository_id: Uuid, object_id: &str) -> Result<Option<Commit>> {
+        match self.get_typed_object(repository_id, object_id, ObjectKind::Commit).await? {
+            Some(obj) => Ok(Some(ObjectHandler::new().parse_commit(&obj.content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get a tree object, failing fast if the stored object is not a tree
+    pub async fn get_tree_object(&self, repository_id: Uuid, object_id: &str) -> Result<Option<Tree>> {
+        match self.get_typed_object(repository_id, object_id, ObjectKind::Tree).await? {
+            Some(obj) => {
+                // A tree's entries are named in the same format as the tree itself
+                let hash = HashAlgorithm::from_hex_len(object_id.len()).unwrap_or_default();
+                Ok(Some(ObjectHandler::new().with_hash_algorithm(hash).parse_tree(&obj.content)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get an annotated tag object, failing fast if the stored object is not a tag
+    pub async fn get_tag_object(&self, repository_id: Uuid, object_id: &str) -> Result<Option<Tag>> {
+        match self.get_typed_object(repository_id, object_id, ObjectKind::Tag).await? {
+            Some(obj) => Ok(Some(ObjectHandler::new().parse_tag(&obj.content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Size of a blob in bytes, read from its row without loading the content
+    pub async fn get_blob_size(&self, repository_id: Uuid, object_id: &str) -> Result<Option<u64>> {
+        let obj = self.find_typed_object(repository_id, object_id, ObjectKind::Blob).await?;
+        Ok(obj.map(|obj| obj.size as u64))
+    }
+
+    /// Read `length` bytes of a blob starting at `start`. Blobs in blob storage are
+    /// read by seeking, so only the requested slice is loaded.
+    pub async fn read_blob_range(
+        &self,
+        repository_id: Uuid,
+        object_id: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(obj) = self.find_typed_object(repository_id, object_id, ObjectKind::Blob).await? else {
+            return Ok(None);
+        };
+
+        let Some(blob_path) = obj.blob_path.as_ref() else {
+            let content = self.load_object_content(obj)?.content;
+            let start = (start as usize).min(content.len());
+            let end = start.saturating_add(length as usize).min(content.len());
+            return Ok(Some(content[start..end].to_vec()));
+        };
+
+        let mut file = fs::File::open(self.locate_blob_file(blob_path, &obj.id))
+            .map_err(|_| anyhow!("Failed to read blob file: {}", blob_path))?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut slice = Vec::with_capacity(length as usize);
+        file.take(length).read_to_end(&mut slice)?;
+        Ok(Some(slice))
+    }
+
+    /// Load an object after checking its stored type, before any content is read
+    async fn get_typed_object(
         &self,
         repository_id: Uuid,
-        object_id: String,
-        object_type: String,
-        size: i64,
-        content: Vec<u8>,
-    ) -> Result<git_object::Model> {
-        let (db_content, blob_path) = if object_type == "blob" {
-            // Store blob in filesystem
-            let blob_path = self.get_blob_path(&object_id);
-            
-            // Create directory structure if it doesn't exist
-            if let Some(parent) = blob_path.parent() {
-                fs::create_dir_all(parent)?;
+        object_id: &str,
+        expected_type: ObjectKind,
+    ) -> Result<Option<GitObjectWithContent>> {
+        match self.find_typed_object(repository_id, object_id, expected_type).await? {
+            Some(obj) => Ok(Some(self.load_object_content(obj)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Look up an object row, failing if it is not of `expected_type`
+    async fn find_typed_object(
+        &self,
+        repository_id: Uuid,
+        object_id: &str,
+        expected_type: ObjectKind,
+    ) -> Result<Option<git_object::Model>> {
+        match self.find_object(repository_id, object_id).await? {
+            Some(obj) if obj.object_type != expected_type => Err(StorageError::ObjectTypeMismatch {
+                id: obj.id,
+                expected: expected_type.to_string(),
+                found: obj.object_type.to_string(),
             }
-            
-            // Write blob content to file
-            fs::write(&blob_path, &content)?;
-            
-            // Store empty content in database and blob path
-            (Some(Vec::new()), Some(blob_path.to_string_lossy().to_string()))
-        } else {
-            // Store commit, tree, tag objects in database
-            (Some(content), None)
-        };
+            .into()),
+            obj => Ok(obj),
+        }
+    }
 
-        let obj = git_object::ActiveModel {
-            id: Set(object_id),
-            repository_id: Set(repository_id),
-            object_type: Set(object_type),
-            size: Set(size),
-            content: Set(db_content),
-            blob_path: Set(blob_path),
-            created_at: Set(Utc::now().into()),
+    /// Resolve an object's content from the database or blob storage
+    pub(crate) fn load_object_content(&self, obj: git_object::Model) -> Result<GitObjectWithContent> {
+        let blob_path = obj.blob_path.as_ref().filter(|_| obj.object_type == ObjectKind::Blob);
+        let content = if let Some(blob_path) = blob_path {
+            // Read blob content from filesystem
+            match fs::read(self.locate_blob_file(blob_path, &obj.id)) {
+                Ok(content) => content,
+                Err(_) => {
+                    return Err(anyhow!("Failed to read blob file: {}", blob_path));
+                }
+            }
+        } else if let Some(content) = obj.content.clone() {
+            // For non-blob objects or if blob_path is not set, use content from DB
+            if content.is_empty() && obj.object_type == ObjectKind::Blob {
+                return Err(anyhow!("Blob content not found in filesystem or database"));
+            }
+            content
+        } else {
+            return Err(anyhow!("Object content not found"));
         };
 
-        let result = obj.insert(&self.db).await?;
-        Ok(result)
+        Ok(GitObjectWithContent {
+            id: obj.id,
+            repository_id: obj.repository_id,
+            object_type: obj.object_type,
+            size: obj.size,
+            content,
+            created_at: obj.created_at,
+        })
     }
 
-    /// Get a Git object (handles reading from filesystem for blobs)
-    pub async fn get_object(&self, object_id: &str) -> Result<Option<GitObjectWithContent>> {
-        let obj = git_object::Entity::find_by_id(object_id)
-            .one(&self.db)
-            .await?;
-        
-        if let Some(obj) = obj {
-            let content = if obj.object_type == "blob" && obj.blob_path.is_some() {
-                // Read blob content from filesystem
-                let blob_path = obj.blob_path.as_ref().unwrap();
-                match fs::read(blob_path) {
-                    Ok(content) => content,
-                    Err(_) => {
-                        return Err(anyhow!("Failed to read blob file: {}", blob_path));
-                    }
-                }
-            } else if let Some(content) = obj.content.clone() {
-                // For non-blob objects or if blob_path is not set, use content from DB
-                if content.is_empty() && obj.object_type == "blob" {
-                    return Err(anyhow!("Blob content not found in filesystem or database"));
-                }
-                content
-            } else {
-                return Err(anyhow!("Object content not found"));
-            };
+    /// Location of a blob file from its key. Rows written before keys were made
+    /// relative hold absolute paths, which `join` passes through unchanged.
+    fn blob_file(&self, key: &str) -> PathBuf {
+        self.blob_storage_path.join(Path::new(key))
+    }
 
-            Ok(Some(GitObjectWithContent {
-                id: obj.id,
-                repository_id: obj.repository_id,
-                object_type: obj.object_type,
-                size: obj.size,
-                content,
-                created_at: obj.created_at,
-            }))
-        } else {
-            Ok(None)
+    /// Where a blob's file is: at its stored key, or else under whichever shard
+    /// depth has it, so files moved between layouts are still read. The stored key's
+    /// path when no layout has the file.
+    pub(crate) fn locate_blob_file(&self, key: &str, blob_id: &str) -> PathBuf {
+        let stored = self.blob_file(key);
+        if stored.exists() {
+            return stored;
         }
+        self.blob_keys(blob_id)
+            .map(|key| self.blob_file(&key))
+            .find(|path| path.exists())
+            .unwrap_or(stored)
     }
 
-    /// Get blob path for storage
-    fn get_blob_path(&self, object_id: &str) -> PathBuf {
-        // Use git-like directory structure: first 2 chars as directory, rest as filename
-        let (dir, filename) = object_id.split_at(2);
-        self.blob_storage_path.join(dir).join(filename)
+    /// Keys a blob's file may have, the configured shard depth first
+    fn blob_keys<'a>(&self, blob_id: &'a str) -> impl Iterator<Item = String> + 'a {
+        let depth = self.blob_shard_depth;
+        std::iter::once(depth)
+            .chain((1..=MAX_BLOB_SHARD_DEPTH).filter(move |d| *d != depth))
+            .map(move |depth| blob_key(blob_id, depth))
     }
 
     /// Get objects by repository
@@ -208,6 +1688,26 @@ impl RepositoryService {
         Ok(objects)
     }
 
+    /// Get one page of a repository's objects with their content, ordered by id
+    pub async fn get_objects_page(
+        &self,
+        repository_id: Uuid,
+        page: u64,
+        page_size: u64,
+    ) -> Result<Vec<GitObjectWithContent>> {
+        let objects = git_object::Entity::find()
+            .filter(git_object::Column::RepositoryId.eq(repository_id))
+            .order_by_asc(git_object::Column::Id)
+            .paginate(&self.db, page_size)
+            .fetch_page(page)
+            .await?;
+
+        objects
+            .into_iter()
+            .map(|obj| self.load_object_content(obj))
+            .collect()
+    }
+
     /// Store or update a Git reference
     pub async fn store_ref(
         &self,
@@ -216,6 +1716,9 @@ impl RepositoryService {
         target: String,
         is_symbolic: bool,
     ) -> Result<git_ref::Model> {
+        if !is_symbolic {
+            self.check_object_format(&self.db, repository_id, [target.as_str()]).await?;
+        }
         // Check if ref already exists
         if let Some(existing_ref) = git_ref::Entity::find()
             .filter(git_ref::Column::RepositoryId.eq(repository_id))
@@ -229,6 +1732,7 @@ impl RepositoryService {
             ref_active.is_symbolic = Set(is_symbolic);
             ref_active.updated_at = Set(Utc::now().into());
             let result = ref_active.update(&self.db).await?;
+            bump_ref_generation(&self.db, repository_id).await?;
             Ok(result)
         } else {
             // Create new ref
@@ -242,11 +1746,13 @@ impl RepositoryService {
                 updated_at: Set(Utc::now().into()),
             };
             let result = git_ref.insert(&self.db).await?;
+            bump_ref_generation(&self.db, repository_id).await?;
             Ok(result)
         }
     }
 
-    /// Get references by repository
+    /// Get references by repository, as stored: symbolic refs carry the name of the
+    /// ref they point at. Protocol code wants `get_resolved_refs` instead.
     pub async fn get_refs_by_repository(
         &self,
         repository_id: Uuid,
@@ -258,6 +1764,87 @@ impl RepositoryService {
         Ok(refs)
     }
 
+    /// References with every target an object id, for advertising to git clients.
+    /// Symbolic refs are resolved through any number of levels; those that end at an
+    /// unborn branch or loop back on themselves have nothing to advertise and are left out.
+    pub async fn get_resolved_refs(&self, repository_id: Uuid) -> Result<Vec<ResolvedRef>> {
+        let refs = if self.packed_refs {
+            self.get_packed_refs(repository_id).await?
+        } else {
+            self.get_refs_by_repository(repository_id)
+                .await?
+                .into_iter()
+                .map(GitRef::from)
+                .collect()
+        };
+
+        let lookup = |current: &str| {
+            refs.iter()
+                .find(|r| r.name == current)
+                .map(|r| (r.is_symbolic, r.target.as_str()))
+        };
+        Ok(refs
+            .iter()
+            .filter_map(|git_ref| {
+                let target = follow_ref(&git_ref.name, lookup).ok().flatten()?;
+                Some(ResolvedRef {
+                    name: git_ref.name.clone(),
+                    target,
+                    symref_target: git_ref.is_symbolic.then(|| git_ref.target.clone()),
+                })
+            })
+            .collect())
+    }
+
+    /// All refs of a repository from its packed-refs cache, rebuilding the cache
+    /// from the ref table when it was built at an older `ref_generation`
+    pub async fn get_packed_refs(&self, repository_id: Uuid) -> Result<Vec<GitRef>> {
+        let generation: Option<i64> = repository::Entity::find_by_id(repository_id)
+            .select_only()
+            .column(repository::Column::RefGeneration)
+            .into_tuple()
+            .one(&self.db)
+            .await?;
+        let Some(generation) = generation else {
+            return Ok(Vec::new());
+        };
+
+        if let Some(cached) = packed_refs::Entity::find_by_id(repository_id).one(&self.db).await? {
+            if cached.ref_generation == generation {
+                return parse_packed_refs(&cached.content);
+            }
+        }
+
+        // Rows are read after the generation: a change in between leaves the blob
+        // newer than its generation, which only costs a rebuild next time
+        let refs: Vec<GitRef> = self
+            .get_refs_by_repository(repository_id)
+            .await?
+            .into_iter()
+            .map(GitRef::from)
+            .collect();
+        let now = Utc::now();
+        packed_refs::Entity::insert(packed_refs::ActiveModel {
+            repository_id: Set(repository_id),
+            ref_generation: Set(generation),
+            content: Set(write_packed_refs(&refs)),
+            updated_at: Set(now.into()),
+        })
+        .on_conflict(
+            OnConflict::column(packed_refs::Column::RepositoryId)
+                .update_columns([
+                    packed_refs::Column::RefGeneration,
+                    packed_refs::Column::Content,
+                    packed_refs::Column::UpdatedAt,
+                ])
+                .to_owned(),
+        )
+        .exec_without_returning(&self.db)
+        .await
+        .map_err(map_busy_error)?;
+        Ok(refs)
+    }
+
     /// Get a specific reference
     pub async fn get_ref(
         &self,
@@ -272,6 +1859,23 @@ impl RepositoryService {
         Ok(git_ref)
     }
 
+    /// Reflog of one ref, newest first
+    pub async fn get_ref_log(
+        &self,
+        repository_id: Uuid,
+        name: &str,
+        limit: u64,
+    ) -> Result<Vec<ref_log::Model>> {
+        let entries = ref_log::Entity::find()
+            .filter(ref_log::Column::RepositoryId.eq(repository_id))
+            .filter(ref_log::Column::RefName.eq(name))
+            .order_by_desc(ref_log::Column::CreatedAt)
+            .limit(limit)
+            .all(&self.db)
+            .await?;
+        Ok(entries)
+    }
+
     /// Delete a reference
     pub async fn delete_ref(&self, repository_id: Uuid, name: &str) -> Result<()> {
         git_ref::Entity::delete_many()
@@ -279,12 +1883,13 @@ impl RepositoryService {
             .filter(git_ref::Column::Name.eq(name))
             .exec(&self.db)
             .await?;
+        bump_ref_generation(&self.db, repository_id).await?;
         Ok(())
     }
 
-    /// Check if object exists
-    pub async fn object_exists(&self, object_id: &str) -> Result<bool> {
-        let count = git_object::Entity::find_by_id(object_id)
+    /// Check if a repository has an object
+    pub async fn object_exists(&self, repository_id: Uuid, object_id: &str) -> Result<bool> {
+        let count = git_object::Entity::find_by_id((repository_id, object_id.to_string()))
             .count(&self.db)
             .await?;
         Ok(count > 0)
@@ -309,6 +1914,179 @@ impl RepositoryService {
     }
 }
 
+/// Trim, lowercase, dedupe and sort topics, rejecting any that aren't `[a-z0-9-]`
+/// or are longer than `MAX_TOPIC_LEN`
+pub fn normalize_topics(topics: Vec<String>) -> Result<Vec<String>> {
+    let mut normalized = BTreeSet::new();
+    for topic in topics {
+        let topic = topic.trim().to_lowercase();
+        let reason = if topic.is_empty() {
+            Some("topics cannot be empty".to_string())
+        } else if topic.chars().count() > MAX_TOPIC_LEN {
+            Some(format!("topics can be at most {} characters", MAX_TOPIC_LEN))
+        } else if !topic.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+            Some("topics may only contain letters, digits and hyphens".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(StorageError::InvalidTopic { topic, reason }.into());
+        }
+        normalized.insert(topic);
+    }
+
+    if normalized.len() > MAX_TOPICS_PER_REPOSITORY {
+        return Err(StorageError::TooManyTopics { max: MAX_TOPICS_PER_REPOSITORY }.into());
+    }
+    Ok(normalized.into_iter().collect())
+}
+
+/// Append a reflog entry for a ref change. Callers pass their transaction so the
+/// entry commits or rolls back together with the ref itself.
+pub(crate) async fn record_ref_change<C: ConnectionTrait>(
+    db: &C,
+    repository_id: Uuid,
+    name: &str,
+    old_target: Option<&str>,
+    new_target: Option<&str>,
+) -> std::result::Result<(), sea_orm::DbErr> {
+    if old_target == new_target {
+        return Ok(());
+    }
+
+    let entry = ref_log::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        repository_id: Set(repository_id),
+        ref_name: Set(name.to_string()),
+        old_target: Set(old_target.map(|t| t.to_string())),
+        new_target: Set(new_target.map(|t| t.to_string())),
+        created_at: Set(Utc::now().into()),
+    };
+    entry.insert(db).await?;
+    bump_ref_generation(db, repository_id).await
+}
+
+/// Keep a branch's `branches` row in step with its ref: moved, created and
+/// removed along with it
+async fn sync_branch_row<C: ConnectionTrait>(
+    db: &C,
+    repository_id: Uuid,
+    branch: &str,
+    target: Option<&str>,
+) -> std::result::Result<(), sea_orm::DbErr> {
+    let Some(target) = target else {
+        branch::Entity::delete_many()
+            .filter(branch::Column::RepositoryId.eq(repository_id))
+            .filter(branch::Column::Name.eq(branch))
+            .exec(db)
+            .await?;
+        return Ok(());
+    };
+
+    let now = Utc::now();
+    let moved = branch::Entity::update_many()
+        .col_expr(branch::Column::CommitId, Expr::value(target))
+        .col_expr(branch::Column::UpdatedAt, Expr::value(now))
+        .filter(branch::Column::RepositoryId.eq(repository_id))
+        .filter(branch::Column::Name.eq(branch))
+        .exec(db)
+        .await?;
+    if moved.rows_affected == 0 {
+        let is_default = repository::Entity::find_by_id(repository_id)
+            .one(db)
+            .await?
+            .is_some_and(|repository| repository.default_branch == branch);
+        branch::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            repository_id: Set(repository_id),
+            name: Set(branch.to_string()),
+            commit_id: Set(target.to_string()),
+            is_default: Set(is_default),
+            created_at: Set(now.into()),
+            updated_at: Set(now.into()),
+        }
+        .insert(db)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Count a ref change against `repositories.ref_generation`
+pub(crate) async fn bump_ref_generation<C: ConnectionTrait>(
+    db: &C,
+    repository_id: Uuid,
+) -> std::result::Result<(), sea_orm::DbErr> {
+    repository::Entity::update_many()
+        .col_expr(
+            repository::Column::RefGeneration,
+            Expr::col(repository::Column::RefGeneration).add(1),
+        )
+        .filter(repository::Column::Id.eq(repository_id))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+tokio::task_local! {
+    /// Blob files written inside `RepositoryService::transaction`
+    static STAGED_BLOBS: StagedBlobs;
+}
+
+/// A blob file written beside its final location, waiting for the transaction
+#[derive(Debug, Clone)]
+struct StagedBlob {
+    partial: PathBuf,
+    path: PathBuf,
+}
+
+impl StagedBlob {
+    /// Move the file into place; if another writer got there first the content is
+    /// the same, so the staged copy is dropped
+    fn finalize(&self) -> Result<()> {
+        if self.path.exists() {
+            let _ = fs::remove_file(&self.partial);
+            return Ok(());
+        }
+        fs::rename(&self.partial, &self.path)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct StagedBlobs(Arc<std::sync::Mutex<Vec<StagedBlob>>>);
+
+impl StagedBlobs {
+    fn push(&self, blob: StagedBlob) {
+        self.0.lock().unwrap().push(blob);
+    }
+
+    fn finalize(&self) -> Result<()> {
+        let blobs = std::mem::take(&mut *self.0.lock().unwrap());
+        blobs.iter().try_for_each(StagedBlob::finalize)
+    }
+
+    fn discard(&self) {
+        for blob in std::mem::take(&mut *self.0.lock().unwrap()) {
+            let _ = fs::remove_file(&blob.partial);
+        }
+    }
+}
+
+/// Key of a blob file under the blob storage root, `depth` directories of two hex
+/// digits deep: `ab/cdef...` for 1, like git's loose objects
+fn blob_key(object_id: &str, depth: usize) -> String {
+    let mut key = String::with_capacity(object_id.len() + depth);
+    let mut rest = object_id;
+    for _ in 0..depth {
+        let (dir, tail) = rest.split_at(2.min(rest.len()));
+        key.push_str(dir);
+        key.push('/');
+        rest = tail;
+    }
+    key.push_str(rest);
+    key
+}
+
 #[derive(Debug)]
 pub struct RepositoryStats {
     pub object_count: u64,
@@ -319,8 +2097,874 @@ pub struct RepositoryStats {
 pub struct GitObjectWithContent {
     pub id: String,
     pub repository_id: Uuid,
-    pub object_type: String,
+    pub object_type: ObjectKind,
     pub size: i64,
     pub content: Vec<u8>,
     pub created_at: chrono::DateTime<chrono::FixedOffset>,
-}
\ No newline at end of file
+}
+
+impl GitObjectWithContent {
+    pub fn into_git_object(self) -> Result<GitObject> {
+        Ok(GitObject {
+            obj_type: self.object_type.into(),
+            size: self.content.len(),
+            id: self.id,
+            content: self.content,
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_db, run_migrations, UserService};
+    use git_protocol::ObjectType;
+
+    async fn setup() -> (RepositoryService, repository::Model) {
+        setup_with_db("sqlite::memory:").await
+    }
+
+    async fn setup_with_db(database_url: &str) -> (RepositoryService, repository::Model) {
+        let db = init_db(database_url).await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let blob_storage_path = std::env::temp_dir().join(format!("git-storage-test-{}", Uuid::new_v4()));
+        let service = RepositoryService::new(db.clone(), Some(blob_storage_path));
+        let owner = UserService::new(db)
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = service
+            .create_repository("test-repo".to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        (service, repo)
+    }
+
+    #[tokio::test]
+    async fn test_object_types_round_trip_and_reject_unknown_values() {
+        use sea_orm::{ConnectionTrait, Statement};
+
+        let (service, repo) = setup().await;
+        let handler = ObjectHandler::new();
+        let tree = handler.create_tree(&Tree { entries: vec![] }).unwrap();
+        let objects = [
+            (ObjectKind::Blob, handler.create_blob(b"blob").unwrap()),
+            (ObjectKind::Tree, tree),
+            (ObjectKind::Commit, handler.parse_object(ObjectType::Commit, b"not parsed").unwrap()),
+            (ObjectKind::Tag, handler.parse_object(ObjectType::Tag, b"not parsed").unwrap()),
+        ];
+        for (kind, obj) in &objects {
+            assert_eq!(kind.as_str().parse::<ObjectKind>().unwrap(), *kind);
+            assert_eq!(ObjectKind::from(&obj.obj_type), *kind);
+            service
+                .store_object(repo.id, obj.id.clone(), *kind, obj.size as i64, obj.content.clone())
+                .await
+                .unwrap();
+            let stored = service.get_object(repo.id, &obj.id).await.unwrap().unwrap();
+            assert_eq!(stored.object_type, *kind);
+            assert_eq!(stored.into_git_object().unwrap().obj_type, obj.obj_type);
+        }
+        assert!(matches!(
+            "Commit".parse::<ObjectKind>(),
+            Err(StorageError::UnknownObjectType { value }) if value == "Commit"
+        ));
+
+        // The CHECK constraint refuses anything else ...
+        let db = service.get_db();
+        let set_type = |value: &str| {
+            Statement::from_string(
+                db.get_database_backend(),
+                format!("UPDATE git_objects SET object_type = '{}' WHERE id = '{}'", value, objects[0].1.id),
+            )
+        };
+        let err = db.execute(set_type("Commit")).await.unwrap_err();
+        assert!(err.to_string().contains("CHECK"), "{}", err);
+
+        // ... and a row that got past it anyway fails to load instead of being misread
+        db.execute(Statement::from_string(
+            db.get_database_backend(),
+            "PRAGMA ignore_check_constraints = ON".to_string(),
+        ))
+        .await
+        .unwrap();
+        db.execute(set_type("garbage")).await.unwrap();
+        assert!(service.get_object(repo.id, &objects[0].1.id).await.is_err());
+        assert!(service.get_object(repo.id, &objects[1].1.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tag_target_types_round_trip_and_reject_unknown_values() {
+        use crate::entities::tag;
+
+        let (service, repo) = setup().await;
+        let db = service.get_db();
+        let mut ids = Vec::new();
+        for kind in [ObjectKind::Commit, ObjectKind::Tree, ObjectKind::Blob, ObjectKind::Tag] {
+            let now = chrono::Utc::now();
+            let stored = tag::ActiveModel {
+                id: Set(Uuid::new_v4()),
+                repository_id: Set(repo.id),
+                name: Set(format!("v-{}", kind)),
+                target_id: Set("a".repeat(40)),
+                target_type: Set(kind),
+                tag_object_id: Set(None),
+                tagger_name: Set(None),
+                tagger_email: Set(None),
+                tagger_date: Set(None),
+                message: Set(None),
+                content: Set(None),
+                is_lightweight: Set(true),
+                created_at: Set(now.into()),
+                updated_at: Set(now.into()),
+            }
+            .insert(db)
+            .await
+            .unwrap();
+            let loaded = tag::Entity::find_by_id(stored.id).one(db).await.unwrap().unwrap();
+            assert_eq!(loaded.target_type, kind);
+            assert_eq!(ObjectType::from(loaded.target_type), ObjectType::from(kind));
+            ids.push(stored.id);
+        }
+
+        // A legacy value the migration couldn't map fails to load instead of being misread
+        tag::Entity::update_many()
+            .col_expr(tag::Column::TargetType, sea_orm::sea_query::Expr::value("garbage"))
+            .filter(tag::Column::Id.eq(ids[0]))
+            .exec(db)
+            .await
+            .unwrap();
+        let err = tag::Entity::find_by_id(ids[0]).one(db).await.unwrap_err();
+        assert!(err.to_string().contains("garbage"), "{}", err);
+        assert!(tag::Entity::find_by_id(ids[1]).one(db).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_get_commit_object_rejects_blob() {
+        let (service, repo) = setup().await;
+        let blob = ObjectHandler::new().create_blob(b"not a commit").unwrap();
+        service
+            .store_object(repo.id, blob.id.clone(), ObjectKind::Blob, blob.size as i64, blob.content)
+            .await
+            .unwrap();
+
+        let err = service.get_commit_object(repo.id, &blob.id).await.unwrap_err();
+        match err.downcast_ref::<StorageError>() {
+            Some(StorageError::ObjectTypeMismatch { id, expected, found }) => {
+                assert_eq!(id, &blob.id);
+                assert_eq!(expected, "commit");
+                assert_eq!(found, "blob");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(err.to_string(), format!("expected commit, found blob for {}", blob.id));
+
+        // Other typed getters apply the same guard, and unknown ids are simply absent
+        assert!(service.get_tree_object(repo.id, &blob.id).await.is_err());
+        assert!(service.get_commit_object(repo.id, &"0".repeat(40)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_commit_object_parses_commit() {
+        let (service, repo) = setup().await;
+        let commit = Commit {
+            tree: "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+            parents: vec![],
+            author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+            committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+            message: "Initial commit\n".to_string(),
+            author_date: Utc::now(),
+            commit_date: Utc::now(),
+            encoding: None,
+        };
+        let obj = ObjectHandler::new().create_commit(&commit).unwrap();
+        service
+            .store_object(repo.id, obj.id.clone(), ObjectKind::Commit, obj.size as i64, obj.content)
+            .await
+            .unwrap();
+
+        let parsed = service.get_commit_object(repo.id, &obj.id).await.unwrap().unwrap();
+        assert_eq!(parsed.tree, commit.tree);
+        assert_eq!(parsed.author, commit.author);
+    }
+
+    #[tokio::test]
+    async fn test_object_lookup_is_scoped_to_repository() {
+        let (service, repo_a) = setup().await;
+        let repo_b = service
+            .create_repository("other-repo".to_string(), None, "main".to_string(), repo_a.owner_id, Visibility::Public, None)
+            .await
+            .unwrap();
+
+        let blob = ObjectHandler::new().create_blob(b"only in A").unwrap();
+        service
+            .store_object(repo_a.id, blob.id.clone(), ObjectKind::Blob, blob.size as i64, blob.content.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(service.get_object(repo_a.id, &blob.id).await.unwrap().unwrap().content, blob.content);
+        assert!(service.get_object(repo_b.id, &blob.id).await.unwrap().is_none());
+        assert!(service.object_exists(repo_a.id, &blob.id).await.unwrap());
+        assert!(!service.object_exists(repo_b.id, &blob.id).await.unwrap());
+
+        // An unknown repository is reported as such rather than as a missing object
+        let err = service.get_object(Uuid::new_v4(), &blob.id).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::RepositoryNotFound { .. })));
+
+        // Both repositories can hold the same object
+        service
+            .store_object(repo_b.id, blob.id.clone(), ObjectKind::Blob, blob.size as i64, blob.content.clone())
+            .await
+            .unwrap();
+        assert!(service.get_object(repo_b.id, &blob.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_update_repository_rejects_stale_version() {
+        let (service, repo) = setup().await;
+        assert_eq!(repo.version, 1);
+
+        let first = RepositoryUpdate { description: Some("first".to_string()), ..Default::default() };
+        let updated = service.update_repository(repo.id, repo.version, first).await.unwrap();
+        assert_eq!(updated.version, 2);
+        assert_eq!(updated.description.as_deref(), Some("first"));
+
+        // A second editor still holding version 1 must not overwrite the first
+        let second = RepositoryUpdate { visibility: Some(Visibility::Private), ..Default::default() };
+        let err = service.update_repository(repo.id, repo.version, second).await.unwrap_err();
+        match err.downcast_ref::<StorageError>() {
+            Some(StorageError::VersionConflict { expected, current, .. }) => {
+                assert_eq!((*expected, *current), (1, 2));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        let current = service.get_repository_by_id(repo.id).await.unwrap().unwrap();
+        assert_eq!(current.visibility, Visibility::Public);
+    }
+
+    #[tokio::test]
+    async fn test_visibility_levels_per_reader() {
+        let (service, public) = setup().await;
+        let users = UserService::new(service.get_db().clone());
+        let owner = users.get_user_by_id(public.owner_id).await.unwrap().unwrap();
+        let stranger = users
+            .create_user("stranger".to_string(), "stranger@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let admin = users
+            .create_user("admin".to_string(), "admin@example.com".to_string(), "hash".to_string(), None, true)
+            .await
+            .unwrap();
+        let internal = service
+            .create_repository("internal".to_string(), None, "main".to_string(), owner.id, Visibility::Internal, None)
+            .await
+            .unwrap();
+        let private = service
+            .create_repository("private".to_string(), None, "main".to_string(), owner.id, Visibility::Private, None)
+            .await
+            .unwrap();
+
+        let readers = [
+            (None, vec!["test-repo"]),
+            (Some(&stranger), vec!["internal", "test-repo"]),
+            (Some(&owner), vec!["internal", "private", "test-repo"]),
+            (Some(&admin), vec!["internal", "private", "test-repo"]),
+        ];
+        for (reader, expected) in readers {
+            let mut readable: Vec<&str> = [&public, &internal, &private]
+                .into_iter()
+                .filter(|repo| repo.readable_by(reader))
+                .map(|repo| repo.name.as_str())
+                .collect();
+            let mut listed: Vec<String> = service
+                .list_visible_repositories(reader, None, None)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|repo| repo.name)
+                .collect();
+            listed.sort();
+            readable.sort();
+            let who = reader.map(|user| user.username.as_str()).unwrap_or("anonymous");
+            assert_eq!(readable, expected, "readable by {}", who);
+            assert_eq!(listed, expected, "listed for {}", who);
+        }
+
+        let listed = service.list_visible_repositories(Some(&stranger), Some(stranger.id), None).await.unwrap();
+        assert!(listed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_pushes_are_serialized() {
+        let test_dir = std::env::temp_dir().join(format!("git-storage-stress-{}", Uuid::new_v4()));
+        fs::create_dir_all(&test_dir).unwrap();
+        let database_url = format!("sqlite:{}?mode=rwc", test_dir.join("stress.db").display());
+        let (service, repo) = setup_with_db(&database_url).await;
+
+        let mut pushes = Vec::new();
+        for i in 0..10 {
+            let service = service.clone();
+            let repository_id = repo.id;
+            pushes.push(tokio::spawn(async move {
+                let handler = ObjectHandler::new();
+                let guard = service.lock_writes(repository_id).await?;
+
+                let parent = service.get_ref(repository_id, "refs/heads/main").await?.map(|r| r.target);
+                let blob = handler.create_blob(format!("push {}\n", i).as_bytes())?;
+                let tree = handler.create_tree(&Tree {
+                    entries: vec![git_protocol::objects::TreeEntry {
+                        mode: "100644".to_string(),
+                        name: "file.txt".to_string(),
+                        hash: blob.id.clone(),
+                    }],
+                })?;
+                let commit = handler.create_commit(&Commit {
+                    tree: tree.id.clone(),
+                    parents: parent.clone().into_iter().collect(),
+                    author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                    message: format!("push {}\n", i),
+                    author_date: Utc::now(),
+                    commit_date: Utc::now(),
+                    encoding: None,
+                })?;
+                let commit_id = commit.id.clone();
+
+                service.store_objects(&guard, vec![blob, tree, commit]).await?;
+                service
+                    .update_refs(
+                        &guard,
+                        vec![RefUpdate {
+                            name: "refs/heads/main".to_string(),
+                            old_target: parent,
+                            new_target: Some(commit_id),
+                        }],
+                    )
+                    .await?;
+                anyhow::Ok(())
+            }));
+        }
+
+        for push in pushes {
+            push.await.unwrap().unwrap();
+        }
+
+        // Every push landed on top of the previous one
+        let mut history = 0;
+        let mut current = service.get_ref(repo.id, "refs/heads/main").await.unwrap().map(|r| r.target);
+        while let Some(id) = current {
+            let commit = service.get_commit_object(repo.id, &id).await.unwrap().unwrap();
+            history += 1;
+            current = commit.parents.first().cloned();
+        }
+        assert_eq!(history, 10);
+
+        let metrics = service.write_metrics();
+        assert!(metrics.enabled);
+        assert_eq!(metrics.acquisitions, 10);
+        assert_eq!(metrics.timeouts, 0);
+    }
+
+    #[tokio::test]
+    async fn test_update_refs_rejects_stale_old_target() {
+        let (service, repo) = setup().await;
+        let guard = service.lock_writes(repo.id).await.unwrap();
+        service
+            .update_refs(
+                &guard,
+                vec![RefUpdate {
+                    name: "refs/heads/main".to_string(),
+                    old_target: None,
+                    new_target: Some("a".repeat(40)),
+                }],
+            )
+            .await
+            .unwrap();
+
+        let err = service
+            .update_refs(
+                &guard,
+                vec![RefUpdate {
+                    name: "refs/heads/main".to_string(),
+                    old_target: Some("b".repeat(40)),
+                    new_target: Some("c".repeat(40)),
+                }],
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::StaleRef { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_set_head_requires_existing_branch() {
+        let (service, repo) = setup().await;
+        let guard = service.lock_writes(repo.id).await.unwrap();
+
+        let err = service.set_head(&guard, "refs/heads/dev").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::RefNotFound { .. })));
+        assert_eq!(service.get_head_target(repo.id).await.unwrap(), "refs/heads/main");
+
+        service
+            .update_refs(
+                &guard,
+                vec![RefUpdate {
+                    name: "refs/heads/dev".to_string(),
+                    old_target: None,
+                    new_target: Some("a".repeat(40)),
+                }],
+            )
+            .await
+            .unwrap();
+        service.set_head(&guard, "refs/heads/dev").await.unwrap();
+
+        let log = service.get_ref_log(repo.id, "refs/heads/dev", 10).await.unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].old_target, None);
+        assert_eq!(log[0].new_target, Some("a".repeat(40)));
+
+        assert_eq!(service.get_head_target(repo.id).await.unwrap(), "refs/heads/dev");
+        let repo = service.get_repository_by_id(repo.id).await.unwrap().unwrap();
+        assert_eq!(repo.default_branch, "dev");
+    }
+
+    #[tokio::test]
+    async fn test_write_lock_timeout_is_retryable() {
+        let (service, repo) = setup().await;
+        let service = service.with_write_lock(WriteLockScope::Global, Duration::from_millis(20));
+
+        let _held = service.lock_writes(repo.id).await.unwrap();
+        let err = service.lock_writes(Uuid::new_v4()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::ResourceBusy)));
+        assert_eq!(service.write_metrics().timeouts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_set_topics_replaces_whole_set() {
+        let (service, repo) = setup().await;
+
+        let topics = vec![" Rust ".to_string(), "git".to_string(), "rust".to_string()];
+        assert_eq!(service.set_topics(repo.id, topics).await.unwrap(), vec!["git", "rust"]);
+
+        let topics = vec!["storage".to_string(), "git".to_string()];
+        service.set_topics(repo.id, topics).await.unwrap();
+        assert_eq!(service.get_topics(repo.id).await.unwrap(), vec!["git", "storage"]);
+
+        // A rejected set leaves the previous one in place
+        let topics = vec!["ok".to_string(), "not ok".to_string()];
+        assert!(service.set_topics(repo.id, topics).await.is_err());
+        assert_eq!(service.get_topics(repo.id).await.unwrap(), vec!["git", "storage"]);
+
+        service.set_topics(repo.id, vec![]).await.unwrap();
+        assert!(service.get_topics(repo.id).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_normalize_topics_validation() {
+        let invalid = |topic: &str| {
+            let err = normalize_topics(vec![topic.to_string()]).unwrap_err();
+            matches!(err.downcast_ref::<StorageError>(), Some(StorageError::InvalidTopic { .. }))
+        };
+        assert!(invalid(""));
+        assert!(invalid("under_score"));
+        assert!(invalid("caf\u{e9}"));
+        assert!(invalid(&"a".repeat(MAX_TOPIC_LEN + 1)));
+        assert_eq!(normalize_topics(vec!["a".repeat(MAX_TOPIC_LEN)]).unwrap().len(), 1);
+
+        let too_many: Vec<String> = (0..=MAX_TOPICS_PER_REPOSITORY).map(|i| format!("topic-{}", i)).collect();
+        let err = normalize_topics(too_many).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::TooManyTopics { max: 20 })));
+
+        // Duplicates collapse before the limit is checked
+        let repeated = vec!["same".to_string(); MAX_TOPICS_PER_REPOSITORY + 1];
+        assert_eq!(normalize_topics(repeated).unwrap(), vec!["same"]);
+    }
+
+    /// Files under the blob storage root, temporaries included
+    fn count_blob_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|entry| match entry.file_type() {
+                        Ok(t) if t.is_dir() => count_blob_files(&entry.path()),
+                        _ => 1,
+                    })
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_symbolic_ref_chains_resolve_and_cycles_error() {
+        let (service, repo) = setup().await;
+        let tip = "1".repeat(40);
+        for (name, target, is_symbolic) in [
+            ("refs/heads/main", tip.as_str(), false),
+            ("refs/remotes/origin/main", tip.as_str(), false),
+            // Two levels: alias -> origin/HEAD -> origin/main
+            ("refs/remotes/origin/HEAD", "refs/remotes/origin/main", true),
+            ("refs/aliases/upstream", "refs/remotes/origin/HEAD", true),
+            ("refs/loop/a", "refs/loop/b", true),
+            ("refs/loop/b", "refs/loop/a", true),
+            ("refs/aliases/unborn", "refs/heads/missing", true),
+        ] {
+            service
+                .store_ref(repo.id, name.to_string(), target.to_string(), is_symbolic)
+                .await
+                .unwrap();
+        }
+
+        let raw = service.get_refs_by_repository(repo.id).await.unwrap();
+        assert_eq!(resolve_ref(&raw, "refs/aliases/upstream").unwrap(), Some(tip.clone()));
+        assert_eq!(resolve_ref(&raw, "refs/aliases/unborn").unwrap(), None);
+        let err = resolve_ref(&raw, "refs/loop/a").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::SymbolicRefCycle { name }) if name == "refs/loop/a"
+        ));
+
+        let resolved = service.get_resolved_refs(repo.id).await.unwrap();
+        let upstream = resolved.iter().find(|r| r.name == "refs/aliases/upstream").unwrap();
+        assert_eq!(upstream.target, tip);
+        assert_eq!(upstream.symref_target.as_deref(), Some("refs/remotes/origin/HEAD"));
+        assert!(resolved.iter().all(|r| r.target.len() == 40));
+        assert!(!resolved.iter().any(|r| r.name.starts_with("refs/loop/") || r.name == "refs/aliases/unborn"));
+
+        // Symbolic refs can't be moved like branches
+        let guard = service.lock_writes(repo.id).await.unwrap();
+        let err = service
+            .update_refs(
+                &guard,
+                vec![RefUpdate {
+                    name: "refs/remotes/origin/HEAD".to_string(),
+                    old_target: Some("refs/remotes/origin/main".to_string()),
+                    new_target: Some("2".repeat(40)),
+                }],
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "cannot update symbolic ref refs/remotes/origin/HEAD");
+    }
+
+    #[tokio::test]
+    async fn test_packed_refs_cache_follows_ref_changes() {
+        let (service, repo) = setup().await;
+        let packed = service.clone().with_packed_refs(true);
+        let sorted = |mut refs: Vec<ResolvedRef>| {
+            refs.sort_by(|a, b| a.name.cmp(&b.name));
+            refs
+        };
+        let cached = |service: &RepositoryService| {
+            let service = service.clone();
+            async move { packed_refs::Entity::find_by_id(repo.id).one(&service.db).await.unwrap() }
+        };
+
+        let (one, two) = ("1".repeat(40), "2".repeat(40));
+        service.store_ref(repo.id, "refs/heads/main".to_string(), one.clone(), false).await.unwrap();
+        service.store_ref(repo.id, "refs/tags/v1".to_string(), one.clone(), false).await.unwrap();
+        service.store_ref(repo.id, "HEAD".to_string(), "refs/heads/main".to_string(), true).await.unwrap();
+        assert!(cached(&service).await.is_none());
+
+        // The first read builds the blob; the advertisement matches the per-row one
+        let rows = sorted(service.get_resolved_refs(repo.id).await.unwrap());
+        assert_eq!(sorted(packed.get_resolved_refs(repo.id).await.unwrap()), rows);
+        assert!(rows.iter().any(|r| r.name == "HEAD" && r.symref_target.as_deref() == Some("refs/heads/main")));
+        let built = cached(&service).await.unwrap();
+        assert!(built.content.contains(&format!("{} refs/tags/v1", one)));
+
+        // Unchanged refs are served from the blob as is
+        assert_eq!(sorted(packed.get_resolved_refs(repo.id).await.unwrap()), rows);
+        assert_eq!(cached(&service).await.unwrap().updated_at, built.updated_at);
+
+        // Moving, adding and deleting refs all show up on the next read
+        service.store_ref(repo.id, "refs/heads/main".to_string(), two.clone(), false).await.unwrap();
+        service.store_ref(repo.id, "refs/heads/topic".to_string(), one.clone(), false).await.unwrap();
+        service.delete_ref(repo.id, "refs/tags/v1").await.unwrap();
+        let rows = sorted(service.get_resolved_refs(repo.id).await.unwrap());
+        assert_eq!(sorted(packed.get_resolved_refs(repo.id).await.unwrap()), rows);
+        assert_eq!(rows.iter().find(|r| r.name == "HEAD").unwrap().target, two);
+        let rebuilt = cached(&service).await.unwrap();
+        assert!(rebuilt.ref_generation > built.ref_generation);
+        assert!(!rebuilt.content.contains("refs/tags/v1"));
+
+        // Single lookups still read the table
+        assert_eq!(packed.get_ref(repo.id, "refs/heads/topic").await.unwrap().unwrap().target, one);
+    }
+
+    #[tokio::test]
+    async fn test_soft_deleted_repositories_can_be_restored_until_purged() {
+        let (service, repo) = setup().await;
+        let handler = ObjectHandler::new();
+        let blob = handler.create_blob(b"kept until purged").unwrap();
+        service
+            .store_object(repo.id, blob.id.clone(), ObjectKind::Blob, blob.size as i64, blob.content.clone())
+            .await
+            .unwrap();
+        service
+            .store_ref(repo.id, "refs/heads/main".to_string(), "1".repeat(40), false)
+            .await
+            .unwrap();
+
+        let deleted = service.soft_delete_repository(repo.id).await.unwrap();
+        assert!(deleted.deleted_at.is_some());
+        assert_eq!(deleted.deleted_name.as_deref(), Some("test-repo"));
+        assert!(service.get_repository_by_id(repo.id).await.unwrap().is_none());
+        assert!(service.get_repository_by_name("test-repo").await.unwrap().is_none());
+        assert!(service.list_repositories().await.unwrap().is_empty());
+        assert_eq!(service.list_deleted_repositories().await.unwrap().len(), 1);
+
+        // The name is free meanwhile, which blocks restoring until it is again
+        let reuse = service
+            .create_repository("test-repo".to_string(), None, "main".to_string(), repo.owner_id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let err = service.restore_repository(repo.id).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::RepositoryNameTaken { name }) if name == "test-repo"
+        ));
+        service.soft_delete_repository(reuse.id).await.unwrap();
+
+        let restored = service.restore_repository(repo.id).await.unwrap();
+        assert_eq!(restored.name, "test-repo");
+        assert!(restored.deleted_at.is_none() && restored.deleted_name.is_none());
+        assert_eq!(service.get_refs_by_repository(repo.id).await.unwrap().len(), 1);
+        assert_eq!(service.get_object(repo.id, &blob.id).await.unwrap().unwrap().content, blob.content);
+
+        // Purging only takes repositories deleted before the cutoff
+        service.soft_delete_repository(repo.id).await.unwrap();
+        let now = Utc::now();
+        let purged = service.purge_deleted_repositories(now - chrono::Duration::days(7)).await.unwrap();
+        assert!(purged.is_empty());
+        let mut purged = service.purge_deleted_repositories(now + chrono::Duration::days(8)).await.unwrap();
+        purged.sort();
+        let mut expected = vec![repo.id, reuse.id];
+        expected.sort();
+        assert_eq!(purged, expected);
+        assert!(service.list_deleted_repositories().await.unwrap().is_empty());
+        assert!(blob_ref::Entity::find_by_id(blob.id.clone()).one(&service.db).await.unwrap().is_none());
+        assert_eq!(count_blob_files(&service.blob_storage_path), 0);
+    }
+
+    #[tokio::test]
+    async fn test_forks_share_blob_files() {
+        let (service, repo) = setup().await;
+        let handler = ObjectHandler::new();
+        let blobs: Vec<_> = [&b"vendored"[..], b"dependency"]
+            .iter()
+            .map(|content| handler.create_blob(content).unwrap())
+            .collect();
+        for blob in &blobs {
+            service
+                .store_object(repo.id, blob.id.clone(), ObjectKind::Blob, blob.size as i64, blob.content.clone())
+                .await
+                .unwrap();
+        }
+        service
+            .store_ref(repo.id, "refs/heads/main".to_string(), "1".repeat(40), false)
+            .await
+            .unwrap();
+        assert_eq!(count_blob_files(&service.blob_storage_path), 2);
+
+        let fork = service
+            .fork_repository(repo.id, "test-repo-fork".to_string(), repo.owner_id)
+            .await
+            .unwrap();
+        assert_eq!(count_blob_files(&service.blob_storage_path), 2);
+        let refs = service.get_refs_by_repository(fork.id).await.unwrap();
+        assert_eq!(refs.len(), 1);
+
+        // A third repository pushing the same content doesn't rewrite the file either
+        let other = service
+            .create_repository("other".to_string(), None, "main".to_string(), repo.owner_id, Visibility::Public, None)
+            .await
+            .unwrap();
+        service
+            .store_object(other.id, blobs[0].id.clone(), ObjectKind::Blob, blobs[0].size as i64, blobs[0].content.clone())
+            .await
+            .unwrap();
+        assert_eq!(count_blob_files(&service.blob_storage_path), 2);
+        let counts = |id: String| async {
+            blob_ref::Entity::find_by_id(id).one(&service.db).await.unwrap().map(|b| b.ref_count)
+        };
+        assert_eq!(counts(blobs[0].id.clone()).await, Some(3));
+
+        service.delete_repository(repo.id).await.unwrap();
+        for blob in &blobs {
+            let obj = service.get_object(fork.id, &blob.id).await.unwrap().unwrap();
+            assert_eq!(obj.content, blob.content);
+        }
+        assert_eq!(counts(blobs[1].id.clone()).await, Some(1));
+
+        // The last reference takes the file with it
+        service.delete_repository(fork.id).await.unwrap();
+        assert_eq!(counts(blobs[1].id.clone()).await, None);
+        assert_eq!(count_blob_files(&service.blob_storage_path), 1);
+        let obj = service.get_object(other.id, &blobs[0].id).await.unwrap().unwrap();
+        assert_eq!(obj.content, blobs[0].content);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_ownership_moves_repository_and_records_event() {
+        use crate::events::EventService;
+
+        let (service, repo) = setup().await;
+        let users = UserService::new(service.get_db().clone());
+        let new_owner = users
+            .create_user("new-owner".to_string(), "new@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+
+        let moved = service
+            .transfer_ownership(repo.id, new_owner.id, None, Some(repo.owner_id))
+            .await
+            .unwrap();
+        assert_eq!(moved.owner_id, new_owner.id);
+        assert_eq!(moved.name, "test-repo");
+        assert_eq!(moved.version, repo.version + 1);
+
+        let events = EventService::new(service.get_db().clone()).list_by_repository(repo.id, 10).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, "transferred");
+        assert_eq!(events[0].actor_id, Some(repo.owner_id));
+        let payload: TransferEventPayload = serde_json::from_str(events[0].payload.as_deref().unwrap()).unwrap();
+        assert_eq!(
+            payload,
+            TransferEventPayload {
+                from_owner: repo.owner_id,
+                to_owner: new_owner.id,
+                from_name: "test-repo".to_string(),
+                to_name: "test-repo".to_string(),
+            }
+        );
+
+        // Handing it back under a name its old owner already uses is refused, not renamed around
+        service
+            .create_repository("taken".to_string(), None, "main".to_string(), repo.owner_id, Visibility::Public, None)
+            .await
+            .unwrap();
+        let err = service
+            .transfer_ownership(repo.id, repo.owner_id, Some("taken".to_string()), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::RepositoryNameTaken { name }) if name == "taken"
+        ));
+        let err = service.transfer_ownership(repo.id, Uuid::new_v4(), None, None).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::UserNotFound { .. })));
+
+        // Neither failure left a trace
+        let unchanged = service.get_repository_by_id(repo.id).await.unwrap().unwrap();
+        assert_eq!((unchanged.owner_id, unchanged.name.as_str()), (new_owner.id, "test-repo"));
+        let events = EventService::new(service.get_db().clone()).list_by_repository(repo.id, 10).await.unwrap();
+        assert_eq!(events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_repository_object_format_defaults_and_guards_ids() {
+        let (service, repo) = setup().await;
+        assert_eq!(repo.object_format, ObjectFormat::Sha1);
+
+        // The service default applies unless creation names a format
+        let service = service.with_default_object_format(HashAlgorithm::Sha256);
+        let sha256 = service
+            .create_repository("sha256".to_string(), None, "main".to_string(), repo.owner_id, Visibility::Public, None)
+            .await
+            .unwrap();
+        assert_eq!(sha256.object_format, ObjectFormat::Sha256);
+        let sha1 = service
+            .create_repository(
+                "sha1".to_string(),
+                None,
+                "main".to_string(),
+                repo.owner_id,
+                Visibility::Public,
+                Some(HashAlgorithm::Sha1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(service.object_format(sha1.id).await.unwrap(), HashAlgorithm::Sha1);
+
+        let handler = ObjectHandler::new().with_hash_algorithm(HashAlgorithm::Sha256);
+        let blob = handler.create_blob(b"named with sha256\n").unwrap();
+        assert_eq!(blob.id.len(), 64);
+        service
+            .store_object(sha256.id, blob.id.clone(), ObjectKind::Blob, blob.size as i64, blob.content.clone())
+            .await
+            .unwrap();
+        service
+            .store_ref(sha256.id, "refs/heads/main".to_string(), blob.id.clone(), false)
+            .await
+            .unwrap();
+
+        // Ids of the other format are refused in either direction
+        let sha1_blob = ObjectHandler::new().create_blob(b"named with sha1\n").unwrap();
+        let err = service
+            .store_object(sha256.id, sha1_blob.id.clone(), ObjectKind::Blob, sha1_blob.size as i64, sha1_blob.content)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::ObjectFormatMismatch { id, .. }) if *id == sha1_blob.id
+        ));
+        let err = service
+            .store_ref(sha1.id, "refs/heads/main".to_string(), blob.id.clone(), false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::ObjectFormatMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_blob_shard_depth_reads_both_layouts() {
+        let (flat, repo) = setup().await;
+        let sharded = RepositoryService::new(flat.db.clone(), Some(flat.blob_storage_path.clone())).with_blob_shard_depth(2);
+        let handler = ObjectHandler::new();
+        let old = handler.create_blob(b"stored before sharding").unwrap();
+        let new = handler.create_blob(b"stored two levels deep").unwrap();
+        let root = flat.blob_storage_path.clone();
+        let store = |service: &RepositoryService, repo_id: Uuid, blob: &git_protocol::GitObject| {
+            let (service, blob) = (service.clone(), blob.clone());
+            async move {
+                service
+                    .store_object(repo_id, blob.id.clone(), ObjectKind::Blob, blob.size as i64, blob.content)
+                    .await
+                    .unwrap();
+            }
+        };
+
+        store(&flat, repo.id, &old).await;
+        store(&sharded, repo.id, &new).await;
+        assert!(root.join(&old.id[..2]).join(&old.id[2..]).is_file());
+        let deep = root.join(&new.id[..2]).join(&new.id[2..4]).join(&new.id[4..]);
+        assert!(deep.is_file());
+
+        for blob in [&old, &new] {
+            let obj = sharded.get_object(repo.id, &blob.id).await.unwrap().unwrap();
+            assert_eq!(obj.content, blob.content);
+            let slice = sharded.read_blob_range(repo.id, &blob.id, 0, 6).await.unwrap().unwrap();
+            assert_eq!(slice, blob.content[..6]);
+        }
+
+        // Another repository storing the single-level blob shares its file
+        let other = sharded
+            .create_repository("other".to_string(), None, "main".to_string(), repo.owner_id, Visibility::Public, None)
+            .await
+            .unwrap();
+        store(&sharded, other.id, &old).await;
+        assert_eq!(count_blob_files(&root), 2);
+
+        // A file moved to the other layout on disk is still found, and nothing is orphaned
+        let moved = root.join(&new.id[..2]).join(&new.id[2..]);
+        fs::rename(&deep, &moved).unwrap();
+        let obj = flat.get_object(repo.id, &new.id).await.unwrap().unwrap();
+        assert_eq!(obj.content, new.content);
+        let report = sharded.check_consistency(None, &crate::CheckOptions::default()).await.unwrap();
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+
+        sharded.delete_repository(repo.id).await.unwrap();
+        assert!(!moved.exists());
+        sharded.delete_repository(other.id).await.unwrap();
+        assert_eq!(count_blob_files(&root), 0);
+    }
+}
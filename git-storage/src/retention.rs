@@ -0,0 +1,470 @@
+use crate::entities::{ref_log, repository_event};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sea_orm::sea_query::{Alias, Expr, Func, Query, SimpleExpr};
+use sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, QuerySelect,
+};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Rows deleted per statement, keeping each write lock short
+pub const DEFAULT_PRUNE_BATCH_SIZE: u64 = 500;
+
+/// Batches per table per run; whatever is left waits for the next run
+pub const DEFAULT_PRUNE_MAX_BATCHES: u32 = 20;
+
+/// How long reflog entries are kept, matching `git gc`'s `gc.reflogExpire`
+pub const DEFAULT_REF_LOG_MAX_AGE: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// How long push/fetch/settings events are kept
+pub const DEFAULT_EVENTS_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Limits for one table; `None` keeps rows regardless of that criterion
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_rows_per_repository: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    /// Reflog pruning never removes the newest entry of a ref
+    pub ref_log: RetentionPolicy,
+    pub events: RetentionPolicy,
+    pub batch_size: u64,
+    pub max_batches_per_run: u32,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            ref_log: RetentionPolicy {
+                max_age: Some(DEFAULT_REF_LOG_MAX_AGE),
+                max_rows_per_repository: None,
+            },
+            events: RetentionPolicy {
+                max_age: Some(DEFAULT_EVENTS_MAX_AGE),
+                max_rows_per_repository: None,
+            },
+            batch_size: DEFAULT_PRUNE_BATCH_SIZE,
+            max_batches_per_run: DEFAULT_PRUNE_MAX_BATCHES,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TablePruneStats {
+    pub table: &'static str,
+    pub deleted: u64,
+    pub batches: u32,
+    /// False when the batch limit stopped the run before everything eligible was gone
+    pub complete: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PruneReport {
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub tables: Vec<TablePruneStats>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RetainedTable {
+    RefLog,
+    Events,
+}
+
+impl RetainedTable {
+    fn name(&self) -> &'static str {
+        match self {
+            RetainedTable::RefLog => "ref_log",
+            RetainedTable::Events => "repository_events",
+        }
+    }
+}
+
+/// Which rows a batch selects
+#[derive(Debug, Clone, Copy)]
+enum PruneRule {
+    OlderThan(DateTime<Utc>),
+    /// Everything past the newest `keep` rows of a repository
+    Overflow { repository_id: Uuid, keep: u64 },
+}
+
+/// Prunes the reflog and repository events in bounded batches
+pub struct RetentionService {
+    db: DatabaseConnection,
+    config: RetentionConfig,
+    last_report: Mutex<Option<PruneReport>>,
+}
+
+impl RetentionService {
+    pub fn new(db: DatabaseConnection, config: RetentionConfig) -> Self {
+        Self {
+            db,
+            config,
+            last_report: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &RetentionConfig {
+        &self.config
+    }
+
+    /// Stats from the most recent run, if any
+    pub fn last_report(&self) -> Option<PruneReport> {
+        self.last_report.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Prune every table once, up to `max_batches_per_run` batches each
+    pub async fn run(&self) -> Result<PruneReport> {
+        let started_at = Utc::now();
+        let started = Instant::now();
+
+        let mut tables = Vec::new();
+        for (table, policy) in [
+            (RetainedTable::RefLog, self.config.ref_log),
+            (RetainedTable::Events, self.config.events),
+        ] {
+            tables.push(self.prune_table(table, policy).await?);
+        }
+
+        let report = PruneReport {
+            started_at,
+            duration_ms: started.elapsed().as_millis() as u64,
+            tables,
+        };
+        *self.last_report.lock().unwrap_or_else(|e| e.into_inner()) = Some(report.clone());
+        Ok(report)
+    }
+
+    async fn prune_table(&self, table: RetainedTable, policy: RetentionPolicy) -> Result<TablePruneStats> {
+        let mut stats = TablePruneStats {
+            table: table.name(),
+            deleted: 0,
+            batches: 0,
+            complete: true,
+        };
+
+        if let Some(max_age) = policy.max_age {
+            let cutoff = Utc::now() - chrono::Duration::from_std(max_age)?;
+            if !self.run_batches(table, PruneRule::OlderThan(cutoff), &mut stats).await? {
+                return Ok(stats);
+            }
+        }
+
+        if let Some(keep) = policy.max_rows_per_repository {
+            for repository_id in self.repositories_over(table, keep).await? {
+                let rule = PruneRule::Overflow { repository_id, keep };
+                if !self.run_batches(table, rule, &mut stats).await? {
+                    return Ok(stats);
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Delete batches until nothing matches the rule. Returns false once the
+    /// table's batch budget for this run is spent.
+    async fn run_batches(
+        &self,
+        table: RetainedTable,
+        rule: PruneRule,
+        stats: &mut TablePruneStats,
+    ) -> Result<bool> {
+        loop {
+            let ids = self.candidate_ids(table, rule).await?;
+            if ids.is_empty() {
+                return Ok(true);
+            }
+            if stats.batches >= self.config.max_batches_per_run {
+                stats.complete = false;
+                return Ok(false);
+            }
+            let full_batch = ids.len() as u64 >= self.config.batch_size;
+
+            stats.deleted += self.delete_ids(table, ids).await?;
+            stats.batches += 1;
+            if !full_batch {
+                return Ok(true);
+            }
+
+            // Let queued writers at the connection between batches
+            tokio::task::yield_now().await;
+        }
+    }
+
+    async fn candidate_ids(&self, table: RetainedTable, rule: PruneRule) -> Result<Vec<Uuid>> {
+        let ids = match table {
+            RetainedTable::RefLog => {
+                let mut query = ref_log::Entity::find()
+                    .select_only()
+                    .column(ref_log::Column::Id)
+                    .filter(ref_log_has_newer_entry());
+                query = match rule {
+                    PruneRule::OlderThan(cutoff) => query
+                        .filter(ref_log::Column::CreatedAt.lt(cutoff))
+                        .order_by_asc(ref_log::Column::CreatedAt),
+                    PruneRule::Overflow { repository_id, keep } => query
+                        .filter(ref_log::Column::RepositoryId.eq(repository_id))
+                        .order_by_desc(ref_log::Column::CreatedAt)
+                        .offset(keep),
+                };
+                query.limit(self.config.batch_size).into_tuple().all(&self.db).await?
+            }
+            RetainedTable::Events => {
+                let mut query = repository_event::Entity::find()
+                    .select_only()
+                    .column(repository_event::Column::Id);
+                query = match rule {
+                    PruneRule::OlderThan(cutoff) => query
+                        .filter(repository_event::Column::CreatedAt.lt(cutoff))
+                        .order_by_asc(repository_event::Column::CreatedAt),
+                    PruneRule::Overflow { repository_id, keep } => query
+                        .filter(repository_event::Column::RepositoryId.eq(repository_id))
+                        .order_by_desc(repository_event::Column::CreatedAt)
+                        .offset(keep),
+                };
+                query.limit(self.config.batch_size).into_tuple().all(&self.db).await?
+            }
+        };
+        Ok(ids)
+    }
+
+    async fn delete_ids(&self, table: RetainedTable, ids: Vec<Uuid>) -> Result<u64> {
+        let result = match table {
+            RetainedTable::RefLog => {
+                ref_log::Entity::delete_many()
+                    .filter(ref_log::Column::Id.is_in(ids))
+                    .exec(&self.db)
+                    .await?
+            }
+            RetainedTable::Events => {
+                repository_event::Entity::delete_many()
+                    .filter(repository_event::Column::Id.is_in(ids))
+                    .exec(&self.db)
+                    .await?
+            }
+        };
+        Ok(result.rows_affected)
+    }
+
+    /// Repositories holding more than `keep` rows in the table
+    async fn repositories_over(&self, table: RetainedTable, keep: u64) -> Result<Vec<Uuid>> {
+        let ids = match table {
+            RetainedTable::RefLog => {
+                ref_log::Entity::find()
+                    .select_only()
+                    .column(ref_log::Column::RepositoryId)
+                    .group_by(ref_log::Column::RepositoryId)
+                    .having(Expr::expr(Func::count(Expr::col(ref_log::Column::Id))).gt(keep))
+                    .into_tuple()
+                    .all(&self.db)
+                    .await?
+            }
+            RetainedTable::Events => {
+                repository_event::Entity::find()
+                    .select_only()
+                    .column(repository_event::Column::RepositoryId)
+                    .group_by(repository_event::Column::RepositoryId)
+                    .having(Expr::expr(Func::count(Expr::col(repository_event::Column::Id))).gt(keep))
+                    .into_tuple()
+                    .all(&self.db)
+                    .await?
+            }
+        };
+        Ok(ids)
+    }
+}
+
+/// Matches reflog rows that have a later entry for the same ref, so the newest
+/// entry of every ref (its current "previous value") is never a candidate
+fn ref_log_has_newer_entry() -> SimpleExpr {
+    let newer = Alias::new("newer");
+    Expr::exists(
+        Query::select()
+            .expr(Expr::val(1))
+            .from_as(ref_log::Entity, newer.clone())
+            .and_where(
+                Expr::col((newer.clone(), ref_log::Column::RepositoryId))
+                    .equals((ref_log::Entity, ref_log::Column::RepositoryId)),
+            )
+            .and_where(
+                Expr::col((newer.clone(), ref_log::Column::RefName))
+                    .equals((ref_log::Entity, ref_log::Column::RefName)),
+            )
+            .and_where(
+                Expr::col((newer, ref_log::Column::CreatedAt))
+                    .gt(Expr::col((ref_log::Entity, ref_log::Column::CreatedAt))),
+            )
+            .to_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::repository::Visibility;
+    use crate::{init_db, run_migrations, RepositoryService, UserService};
+    use sea_orm::{ActiveModelTrait, PaginatorTrait, Set};
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    async fn setup() -> (DatabaseConnection, Uuid, Uuid) {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let blob_storage_path = std::env::temp_dir().join(format!("git-storage-test-{}", Uuid::new_v4()));
+        let service = RepositoryService::new(db.clone(), Some(blob_storage_path));
+        let owner = UserService::new(db.clone())
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let mut repos = Vec::new();
+        for name in ["first", "second"] {
+            let repo = service
+                .create_repository(name.to_string(), None, "main".to_string(), owner.id, Visibility::Public, None)
+                .await
+                .unwrap();
+            repos.push(repo.id);
+        }
+
+        (db, repos[0], repos[1])
+    }
+
+    async fn seed_ref_log(db: &DatabaseConnection, repository_id: Uuid, ref_name: &str, age_secs: i64) {
+        ref_log::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            repository_id: Set(repository_id),
+            ref_name: Set(ref_name.to_string()),
+            old_target: Set(None),
+            new_target: Set(Some(format!("{:040x}", age_secs))),
+            created_at: Set((Utc::now() - chrono::Duration::seconds(age_secs)).into()),
+        }
+        .insert(db)
+        .await
+        .unwrap();
+    }
+
+    async fn seed_event(db: &DatabaseConnection, repository_id: Uuid, age_secs: i64) {
+        repository_event::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            repository_id: Set(repository_id),
+            event_type: Set("push".to_string()),
+            transport: Set("http".to_string()),
+            protocol_version: Set(0),
+            agent: Set(None),
+            user_agent: Set(None),
+            payload: Set(None),
+            actor_id: Set(None),
+            created_at: Set((Utc::now() - chrono::Duration::seconds(age_secs)).into()),
+        }
+        .insert(db)
+        .await
+        .unwrap();
+    }
+
+    async fn ref_log_count(db: &DatabaseConnection, repository_id: Uuid, ref_name: &str) -> u64 {
+        ref_log::Entity::find()
+            .filter(ref_log::Column::RepositoryId.eq(repository_id))
+            .filter(ref_log::Column::RefName.eq(ref_name))
+            .count(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ref_log_pruning_keeps_newest_entry_per_ref() {
+        let (db, repo, other) = setup().await;
+        for age in [300, 200, 100] {
+            seed_ref_log(&db, repo, "refs/heads/main", age * DAY).await;
+        }
+        seed_ref_log(&db, repo, "refs/heads/main", DAY).await;
+        // Stale refs keep their only entry even though it's past the cutoff
+        seed_ref_log(&db, repo, "refs/heads/stale", 400 * DAY).await;
+        for age in [500, 450] {
+            seed_ref_log(&db, other, "refs/heads/main", age * DAY).await;
+        }
+
+        let config = RetentionConfig {
+            ref_log: RetentionPolicy { max_age: Some(Duration::from_secs(90 * DAY as u64)), max_rows_per_repository: None },
+            events: RetentionPolicy::default(),
+            batch_size: 1,
+            max_batches_per_run: 2,
+        };
+        let service = RetentionService::new(db.clone(), config);
+
+        // Four rows are eligible; the batch limit stops the first run halfway
+        let report = service.run().await.unwrap();
+        assert_eq!(report.tables[0].table, "ref_log");
+        assert_eq!(report.tables[0].deleted, 2);
+        assert!(!report.tables[0].complete);
+
+        let report = service.run().await.unwrap();
+        assert_eq!(report.tables[0].deleted, 2);
+        assert!(report.tables[0].complete);
+        assert_eq!(service.last_report().unwrap().tables[0].deleted, 2);
+
+        assert_eq!(ref_log_count(&db, repo, "refs/heads/main").await, 1);
+        assert_eq!(ref_log_count(&db, repo, "refs/heads/stale").await, 1);
+        assert_eq!(ref_log_count(&db, other, "refs/heads/main").await, 1);
+
+        // The survivor of an all-old ref is its newest entry
+        let kept = ref_log::Entity::find()
+            .filter(ref_log::Column::RepositoryId.eq(other))
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(kept.new_target, Some(format!("{:040x}", 450 * DAY)));
+
+        assert_eq!(service.run().await.unwrap().tables[0].deleted, 0);
+    }
+
+    #[tokio::test]
+    async fn test_event_pruning_by_age_and_row_cap() {
+        let (db, repo, other) = setup().await;
+        for age in 1..=5 {
+            seed_event(&db, repo, age * DAY).await;
+        }
+        seed_event(&db, repo, 40 * DAY).await;
+        for age in 1..=2 {
+            seed_event(&db, other, age * DAY).await;
+        }
+
+        let config = RetentionConfig {
+            ref_log: RetentionPolicy::default(),
+            events: RetentionPolicy {
+                max_age: Some(Duration::from_secs(30 * DAY as u64)),
+                max_rows_per_repository: Some(3),
+            },
+            batch_size: 10,
+            max_batches_per_run: 10,
+        };
+        let report = RetentionService::new(db.clone(), config).run().await.unwrap();
+        assert_eq!(report.tables[1].table, "repository_events");
+        assert_eq!(report.tables[1].deleted, 3);
+        assert!(report.tables[1].complete);
+
+        let remaining = repository_event::Entity::find()
+            .filter(repository_event::Column::RepositoryId.eq(repo))
+            .order_by_desc(repository_event::Column::CreatedAt)
+            .all(&db)
+            .await
+            .unwrap();
+        assert_eq!(remaining.len(), 3);
+        let oldest_kept = Utc::now() - remaining[2].created_at.with_timezone(&Utc);
+        assert!(oldest_kept < chrono::Duration::seconds(4 * DAY));
+
+        let other_count = repository_event::Entity::find()
+            .filter(repository_event::Column::RepositoryId.eq(other))
+            .count(&db)
+            .await
+            .unwrap();
+        assert_eq!(other_count, 2);
+    }
+}
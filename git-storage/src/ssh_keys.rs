@@ -0,0 +1,75 @@
+use crate::entities::ssh_key;
+use crate::error::StorageError;
+use anyhow::Result;
+use chrono::Utc;
+use sea_orm::{ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, Set};
+use uuid::Uuid;
+
+/// A parsed and validated public key, ready to register
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewSshKey {
+    pub key_type: String,
+    pub public_key: String,
+    pub fingerprint: String,
+    pub comment: Option<String>,
+}
+
+/// Public keys users authenticate over SSH with. Keys arrive already validated;
+/// parsing them is up to the caller.
+pub struct SshKeyService {
+    db: DatabaseConnection,
+}
+
+impl SshKeyService {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Register `key` for `user_id`. Returns `None` when the user already has it,
+    /// and `StorageError::SshKeyInUse` when another user does.
+    pub async fn add_key(&self, user_id: Uuid, key: NewSshKey) -> Result<Option<ssh_key::Model>> {
+        if let Some(existing) = self.get_key_by_fingerprint(&key.fingerprint).await? {
+            if existing.user_id == user_id {
+                return Ok(None);
+            }
+            return Err(StorageError::SshKeyInUse { fingerprint: key.fingerprint }.into());
+        }
+
+        let model = ssh_key::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            user_id: Set(user_id),
+            key_type: Set(key.key_type),
+            public_key: Set(key.public_key),
+            fingerprint: Set(key.fingerprint),
+            comment: Set(key.comment),
+            created_at: Set(Utc::now().into()),
+        };
+        Ok(Some(model.insert(&self.db).await?))
+    }
+
+    /// The user's keys, oldest first
+    pub async fn list_keys(&self, user_id: Uuid) -> Result<Vec<ssh_key::Model>> {
+        Ok(ssh_key::Entity::find()
+            .filter(ssh_key::Column::UserId.eq(user_id))
+            .order_by_asc(ssh_key::Column::CreatedAt)
+            .all(&self.db)
+            .await?)
+    }
+
+    /// Remove one of the user's keys; `false` when they have no key `id`
+    pub async fn delete_key(&self, user_id: Uuid, id: Uuid) -> Result<bool> {
+        let result = ssh_key::Entity::delete_many()
+            .filter(ssh_key::Column::Id.eq(id))
+            .filter(ssh_key::Column::UserId.eq(user_id))
+            .exec(&self.db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+
+    pub async fn get_key_by_fingerprint(&self, fingerprint: &str) -> Result<Option<ssh_key::Model>> {
+        Ok(ssh_key::Entity::find()
+            .filter(ssh_key::Column::Fingerprint.eq(fingerprint))
+            .one(&self.db)
+            .await?)
+    }
+}
@@ -0,0 +1,118 @@
+use crate::entities::commit_status;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitStatusState {
+    Pending,
+    Success,
+    Failure,
+    Error,
+}
+
+impl CommitStatusState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommitStatusState::Pending => "pending",
+            CommitStatusState::Success => "success",
+            CommitStatusState::Failure => "failure",
+            CommitStatusState::Error => "error",
+        }
+    }
+}
+
+impl FromStr for CommitStatusState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pending" => Ok(CommitStatusState::Pending),
+            "success" => Ok(CommitStatusState::Success),
+            "failure" => Ok(CommitStatusState::Failure),
+            "error" => Ok(CommitStatusState::Error),
+            other => Err(anyhow!("Unknown commit status state: {}", other)),
+        }
+    }
+}
+
+/// A status to report against a commit
+#[derive(Debug, Clone)]
+pub struct NewCommitStatus {
+    pub repository_id: Uuid,
+    pub commit_id: String,
+    pub state: CommitStatusState,
+    pub context: String,
+    pub description: Option<String>,
+    pub target_url: Option<String>,
+    pub creator_id: Option<Uuid>,
+}
+
+/// Stores commit statuses; each context keeps only its latest report per commit
+pub struct StatusService {
+    db: DatabaseConnection,
+}
+
+impl StatusService {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Record a status, replacing any earlier one for the same commit and context
+    pub async fn record(&self, status: NewCommitStatus) -> Result<commit_status::Model> {
+        let now = Utc::now();
+        let existing = commit_status::Entity::find()
+            .filter(commit_status::Column::RepositoryId.eq(status.repository_id))
+            .filter(commit_status::Column::CommitId.eq(status.commit_id.as_str()))
+            .filter(commit_status::Column::Context.eq(status.context.as_str()))
+            .one(&self.db)
+            .await?;
+
+        let model = match existing {
+            Some(existing) => {
+                let mut active: commit_status::ActiveModel = existing.into();
+                active.state = Set(status.state.as_str().to_string());
+                active.description = Set(status.description);
+                active.target_url = Set(status.target_url);
+                active.creator_id = Set(status.creator_id);
+                active.updated_at = Set(now.into());
+                active.update(&self.db).await?
+            }
+            None => {
+                commit_status::ActiveModel {
+                    id: Set(Uuid::new_v4()),
+                    repository_id: Set(status.repository_id),
+                    commit_id: Set(status.commit_id),
+                    state: Set(status.state.as_str().to_string()),
+                    context: Set(status.context),
+                    description: Set(status.description),
+                    target_url: Set(status.target_url),
+                    creator_id: Set(status.creator_id),
+                    created_at: Set(now.into()),
+                    updated_at: Set(now.into()),
+                }
+                .insert(&self.db)
+                .await?
+            }
+        };
+
+        Ok(model)
+    }
+
+    /// List the statuses reported for a commit, ordered by context
+    pub async fn list(&self, repository_id: Uuid, commit_id: &str) -> Result<Vec<commit_status::Model>> {
+        let statuses = commit_status::Entity::find()
+            .filter(commit_status::Column::RepositoryId.eq(repository_id))
+            .filter(commit_status::Column::CommitId.eq(commit_id))
+            .order_by_asc(commit_status::Column::Context)
+            .all(&self.db)
+            .await?;
+        Ok(statuses)
+    }
+}
@@ -0,0 +1,151 @@
+use crate::entities::access_token;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, Set,
+};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Prefix on every issued token so leaked tokens are easy to spot
+pub const TOKEN_PREFIX: &str = "gst_";
+
+/// Issues and checks personal access tokens. Only a SHA-256 of each token is
+/// stored; the plaintext is returned once, at creation.
+pub struct TokenService {
+    db: DatabaseConnection,
+}
+
+impl TokenService {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Create a token for `user_id` carrying `scopes`. Returns the stored row and
+    /// the plaintext token.
+    pub async fn create_token(
+        &self,
+        user_id: Uuid,
+        name: String,
+        scopes: &[String],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(access_token::Model, String)> {
+        let plaintext = format!(
+            "{}{}{}",
+            TOKEN_PREFIX,
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        );
+
+        let token = access_token::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            user_id: Set(user_id),
+            name: Set(name),
+            token_hash: Set(hash_token(&plaintext)),
+            scopes: Set(scopes.join(" ")),
+            created_at: Set(Utc::now().into()),
+            last_used_at: Set(None),
+            expires_at: Set(expires_at.map(Into::into)),
+        }
+        .insert(&self.db)
+        .await?;
+
+        Ok((token, plaintext))
+    }
+
+    /// Look up an unexpired token and record that it was used
+    pub async fn authenticate(&self, plaintext: &str) -> Result<Option<access_token::Model>> {
+        let Some(token) = access_token::Entity::find()
+            .filter(access_token::Column::TokenHash.eq(hash_token(plaintext)))
+            .one(&self.db)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let now = Utc::now();
+        if token.expires_at.is_some_and(|expires| expires <= now) {
+            return Ok(None);
+        }
+
+        let mut active: access_token::ActiveModel = token.into();
+        active.last_used_at = Set(Some(now.into()));
+        Ok(Some(active.update(&self.db).await?))
+    }
+
+    /// List a user's tokens, newest first
+    pub async fn list_tokens(&self, user_id: Uuid) -> Result<Vec<access_token::Model>> {
+        let tokens = access_token::Entity::find()
+            .filter(access_token::Column::UserId.eq(user_id))
+            .order_by_desc(access_token::Column::CreatedAt)
+            .all(&self.db)
+            .await?;
+        Ok(tokens)
+    }
+
+    /// Delete one of a user's tokens; returns false if it does not exist or
+    /// belongs to someone else
+    pub async fn revoke_token(&self, user_id: Uuid, token_id: Uuid) -> Result<bool> {
+        let result = access_token::Entity::delete_many()
+            .filter(access_token::Column::Id.eq(token_id))
+            .filter(access_token::Column::UserId.eq(user_id))
+            .exec(&self.db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+}
+
+impl access_token::Model {
+    /// The scopes this token carries
+    pub fn scope_list(&self) -> Vec<&str> {
+        self.scopes.split_whitespace().collect()
+    }
+}
+
+pub(crate) fn hash_token(plaintext: &str) -> String {
+    hex::encode(Sha256::digest(plaintext.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_db, run_migrations, UserService};
+
+    #[tokio::test]
+    async fn test_token_lifecycle() {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let user = UserService::new(db.clone())
+            .create_user("ci".to_string(), "ci@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let service = TokenService::new(db.clone());
+
+        let scopes = vec!["repo:read".to_string(), "status:write".to_string()];
+        let (token, plaintext) = service
+            .create_token(user.id, "ci".to_string(), &scopes, None)
+            .await
+            .unwrap();
+        assert!(plaintext.starts_with(TOKEN_PREFIX));
+        assert_ne!(token.token_hash, plaintext);
+        assert_eq!(token.scope_list(), vec!["repo:read", "status:write"]);
+
+        let found = service.authenticate(&plaintext).await.unwrap().unwrap();
+        assert_eq!(found.id, token.id);
+        assert!(found.last_used_at.is_some());
+        assert!(service.authenticate("gst_bogus").await.unwrap().is_none());
+
+        // Expired tokens no longer authenticate
+        let (_, expired) = service
+            .create_token(user.id, "old".to_string(), &scopes, Some(Utc::now() - chrono::Duration::hours(1)))
+            .await
+            .unwrap();
+        assert!(service.authenticate(&expired).await.unwrap().is_none());
+
+        assert_eq!(service.list_tokens(user.id).await.unwrap().len(), 2);
+        assert!(!service.revoke_token(Uuid::new_v4(), token.id).await.unwrap());
+        assert!(service.revoke_token(user.id, token.id).await.unwrap());
+        assert!(service.authenticate(&plaintext).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,400 @@
+use crate::entities::repository::Visibility;
+use crate::repository::{RefUpdate, RepositoryService};
+use crate::user::UserService;
+use anyhow::{anyhow, Context, Result};
+use git_protocol::pack::PackWriter;
+use git_protocol::{GitProtocol, HashAlgorithm, ProtocolHandler};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+/// Version of the export directory layout
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+
+/// Repository settings and refs, written next to the bundle
+pub const METADATA_FILE: &str = "repository.json";
+
+/// Git bundle (v2) holding every ref and object of the repository
+pub const BUNDLE_FILE: &str = "repository.bundle";
+
+/// Objects loaded from the database per page while streaming a bundle
+const EXPORT_PAGE_SIZE: u64 = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RepositoryExport {
+    pub format_version: u32,
+    pub repository: ExportedRepository,
+    pub refs: Vec<ExportedRef>,
+    pub object_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedRepository {
+    pub name: String,
+    pub description: Option<String>,
+    pub default_branch: String,
+    /// For servers without visibility levels, which read internal as private
+    pub is_private: bool,
+    /// Missing from exports made before visibility levels, which only had `is_private`
+    #[serde(default)]
+    pub visibility: Option<Visibility>,
+    /// Owner username; users are matched by name on import
+    pub owner: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedRef {
+    pub name: String,
+    pub target: String,
+    pub is_symbolic: bool,
+}
+
+/// Outcome of an import
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub repository_id: Uuid,
+    pub refs_imported: usize,
+    pub objects_imported: usize,
+    /// Usernames referenced by the export that don't exist on this server
+    pub unmatched_users: Vec<String>,
+}
+
+/// Export `owner/name` into `out_dir` as a metadata file plus a git bundle.
+/// Objects are streamed page by page into the bundle.
+pub async fn export_repository(
+    repository_service: &RepositoryService,
+    user_service: &UserService,
+    owner: &str,
+    name: &str,
+    out_dir: &Path,
+) -> Result<RepositoryExport> {
+    let owner_user = user_service
+        .get_user_by_username(owner)
+        .await?
+        .ok_or_else(|| anyhow!("User not found: {}", owner))?;
+    let repository = repository_service
+        .get_repository_by_name_and_owner(name, owner_user.id)
+        .await?
+        .ok_or_else(|| anyhow!("Repository not found: {}/{}", owner, name))?;
+
+    // Hold the write lock so refs and objects stay consistent while streaming
+    let _guard = repository_service.lock_writes(repository.id).await?;
+
+    let refs = repository_service.get_refs_by_repository(repository.id).await?;
+    let object_count = repository_service
+        .get_repository_stats(repository.id)
+        .await?
+        .object_count;
+
+    fs::create_dir_all(out_dir)?;
+
+    // v2 bundles are SHA-1 only; v3 names the object format
+    let hash = repository.object_format.hash_algorithm();
+    let mut bundle = BufWriter::new(File::create(out_dir.join(BUNDLE_FILE))?);
+    if hash == HashAlgorithm::Sha1 {
+        writeln!(bundle, "# v2 git bundle")?;
+    } else {
+        writeln!(bundle, "# v3 git bundle")?;
+        writeln!(bundle, "@object-format={}", hash.name())?;
+    }
+    for git_ref in refs.iter().filter(|r| !r.is_symbolic) {
+        writeln!(bundle, "{} {}", git_ref.target, git_ref.name)?;
+    }
+    writeln!(bundle)?;
+
+    let object_count_u32 = u32::try_from(object_count)
+        .map_err(|_| anyhow!("Too many objects for a single pack: {}", object_count))?;
+    let mut pack = PackWriter::with_hash_algorithm(bundle, object_count_u32, hash)?;
+    let pages = object_count.div_ceil(EXPORT_PAGE_SIZE);
+    for page in 0..pages {
+        for obj in repository_service
+            .get_objects_page(repository.id, page, EXPORT_PAGE_SIZE)
+            .await?
+        {
+            pack.write_object(&obj.into_git_object()?)?;
+        }
+    }
+    pack.finish()?;
+
+    let export = RepositoryExport {
+        format_version: EXPORT_FORMAT_VERSION,
+        repository: ExportedRepository {
+            name: repository.name,
+            description: repository.description,
+            default_branch: repository.default_branch,
+            is_private: repository.visibility != Visibility::Public,
+            visibility: Some(repository.visibility),
+            owner: owner_user.username,
+        },
+        refs: refs
+            .into_iter()
+            .map(|r| ExportedRef {
+                name: r.name,
+                target: r.target,
+                is_symbolic: r.is_symbolic,
+            })
+            .collect(),
+        object_count,
+    };
+    fs::write(out_dir.join(METADATA_FILE), serde_json::to_vec_pretty(&export)?)?;
+
+    Ok(export)
+}
+
+/// Recreate a repository exported by `export_repository`, owned by `owner`
+pub async fn import_repository(
+    repository_service: &RepositoryService,
+    user_service: &UserService,
+    in_dir: &Path,
+    owner: &str,
+) -> Result<ImportReport> {
+    let metadata = fs::read(in_dir.join(METADATA_FILE))
+        .with_context(|| format!("Failed to read {}", METADATA_FILE))?;
+    let export: RepositoryExport = serde_json::from_slice(&metadata)?;
+    if export.format_version != EXPORT_FORMAT_VERSION {
+        return Err(anyhow!("Unsupported export format version: {}", export.format_version));
+    }
+
+    let owner_user = user_service
+        .get_user_by_username(owner)
+        .await?
+        .ok_or_else(|| anyhow!("User not found: {}", owner))?;
+
+    let mut unmatched_users = Vec::new();
+    if !user_service.username_exists(&export.repository.owner).await? {
+        unmatched_users.push(export.repository.owner.clone());
+    }
+
+    if repository_service
+        .get_repository_by_name_and_owner(&export.repository.name, owner_user.id)
+        .await?
+        .is_some()
+    {
+        return Err(anyhow!("Repository already exists: {}/{}", owner, export.repository.name));
+    }
+
+    let bundle = fs::read(in_dir.join(BUNDLE_FILE))
+        .with_context(|| format!("Failed to read {}", BUNDLE_FILE))?;
+    let (bundle_refs, hash, pack) = parse_bundle(&bundle)?;
+
+    let protocol = ProtocolHandler::new().with_hash_algorithm(hash);
+    let handler = protocol.object_handler();
+    let objects = protocol
+        .parse_pack(pack)?
+        .into_iter()
+        .map(|entry| handler.parse_object(entry.object_type, &entry.data))
+        .collect::<Result<Vec<_>>>()?;
+    let objects_imported = objects.len();
+
+    let repository = repository_service
+        .create_repository(
+            export.repository.name.clone(),
+            export.repository.description.clone(),
+            export.repository.default_branch.clone(),
+            owner_user.id,
+            export
+                .repository
+                .visibility
+                .unwrap_or_else(|| Visibility::from_is_private(export.repository.is_private)),
+            Some(hash),
+        )
+        .await?;
+
+    let guard = repository_service.lock_writes(repository.id).await?;
+    repository_service.store_objects(&guard, objects).await?;
+
+    let refs_imported = bundle_refs.len();
+    repository_service
+        .update_refs(
+            &guard,
+            bundle_refs
+                .into_iter()
+                .map(|r| RefUpdate {
+                    name: r.name,
+                    old_target: None,
+                    new_target: Some(r.target),
+                })
+                .collect(),
+        )
+        .await?;
+
+    if let Some(head) = export.refs.iter().find(|r| r.name == "HEAD" && r.is_symbolic) {
+        repository_service.set_head(&guard, &head.target).await?;
+    }
+
+    Ok(ImportReport {
+        repository_id: repository.id,
+        refs_imported,
+        objects_imported,
+        unmatched_users,
+    })
+}
+
+/// Split a v2 bundle into its ref list and pack data
+fn parse_bundle(bundle: &[u8]) -> Result<(Vec<ExportedRef>, HashAlgorithm, &[u8])> {
+    let mut refs = Vec::new();
+    let mut hash = HashAlgorithm::Sha1;
+    let mut rest = bundle;
+    let mut first = true;
+
+    loop {
+        let newline = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| anyhow!("Truncated bundle header"))?;
+        let line = std::str::from_utf8(&rest[..newline])?;
+        rest = &rest[newline + 1..];
+
+        if first {
+            if line != "# v2 git bundle" && line != "# v3 git bundle" {
+                return Err(anyhow!("Not a v2 or v3 git bundle"));
+            }
+            first = false;
+            continue;
+        }
+
+        if line.is_empty() {
+            break;
+        }
+        // v3 capabilities; the object format is the only one that matters here
+        if let Some(capability) = line.strip_prefix('@') {
+            if let Some(name) = capability.strip_prefix("object-format=") {
+                hash = HashAlgorithm::from_name(name).ok_or_else(|| anyhow!("Unknown object format: {}", name))?;
+            }
+            continue;
+        }
+
+        let (target, name) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Invalid bundle ref line: {}", line))?;
+        refs.push(ExportedRef {
+            name: name.to_string(),
+            target: target.to_string(),
+            is_symbolic: false,
+        });
+    }
+
+    Ok((refs, hash, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_db, run_migrations};
+    use chrono::Utc;
+    use git_protocol::objects::{Commit, ObjectHandler, Tree, TreeEntry};
+
+    async fn services() -> (RepositoryService, UserService) {
+        let db = init_db("sqlite::memory:").await.unwrap();
+        run_migrations(&db).await.unwrap();
+        let blob_storage_path = std::env::temp_dir().join(format!("git-storage-test-{}", Uuid::new_v4()));
+        (
+            RepositoryService::new(db.clone(), Some(blob_storage_path)),
+            UserService::new(db),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_export_import_round_trip() {
+        let (source_repos, source_users) = services().await;
+        let owner = source_users
+            .create_user("owner".to_string(), "owner@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let repo = source_repos
+            .create_repository("project".to_string(), Some("A project".to_string()), "main".to_string(), owner.id, Visibility::Private, None)
+            .await
+            .unwrap();
+
+        let handler = ObjectHandler::new();
+        let blob = handler.create_blob(b"hello\n").unwrap();
+        let tree = handler
+            .create_tree(&Tree {
+                entries: vec![TreeEntry {
+                    mode: "100644".to_string(),
+                    name: "hello.txt".to_string(),
+                    hash: blob.id.clone(),
+                }],
+            })
+            .unwrap();
+        let commit = handler
+            .create_commit(&Commit {
+                tree: tree.id.clone(),
+                parents: vec![],
+                author: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                committer: "A U Thor <author@example.com> 1700000000 +0000".to_string(),
+                message: "Initial commit\n".to_string(),
+                author_date: Utc::now(),
+                commit_date: Utc::now(),
+                encoding: None,
+            })
+            .unwrap();
+        let commit_id = commit.id.clone();
+
+        let guard = source_repos.lock_writes(repo.id).await.unwrap();
+        source_repos.store_objects(&guard, vec![blob, tree, commit]).await.unwrap();
+        source_repos
+            .update_refs(
+                &guard,
+                ["refs/heads/main", "refs/heads/dev", "refs/tags/v1"]
+                    .into_iter()
+                    .map(|name| RefUpdate {
+                        name: name.to_string(),
+                        old_target: None,
+                        new_target: Some(commit_id.clone()),
+                    })
+                    .collect(),
+            )
+            .await
+            .unwrap();
+        source_repos.set_head(&guard, "refs/heads/dev").await.unwrap();
+        drop(guard);
+
+        let out_dir = std::env::temp_dir().join(format!("git-export-test-{}", Uuid::new_v4()));
+        let export = export_repository(&source_repos, &source_users, "owner", "project", &out_dir)
+            .await
+            .unwrap();
+        assert_eq!(export.object_count, 3);
+        assert!(fs::read(out_dir.join(BUNDLE_FILE)).unwrap().starts_with(b"# v2 git bundle\n"));
+
+        // Import into a fresh database whose users don't include the original owner
+        let (target_repos, target_users) = services().await;
+        target_users
+            .create_user("alice".to_string(), "alice@example.com".to_string(), "hash".to_string(), None, false)
+            .await
+            .unwrap();
+        let report = import_repository(&target_repos, &target_users, &out_dir, "alice")
+            .await
+            .unwrap();
+        assert_eq!(report.objects_imported, 3);
+        assert_eq!(report.refs_imported, 3);
+        assert_eq!(report.unmatched_users, vec!["owner"]);
+
+        let imported = target_repos.get_repository_by_id(report.repository_id).await.unwrap().unwrap();
+        assert_eq!(imported.name, "project");
+        assert_eq!(imported.description.as_deref(), Some("A project"));
+        assert_eq!(imported.default_branch, "dev");
+        assert_eq!(imported.visibility, Visibility::Private);
+
+        let ref_list = |refs: Vec<crate::entities::git_ref::Model>| {
+            let mut refs: Vec<_> = refs.into_iter().map(|r| (r.name, r.target, r.is_symbolic)).collect();
+            refs.sort();
+            refs
+        };
+        assert_eq!(
+            ref_list(source_repos.get_refs_by_repository(repo.id).await.unwrap()),
+            ref_list(target_repos.get_refs_by_repository(imported.id).await.unwrap())
+        );
+
+        let source_stats = source_repos.get_repository_stats(repo.id).await.unwrap();
+        let target_stats = target_repos.get_repository_stats(imported.id).await.unwrap();
+        assert_eq!(source_stats.object_count, target_stats.object_count);
+        assert_eq!(source_stats.ref_count, target_stats.ref_count);
+        assert_eq!(
+            target_repos.get_object(imported.id, &commit_id).await.unwrap().unwrap().content,
+            source_repos.get_object(repo.id, &commit_id).await.unwrap().unwrap().content
+        );
+    }
+}
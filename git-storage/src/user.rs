@@ -1,18 +1,114 @@
 use crate::entities::user;
-use anyhow::Result;
+use crate::error::StorageError;
+use anyhow::{anyhow, Result};
 use chrono::Utc;
+use sea_orm::sea_query::{Expr, Func, SimpleExpr};
 use sea_orm::{
     ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter, Set,
+    TransactionTrait,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use uuid::Uuid;
 
+/// bcrypt work factor for new password hashes: each step doubles the time to hash
+pub const DEFAULT_PASSWORD_HASH_COST: u32 = 12;
+
+/// Costs bcrypt accepts
+const PASSWORD_HASH_COSTS: std::ops::RangeInclusive<u32> = 4..=31;
+
+/// Prefix of the unsalted hashes written before bcrypt was used
+const LEGACY_HASH_PREFIX: &str = "hashed_";
+
+/// Longest email address that fits in an SMTP path
+pub const MAX_EMAIL_LEN: usize = 254;
+
+/// Longest local part (before the `@`) SMTP allows
+const MAX_EMAIL_LOCAL_LEN: usize = 64;
+
+/// Characters besides letters and digits a local part may hold, as in an RFC 5322
+/// dot-atom; dots are checked separately
+const EMAIL_LOCAL_SPECIALS: &str = "!#$%&'*+/=?^_`{|}~-";
+
+/// One user of a bulk import. `password_hash` is stored as is and must be in a
+/// format `verify_password` reads; without one the user can't sign in with a
+/// password until one is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportedUser {
+    pub username: String,
+    pub email: String,
+    pub password_hash: Option<String>,
+    pub full_name: Option<String>,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserImportStatus {
+    Created,
+    Failed,
+    /// Valid, but not created because another row failed an all-or-nothing import
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserImportRow {
+    /// 0-based position in the submitted array
+    pub index: usize,
+    pub username: String,
+    pub status: UserImportStatus,
+    pub id: Option<Uuid>,
+    /// Why the row failed
+    pub message: Option<String>,
+}
+
+/// Per-row results of `UserService::import_users`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserImportReport {
+    pub created: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub rows: Vec<UserImportRow>,
+}
+
+impl UserImportReport {
+    fn push(&mut self, row: UserImportRow) {
+        match row.status {
+            UserImportStatus::Created => self.created += 1,
+            UserImportStatus::Failed => self.failed += 1,
+            UserImportStatus::Skipped => self.skipped += 1,
+        }
+        self.rows.push(row);
+    }
+}
+
 pub struct UserService {
     db: DatabaseConnection,
+    password_hash_cost: u32,
 }
 
 impl UserService {
     pub fn new(db: DatabaseConnection) -> Self {
-        Self { db }
+        Self {
+            db,
+            password_hash_cost: DEFAULT_PASSWORD_HASH_COST,
+        }
+    }
+
+    /// Hash new passwords with bcrypt cost `cost` (4-31). Hashes already stored keep
+    /// the cost they were made with and still verify.
+    pub fn with_password_hash_cost(mut self, cost: u32) -> Result<Self> {
+        if !PASSWORD_HASH_COSTS.contains(&cost) {
+            return Err(anyhow!(
+                "password hash cost must be between {} and {}, got {}",
+                PASSWORD_HASH_COSTS.start(),
+                PASSWORD_HASH_COSTS.end(),
+                cost
+            ));
+        }
+        self.password_hash_cost = cost;
+        Ok(self)
     }
 
     /// Create a new user
@@ -24,22 +120,96 @@ impl UserService {
         full_name: Option<String>,
         is_admin: bool,
     ) -> Result<user::Model> {
-        let user = user::ActiveModel {
-            id: Set(Uuid::new_v4()),
-            username: Set(username),
-            email: Set(email),
-            password_hash: Set(password_hash),
-            full_name: Set(full_name),
-            is_active: Set(true),
-            is_admin: Set(is_admin),
-            created_at: Set(Utc::now().into()),
-            updated_at: Set(Utc::now().into()),
-        };
+        let email = normalize_email(&email)?;
+        if self.email_exists(&email).await? {
+            return Err(StorageError::EmailTaken { email }.into());
+        }
+        let user = new_user(username, email, password_hash, full_name, is_admin);
 
         let result = user.insert(&self.db).await?;
         Ok(result)
     }
 
+    /// Create many users in one transaction, checking each against existing users
+    /// and the rows before it. A bad row is reported and the rest still go in,
+    /// unless `all_or_nothing` is set, when any failure leaves every row out.
+    pub async fn import_users(&self, users: Vec<ImportedUser>, all_or_nothing: bool) -> Result<UserImportReport> {
+        let txn = self.db.begin().await?;
+        let mut rows = Vec::with_capacity(users.len());
+        let (mut usernames, mut emails) = (HashSet::new(), HashSet::new());
+        for (index, imported) in users.into_iter().enumerate() {
+            let username = imported.username.trim().to_string();
+            let email = normalize_email(&imported.email);
+            let problem = match &email {
+                _ if username.is_empty() => Some("username cannot be empty".to_string()),
+                Err(e) => Some(e.to_string()),
+                _ if !usernames.insert(username.clone()) => Some(format!("username {} appears earlier in the import", username)),
+                Ok(email) if !emails.insert(email.clone()) => Some(format!("{} appears earlier in the import", email)),
+                Ok(_) if imported.password_hash.as_deref().is_some_and(|hash| !is_password_hash(hash)) => {
+                    Some("password_hash is not a bcrypt or legacy hash".to_string())
+                }
+                Ok(email) => {
+                    let username_taken = user::Entity::find()
+                        .filter(user::Column::Username.eq(username.as_str()))
+                        .count(&txn)
+                        .await?;
+                    let email_taken = user::Entity::find()
+                        .filter(lower_email().eq(email.as_str()))
+                        .count(&txn)
+                        .await?;
+                    if username_taken > 0 {
+                        Some(format!("username {} already exists", username))
+                    } else if email_taken > 0 {
+                        Some(StorageError::EmailTaken { email: email.clone() }.to_string())
+                    } else {
+                        None
+                    }
+                }
+            };
+
+            let row = match (problem, email) {
+                (None, Ok(email)) => {
+                    let password_hash = imported.password_hash.unwrap_or_default();
+                    let user = new_user(username.clone(), email, password_hash, imported.full_name, imported.is_admin)
+                        .insert(&txn)
+                        .await?;
+                    UserImportRow {
+                        index,
+                        username,
+                        status: UserImportStatus::Created,
+                        id: Some(user.id),
+                        message: None,
+                    }
+                }
+                (problem, _) => UserImportRow {
+                    index,
+                    username,
+                    status: UserImportStatus::Failed,
+                    id: None,
+                    message: problem,
+                },
+            };
+            rows.push(row);
+        }
+
+        let failed = rows.iter().any(|row| row.status == UserImportStatus::Failed);
+        if all_or_nothing && failed {
+            txn.rollback().await?;
+            for row in rows.iter_mut().filter(|row| row.status == UserImportStatus::Created) {
+                row.status = UserImportStatus::Skipped;
+                row.id = None;
+            }
+        } else {
+            txn.commit().await?;
+        }
+
+        let mut report = UserImportReport::default();
+        for row in rows {
+            report.push(row);
+        }
+        Ok(report)
+    }
+
     /// Get user by username
     pub async fn get_user_by_username(&self, username: &str) -> Result<Option<user::Model>> {
         let user = user::Entity::find()
@@ -49,10 +219,10 @@ impl UserService {
         Ok(user)
     }
 
-    /// Get user by email
+    /// Get user by email, in any case
     pub async fn get_user_by_email(&self, email: &str) -> Result<Option<user::Model>> {
         let user = user::Entity::find()
-            .filter(user::Column::Email.eq(email))
+            .filter(lower_email().eq(email.trim().to_lowercase()))
             .one(&self.db)
             .await?;
         Ok(user)
@@ -64,13 +234,23 @@ impl UserService {
         Ok(user)
     }
 
+    /// Usernames of the given users, keyed by ID; unknown IDs are left out
+    pub async fn usernames_by_ids(&self, ids: &[Uuid]) -> Result<HashMap<Uuid, String>> {
+        let users = user::Entity::find()
+            .filter(user::Column::Id.is_in(ids.iter().copied()))
+            .all(&self.db)
+            .await?;
+        Ok(users.into_iter().map(|user| (user.id, user.username)).collect())
+    }
+
     /// List all users
     pub async fn list_users(&self) -> Result<Vec<user::Model>> {
         let users = user::Entity::find().all(&self.db).await?;
         Ok(users)
     }
 
-    /// Update user
+    /// Update user. A new email is normalized and must not belong to anyone else.
+    #[allow(clippy::too_many_arguments)]
     pub async fn update_user(
         &self,
         id: Uuid,
@@ -88,6 +268,15 @@ impl UserService {
                 user_active.username = Set(username);
             }
             if let Some(email) = email {
+                let email = normalize_email(&email)?;
+                let taken = user::Entity::find()
+                    .filter(lower_email().eq(email.as_str()))
+                    .filter(user::Column::Id.ne(id))
+                    .count(&self.db)
+                    .await?;
+                if taken > 0 {
+                    return Err(StorageError::EmailTaken { email }.into());
+                }
                 user_active.email = Set(email);
             }
             if let Some(password_hash) = password_hash {
@@ -111,6 +300,48 @@ impl UserService {
         }
     }
 
+    /// Show a noreply address instead of the user's email, see `IdentityMap::redact`
+    pub async fn set_hide_email(&self, id: Uuid, hide_email: bool) -> Result<Option<user::Model>> {
+        let Some(user) = user::Entity::find_by_id(id).one(&self.db).await? else {
+            return Ok(None);
+        };
+        let mut user_active: user::ActiveModel = user.into();
+        user_active.hide_email = Set(hide_email);
+        user_active.updated_at = Set(Utc::now().into());
+        Ok(Some(user_active.update(&self.db).await?))
+    }
+
+    /// Replace the user's quota overrides; `None` uses the server setting
+    pub async fn set_quota(
+        &self,
+        id: Uuid,
+        max_repositories: Option<i32>,
+        max_total_bytes: Option<i64>,
+    ) -> Result<Option<user::Model>> {
+        let Some(user) = user::Entity::find_by_id(id).one(&self.db).await? else {
+            return Ok(None);
+        };
+        let mut user_active: user::ActiveModel = user.into();
+        user_active.max_repositories = Set(max_repositories);
+        user_active.max_total_bytes = Set(max_total_bytes);
+        user_active.updated_at = Set(Utc::now().into());
+        Ok(Some(user_active.update(&self.db).await?))
+    }
+
+    /// Point a user at a new uploaded avatar, or back at their identicon with
+    /// `None`, bumping the avatar version
+    pub async fn set_avatar(&self, id: Uuid, avatar_blob_key: Option<String>) -> Result<Option<user::Model>> {
+        let Some(user) = user::Entity::find_by_id(id).one(&self.db).await? else {
+            return Ok(None);
+        };
+        let version = user.avatar_version + 1;
+        let mut user_active: user::ActiveModel = user.into();
+        user_active.avatar_blob_key = Set(avatar_blob_key);
+        user_active.avatar_version = Set(version);
+        user_active.updated_at = Set(Utc::now().into());
+        Ok(Some(user_active.update(&self.db).await?))
+    }
+
     /// Delete user
     pub async fn delete_user(&self, id: Uuid) -> Result<()> {
         user::Entity::delete_by_id(id).exec(&self.db).await?;
@@ -126,10 +357,11 @@ impl UserService {
         Ok(count > 0)
     }
 
-    /// Check if email exists
+    /// Check if email exists, in any case. Addresses stored before emails were
+    /// normalized may still be mixed-case, so the column is lowercased too.
     pub async fn email_exists(&self, email: &str) -> Result<bool> {
         let count = user::Entity::find()
-            .filter(user::Column::Email.eq(email))
+            .filter(lower_email().eq(email.trim().to_lowercase()))
             .count(&self.db)
             .await?;
         Ok(count > 0)
@@ -147,29 +379,121 @@ impl UserService {
             None => self.get_user_by_email(username_or_email).await?,
         };
 
-        if let Some(user) = user {
-            // Verify password (this would use proper bcrypt verification in production)
-            if self.verify_password(password, &user.password_hash)? {
-                Ok(Some(user))
-            } else {
-                Ok(None)
-            }
-        } else {
-            Ok(None)
+        let Some(user) = user else {
+            return Ok(None);
+        };
+        if !self.verify_password(password, &user.password_hash)? {
+            return Ok(None);
+        }
+
+        // The password is at hand, so bring an old or differently-costed hash up to date
+        if self.needs_rehash(&user.password_hash) {
+            let password_hash = self.hash_password(password)?;
+            let mut user_active: user::ActiveModel = user.into();
+            user_active.password_hash = Set(password_hash);
+            return Ok(Some(user_active.update(&self.db).await?));
         }
+        Ok(Some(user))
     }
 
-    /// Hash password (placeholder - would use bcrypt in production)
+    /// Hash a password with bcrypt at the configured cost
     pub fn hash_password(&self, password: &str) -> Result<String> {
-        // For now, just prefix with "hashed_"
-        // In production, use: bcrypt::hash(password, bcrypt::DEFAULT_COST)?
-        Ok(format!("hashed_{}", password))
+        Ok(bcrypt::hash(password, self.password_hash_cost)?)
     }
 
-    /// Verify password against hash (placeholder - would use bcrypt in production)  
+    /// Verify a password against a stored hash. bcrypt hashes carry their own cost
+    /// and salt, so hashes made under an earlier cost setting still verify.
     pub fn verify_password(&self, password: &str, hash: &str) -> Result<bool> {
-        // For now, just check if hash matches "hashed_" + password
-        // In production, use: bcrypt::verify(password, hash)?
-        Ok(hash == format!("hashed_{}", password))
+        if let Some(legacy) = hash.strip_prefix(LEGACY_HASH_PREFIX) {
+            return Ok(legacy == password);
+        }
+        // A stored value that isn't a bcrypt hash matches no password
+        Ok(bcrypt::verify(password, hash).unwrap_or(false))
+    }
+
+    /// Whether a stored hash predates bcrypt or uses a cost other than the configured one
+    fn needs_rehash(&self, hash: &str) -> bool {
+        match hash.parse::<bcrypt::HashParts>() {
+            Ok(parts) => parts.get_cost() != self.password_hash_cost,
+            Err(_) => hash.starts_with(LEGACY_HASH_PREFIX),
+        }
+    }
+}
+
+/// A new, active user without quota overrides or an avatar
+fn new_user(
+    username: String,
+    email: String,
+    password_hash: String,
+    full_name: Option<String>,
+    is_admin: bool,
+) -> user::ActiveModel {
+    user::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        username: Set(username),
+        email: Set(email),
+        password_hash: Set(password_hash),
+        full_name: Set(full_name),
+        is_active: Set(true),
+        is_admin: Set(is_admin),
+        hide_email: Set(false),
+        max_repositories: Set(None),
+        max_total_bytes: Set(None),
+        avatar_blob_key: Set(None),
+        avatar_version: Set(0),
+        created_at: Set(Utc::now().into()),
+        updated_at: Set(Utc::now().into()),
+    }
+}
+
+/// Whether `hash` is in a format `verify_password` reads: bcrypt, or a legacy hash
+fn is_password_hash(hash: &str) -> bool {
+    hash.parse::<bcrypt::HashParts>().is_ok() || hash.starts_with(LEGACY_HASH_PREFIX)
+}
+
+/// `LOWER(email)`, for comparing with normalized addresses
+fn lower_email() -> Expr {
+    Expr::expr(SimpleExpr::from(Func::lower(Expr::col(user::Column::Email))))
+}
+
+/// Trim and lowercase `email`, rejecting it unless it is shaped like
+/// `local@example.com`: a dot-atom local part of at most `MAX_EMAIL_LOCAL_LEN`
+/// characters and a domain of two or more letter, digit and hyphen labels
+pub fn normalize_email(email: &str) -> Result<String> {
+    let email = email.trim().to_lowercase();
+    let reason = match email.split_once('@') {
+        _ if email.is_empty() => Some("an email address is required"),
+        _ if email.len() > MAX_EMAIL_LEN => Some("email addresses can be at most 254 characters"),
+        None => Some("email addresses need an '@'"),
+        Some((local, domain)) => {
+            let labels: Vec<&str> = domain.split('.').collect();
+            if local.is_empty() {
+                Some("the part before the '@' is empty")
+            } else if domain.is_empty() {
+                Some("the domain after the '@' is empty")
+            } else if local.len() > MAX_EMAIL_LOCAL_LEN {
+                Some("the part before the '@' can be at most 64 characters")
+            } else if !local.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || EMAIL_LOCAL_SPECIALS.contains(c)) {
+                Some("the part before the '@' has characters email addresses can't hold")
+            } else if local.split('.').any(str::is_empty) {
+                Some("the part before the '@' can't start or end with a dot or hold two in a row")
+            } else if labels.len() < 2 {
+                Some("the domain needs a dot, as in example.com")
+            } else if labels.iter().any(|label| {
+                label.is_empty()
+                    || label.len() > 63
+                    || label.starts_with('-')
+                    || label.ends_with('-')
+                    || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            }) {
+                Some("the domain must be letters, digits and hyphens separated by dots")
+            } else {
+                None
+            }
+        }
+    };
+    match reason {
+        Some(reason) => Err(StorageError::InvalidEmail { email, reason: reason.to_string() }.into()),
+        None => Ok(email),
     }
-}
\ No newline at end of file
+}
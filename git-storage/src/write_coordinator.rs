@@ -0,0 +1,156 @@
+use crate::error::StorageError;
+use sea_orm::{DatabaseBackend, DbErr, RuntimeErr};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedMutexGuard;
+use uuid::Uuid;
+
+/// Default time a writer waits for the write lock before giving up
+pub const DEFAULT_WRITE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Granularity of write serialization
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteLockScope {
+    /// One writer per repository at a time
+    Repository,
+    /// One writer across the whole server
+    Global,
+}
+
+impl std::str::FromStr for WriteLockScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "repository" => Ok(WriteLockScope::Repository),
+            "global" => Ok(WriteLockScope::Global),
+            _ => Err(anyhow::anyhow!("Unknown write lock scope: {}", s)),
+        }
+    }
+}
+
+/// Serializes ref transactions and bulk object writes on backends that only
+/// allow a single writer (SQLite). On other backends it is a no-op.
+pub struct WriteCoordinator {
+    enabled: bool,
+    scope: WriteLockScope,
+    lock_timeout: Duration,
+    global_lock: Arc<tokio::sync::Mutex<()>>,
+    repository_locks: Mutex<HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>>,
+    acquisitions: AtomicU64,
+    timeouts: AtomicU64,
+    total_wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+/// Held for the duration of a serialized write
+#[derive(Debug)]
+pub struct WriteGuard {
+    repository_id: Uuid,
+    _guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl WriteGuard {
+    /// Repository this guard was acquired for
+    pub fn repository_id(&self) -> Uuid {
+        self.repository_id
+    }
+}
+
+/// Point-in-time view of write lock contention
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteMetrics {
+    pub enabled: bool,
+    pub scope: WriteLockScope,
+    pub acquisitions: u64,
+    pub timeouts: u64,
+    pub total_wait_ms: f64,
+    pub max_wait_ms: f64,
+}
+
+impl WriteCoordinator {
+    pub fn new(backend: DatabaseBackend, scope: WriteLockScope, lock_timeout: Duration) -> Self {
+        Self {
+            enabled: backend == DatabaseBackend::Sqlite,
+            scope,
+            lock_timeout,
+            global_lock: Arc::new(tokio::sync::Mutex::new(())),
+            repository_locks: Mutex::new(HashMap::new()),
+            acquisitions: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            total_wait_micros: AtomicU64::new(0),
+            max_wait_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait for the write lock covering `repository_id`
+    pub async fn acquire(&self, repository_id: Uuid) -> Result<WriteGuard, StorageError> {
+        if !self.enabled {
+            return Ok(WriteGuard { repository_id, _guard: None });
+        }
+
+        let lock = match self.scope {
+            WriteLockScope::Global => self.global_lock.clone(),
+            WriteLockScope::Repository => self
+                .repository_locks
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(repository_id)
+                .or_default()
+                .clone(),
+        };
+
+        let started = Instant::now();
+        let guard = tokio::time::timeout(self.lock_timeout, lock.lock_owned()).await;
+        let waited = started.elapsed().as_micros() as u64;
+
+        self.total_wait_micros.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait_micros.fetch_max(waited, Ordering::Relaxed);
+
+        match guard {
+            Ok(guard) => {
+                self.acquisitions.fetch_add(1, Ordering::Relaxed);
+                Ok(WriteGuard { repository_id, _guard: Some(guard) })
+            }
+            Err(_) => {
+                self.timeouts.fetch_add(1, Ordering::Relaxed);
+                Err(StorageError::ResourceBusy)
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> WriteMetrics {
+        WriteMetrics {
+            enabled: self.enabled,
+            scope: self.scope,
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            total_wait_ms: self.total_wait_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+            max_wait_ms: self.max_wait_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+/// Convert lock contention reported by the database into `StorageError::ResourceBusy`
+pub fn map_busy_error(err: DbErr) -> anyhow::Error {
+    let busy = match &err {
+        DbErr::ConnectionAcquire(_) => true,
+        DbErr::Exec(RuntimeErr::SqlxError(sqlx::Error::Database(e)))
+        | DbErr::Query(RuntimeErr::SqlxError(sqlx::Error::Database(e))) => {
+            // SQLITE_BUSY (5) and SQLITE_LOCKED (6); Postgres serialization
+            // failures (40001), deadlocks (40P01) and lock timeouts (55P03)
+            matches!(e.code().as_deref(), Some("5" | "6" | "40001" | "40P01" | "55P03"))
+        }
+        _ => false,
+    };
+
+    if busy {
+        StorageError::ResourceBusy.into()
+    } else {
+        err.into()
+    }
+}